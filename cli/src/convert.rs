@@ -1,8 +1,8 @@
-use byte_unit::Byte;
+use rpc::capacity::Capacity;
 /// converts a human string into a blocklen
 pub(crate) fn parse_block_len(src: &str) -> Result<u32, String> {
-    if let Ok(val) = Byte::from_str(src) {
-        let val = val.get_bytes() as u32;
+    if let Ok(val) = src.parse::<Capacity>() {
+        let val = val.bytes() as u32;
         if !val.is_power_of_two() {
             Err(format!("{} is not a power of two", val))
         } else {
@@ -15,9 +15,7 @@ pub(crate) fn parse_block_len(src: &str) -> Result<u32, String> {
 
 /// parses a human string into bytes accounts for MiB and MB
 pub(crate) fn parse_size(src: &str) -> Result<u64, String> {
-    if let Ok(val) = Byte::from_str(src) {
-        Ok(val.get_bytes() as u64)
-    } else {
-        Err(src.to_string())
-    }
+    src.parse::<Capacity>()
+        .map(u64::from)
+        .map_err(|err| err.to_string())
 }
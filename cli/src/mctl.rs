@@ -98,6 +98,9 @@ enum Sub {
         /// name of the nbd device to unshare
         name: String,
     },
+    #[structopt(name = "capabilities")]
+    /// Show the architecture, hugepages and backends available on the node
+    Capabilities,
 }
 
 fn fut(
@@ -178,6 +181,7 @@ fn main() -> Result<(), ()> {
         Sub::UnShare {
             name,
         } => fut(opt.socket, "stop_nbd_disk", json!({ "bdev_name": name })),
+        Sub::Capabilities => fut(opt.socket, "get_capabilities", json!(null)),
     };
 
     let _res = rt.block_on(fut);
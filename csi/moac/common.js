@@ -73,6 +73,24 @@ function parseMayastorNodeId(nodeId) {
   };
 }
 
+// Format size in bytes for humans in binary units (the same way as the
+// tools of mayastor do, see rpc/src/capacity.rs), i.e. "1.5 GiB".
+function formatCapacity(bytes) {
+  bytes = Number(bytes);
+  const units = [
+    [Math.pow(2, 40), 'TiB'],
+    [Math.pow(2, 30), 'GiB'],
+    [Math.pow(2, 20), 'MiB'],
+    [Math.pow(2, 10), 'KiB'],
+  ];
+  for (let [size, name] of units) {
+    if (bytes >= size) {
+      return `${parseFloat((bytes / size).toFixed(2))} ${name}`;
+    }
+  }
+  return `${bytes} B`;
+}
+
 // Return true if the storage pool is accessible via gRPC
 function isPoolAccessible(pool) {
   return pool.state == 'ONLINE' || pool.state == 'DEGRADED';
@@ -80,6 +98,7 @@ function isPoolAccessible(pool) {
 
 module.exports = {
   PLUGIN_NAME,
+  formatCapacity,
   isPoolAccessible,
   mayastor,
   GrpcError,
@@ -8,14 +8,30 @@ const log = require('./logger').Logger('csi');
 const {
   PLUGIN_NAME,
   GrpcError,
+  formatCapacity,
   parseMayastorNodeId,
   isPoolAccessible,
 } = require('./common');
+const { Journal } = require('./journal');
 
 const PROTO_PATH = __dirname + '/../proto/csi.proto';
 // TODO: can we generate version with commit SHA dynamically?
 const VERSION = '0.1';
 const PVC_RE = /pvc-([0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12})/;
+// storage class parameters which are applied by the node plugin at stage time
+// and patterns of their valid values
+const NODE_PARAMETERS = {
+  read_ahead_kb: /^[0-9]+$/,
+  direct_io: /^(true|false)$/,
+};
+// storage class parameters for publishing a sub-directory of the filesystem
+// and patterns of their valid values (the sub-path may contain ${pod.*}
+// placeholders which are expanded by the node plugin)
+const SUB_PATH_PARAMETERS = {
+  sub_path: /^(?!\/)(?!.*(^|\/)\.\.?(\/|$)).+$/,
+  sub_path_owner: /^[0-9]+:[0-9]+$/,
+  sub_path_mode: /^[0-7]{3,4}$/,
+};
 
 // Load csi proto file with controller and identity services
 const packageDefinition = protoLoader.loadSync(PROTO_PATH, {
@@ -52,6 +68,18 @@ function checkCapabilities(caps) {
   }
 }
 
+// Return true if two journal records are about the same subject: the volume
+// itself (create and destroy) or its publication on a node.
+function sameSubject(rec1, rec2) {
+  let lifecycle = rec => rec.op == 'create' || rec.op == 'destroy';
+
+  if (rec1.args.uuid != rec2.args.uuid) return false;
+  if (lifecycle(rec1) || lifecycle(rec2)) {
+    return lifecycle(rec1) && lifecycle(rec2);
+  }
+  return rec1.args.node == rec2.args.node;
+}
+
 // CSI Controller implementation.
 //
 // It implements Identity and Controller grpc services from csi proto file.
@@ -67,6 +95,7 @@ class CsiServer {
     this.sockPath = sockPath;
     this.nextListContextId = 1;
     this.listContexts = {};
+    this.journal = new Journal();
 
     // The data returned by identity service should be kept in sync with
     // responses for the same methods on storage node.
@@ -159,11 +188,116 @@ class CsiServer {
   }
 
   // Switch csi server to ready state (returned by identity.probe method).
-  // This will enable serving controller grpc service requests.
-  makeReady(poolOperator, volumeOperator) {
+  // This will enable serving controller grpc service requests. Operations
+  // are recorded in the journal if given (otherwise in memory only).
+  makeReady(poolOperator, volumeOperator, journal) {
     this.ready = true;
     this.pools = poolOperator;
     this.volumes = volumeOperator;
+    if (journal) this.journal = journal;
+  }
+
+  // Resolve operations which were started by the previous instance of moac
+  // but have not finished (see journal.js). It must be done before the
+  // server is ready. Volumes whose creation has not been confirmed are
+  // destroyed (unless they are published, which means that k8s knows about
+  // them), so are volumes which were being destroyed. Publish which has
+  // not been confirmed is undone unless the volume had been published
+  // before and unpublish is finished. An operation followed by a later
+  // interrupted operation on the same subject is left to the later one.
+  // Create and destroy which can't be resolved now (i.e. the node is not
+  // reachable) stay in the journal until the next start.
+  async replayJournal(journal, volumeOperator) {
+    let pending = journal.pending();
+
+    for (let i = 0; i < pending.length; i++) {
+      let rec = pending[i];
+      let { node, uuid } = rec.args;
+      let what = `${rec.op} of volume "${uuid}" on node "${node}"`;
+
+      if (pending.slice(i + 1).some(later => sameSubject(later, rec))) {
+        log.info(`Interrupted ${what} is superseded by a later operation`);
+        await journal.end(rec.id);
+        continue;
+      }
+      try {
+        switch (rec.op) {
+          case 'create': {
+            let deps = volumeOperator.dependents(uuid);
+            if (deps && deps.publications.length > 0) {
+              log.info(`Keeping published volume of interrupted ${what}`);
+              break;
+            }
+            await volumeOperator.destroy(node, uuid);
+            break;
+          }
+          case 'destroy':
+            await volumeOperator.destroy(node, uuid);
+            break;
+          case 'publish':
+          case 'unpublish':
+            if (
+              (rec.op == 'unpublish' || !rec.args.published) &&
+              volumeOperator.get(uuid)
+            ) {
+              try {
+                await volumeOperator.destroyBlkdev(node, uuid);
+              } catch (err) {
+                // most likely the blkdev has not been created
+                log.warn(
+                  `Failed to resolve blkdev of interrupted ${what}: ${err}`
+                );
+              }
+            }
+            break;
+          default:
+            log.warn(`Dropping unknown operation ${what} from the journal`);
+        }
+      } catch (err) {
+        log.error(`Failed to resolve interrupted ${what}: ${err}`);
+        continue;
+      }
+      log.info(`Resolved interrupted ${what}`);
+      await journal.end(rec.id);
+    }
+  }
+
+  // Run the operation recorded in the journal, so that it can be resolved
+  // if moac crashes before it has finished.
+  async _journaled(op, args, fn) {
+    let id;
+    try {
+      id = await this.journal.begin(op, args);
+    } catch (err) {
+      throw new GrpcError(
+        grpc.status.INTERNAL,
+        `Failed to record ${op} of volume "${args.uuid}": ${err}`
+      );
+    }
+    let res;
+    try {
+      res = await fn();
+    } catch (err) {
+      // a failed operation does not need to be resolved either
+      await this.journal.end(id).catch(() => {});
+      throw err;
+    }
+    try {
+      await this.journal.end(id);
+      // earlier interrupted operations which have not been resolved yet are
+      // overridden (i.e. create retried by k8s confirms the volume)
+      let rec = { id, op, args };
+      await this.journal.supersede(
+        other => other.id < id && sameSubject(other, rec)
+      );
+    } catch (err) {
+      // the operation would be resolved as interrupted at the next start
+      throw new GrpcError(
+        grpc.status.INTERNAL,
+        `Failed to record completed ${op} of volume "${args.uuid}": ${err}`
+      );
+    }
+    return res;
   }
 
   // Stop serving controller requests, but the identity service still works.
@@ -237,6 +371,40 @@ class CsiServer {
     return pools;
   }
 
+  // Return the pool given by "pool" parameter of the storage class if the
+  // volume can be created on it. The placement rules are bypassed, so that
+  // admins can pin the volume to a pool of their choice.
+  pinnedPool(name, requiredBytes, mustNodes) {
+    let pool = this.pools.get(name);
+    if (!pool) {
+      throw new GrpcError(
+        grpc.status.INVALID_ARGUMENT,
+        `Storage pool "${name}" does not exist`
+      );
+    }
+    if (mustNodes.length > 0 && mustNodes.indexOf(pool.node) < 0) {
+      throw new GrpcError(
+        grpc.status.INVALID_ARGUMENT,
+        `Storage pool "${name}" is on node "${pool.node}", which does not ` +
+          'meet the topology requirements'
+      );
+    }
+    if (!isPoolAccessible(pool)) {
+      throw new GrpcError(
+        grpc.status.INTERNAL,
+        `Storage pool "${name}" not accessible`
+      );
+    }
+    if (pool.capacity - pool.used < requiredBytes) {
+      throw new GrpcError(
+        grpc.status.RESOURCE_EXHAUSTED,
+        `Storage pool "${name}" does not have ${requiredBytes} bytes of ` +
+          'free space'
+      );
+    }
+    return pool;
+  }
+
   //
   // Implementation of CSI identity methods
   //
@@ -291,8 +459,9 @@ class CsiServer {
 
     log.debug(
       `Request to create volume "${args.name}" with size ` +
-        args.capacityRange.requiredBytes +
-        ` (limit ${args.capacityRange.limitBytes})`
+        formatCapacity(args.capacityRange.requiredBytes) +
+        ` (${args.capacityRange.requiredBytes} bytes, ` +
+        `limit ${args.capacityRange.limitBytes})`
     );
 
     if (args.volumeContentSource) {
@@ -320,6 +489,55 @@ class CsiServer {
     } catch (err) {
       return cb(err);
     }
+    let volumeContext = {};
+    let parameters = args.parameters || {};
+    for (let name in NODE_PARAMETERS) {
+      if (parameters[name] === undefined) {
+        continue;
+      }
+      if (!NODE_PARAMETERS[name].test(parameters[name])) {
+        return cb(
+          new GrpcError(
+            grpc.status.INVALID_ARGUMENT,
+            `Invalid value of parameter ${name}: ${parameters[name]}`
+          )
+        );
+      }
+      volumeContext[name] = parameters[name];
+    }
+    for (let name in SUB_PATH_PARAMETERS) {
+      if (parameters[name] === undefined) {
+        continue;
+      }
+      if (!SUB_PATH_PARAMETERS[name].test(parameters[name])) {
+        return cb(
+          new GrpcError(
+            grpc.status.INVALID_ARGUMENT,
+            `Invalid value of parameter ${name}: ${parameters[name]}`
+          )
+        );
+      }
+      volumeContext[name] = parameters[name];
+    }
+    if (
+      parameters.compression !== undefined &&
+      ['true', 'false'].indexOf(parameters.compression) < 0
+    ) {
+      return cb(
+        new GrpcError(
+          grpc.status.INVALID_ARGUMENT,
+          `Invalid value of parameter compression: ${parameters.compression}`
+        )
+      );
+    }
+    if (parameters.pool === '') {
+      return cb(
+        new GrpcError(
+          grpc.status.INVALID_ARGUMENT,
+          'Invalid value of parameter pool: empty name'
+        )
+      );
+    }
     let mustNodes = [];
     let shouldNodes = [];
 
@@ -373,9 +591,22 @@ class CsiServer {
             `A different volume with name "${args.name}" already exists`
           )
         );
-      } else {
-        return cb(null, vol);
       }
+      // the volume is confirmed to k8s now even if its creation has been
+      // interrupted
+      try {
+        await this.journal.supersede(
+          rec => rec.op == 'create' && rec.args.uuid == uuid
+        );
+      } catch (err) {
+        return cb(
+          new GrpcError(
+            grpc.status.INTERNAL,
+            `Failed to record create of volume "${uuid}": ${err}`
+          )
+        );
+      }
+      return cb(null, vol);
     }
     // limitBytes is 0 if not set, so fix it to be at least what is required
     if (args.capacityRange.requiredBytes > args.capacityRange.limitBytes) {
@@ -385,19 +616,35 @@ class CsiServer {
     // sync used and capacity pool properties before making the decision
     // of where to provision the volume
     await this.pools.syncNode();
-    let pools = this.choosePools(
-      args.capacityRange.requiredBytes,
-      mustNodes,
-      shouldNodes
-    );
+    let pools;
+    if (parameters.pool !== undefined) {
+      try {
+        pools = [
+          this.pinnedPool(
+            parameters.pool,
+            args.capacityRange.requiredBytes,
+            mustNodes
+          ),
+        ];
+      } catch (err) {
+        log.error(`Cannot create volume "${args.name}": ${err.message}`);
+        return cb(err);
+      }
+    } else {
+      pools = this.choosePools(
+        args.capacityRange.requiredBytes,
+        mustNodes,
+        shouldNodes
+      );
+    }
     if (pools.length == 0) {
       log.error(
         'No suitable pool for the volume "' +
           args.name +
           '" with capacity range ' +
-          args.capacityRange.requiredBytes +
+          formatCapacity(args.capacityRange.requiredBytes) +
           ' - ' +
-          args.capacityRange.limitBytes
+          formatCapacity(args.capacityRange.limitBytes)
       );
 
       return cb(
@@ -434,7 +681,18 @@ class CsiServer {
       }
 
       try {
-        await this.volumes.create(pool.node, pool.name, uuid, size);
+        await this._journaled(
+          'create',
+          { node: pool.node, pool: pool.name, uuid, size },
+          () =>
+            this.volumes.create(
+              pool.node,
+              pool.name,
+              uuid,
+              size,
+              args.parameters
+            )
+        );
       } catch (err) {
         log.error(err.message);
         errors.push(err.message);
@@ -442,13 +700,15 @@ class CsiServer {
       }
 
       log.info(
-        `Volume "${args.name}" with size ${size} created on pool "${pool.name}"`
+        `Volume "${args.name}" with size ${formatCapacity(size)} created ` +
+          `on pool "${pool.name}"`
       );
 
       return cb(null, {
         volume: {
           capacityBytes: size,
           volumeId: uuid,
+          volumeContext,
           // enfore local access to the volume
           accessibleTopology: [
             {
@@ -472,6 +732,23 @@ class CsiServer {
       // most likely already deleted
       return cb();
     }
+    // refuse to destroy volume which is in use and tell the caller why (as
+    // the node sees it, the cache might be out of date)
+    if (!(await this.volumes.refresh(vol.node))) {
+      log.warn(
+        `Failed to get state of volume "${args.volumeId}" from node "${vol.node}"`
+      );
+    }
+    let deps = this.volumes.dependents(args.volumeId);
+    if (deps.publications.length > 0) {
+      return cb(
+        new GrpcError(
+          grpc.status.FAILED_PRECONDITION,
+          `Volume "${args.volumeId}" is published on node(s) ` +
+            deps.publications.map(p => `"${p.node}"`).join(', ')
+        )
+      );
+    }
     let pool = this.pools.get(vol.pool);
     assert(pool, 'Volume exists but pool does not');
     if (!isPoolAccessible(pool)) {
@@ -484,7 +761,11 @@ class CsiServer {
     }
 
     try {
-      await this.volumes.destroy(pool.node, args.volumeId);
+      await this._journaled(
+        'destroy',
+        { node: pool.node, uuid: args.volumeId },
+        () => this.volumes.destroy(pool.node, args.volumeId)
+      );
     } catch (err) {
       return cb(err);
     }
@@ -595,19 +876,29 @@ class CsiServer {
       return cb(err);
     }
 
+    let deps = this.volumes.dependents(args.volumeId);
+    let published = deps.publications.length > 0;
     try {
-      await this.volumes.createBlkdev(pool.node, args.volumeId);
+      await this._journaled(
+        'publish',
+        { node: pool.node, uuid: args.volumeId, published },
+        () => this.volumes.createBlkdev(pool.node, args.volumeId)
+      );
     } catch (err) {
       if (err.code === grpc.status.ALREADY_EXISTS) {
         log.debug(`Volume "${args.volumeId}" already published on this node`);
-        cb(null, {});
       } else {
         return cb(err);
       }
     }
 
-    log.info(`Published volume "${args.volumeId}"`);
-    cb(null, {});
+    // The node refuses to stage the volume with an epoch older than the one
+    // the device has been exported with, which fences stale attachments.
+    let epoch = this.volumes.get(args.volumeId).fencingEpoch;
+    log.info(`Published volume "${args.volumeId}" with fencing epoch ${epoch}`);
+    cb(null, {
+      publishContext: { fencing_epoch: epoch.toString() },
+    });
   }
 
   async controllerUnpublishVolume(call, cb) {
@@ -651,7 +942,11 @@ class CsiServer {
     }
 
     try {
-      await this.volumes.destroyBlkdev(pool.node, args.volumeId);
+      await this._journaled(
+        'unpublish',
+        { node: pool.node, uuid: args.volumeId },
+        () => this.volumes.destroyBlkdev(pool.node, args.volumeId)
+      );
     } catch (err) {
       return cb(err);
     }
@@ -715,7 +1010,9 @@ class CsiServer {
               return isPoolAccessible(p) ? acc + (p.capacity - p.used) : 0;
             }, 0);
           // jshint ignore:end
-          log.debug(`Get capacity of node "${nodeName}": ${capacity} bytes`);
+          log.debug(
+            `Get capacity of node "${nodeName}": ${formatCapacity(capacity)}`
+          );
           return cb(null, { availableCapacity: capacity });
         }
       }
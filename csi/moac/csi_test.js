@@ -9,6 +9,7 @@ const grpc = require('grpc-uds');
 const grpc_promise = require('grpc-promise');
 const { CsiServer, csi, GrpcError } = require('./csi');
 const { VolumeOperatorMock } = require('./volumes');
+const { Journal } = require('./journal');
 
 const SOCKPATH = '/tmp/csi_controller_test.sock';
 // uuid used whenever we need some uuid and don't care about which one
@@ -130,6 +131,128 @@ module.exports = function() {
     });
   });
 
+  describe('journal', function() {
+    var server;
+    var volumes;
+    var journal;
+    const UUID2 = 'd01b8bfb-0116-47b0-a03a-447fcbdc0e98';
+
+    beforeEach(() => {
+      server = new CsiServer(SOCKPATH);
+      volumes = new VolumeOperatorMock([
+        { uuid: UUID, pool: 'pool', node: 'node', size: 10 },
+        { uuid: UUID2, pool: 'pool', node: 'node', size: 10, dev: '/dev/nbd0' },
+      ]);
+      journal = new Journal();
+    });
+
+    it('should record operation until it is done', async () => {
+      server.makeReady(new FakePoolOperator(), volumes, journal);
+      await server._journaled('destroy', { node: 'node', uuid: UUID }, () => {
+        assert.lengthOf(journal.pending(), 1);
+        assert.equal(journal.pending()[0].op, 'destroy');
+        return volumes.destroy('node', UUID);
+      });
+      assert.lengthOf(journal.pending(), 0);
+
+      // failed operation is done too
+      volumes.injectError(new GrpcError(grpc.status.INTERNAL, 'test'));
+      await shouldFailWith(grpc.status.INTERNAL, () =>
+        server._journaled('destroy', { node: 'node', uuid: UUID2 }, () =>
+          volumes.destroy('node', UUID2)
+        )
+      );
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should roll back interrupted create and finish destroy', async () => {
+      await journal.begin('create', { node: 'node', pool: 'pool', uuid: UUID });
+      await journal.begin('destroy', { node: 'node', uuid: UUID2 });
+      await server.replayJournal(journal, volumes);
+      assert.lengthOf(volumes.get(), 0);
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should undo interrupted publish and finish unpublish', async () => {
+      volumes.get(UUID).dev = '/dev/nbd1';
+      await journal.begin('publish', {
+        node: 'node',
+        uuid: UUID,
+        published: false,
+      });
+      // was published before the interrupted publish
+      await journal.begin('publish', {
+        node: 'node',
+        uuid: UUID2,
+        published: true,
+      });
+      await server.replayJournal(journal, volumes);
+      assert.isUndefined(volumes.get(UUID).dev);
+      assert.equal(volumes.get(UUID2).dev, '/dev/nbd0');
+      assert.lengthOf(journal.pending(), 0);
+
+      await journal.begin('unpublish', { node: 'node', uuid: UUID2 });
+      await server.replayJournal(journal, volumes);
+      assert.isUndefined(volumes.get(UUID2).dev);
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should keep published volume of interrupted create', async () => {
+      await journal.begin('create', { node: 'node', pool: 'pool', uuid: UUID2 });
+      await server.replayJournal(journal, volumes);
+      assert.equal(volumes.get(UUID2).dev, '/dev/nbd0');
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should leave interrupted operation to a later one', async () => {
+      // both publish and the following unpublish have been interrupted, the
+      // blkdev must be destroyed just once
+      volumes.get(UUID).dev = '/dev/nbd1';
+      await journal.begin('publish', {
+        node: 'node',
+        uuid: UUID,
+        published: false,
+      });
+      await journal.begin('unpublish', { node: 'node', uuid: UUID });
+      // destroy of the volume is not about its publication
+      await journal.begin('destroy', { node: 'node', uuid: UUID });
+      await server.replayJournal(journal, volumes);
+      assert.isUndefined(volumes.get(UUID));
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should keep operation which cannot be resolved', async () => {
+      await journal.begin('create', { node: 'node', pool: 'pool', uuid: UUID });
+      volumes.injectError(new GrpcError(grpc.status.INTERNAL, 'node down'));
+      await server.replayJournal(journal, volumes);
+      assert.lengthOf(volumes.get(), 2);
+      assert.lengthOf(journal.pending(), 1);
+
+      // resolved at the next start
+      await server.replayJournal(journal, volumes);
+      assert.lengthOf(volumes.get(), 1);
+      assert.lengthOf(journal.pending(), 0);
+    });
+
+    it('should not destroy volume whose create was retried', async () => {
+      server.makeReady(new FakePoolOperator(), volumes, journal);
+      await journal.begin('create', { node: 'node', pool: 'pool', uuid: UUID });
+      volumes.injectError(new GrpcError(grpc.status.INTERNAL, 'node down'));
+      await server.replayJournal(journal, volumes);
+      assert.lengthOf(journal.pending(), 1);
+
+      // k8s has retried the creation meanwhile
+      await server._journaled(
+        'create',
+        { node: 'node', pool: 'pool', uuid: UUID },
+        () => volumes.create('node', 'pool', UUID, 10)
+      );
+      assert.lengthOf(journal.pending(), 0);
+      await server.replayJournal(journal, volumes);
+      assert.isOk(volumes.get(UUID));
+    });
+  });
+
   describe('controller', function() {
     var client;
 
@@ -744,6 +867,339 @@ module.exports = function() {
         assert.equal(vols[0].size, 50);
       });
 
+      it('should pass node parameters in volume context', async () => {
+        server = await mockedServer([
+          {
+            name: 'online',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        let res = await client.createVolume().sendMessage({
+          name: 'pvc-' + UUID,
+          capacityRange: {
+            requiredBytes: 50,
+            limitBytes: 50,
+          },
+          volumeCapabilities: [
+            {
+              accessMode: { mode: 'SINGLE_NODE_WRITER' },
+              block: {},
+            },
+          ],
+          parameters: { read_ahead_kb: '4096', direct_io: 'true' },
+        });
+        assert.equal(res.volume.volumeId, UUID);
+        assert.deepEqual(res.volume.volumeContext, {
+          read_ahead_kb: '4096',
+          direct_io: 'true',
+        });
+      });
+
+      it('should fail if read_ahead_kb parameter is invalid', async () => {
+        server = await mockedServer([
+          {
+            name: 'online',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        await shouldFailWith(grpc.status.INVALID_ARGUMENT, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 50,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                block: {},
+              },
+            ],
+            parameters: { read_ahead_kb: '4M' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
+      it('should pass sub_path parameters in volume context', async () => {
+        server = await mockedServer([
+          {
+            name: 'online',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        let res = await client.createVolume().sendMessage({
+          name: 'pvc-' + UUID,
+          capacityRange: {
+            requiredBytes: 50,
+            limitBytes: 50,
+          },
+          volumeCapabilities: [
+            {
+              accessMode: { mode: 'SINGLE_NODE_WRITER' },
+              mount: { fsType: 'xfs' },
+            },
+          ],
+          parameters: {
+            sub_path: 'data/${pod.namespace}/${pod.name}',
+            sub_path_owner: '1000:1000',
+            sub_path_mode: '0750',
+          },
+        });
+        assert.equal(res.volume.volumeId, UUID);
+        assert.deepEqual(res.volume.volumeContext, {
+          sub_path: 'data/${pod.namespace}/${pod.name}',
+          sub_path_owner: '1000:1000',
+          sub_path_mode: '0750',
+        });
+      });
+
+      it('should fail if sub_path parameter escapes the volume', async () => {
+        server = await mockedServer([
+          {
+            name: 'online',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        await shouldFailWith(grpc.status.INVALID_ARGUMENT, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 50,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                mount: { fsType: 'xfs' },
+              },
+            ],
+            parameters: { sub_path: 'data/../../etc' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
+      it('should fail if compression parameter is invalid', async () => {
+        server = await mockedServer([
+          {
+            name: 'online',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        await shouldFailWith(grpc.status.INVALID_ARGUMENT, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 50,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                block: {},
+              },
+            ],
+            parameters: { compression: 'yes' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
+      it('should create volume on pool given by parameter', async () => {
+        server = await mockedServer([
+          {
+            // by all measures this one would normally be preferred
+            name: 'online',
+            node: 'node-other',
+            disks: ['/dev/sdb'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 0,
+          },
+          {
+            name: 'degraded',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'DEGRADED',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        let res = await client.createVolume().sendMessage({
+          name: 'pvc-' + UUID,
+          capacityRange: {
+            requiredBytes: 50,
+            limitBytes: 0,
+          },
+          volumeCapabilities: [
+            {
+              accessMode: { mode: 'SINGLE_NODE_WRITER' },
+              filesystem: {},
+            },
+          ],
+          parameters: { pool: 'degraded' },
+        });
+        assert.equal(res.volume.capacityBytes, 50);
+        let vols = server.volumes.get();
+        assert.lengthOf(vols, 1);
+        assert.equal(vols[0].pool, 'degraded');
+      });
+
+      it('should fail if pool given by parameter does not exist', async () => {
+        server = await mockedServer([
+          {
+            // by all measures this one would normally be preferred
+            name: 'online',
+            node: 'node-other',
+            disks: ['/dev/sdb'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 0,
+          },
+          {
+            name: 'degraded',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'DEGRADED',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        await shouldFailWith(grpc.status.INVALID_ARGUMENT, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 0,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                filesystem: {},
+              },
+            ],
+            parameters: { pool: 'missing' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
+      it('should fail if pool given by parameter is full', async () => {
+        server = await mockedServer([
+          {
+            // by all measures this one would normally be preferred
+            name: 'online',
+            node: 'node-other',
+            disks: ['/dev/sdb'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 0,
+          },
+          {
+            name: 'degraded',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'DEGRADED',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        server.pools.once('sync', () => {
+          server.pools.pools[1].used += 10;
+        });
+        await shouldFailWith(grpc.status.RESOURCE_EXHAUSTED, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 0,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                filesystem: {},
+              },
+            ],
+            parameters: { pool: 'degraded' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
+      it('should fail if pool given by parameter is on other node', async () => {
+        server = await mockedServer([
+          {
+            // by all measures this one would normally be preferred
+            name: 'online',
+            node: 'node-other',
+            disks: ['/dev/sdb'],
+            state: 'ONLINE',
+            capacity: 100,
+            used: 0,
+          },
+          {
+            name: 'degraded',
+            node: 'node',
+            disks: ['/dev/sda'],
+            state: 'DEGRADED',
+            capacity: 100,
+            used: 50,
+          },
+        ]);
+
+        await shouldFailWith(grpc.status.INVALID_ARGUMENT, () =>
+          client.createVolume().sendMessage({
+            name: 'pvc-' + UUID,
+            capacityRange: {
+              requiredBytes: 50,
+              limitBytes: 0,
+            },
+            volumeCapabilities: [
+              {
+                accessMode: { mode: 'SINGLE_NODE_WRITER' },
+                filesystem: {},
+              },
+            ],
+            accessibilityRequirements: {
+              requisite: [
+                { segments: { 'kubernetes.io/hostname': 'node-other' } },
+              ],
+            },
+            parameters: { pool: 'degraded' },
+          })
+        );
+        assert.lengthOf(server.volumes.get(), 0);
+      });
+
       it('should not fail if it already exists', async () => {
         server = await mockedServer([
           {
@@ -962,6 +1418,35 @@ module.exports = function() {
           client.deleteVolume().sendMessage({ volumeId: UUID })
         );
       });
+
+      it('should not delete published volume', async () => {
+        server = await mockedServer(
+          [
+            {
+              name: 'pool',
+              node: 'node',
+              disks: ['/dev/sda'],
+              state: 'ONLINE',
+              capacity: 100,
+              used: 50,
+            },
+          ],
+          [
+            {
+              uuid: UUID,
+              pool: 'pool',
+              node: 'node',
+              size: 50,
+              dev: '/dev/nbd0',
+            },
+          ]
+        );
+
+        await shouldFailWith(grpc.status.FAILED_PRECONDITION, () =>
+          client.deleteVolume().sendMessage({ volumeId: UUID })
+        );
+        assert.lengthOf(server.volumes.get(), 1);
+      });
     });
 
     describe('ListVolumes', function() {
@@ -1100,7 +1585,7 @@ module.exports = function() {
       });
 
       it('should publish volume', async () => {
-        await client.controllerPublishVolume().sendMessage({
+        let res = await client.controllerPublishVolume().sendMessage({
           volumeId: UUID,
           nodeId: 'mayastor://node/10.244.2.15:10124',
           readonly: false,
@@ -1118,6 +1603,8 @@ module.exports = function() {
         assert.isNotNull(vols[0].dev);
         assert.equal(vols[1].uuid, offlineUuid);
         assert(!vols[1].dev);
+        assert.equal(res.publishContext.fencing_epoch, '1');
+        assert.equal(vols[0].fencingEpoch, 1);
       });
 
       it('should not publish volume if it does not exist', async () => {
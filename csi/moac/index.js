@@ -11,7 +11,10 @@ const logger = require('./logger');
 const { NodeOperator } = require('./nodes');
 const { PoolOperator } = require('./pools');
 const { VolumeOperator } = require('./volumes');
+const { VolumeMirror } = require('./volume_mirror');
 const { ApiServer } = require('./rest_api');
+const { Journal } = require('./journal');
+const { registerDriver, NodeLabeler } = require('./registration');
 const CsiServer = require('./csi').CsiServer;
 
 const log = new logger.Logger();
@@ -47,10 +50,13 @@ function printStatus(nodeOper, poolOper, volumeOper) {
 
 async function main() {
   var volumeOper;
+  var volumeMirror;
   var poolOper;
   var nodeOper;
   var csiServer;
   var apiServer;
+  var nodeLabeler;
+  var journal;
 
   let opts = yargs
     .options({
@@ -60,11 +66,28 @@ async function main() {
         default: '/var/tmp/csi.sock',
         string: true,
       },
+      j: {
+        alias: 'journal',
+        describe: 'Path to journal of controller operations (none if not set)',
+        string: true,
+      },
       k: {
         alias: 'kubeconfig',
         describe: 'Path to kubeconfig file',
         string: true,
       },
+      m: {
+        alias: 'mirror-volumes',
+        describe: 'Mirror volumes to mayastorvolume custom resources',
+        default: false,
+        boolean: true,
+      },
+      r: {
+        alias: 'register',
+        describe: 'Register CSI driver object and label mayastor nodes',
+        default: false,
+        boolean: true,
+      },
       p: {
         alias: 'port',
         describe: 'Port the REST API server should listen on',
@@ -97,10 +120,13 @@ async function main() {
   async function cleanUp() {
     csiServer.undoReady();
     if (apiServer) await apiServer.stop();
+    if (volumeMirror) await volumeMirror.stop();
     if (volumeOper) await volumeOper.stop();
+    if (nodeLabeler) nodeLabeler.stop();
     if (poolOper) await poolOper.stop();
     if (nodeOper) await nodeOper.stop();
     if (csiServer) await csiServer.stop();
+    if (journal) await journal.close();
     process.exit(0);
   }
   process.on('SIGTERM', async () => {
@@ -130,13 +156,36 @@ async function main() {
 
   volumeOper = new VolumeOperator(nodeOper);
   apiServer = new ApiServer(volumeOper);
+  if (opts.mirrorVolumes) {
+    volumeMirror = new VolumeMirror(volumeOper);
+    await volumeMirror.init(client);
+  }
 
   await nodeOper.start();
+  if (opts.register) {
+    try {
+      await registerDriver(client);
+    } catch (err) {
+      log.error(`Failed to register CSI driver: ${err}`);
+    }
+    nodeLabeler = new NodeLabeler(client, nodeOper);
+    await nodeLabeler.start();
+  }
   await apiServer.start(opts.port);
   await poolOper.start();
   await volumeOper.start();
+  if (volumeMirror) await volumeMirror.start();
 
-  csiServer.makeReady(poolOper, volumeOper);
+  // resolve operations interrupted by a crash before serving new ones
+  journal = new Journal(opts.journal);
+  try {
+    await journal.open();
+  } catch (err) {
+    log.error(`Cannot open journal ${opts.journal}: ${err}`);
+    process.exit(1);
+  }
+  await csiServer.replayJournal(journal, volumeOper);
+  csiServer.makeReady(poolOper, volumeOper, journal);
 
   // print node, pool & volume list when we start
   printStatus(nodeOper, poolOper, volumeOper);
@@ -0,0 +1,134 @@
+// Write-ahead journal of operations of the CSI controller
+//
+// Each operation changing the state of storage nodes (create and destroy of
+// a volume, publish and unpublish) is recorded in the journal before it is
+// started and marked as done when it has finished (no matter whether it has
+// succeeded or failed). If moac crashes in the middle of an operation, the
+// record stays incomplete and the next instance of moac resolves it at
+// start, before serving CSI requests, by finishing or rolling back the
+// operation (see CsiServer.replayJournal). Otherwise i.e. a replica created
+// for a volume whose creation has never been confirmed to k8s would be
+// leaked.
+//
+// The journal is a file with one json record per line, which is appended
+// and synced to the disk before the operation is started. It is compacted
+// when opened, so that only incomplete operations are kept. Without a path
+// the journal is kept in memory only, which protects nothing, but saves the
+// callers from checking whether there is a journal.
+
+'use strict';
+
+const fs = require('fs').promises;
+const log = require('./logger').Logger('journal');
+
+class Journal {
+  // Create journal stored in the file with given path (null for in-memory).
+  constructor(path) {
+    this.path = path || null;
+    this.file = null;
+    this.nextId = 1;
+    // incomplete operations keyed by id
+    this.incomplete = {};
+  }
+
+  // Read incomplete operations from the file, rewrite the file with just
+  // those and open it for appending new records.
+  async open() {
+    if (!this.path) return;
+
+    let data = '';
+    try {
+      data = await fs.readFile(this.path, 'utf8');
+    } catch (err) {
+      if (err.code != 'ENOENT') throw err;
+    }
+    let lines = data.split('\n').filter(line => line.length > 0);
+    for (let i = 0; i < lines.length; i++) {
+      let rec;
+      try {
+        rec = JSON.parse(lines[i]);
+      } catch (err) {
+        // the last record may be cut short by a crash while writing it, in
+        // which case the operation has not been started
+        if (i < lines.length - 1) {
+          log.warn(`Skipping corrupted record in journal ${this.path}`);
+        }
+        continue;
+      }
+      this.nextId = Math.max(this.nextId, rec.id + 1);
+      if (rec.done) {
+        delete this.incomplete[rec.id];
+      } else {
+        this.incomplete[rec.id] = rec;
+      }
+    }
+
+    let tmpPath = this.path + '.tmp';
+    let tmp = await fs.open(tmpPath, 'w');
+    try {
+      let recs = this.pending().map(rec => JSON.stringify(rec) + '\n');
+      await tmp.writeFile(recs.join(''));
+      await tmp.sync();
+    } finally {
+      await tmp.close();
+    }
+    await fs.rename(tmpPath, this.path);
+    this.file = await fs.open(this.path, 'a');
+    log.debug(
+      `Journal ${this.path} opened with ${this.pending().length} ` +
+        'incomplete operations'
+    );
+  }
+
+  async close() {
+    if (this.file) {
+      await this.file.close();
+      this.file = null;
+    }
+  }
+
+  // Return incomplete operations in the order in which they were started.
+  pending() {
+    return Object.values(this.incomplete).sort((a, b) => a.id - b.id);
+  }
+
+  async _append(rec) {
+    if (!this.file) return;
+    await this.file.write(JSON.stringify(rec) + '\n');
+    await this.file.sync();
+  }
+
+  // Record start of the operation with given arguments and return its id.
+  // The operation must not be started if this fails.
+  async begin(op, args) {
+    let rec = {
+      id: this.nextId++,
+      op: op,
+      args: args,
+      time: new Date().toISOString(),
+    };
+    await this._append(rec);
+    this.incomplete[rec.id] = rec;
+    return rec.id;
+  }
+
+  // Record that the operation has finished.
+  async end(id) {
+    if (!this.incomplete[id]) return;
+    await this._append({ id: id, done: true });
+    delete this.incomplete[id];
+  }
+
+  // Record that incomplete operations matching the predicate have been
+  // superseded by a later operation, so that they are not resolved at the
+  // next start.
+  async supersede(pred) {
+    for (let rec of this.pending().filter(pred)) {
+      await this.end(rec.id);
+    }
+  }
+}
+
+module.exports = {
+  Journal,
+};
@@ -0,0 +1,103 @@
+// Unit tests for the journal of controller operations
+
+'use strict';
+
+const assert = require('chai').assert;
+const fs = require('fs').promises;
+const os = require('os');
+const path = require('path');
+const { Journal } = require('./journal');
+
+const JOURNAL_PATH = path.join(os.tmpdir(), 'moac-journal-test');
+
+module.exports = function() {
+  var journal;
+
+  beforeEach(async () => {
+    await fs.unlink(JOURNAL_PATH).catch(() => {});
+  });
+
+  afterEach(async () => {
+    if (journal) {
+      await journal.close();
+      journal = null;
+    }
+    await fs.unlink(JOURNAL_PATH).catch(() => {});
+  });
+
+  it('should create empty journal', async () => {
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    assert.lengthOf(journal.pending(), 0);
+    await fs.stat(JOURNAL_PATH);
+  });
+
+  it('should keep incomplete operations across restarts', async () => {
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    let id1 = await journal.begin('create', { node: 'node', uuid: 'vol1' });
+    let id2 = await journal.begin('destroy', { node: 'node', uuid: 'vol2' });
+    let id3 = await journal.begin('publish', { node: 'node', uuid: 'vol3' });
+    await journal.end(id2);
+    assert.deepEqual(journal.pending().map(r => r.id), [id1, id3]);
+    await journal.close();
+
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    let pending = journal.pending();
+    assert.lengthOf(pending, 2);
+    assert.equal(pending[0].op, 'create');
+    assert.deepEqual(pending[0].args, { node: 'node', uuid: 'vol1' });
+    assert.equal(pending[1].op, 'publish');
+    // new ids don't collide with the old ones
+    let id4 = await journal.begin('unpublish', { node: 'node', uuid: 'vol3' });
+    assert.isAbove(id4, id3);
+    await journal.end(id1);
+    await journal.end(id3);
+    await journal.end(id4);
+    await journal.close();
+
+    // the file is compacted when opened
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    assert.lengthOf(journal.pending(), 0);
+    let data = await fs.readFile(JOURNAL_PATH, 'utf8');
+    assert.equal(data, '');
+  });
+
+  it('should ignore record cut short by a crash', async () => {
+    await fs.writeFile(
+      JOURNAL_PATH,
+      '{"id":1,"op":"create","args":{"uuid":"vol1"}}\n{"id":2,"op":"cre'
+    );
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    let pending = journal.pending();
+    assert.lengthOf(pending, 1);
+    assert.equal(pending[0].id, 1);
+  });
+
+  it('should end superseded operations', async () => {
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    let id1 = await journal.begin('create', { node: 'node', uuid: 'vol1' });
+    let id2 = await journal.begin('create', { node: 'node', uuid: 'vol2' });
+    await journal.supersede(rec => rec.args.uuid == 'vol1');
+    assert.deepEqual(journal.pending().map(r => r.id), [id2]);
+    await journal.close();
+
+    journal = new Journal(JOURNAL_PATH);
+    await journal.open();
+    assert.deepEqual(journal.pending().map(r => r.id), [id2]);
+    assert.notEqual(id1, id2);
+  });
+
+  it('should keep operations in memory without a path', async () => {
+    journal = new Journal();
+    await journal.open();
+    let id = await journal.begin('create', { node: 'node', uuid: 'vol1' });
+    assert.lengthOf(journal.pending(), 1);
+    await journal.end(id);
+    assert.lengthOf(journal.pending(), 0);
+  });
+};
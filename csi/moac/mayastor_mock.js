@@ -27,6 +27,8 @@ class MayastorServer {
 
     this.pools = pools || [];
     this.replicas = replicas || [];
+    // keys of encrypted pools as they were passed in by the pool name
+    this.encryptionKeys = {};
     this.statCounter = 0;
 
     var self = this;
@@ -35,7 +37,12 @@ class MayastorServer {
       // capacity to 100 and used to 4.
       createPool: (call, cb) => {
         let args = call.request;
-        assert.hasAllKeys(args, ['name', 'disks', 'blockSize']);
+        assert.hasAllKeys(args, [
+          'name',
+          'disks',
+          'blockSize',
+          'encryptionKey',
+        ]);
         if (self.pools.find(p => p.name == args.name)) {
           let err = new Error('already exists');
           err.code = grpc.status.ALREADY_EXISTS;
@@ -47,7 +54,9 @@ class MayastorServer {
             state: 0,
             capacity: 100,
             used: 4,
+            encrypted: args.encryptionKey != '',
           });
+          self.encryptionKeys[args.name] = args.encryptionKey;
           cb(null, {});
         }
       },
@@ -69,7 +78,7 @@ class MayastorServer {
       },
       createReplica: (call, cb) => {
         let args = call.request;
-        assert.hasAllKeys(args, ['uuid', 'pool', 'size', 'thin']);
+        assert.hasAllKeys(args, ['uuid', 'pool', 'size', 'thin', 'compress']);
         if (self.replicas.find(r => r.uuid == args.uuid)) {
           let err = new Error('already exists');
           err.code = grpc.status.ALREADY_EXISTS;
@@ -81,7 +90,7 @@ class MayastorServer {
           err.code = grpc.status.NOT_FOUND;
           return cb(err);
         }
-        if (!args.thin) {
+        if (!args.thin && !args.compress) {
           pool.used += args.size;
         }
 
@@ -89,7 +98,8 @@ class MayastorServer {
           uuid: args.uuid,
           pool: args.pool,
           size: args.size,
-          thin: args.thin,
+          thin: args.thin || args.compress,
+          compressed: args.compress,
         });
         cb(null, {});
       },
@@ -113,6 +123,37 @@ class MayastorServer {
       listReplicas: (_, cb) => {
         cb(null, { replicas: self.replicas });
       },
+      createBlkdev: (call, cb) => {
+        let args = call.request;
+        assert.hasAllKeys(args, ['uuid', 'fencingEpoch']);
+        let r = self.replicas.find(r => r.uuid == args.uuid);
+        if (!r) {
+          let err = new Error('not found');
+          err.code = grpc.status.NOT_FOUND;
+          return cb(err);
+        }
+        let epoch = parseInt(args.fencingEpoch);
+        if (epoch < (r.fencingEpoch || 0)) {
+          let err = new Error('stale attachment');
+          err.code = grpc.status.FAILED_PRECONDITION;
+          return cb(err);
+        }
+        r.fencingEpoch = epoch;
+        r.blkdev = '/dev/nbd0';
+        cb(null, { blkDev: r.blkdev });
+      },
+      destroyBlkdev: (call, cb) => {
+        let args = call.request;
+        assert.hasAllKeys(args, ['uuid']);
+        let r = self.replicas.find(r => r.uuid == args.uuid);
+        if (!r || !r.blkdev) {
+          let err = new Error('not found');
+          err.code = grpc.status.NOT_FOUND;
+          return cb(err);
+        }
+        delete r.blkdev;
+        cb(null, {});
+      },
       statReplicas: (_, cb) => {
         self.statCounter += STAT_DELTA;
         cb(null, {
@@ -97,6 +97,9 @@ class PoolOperator extends EventEmitter {
     };
     // sort the disks for easy string to string comparison
     pool.disks.sort();
+    if (msp.spec.encryption) {
+      pool.encryption = msp.spec.encryption;
+    }
 
     return pool;
   }
@@ -374,11 +377,27 @@ class PoolOperator extends EventEmitter {
   // This function does not throw and takes care of updating pool status if
   // the create fails.
   async _createPoolWithClient(client, pool) {
+    var encryptionKey = '';
+
+    if (pool.encryption) {
+      try {
+        encryptionKey = await this._getEncryptionKey(pool.encryption);
+      } catch (err) {
+        log.error(`Cannot create pool "${pool.name}": ${err}`);
+        await this._updateStatus(pool.name, {
+          state: 'PENDING',
+          reason: err.toString(),
+        });
+        return;
+      }
+    }
+
     log.debug(`Creating pool "${pool.name}" on node "${pool.node}"`);
     try {
       await client.CreatePool().sendMessage({
         name: pool.name,
         disks: pool.disks,
+        encryptionKey: encryptionKey,
       });
       log.info(`Created pool "${pool.name}" on node "${pool.node}"`);
     } catch (err) {
@@ -429,6 +448,33 @@ class PoolOperator extends EventEmitter {
     await this._updateStatus(pool.name, poolStatus);
   }
 
+  // Read the key of encrypted pool from the k8s secret referenced by the
+  // pool resource. The key is never stored in the pool resource itself, so
+  // it can be managed (and rotated) by a KMS which populates the secret.
+  // The key is binary, so it is passed to mayastor hex encoded.
+  async _getEncryptionKey(encryption) {
+    let ref = encryption.keySecret;
+    let res;
+
+    try {
+      res = await this.client.api.v1
+        .namespaces(ref.namespace)
+        .secrets(ref.name)
+        .get();
+    } catch (err) {
+      throw new Error(
+        `Failed to read encryption key from secret "${ref.namespace}/${ref.name}": ${err}`
+      );
+    }
+    let data = res.body.data || {};
+    if (!data.key) {
+      throw new Error(
+        `Secret "${ref.namespace}/${ref.name}" does not contain "key"`
+      );
+    }
+    return Buffer.from(data.key, 'base64').toString('hex');
+  }
+
   // Create a pool if we already have mayastor client handle.
   // This function does not throw and takes care of updating pool status if
   // the create fails.
@@ -19,16 +19,39 @@ const EGRESS_ENDPOINT = '127.0.0.1:12345';
 
 // k8s api client mock.
 //
-// Currently the only endpoint needed by pool operator is PUT on mayastorpool
-// status endpoint (the rest of the endpoints for mayastor pools is used
-// internally by the watcher). Each time the endpoint is called, an event is
-// recorded and can be later asynchronously retrieved.
+// The endpoints needed by pool operator are PUT on mayastorpool status
+// endpoint (the rest of the endpoints for mayastor pools is used internally
+// by the watcher) and GET on secrets with keys of encrypted pools. Each time
+// the status endpoint is called, an event is recorded and can be later
+// asynchronously retrieved.
 class FakeApiClient extends EventEmitter {
   constructor() {
     super();
     this.calls = [];
     this.waits = [];
+    this.secrets = {}; // secret data indexed by "namespace/name"
     var self = this;
+    this.api = {
+      v1: {
+        namespaces: function(ns) {
+          return {
+            secrets: function(name) {
+              return {
+                get: async function() {
+                  let data = self.secrets[`${ns}/${name}`];
+                  if (!data) {
+                    let err = new Error('secrets "' + name + '" not found');
+                    err.statusCode = 404;
+                    throw err;
+                  }
+                  return { body: { data } };
+                },
+              };
+            },
+          };
+        },
+      },
+    };
     this.apis = {
       'openebs.io': {
         v1alpha1: {
@@ -352,6 +375,79 @@ module.exports = function() {
         assert.lengthOf(plist, 1);
         assert.equal(plist[0].name, 'pool');
       });
+
+      it('should create an encrypted pool with the key from the secret', async () => {
+        srv = startMayastorServer();
+        oper = await MockedPoolOperator([]);
+        oper.client.secrets['mayastor/pool-key'] = {
+          key: Buffer.from('0123456789abcdef').toString('base64'),
+        };
+        let obj = createPoolCR('pool', 'node', ['/dev/sdb']);
+        obj.spec.encryption = {
+          keySecret: { name: 'pool-key', namespace: 'mayastor' },
+        };
+        oper.watcher.newObject(obj);
+
+        let { name, stat } = await oper.client.called();
+        assert.equal(name, 'pool');
+        assert.equal(stat.state, 'ONLINE');
+        assert.equal(stat.reason, '');
+
+        let plist = srv.get();
+        assert.lengthOf(plist, 1);
+        assert.equal(plist[0].name, 'pool');
+        assert.isTrue(plist[0].encrypted);
+        assert.equal(
+          srv.encryptionKeys.pool,
+          Buffer.from('0123456789abcdef').toString('hex')
+        );
+      });
+
+      it('should pass a binary key from the secret intact', async () => {
+        // not valid UTF-8 (0x80, 0xff) and the high bytes would be mangled
+        // by conversion to a string
+        let key = Buffer.from([
+          0xff, 0x80, 0x01, 0xc3, 0x28, 0xa0, 0xa1, 0xe2,
+          0x28, 0xa1, 0xf0, 0x90, 0x28, 0xbc, 0x7f, 0xfe,
+        ]);
+        srv = startMayastorServer();
+        oper = await MockedPoolOperator([]);
+        oper.client.secrets['mayastor/pool-key'] = {
+          key: key.toString('base64'),
+        };
+        let obj = createPoolCR('pool', 'node', ['/dev/sdb']);
+        obj.spec.encryption = {
+          keySecret: { name: 'pool-key', namespace: 'mayastor' },
+        };
+        oper.watcher.newObject(obj);
+
+        let { stat } = await oper.client.called();
+        assert.equal(stat.state, 'ONLINE');
+        assert.equal(
+          srv.encryptionKeys.pool,
+          'ff8001c328a0a1e228a1f09028bc7ffe'
+        );
+        assert.deepEqual(Buffer.from(srv.encryptionKeys.pool, 'hex'), key);
+      });
+
+      it('should not create an encrypted pool if the secret does not exist', async () => {
+        srv = startMayastorServer();
+        oper = await MockedPoolOperator([]);
+        let obj = createPoolCR('pool', 'node', ['/dev/sdb']);
+        obj.spec.encryption = {
+          keySecret: { name: 'pool-key', namespace: 'mayastor' },
+        };
+        oper.watcher.newObject(obj);
+
+        let { name, stat } = await oper.client.called();
+        assert.equal(name, 'pool');
+        assert.equal(stat.state, 'PENDING');
+        assert.match(
+          stat.reason,
+          /Failed to read encryption key from secret "mayastor\/pool-key"/
+        );
+        assert.lengthOf(srv.get(), 0);
+      });
     });
 
     describe('del event', () => {
@@ -0,0 +1,122 @@
+// Self-registration of mayastor CSI driver in k8s.
+//
+// Instead of relying on static yaml matching the capabilities of each
+// release, moac can create (or update) CSIDriver object of the plugin and
+// label the nodes which run mayastor when it starts. The spec of CSIDriver
+// object is immutable, so if it differs from what we need, the object is
+// deleted and created again.
+//
+// Labels are only ever added. A node which stops running mayastor keeps the
+// label until it is removed by the administrator, because we can't tell if
+// mayastor is gone for good or just restarting.
+
+'use strict';
+
+const log = require('./logger').Logger('registration');
+const { PLUGIN_NAME } = require('./common');
+
+// Spec of the CSIDriver object of mayastor
+const DRIVER_SPEC = {
+  // moac publishes nexus on a node in ControllerPublishVolume
+  attachRequired: true,
+  // pod info is needed for sub_path placeholders and operation history
+  podInfoOnMount: true,
+};
+
+// Label of nodes running mayastor (usable in node selectors and affinity)
+const NODE_LABEL = 'openebs.io/engine';
+const NODE_LABEL_VALUE = 'mayastor';
+
+// Return true if the spec of existing CSIDriver object matches what we need.
+function specMatches(spec) {
+  spec = spec || {};
+  return Object.keys(DRIVER_SPEC).every(
+    key => spec[key] === DRIVER_SPEC[key]
+  );
+}
+
+// Create CSIDriver object of mayastor if it does not exist or recreate it if
+// it has a different spec.
+async function registerDriver(client) {
+  let csidrivers = client.apis['storage.k8s.io'].v1beta1.csidrivers;
+  let body = {
+    apiVersion: 'storage.k8s.io/v1beta1',
+    kind: 'CSIDriver',
+    metadata: { name: PLUGIN_NAME },
+    spec: DRIVER_SPEC,
+  };
+  let existing = null;
+
+  try {
+    let res = await csidrivers(PLUGIN_NAME).get();
+    existing = res.body;
+  } catch (err) {
+    if (err.statusCode !== 404) throw err;
+  }
+  if (existing) {
+    if (specMatches(existing.spec)) {
+      log.debug(`CSIDriver ${PLUGIN_NAME} is up to date`);
+      return;
+    }
+    log.info(`Recreating CSIDriver ${PLUGIN_NAME} with a different spec`);
+    await csidrivers(PLUGIN_NAME).delete();
+  }
+  await csidrivers.post({ body });
+  log.info(`Registered CSIDriver ${PLUGIN_NAME}`);
+}
+
+// Label nodes running mayastor. It labels the nodes known to the node
+// operator and those which join the cluster later.
+class NodeLabeler {
+  constructor(client, nodeOperator) {
+    this.client = client;
+    this.nodes = nodeOperator;
+    this.labeled = {}; // names of nodes which we have labeled
+    this.onAdd = this._onAdd.bind(this);
+  }
+
+  // The node operator should be in ready state when calling this function.
+  async start() {
+    this.nodes.on('add', this.onAdd);
+    let nodes = this.nodes.get();
+    for (let i = 0; i < nodes.length; i++) {
+      await this.label(nodes[i].node);
+    }
+  }
+
+  stop() {
+    this.nodes.removeListener('add', this.onAdd);
+  }
+
+  _onAdd(ev) {
+    // errors are logged by label()
+    this.label(ev.node);
+  }
+
+  // Set mayastor label on the node. This method does not throw as there is
+  // nothing we can do except logging an error.
+  async label(name) {
+    if (this.labeled[name]) {
+      return;
+    }
+    let labels = {};
+    labels[NODE_LABEL] = NODE_LABEL_VALUE;
+    try {
+      await this.client.api.v1
+        .nodes(name)
+        .patch({ body: { metadata: { labels } } });
+      this.labeled[name] = true;
+      log.info(`Labeled node "${name}" with ${NODE_LABEL}=${NODE_LABEL_VALUE}`);
+    } catch (err) {
+      log.error(`Failed to label node "${name}": ${err}`);
+    }
+  }
+}
+
+module.exports = {
+  DRIVER_SPEC,
+  NODE_LABEL,
+  NODE_LABEL_VALUE,
+  registerDriver,
+  NodeLabeler,
+};
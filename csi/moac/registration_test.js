@@ -0,0 +1,144 @@
+// Unit tests for self-registration of the CSI driver
+
+'use strict';
+
+const assert = require('chai').assert;
+const sleep = require('sleep-promise');
+const { NodeOperatorMock } = require('./nodes');
+const { PLUGIN_NAME } = require('./common');
+const {
+  DRIVER_SPEC,
+  NODE_LABEL,
+  NODE_LABEL_VALUE,
+  registerDriver,
+  NodeLabeler,
+} = require('./registration');
+
+// k8s api client mock with CSIDriver objects and patch of nodes.
+class FakeApiClient {
+  constructor(driver) {
+    var self = this;
+    this.driver = driver || null; // existing CSIDriver object
+    this.calls = []; // names of called endpoints
+    this.patches = {}; // body of the last patch indexed by node name
+    this.failNodes = []; // nodes which fail to be patched
+
+    let csidrivers = function(name) {
+      assert.equal(name, PLUGIN_NAME);
+      return {
+        get: async function() {
+          self.calls.push('get');
+          if (!self.driver) {
+            let err = new Error(`csidrivers "${name}" not found`);
+            err.statusCode = 404;
+            throw err;
+          }
+          return { body: self.driver };
+        },
+        delete: async function() {
+          self.calls.push('delete');
+          self.driver = null;
+        },
+      };
+    };
+    csidrivers.post = async function(args) {
+      self.calls.push('post');
+      self.driver = args.body;
+    };
+    this.apis = {
+      'storage.k8s.io': { v1beta1: { csidrivers } },
+    };
+    this.api = {
+      v1: {
+        nodes: function(name) {
+          return {
+            patch: async function(args) {
+              if (self.failNodes.indexOf(name) >= 0) {
+                throw new Error('nodes "' + name + '" is forbidden');
+              }
+              self.patches[name] = args.body;
+            },
+          };
+        },
+      },
+    };
+  }
+}
+
+module.exports = function() {
+  describe('CSI driver', () => {
+    it('should create CSIDriver object if it does not exist', async () => {
+      let client = new FakeApiClient();
+      await registerDriver(client);
+      assert.deepEqual(client.calls, ['get', 'post']);
+      assert.equal(client.driver.kind, 'CSIDriver');
+      assert.equal(client.driver.metadata.name, PLUGIN_NAME);
+      assert.deepEqual(client.driver.spec, DRIVER_SPEC);
+    });
+
+    it('should leave alone CSIDriver object which is up to date', async () => {
+      let client = new FakeApiClient({
+        metadata: { name: PLUGIN_NAME },
+        spec: Object.assign(
+          { volumeLifecycleModes: ['Persistent'] },
+          DRIVER_SPEC
+        ),
+      });
+      await registerDriver(client);
+      assert.deepEqual(client.calls, ['get']);
+    });
+
+    it('should recreate CSIDriver object with different spec', async () => {
+      let client = new FakeApiClient({
+        metadata: { name: PLUGIN_NAME },
+        spec: { attachRequired: true, podInfoOnMount: false },
+      });
+      await registerDriver(client);
+      assert.deepEqual(client.calls, ['get', 'delete', 'post']);
+      assert.deepEqual(client.driver.spec, DRIVER_SPEC);
+    });
+  });
+
+  describe('node labels', () => {
+    let expected = { metadata: { labels: {} } };
+    expected.metadata.labels[NODE_LABEL] = NODE_LABEL_VALUE;
+
+    it('should label existing and new mayastor nodes', async () => {
+      let client = new FakeApiClient();
+      let nodes = new NodeOperatorMock([
+        { node: 'node1', endpoint: '127.0.0.1:123' },
+      ]);
+      let labeler = new NodeLabeler(client, nodes);
+
+      await labeler.start();
+      assert.deepEqual(client.patches, { node1: expected });
+
+      nodes.addNode('node2', '127.0.0.1:124');
+      await sleep(10);
+      assert.deepEqual(client.patches, { node1: expected, node2: expected });
+      labeler.stop();
+
+      nodes.addNode('node3', '127.0.0.1:125');
+      await sleep(10);
+      assert.notProperty(client.patches, 'node3');
+    });
+
+    it('should retry labeling of node when it is added again', async () => {
+      let client = new FakeApiClient();
+      client.failNodes = ['node1'];
+      let nodes = new NodeOperatorMock([
+        { node: 'node1', endpoint: '127.0.0.1:123' },
+      ]);
+      let labeler = new NodeLabeler(client, nodes);
+
+      await labeler.start();
+      assert.deepEqual(client.patches, {});
+
+      client.failNodes = [];
+      nodes.emit('add', { node: 'node1', endpoint: '127.0.0.1:124' });
+      await sleep(10);
+      assert.deepEqual(client.patches, { node1: expected });
+      labeler.stop();
+    });
+  });
+};
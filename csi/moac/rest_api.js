@@ -1,15 +1,62 @@
 // moac REST API server
 //
 // Auxilliary interface for all stuff which using k8s resources would be
-// awkward for. Currently we use it only for exposing stats to decouple
+// awkward for. Currently we use it for exposing stats to decouple
 // the way of storing and presenting the stats from the mayastor
-// implementation.
+// implementation, for listing resources which depend on a volume (so that
+// automation can remove them before deleting the volume) and for exporting
+// inventory of volumes for capacity planning and CMDB import. Nodes can push
+// their stats here instead of being polled for them.
 
 'use strict';
 
 const express = require('express');
+const { StatsStore } = require('./stats_store');
 const log = require('./logger').Logger('api');
 
+// Max size of (inflated) stats push from a node.
+const STATS_PUSH_LIMIT = '4mb';
+
+const CSV_COLUMNS = [
+  'uuid',
+  'size',
+  'pool',
+  'node',
+  'replicas',
+  'created',
+  'parameters',
+  'lastBackup',
+];
+
+// Quote the value if needed as described in RFC 4180. Text which a
+// spreadsheet would take for a formula is prefixed with a quote.
+function csvValue(val) {
+  if (val === null || val === undefined) return '';
+  if (typeof val === 'object') {
+    val = Object.keys(val)
+      .map(k => `${k}=${val[k]}`)
+      .join(';');
+  }
+  let text = val.toString();
+  if (typeof val !== 'number' && /^[=+\-@\t\r]/.test(text)) {
+    text = "'" + text;
+  }
+  if (/[",\r\n]/.test(text)) {
+    text = '"' + text.replace(/"/g, '""') + '"';
+  }
+  return text;
+}
+
+// Convert volume inventory records to CSV with a header line.
+function inventoryToCsv(records) {
+  return [CSV_COLUMNS.join(',')]
+    .concat(
+      records.map(r => CSV_COLUMNS.map(col => csvValue(r[col])).join(','))
+    )
+    .map(line => line + '\r\n')
+    .join('');
+}
+
 class ApiServer {
   constructor(volumeOperator) {
     var self = this;
@@ -23,6 +70,52 @@ class ApiServer {
           err => res.status(500).send(err.toString())
         );
     });
+    // The body may be compressed (Content-Encoding deflate or gzip), which
+    // the json parser takes care of.
+    //
+    // The push is not authenticated. It is accepted only from the address of
+    // mayastor on the node which the push is for, so that the stats of a node
+    // can't be forged from outside of the node network.
+    this.app.post(
+      '/stats/push',
+      express.json({ limit: STATS_PUSH_LIMIT }),
+      (req, res) => {
+        let err = StatsStore.validate(req.body);
+        if (err) {
+          res.status(400).send(`Invalid stats push: ${err}`);
+        } else if (
+          !self.volumes.isNodeAddress(req.body.node, req.socket.remoteAddress)
+        ) {
+          log.warn(
+            `Stats push for node "${req.body.node}" from ${req.socket.remoteAddress} refused`
+          );
+          res.status(403).send('Stats push not from the node');
+        } else if (self.volumes.pushStats(req.body)) {
+          res.status(204).end();
+        } else {
+          res.status(409).send('Unknown base of stats push');
+        }
+      }
+    );
+    this.app.get('/volumes/export', (req, res) => {
+      let format = req.query.format || 'json';
+      let records = self.volumes.inventory();
+      if (format == 'json') {
+        res.json(records);
+      } else if (format == 'csv') {
+        res.type('text/csv').send(inventoryToCsv(records));
+      } else {
+        res.status(400).send(`Unknown export format "${format}"`);
+      }
+    });
+    this.app.get('/volumes/:uuid/dependents', (req, res) => {
+      let deps = self.volumes.dependents(req.params.uuid);
+      if (deps) {
+        res.json(deps);
+      } else {
+        res.status(404).send(`Volume "${req.params.uuid}" does not exist`);
+      }
+    });
   }
 
   async start(port) {
@@ -41,4 +134,5 @@ class ApiServer {
 
 module.exports = {
   ApiServer,
+  csvValue,
 };
@@ -4,8 +4,9 @@
 
 const assert = require('chai').assert;
 const http = require('http');
+const zlib = require('zlib');
 const { VolumeOperatorMock } = require('./volumes');
-const { ApiServer } = require('./rest_api');
+const { ApiServer, csvValue } = require('./rest_api');
 
 const PORT = 12312;
 const STAT_COUNTER = 1000000; // feels good!
@@ -23,6 +24,8 @@ module.exports = function() {
           pool: 'pool',
           node: 'node',
           size: 10,
+          parameters: { tier: 'gold' },
+          dev: '/dev/nbd0',
         },
       ],
       STAT_COUNTER
@@ -59,4 +62,213 @@ module.exports = function() {
       })
       .on('error', done);
   });
+
+  it('should get volume dependents', done => {
+    http
+      .get(
+        'http://127.0.0.1:' + PORT + '/volumes/' + UUID + '/dependents',
+        resp => {
+          assert.equal(resp.statusCode, 200);
+
+          let data = '';
+          resp.on('data', chunk => {
+            data += chunk;
+          });
+          resp.on('end', () => {
+            let deps = JSON.parse(data);
+            assert.equal(deps.volume, UUID);
+            assert.lengthOf(deps.snapshots, 0);
+            assert.lengthOf(deps.clones, 0);
+            assert.deepEqual(deps.publications, [{ node: 'node' }]);
+            done();
+          });
+        }
+      )
+      .on('error', done);
+  });
+
+  it('should return 404 for dependents of unknown volume', done => {
+    http
+      .get(
+        'http://127.0.0.1:' + PORT + '/volumes/unknown/dependents',
+        resp => {
+          assert.equal(resp.statusCode, 404);
+          resp.resume();
+          done();
+        }
+      )
+      .on('error', done);
+  });
+
+  it('should export volume inventory in json', done => {
+    http
+      .get('http://127.0.0.1:' + PORT + '/volumes/export', resp => {
+        assert.equal(resp.statusCode, 200);
+
+        let data = '';
+        resp.on('data', chunk => {
+          data += chunk;
+        });
+        resp.on('end', () => {
+          let records = JSON.parse(data);
+          assert.deepEqual(records, [
+            {
+              uuid: UUID,
+              size: 10,
+              pool: 'pool',
+              node: 'node',
+              replicas: 1,
+              created: null,
+              parameters: { tier: 'gold' },
+              lastBackup: null,
+            },
+          ]);
+          done();
+        });
+      })
+      .on('error', done);
+  });
+
+  it('should export volume inventory in csv', done => {
+    http
+      .get(
+        'http://127.0.0.1:' + PORT + '/volumes/export?format=csv',
+        resp => {
+          assert.equal(resp.statusCode, 200);
+          assert.match(resp.headers['content-type'], /^text\/csv/);
+
+          let data = '';
+          resp.on('data', chunk => {
+            data += chunk;
+          });
+          resp.on('end', () => {
+            assert.equal(
+              data,
+              'uuid,size,pool,node,replicas,created,parameters,lastBackup\r\n' +
+                `${UUID},10,pool,node,1,,tier=gold,\r\n`
+            );
+            done();
+          });
+        }
+      )
+      .on('error', done);
+  });
+
+  it('should escape csv values which look like formulas', () => {
+    assert.equal(csvValue('=HYPERLINK("x")'), '"\'=HYPERLINK(""x"")"');
+    assert.equal(csvValue('+1'), "'+1");
+    assert.equal(csvValue('-1'), "'-1");
+    assert.equal(csvValue('@SUM(A1)'), "'@SUM(A1)");
+    assert.equal(csvValue({ '=cmd': 'x' }), "'=cmd=x");
+    assert.equal(csvValue('a=b'), 'a=b');
+    assert.equal(csvValue(-1), '-1');
+  });
+
+  it('should reject unknown export format', done => {
+    http
+      .get(
+        'http://127.0.0.1:' + PORT + '/volumes/export?format=xml',
+        resp => {
+          assert.equal(resp.statusCode, 400);
+          resp.resume();
+          done();
+        }
+      )
+      .on('error', done);
+  });
+  // Send compressed stats push and call back with status code of the reply.
+  function push(body, cb) {
+    let data = zlib.deflateSync(JSON.stringify(body));
+    let req = http.request(
+      {
+        host: '127.0.0.1',
+        port: PORT,
+        path: '/stats/push',
+        method: 'POST',
+        headers: {
+          'Content-Type': 'application/json',
+          'Content-Encoding': 'deflate',
+          'Content-Length': data.length,
+        },
+      },
+      resp => {
+        resp.resume();
+        cb(null, resp.statusCode);
+      }
+    );
+    req.on('error', cb);
+    req.end(data);
+  }
+
+  it('should accept compressed stats push', done => {
+    let body = {
+      node: 'node',
+      seq: 1,
+      base: null,
+      interval: 10,
+      replicas: [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          reset: true,
+          num_read_ops: 1,
+          num_write_ops: 2,
+          bytes_read: 512,
+          bytes_written: 1024,
+        },
+      ],
+      removed: [],
+    };
+    push(body, (err, status) => {
+      if (err) return done(err);
+      assert.equal(status, 204);
+      assert.deepEqual(
+        volumeOperator.pushes[volumeOperator.pushes.length - 1],
+        body
+      );
+      done();
+    });
+  });
+
+  it('should reject stats push with unknown base', done => {
+    let body = {
+      node: 'node',
+      seq: 3,
+      base: 2,
+      interval: 10,
+      replicas: [],
+      removed: [],
+    };
+    push(body, (err, status) => {
+      if (err) return done(err);
+      assert.equal(status, 409);
+      done();
+    });
+  });
+
+  it('should reject stats push from other than node address', done => {
+    let body = {
+      node: 'node',
+      seq: 1,
+      base: null,
+      interval: 10,
+      replicas: [],
+      removed: [],
+    };
+    volumeOperator.nodeAddress = '10.0.0.1';
+    push(body, (err, status) => {
+      volumeOperator.nodeAddress = '127.0.0.1';
+      if (err) return done(err);
+      assert.equal(status, 403);
+      done();
+    });
+  });
+
+  it('should reject invalid stats push', done => {
+    push({ node: 'node', seq: 1, base: null }, (err, status) => {
+      if (err) return done(err);
+      assert.equal(status, 400);
+      done();
+    });
+  });
 };
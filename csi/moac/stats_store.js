@@ -0,0 +1,102 @@
+// Store of replica stats pushed by the nodes
+//
+// Instead of being polled, mayastor agent can push the stats of replicas on
+// its node periodically. The push contains only changes since the push it
+// is based on. Counters of replicas are deltas to be added to the stored
+// values unless the replica is marked by reset flag, in which case they are
+// absolute values. A push without a base is a full snapshot replacing all
+// stats of the node. Stats of a node are considered stale if the node has
+// not pushed them for a few of its intervals, and then the volume operator
+// goes back to polling the node.
+
+'use strict';
+
+const log = require('./logger').Logger('stats');
+
+// How many push intervals may pass before the stats of the node are stale.
+const STALE_INTERVALS = 3;
+
+const COUNTERS = [
+  'num_read_ops',
+  'num_write_ops',
+  'bytes_read',
+  'bytes_written',
+];
+
+class StatsStore {
+  constructor() {
+    // stats of replicas, sequence number of the last push and time when it
+    // is expected to be followed by the next one for each node
+    this.nodes = {};
+  }
+
+  // Check that the push has all required fields with the right types.
+  static validate(push) {
+    if (!push || typeof push != 'object') return 'push must be an object';
+    if (typeof push.node != 'string' || !push.node) {
+      return 'missing node';
+    }
+    if (!Number.isInteger(push.seq)) return 'missing seq';
+    if (push.base !== null && !Number.isInteger(push.base)) {
+      return 'base must be integer or null';
+    }
+    if (!Array.isArray(push.replicas)) return 'missing replicas';
+    for (let r of push.replicas) {
+      if (!r || typeof r.uuid != 'string') return 'replica without uuid';
+      for (let c of COUNTERS) {
+        if (!Number.isInteger(r[c]) || r[c] < 0) {
+          return `invalid ${c} of replica ${r.uuid}`;
+        }
+      }
+    }
+    if (push.removed && !Array.isArray(push.removed)) {
+      return 'removed must be an array';
+    }
+  }
+
+  // Apply the push to the stored stats. Return false if the push is based
+  // on a push which we don't have, so the node must send a full snapshot.
+  apply(push, now) {
+    now = now || Date.now();
+    let entry = this.nodes[push.node];
+
+    if (push.base === null) {
+      entry = { replicas: {} };
+    } else if (!entry || entry.seq !== push.base) {
+      log.debug(
+        `Stats push ${push.seq} from node "${push.node}" is based on ` +
+          `unknown push ${push.base}`
+      );
+      if (entry) delete this.nodes[push.node];
+      return false;
+    }
+    for (let r of push.replicas) {
+      let old = entry.replicas[r.uuid];
+      let stats = { pool: r.pool };
+      for (let c of COUNTERS) {
+        stats[c] = r.reset || !old ? r[c] : old[c] + r[c];
+      }
+      entry.replicas[r.uuid] = stats;
+    }
+    for (let uuid of push.removed || []) {
+      delete entry.replicas[uuid];
+    }
+    entry.seq = push.seq;
+    entry.expires = now + STALE_INTERVALS * (push.interval || 10) * 1000;
+    this.nodes[push.node] = entry;
+    return true;
+  }
+
+  // Return stats of replicas on the node keyed by replica uuid or undefined
+  // if the node has not pushed them recently.
+  get(nodeName, now) {
+    now = now || Date.now();
+    let entry = this.nodes[nodeName];
+    if (!entry || entry.expires < now) return;
+    return entry.replicas;
+  }
+}
+
+module.exports = {
+  StatsStore,
+};
@@ -0,0 +1,118 @@
+// Unit tests for the store of pushed replica stats
+
+'use strict';
+
+const assert = require('chai').assert;
+const { StatsStore } = require('./stats_store');
+
+const UUID1 = '02de3df9-ce18-4164-89e1-b1cbf7a88e56';
+const UUID2 = 'ba5e39e9-0c0e-4973-8a3a-0dccada09cbb';
+
+function replica(uuid, count, reset) {
+  let r = {
+    uuid: uuid,
+    pool: 'pool',
+    num_read_ops: count,
+    num_write_ops: count,
+    bytes_read: count * 512,
+    bytes_written: count * 512,
+  };
+  if (reset) r.reset = true;
+  return r;
+}
+
+function push(seq, base, replicas, removed) {
+  return {
+    node: 'node',
+    seq: seq,
+    base: base,
+    interval: 10,
+    replicas: replicas,
+    removed: removed || [],
+  };
+}
+
+module.exports = function() {
+  var store;
+
+  beforeEach(() => {
+    store = new StatsStore();
+  });
+
+  it('should store full snapshot', () => {
+    assert.isTrue(store.apply(push(1, null, [replica(UUID1, 1, true)]), 1000));
+    assert.deepEqual(store.get('node', 1000), {
+      [UUID1]: {
+        pool: 'pool',
+        num_read_ops: 1,
+        num_write_ops: 1,
+        bytes_read: 512,
+        bytes_written: 512,
+      },
+    });
+    assert.isUndefined(store.get('other-node', 1000));
+  });
+
+  it('should add deltas and replace reset counters', () => {
+    store.apply(
+      push(1, null, [replica(UUID1, 1, true), replica(UUID2, 5, true)]),
+      1000
+    );
+    assert.isTrue(
+      store.apply(
+        push(2, 1, [replica(UUID1, 2), replica(UUID2, 3, true)]),
+        2000
+      )
+    );
+    let stats = store.get('node', 2000);
+    assert.equal(stats[UUID1].num_read_ops, 3);
+    assert.equal(stats[UUID1].bytes_written, 3 * 512);
+    assert.equal(stats[UUID2].num_write_ops, 3);
+  });
+
+  it('should remove replicas', () => {
+    store.apply(
+      push(1, null, [replica(UUID1, 1, true), replica(UUID2, 1, true)]),
+      1000
+    );
+    assert.isTrue(store.apply(push(2, 1, [], [UUID2]), 2000));
+    assert.hasAllKeys(store.get('node', 2000), [UUID1]);
+  });
+
+  it('should replace all stats of the node by full snapshot', () => {
+    store.apply(
+      push(1, null, [replica(UUID1, 1, true), replica(UUID2, 1, true)]),
+      1000
+    );
+    store.apply(push(5, null, [replica(UUID2, 7, true)]), 2000);
+    let stats = store.get('node', 2000);
+    assert.hasAllKeys(stats, [UUID2]);
+    assert.equal(stats[UUID2].num_read_ops, 7);
+  });
+
+  it('should refuse push with unknown base and forget the node', () => {
+    store.apply(push(1, null, [replica(UUID1, 1, true)]), 1000);
+    assert.isFalse(store.apply(push(3, 2, [replica(UUID1, 1)]), 2000));
+    assert.isUndefined(store.get('node', 2000));
+    // following delta is refused too until the node sends everything
+    assert.isFalse(store.apply(push(4, 3, [replica(UUID1, 1)]), 2000));
+    assert.isTrue(store.apply(push(5, null, [replica(UUID1, 4, true)]), 2000));
+    assert.equal(store.get('node', 2000)[UUID1].num_read_ops, 4);
+  });
+
+  it('should consider stats stale after three intervals', () => {
+    store.apply(push(1, null, [replica(UUID1, 1, true)]), 1000);
+    assert.isDefined(store.get('node', 1000 + 30000));
+    assert.isUndefined(store.get('node', 1000 + 30001));
+  });
+
+  it('should validate push', () => {
+    assert.isUndefined(StatsStore.validate(push(1, null, [replica(UUID1, 1)])));
+    assert.isString(StatsStore.validate(null));
+    assert.isString(StatsStore.validate(push(1, 'x', [])));
+    assert.isString(StatsStore.validate(push(1, null, [{ uuid: UUID1 }])));
+    let r = replica(UUID1, 1);
+    r.num_read_ops = -1;
+    assert.isString(StatsStore.validate(push(1, null, [r])));
+  });
+};
@@ -6,8 +6,12 @@ const watcherTest = require('./watcher_test.js');
 const nodesTest = require('./nodes_test.js');
 const poolsTest = require('./pools_test.js');
 const volumesTest = require('./volumes_test.js');
+const volumeMirrorTest = require('./volume_mirror_test.js');
 const csiTest = require('./csi_test.js');
 const restApiServer = require('./rest_api_test.js');
+const statsStoreTest = require('./stats_store_test.js');
+const journalTest = require('./journal_test.js');
+const registrationTest = require('./registration_test.js');
 
 logger.setLevel('debug');
 
@@ -16,6 +20,10 @@ describe('moac', function() {
   describe('node operator', nodesTest);
   describe('pool operator', poolsTest);
   describe('volume operator', volumesTest);
+  describe('volume mirror', volumeMirrorTest);
   describe('CSI controller', csiTest);
   describe('REST API server', restApiServer);
+  describe('stats store', statsStoreTest);
+  describe('journal', journalTest);
+  describe('registration', registrationTest);
 });
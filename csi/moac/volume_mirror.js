@@ -0,0 +1,163 @@
+// Mirror of volumes from the volume cache to mayastorvolume custom resources.
+//
+// The resources are status-only. They are created, updated and deleted by us
+// and changing them has no effect on the volumes. They exist for users who
+// want to see the volumes using kubectl or build tools on top of k8s API.
+// Volume operator does not tell us about changes in the cache, so we
+// periodically compare the resources with the cache and fix the differences.
+// The first sync happens at start, so resources left behind by a previous
+// instance of moac are fixed up right after a restart.
+
+'use strict';
+
+const fs = require('fs');
+const yaml = require('js-yaml');
+const log = require('./logger').Logger('volume-mirror');
+
+const crdVolume = yaml.safeLoad(
+  fs.readFileSync(__dirname + '/crds/mayastorvolume.yaml', 'utf8')
+);
+
+// Create status of volume resource from volume in the cache.
+function createVolumeStatus(vol, deps) {
+  return {
+    node: vol.node,
+    pool: vol.pool,
+    size: vol.size,
+    published: deps.publications.length > 0,
+  };
+}
+
+// Return true if the status of k8s resource is the same as the new one.
+function isStatusEqual(oldStatus, newStatus) {
+  if (!oldStatus) return false;
+  return Object.keys(newStatus).every(key => oldStatus[key] == newStatus[key]);
+}
+
+class VolumeMirror {
+  constructor(volumeOperator) {
+    this.volumes = volumeOperator;
+    this.client = null; // k8s client
+    this.syncTimer = null;
+    this.running = false;
+  }
+
+  // Create volume CRD if it doesn't exist and augment client object so that
+  // CRD can be manipulated as any other standard k8s api object.
+  async init(client) {
+    log.info('Initializing volume mirror');
+
+    try {
+      await client.apis[
+        'apiextensions.k8s.io'
+      ].v1beta1.customresourcedefinitions.post({ body: crdVolume });
+      log.info('Created CRD ' + crdVolume.spec.names.kind);
+    } catch (err) {
+      // API returns a 409 Conflict if CRD already exists.
+      if (err.statusCode !== 409) throw err;
+    }
+    client.addCustomResourceDefinition(crdVolume);
+    this.client = client;
+  }
+
+  // Do the initial sync and schedule the periodic ones.
+  async start() {
+    var self = this;
+
+    this.running = true;
+    async function syncLoop() {
+      self.syncTimer = null;
+      await self.sync();
+      if (self.running) {
+        self.syncTimer = setTimeout(syncLoop, exports.syncInterval * 1000);
+      }
+    }
+    await syncLoop();
+  }
+
+  async stop() {
+    this.running = false;
+    if (this.syncTimer) {
+      clearTimeout(this.syncTimer);
+      this.syncTimer = null;
+    }
+  }
+
+  // Make the volume resources reflect the content of the volume cache.
+  //
+  // NOTE: This method does not throw as there is nothing we can do if it
+  // fails except logging an error message and trying again next time.
+  async sync() {
+    var api = this.client.apis['openebs.io'].v1alpha1;
+    var items;
+
+    try {
+      let res = await api.mayastorvolumes.get();
+      items = res.body.items;
+    } catch (err) {
+      log.error('Failed to list volume resources: ' + err);
+      return;
+    }
+
+    var volumes = {};
+    this.volumes.get().forEach(v => (volumes[v.uuid] = v));
+
+    // delete resources of volumes which no longer exist
+    for (let i = 0; i < items.length; i++) {
+      let name = items[i].metadata.name;
+      if (volumes[name]) continue;
+
+      try {
+        await api.mayastorvolumes(name).delete();
+        log.info(`Deleted volume resource "${name}"`);
+      } catch (err) {
+        if (err.statusCode !== 404) {
+          log.error(`Failed to delete volume resource "${name}": ${err}`);
+        }
+      }
+    }
+
+    // create missing resources and update status of the existing ones
+    for (let uuid in volumes) {
+      let status = createVolumeStatus(
+        volumes[uuid],
+        this.volumes.dependents(uuid)
+      );
+      let obj = items.find(ent => ent.metadata.name == uuid);
+
+      if (!obj) {
+        try {
+          let res = await api.mayastorvolumes.post({
+            body: {
+              apiVersion: 'openebs.io/v1alpha1',
+              kind: 'MayastorVolume',
+              metadata: { name: uuid },
+            },
+          });
+          obj = res.body;
+          log.info(`Created volume resource "${uuid}"`);
+        } catch (err) {
+          log.error(`Failed to create volume resource "${uuid}": ${err}`);
+          continue;
+        }
+      } else if (isStatusEqual(obj.status, status)) {
+        continue;
+      }
+
+      // status is ignored by create, it must be always set separately
+      obj.status = status;
+      try {
+        await api.mayastorvolumes(uuid).status.put({ body: obj });
+      } catch (err) {
+        log.error(`Failed to update volume resource "${uuid}": ${err}`);
+      }
+    }
+  }
+}
+
+var exports = {
+  VolumeMirror,
+  syncInterval: 30, // secs
+};
+
+module.exports = exports;
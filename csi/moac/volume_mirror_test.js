@@ -0,0 +1,128 @@
+// Unit tests for the volume mirror
+//
+// We don't test the init method which depends on k8s api client. For k8s
+// api client we provide a fake object which keeps the resources in memory.
+
+'use strict';
+
+const assert = require('chai').assert;
+const { VolumeOperatorMock } = require('./volumes');
+const { VolumeMirror } = require('./volume_mirror');
+
+const UUID1 = 'ba5e39e9-0c0e-4973-8a3a-0dccada09cb1';
+const UUID2 = 'ba5e39e9-0c0e-4973-8a3a-0dccada09cb2';
+
+// k8s api client mock with just the endpoints used by volume mirror.
+class FakeApiClient {
+  constructor(objs) {
+    var self = this;
+    this.objs = {};
+    (objs || []).forEach(obj => (self.objs[obj.metadata.name] = obj));
+
+    let mayastorvolumes = function(name) {
+      return {
+        delete: async function() {
+          if (!self.objs[name]) {
+            let err = new Error('Not found');
+            err.statusCode = 404;
+            throw err;
+          }
+          delete self.objs[name];
+        },
+        status: {
+          put: async function(payload) {
+            assert.equal(payload.body.metadata.name, name);
+            assert(self.objs[name], 'Updated object does not exist');
+            self.objs[name].status = payload.body.status;
+          },
+        },
+      };
+    };
+    mayastorvolumes.get = async function() {
+      return {
+        body: {
+          items: Object.values(self.objs).map(obj =>
+            JSON.parse(JSON.stringify(obj))
+          ),
+        },
+      };
+    };
+    mayastorvolumes.post = async function(payload) {
+      let obj = JSON.parse(JSON.stringify(payload.body));
+      assert.isUndefined(obj.status);
+      self.objs[obj.metadata.name] = obj;
+      return { body: JSON.parse(JSON.stringify(obj)) };
+    };
+    this.apis = {
+      'openebs.io': {
+        v1alpha1: {
+          mayastorvolumes: mayastorvolumes,
+        },
+      },
+    };
+  }
+}
+
+function createVolumeCR(uuid, status) {
+  return {
+    apiVersion: 'openebs.io/v1alpha1',
+    kind: 'MayastorVolume',
+    metadata: { name: uuid },
+    status: status,
+  };
+}
+
+module.exports = function() {
+  it('should create resources for volumes', async () => {
+    let volumes = new VolumeOperatorMock([
+      { uuid: UUID1, pool: 'pool', node: 'node', size: 10 },
+      { uuid: UUID2, pool: 'pool', node: 'node', size: 20, dev: '/dev/nbd0' },
+    ]);
+    let mirror = new VolumeMirror(volumes);
+    mirror.client = new FakeApiClient();
+
+    await mirror.sync();
+
+    let objs = mirror.client.objs;
+    assert.hasAllKeys(objs, [UUID1, UUID2]);
+    assert.deepEqual(objs[UUID1].status, {
+      node: 'node',
+      pool: 'pool',
+      size: 10,
+      published: false,
+    });
+    assert.deepEqual(objs[UUID2].status, {
+      node: 'node',
+      pool: 'pool',
+      size: 20,
+      published: true,
+    });
+  });
+
+  it('should update status and delete stale resources', async () => {
+    let volumes = new VolumeOperatorMock([
+      { uuid: UUID1, pool: 'pool', node: 'node', size: 10, dev: '/dev/nbd0' },
+    ]);
+    let mirror = new VolumeMirror(volumes);
+    mirror.client = new FakeApiClient([
+      createVolumeCR(UUID1, {
+        node: 'node',
+        pool: 'pool',
+        size: 10,
+        published: false,
+      }),
+      createVolumeCR(UUID2, {
+        node: 'node',
+        pool: 'pool',
+        size: 20,
+        published: false,
+      }),
+    ]);
+
+    await mirror.sync();
+
+    let objs = mirror.client.objs;
+    assert.hasAllKeys(objs, [UUID1]);
+    assert.isTrue(objs[UUID1].status.published);
+  });
+};
@@ -8,6 +8,7 @@ const EventEmitter = require('events');
 const grpc = require('grpc-uds');
 const grpc_promise = require('grpc-promise');
 const { mayastor, GrpcError } = require('./common');
+const { StatsStore } = require('./stats_store');
 const log = require('./logger').Logger('volumes');
 
 // Create k8s volume object as returned by CSI list volumes method.
@@ -24,6 +25,36 @@ function createK8sVolumeObject(obj) {
   };
 }
 
+// Create object describing resources which depend on the volume and must be
+// dealt with before the volume can be destroyed. Snapshots and clones are not
+// supported yet, so the lists are always empty, but they are part of the
+// format so that the consumers don't have to change when they are added.
+function createDependentsObject(vol, published) {
+  return {
+    volume: vol.uuid,
+    snapshots: [],
+    clones: [],
+    publications: published ? [{ node: vol.node }] : [],
+  };
+}
+
+// Create inventory record of the volume used for exporting the catalog of
+// volumes. Things which we don't know (i.e. creation time of volumes created
+// by previous instance of moac) are null. Backups are not supported yet, so
+// the last backup time is always null.
+function createInventoryRecord(vol) {
+  return {
+    uuid: vol.uuid,
+    size: vol.size,
+    pool: vol.pool,
+    node: vol.node,
+    replicas: 1,
+    created: vol.created || null,
+    parameters: vol.parameters || {},
+    lastBackup: null,
+  };
+}
+
 // Volume cache with create, destroy and list methods.
 class VolumeOperator {
   constructor(nodeOperator) {
@@ -35,6 +66,8 @@ class VolumeOperator {
     this.pendingSync = {};
     // timers for sync retries in case of failures
     this.retrySync = {};
+    // replica stats pushed by the nodes
+    this.pushedStats = new StatsStore();
   }
 
   // TODO: We use Mayastor for v0.1 but later moac will have to use ingress
@@ -128,16 +161,34 @@ class VolumeOperator {
     }
     for (let i = 0; i < res.replicas.length; i++) {
       let r = res.replicas[i];
+      if (r.quarantined) {
+        // lvol which has not been created by mayastor (until adopted)
+        log.warn(
+          `Ignoring quarantined lvol ${r.pool}/${r.uuid} on node "${nodeName}"`
+        );
+        continue;
+      }
       if (this.volumes[r.uuid]) {
         log.debug(`Adding volume ${r.uuid} to the cache`);
       } else {
         log.debug(`Updating volume ${r.uuid} in the cache`);
       }
+      let old = this.volumes[r.uuid] || {};
       this.volumes[r.uuid] = {
         uuid: r.uuid,
         pool: r.pool,
         node: nodeName,
         size: r.size,
+        compressed: r.compressed,
+        // the node exports the replica over nbd while it is published
+        published: !!r.blkdev,
+        // the node remembers the epoch, so that we continue from it
+        fencingEpoch: Math.max(
+          parseInt(r.fencingEpoch) || 0,
+          old.fencingEpoch || 0
+        ),
+        created: old.created,
+        parameters: old.parameters,
       };
     }
     return true;
@@ -176,6 +227,12 @@ class VolumeOperator {
     }
   }
 
+  // Update volumes of the node in the cache by what the node reports (i.e.
+  // whether they are published). Return false if the node can't be asked.
+  async refresh(nodeName) {
+    return this._syncNode(nodeName);
+  }
+
   // Destroy volume on storage node and remove it from the cache.
   // Throws grpc error if error.
   async destroy(nodeName, uuid) {
@@ -204,13 +261,16 @@ class VolumeOperator {
     delete this.volumes[uuid];
   }
 
-  // Create volume and add it to the cache.
+  // Create volume and add it to the cache. Parameters are the storage class
+  // parameters, which are remembered with the volume. Parameter
+  // "compression" with value "true" creates volume with compressed data.
   // Throws a string (error message) if error.
-  async create(nodeName, poolName, uuid, size) {
+  async create(nodeName, poolName, uuid, size, parameters) {
     let client = this._getNodeClient(nodeName);
     if (!client) {
       throw `Failed to obtain grpc client for node "${nodeName}"`;
     }
+    let compress = !!parameters && parameters.compression === 'true';
 
     try {
       await client.createReplica().sendMessage({
@@ -218,6 +278,7 @@ class VolumeOperator {
         pool: poolName,
         size: size,
         thin: false,
+        compress: compress,
       });
     } catch (err) {
       throw new GrpcError(
@@ -234,6 +295,10 @@ class VolumeOperator {
       pool: poolName,
       node: nodeName,
       size: size,
+      compressed: compress,
+      published: false,
+      created: new Date().toISOString(),
+      parameters: parameters || {},
     };
   }
 
@@ -271,12 +336,64 @@ class VolumeOperator {
     return Object.values(this.volumes).map(createK8sVolumeObject);
   }
 
+  // Return resources depending on the volume or undefined if the volume
+  // does not exist.
+  dependents(uuid) {
+    let vol = this.volumes[uuid];
+    if (!vol) return;
+    return createDependentsObject(vol, vol.published);
+  }
+
+  // Return inventory records of all volumes.
+  inventory() {
+    return Object.values(this.volumes).map(createInventoryRecord);
+  }
+
+  // Apply stats pushed by a node. Return false if the node must push all
+  // of them.
+  pushStats(push) {
+    return this.pushedStats.apply(push);
+  }
+
+  // Return true if the address is the one of mayastor on the given node.
+  isNodeAddress(nodeName, addr) {
+    let node = this.nodes.get(nodeName);
+    if (!node || !addr) return false;
+    let host = node.endpoint
+      .replace(/:\d+$/, '')
+      .replace(/^\[(.*)\]$/, '$1');
+    return addr.replace(/^::ffff:/, '') === host;
+  }
+
   async getStats() {
     var self = this;
     var vols = [];
     var nodes = self.nodes.get();
 
     for (let i in nodes) {
+      let replicas = self.pushedStats.get(nodes[i].node);
+
+      if (replicas) {
+        vols = vols.concat(
+          Object.keys(replicas)
+            .filter(uuid => !!self.volumes[uuid])
+            .map(uuid => {
+              let r = replicas[uuid];
+              return {
+                volume: uuid,
+                pool: r.pool,
+                stats: {
+                  num_read_ops: r.num_read_ops,
+                  num_write_ops: r.num_write_ops,
+                  bytes_read: r.bytes_read,
+                  bytes_written: r.bytes_written,
+                },
+              };
+            })
+        );
+        continue;
+      }
+
       let client = self._createClient(nodes[i]);
       let res;
 
@@ -316,21 +433,38 @@ class VolumeOperator {
     return vols;
   }
 
+  // Create block device for the volume on the node. The device is exported
+  // with the next fencing epoch of the volume and the node refuses older
+  // epochs from then on (see fencingEpoch of the volume).
   async createBlkdev(nodeName, uuid) {
     let client = this._getNodeClient(nodeName);
     if (!client) {
       throw `Failed to obtain grpc client handle for node  "${nodeName}"`;
     }
+    let vol = this.volumes[uuid];
+    let epoch = ((vol && vol.fencingEpoch) || 0) + 1;
     try {
       await client.createBlkdev().sendMessage({
-        replica: uuid,
+        uuid: uuid,
+        fencingEpoch: epoch,
       });
     } catch (err) {
+      if (err.code === grpc.status.ALREADY_EXISTS && vol) {
+        // the node has taken the epoch before it found the device
+        vol.fencingEpoch = epoch;
+      }
       throw new GrpcError(
-        grpc.status.INTERNAL,
+        err.code === grpc.status.ALREADY_EXISTS ||
+        err.code === grpc.status.FAILED_PRECONDITION
+          ? err.code
+          : grpc.status.INTERNAL,
         `Failed to create blkdev for volume ${uuid}: ` + err
       );
     }
+    if (vol) {
+      vol.published = true;
+      vol.fencingEpoch = epoch;
+    }
   }
 
   async destroyBlkdev(nodeName, uuid) {
@@ -340,7 +474,7 @@ class VolumeOperator {
     }
     try {
       await client.destroyBlkdev().sendMessage({
-        replica: uuid,
+        uuid: uuid,
       });
     } catch (err) {
       throw new GrpcError(
@@ -348,6 +482,9 @@ class VolumeOperator {
         `Failed to destroy blkdev for volume ${uuid}: ` + err
       );
     }
+    if (this.volumes[uuid]) {
+      this.volumes[uuid].published = false;
+    }
   }
 }
 
@@ -360,6 +497,8 @@ class VolumeOperatorMock extends EventEmitter {
     this.volumes = volumes || [];
     this.errors = [];
     this.stat = stat || 0;
+    this.pushes = [];
+    this.nodeAddress = '127.0.0.1';
   }
 
   // Get volume with given name
@@ -376,7 +515,21 @@ class VolumeOperatorMock extends EventEmitter {
     return this.volumes.map(createK8sVolumeObject);
   }
 
-  async create(nodeName, poolName, uuid, size) {
+  dependents(uuid) {
+    let vol = this.get(uuid);
+    if (!vol) return;
+    return createDependentsObject(vol, !!vol.dev);
+  }
+
+  async refresh(nodeName) {
+    return true;
+  }
+
+  inventory() {
+    return this.volumes.map(createInventoryRecord);
+  }
+
+  async create(nodeName, poolName, uuid, size, parameters) {
     let err = this.errors.shift();
     if (err) {
       throw err;
@@ -386,6 +539,7 @@ class VolumeOperatorMock extends EventEmitter {
       pool: poolName,
       node: nodeName,
       size: size,
+      parameters: parameters,
       dev: null, // a field only present in mock for testing (un)publish
     };
     let idx = this.volumes.findIndex(v => v.uuid == uuid);
@@ -420,11 +574,26 @@ class VolumeOperatorMock extends EventEmitter {
     });
   }
 
+  // Record the push and accept it if it is based on the last one.
+  pushStats(push) {
+    let last = this.pushes[this.pushes.length - 1];
+    if (push.base !== null && (!last || last.seq !== push.base)) {
+      return false;
+    }
+    this.pushes.push(push);
+    return true;
+  }
+
+  isNodeAddress(nodeName, addr) {
+    return addr.replace(/^::ffff:/, '') === this.nodeAddress;
+  }
+
   async createBlkdev(noneName, uuid) {
     let vol = this.volumes.find(v => v.uuid == uuid);
     assert(vol);
     assert(!vol.dev);
     vol.dev = '/dev/nbd0';
+    vol.fencingEpoch = (vol.fencingEpoch || 0) + 1;
   }
 
   async destroyBlkdev(noneName, uuid) {
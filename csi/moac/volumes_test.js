@@ -6,6 +6,8 @@ const assert = require('chai').assert;
 const EventEmitter = require('events');
 const grpc = require('grpc-uds');
 const sleep = require('sleep-promise');
+const { CsiServer } = require('./csi');
+const { Journal } = require('./journal');
 const { MayastorServer, STAT_DELTA } = require('./mayastor_mock');
 const { NodeOperatorMock } = require('./nodes');
 const volumesMod = require('./volumes');
@@ -15,6 +17,7 @@ const EGRESS_ENDPOINT = '127.0.0.1:1235';
 const VolumeOperator = volumesMod.VolumeOperator;
 
 const UUID = 'ba5e39e9-0c0e-4973-8a3a-0dccada09cbb';
+const UUID2 = 'ba5e39e9-0c0e-4973-8a3a-0dccada09cbc';
 
 function startMayastorServer(pools, replicas) {
   return new MayastorServer(EGRESS_ENDPOINT, pools, replicas).start();
@@ -82,6 +85,65 @@ module.exports = function() {
     );
   });
 
+  it('should list created volume in the inventory', async () => {
+    mayastorSrv = startMayastorServer([
+      {
+        name: 'pool',
+        disks: ['/dev/sda'],
+        state: 0,
+        capacity: 100,
+        used: 50,
+      },
+    ]);
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.create('node', 'pool', UUID, 10, { tier: 'gold' });
+
+    let records = volumeOperator.inventory();
+    assert.lengthOf(records, 1);
+    assert.equal(records[0].uuid, UUID);
+    assert.equal(records[0].size, 10);
+    assert.equal(records[0].pool, 'pool');
+    assert.equal(records[0].node, 'node');
+    assert.equal(records[0].replicas, 1);
+    assert.isString(records[0].created);
+    assert.deepEqual(records[0].parameters, { tier: 'gold' });
+    assert.isNull(records[0].lastBackup);
+  });
+
+  it('should create compressed volume', async () => {
+    mayastorSrv = startMayastorServer([
+      {
+        name: 'pool',
+        disks: ['/dev/sda'],
+        state: 0,
+        capacity: 100,
+        used: 50,
+      },
+    ]);
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.create('node', 'pool', UUID, 10, {
+      compression: 'true',
+    });
+
+    let replicas = mayastorSrv.getReplicas();
+    assert.lengthOf(replicas, 1);
+    assert.isTrue(replicas[0].compressed);
+    assert.isTrue(replicas[0].thin);
+    assert.isTrue(volumeOperator.get(UUID).compressed);
+  });
+
   it('should not create volume if grpc fails', async () => {
     let nodeOperator = new NodeOperatorMock([
       {
@@ -194,6 +256,90 @@ module.exports = function() {
     );
   });
 
+  it('should use stats pushed by the node instead of polling it', async () => {
+    mayastorSrv = startMayastorServer(
+      [
+        {
+          name: 'pool',
+          disks: ['/dev/sda'],
+          state: 0,
+          capacity: 100,
+          used: 50,
+        },
+      ],
+      [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+        },
+      ]
+    );
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    // the volume is known now, make the node unreachable for polling
+    nodeOperator.nodes[0].endpoint = '127.0.0.1:12358';
+
+    let accepted = volumeOperator.pushStats({
+      node: 'node',
+      seq: 1,
+      base: null,
+      interval: 10,
+      replicas: [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          reset: true,
+          num_read_ops: 10,
+          num_write_ops: 20,
+          bytes_read: 512,
+          bytes_written: 1024,
+        },
+      ],
+      removed: [],
+    });
+    assert.isTrue(accepted);
+    accepted = volumeOperator.pushStats({
+      node: 'node',
+      seq: 2,
+      base: 1,
+      interval: 10,
+      replicas: [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          num_read_ops: 1,
+          num_write_ops: 0,
+          bytes_read: 512,
+          bytes_written: 0,
+        },
+      ],
+      removed: [],
+    });
+    assert.isTrue(accepted);
+
+    let stats = await volumeOperator.getStats();
+    assert.deepEqual(stats, [
+      {
+        volume: UUID,
+        pool: 'pool',
+        stats: {
+          num_read_ops: 11,
+          num_write_ops: 20,
+          bytes_read: 1024,
+          bytes_written: 1024,
+        },
+      },
+    ]);
+  });
+
   it('should sync volumes if new storage node is added', async () => {
     mayastorSrv = startMayastorServer(
       [
@@ -267,6 +413,222 @@ module.exports = function() {
     );
   });
 
+  it('should not sync quarantined volumes', async () => {
+    mayastorSrv = startMayastorServer(
+      [
+        {
+          name: 'pool',
+          disks: ['/dev/sda'],
+          state: 0,
+          capacity: 100,
+          used: 50,
+        },
+      ],
+      [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+        },
+        {
+          uuid: 'foreign-lvol',
+          pool: 'pool',
+          size: 20,
+          thin: false,
+          quarantined: true,
+        },
+      ]
+    );
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    let vols = volumeOperator.snapshot();
+    assert.lengthOf(vols, 1);
+    assert.equal(vols[0].volumeId, UUID);
+  });
+
+  it('should export the volume with the next fencing epoch', async () => {
+    mayastorSrv = startMayastorServer(
+      [
+        {
+          name: 'pool',
+          disks: ['/dev/sda'],
+          state: 0,
+          capacity: 100,
+          used: 50,
+        },
+      ],
+      [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+          fencingEpoch: 5,
+        },
+      ]
+    );
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    assert.equal(volumeOperator.get(UUID).fencingEpoch, 5);
+
+    await volumeOperator.createBlkdev('node', UUID);
+    assert.equal(volumeOperator.get(UUID).fencingEpoch, 6);
+    assert.equal(mayastorSrv.getReplicas()[0].fencingEpoch, 6);
+    assert.isTrue(volumeOperator.get(UUID).published);
+  });
+
+  it('should not export the volume with a stale fencing epoch', async () => {
+    mayastorSrv = startMayastorServer(
+      [
+        {
+          name: 'pool',
+          disks: ['/dev/sda'],
+          state: 0,
+          capacity: 100,
+          used: 50,
+        },
+      ],
+      [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+          fencingEpoch: 5,
+        },
+      ]
+    );
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    // another instance of moac has exported the volume in the meantime
+    mayastorSrv.getReplicas()[0].fencingEpoch = 8;
+
+    await shouldFailWith(grpc.status.FAILED_PRECONDITION, () =>
+      volumeOperator.createBlkdev('node', UUID)
+    );
+    assert.equal(volumeOperator.get(UUID).fencingEpoch, 5);
+    assert.isFalse(volumeOperator.get(UUID).published);
+  });
+
+  it('should list volume exported by the node as published', async () => {
+    mayastorSrv = startMayastorServer(
+      [
+        {
+          name: 'pool',
+          disks: ['/dev/sda'],
+          state: 0,
+          capacity: 100,
+          used: 50,
+        },
+      ],
+      [
+        {
+          uuid: UUID,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+          blkdev: '/dev/nbd1',
+        },
+        {
+          uuid: UUID2,
+          pool: 'pool',
+          size: 10,
+          thin: false,
+        },
+      ]
+    );
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+
+    assert.deepEqual(volumeOperator.dependents(UUID), {
+      volume: UUID,
+      snapshots: [],
+      clones: [],
+      publications: [{ node: 'node' }],
+    });
+    assert.lengthOf(volumeOperator.dependents(UUID2).publications, 0);
+    assert.isUndefined(volumeOperator.dependents('unknown'));
+
+    await volumeOperator.destroyBlkdev('node', UUID);
+    assert.lengthOf(volumeOperator.dependents(UUID).publications, 0);
+    await volumeOperator.createBlkdev('node', UUID2);
+    assert.lengthOf(volumeOperator.dependents(UUID2).publications, 1);
+
+    // exported behind our back
+    mayastorSrv.getReplicas()[0].blkdev = '/dev/nbd2';
+    assert.lengthOf(volumeOperator.dependents(UUID).publications, 0);
+    assert.isTrue(await volumeOperator.refresh('node'));
+    assert.lengthOf(volumeOperator.dependents(UUID).publications, 1);
+  });
+
+  it('should replay journal with publications from before restart', async () => {
+    mayastorSrv = startMayastorServer([
+      {
+        name: 'pool',
+        disks: ['/dev/sda'],
+        state: 0,
+        capacity: 100,
+        used: 50,
+      },
+    ]);
+    let nodeOperator = new NodeOperatorMock([
+      {
+        node: 'node',
+        endpoint: EGRESS_ENDPOINT,
+      },
+    ]);
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    let journal = new Journal();
+    // k8s knows about the published volume, but not about the other one
+    for (let uuid of [UUID, UUID2]) {
+      await journal.begin('create', { node: 'node', pool: 'pool', uuid });
+      await volumeOperator.create('node', 'pool', uuid, 10);
+    }
+    await volumeOperator.createBlkdev('node', UUID);
+
+    // moac is restarted
+    await volumeOperator.stop();
+    volumeOperator = new VolumeOperator(nodeOperator);
+    await volumeOperator.start();
+    await new CsiServer('/tmp/csi_volumes_test.sock').replayJournal(
+      journal,
+      volumeOperator
+    );
+
+    let replicas = mayastorSrv.getReplicas();
+    assert.lengthOf(replicas, 1);
+    assert.equal(replicas[0].uuid, UUID);
+    assert.isTrue(volumeOperator.get(UUID).published);
+    assert.isUndefined(volumeOperator.get(UUID2));
+    assert.lengthOf(journal.pending(), 0);
+  });
+
   it('should retry sync of volumes after failure', async () => {
     // change retry interval to 1s not to wait so long
     volumesMod.retrySyncInterval = 1000;
@@ -0,0 +1,384 @@
+//! Benchmark of a staged volume by fio.
+//!
+//! The benchmark runs a bounded fio job on a test file in the filesystem of
+//! a volume which is staged on this node but not published to any
+//! application, so that users can compare the performance of their pools
+//! without crafting pods. The test file is removed when the job finishes.
+//! Only one benchmark can run on the node at a time. The caller holds the
+//! volume (see `Quiesce::begin_volume`) until the job finishes, so that the
+//! volume cannot be published or unstaged under the benchmark.
+
+use crate::{
+    mount::find_mounts,
+    rpc::mayastor::{
+        BenchmarkProfile,
+        BenchmarkStats,
+        BenchmarkVolumeReply,
+        BenchmarkVolumeRequest,
+    },
+    staging::StagingStore,
+};
+use futures::{future, sync::oneshot, Future};
+use serde_json::Value;
+use std::{
+    fs,
+    path::Path,
+    process::Command,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+use tower_grpc::{Code, Status};
+
+const DEFAULT_RUNTIME: u32 = 10;
+const MAX_RUNTIME: u32 = 60;
+const DEFAULT_SIZE: u64 = 256 * 1024 * 1024;
+const MAX_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+const DEFAULT_IODEPTH: u32 = 16;
+const MAX_IODEPTH: u32 = 256;
+/// Name of the test file created in the root of the volume's filesystem.
+const TEST_FILE: &str = ".mayastor-benchmark";
+
+/// Set while a benchmark is running.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+/// Parameters of fio job.
+#[derive(Debug, PartialEq)]
+struct Job {
+    rw: &'static str,
+    block_size: &'static str,
+    runtime: u32,
+    size: u64,
+    iodepth: u32,
+}
+
+impl Job {
+    /// Validate the request and fill in defaults.
+    fn new(msg: &BenchmarkVolumeRequest) -> Result<Self, String> {
+        let (rw, block_size) = match BenchmarkProfile::from_i32(msg.profile) {
+            Some(BenchmarkProfile::RandRead4k) => ("randread", "4k"),
+            Some(BenchmarkProfile::RandWrite4k) => ("randwrite", "4k"),
+            Some(BenchmarkProfile::RandRw4k) => ("randrw", "4k"),
+            Some(BenchmarkProfile::SeqRead128k) => ("read", "128k"),
+            Some(BenchmarkProfile::SeqWrite128k) => ("write", "128k"),
+            None => {
+                return Err(format!(
+                    "Invalid benchmark profile {}",
+                    msg.profile
+                ))
+            }
+        };
+        let runtime = match msg.runtime {
+            0 => DEFAULT_RUNTIME,
+            n if n > MAX_RUNTIME => {
+                return Err(format!(
+                    "Runtime {}s exceeds the limit of {}s",
+                    n, MAX_RUNTIME
+                ))
+            }
+            n => n,
+        };
+        let size = match msg.size {
+            0 => DEFAULT_SIZE,
+            n if n > MAX_SIZE => {
+                return Err(format!(
+                    "Size {} exceeds the limit of {} bytes",
+                    n, MAX_SIZE
+                ))
+            }
+            n => n,
+        };
+        let iodepth = match msg.iodepth {
+            0 => DEFAULT_IODEPTH,
+            n if n > MAX_IODEPTH => {
+                return Err(format!(
+                    "IO depth {} exceeds the limit of {}",
+                    n, MAX_IODEPTH
+                ))
+            }
+            n => n,
+        };
+        Ok(Self {
+            rw,
+            block_size,
+            runtime,
+            size,
+            iodepth,
+        })
+    }
+
+    fn args(&self, file: &Path) -> Vec<String> {
+        let mut args = vec![
+            "--name=benchmark".to_owned(),
+            format!("--filename={}", file.display()),
+            format!("--size={}", self.size),
+            format!("--runtime={}", self.runtime),
+            "--time_based".to_owned(),
+            "--ioengine=libaio".to_owned(),
+            "--direct=1".to_owned(),
+            format!("--rw={}", self.rw),
+            format!("--bs={}", self.block_size),
+            format!("--iodepth={}", self.iodepth),
+            "--output-format=json".to_owned(),
+        ];
+        if self.rw == "randrw" {
+            args.push("--rwmixread=70".to_owned());
+        }
+        args
+    }
+}
+
+/// Convert results of one direction from fio json output to stats. Fio
+/// reports bandwidth in KiB/s and latencies in nanoseconds.
+fn parse_stats(val: &Value) -> BenchmarkStats {
+    let p99 = &val["clat_ns"]["percentile"]["99.000000"];
+
+    BenchmarkStats {
+        iops: val["iops"].as_f64().unwrap_or(0.0).round() as u64,
+        bandwidth: val["bw"].as_u64().unwrap_or(0) * 1024,
+        mean_latency: (val["clat_ns"]["mean"].as_f64().unwrap_or(0.0) / 1000.0)
+            .round() as u64,
+        p99_latency: p99.as_u64().unwrap_or(0) / 1000,
+    }
+}
+
+/// Extract results from fio json output.
+fn parse_output(
+    job: &Job,
+    output: &[u8],
+) -> Result<BenchmarkVolumeReply, String> {
+    let val: Value = serde_json::from_slice(output)
+        .map_err(|err| format!("Invalid fio output: {}", err))?;
+    let result = match val["jobs"].get(0) {
+        Some(result) => result,
+        None => return Err("Fio output without job results".to_owned()),
+    };
+    if let Some(code) = result["error"].as_i64() {
+        if code != 0 {
+            return Err(format!("Fio job failed with error {}", code));
+        }
+    }
+    let reads = job.rw.contains("read") || job.rw == "randrw";
+    let writes = job.rw.contains("write") || job.rw == "randrw";
+
+    Ok(BenchmarkVolumeReply {
+        read: if reads {
+            Some(parse_stats(&result["read"]))
+        } else {
+            None
+        },
+        write: if writes {
+            Some(parse_stats(&result["write"]))
+        } else {
+            None
+        },
+        runtime: (result["job_runtime"].as_u64().unwrap_or(0) / 1000) as u32,
+    })
+}
+
+/// Run fio and remove the test file afterwards.
+fn run_fio(job: &Job, file: &Path) -> Result<BenchmarkVolumeReply, String> {
+    let output = Command::new("fio").args(job.args(file)).output();
+    let _ = fs::remove_file(file);
+
+    let output = output.map_err(|err| format!("Failed to run fio: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Fio failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_output(job, &output.stdout)
+}
+
+/// Return true if a benchmark is running.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Benchmark the volume if it is staged and not in use by an application.
+pub fn benchmark_volume(
+    staging: &StagingStore,
+    msg: &BenchmarkVolumeRequest,
+) -> Box<dyn Future<Item = BenchmarkVolumeReply, Error = Status> + Send> {
+    let job = match Job::new(msg) {
+        Ok(job) => job,
+        Err(reason) => {
+            return Box::new(future::err(Status::new(
+                Code::InvalidArgument,
+                reason,
+            )))
+        }
+    };
+    let record = match staging.list() {
+        Ok(records) => records.into_iter().find(|r| r.volume_id == msg.uuid),
+        Err(reason) => {
+            return Box::new(future::err(Status::new(Code::Internal, reason)))
+        }
+    };
+    let record = match record {
+        Some(record) => record,
+        None => {
+            return Box::new(future::err(Status::new(
+                Code::FailedPrecondition,
+                format!("Volume {} is not staged on this node", msg.uuid),
+            )))
+        }
+    };
+    if record.is_block() {
+        return Box::new(future::err(Status::new(
+            Code::FailedPrecondition,
+            format!("Volume {} is a raw block volume", msg.uuid),
+        )));
+    }
+    // bind mounts of the device other than the staging path are publishes
+    if find_mounts(&record.device)
+        .iter()
+        .any(|m| m.dest != record.staging_path)
+    {
+        return Box::new(future::err(Status::new(
+            Code::FailedPrecondition,
+            format!("Volume {} is in use by an application", msg.uuid),
+        )));
+    }
+    if RUNNING.compare_and_swap(false, true, Ordering::SeqCst) {
+        return Box::new(future::err(Status::new(
+            Code::Unavailable,
+            "Another benchmark is running on the node".to_owned(),
+        )));
+    }
+
+    info!(
+        "Benchmarking volume {} with {:?} for {}s",
+        msg.uuid, job, job.runtime
+    );
+    let file = Path::new(&record.staging_path).join(TEST_FILE);
+    let uuid = msg.uuid.clone();
+    let (sender, receiver) = oneshot::channel();
+
+    // fio runs for many seconds, don't block the executor meanwhile
+    thread::spawn(move || {
+        let res = run_fio(&job, &file);
+        RUNNING.store(false, Ordering::SeqCst);
+        let _ = sender.send(res);
+    });
+
+    Box::new(
+        receiver
+            .map_err(|_| {
+                Status::new(
+                    Code::Internal,
+                    "Benchmark thread has terminated".to_owned(),
+                )
+            })
+            .and_then(move |res| match res {
+                Ok(reply) => {
+                    debug!("Benchmark of volume {}: {:?}", uuid, reply);
+                    Ok(reply)
+                }
+                Err(reason) => {
+                    error!("Benchmark of volume {} failed: {}", uuid, reason);
+                    Err(Status::new(Code::Internal, reason))
+                }
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn job(profile: BenchmarkProfile) -> Job {
+        Job::new(&BenchmarkVolumeRequest {
+            uuid: "vol".to_owned(),
+            profile: profile as i32,
+            runtime: 0,
+            size: 0,
+            iodepth: 0,
+        })
+        .unwrap()
+    }
+
+    const OUTPUT: &str = r#"{
+        "fio version": "fio-3.12",
+        "jobs": [{
+            "jobname": "benchmark",
+            "error": 0,
+            "job_runtime": 10002,
+            "read": {
+                "iops": 2512.49,
+                "bw": 10049,
+                "clat_ns": {
+                    "mean": 6352.7,
+                    "percentile": { "99.000000": 14976 }
+                }
+            },
+            "write": {
+                "iops": 1076.8,
+                "bw": 4307,
+                "clat_ns": {
+                    "mean": 1520.2,
+                    "percentile": { "99.000000": 3024 }
+                }
+            }
+        }]
+    }"#;
+
+    #[test]
+    fn parse_read_write_job() {
+        let reply =
+            parse_output(&job(BenchmarkProfile::RandRw4k), OUTPUT.as_bytes())
+                .unwrap();
+
+        assert_eq!(reply.runtime, 10);
+        assert_eq!(
+            reply.read,
+            Some(BenchmarkStats {
+                iops: 2512,
+                bandwidth: 10049 * 1024,
+                mean_latency: 6,
+                p99_latency: 14,
+            })
+        );
+        assert_eq!(
+            reply.write,
+            Some(BenchmarkStats {
+                iops: 1077,
+                bandwidth: 4307 * 1024,
+                mean_latency: 2,
+                p99_latency: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_only_direction_of_the_job() {
+        let read = parse_output(
+            &job(BenchmarkProfile::SeqRead128k),
+            OUTPUT.as_bytes(),
+        )
+        .unwrap();
+        assert!(read.read.is_some());
+        assert!(read.write.is_none());
+
+        let write = parse_output(
+            &job(BenchmarkProfile::RandWrite4k),
+            OUTPUT.as_bytes(),
+        )
+        .unwrap();
+        assert!(write.read.is_none());
+        assert!(write.write.is_some());
+    }
+
+    #[test]
+    fn parse_failed_job() {
+        let job = job(BenchmarkProfile::RandRead4k);
+
+        assert!(parse_output(&job, b"fio: pid=0, err=5/file:io_u.c").is_err());
+        assert!(parse_output(&job, br#"{"jobs": []}"#).is_err());
+        assert_eq!(
+            parse_output(&job, br#"{"jobs": [{"error": 5}]}"#),
+            Err("Fio job failed with error 5".to_owned())
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Background health check of staged volumes (read canary).
+//!
+//! When enabled, a small direct-IO read from the beginning of the device of
+//! each staged volume is done periodically. If the read fails or does not
+//! complete in time, the volume is reported as abnormal in the volume
+//! condition of NodeGetVolumeStats, so that a dead path is noticed before
+//! the application runs into it. The read bypasses the page cache, so it
+//! really goes to mayastor.
+//!
+//! A read from a dead nbd device can block forever, hence each read is done
+//! in its own thread and a new read of the device is not started until the
+//! previous one has finished.
+
+use crate::{csi::VolumeCondition, staging::StagingStore};
+use futures::{Future, Stream};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::Read,
+    os::unix::fs::OpenOptionsExt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// Size of the read (and alignment of the buffer required by O_DIRECT).
+const BLOCK_SIZE: usize = 4096;
+
+/// Result of the last check of a volume.
+#[derive(Clone, Debug, PartialEq)]
+struct Health {
+    abnormal: bool,
+    message: String,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// results by volume id
+    health: HashMap<String, Health>,
+    /// reads in progress by volume id and when they started
+    in_flight: HashMap<String, Instant>,
+}
+
+/// Read the first block of the device bypassing the page cache.
+fn read_block(device: &str) -> Result<(), String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(device)
+        .map_err(|err| format!("Failed to open {}: {}", device, err))?;
+    let mut buf = vec![0u8; 2 * BLOCK_SIZE];
+    let offset = buf.as_ptr().align_offset(BLOCK_SIZE);
+
+    match file.read(&mut buf[offset .. offset + BLOCK_SIZE]) {
+        Ok(0) => Err(format!("Unexpected end of {}", device)),
+        Ok(_) => Ok(()),
+        Err(err) => Err(format!("Failed to read {}: {}", device, err)),
+    }
+}
+
+/// Update the health of the volume and log changes of the condition.
+fn set_health(state: &mut State, volume_id: &str, health: Health) {
+    let was_abnormal =
+        state.health.get(volume_id).map_or(false, |h| h.abnormal);
+
+    if health.abnormal && !was_abnormal {
+        error!("Volume {} is abnormal: {}", volume_id, health.message);
+    } else if !health.abnormal && was_abnormal {
+        info!("Volume {} is healthy again", volume_id);
+    }
+    state.health.insert(volume_id.to_owned(), health);
+}
+
+#[derive(Clone, Debug)]
+pub struct Canary {
+    /// how often the volumes are checked
+    period: Duration,
+    /// max duration of the read
+    timeout: Duration,
+    staging: StagingStore,
+    state: Arc<Mutex<State>>,
+    /// no new reads are started while paused
+    paused: Arc<AtomicBool>,
+}
+
+impl Canary {
+    pub fn new(
+        period: Duration,
+        timeout: Duration,
+        staging: StagingStore,
+    ) -> Self {
+        Self {
+            period,
+            timeout,
+            staging,
+            state: Arc::new(Mutex::new(State::default())),
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stop or restart the checks (the node is being quiesced).
+    pub fn pause(&self, paused: bool) {
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Return number of reads which have not finished yet.
+    pub fn reads_in_flight(&self) -> usize {
+        self.state.lock().unwrap().in_flight.len()
+    }
+
+    /// Return condition of the volume for NodeGetVolumeStats.
+    pub fn condition(&self, volume_id: &str) -> VolumeCondition {
+        match self.state.lock().unwrap().health.get(volume_id) {
+            Some(health) => VolumeCondition {
+                abnormal: health.abnormal,
+                message: health.message.clone(),
+            },
+            None => VolumeCondition {
+                abnormal: false,
+                message: "The volume has not been checked yet".to_owned(),
+            },
+        }
+    }
+
+    /// Start reads of the staged volumes and check for reads which take
+    /// too long.
+    fn check(&self) {
+        if self.paused.load(Ordering::SeqCst) {
+            return;
+        }
+        let records = match self.staging.list() {
+            Ok(records) => records,
+            Err(reason) => {
+                warn!("Read canary cannot list staged volumes: {}", reason);
+                return;
+            }
+        };
+        let mut state = self.state.lock().unwrap();
+
+        state
+            .health
+            .retain(|id, _| records.iter().any(|r| &r.volume_id == id));
+
+        for record in records {
+            let volume_id = record.volume_id;
+
+            if let Some(started) = state.in_flight.get(&volume_id).cloned() {
+                if started.elapsed() > self.timeout {
+                    let message = format!(
+                        "Read from {} has not completed in {}s",
+                        record.device,
+                        started.elapsed().as_secs()
+                    );
+                    set_health(
+                        &mut state,
+                        &volume_id,
+                        Health {
+                            abnormal: true,
+                            message,
+                        },
+                    );
+                }
+                continue;
+            }
+            state.in_flight.insert(volume_id.clone(), Instant::now());
+
+            let shared = Arc::clone(&self.state);
+            let device = record.device;
+            thread::spawn(move || {
+                let res = read_block(&device);
+                let mut state = shared.lock().unwrap();
+
+                state.in_flight.remove(&volume_id);
+                let health = match res {
+                    Ok(()) => Health {
+                        abnormal: false,
+                        message: format!("Read from {} succeeded", device),
+                    },
+                    Err(message) => Health {
+                        abnormal: true,
+                        message,
+                    },
+                };
+                set_health(&mut state, &volume_id, health);
+            });
+        }
+    }
+
+    /// Periodically check the staged volumes.
+    pub fn run(&self) -> impl Future<Item = (), Error = ()> {
+        let canary = self.clone();
+
+        info!(
+            "Read canary checks staged volumes every {}s",
+            self.period.as_secs()
+        );
+        Interval::new(Instant::now() + self.period, self.period)
+            .map_err(|err| error!("Read canary timer failed: {}", err))
+            .for_each(move |_| {
+                canary.check();
+                Ok(())
+            })
+    }
+}
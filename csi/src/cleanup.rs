@@ -0,0 +1,302 @@
+//! Deferred cleanup of unstaged volumes.
+//!
+//! The filesystem is unmounted lazily, so when unstage returns, the device
+//! may still be busy (i.e. a process has files open on it or the unmount
+//! itself failed with EBUSY). Rather than failing unstage until the device
+//! is released, the volume is registered here and the cleanup is finished in
+//! the background: the staging path is unmounted if the mount is still
+//! there and the staging record is removed once the device is no longer in
+//! use. Failed attempts are retried with exponential backoff.
+//!
+//! The nbd device can be given to another volume after the volume has been
+//! unpublished. It is then busy because of the other volume, so before
+//! waiting for it the device is checked to be still exported for our volume
+//! (or not exported at all).
+
+use crate::{
+    mount::{match_mount, unmount_fs},
+    nbd,
+    staging::StagingStore,
+};
+use futures::{future::Either, Future, Stream};
+use std::{
+    collections::HashMap,
+    fs::OpenOptions,
+    io::ErrorKind,
+    os::unix::fs::OpenOptionsExt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// How often the pending cleanups are checked.
+const CHECK_PERIOD: Duration = Duration::from_secs(1);
+/// Upper bound of the delay between two attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct Pending {
+    staging_path: String,
+    device: String,
+    attempts: u32,
+    next_try: Instant,
+}
+
+impl Pending {
+    fn backoff(&mut self) {
+        let secs = 1u64 << self.attempts.min(16);
+        self.attempts += 1;
+        self.next_try =
+            Instant::now() + Duration::from_secs(secs).min(MAX_BACKOFF);
+    }
+}
+
+/// Return true if the block device is opened exclusively by somebody (a
+/// filesystem which has not been fully unmounted yet, device mapper, ...).
+pub fn device_busy(device: &str) -> bool {
+    match OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_EXCL)
+        .open(device)
+    {
+        Ok(_) => false,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => false,
+        Err(err) => {
+            debug!("Device {} is busy: {}", device, err);
+            true
+        }
+    }
+}
+
+/// Volumes waiting for cleanup.
+#[derive(Clone, Debug)]
+pub struct Cleanup {
+    staging: StagingStore,
+    // mayastor json-rpc socket for finding out who owns nbd devices
+    socket: String,
+    pending: Arc<Mutex<HashMap<String, Pending>>>,
+}
+
+impl Cleanup {
+    pub fn new(staging: StagingStore, socket: &str) -> Self {
+        Self {
+            staging,
+            socket: socket.to_owned(),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Register the volume for deferred cleanup.
+    pub fn defer(&self, volume_id: &str, staging_path: &str, device: &str) {
+        warn!(
+            "Device {} of volume {} is busy, cleanup of {} has been deferred",
+            device, volume_id, staging_path
+        );
+        let mut pending = Pending {
+            staging_path: staging_path.to_owned(),
+            device: device.to_owned(),
+            attempts: 0,
+            next_try: Instant::now(),
+        };
+        pending.backoff();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(volume_id.to_owned(), pending);
+    }
+
+    /// Forget pending cleanup of the volume (it is being staged again).
+    pub fn cancel(&self, volume_id: &str) {
+        if self.pending.lock().unwrap().remove(volume_id).is_some() {
+            info!("Deferred cleanup of volume {} cancelled", volume_id);
+        }
+    }
+
+    /// Return ids of volumes waiting for cleanup.
+    pub fn pending(&self) -> Vec<String> {
+        let mut ids: Vec<String> =
+            self.pending.lock().unwrap().keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    /// Try to finish the cleanup. Return true if it is done. Exported
+    /// volumes by nbd devices are None if they are not known.
+    fn try_cleanup(
+        &self,
+        volume_id: &str,
+        pending: &Pending,
+        exported: Option<&HashMap<String, String>>,
+    ) -> bool {
+        if match_mount(None, Some(&pending.staging_path), false).is_some() {
+            if let Err(reason) = unmount_fs(&pending.staging_path, false) {
+                debug!("{}", reason);
+                return false;
+            }
+        }
+        let owner = exported.and_then(|volumes| volumes.get(&pending.device));
+        match owner {
+            Some(owner) if owner != volume_id => info!(
+                "Device {} of unstaged volume {} is used by volume {} now",
+                pending.device, volume_id, owner
+            ),
+            _ => {
+                if device_busy(&pending.device) {
+                    return false;
+                }
+            }
+        }
+        match self.staging.get(volume_id) {
+            // the record does not describe the staging being cleaned up
+            Ok(Some(ref record)) if record.device != pending.device => (),
+            _ => {
+                if let Err(reason) = self.staging.remove(volume_id) {
+                    warn!("{}", reason);
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Return true if some cleanups are due.
+    fn any_due(&self) -> bool {
+        let now = Instant::now();
+        self.pending
+            .lock()
+            .unwrap()
+            .values()
+            .any(|entry| entry.next_try <= now)
+    }
+
+    /// Retry the cleanups which are due.
+    fn check(&self, exported: Option<&HashMap<String, String>>) {
+        let now = Instant::now();
+        let mut pending = self.pending.lock().unwrap();
+
+        pending.retain(|volume_id, entry| {
+            if entry.next_try > now {
+                return true;
+            }
+            if self.try_cleanup(volume_id, entry, exported) {
+                info!(
+                    "Deferred cleanup of volume {} finished after {} attempts",
+                    volume_id, entry.attempts
+                );
+                false
+            } else {
+                entry.backoff();
+                true
+            }
+        });
+    }
+
+    /// Periodically retry pending cleanups.
+    pub fn run(&self) -> impl Future<Item = (), Error = ()> {
+        let cleanup = self.clone();
+
+        Interval::new(Instant::now() + CHECK_PERIOD, CHECK_PERIOD)
+            .map_err(|err| error!("Cleanup timer failed: {}", err))
+            .for_each(move |_| {
+                if !cleanup.any_due() {
+                    return Either::A(futures::future::ok(()));
+                }
+                let cleanup = cleanup.clone();
+                Either::B(nbd::exported_volumes(&cleanup.socket).then(
+                    move |res| {
+                        // without the list the devices are waited for
+                        let exported =
+                            res.map_err(|reason| warn!("{}", reason)).ok();
+                        cleanup.check(exported.as_ref());
+                        Ok(())
+                    },
+                ))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::StagingRecord;
+    use std::{env, fs, path::PathBuf};
+
+    // A path below a regular file can't be opened (ENOTDIR), which
+    // device_busy() takes for a busy device.
+    const BUSY_DEVICE: &str = "/etc/passwd/nbd0";
+
+    fn cleanup(name: &str) -> (PathBuf, Cleanup) {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let staging = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        for volume_id in &["vol1", "vol2"] {
+            let record = StagingRecord::new(
+                volume_id,
+                "/nonexistent/stage",
+                BUSY_DEVICE,
+                "xfs",
+                &[],
+            );
+            staging.save(&record).unwrap();
+        }
+        let cleanup = Cleanup::new(staging, "");
+        for volume_id in &["vol1", "vol2"] {
+            cleanup.defer(volume_id, "/nonexistent/stage", BUSY_DEVICE);
+        }
+        (dir, cleanup)
+    }
+
+    fn make_due(cleanup: &Cleanup) {
+        for entry in cleanup.pending.lock().unwrap().values_mut() {
+            entry.next_try = Instant::now();
+        }
+    }
+
+    #[test]
+    fn busy_device_is_waited_for() {
+        let (dir, cleanup) = cleanup("csi-cleanup-busy-test");
+        assert!(!cleanup.any_due());
+
+        make_due(&cleanup);
+        assert!(cleanup.any_due());
+        cleanup.check(None);
+        assert_eq!(cleanup.pending(), vec!["vol1", "vol2"]);
+        assert!(!cleanup.any_due());
+
+        // vol1 is still exported on the device
+        let mut exported = HashMap::new();
+        exported.insert(BUSY_DEVICE.to_owned(), "vol1".to_owned());
+        make_due(&cleanup);
+        cleanup.check(Some(&exported));
+        // the device of vol2 has been given to vol1
+        assert_eq!(cleanup.pending(), vec!["vol1"]);
+        assert!(cleanup.staging.get("vol1").unwrap().is_some());
+        assert!(cleanup.staging.get("vol2").unwrap().is_none());
+
+        cleanup.cancel("vol1");
+        assert!(cleanup.pending().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_of_new_staging_is_kept() {
+        let (dir, cleanup) = cleanup("csi-cleanup-record-test");
+        let record = StagingRecord::new(
+            "vol1",
+            "/nonexistent/stage",
+            "/dev/nbd1",
+            "xfs",
+            &[],
+        );
+        cleanup.staging.save(&record).unwrap();
+
+        let mut exported = HashMap::new();
+        exported.insert(BUSY_DEVICE.to_owned(), "vol3".to_owned());
+        make_due(&cleanup);
+        cleanup.check(Some(&exported));
+        assert!(cleanup.pending().is_empty());
+        assert_eq!(cleanup.staging.get("vol1").unwrap(), Some(record));
+        assert!(cleanup.staging.get("vol2").unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,17 +8,32 @@
 #[macro_use]
 extern crate clap;
 
-use bytesize::ByteSize;
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use futures::{future, Future};
+use futures::{future, Future, Stream};
 use hyper::client::connect::{Destination, HttpConnector};
-use rpc::{self, service::client::Mayastor};
+use rpc::{
+    self,
+    capacity::{Capacity, MIB},
+    service::client::Mayastor,
+};
+use serde::{Deserialize, Serialize};
+use std::{env, fs, os::unix::fs::DirBuilderExt, process};
 use tokio::runtime::Runtime;
-use tower_grpc::BoxBody;
+use tower_grpc::{BoxBody, Response, Status};
 use tower_hyper::{client, util, Connection};
 use tower_request_modifier::{Builder, RequestModifier};
 use tower_util::MakeService;
 
+/// Parse size given by the argument (MiB if it is a number without unit).
+fn size_arg(matches: &ArgMatches, name: &str) -> Result<u64, String> {
+    match matches.value_of(name) {
+        Some(val) => Capacity::parse_with_unit(val, MIB)
+            .map(u64::from)
+            .map_err(|err| err.to_string()),
+        None => Ok(0),
+    }
+}
+
 fn create_pool(
     mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
     matches: &ArgMatches,
@@ -31,6 +46,19 @@ fn create_pool(
         .map(|dev| dev.to_owned())
         .collect();
     let block_size = value_t!(matches.value_of("block-size"), u32).unwrap_or(0);
+    // the file has the raw key, it is passed hex encoded
+    let encryption_key = match matches.value_of("key-file") {
+        Some(path) => match fs::read(path) {
+            Ok(key) => key.iter().map(|b| format!("{:02x}", b)).collect(),
+            Err(err) => {
+                return Box::new(future::err(format!(
+                    "Failed to read key file {}: {}",
+                    path, err
+                )))
+            }
+        },
+        None => String::new(),
+    };
 
     if verbose {
         println!("Creating the pool {}", name);
@@ -43,6 +71,7 @@ fn create_pool(
                     name,
                     disks,
                     block_size,
+                    encryption_key,
                 },
             ))
             .map_err(|err| format!("Grpc failed: {}", err))
@@ -85,40 +114,38 @@ fn list_pools(
     let f = client
         .list_pools(tower_grpc::Request::new(rpc::mayastor::Null {}))
         .map_err(|err| format!("Grpc failed: {}", err))
-        .map(move |resp| {
-            let pools = &resp.get_ref().pools;
+        .map(move |resp| print_pools(&resp.get_ref().pools, quiet));
+    Box::new(f)
+}
 
-            if pools.is_empty() && !quiet {
-                println!("No pools have been created");
-            } else {
-                if !quiet {
-                    println!(
-                        "{: <20} {: <8} {: >12} {: >12}   DISKS",
-                        "NAME", "STATE", "CAPACITY", "USED"
-                    );
-                }
-                for p in pools {
-                    print!(
-                        "{: <20} {: <8} {: >12} {: >12}  ",
-                        p.name,
-                        match rpc::mayastor::PoolState::from_i32(p.state)
-                            .unwrap()
-                        {
-                            rpc::mayastor::PoolState::Online => "online",
-                            rpc::mayastor::PoolState::Degraded => "degraded",
-                            rpc::mayastor::PoolState::Faulty => "faulty",
-                        },
-                        ByteSize::b(p.capacity).to_string_as(true),
-                        ByteSize::b(p.used).to_string_as(true),
-                    );
-                    for disk in &p.disks {
-                        print!(" {}", disk);
-                    }
-                    println!();
-                }
+fn print_pools(pools: &[rpc::mayastor::Pool], quiet: bool) {
+    if pools.is_empty() && !quiet {
+        println!("No pools have been created");
+    } else {
+        if !quiet {
+            println!(
+                "{: <20} {: <8} {: >12} {: >12}   DISKS",
+                "NAME", "STATE", "CAPACITY", "USED"
+            );
+        }
+        for p in pools {
+            print!(
+                "{: <20} {: <8} {: >12} {: >12}  ",
+                p.name,
+                match rpc::mayastor::PoolState::from_i32(p.state).unwrap() {
+                    rpc::mayastor::PoolState::Online => "online",
+                    rpc::mayastor::PoolState::Degraded => "degraded",
+                    rpc::mayastor::PoolState::Faulty => "faulty",
+                },
+                Capacity::from_bytes(p.capacity).to_string(),
+                Capacity::from_bytes(p.used).to_string(),
+            );
+            for disk in &p.disks {
+                print!(" {}", disk);
             }
-        });
-    Box::new(f)
+            println!();
+        }
+    }
 }
 
 fn create_replica(
@@ -128,8 +155,12 @@ fn create_replica(
 ) -> Box<dyn Future<Item = (), Error = String> + Send> {
     let pool = matches.value_of("POOL").unwrap().to_owned();
     let uuid = matches.value_of("UUID").unwrap().to_owned();
-    let size = value_t!(matches.value_of("size"), u64).unwrap();
+    let size = match size_arg(matches, "size") {
+        Ok(size) => size,
+        Err(err) => return Box::new(future::err(err)),
+    };
     let thin = matches.is_present("thin");
+    let compress = matches.is_present("compress");
 
     if verbose {
         println!("Creating replica {} on pool {}", uuid, pool);
@@ -142,7 +173,8 @@ fn create_replica(
                     uuid,
                     pool,
                     thin,
-                    size: size * (1024 * 1024),
+                    size,
+                    compress,
                 },
             ))
             .map_err(|err| format!("Grpc failed: {}", err))
@@ -173,6 +205,31 @@ fn destroy_replica(
     )
 }
 
+fn adopt_replica(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let pool = matches.value_of("POOL").unwrap().to_owned();
+    let name = matches.value_of("NAME").unwrap().to_owned();
+
+    if verbose {
+        println!("Adopting lvol {} on pool {}", name, pool);
+    }
+
+    Box::new(
+        client
+            .adopt_volume(tower_grpc::Request::new(
+                rpc::mayastor::AdoptVolumeRequest {
+                    pool,
+                    name,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(|resp| println!("{}", resp.get_ref().uuid)),
+    )
+}
+
 fn list_replicas(
     mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
     verbose: bool,
@@ -186,74 +243,598 @@ fn list_replicas(
         client
             .list_replicas(tower_grpc::Request::new(rpc::mayastor::Null {}))
             .map_err(|err| format!("Grpc failed: {}", err))
+            .map(move |resp| print_replicas(&resp.get_ref().replicas, quiet)),
+    )
+}
+
+fn print_replicas(replicas: &[rpc::mayastor::Replica], quiet: bool) {
+    if replicas.is_empty() && !quiet {
+        println!("No replicas have been created");
+    } else {
+        if !quiet {
+            println!(
+                "{: <20} {: <36} {: <8} {: <10} {: <11} {: >10} {: <36}",
+                "POOL",
+                "NAME",
+                "THIN",
+                "COMPRESSED",
+                "QUARANTINED",
+                "SIZE",
+                "TEMPLATE"
+            );
+        }
+        for r in replicas {
+            println!(
+                "{: <20} {: <36} {: <8} {: <10} {: <11} {: >10} {: <36}",
+                r.pool,
+                r.uuid,
+                r.thin,
+                r.compressed,
+                r.quarantined,
+                Capacity::from_bytes(r.size).to_string(),
+                r.template,
+            );
+        }
+    }
+}
+
+fn stat_replicas(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    verbose: bool,
+    quiet: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    if verbose {
+        println!("Requesting replicas stats");
+    }
+
+    Box::new(
+        client
+            .stat_replicas(tower_grpc::Request::new(rpc::mayastor::Null {}))
+            .map_err(|err| format!("Grpc failed: {}", err))
             .map(move |resp| {
-                let replicas = &resp.get_ref().replicas;
+                print_replica_stats(&resp.get_ref().replicas, quiet)
+            }),
+    )
+}
 
-                if replicas.is_empty() && !quiet {
-                    println!("No replicas have been created");
-                } else {
-                    if !quiet {
+fn print_replica_stats(replicas: &[rpc::mayastor::ReplicaStats], quiet: bool) {
+    if replicas.is_empty() && !quiet {
+        println!("No replicas have been created");
+    } else {
+        if !quiet {
+            println!(
+                "{: <20} {: <36} {: >10} {: >10} {: >10} {: >10} {: >10} {: >10}",
+                "POOL",
+                "NAME",
+                "RDCNT",
+                "WRCNT",
+                "RDBYTES",
+                "WRBYTES",
+                "SIZE",
+                "ALLOCATED",
+            );
+        }
+        for r in replicas {
+            let stats = r.stats.as_ref().unwrap();
+            println!(
+                "{: <20} {: <36} {: >10} {: >10} {: >10} {: >10} {: >10} {: >10}",
+                r.pool,
+                r.uuid,
+                stats.num_read_ops,
+                stats.num_write_ops,
+                stats.bytes_read,
+                stats.bytes_written,
+                Capacity::from_bytes(r.size).to_string(),
+                Capacity::from_bytes(r.allocated).to_string(),
+            );
+        }
+    }
+}
+
+fn create_template(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+    let replica = matches.value_of("REPLICA").unwrap().to_owned();
+
+    if verbose {
+        println!("Creating template {} from replica {}", uuid, replica);
+    }
+
+    Box::new(
+        client
+            .create_template(tower_grpc::Request::new(
+                rpc::mayastor::CreateTemplateRequest {
+                    uuid,
+                    replica,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(|_null_resp| ()),
+    )
+}
+
+fn clone_template(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+    let clones: Vec<String> = matches
+        .values_of("CLONE")
+        .unwrap()
+        .map(|c| c.to_owned())
+        .collect();
+
+    if verbose {
+        println!("Creating {} clones of template {}", clones.len(), uuid);
+    }
+
+    Box::new(
+        client
+            .clone_template(tower_grpc::Request::new(
+                rpc::mayastor::CloneTemplateRequest {
+                    uuid,
+                    clones,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(|_null_resp| ()),
+    )
+}
+
+fn destroy_template(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+
+    if verbose {
+        println!("Destroying template {}", uuid);
+    }
+
+    Box::new(
+        client
+            .destroy_template(tower_grpc::Request::new(
+                rpc::mayastor::DestroyTemplateRequest {
+                    uuid,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(|_null_resp| ()),
+    )
+}
+
+fn list_templates(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    verbose: bool,
+    quiet: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    if verbose {
+        println!("Requesting a list of templates");
+    }
+
+    Box::new(
+        client
+            .list_templates(tower_grpc::Request::new(rpc::mayastor::Null {}))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(move |resp| print_templates(&resp.get_ref().templates, quiet)),
+    )
+}
+
+fn print_templates(templates: &[rpc::mayastor::Template], quiet: bool) {
+    if templates.is_empty() && !quiet {
+        println!("No templates have been created");
+    } else {
+        if !quiet {
+            println!(
+                "{: <20} {: <36} {: >10} {: >8} {: <8}",
+                "POOL", "NAME", "SIZE", "CLONES", "RETIRED"
+            );
+        }
+        for t in templates {
+            println!(
+                "{: <20} {: <36} {: >10} {: >8} {: <8}",
+                t.pool,
+                t.uuid,
+                Capacity::from_bytes(t.size).to_string(),
+                t.clones,
+                t.retired,
+            );
+        }
+    }
+}
+
+fn benchmark_volume(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+    quiet: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+    let profile = match matches.value_of("profile").unwrap_or("randread") {
+        "randread" => rpc::mayastor::BenchmarkProfile::RandRead4k,
+        "randwrite" => rpc::mayastor::BenchmarkProfile::RandWrite4k,
+        "randrw" => rpc::mayastor::BenchmarkProfile::RandRw4k,
+        "read" => rpc::mayastor::BenchmarkProfile::SeqRead128k,
+        _ => rpc::mayastor::BenchmarkProfile::SeqWrite128k,
+    };
+    let runtime = value_t!(matches.value_of("runtime"), u32).unwrap_or(0);
+    let size = match size_arg(matches, "size") {
+        Ok(size) => size,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let iodepth = value_t!(matches.value_of("iodepth"), u32).unwrap_or(0);
+
+    if verbose {
+        println!("Benchmarking volume {} with {:?}", uuid, profile);
+    }
+
+    Box::new(
+        client
+            .benchmark_volume(tower_grpc::Request::new(
+                rpc::mayastor::BenchmarkVolumeRequest {
+                    uuid,
+                    profile: profile as i32,
+                    runtime,
+                    size,
+                    iodepth,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(move |resp| {
+                let reply = resp.get_ref();
+
+                if !quiet {
+                    println!(
+                        "{: <6} {: >10} {: >12} {: >12} {: >12}",
+                        "", "IOPS", "BANDWIDTH", "LAT(us)", "P99(us)"
+                    );
+                }
+                for (name, stats) in
+                    &[("read", &reply.read), ("write", &reply.write)]
+                {
+                    if let Some(stats) = stats {
                         println!(
-                            "{: <20} {: <36} {: <8} {: >10}",
-                            "POOL", "NAME", "THIN", "SIZE"
+                            "{: <6} {: >10} {: >12} {: >12} {: >12}",
+                            name,
+                            stats.iops,
+                            format!(
+                                "{}/s",
+                                Capacity::from_bytes(stats.bandwidth)
+                            ),
+                            stats.mean_latency,
+                            stats.p99_latency,
                         );
                     }
-                    for r in replicas {
+                }
+            }),
+    )
+}
+
+fn quiesce_io(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let timeout = value_t!(matches.value_of("timeout"), u32).unwrap_or(0);
+
+    if verbose {
+        println!("Quiescing the node");
+    }
+
+    Box::new(
+        client
+            .quiesce_io(tower_grpc::Request::new(
+                rpc::mayastor::QuiesceIoRequest {
+                    timeout,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .and_then(|resp| {
+                let reply = resp.into_inner();
+
+                if reply.drained {
+                    println!("The node has been quiesced");
+                    Ok(())
+                } else {
+                    Err(format!(
+                        "The node has not been drained, still in progress:\n  {}",
+                        reply.pending.join("\n  ")
+                    ))
+                }
+            }),
+    )
+}
+
+fn integrity_manifest(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuids: Vec<String> = matches
+        .values_of("UUID")
+        .map(|vals| vals.map(|v| v.to_owned()).collect())
+        .unwrap_or_default();
+    let scrub = matches.is_present("scrub");
+    let output = matches.value_of("output").map(|o| o.to_owned());
+
+    if verbose {
+        println!("Requesting integrity manifest");
+    }
+
+    Box::new(
+        client
+            .get_integrity_manifest(tower_grpc::Request::new(
+                rpc::mayastor::GetIntegrityManifestRequest {
+                    uuids,
+                    scrub,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .and_then(move |resp| {
+                let reply = resp.into_inner();
+                let signature =
+                    format!("hmac-sha256 {} {}", reply.key_id, reply.signature);
+
+                // the signature is valid only for the exact bytes
+                match output {
+                    Some(output) => {
+                        let sig_file = format!("{}.sig", output);
+                        fs::write(&output, &reply.manifest)
+                            .and_then(|_| {
+                                fs::write(&sig_file, format!("{}\n", signature))
+                            })
+                            .map_err(|err| {
+                                format!("Failed to write {}: {}", output, err)
+                            })?;
                         println!(
-                            "{: <20} {: <36} {: <8} {: >10}",
-                            r.pool,
-                            r.uuid,
-                            r.thin,
-                            ByteSize::b(r.size).to_string_as(true),
+                            "Manifest written to {} and its signature to {}",
+                            output, sig_file
                         );
                     }
+                    None => {
+                        println!("{}", reply.manifest);
+                        println!("{}", signature);
+                    }
                 }
+                Ok(())
             }),
     )
 }
 
-fn stat_replicas(
+fn import_volume(
     mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let pool = matches.value_of("POOL").unwrap().to_owned();
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+    let source = matches.value_of("SOURCE").unwrap().to_owned();
+    let rate_limit = value_t!(matches.value_of("rate"), u32).unwrap_or(0);
+
+    if verbose {
+        println!("Importing {} to replica {} on pool {}", source, uuid, pool);
+    }
+
+    Box::new(
+        client
+            .import_volume(tower_grpc::Request::new(
+                rpc::mayastor::ImportVolumeRequest {
+                    uuid: uuid.clone(),
+                    pool,
+                    source,
+                    rate_limit,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .and_then(move |resp| {
+                let reply = resp.into_inner();
+                println!(
+                    "Imported {} bytes ({} zero) to replica {} in {}s",
+                    reply.size, reply.skipped, uuid, reply.duration
+                );
+                println!("sha256 {}", reply.checksum);
+                Ok(())
+            }),
+    )
+}
+
+fn soak(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let pool = matches.value_of("POOL").unwrap().to_owned();
+    let volumes = value_t!(matches.value_of("volumes"), u32).unwrap_or(4);
+    let cycles = value_t!(matches.value_of("cycles"), u32).unwrap_or(10);
+    let size = match size_arg(matches, "size") {
+        Ok(size) => size,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    if verbose {
+        println!(
+            "Running {} volumes through {} cycles on pool {}",
+            volumes, cycles, pool
+        );
+    }
+
+    Box::new(
+        client
+            .soak(tower_grpc::Request::new(rpc::mayastor::SoakRequest {
+                pool,
+                volumes,
+                cycles,
+                size,
+            }))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .and_then(move |resp| {
+                let reply = resp.into_inner();
+                println!(
+                    "{} of {} cycles succeeded in {}s",
+                    reply.cycles,
+                    volumes * cycles,
+                    reply.duration
+                );
+                for failure in &reply.failures {
+                    println!("FAILED: {}", failure);
+                }
+                for leak in &reply.leaks {
+                    println!("LEAKED: {}", leak);
+                }
+                if reply.failures.is_empty() && reply.leaks.is_empty() {
+                    Ok(())
+                } else {
+                    Err("Soak test failed".to_owned())
+                }
+            }),
+    )
+}
+
+fn resume_io(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    if verbose {
+        println!("Resuming the node");
+    }
+
+    Box::new(
+        client
+            .resume_io(tower_grpc::Request::new(rpc::mayastor::Null {}))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(|_| println!("The node has been resumed")),
+    )
+}
+
+fn volume_history(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+    quiet: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let uuid = matches.value_of("UUID").unwrap().to_owned();
+
+    if verbose {
+        println!("Requesting history of volume {}", uuid);
+    }
+
+    Box::new(
+        client
+            .get_volume_history(tower_grpc::Request::new(
+                rpc::mayastor::GetVolumeHistoryRequest {
+                    uuid,
+                },
+            ))
+            .map_err(|err| format!("Grpc failed: {}", err))
+            .map(move |resp| {
+                let operations = &resp.get_ref().operations;
+
+                if operations.is_empty() {
+                    if !quiet {
+                        println!("No operations found");
+                    }
+                    return;
+                }
+                if !quiet {
+                    println!(
+                        "{: <29} {: <20} {: <30} {: <50} RESULT",
+                        "TIME", "OPERATION", "REQUESTER", "PATH"
+                    );
+                }
+                for op in operations {
+                    println!(
+                        "{: <29} {: <20} {: <30} {: <50} {}",
+                        op.timestamp,
+                        op.operation,
+                        if op.requester.is_empty() {
+                            "-"
+                        } else {
+                            &op.requester
+                        },
+                        op.path,
+                        op.result,
+                    );
+                }
+            }),
+    )
+}
+
+fn reconcile_now(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
     verbose: bool,
     quiet: bool,
 ) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let (scope, name) = if let Some(pool) = matches.value_of("pool") {
+        (rpc::mayastor::ReconcileScope::Pool, pool.to_owned())
+    } else if let Some(uuid) = matches.value_of("volume") {
+        (rpc::mayastor::ReconcileScope::Volume, uuid.to_owned())
+    } else {
+        (rpc::mayastor::ReconcileScope::Node, String::new())
+    };
+    let dry_run = matches.is_present("dry-run");
+
     if verbose {
-        println!("Requesting replicas stats");
+        println!("Reconciling {:?} {}", scope, name);
     }
 
     Box::new(
         client
-            .stat_replicas(tower_grpc::Request::new(rpc::mayastor::Null {}))
+            .reconcile_now(tower_grpc::Request::new(
+                rpc::mayastor::ReconcileNowRequest {
+                    scope: scope as i32,
+                    name,
+                    dry_run,
+                },
+            ))
             .map_err(|err| format!("Grpc failed: {}", err))
-            .map(move |resp| {
-                let replicas = &resp.get_ref().replicas;
+            .and_then(move |resp| {
+                let reply = resp.into_inner();
 
-                if replicas.is_empty() && !quiet {
-                    println!("No replicas have been created");
-                } else {
+                if verbose {
+                    println!(
+                        "Reconcilers {} checked {} objects",
+                        reply.reconcilers.join(", "),
+                        reply.checked
+                    );
+                }
+                if !reply.findings.is_empty() {
                     if !quiet {
                         println!(
-                            "{: <20} {: <36} {: >10} {: >10} {: >10} {: >10}",
-                            "POOL",
-                            "NAME",
-                            "RDCNT",
-                            "WRCNT",
-                            "RDBYTES",
-                            "WRBYTES",
+                            "{: <10} {: <40} {: <60} ACTION",
+                            "RECONCILER", "OBJECT", "PROBLEM"
                         );
                     }
-                    for r in replicas {
-                        let stats = r.stats.as_ref().unwrap();
+                    for f in &reply.findings {
                         println!(
-                            "{: <20} {: <36} {: >10} {: >10} {: >10} {: >10}",
-                            r.pool,
-                            r.uuid,
-                            stats.num_read_ops,
-                            stats.num_write_ops,
-                            stats.bytes_read,
-                            stats.bytes_written,
+                            "{: <10} {: <40} {: <60} {}",
+                            f.reconciler,
+                            f.object,
+                            f.problem,
+                            if f.action.is_empty() { "-" } else { &f.action },
                         );
                     }
+                } else if !quiet {
+                    println!("Nothing to reconcile");
+                }
+                for err in &reply.errors {
+                    eprintln!("FAILED: {}", err);
+                }
+                let unfixed =
+                    reply.findings.iter().filter(|f| !f.fixed).count();
+                if unfixed > 0 || !reply.errors.is_empty() {
+                    Err(format!(
+                        "{} problems not fixed, {} reconcilers failed",
+                        unfixed,
+                        reply.errors.len()
+                    ))
+                } else {
+                    Ok(())
                 }
             }),
     )
@@ -295,6 +876,7 @@ fn dispatch_replica_cmd(
     match matches.subcommand() {
         ("create", Some(matches)) => create_replica(client, matches, verbose),
         ("destroy", Some(matches)) => destroy_replica(client, matches, verbose),
+        ("adopt", Some(matches)) => adopt_replica(client, matches, verbose),
         ("list", Some(_matches)) => list_replicas(client, verbose, quiet),
         ("stats", Some(_matches)) => stat_replicas(client, verbose, quiet),
         _ => Box::new(future::err(format!(
@@ -304,10 +886,558 @@ fn dispatch_replica_cmd(
     }
 }
 
+/// The same dispatch function as for the pool commands above but this one
+/// is for template commands.
+fn dispatch_template_cmd(
+    client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+    quiet: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    match matches.subcommand() {
+        ("create", Some(matches)) => create_template(client, matches, verbose),
+        ("clone", Some(matches)) => clone_template(client, matches, verbose),
+        ("destroy", Some(matches)) => {
+            destroy_template(client, matches, verbose)
+        }
+        ("list", Some(_matches)) => list_templates(client, verbose, quiet),
+        _ => Box::new(future::err(format!(
+            "Command invalid\n {}",
+            matches.usage().to_string()
+        ))),
+    }
+}
+
+fn format_log_record(rec: &rpc::mayastor::LogRecord) -> String {
+    format!(
+        "{} {: <5} {}: {}",
+        rec.timestamp,
+        rec.level.to_uppercase(),
+        rec.module,
+        rec.message
+    )
+}
+
+/// Print log records of the server.
+fn tail_logs(
+    mut client: Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let req = rpc::mayastor::TailLogsRequest {
+        level: matches.value_of("level").unwrap_or("info").to_owned(),
+        module: matches.value_of("module").unwrap_or("").to_owned(),
+        follow: matches.is_present("follow"),
+    };
+
+    if verbose {
+        println!("Requesting {} logs", req.level);
+    }
+
+    let f = client
+        .tail_logs(tower_grpc::Request::new(req))
+        .map_err(|err| format!("Grpc failed: {}", err))
+        .and_then(|resp| {
+            resp.into_inner()
+                .map_err(|err| format!("Log stream failed: {}", err))
+                .for_each(|rec| {
+                    println!("{}", format_log_record(&rec));
+                    Ok(())
+                })
+        });
+    Box::new(f)
+}
+
+type Client = Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>;
+/// Files of support bundle (name and content).
+type BundleFiles = Vec<(String, String)>;
+
+/// Call gRPC method and add its reply in json format to the bundle files.
+/// Failure of the method is recorded in the file instead of the reply.
+fn bundle_reply<T, F, R>(
+    client: Client,
+    mut files: BundleFiles,
+    name: &'static str,
+    method: F,
+) -> Box<dyn Future<Item = (Client, BundleFiles), Error = String> + Send>
+where
+    T: Serialize + Send + 'static,
+    F: FnOnce(&mut Client) -> R + Send + 'static,
+    R: Future<Item = Response<T>, Error = Status> + Send + 'static,
+{
+    Box::new(
+        client
+            .ready()
+            .map_err(|err| format!("Error waiting for ready: {}", err))
+            .and_then(move |mut client| {
+                method(&mut client).then(move |res| {
+                    let content = match res {
+                        Ok(resp) => {
+                            serde_json::to_string_pretty(resp.get_ref())
+                                .unwrap()
+                        }
+                        Err(err) => format!("Grpc failed: {}", err),
+                    };
+                    files.push((name.to_owned(), content));
+                    Ok((client, files))
+                })
+            }),
+    )
+}
+
+/// Write the files to a temporary directory and pack them to tar.gz archive.
+fn write_bundle(output: &str, files: BundleFiles) -> Result<(), String> {
+    // the directory must be a new one accessible only by us: creating it
+    // fails if it exists (i.e. somebody has put a symlink there)
+    let tmp_dir = env::temp_dir().join(format!("mayastor-{}", process::id()));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&tmp_dir)
+        .map_err(|err| {
+            format!("Failed to create {}: {}", tmp_dir.display(), err)
+        })?;
+    let bundle_dir = tmp_dir.join("mayastor-bundle");
+
+    let res = fs::create_dir(&bundle_dir)
+        .map_err(|err| {
+            format!("Failed to create {}: {}", bundle_dir.display(), err)
+        })
+        .and_then(|_| {
+            for (name, content) in &files {
+                let path = bundle_dir.join(name);
+                fs::write(&path, content).map_err(|err| {
+                    format!("Failed to write {}: {}", path.display(), err)
+                })?;
+            }
+            process::Command::new("tar")
+                .arg("czf")
+                .arg(output)
+                .arg("-C")
+                .arg(&tmp_dir)
+                .arg("mayastor-bundle")
+                .status()
+                .map_err(|err| format!("Failed to run tar: {}", err))
+        })
+        .and_then(|status| {
+            if status.success() {
+                Ok(())
+            } else {
+                Err(format!("tar failed: {}", status))
+            }
+        });
+    let _ = fs::remove_dir_all(&tmp_dir);
+    res
+}
+
+/// Collect state of the server, its logs and node-local information and
+/// store it in a single archive, which can be attached to a bug report.
+fn support_bundle(
+    client: Client,
+    matches: &ArgMatches,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let output = match matches.value_of("output") {
+        Some(output) => output.to_owned(),
+        None => format!(
+            "mayastor-bundle-{}.tar.gz",
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ),
+    };
+
+    if verbose {
+        println!("Collecting support bundle");
+    }
+
+    let f = bundle_reply(client, Vec::new(), "pools.json", |c| {
+        c.list_pools(tower_grpc::Request::new(rpc::mayastor::Null {}))
+    })
+    .and_then(|(client, files)| {
+        bundle_reply(client, files, "replicas.json", |c| {
+            c.list_replicas(tower_grpc::Request::new(rpc::mayastor::Null {}))
+        })
+    })
+    .and_then(|(client, files)| {
+        bundle_reply(client, files, "replica_stats.json", |c| {
+            c.stat_replicas(tower_grpc::Request::new(rpc::mayastor::Null {}))
+        })
+    })
+    .and_then(|(client, files)| {
+        bundle_reply(client, files, "templates.json", |c| {
+            c.list_templates(tower_grpc::Request::new(rpc::mayastor::Null {}))
+        })
+    })
+    .and_then(|(client, files)| {
+        bundle_reply(client, files, "nexus.json", |c| {
+            c.list_nexus(tower_grpc::Request::new(rpc::mayastor::Null {}))
+        })
+    })
+    .and_then(|(client, files)| {
+        client
+            .ready()
+            .map_err(|err| format!("Error waiting for ready: {}", err))
+            .and_then(move |mut client| {
+                client
+                    .get_support_info(tower_grpc::Request::new(
+                        rpc::mayastor::Null {},
+                    ))
+                    .then(move |res| {
+                        let mut files = files;
+                        match res {
+                            Ok(resp) => files.extend(
+                                resp.into_inner()
+                                    .files
+                                    .into_iter()
+                                    .map(|f| (f.name, f.content)),
+                            ),
+                            Err(err) => files.push((
+                                "support_info.txt".to_owned(),
+                                format!("Grpc failed: {}", err),
+                            )),
+                        }
+                        Ok((client, files))
+                    })
+            })
+    })
+    .and_then(|(client, files)| {
+        client
+            .ready()
+            .map_err(|err| format!("Error waiting for ready: {}", err))
+            .and_then(move |mut client| {
+                client
+                    .tail_logs(tower_grpc::Request::new(
+                        rpc::mayastor::TailLogsRequest {
+                            level: "trace".to_owned(),
+                            module: String::new(),
+                            follow: false,
+                        },
+                    ))
+                    .and_then(|resp| resp.into_inner().collect())
+                    .then(move |res| {
+                        let mut files = files;
+                        files.push((
+                            "agent.log".to_owned(),
+                            match res {
+                                Ok(records) => records
+                                    .iter()
+                                    .map(format_log_record)
+                                    .collect::<Vec<_>>()
+                                    .join("\n"),
+                                Err(err) => format!("Grpc failed: {}", err),
+                            },
+                        ));
+                        Ok(files)
+                    })
+            })
+    })
+    .and_then(move |files| {
+        write_bundle(&output, files)?;
+        println!("Support bundle written to {}", output);
+        Ok(())
+    });
+
+    Box::new(f)
+}
+
+/// Version of the format of state dumps.
+const DUMP_VERSION: u32 = 1;
+
+/// Read-only snapshot of the state of the node in one json document. It can
+/// be viewed by the client without access to the node (`--from-dump`).
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StateDump {
+    version: u32,
+    /// when the dump was made (RFC 3339)
+    created: String,
+    /// server which the dump was made from
+    endpoint: String,
+    pools: Vec<rpc::mayastor::Pool>,
+    replicas: Vec<rpc::mayastor::Replica>,
+    replica_stats: Vec<rpc::mayastor::ReplicaStats>,
+    templates: Vec<rpc::mayastor::Template>,
+    nexus: Vec<rpc::mayastor::Nexus>,
+    /// volumes staged on the node
+    volumes: serde_json::Value,
+    /// operations and background jobs in progress
+    jobs: serde_json::Value,
+    /// effective config of the agent (secrets are redacted)
+    config: serde_json::Value,
+    /// what could not be obtained and why
+    errors: Vec<String>,
+}
+
+/// Call gRPC method and store its reply in the dump. Failure of the method
+/// is recorded in the errors of the dump instead.
+fn dump_reply<T, F, R, S>(
+    client: Client,
+    mut dump: StateDump,
+    what: &'static str,
+    method: F,
+    store: S,
+) -> Box<dyn Future<Item = (Client, StateDump), Error = String> + Send>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut Client) -> R + Send + 'static,
+    R: Future<Item = Response<T>, Error = Status> + Send + 'static,
+    S: FnOnce(&mut StateDump, T) + Send + 'static,
+{
+    Box::new(
+        client
+            .ready()
+            .map_err(|err| format!("Error waiting for ready: {}", err))
+            .and_then(move |mut client| {
+                method(&mut client).then(move |res| {
+                    match res {
+                        Ok(resp) => store(&mut dump, resp.into_inner()),
+                        Err(err) => dump
+                            .errors
+                            .push(format!("{}: Grpc failed: {}", what, err)),
+                    }
+                    Ok((client, dump))
+                })
+            }),
+    )
+}
+
+/// Json content of a support file or the content as a string if it is not
+/// json (i.e. an error message).
+fn support_json(content: String) -> serde_json::Value {
+    serde_json::from_str(&content)
+        .unwrap_or_else(|_| serde_json::Value::String(content))
+}
+
+/// Collect the state of the node for a dump.
+fn collect_state(
+    client: Client,
+    endpoint: String,
+) -> Box<dyn Future<Item = StateDump, Error = String> + Send> {
+    let dump = StateDump {
+        version: DUMP_VERSION,
+        created: chrono::Utc::now().to_rfc3339(),
+        endpoint,
+        ..Default::default()
+    };
+
+    let f = dump_reply(
+        client,
+        dump,
+        "pools",
+        |c| c.list_pools(tower_grpc::Request::new(rpc::mayastor::Null {})),
+        |dump, reply| dump.pools = reply.pools,
+    )
+    .and_then(|(client, dump)| {
+        dump_reply(
+            client,
+            dump,
+            "replicas",
+            |c| {
+                c.list_replicas(tower_grpc::Request::new(
+                    rpc::mayastor::Null {},
+                ))
+            },
+            |dump, reply| dump.replicas = reply.replicas,
+        )
+    })
+    .and_then(|(client, dump)| {
+        dump_reply(
+            client,
+            dump,
+            "replica stats",
+            |c| {
+                c.stat_replicas(tower_grpc::Request::new(
+                    rpc::mayastor::Null {},
+                ))
+            },
+            |dump, reply| dump.replica_stats = reply.replicas,
+        )
+    })
+    .and_then(|(client, dump)| {
+        dump_reply(
+            client,
+            dump,
+            "templates",
+            |c| {
+                c.list_templates(tower_grpc::Request::new(
+                    rpc::mayastor::Null {},
+                ))
+            },
+            |dump, reply| dump.templates = reply.templates,
+        )
+    })
+    .and_then(|(client, dump)| {
+        dump_reply(
+            client,
+            dump,
+            "nexus",
+            |c| c.list_nexus(tower_grpc::Request::new(rpc::mayastor::Null {})),
+            |dump, reply| dump.nexus = reply.nexus_list,
+        )
+    })
+    .and_then(|(client, dump)| {
+        dump_reply(
+            client,
+            dump,
+            "support info",
+            |c| {
+                c.get_support_info(tower_grpc::Request::new(
+                    rpc::mayastor::Null {},
+                ))
+            },
+            |dump, reply| {
+                for file in reply.files {
+                    match file.name.as_str() {
+                        "config.json" => {
+                            dump.config = support_json(file.content)
+                        }
+                        "staging.json" => {
+                            dump.volumes = support_json(file.content)
+                        }
+                        "jobs.json" => dump.jobs = support_json(file.content),
+                        _ => (),
+                    }
+                }
+            },
+        )
+    })
+    .map(|(_client, dump)| dump);
+
+    Box::new(f)
+}
+
+/// Load the state dump from the file.
+fn load_dump(path: &str) -> Result<StateDump, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let dump: StateDump = serde_json::from_str(&content)
+        .map_err(|err| format!("Invalid state dump {}: {}", path, err))?;
+    if dump.version == 0 || dump.version > DUMP_VERSION {
+        return Err(format!(
+            "Unsupported version {} of state dump {} (max {})",
+            dump.version, path, DUMP_VERSION
+        ));
+    }
+    Ok(dump)
+}
+
+/// Print the section of the dump or its summary if no section is given.
+fn show_state(dump: &StateDump, section: Option<&str>) -> Result<(), String> {
+    let section = match section {
+        Some("nexus") => serde_json::to_value(&dump.nexus).unwrap(),
+        Some("volumes") => dump.volumes.clone(),
+        Some("jobs") => dump.jobs.clone(),
+        Some("config") => dump.config.clone(),
+        Some("errors") => serde_json::to_value(&dump.errors).unwrap(),
+        Some(name) => return Err(format!("Unknown section {}", name)),
+        None => {
+            println!("State of {} at {}", dump.endpoint, dump.created);
+            println!("  pools:     {}", dump.pools.len());
+            println!("  replicas:  {}", dump.replicas.len());
+            println!("  templates: {}", dump.templates.len());
+            println!("  nexus:     {}", dump.nexus.len());
+            println!(
+                "  volumes:   {}",
+                dump.volumes.as_array().map_or(0, Vec::len)
+            );
+            println!(
+                "  jobs:      {}",
+                dump.jobs.as_array().map_or(0, Vec::len)
+            );
+            for err in &dump.errors {
+                println!("Missing {}", err);
+            }
+            return Ok(());
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&section).unwrap());
+    Ok(())
+}
+
+/// Write the state of the node to a file.
+fn state_dump(
+    client: Client,
+    matches: &ArgMatches,
+    endpoint: String,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    let path = matches.value_of("at").unwrap().to_owned();
+
+    if verbose {
+        println!("Collecting state of the node");
+    }
+
+    Box::new(collect_state(client, endpoint).and_then(move |dump| {
+        let content = serde_json::to_string_pretty(&dump).unwrap();
+        fs::write(&path, content)
+            .map_err(|err| format!("Failed to write {}: {}", path, err))?;
+        if !dump.errors.is_empty() {
+            eprintln!("Incomplete state dump:\n  {}", dump.errors.join("\n  "));
+        }
+        println!("State dump written to {}", path);
+        Ok(())
+    }))
+}
+
+/// The same dispatch function as for the pool commands above but this one
+/// is for state commands.
+fn dispatch_state_cmd(
+    client: Client,
+    matches: &ArgMatches,
+    endpoint: String,
+    verbose: bool,
+) -> Box<dyn Future<Item = (), Error = String> + Send> {
+    match matches.subcommand() {
+        ("dump", Some(matches)) => {
+            state_dump(client, matches, endpoint, verbose)
+        }
+        ("show", Some(matches)) => {
+            let section = matches.value_of("SECTION").map(String::from);
+            Box::new(collect_state(client, endpoint).and_then(move |dump| {
+                show_state(&dump, section.as_ref().map(String::as_str))
+            }))
+        }
+        _ => Box::new(future::err(format!(
+            "Command invalid\n {}",
+            matches.usage().to_string()
+        ))),
+    }
+}
+
+/// Run the command on the state dump instead of the server. Only commands
+/// which read the state are available.
+fn view_dump(
+    path: &str,
+    matches: &ArgMatches,
+    quiet: bool,
+) -> Result<(), String> {
+    let dump = load_dump(path)?;
+    let (command, args) = match matches.subcommand() {
+        (name, Some(m)) => match m.subcommand() {
+            (sub, Some(m)) => (format!("{} {}", name, sub), m),
+            _ => (name.to_owned(), m),
+        },
+        (name, None) => return Err(format!("Command {} invalid", name)),
+    };
+
+    match command.as_str() {
+        "pool list" => print_pools(&dump.pools, quiet),
+        "replica list" => print_replicas(&dump.replicas, quiet),
+        "replica stats" => print_replica_stats(&dump.replica_stats, quiet),
+        "template list" => print_templates(&dump.templates, quiet),
+        "state show" => show_state(&dump, args.value_of("SECTION"))?,
+        name => {
+            return Err(format!(
+                "Command {} is not available on a state dump",
+                name
+            ))
+        }
+    }
+    Ok(())
+}
+
 pub fn main() {
     let matches = App::new("Mayastor grpc client")
         .version("0.1")
-        .settings(&[AppSettings::SubcommandRequiredElseHelp,
+        .settings(&[AppSettings::ArgRequiredElseHelp,
                   AppSettings::ColoredHelp, AppSettings::ColorAlways])
         .about("Client for mayastor gRPC server")
         .arg(
@@ -339,6 +1469,18 @@ pub fn main() {
                 .long("quiet")
                 .help("Do not print any output except for list records"),
         )
+        .arg(
+            Arg::with_name("dump-schema")
+                .long("dump-schema")
+                .help("Print JSON schema of all API messages and exit"),
+        )
+        .arg(
+            Arg::with_name("from-dump")
+                .long("from-dump")
+                .value_name("FILE")
+                .help("Read the state from a dump made by 'state dump' instead of the server")
+                .takes_value(true),
+        )
         .subcommand(
             SubCommand::with_name("pool")
                 .about("Storage pool management")
@@ -353,6 +1495,14 @@ pub fn main() {
                                 .help("block size of the underlying devices")
                                 .takes_value(true),
                         )
+                        .arg(
+                            Arg::with_name("key-file")
+                                .short("k")
+                                .long("key-file")
+                                .value_name("FILE")
+                                .help("Encrypt the pool with the raw key (16 bytes) in the file")
+                                .takes_value(true),
+                        )
                         .arg(
                             Arg::with_name("POOL")
                                 .help("Storage pool name")
@@ -401,8 +1551,11 @@ pub fn main() {
                             Arg::with_name("size")
                                 .short("s")
                                 .long("size")
-                                .value_name("NUMBER")
-                                .help("Size of the replica in MiB")
+                                .value_name("SIZE")
+                                .help(
+                                    "Size of the replica (i.e. 64MiB, 10GiB, \
+                                     MiB if no unit is given)",
+                                )
                                 .takes_value(true)
                                 .required(true),
                         )
@@ -412,6 +1565,13 @@ pub fn main() {
                                 .long("thin")
                                 .help("Replica is thin provisioned (default false)")
                                 .takes_value(false),
+                        )
+                        .arg(
+                            Arg::with_name("compress")
+                                .short("c")
+                                .long("compress")
+                                .help("Compress data of the replica (implies thin)")
+                                .takes_value(false),
                         ),
                 )
                 .subcommand(
@@ -424,11 +1584,338 @@ pub fn main() {
                                 .index(1),
                         ),
                 )
+                .subcommand(
+                    SubCommand::with_name("adopt")
+                        .about("Bring quarantined lvol under management as a replica")
+                        .arg(
+                            Arg::with_name("POOL")
+                                .help("Storage pool name")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("NAME")
+                                .help("Name of the lvol")
+                                .required(true)
+                                .index(2),
+                        ),
+                )
                 .subcommand(SubCommand::with_name("list").about("List replicas"))
                 .subcommand(SubCommand::with_name("stats").about("IO stats of replicas")),
         )
+        .subcommand(
+            SubCommand::with_name("template")
+                .about("Golden image templates and their clones")
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create template from replica")
+                        .arg(
+                            Arg::with_name("REPLICA")
+                                .help("Replica uuid with the data of the template")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("UUID")
+                                .help("Unique template uuid")
+                                .required(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("clone")
+                        .about("Create thin clones (replicas) of template")
+                        .arg(
+                            Arg::with_name("UUID")
+                                .help("Template uuid")
+                                .required(true)
+                                .index(1),
+                        )
+                        .arg(
+                            Arg::with_name("CLONE")
+                                .help("Unique uuids of the clones")
+                                .required(true)
+                                .multiple(true)
+                                .index(2),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("destroy")
+                        .about("Destroy template (retire it if it has clones)")
+                        .arg(
+                            Arg::with_name("UUID")
+                                .help("Template uuid")
+                                .required(true)
+                                .index(1),
+                        ),
+                )
+                .subcommand(SubCommand::with_name("list").about("List templates")),
+        )
+        .subcommand(
+            SubCommand::with_name("benchmark")
+                .about("Run fio benchmark on a volume staged on the node")
+                .arg(
+                    Arg::with_name("UUID")
+                        .help("Volume uuid")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .value_name("PROFILE")
+                        .help("Workload: 4k random or 128k sequential IO (default randread)")
+                        .possible_values(&["randread", "randwrite", "randrw", "read", "write"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("runtime")
+                        .short("t")
+                        .long("runtime")
+                        .value_name("SECONDS")
+                        .help("Duration of the benchmark (default 10, max 60)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .short("s")
+                        .long("size")
+                        .value_name("SIZE")
+                        .help("Size of the test file (default 256MiB)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("iodepth")
+                        .long("iodepth")
+                        .value_name("NUMBER")
+                        .help("Number of IOs in flight (default 16)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("history")
+                .about("Show recent operations on a volume on the node")
+                .arg(
+                    Arg::with_name("UUID")
+                        .help("Volume uuid")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("quiesce")
+                .about("Stop control operations on the node and wait until it is drained")
+                .arg(
+                    Arg::with_name("timeout")
+                        .short("t")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .help("How long to wait for draining (default 30, max 600)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("Signed manifest of checksums of replicas for integrity audits")
+                .arg(
+                    Arg::with_name("scrub")
+                        .short("s")
+                        .long("scrub")
+                        .help("Compute the checksums now (reads all data of the replicas)")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Write the manifest to the file and its signature to FILE.sig")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("UUID")
+                        .help("Replicas in the manifest (default all scrubbed replicas)")
+                        .multiple(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Create replica with data copied from a block device or file on the node")
+                .arg(
+                    Arg::with_name("rate")
+                        .short("r")
+                        .long("rate")
+                        .value_name("MiB/s")
+                        .help("Limit the rate of the copy (default unlimited)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("POOL")
+                        .help("Storage pool name")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("UUID")
+                        .help("Replica uuid")
+                        .required(true)
+                        .index(2),
+                )
+                .arg(
+                    Arg::with_name("SOURCE")
+                        .help("Path of the source block device or file")
+                        .required(true)
+                        .index(3),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("soak")
+                .about("Run volumes through create, stage, publish, write, unpublish, unstage and delete cycles")
+                .arg(
+                    Arg::with_name("volumes")
+                        .short("n")
+                        .long("volumes")
+                        .value_name("N")
+                        .help("Number of volumes tested at once (default 4, max 8)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cycles")
+                        .short("c")
+                        .long("cycles")
+                        .value_name("M")
+                        .help("Number of cycles of each volume (default 10)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("size")
+                        .short("s")
+                        .long("size")
+                        .value_name("SIZE")
+                        .help("Size of the volumes (default 64MiB)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("POOL")
+                        .help("Storage pool for the volumes")
+                        .required(true)
+                        .index(1),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reconcile")
+                .about("Run mount, nbd and config reconcilers now and report what they have found and fixed")
+                .arg(
+                    Arg::with_name("pool")
+                        .long("pool")
+                        .value_name("NAME")
+                        .conflicts_with("volume")
+                        .help("Limit the reconcilers to the pool and its volumes")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("volume")
+                        .long("volume")
+                        .value_name("UUID")
+                        .help("Limit the reconcilers to the volume")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Only report the problems, don't fix them"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("resume")
+                .about("Accept control operations on the quiesced node again"),
+        )
+        .subcommand(
+            SubCommand::with_name("support-bundle")
+                .about("Collect state and logs of the server for a bug report")
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .value_name("FILE")
+                        .help("Output file (default mayastor-bundle-<time>.tar.gz)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("logs")
+                .about("Show log records of the server")
+                .arg(
+                    Arg::with_name("follow")
+                        .short("f")
+                        .long("follow")
+                        .help("Keep printing new records as they come"),
+                )
+                .arg(
+                    Arg::with_name("level")
+                        .short("l")
+                        .long("level")
+                        .value_name("LEVEL")
+                        .help("Least severe level of records (default info)")
+                        .possible_values(&["error", "warn", "info", "debug", "trace"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("module")
+                        .short("m")
+                        .long("module")
+                        .value_name("MODULE")
+                        .help("Show only records from modules with this prefix")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("state")
+                .about("Snapshot of the state of the node for offline diagnostics")
+                .subcommand(
+                    SubCommand::with_name("dump")
+                        .about("Write pools, replicas, nexus, volumes, jobs and config to a json file")
+                        .arg(
+                            Arg::with_name("at")
+                                .long("at")
+                                .value_name("PATH")
+                                .help("Path of the dump file")
+                                .required(true)
+                                .takes_value(true),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("show")
+                        .about("Show summary or a section of the state")
+                        .arg(
+                            Arg::with_name("SECTION")
+                                .help("Section to print as json")
+                                .possible_values(&["nexus", "volumes", "jobs", "config", "errors"])
+                                .index(1),
+                        ),
+                ),
+        )
         .get_matches();
 
+    if matches.is_present("dump-schema") {
+        println!("{}", rpc::SCHEMA);
+        return;
+    }
+    if matches.subcommand_name().is_none() {
+        eprintln!("{}\n\nFor more information try --help", matches.usage());
+        process::exit(1);
+    }
+    if let Some(path) = matches.value_of("from-dump") {
+        if let Err(err) = view_dump(path, &matches, matches.is_present("quiet"))
+        {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return;
+    }
+
     let endpoint = {
         let addr = matches.value_of("address").unwrap_or("127.0.0.1");
         let port = value_t!(matches.value_of("port"), u16).unwrap_or(10124);
@@ -439,6 +1926,7 @@ pub fn main() {
 
     let uri: http::Uri = format!("http://{}", endpoint).parse().unwrap();
     let dst = Destination::try_from_uri(uri.clone()).unwrap();
+    let server = endpoint.clone();
     let connector = util::Connector::new(HttpConnector::new(1));
     let settings = client::Builder::new().http2_only(true).clone();
     let mut make_client = client::Connect::with_builder(connector, settings);
@@ -477,6 +1965,32 @@ pub fn main() {
                     ("replica", Some(m)) => {
                         dispatch_replica_cmd(client, &m, verbose, quiet)
                     }
+                    ("template", Some(m)) => {
+                        dispatch_template_cmd(client, &m, verbose, quiet)
+                    }
+                    ("benchmark", Some(m)) => {
+                        benchmark_volume(client, &m, verbose, quiet)
+                    }
+                    ("history", Some(m)) => {
+                        volume_history(client, &m, verbose, quiet)
+                    }
+                    ("quiesce", Some(m)) => quiesce_io(client, &m, verbose),
+                    ("resume", Some(_)) => resume_io(client, verbose),
+                    ("reconcile", Some(m)) => {
+                        reconcile_now(client, &m, verbose, quiet)
+                    }
+                    ("manifest", Some(m)) => {
+                        integrity_manifest(client, &m, verbose)
+                    }
+                    ("import", Some(m)) => import_volume(client, &m, verbose),
+                    ("soak", Some(m)) => soak(client, &m, verbose),
+                    ("logs", Some(m)) => tail_logs(client, &m, verbose),
+                    ("support-bundle", Some(m)) => {
+                        support_bundle(client, &m, verbose)
+                    }
+                    ("state", Some(m)) => {
+                        dispatch_state_cmd(client, &m, server, verbose)
+                    }
                     _ => panic!("unexpected input"),
                 }
             })
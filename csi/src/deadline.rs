@@ -0,0 +1,163 @@
+//! Server-side deadlines of CSI node methods.
+//!
+//! Operators can cap the time a method is allowed to take. When the cap is
+//! reached, the future doing the work is dropped, which cancels whatever
+//! downstream work (json-rpc calls to mayastor, waiting for a device) is in
+//! progress, and the caller gets DEADLINE_EXCEEDED. The caps are applicable
+//! only to methods which do their work asynchronously. Blocking steps (mkfs
+//! in particular) are not interrupted, the deadline is noticed only after
+//! they have finished. A stage which has exceeded its deadline is undone
+//! (unmounted and forgotten) unless the volume had been staged before.
+//!
+//! The deadline of the caller (grpc-timeout header of the request) applies
+//! the same way, so that no work is done for a caller which has given up.
+
+use futures::Future;
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::timer::Timeout;
+use tower_grpc::{Code, Request, Status};
+
+/// Methods which can have a deadline.
+const METHODS: [&str; 3] =
+    ["NodeStageVolume", "NodeUnstageVolume", "NodeGetVolumeStats"];
+
+#[derive(Clone, Debug, Default)]
+pub struct Deadlines {
+    caps: HashMap<String, Duration>,
+}
+
+impl Deadlines {
+    /// Parse deadline specifications in form of METHOD=SECONDS.
+    pub fn parse<'a, I>(specs: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut caps = HashMap::new();
+
+        for spec in specs {
+            let (method, secs) = match spec.find('=') {
+                Some(idx) => (&spec[.. idx], &spec[idx + 1 ..]),
+                None => {
+                    return Err(format!(
+                        "Invalid deadline \"{}\": expected METHOD=SECONDS",
+                        spec
+                    ))
+                }
+            };
+            if !METHODS.contains(&method) {
+                return Err(format!(
+                    "Deadline cannot be set for {} (supported are {})",
+                    method,
+                    METHODS.join(", ")
+                ));
+            }
+            let secs: u64 = match secs.parse() {
+                Ok(secs) if secs > 0 => secs,
+                _ => {
+                    return Err(format!(
+                        "Invalid deadline \"{}\": expected positive number of seconds",
+                        spec
+                    ))
+                }
+            };
+            caps.insert(method.to_owned(), Duration::from_secs(secs));
+        }
+        Ok(Self {
+            caps,
+        })
+    }
+
+    /// Return the caps as METHOD=SECONDS strings.
+    pub fn list(&self) -> Vec<String> {
+        let mut list: Vec<String> = self
+            .caps
+            .iter()
+            .map(|(method, cap)| format!("{}={}", method, cap.as_secs()))
+            .collect();
+        list.sort();
+        list
+    }
+
+    /// Limit the duration of the future if there is a cap for the method or
+    /// a deadline of the caller, whichever is sooner.
+    pub fn apply<F>(
+        &self,
+        method: &str,
+        deadline: Option<Instant>,
+        fut: F,
+    ) -> Box<dyn Future<Item = F::Item, Error = Status> + Send>
+    where
+        F: Future<Error = Status> + Send + 'static,
+    {
+        let cap = self.caps.get(method).cloned();
+        let left = deadline.map(|deadline| {
+            let now = Instant::now();
+            if deadline > now {
+                deadline - now
+            } else {
+                Duration::from_secs(0)
+            }
+        });
+        let (limit, by_caller) = match (cap, left) {
+            (Some(cap), Some(left)) if left < cap => (left, true),
+            (Some(cap), _) => (cap, false),
+            (None, Some(left)) => (left, true),
+            (None, None) => return Box::new(fut),
+        };
+        let method = method.to_owned();
+
+        Box::new(Timeout::new(fut, limit).map_err(move |err| {
+            if err.is_elapsed() {
+                let msg = if by_caller {
+                    format!(
+                        "{} did not complete before the deadline of the caller",
+                        method
+                    )
+                } else {
+                    format!(
+                        "{} did not complete within {}s",
+                        method,
+                        limit.as_secs()
+                    )
+                };
+                error!("{}", msg);
+                Status::new(Code::DeadlineExceeded, msg)
+            } else if err.is_inner() {
+                err.into_inner().unwrap()
+            } else {
+                Status::new(
+                    Code::Internal,
+                    format!("Timer error in {}: {:?}", method, err),
+                )
+            }
+        }))
+    }
+}
+
+/// Return the deadline of the caller given by grpc-timeout header of the
+/// request (i.e. "10S" or "500m") if there is one.
+pub fn request_deadline<T>(request: &Request<T>) -> Option<Instant> {
+    let header = request.metadata().get("grpc-timeout")?.to_str().ok()?;
+    parse_grpc_timeout(header).map(|timeout| Instant::now() + timeout)
+}
+
+/// Parse value of grpc-timeout header: up to 8 digits followed by unit.
+fn parse_grpc_timeout(val: &str) -> Option<Duration> {
+    if val.len() < 2 || val.len() > 9 || !val.is_char_boundary(val.len() - 1) {
+        return None;
+    }
+    let (num, unit) = val.split_at(val.len() - 1);
+    let num: u64 = num.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(num * 3600)),
+        "M" => Some(Duration::from_secs(num * 60)),
+        "S" => Some(Duration::from_secs(num)),
+        "m" => Some(Duration::from_millis(num)),
+        "u" => Some(Duration::from_micros(num)),
+        "n" => Some(Duration::from_nanos(num)),
+        _ => None,
+    }
+}
@@ -1,9 +1,27 @@
+use crate::hostenv;
 use nix::{convert_ioctl_res, libc::ioctl};
 use std::convert::TryInto;
 // include/uapi/linux/fs.h
 const IOCTL_BLKGETSIZE: u32 = ior!(0x12, 114, std::mem::size_of::<u64>());
+const IOCTL_BLKRASET: u32 = io!(0x12, 98);
+const IOCTL_BLKFLSBUF: u32 = io!(0x12, 97);
 
-use std::{fs::OpenOptions, os::unix::io::AsRawFd, path::Path};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, OpenOptions},
+    os::unix::{
+        fs::{FileTypeExt, MetadataExt},
+        io::AsRawFd,
+    },
+    path::Path,
+};
+
+/// Name of the volume parameter for read-ahead of the block device.
+pub const READ_AHEAD_PARAM: &str = "read_ahead_kb";
+/// Name of the volume parameter saying that raw block volume should be
+/// opened with O_DIRECT only.
+pub const DIRECT_IO_PARAM: &str = "direct_io";
 
 pub fn await_size(path: &str) -> Result<usize, String> {
     let device_size = 0;
@@ -41,3 +59,297 @@ pub fn await_size(path: &str) -> Result<usize, String> {
     // no size reported within given time window
     Err("device not ready; invalid size".into())
 }
+
+/// Parse read-ahead parameter of the volume from the publish context or the
+/// volume context (storage class parameters), in that order.
+pub fn read_ahead_param(
+    publish_context: &HashMap<String, String>,
+    volume_context: &HashMap<String, String>,
+) -> Result<Option<u32>, String> {
+    let val = match publish_context
+        .get(READ_AHEAD_PARAM)
+        .or_else(|| volume_context.get(READ_AHEAD_PARAM))
+    {
+        Some(val) => val,
+        None => return Ok(None),
+    };
+    match val.parse() {
+        Ok(kb) => Ok(Some(kb)),
+        Err(_) => Err(format!(
+            "Invalid {} \"{}\": expected number of KiB",
+            READ_AHEAD_PARAM, val
+        )),
+    }
+}
+
+/// Parse direct I/O parameter of the volume from the volume context.
+pub fn direct_io_param(
+    volume_context: &HashMap<String, String>,
+) -> Result<bool, String> {
+    match volume_context.get(DIRECT_IO_PARAM).map(String::as_str) {
+        Some("true") => Ok(true),
+        Some("false") | None => Ok(false),
+        Some(val) => Err(format!(
+            "Invalid {} \"{}\": expected true or false",
+            DIRECT_IO_PARAM, val
+        )),
+    }
+}
+
+/// Set read-ahead of the block device (i.e. /dev/nbd0).
+pub fn set_read_ahead(device: &str, kb: u32) -> Result<(), String> {
+    let name = match Path::new(device).file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return Err(format!("Invalid device path {}", device)),
+    };
+    let queue = Path::new("/sys/block").join(name).join("queue");
+
+    let res = if hostenv::sys_read_only() {
+        set_read_ahead_ioctl(device, kb)
+    } else {
+        sysfs::write_value(&queue, "read_ahead_kb", kb)
+            .map_err(|err| err.to_string())
+    };
+    res.map_err(|err| {
+        format!(
+            "Failed to set read-ahead of {} to {}KiB: {}",
+            device, kb, err
+        )
+    })?;
+    debug!("Read-ahead of {} set to {}KiB", device, kb);
+    Ok(())
+}
+
+/// Set read-ahead by ioctl (in 512-byte sectors) when sysfs is read-only.
+fn set_read_ahead_ioctl(device: &str, kb: u32) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(device)
+        .map_err(|err| err.to_string())?;
+    let res = unsafe {
+        convert_ioctl_res!(ioctl(
+            file.as_raw_fd(),
+            u64::from(IOCTL_BLKRASET).try_into().unwrap(),
+            nix::libc::c_ulong::from(kb) * 2
+        ))
+    };
+    res.map(|_| ()).map_err(|err| err.to_string())
+}
+
+/// Flush the block device (i.e. /dev/nbd0) before it is unstaged. fsync
+/// writes back dirty pages and sends flush to mayastor, which replies only
+/// when all replicas of the nexus have confirmed it, and BLKFLSBUF drops the
+/// page cache of the device so that nothing stale is left behind.
+pub fn flush(device: &str) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(device)
+        .map_err(|err| format!("Failed to open {}: {}", device, err))?;
+    file.sync_all()
+        .map_err(|err| format!("Failed to flush {}: {}", device, err))?;
+    let res = unsafe {
+        convert_ioctl_res!(ioctl(
+            file.as_raw_fd(),
+            u64::from(IOCTL_BLKFLSBUF).try_into().unwrap(),
+            0
+        ))
+    };
+    res.map_err(|err| {
+        format!("Failed to flush buffers of {}: {}", device, err)
+    })?;
+    debug!("Flushed device {}", device);
+    Ok(())
+}
+
+/// Write back the filesystem mounted at the path (syncfs) before a bind
+/// mount of it is removed.
+pub fn sync_fs(path: &str) -> Result<(), String> {
+    let dir = File::open(path)
+        .map_err(|err| format!("Failed to open {}: {}", path, err))?;
+    if unsafe { nix::libc::syncfs(dir.as_raw_fd()) } != 0 {
+        return Err(format!(
+            "Failed to sync filesystem at {}: {}",
+            path,
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Return the device number if the path is a block device node.
+fn block_device_number(path: &str) -> Option<u64> {
+    fs::metadata(path)
+        .ok()
+        .filter(|meta| meta.file_type().is_block_device())
+        .map(|meta| meta.rdev())
+}
+
+/// Return true if the path is a block device node (i.e. the target path of
+/// a published raw block volume).
+pub fn is_block_device(path: &str) -> bool {
+    block_device_number(path).is_some()
+}
+
+/// Return true if both paths are nodes of the same block device.
+pub fn same_block_device(path1: &str, path2: &str) -> bool {
+    match (block_device_number(path1), block_device_number(path2)) {
+        (Some(dev1), Some(dev2)) => dev1 == dev2,
+        _ => false,
+    }
+}
+
+/// Processes which have a block device open, by how they opened it.
+#[derive(Debug, Default, PartialEq)]
+pub struct OpenMode {
+    /// pids of processes with the device opened with O_DIRECT
+    pub direct: Vec<u32>,
+    /// pids of processes with the device opened without O_DIRECT
+    pub buffered: Vec<u32>,
+}
+
+impl fmt::Display for OpenMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pids = |pids: &[u32]| {
+            pids.iter()
+                .map(|pid| pid.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match (self.direct.is_empty(), self.buffered.is_empty()) {
+            (true, true) => write!(f, "not open"),
+            (false, true) => {
+                write!(f, "open with O_DIRECT by pid {}", pids(&self.direct))
+            }
+            (true, false) => {
+                write!(f, "open buffered by pid {}", pids(&self.buffered))
+            }
+            (false, false) => write!(
+                f,
+                "open with O_DIRECT by pid {} and buffered by pid {}",
+                pids(&self.direct),
+                pids(&self.buffered)
+            ),
+        }
+    }
+}
+
+/// Return flags of the open file from its fdinfo in /proc.
+fn fdinfo_flags(fdinfo: &str) -> Option<i32> {
+    fdinfo
+        .lines()
+        .find(|line| line.starts_with("flags:"))
+        .and_then(|line| i32::from_str_radix(line[6 ..].trim(), 8).ok())
+}
+
+/// Return the process which serves the nbd device (i.e. /dev/nbd0). It
+/// keeps the device open as long as it is connected. The kernel knows the
+/// thread which receives the replies, the process is its thread group.
+fn nbd_server(device: &str) -> Option<u32> {
+    let name = Path::new(device).file_name()?.to_str()?;
+    let tid =
+        fs::read_to_string(Path::new("/sys/block").join(name).join("pid"))
+            .ok()?;
+    fs::read_to_string(format!("/proc/{}/status", tid.trim()))
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("Tgid:"))
+        .and_then(|line| line[5 ..].trim().parse().ok())
+}
+
+/// Find processes which have the block device open (other than us and the
+/// nbd server) and how they opened it. Only processes in our pid namespace
+/// can be seen, so the plugin must share the pid namespace of the host to
+/// see the users of published volumes. Processes which go away while they
+/// are being looked at are skipped.
+pub fn open_mode(device: &str) -> Result<OpenMode, String> {
+    let rdev = match block_device_number(device) {
+        Some(rdev) => rdev,
+        None => return Err(format!("{} is not a block device", device)),
+    };
+    let procs = fs::read_dir("/proc")
+        .map_err(|err| format!("Failed to read /proc: {}", err))?;
+    let me = std::process::id();
+    let server = nbd_server(device);
+    let mut mode = OpenMode::default();
+
+    for entry in procs.filter_map(|entry| entry.ok()) {
+        let pid = match entry.file_name().to_str().map(str::parse::<u32>) {
+            Some(Ok(pid)) if pid != me && Some(pid) != server => pid,
+            _ => continue,
+        };
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        let mut direct = None;
+        for fd in fds.filter_map(|fd| fd.ok()) {
+            // the link may lead to a node of the device anywhere (i.e. in
+            // a container), the device number is what counts
+            match fs::metadata(fd.path()) {
+                Ok(ref meta)
+                    if meta.file_type().is_block_device()
+                        && meta.rdev() == rdev => {}
+                _ => continue,
+            }
+            let flags = match fs::read_to_string(
+                entry.path().join("fdinfo").join(fd.file_name()),
+            )
+            .ok()
+            .and_then(|fdinfo| fdinfo_flags(&fdinfo))
+            {
+                Some(flags) => flags,
+                None => continue,
+            };
+            // a process with any buffered descriptor counts as buffered
+            let is_direct = flags & nix::libc::O_DIRECT != 0;
+            direct = Some(direct.unwrap_or(true) && is_direct);
+        }
+        match direct {
+            Some(true) => mode.direct.push(pid),
+            Some(false) => mode.buffered.push(pid),
+            None => (),
+        }
+    }
+    mode.direct.sort();
+    mode.buffered.sort();
+    Ok(mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_flags_are_parsed() {
+        let fdinfo = "pos:\t0\nflags:\t0140002\nmnt_id:\t25\n";
+        let flags = fdinfo_flags(fdinfo).unwrap();
+        assert_eq!(flags & nix::libc::O_ACCMODE, nix::libc::O_RDWR);
+        assert_ne!(flags & nix::libc::O_DIRECT, 0);
+        assert_eq!(fdinfo_flags("pos:\t0\n"), None);
+
+        // our own file
+        let file = File::open("/proc/self/mounts").unwrap();
+        let fdinfo = fs::read_to_string(format!(
+            "/proc/self/fdinfo/{}",
+            file.as_raw_fd()
+        ))
+        .unwrap();
+        let flags = fdinfo_flags(&fdinfo).unwrap();
+        assert_eq!(flags & nix::libc::O_ACCMODE, nix::libc::O_RDONLY);
+        assert_eq!(flags & nix::libc::O_DIRECT, 0);
+    }
+
+    #[test]
+    fn open_mode_is_described() {
+        let mut mode = OpenMode::default();
+        assert_eq!(mode.to_string(), "not open");
+        mode.direct = vec![10, 12];
+        assert_eq!(mode.to_string(), "open with O_DIRECT by pid 10, 12");
+        mode.buffered = vec![11];
+        assert_eq!(
+            mode.to_string(),
+            "open with O_DIRECT by pid 10, 12 and buffered by pid 11"
+        );
+        assert!(open_mode("/proc/self/mounts").is_err());
+    }
+}
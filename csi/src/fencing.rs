@@ -0,0 +1,185 @@
+//! Fencing of stale attachments.
+//!
+//! The controller (moac) keeps a fencing epoch for each volume and bumps it
+//! each time the volume is published. The epoch is part of the export: it is
+//! passed in CreateBlkdev request, which creates the device on the node where
+//! the replica lives, and in the publish context for stage of the volume on
+//! the same node. The node remembers the highest epoch it has seen for each
+//! volume and refuses to export or stage the volume with a lower epoch: such
+//! a request comes from an attachment which has been superseded (i.e. it was
+//! replayed by a stale instance of the controller).
+//!
+//! The epochs are kept in the state directory and survive unstage of the
+//! volume, otherwise an old attachment could be re-staged after a new one
+//! had been unstaged. They are reported in the list of replicas, so that
+//! a restarted controller continues from the highest epoch of the volume.
+
+use crate::staging::check_volume_id;
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// Key of the fencing epoch in the publish context.
+pub const FENCING_EPOCH_PARAM: &str = "fencing_epoch";
+
+/// Subdirectory of the state directory with the epochs.
+const FENCING_DIR: &str = "fencing";
+/// Extension of the files with the epochs. Temporary files have a different
+/// one, so they can't be mistaken for the epoch of a volume.
+const EPOCH_SUFFIX: &str = "epoch";
+
+/// Parse fencing epoch from the publish context. Volumes published by older
+/// versions of the controller don't have it.
+pub fn fencing_epoch_param(
+    publish_context: &HashMap<String, String>,
+) -> Result<Option<u64>, String> {
+    match publish_context.get(FENCING_EPOCH_PARAM) {
+        Some(val) => val.parse::<u64>().map(Some).map_err(|_| {
+            format!("Invalid {} value \"{}\"", FENCING_EPOCH_PARAM, val)
+        }),
+        None => Ok(None),
+    }
+}
+
+/// Highest epochs seen for the volumes - one file per volume.
+#[derive(Clone, Debug)]
+pub struct FencingStore {
+    dir: PathBuf,
+    // serializes check-and-update of the epochs
+    lock: Arc<Mutex<()>>,
+}
+
+impl FencingStore {
+    pub fn new(state_dir: &str) -> Result<Self, String> {
+        let dir = PathBuf::from(state_dir).join(FENCING_DIR);
+        if let Err(err) = fs::create_dir_all(&dir) {
+            return Err(format!(
+                "Failed to create fencing directory {}: {}",
+                dir.display(),
+                err
+            ));
+        }
+        match sysfs::remove_tmp_files(&dir) {
+            Ok(removed) => {
+                for path in removed {
+                    warn!("Removed partially written epoch {}", path.display());
+                }
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Failed to clean up {}: {}",
+                    dir.display(),
+                    err
+                ))
+            }
+        }
+        Ok(Self {
+            dir,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn path(&self, volume_id: &str) -> Result<PathBuf, String> {
+        check_volume_id(volume_id)?;
+        Ok(self.dir.join(format!("{}.{}", volume_id, EPOCH_SUFFIX)))
+    }
+
+    fn read(&self, volume_id: &str) -> Result<Option<u64>, String> {
+        let path = self.path(volume_id)?;
+        match fs::read_to_string(&path) {
+            Ok(data) => data.trim().parse::<u64>().map(Some).map_err(|_| {
+                format!("Invalid fencing epoch in {}", path.display())
+            }),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => {
+                Err(format!("Failed to read {}: {}", path.display(), err))
+            }
+        }
+    }
+
+    fn write(&self, volume_id: &str, epoch: u64) -> Result<(), String> {
+        let path = self.path(volume_id)?;
+
+        sysfs::write_atomic(&path, epoch.to_string().as_bytes()).map_err(
+            |err| format!("Failed to write {}: {}", path.display(), err),
+        )
+    }
+
+    /// Return the highest epoch seen for the volume if any.
+    pub fn get(&self, volume_id: &str) -> Result<Option<u64>, String> {
+        let _guard = self.lock.lock().unwrap();
+
+        self.read(volume_id)
+    }
+
+    /// Check that the epoch is not older than the last one seen for the
+    /// volume and remember it. Return error message if the attachment is
+    /// stale.
+    pub fn check(&self, volume_id: &str, epoch: u64) -> Result<(), String> {
+        let _guard = self.lock.lock().unwrap();
+
+        match self.read(volume_id)? {
+            Some(last) if epoch < last => Err(format!(
+                "Stale attachment of volume {}: fencing epoch {} is older than {}",
+                volume_id, epoch, last
+            )),
+            Some(last) if epoch == last => Ok(()),
+            _ => {
+                self.write(volume_id, epoch)?;
+                debug!("Fencing epoch of volume {} is {}", volume_id, epoch);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn stale_epoch_is_refused() {
+        let dir = env::temp_dir().join("csi-fencing-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FencingStore::new(dir.to_str().unwrap()).unwrap();
+
+        assert_eq!(store.get("vol").unwrap(), None);
+        // export by the controller and stage with the same epoch
+        store.check("vol", 5).unwrap();
+        store.check("vol", 5).unwrap();
+        assert_eq!(store.get("vol").unwrap(), Some(5));
+        // the volume has been published again
+        store.check("vol", 6).unwrap();
+        assert!(store.check("vol", 5).is_err());
+        assert_eq!(store.get("vol").unwrap(), Some(6));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn epochs_of_similar_ids_are_separate() {
+        let dir = env::temp_dir().join("csi-fencing-ids-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = FencingStore::new(dir.to_str().unwrap()).unwrap();
+
+        store.check("a.tmp", 3).unwrap();
+        store.check("a", 1).unwrap();
+        store.check("a.b", 2).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(1));
+        assert_eq!(store.get("a.b").unwrap(), Some(2));
+        assert_eq!(store.get("a.tmp").unwrap(), Some(3));
+
+        // leftover of a write interrupted by a crash
+        let tmp_path = sysfs::tmp_path(&store.path("a").unwrap());
+        fs::write(&tmp_path, "4").unwrap();
+        let store = FencingStore::new(dir.to_str().unwrap()).unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(store.get("a").unwrap(), Some(1));
+        assert_eq!(store.get("a.tmp").unwrap(), Some(3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,352 @@
+//! Filesystem features compatible with kernels of the nodes.
+//!
+//! Recent mkfs tools enable filesystem features by default (i.e. ext4
+//! metadata_csum or xfs bigtime) which older kernels refuse to mount with
+//! "unsupported feature". A volume formatted on a node with a new kernel
+//! could not be staged on a node with an old one, nor on the same node after
+//! its OS has been downgraded. Features which are not supported by the target
+//! kernel (the oldest kernel in the cluster set by `--fs-compat-kernel`, the
+//! running kernel by default) are disabled when formatting. Before the
+//! filesystem is mounted its features are checked against the running
+//! kernel, so that staging fails with an error saying which features are the
+//! problem instead of a failed mount, and they are recorded in the staging
+//! record.
+
+use crate::fshelper::{self, FsTool};
+use std::{fmt, fs, sync::RwLock};
+
+/// Kernel version (major and minor number).
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct KernelVersion(pub u32, pub u32);
+
+impl KernelVersion {
+    /// Parse version like "4.15" or kernel release like "4.15.0-55-generic".
+    pub fn parse(version: &str) -> Result<Self, String> {
+        let mut parts = version.trim().splitn(3, |c: char| !c.is_ascii_digit());
+        let mut number = || -> Option<u32> { parts.next()?.parse().ok() };
+        match (number(), number()) {
+            (Some(major), Some(minor)) => Ok(KernelVersion(major, minor)),
+            _ => Err(format!("Invalid kernel version \"{}\"", version)),
+        }
+    }
+}
+
+impl fmt::Display for KernelVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+/// Features of ext4 (as named by mke2fs) which have not always been
+/// supported by the kernel and the version which supports them.
+const EXT4_FEATURES: &[(&str, KernelVersion)] = &[
+    ("inline_data", KernelVersion(3, 8)),
+    ("sparse_super2", KernelVersion(3, 16)),
+    ("metadata_csum", KernelVersion(3, 18)),
+    ("encrypt", KernelVersion(4, 1)),
+    ("metadata_csum_seed", KernelVersion(4, 4)),
+    ("project", KernelVersion(4, 5)),
+    ("ea_inode", KernelVersion(4, 13)),
+    ("large_dir", KernelVersion(4, 13)),
+    ("casefold", KernelVersion(5, 2)),
+    ("verity", KernelVersion(5, 4)),
+    ("stable_inodes", KernelVersion(5, 5)),
+    ("fast_commit", KernelVersion(5, 10)),
+    ("orphan_file", KernelVersion(5, 15)),
+];
+
+/// Features of xfs (as named by mkfs.xfs) which have not always been
+/// supported by the kernel: mkfs.xfs option which sets it, its name in
+/// output of xfs_db version command and the version which supports it.
+const XFS_FEATURES: &[(&str, &str, &str, KernelVersion)] = &[
+    ("crc", "-m", "crc", KernelVersion(3, 15)),
+    ("finobt", "-m", "finobt", KernelVersion(3, 16)),
+    ("sparse", "-i", "sparse_inodes", KernelVersion(4, 2)),
+    ("rmapbt", "-m", "rmapbt", KernelVersion(4, 8)),
+    ("reflink", "-m", "reflink", KernelVersion(4, 9)),
+    ("bigtime", "-m", "bigtime", KernelVersion(5, 10)),
+    ("inobtcount", "-m", "inobtcnt", KernelVersion(5, 10)),
+    ("nrext64", "-i", "nrext64", KernelVersion(5, 19)),
+];
+
+/// Features enabled by mke2fs for ext4 if its config does not say.
+const EXT4_DEFAULT_FEATURES: &[&str] = &["metadata_csum", "64bit"];
+
+/// Config of mke2fs with the features enabled by default.
+const MKE2FS_CONF: &str = "/etc/mke2fs.conf";
+
+lazy_static! {
+    static ref TARGET: RwLock<Option<KernelVersion>> = RwLock::new(None);
+}
+
+/// Set the version of the oldest kernel which should be able to mount the
+/// filesystems (None for the running kernel).
+pub fn configure(target: Option<&str>) -> Result<(), String> {
+    let target = match target {
+        Some(version) => {
+            let version = KernelVersion::parse(version)?;
+            info!("Filesystems are formatted for kernel {}", version);
+            Some(version)
+        }
+        None => None,
+    };
+    *TARGET.write().unwrap() = target;
+    Ok(())
+}
+
+/// Return the configured target kernel version (None for the running
+/// kernel).
+pub fn target() -> Option<KernelVersion> {
+    *TARGET.read().unwrap()
+}
+
+/// Return the version of the running kernel.
+pub fn kernel_version() -> Result<KernelVersion, String> {
+    let release = fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map_err(|err| format!("Failed to get kernel version: {}", err))?;
+    KernelVersion::parse(&release)
+}
+
+/// Return the kernel version required by the feature of the filesystem (None
+/// if it is supported by any kernel that matters).
+fn required_version(fstype: &str, feature: &str) -> Option<KernelVersion> {
+    match fstype {
+        "ext4" => EXT4_FEATURES
+            .iter()
+            .find(|(name, _)| *name == feature)
+            .map(|(_, version)| *version),
+        "xfs" => XFS_FEATURES
+            .iter()
+            .find(|(name, ..)| *name == feature)
+            .map(|(.., version)| *version),
+        _ => None,
+    }
+}
+
+/// Return ext4 features which mke2fs enables by default, as found in the
+/// fs_types section of its config.
+fn ext4_default_features() -> Vec<String> {
+    let conf = match fs::read_to_string(MKE2FS_CONF) {
+        Ok(conf) => conf,
+        Err(_) => {
+            return EXT4_DEFAULT_FEATURES
+                .iter()
+                .map(|f| (*f).to_owned())
+                .collect()
+        }
+    };
+    let mut features = Vec::new();
+    let mut stanza = "";
+    for line in conf.lines() {
+        let line = line.trim();
+        if line.ends_with('{') {
+            stanza = line
+                .trim_end_matches('{')
+                .trim_end()
+                .trim_end_matches('=')
+                .trim_end();
+        } else if line == "}" {
+            stanza = "";
+        }
+        let value = match line.find('=') {
+            Some(idx)
+                if line[.. idx].trim() == "base_features"
+                    || (stanza == "ext4"
+                        && line[.. idx].trim() == "features") =>
+            {
+                &line[idx + 1 ..]
+            }
+            _ => continue,
+        };
+        for feature in value.split(',').map(|f| f.trim()) {
+            if !feature.is_empty() && !feature.starts_with('^') {
+                features.push(feature.to_owned());
+            }
+        }
+    }
+    features
+}
+
+/// Return xfs features which mkfs.xfs would enable on the device, as printed
+/// by its dry run.
+fn xfs_default_features(device: &str) -> Result<Vec<String>, String> {
+    let output = fshelper::command(FsTool::Mkfs, "xfs")
+        .arg("-N")
+        .arg(device)
+        .output()
+        .map_err(|err| format!("Failed to execute mkfs.xfs: {}", err))?;
+    if !output.status.success() {
+        return Err(format!(
+            "Dry run of mkfs.xfs on {} failed: {}",
+            device,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|opt| opt.ends_with("=1"))
+        .map(|opt| opt.trim_end_matches("=1").to_owned())
+        .collect())
+}
+
+/// Return options of mkfs which disable features enabled by default, which
+/// the target kernel does not support.
+pub fn mkfs_options(fstype: &str, device: &str) -> Vec<String> {
+    let target = match target().map(Ok).unwrap_or_else(kernel_version) {
+        Ok(version) => version,
+        Err(err) => {
+            warn!("{}, formatting with default features", err);
+            return Vec::new();
+        }
+    };
+    let defaults = match fstype {
+        "ext4" => ext4_default_features(),
+        "xfs" => match xfs_default_features(device) {
+            Ok(features) => features,
+            Err(err) => {
+                warn!("{}, formatting with default features", err);
+                return Vec::new();
+            }
+        },
+        _ => return Vec::new(),
+    };
+    let disabled: Vec<&String> = defaults
+        .iter()
+        .filter(|feature| {
+            required_version(fstype, feature)
+                .map(|version| version > target)
+                .unwrap_or(false)
+        })
+        .collect();
+    if disabled.is_empty() {
+        return Vec::new();
+    }
+    info!(
+        "Disabling features {:?} of {} not supported by kernel {}",
+        disabled, fstype, target
+    );
+
+    let mut options = Vec::new();
+    if fstype == "ext4" {
+        let list: Vec<String> =
+            disabled.iter().map(|f| format!("^{}", f)).collect();
+        options.push("-O".to_owned());
+        options.push(list.join(","));
+    } else {
+        for section in &["-m", "-i"] {
+            let list: Vec<String> = XFS_FEATURES
+                .iter()
+                .filter(|(name, sect, ..)| {
+                    sect == section && disabled.iter().any(|f| f == name)
+                })
+                .map(|(name, ..)| format!("{}=0", name))
+                .collect();
+            if !list.is_empty() {
+                options.push((*section).to_owned());
+                options.push(list.join(","));
+            }
+        }
+    }
+    options
+}
+
+/// Return features of the filesystem on the device.
+pub fn features(device: &str, fstype: &str) -> Result<Vec<String>, String> {
+    let mut cmd = fshelper::command(FsTool::Info, fstype);
+    if fstype == "xfs" {
+        cmd.args(&["-r", "-c", "version"]);
+    } else {
+        cmd.arg("-h");
+    }
+    let output = cmd.arg(device).output().map_err(|err| {
+        format!(
+            "Failed to get features of {} on {}: {}",
+            fstype, device, err
+        )
+    })?;
+    if !output.status.success() {
+        return Err(format!(
+            "Failed to get features of {} on {}: {}",
+            fstype,
+            device,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    if fstype == "xfs" {
+        // versionnum [0xb4a5+0x18a] = V5,NLINK,DIRV2,...,CRC,FTYPE,FINOBT
+        let flags = stdout
+            .lines()
+            .find(|line| line.starts_with("versionnum"))
+            .and_then(|line| line.splitn(2, '=').nth(1))
+            .unwrap_or("");
+        Ok(flags
+            .split(',')
+            .map(|flag| {
+                let flag = flag.trim().to_lowercase();
+                XFS_FEATURES
+                    .iter()
+                    .find(|(.., token, _)| *token == flag)
+                    .map(|(name, ..)| (*name).to_owned())
+                    .unwrap_or(flag)
+            })
+            .filter(|flag| !flag.is_empty())
+            .collect())
+    } else {
+        Ok(stdout
+            .lines()
+            .find(|line| line.starts_with("Filesystem features:"))
+            .map(|line| {
+                line["Filesystem features:".len() ..]
+                    .split_whitespace()
+                    .map(String::from)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+/// Check that the kernel supports all features of the filesystem.
+pub fn check(
+    fstype: &str,
+    features: &[String],
+    kernel: KernelVersion,
+) -> Result<(), String> {
+    let unsupported: Vec<String> = features
+        .iter()
+        .filter_map(|feature| {
+            required_version(fstype, feature)
+                .filter(|version| *version > kernel)
+                .map(|version| format!("{} (since {})", feature, version))
+        })
+        .collect();
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "Kernel {} does not support features of {} filesystem: {}",
+            kernel,
+            fstype,
+            unsupported.join(", ")
+        ))
+    }
+}
+
+/// Return features of the filesystem on the device if the running kernel
+/// supports them. If they can't be found out, the mount is left to tell.
+pub fn verify(device: &str, fstype: &str) -> Result<Vec<String>, String> {
+    let features = match features(device, fstype) {
+        Ok(features) => features,
+        Err(err) => {
+            warn!("{}", err);
+            return Ok(Vec::new());
+        }
+    };
+    match kernel_version() {
+        Ok(kernel) => check(fstype, &features, kernel)
+            .map_err(|err| format!("Cannot mount {}: {}", device, err))?,
+        Err(err) => warn!("{}", err),
+    }
+    Ok(features)
+}
@@ -0,0 +1,96 @@
+//! Delegation of filesystem tools (mkfs, info) to external helpers.
+//!
+//! By default the tools are executed directly from the image of the plugin.
+//! If the image lacks tools for a filesystem (i.e. xfsprogs), a helper can be
+//! configured for the filesystem type. The helper is a command prefix which
+//! is prepended to the tool invocation, so it can be a binary on the host
+//! (`nsenter -t 1 -m --`) as well as exec into a container with the tools
+//! (`kubectl exec fs-tools --`). The helper must see the same device files
+//! and /tmp as the plugin.
+
+use std::{collections::HashMap, process::Command, sync::RwLock};
+
+lazy_static! {
+    static ref HELPERS: RwLock<HashMap<String, Vec<String>>> =
+        RwLock::new(HashMap::new());
+}
+
+/// Filesystem tools which can be delegated.
+#[derive(Debug, Clone, Copy)]
+pub enum FsTool {
+    Mkfs,
+    Info,
+}
+
+impl FsTool {
+    /// Name of the program implementing the tool for given filesystem.
+    fn program(self, fstype: &str) -> String {
+        match (self, fstype) {
+            (FsTool::Mkfs, _) => format!("mkfs.{}", fstype),
+            (FsTool::Info, "xfs") => "xfs_db".to_owned(),
+            (FsTool::Info, _) => "dumpe2fs".to_owned(),
+        }
+    }
+}
+
+/// Parse helper specifications in form of FSTYPE=COMMAND and make them
+/// effective.
+pub fn configure<'a, I>(specs: I) -> Result<(), String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut helpers = HashMap::new();
+
+    for spec in specs {
+        let (fstype, cmd) = match spec.find('=') {
+            Some(idx) => (&spec[.. idx], &spec[idx + 1 ..]),
+            None => {
+                return Err(format!(
+                    "Invalid fs helper \"{}\": expected FSTYPE=COMMAND",
+                    spec
+                ))
+            }
+        };
+        let argv: Vec<String> =
+            cmd.split_whitespace().map(String::from).collect();
+        if fstype.is_empty() || argv.is_empty() {
+            return Err(format!(
+                "Invalid fs helper \"{}\": expected FSTYPE=COMMAND",
+                spec
+            ));
+        }
+        info!("Filesystem tools for {} are run by \"{}\"", fstype, cmd);
+        helpers.insert(fstype.to_owned(), argv);
+    }
+
+    *HELPERS.write().unwrap() = helpers;
+    Ok(())
+}
+
+/// Return configured helpers as FSTYPE=COMMAND strings.
+pub fn list() -> Vec<String> {
+    let mut list: Vec<String> = HELPERS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(fstype, argv)| format!("{}={}", fstype, argv.join(" ")))
+        .collect();
+    list.sort();
+    list
+}
+
+/// Create command for running the tool for given filesystem, which goes
+/// through the helper if there is one.
+pub fn command(tool: FsTool, fstype: &str) -> Command {
+    let program = tool.program(fstype);
+
+    match HELPERS.read().unwrap().get(fstype) {
+        Some(argv) => {
+            debug!("Delegating {} to \"{}\"", program, argv.join(" "));
+            let mut cmd = Command::new(&argv[0]);
+            cmd.args(&argv[1 ..]).arg(program);
+            cmd
+        }
+        None => Command::new(program),
+    }
+}
@@ -0,0 +1,370 @@
+//! Timeline of operations on volumes.
+//!
+//! The last few lifecycle operations (stage, publish, unpublish, unstage) of
+//! each volume are kept in the state directory together with their result,
+//! so that questions like "why was my volume remounted at 3am" can be
+//! answered after the fact by GetVolumeHistory method of the mayastor
+//! service. The history survives unstage of the volume and restarts of the
+//! plugin.
+//!
+//! The node is not told when a volume is deleted, so the history of a volume
+//! which is not staged and has not been touched for `HISTORY_RETENTION` is
+//! considered to be history of a deleted volume and it is removed.
+
+use crate::{
+    rpc::mayastor::VolumeOperation,
+    staging::{check_volume_id, StagingStore},
+};
+use chrono::Local;
+use futures::Future;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tower_grpc::Status;
+
+/// Subdirectory of the state directory with the history.
+const HISTORY_DIR: &str = "history";
+/// Extension of the files with the history. Temporary files have a
+/// different one, so they can't be mistaken for history of a volume.
+const HISTORY_SUFFIX: &str = "json";
+/// How many operations are kept for each volume.
+const HISTORY_SIZE: usize = 32;
+/// How long the history of a volume which is not staged is kept after its
+/// last operation.
+const HISTORY_RETENTION: Duration = Duration::from_secs(7 * 24 * 3600);
+
+/// Keys in the volume context of publish call identifying the pod (set only
+/// if the CSI driver object asks for pod info on mount).
+const POD_NAME_KEY: &str = "csi.storage.k8s.io/pod.name";
+const POD_NAMESPACE_KEY: &str = "csi.storage.k8s.io/pod.namespace";
+
+/// Operation as it is stored in the state directory.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Entry {
+    /// when the operation finished (RFC 3339)
+    timestamp: String,
+    /// CSI method
+    operation: String,
+    /// staging or target path
+    path: String,
+    /// who asked for the operation (pod for publish if known)
+    requester: String,
+    /// "OK" or gRPC code and message of the error
+    result: String,
+}
+
+impl From<Entry> for VolumeOperation {
+    fn from(entry: Entry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            operation: entry.operation,
+            path: entry.path,
+            requester: entry.requester,
+            result: entry.result,
+        }
+    }
+}
+
+/// Return "namespace/name" of the pod from volume context of publish call
+/// or empty string if the pod is not known.
+pub fn pod_requester(volume_context: &HashMap<String, String>) -> String {
+    match (
+        volume_context.get(POD_NAMESPACE_KEY),
+        volume_context.get(POD_NAME_KEY),
+    ) {
+        (Some(ns), Some(name)) => format!("{}/{}", ns, name),
+        (None, Some(name)) => name.to_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Operations on volumes - one json file per volume with the most recent
+/// operation last.
+#[derive(Clone, Debug)]
+pub struct History {
+    dir: PathBuf,
+    // tells which volumes are staged
+    staging: StagingStore,
+    // serializes read-modify-write of the files
+    lock: Arc<Mutex<()>>,
+}
+
+impl History {
+    pub fn new(state_dir: &str, staging: StagingStore) -> Result<Self, String> {
+        let dir = PathBuf::from(state_dir).join(HISTORY_DIR);
+        if let Err(err) = fs::create_dir_all(&dir) {
+            return Err(format!(
+                "Failed to create history directory {}: {}",
+                dir.display(),
+                err
+            ));
+        }
+        match sysfs::remove_tmp_files(&dir) {
+            Ok(removed) => {
+                for path in removed {
+                    warn!(
+                        "Removed partially written history {}",
+                        path.display()
+                    );
+                }
+            }
+            Err(err) => {
+                return Err(format!(
+                    "Failed to clean up {}: {}",
+                    dir.display(),
+                    err
+                ))
+            }
+        }
+        let history = Self {
+            dir,
+            staging,
+            lock: Arc::new(Mutex::new(())),
+        };
+        history.prune(HISTORY_RETENTION);
+        Ok(history)
+    }
+
+    fn path(&self, volume_id: &str) -> Result<PathBuf, String> {
+        check_volume_id(volume_id)?;
+        Ok(self.dir.join(format!("{}.{}", volume_id, HISTORY_SUFFIX)))
+    }
+
+    fn read(&self, volume_id: &str) -> Result<VecDeque<Entry>, String> {
+        let path = self.path(volume_id)?;
+        match fs::read_to_string(&path) {
+            Ok(data) => serde_json::from_str(&data).map_err(|err| {
+                format!("Invalid history in {}: {}", path.display(), err)
+            }),
+            Err(ref err) if err.kind() == ErrorKind::NotFound => {
+                Ok(VecDeque::new())
+            }
+            Err(err) => {
+                Err(format!("Failed to read {}: {}", path.display(), err))
+            }
+        }
+    }
+
+    fn write(
+        &self,
+        volume_id: &str,
+        entries: &VecDeque<Entry>,
+    ) -> Result<(), String> {
+        let path = self.path(volume_id)?;
+
+        sysfs::write_atomic(&path, &serde_json::to_vec(entries).unwrap())
+            .map_err(|err| {
+                format!("Failed to write {}: {}", path.display(), err)
+            })
+    }
+
+    /// Remove the history of volumes which are not staged and have not been
+    /// touched for longer than the retention period.
+    fn prune(&self, retention: Duration) {
+        let _guard = self.lock.lock().unwrap();
+
+        let staged = match self.staging.list() {
+            Ok(records) => records,
+            Err(reason) => {
+                warn!("Not pruning volume history: {}", reason);
+                return;
+            }
+        };
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!("Failed to read {}: {}", self.dir.display(), err);
+                return;
+            }
+        };
+        let now = SystemTime::now();
+
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.extension().map_or(true, |ext| ext != HISTORY_SUFFIX) {
+                continue;
+            }
+            let volume_id = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(volume_id) => volume_id.to_owned(),
+                None => continue,
+            };
+            if staged.iter().any(|r| r.volume_id == volume_id) {
+                continue;
+            }
+            let age = entry
+                .metadata()
+                .and_then(|meta| meta.modified())
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok());
+            if age.map_or(false, |age| age >= retention) {
+                match fs::remove_file(&path) {
+                    Ok(_) => {
+                        debug!("Removed history of volume {}", volume_id)
+                    }
+                    Err(err) => {
+                        warn!("Failed to remove {}: {}", path.display(), err)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Append the operation to the history of the volume and drop the oldest
+    /// ones beyond the limit. Damaged history is started from scratch.
+    fn append(&self, volume_id: &str, entry: Entry) -> Result<(), String> {
+        let guard = self.lock.lock().unwrap();
+
+        let mut entries = self.read(volume_id).unwrap_or_else(|reason| {
+            warn!("{}", reason);
+            VecDeque::new()
+        });
+        // first operation of a volume is a good time to forget about the
+        // deleted ones
+        let new_volume = entries.is_empty();
+        entries.push_back(entry);
+        while entries.len() > HISTORY_SIZE {
+            entries.pop_front();
+        }
+        self.write(volume_id, &entries)?;
+        drop(guard);
+        if new_volume {
+            self.prune(HISTORY_RETENTION);
+        }
+        Ok(())
+    }
+
+    /// Return operations on the volume (oldest first).
+    pub fn get(&self, volume_id: &str) -> Result<Vec<VolumeOperation>, String> {
+        let _guard = self.lock.lock().unwrap();
+
+        Ok(self
+            .read(volume_id)?
+            .into_iter()
+            .map(VolumeOperation::from)
+            .collect())
+    }
+
+    /// Record the operation with its result when the future completes.
+    /// Failure to record it is not a failure of the operation.
+    pub fn track<T, F>(
+        &self,
+        volume_id: String,
+        operation: &'static str,
+        path: String,
+        requester: String,
+        fut: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        let history = self.clone();
+
+        Box::new(fut.then(move |res| {
+            let entry = Entry {
+                timestamp: Local::now()
+                    .format("%Y-%m-%dT%H:%M:%S%.3f%:z")
+                    .to_string(),
+                operation: operation.to_owned(),
+                path,
+                requester,
+                result: match &res {
+                    Ok(_) => "OK".to_owned(),
+                    Err(status) => {
+                        format!("{:?}: {}", status.code(), status.message())
+                    }
+                },
+            };
+            if let Err(reason) = history.append(&volume_id, entry) {
+                warn!(
+                    "Failed to record {} of {}: {}",
+                    operation, volume_id, reason
+                );
+            }
+            res
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::StagingRecord;
+    use std::env;
+
+    fn entry(operation: &str) -> Entry {
+        Entry {
+            timestamp: String::new(),
+            operation: operation.to_owned(),
+            path: "/stage".to_owned(),
+            requester: String::new(),
+            result: "OK".to_owned(),
+        }
+    }
+
+    fn open(name: &str) -> (PathBuf, StagingStore, History) {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let state_dir = dir.to_str().unwrap();
+        let staging = StagingStore::new(state_dir).unwrap();
+        let history = History::new(state_dir, staging.clone()).unwrap();
+        (dir, staging, history)
+    }
+
+    #[test]
+    fn history_is_trimmed() {
+        let (dir, _, history) = open("csi-history-trim-test");
+
+        for i in 0 .. HISTORY_SIZE + 5 {
+            history.append("vol", entry(&i.to_string())).unwrap();
+        }
+        let ops = history.get("vol").unwrap();
+        assert_eq!(ops.len(), HISTORY_SIZE);
+        assert_eq!(ops[0].operation, "5");
+        assert_eq!(
+            ops[HISTORY_SIZE - 1].operation,
+            (HISTORY_SIZE + 4).to_string()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn damaged_history_is_started_again() {
+        let (dir, _, history) = open("csi-history-damaged-test");
+
+        history.append("vol", entry("NodeStageVolume")).unwrap();
+        fs::write(history.path("vol").unwrap(), "[{\"timest").unwrap();
+        assert!(history.get("vol").is_err());
+        history.append("vol", entry("NodeUnstageVolume")).unwrap();
+        let ops = history.get("vol").unwrap();
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].operation, "NodeUnstageVolume");
+        assert!(history.append("../vol", entry("NodeStageVolume")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn history_of_unstaged_volumes_is_pruned() {
+        let (dir, staging, history) = open("csi-history-prune-test");
+        let record =
+            StagingRecord::new("staged", "/stage", "/dev/nbd0", "xfs", &[]);
+        staging.save(&record).unwrap();
+
+        history.append("staged", entry("NodeStageVolume")).unwrap();
+        history
+            .append("unstaged", entry("NodeUnstageVolume"))
+            .unwrap();
+        history.prune(HISTORY_RETENTION);
+        assert_eq!(history.get("unstaged").unwrap().len(), 1);
+        history.prune(Duration::from_secs(0));
+        assert_eq!(history.get("staged").unwrap().len(), 1);
+        assert!(history.get("unstaged").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
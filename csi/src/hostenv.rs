@@ -0,0 +1,73 @@
+//! Detection of restricted host environments.
+//!
+//! Security policies commonly mask parts of /proc or mount /sys read-only in
+//! containers. Rather than failing sporadically deep in the stage path, the
+//! environment is checked at startup: what we cannot work without (the mount
+//! table in /proc and block devices in /sys) makes the server exit with
+//! instructions how to fix the deployment, and what we can work around
+//! (read-only /sys) is remembered, so that alternative ways are used later.
+
+use nix::sys::statvfs::{statvfs, FsFlags};
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Mount table of our mount namespace.
+const PROC_MOUNTS: &str = "/proc/self/mounts";
+/// Block devices known to the kernel.
+const SYS_BLOCK: &str = "/sys/class/block";
+
+/// Set if /sys is mounted read-only.
+static SYS_READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Message explaining that the mount table is not available and how to fix
+/// it.
+pub fn proc_unavailable(reason: &str) -> String {
+    format!(
+        "Mount table {} is not available ({}): /proc is masked. \
+         Run the container privileged or with procMount: Unmasked in its \
+         securityContext (docker: --security-opt systempaths=unconfined)",
+        PROC_MOUNTS, reason
+    )
+}
+
+/// Check that the environment provides everything what we need. The error
+/// describes what is wrong and how to remedy it.
+pub fn check() -> Result<(), String> {
+    match fs::read_to_string(PROC_MOUNTS) {
+        // a masked file is an empty file (bind mount of /dev/null)
+        Ok(ref mounts) if mounts.trim().is_empty() => {
+            return Err(proc_unavailable("it is empty"))
+        }
+        Ok(_) => (),
+        Err(err) => return Err(proc_unavailable(&err.to_string())),
+    }
+
+    if let Err(err) = fs::read_dir(SYS_BLOCK) {
+        return Err(format!(
+            "Block devices in {} are not available ({}): /sys is masked. \
+             Mount /sys of the host to the container (hostPath volume) or \
+             run the container privileged",
+            SYS_BLOCK, err
+        ));
+    }
+
+    match statvfs("/sys") {
+        Ok(stat) if stat.flags().contains(FsFlags::ST_RDONLY) => {
+            warn!(
+                "/sys is mounted read-only: read-ahead of devices is set by \
+                 ioctl instead"
+            );
+            SYS_READ_ONLY.store(true, Ordering::SeqCst);
+        }
+        Ok(_) => (),
+        Err(err) => warn!("Cannot tell if /sys is read-only: {}", err),
+    }
+    Ok(())
+}
+
+/// Return true if /sys is mounted read-only.
+pub fn sys_read_only() -> bool {
+    SYS_READ_ONLY.load(Ordering::SeqCst)
+}
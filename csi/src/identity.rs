@@ -1,11 +1,30 @@
 //! Implementation of gRPC methods from CSI Identity gRPC service.
+//!
+//! If keep-alive of the connection to mayastor is enabled
+//! (`--rpc-keepalive`), a persistent json-rpc client checks its health in the
+//! background and Probe reports not ready right away while it is unhealthy.
 
-use super::csi::*;
-use futures::{future, Future};
-use std::{boxed::Box, collections::HashMap};
+use super::{csi::*, deadline::request_deadline};
+use futures::{
+    future::{self, Either, Loop},
+    Future,
+};
+use std::{
+    boxed::Box,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
 use tower_grpc::{Request, Response, Status};
 
-use jsonrpc::{self, error::Error as JsRpcError};
+use jsonrpc::{
+    self,
+    error::Error as JsRpcError,
+    HealthEvent,
+    RetryPolicy,
+    RpcClient,
+};
 
 const PLUGIN_NAME: &str = "io.openebs.csi-mayastor";
 // TODO: can we generate version with commit SHA dynamically?
@@ -14,6 +33,59 @@ const PLUGIN_VERSION: &str = "0.1";
 #[derive(Clone, Debug)]
 pub struct Identity {
     pub socket: String,
+    /// why the connection to mayastor is unhealthy (None if it is healthy or
+    /// it is not checked)
+    pub health: Arc<Mutex<Option<String>>>,
+}
+
+/// Keep a persistent connection to mayastor with periodic health checks and
+/// record its health. Connecting is retried until mayastor comes up. The
+/// future does not complete.
+pub fn watch_health(
+    socket: String,
+    interval: Duration,
+    health: Arc<Mutex<Option<String>>>,
+) -> impl Future<Item = (), Error = ()> + Send {
+    future::loop_fn((), move |()| {
+        let recorded = Arc::clone(&health);
+        let health = Arc::clone(&health);
+
+        RpcClient::builder()
+            .socket(&socket)
+            .retry(RetryPolicy::none())
+            .keepalive(interval)
+            .on_health(move |event| {
+                let mut health = recorded.lock().unwrap();
+                match event {
+                    HealthEvent::Unhealthy(reason) => {
+                        *health = Some(reason.clone())
+                    }
+                    HealthEvent::Healthy => *health = None,
+                    HealthEvent::Reconnected => (),
+                }
+            })
+            .build()
+            .then(move |res| match res {
+                // the client reconnects by itself from now on
+                Ok(client) => {
+                    *health.lock().unwrap() = None;
+                    Either::A(future::empty().then(
+                        move |res: Result<(), ()>| {
+                            drop(client);
+                            res.map(Loop::Break)
+                        },
+                    ))
+                }
+                Err(err) => {
+                    debug!("Failed to connect to mayastor: {}", err);
+                    *health.lock().unwrap() = Some(err.to_string());
+                    Either::B(
+                        Delay::new(Instant::now() + interval)
+                            .then(|_| Ok(Loop::Continue(()))),
+                    )
+                }
+            })
+    })
 }
 
 impl server::Identity for Identity {
@@ -63,11 +135,24 @@ impl server::Identity for Identity {
         }))
     }
 
-    fn probe(&mut self, _request: Request<ProbeRequest>) -> Self::ProbeFuture {
-        let f = jsonrpc::call::<(), bool>(
+    fn probe(&mut self, request: Request<ProbeRequest>) -> Self::ProbeFuture {
+        if let Some(reason) = self.health.lock().unwrap().as_ref() {
+            warn!("Probe request: mayastor is unhealthy: {}", reason);
+            return Box::new(future::ok(Response::new(ProbeResponse {
+                ready: Some(false),
+            })));
+        }
+
+        // probe should report not ready right away instead of waiting for
+        // mayastor to come up
+        let mut options =
+            jsonrpc::CallOptions::default().retry(jsonrpc::RetryPolicy::none());
+        options.deadline = request_deadline(&request);
+        let f = jsonrpc::call_with_options::<(), bool>(
             &self.socket,
             "wait_subsystem_init",
             None,
+            options,
         )
         .then(move |result| match result {
             Ok(val) => {
@@ -76,7 +161,7 @@ impl server::Identity for Identity {
                     ready: Some(val),
                 }))
             }
-            Err(err) => match err {
+            Err(err) => match err.root() {
                 JsRpcError::ConnectError {
                     ..
                 } => {
@@ -0,0 +1,394 @@
+//! Import of data from other storage systems by block-level copy.
+//!
+//! The data of a volume of another CSI driver (hostPath, local PV, ...) are
+//! imported by copying its block device or image file on the node to a new
+//! replica. The replica is exported on an nbd device for the time of the
+//! copy. The copy can be throttled, so that it does not starve applications
+//! on the node, and chunks full of zeros are skipped, because the replica is
+//! thin provisioned and reads zeros where nothing has been written. When the
+//! copy is done, the replica is read back bypassing the page cache and its
+//! checksum is compared to the checksum of the source. The replica is
+//! destroyed if anything fails (unless it existed before the import). The
+//! source must not be in use meanwhile, which is checked only for mounts.
+//!
+//! The plugin reads the source as root, so the source must be a block device
+//! or a file within one of the directories allowed by `--import-root` (none
+//! by default), with symbolic links resolved.
+
+use crate::{
+    manifest::to_hex,
+    mount::find_mounts,
+    nbd,
+    rpc::mayastor::{
+        CreateBlkdevRequest,
+        DestroyBlkdevRequest,
+        ImportVolumeReply,
+        ImportVolumeRequest,
+    },
+};
+use enclose::enclose;
+use futures::{future, sync::oneshot, Future};
+use rpc::jsonrpc as jsondata;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::{FileTypeExt, OpenOptionsExt},
+    path::PathBuf,
+    thread,
+    time::{Duration, Instant},
+};
+use tower_grpc::{Code, Status};
+
+/// Size of the chunks which the data are copied in.
+const CHUNK_SIZE: usize = 1024 * 1024;
+/// Alignment of the buffer and size of reads required by O_DIRECT.
+const BLOCK_SIZE: usize = 4096;
+
+/// Sleep so that the average rate since the start does not exceed the
+/// limit (bytes per second, zero is unlimited).
+fn throttle(started: Instant, done: u64, rate: u64) {
+    if rate == 0 {
+        return;
+    }
+    let due = Duration::from_millis(done * 1000 / rate);
+    let elapsed = started.elapsed();
+    if due > elapsed {
+        thread::sleep(due - elapsed);
+    }
+}
+
+/// Check that the source is a block device or a file within one of the
+/// allowed directories and return its path with symbolic links resolved.
+pub fn check_source(source: &str, roots: &[PathBuf]) -> Result<String, String> {
+    let path = fs::canonicalize(source)
+        .map_err(|err| format!("Failed to resolve {}: {}", source, err))?;
+    let meta = fs::metadata(&path)
+        .map_err(|err| format!("Failed to stat {}: {}", path.display(), err))?;
+    let allowed = if meta.file_type().is_block_device() {
+        path.starts_with("/dev")
+    } else if meta.is_file() {
+        roots.iter().any(|root| match fs::canonicalize(root) {
+            Ok(root) => path.starts_with(root),
+            Err(_) => false,
+        })
+    } else {
+        false
+    };
+    if !allowed {
+        return Err(format!(
+            "Source {} is neither a block device nor a file within allowed directories {:?}",
+            source, roots
+        ));
+    }
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Return size of the block device or file.
+fn source_size(source: &str) -> Result<u64, String> {
+    File::open(source)
+        .and_then(|mut file| file.seek(SeekFrom::End(0)))
+        .map_err(|err| format!("Failed to open source {}: {}", source, err))
+}
+
+/// Copy the source to the device and return the checksum of the data and
+/// the number of bytes which were skipped because they were zero.
+fn copy(
+    source: &str,
+    device: &str,
+    size: u64,
+    rate: u64,
+) -> Result<(String, u64), String> {
+    let mut src = File::open(source)
+        .map_err(|err| format!("Failed to open {}: {}", source, err))?;
+    let mut dst = OpenOptions::new()
+        .write(true)
+        .open(device)
+        .map_err(|err| format!("Failed to open {}: {}", device, err))?;
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    let mut skipped = 0;
+    let started = Instant::now();
+
+    while offset < size {
+        let n = (size - offset).min(CHUNK_SIZE as u64) as usize;
+        src.read_exact(&mut buf[.. n]).map_err(|err| {
+            format!("Failed to read {} at {}: {}", source, offset, err)
+        })?;
+        hasher.input(&buf[.. n]);
+        let res = if buf[.. n].iter().all(|b| *b == 0) {
+            skipped += n as u64;
+            dst.seek(SeekFrom::Current(n as i64)).map(|_| ())
+        } else {
+            dst.write_all(&buf[.. n])
+        };
+        res.map_err(|err| {
+            format!("Failed to write {} at {}: {}", device, offset, err)
+        })?;
+        offset += n as u64;
+        throttle(started, offset, rate);
+    }
+    dst.sync_all()
+        .map_err(|err| format!("Failed to sync {}: {}", device, err))?;
+    Ok((to_hex(&hasher.result()), skipped))
+}
+
+/// Compute checksum of the first `size` bytes of the device bypassing the
+/// page cache.
+fn device_checksum(
+    device: &str,
+    size: u64,
+    rate: u64,
+) -> Result<String, String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(device)
+        .map_err(|err| format!("Failed to open {}: {}", device, err))?;
+    let mut buf = vec![0u8; CHUNK_SIZE + BLOCK_SIZE];
+    let start = buf.as_ptr().align_offset(BLOCK_SIZE);
+    let buf = &mut buf[start .. start + CHUNK_SIZE];
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    let started = Instant::now();
+
+    while offset < size {
+        let n = (size - offset).min(CHUNK_SIZE as u64) as usize;
+        // the device is bigger than the data, the tail is read in full blocks
+        let len = (n + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        file.read_exact(&mut buf[.. len]).map_err(|err| {
+            format!("Failed to read {} at {}: {}", device, offset, err)
+        })?;
+        hasher.input(&buf[.. n]);
+        offset += n as u64;
+        throttle(started, offset, rate);
+    }
+    Ok(to_hex(&hasher.result()))
+}
+
+/// Copy the data and verify them.
+fn copy_and_verify(
+    source: &str,
+    device: &str,
+    size: u64,
+    rate: u64,
+) -> Result<ImportVolumeReply, String> {
+    let started = Instant::now();
+    let (checksum, skipped) = copy(source, device, size, rate)?;
+
+    debug!("Verifying data copied from {} to {}", source, device);
+    let copied = device_checksum(device, size, rate)?;
+    if copied != checksum {
+        return Err(format!(
+            "Checksum of the copy {} does not match the source {}",
+            copied, checksum
+        ));
+    }
+    Ok(ImportVolumeReply {
+        size,
+        skipped,
+        checksum,
+        duration: started.elapsed().as_secs() as u32,
+    })
+}
+
+/// Create replica with the data of the source.
+pub fn import_volume(
+    socket: String,
+    msg: ImportVolumeRequest,
+    roots: &[PathBuf],
+) -> Box<dyn Future<Item = ImportVolumeReply, Error = Status> + Send> {
+    if msg.uuid.is_empty() || msg.pool.is_empty() || msg.source.is_empty() {
+        return Box::new(future::err(Status::new(
+            Code::InvalidArgument,
+            "Uuid, pool and source of the import are required".to_owned(),
+        )));
+    }
+    let source = match check_source(&msg.source, roots) {
+        Ok(source) => source,
+        Err(reason) => {
+            return Box::new(future::err(Status::new(
+                Code::InvalidArgument,
+                reason,
+            )))
+        }
+    };
+    if !find_mounts(&source).is_empty() {
+        return Box::new(future::err(Status::new(
+            Code::FailedPrecondition,
+            format!("Source {} is mounted", msg.source),
+        )));
+    }
+    let size = match source_size(&source) {
+        Ok(0) => {
+            return Box::new(future::err(Status::new(
+                Code::InvalidArgument,
+                format!("Source {} is empty", msg.source),
+            )))
+        }
+        Ok(size) => size,
+        Err(reason) => {
+            return Box::new(future::err(Status::new(
+                Code::InvalidArgument,
+                reason,
+            )))
+        }
+    };
+    let rate = u64::from(msg.rate_limit) * 1024 * 1024;
+    let uuid = msg.uuid.clone();
+
+    info!(
+        "Importing {} ({} bytes) to replica {} on pool {}",
+        source, size, uuid, msg.pool
+    );
+    let args = Some(jsondata::CreateReplicaArgs {
+        uuid: uuid.clone(),
+        pool: msg.pool.clone(),
+        thin_provision: true,
+        // full MiBs, the tail of the replica is left zeroed
+        size: (size + (1 << 20) - 1) >> 20 << 20,
+        compress: false,
+    });
+
+    let created = jsonrpc::call::<_, ()>(&socket, "create_replica", args)
+        .map_err(|err| err.into_status());
+    let imported = {
+        let socket = socket.clone();
+        let uuid = uuid.clone();
+        created.and_then(move |_| {
+            nbd::create_blkdev(
+                socket.clone(),
+                &CreateBlkdevRequest {
+                    uuid: uuid.clone(),
+                    fencing_epoch: 0,
+                },
+            )
+            .and_then(enclose! { (socket, uuid) move |resp| {
+                let device = resp.into_inner().blk_dev;
+                let (sender, receiver) = oneshot::channel();
+
+                // the copy takes long, don't block the executor meanwhile
+                thread::spawn(move || {
+                    let _ = sender
+                        .send(copy_and_verify(&source, &device, size, rate));
+                });
+                receiver
+                    .map_err(|_| {
+                        Status::new(
+                            Code::Internal,
+                            "Import has been aborted".to_owned(),
+                        )
+                    })
+                    .and_then(|res| {
+                        res.map_err(|reason| {
+                            Status::new(Code::Internal, reason)
+                        })
+                    })
+                    .then(move |res| {
+                        nbd::destroy_blkdev(
+                            socket,
+                            &DestroyBlkdevRequest {
+                                uuid,
+                            },
+                        )
+                        .then(|_| res)
+                    })
+            }})
+            .or_else(move |status| {
+                // don't leave behind the replica with partial data, which
+                // has been created by us
+                let args = Some(jsondata::DestroyReplicaArgs {
+                    uuid,
+                });
+                jsonrpc::call::<_, ()>(&socket, "destroy_replica", args)
+                    .then(move |_| Err(status))
+            })
+        })
+    };
+
+    Box::new(imported.then(move |res| {
+        match &res {
+            Ok(reply) => info!(
+                "Imported replica {} (sha256 {}, {}s)",
+                uuid, reply.checksum, reply.duration
+            ),
+            Err(status) => {
+                error!("Failed to import replica {}: {}", uuid, status)
+            }
+        }
+        res
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink};
+
+    #[test]
+    fn source_must_be_device_or_allowed_file() {
+        let dir = env::temp_dir().join("csi-import-test");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("images");
+        fs::create_dir_all(&root).unwrap();
+        let image = root.join("disk.img");
+        fs::write(&image, b"data").unwrap();
+        let outside = dir.join("secret");
+        fs::write(&outside, b"secret").unwrap();
+        symlink(&outside, root.join("link.img")).unwrap();
+        let roots = vec![root.clone()];
+
+        assert_eq!(
+            check_source(image.to_str().unwrap(), &roots).unwrap(),
+            fs::canonicalize(&image).unwrap().to_string_lossy()
+        );
+        // the same file without any allowed directory
+        assert!(check_source(image.to_str().unwrap(), &[]).is_err());
+        for source in &[
+            outside.clone(),
+            root.join("link.img"),
+            root.join("../secret"),
+            root.join("missing.img"),
+            root.clone(),
+            // character device
+            PathBuf::from("/dev/null"),
+        ] {
+            assert!(
+                check_source(source.to_str().unwrap(), &roots).is_err(),
+                "{} accepted",
+                source.display()
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_skips_zeros() {
+        let dir = env::temp_dir().join("csi-import-copy-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let source = dir.join("source");
+        let device = dir.join("device");
+        // zero chunk, data chunk and a short tail
+        let mut data = vec![0u8; CHUNK_SIZE];
+        data.extend(vec![7u8; CHUNK_SIZE]);
+        data.extend(vec![9u8; 512]);
+        fs::write(&source, &data).unwrap();
+        File::create(&device)
+            .and_then(|file| file.set_len(data.len() as u64))
+            .unwrap();
+
+        let (checksum, skipped) = copy(
+            source.to_str().unwrap(),
+            device.to_str().unwrap(),
+            data.len() as u64,
+            0,
+        )
+        .unwrap();
+        assert_eq!(checksum, to_hex(&Sha256::digest(&data)));
+        assert_eq!(skipped, CHUNK_SIZE as u64);
+        assert_eq!(fs::read(&device).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
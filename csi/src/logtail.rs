@@ -0,0 +1,155 @@
+//! Streaming of agent's log records over gRPC.
+//!
+//! We install a logger which wraps env_logger. Besides printing the records
+//! as env_logger normally does, it keeps the most recent records in memory
+//! and sends copies of new records to subscribers (clients of TailLogs
+//! method). Only the records which pass the env_logger filter (given by -v
+//! option or RUST_LOG variable) can be tailed. Subscribers can narrow them
+//! down further by level and module.
+//!
+//! Logging must never block, so if a subscriber is not able to keep up with
+//! the rate of new records, the records are dropped for the subscriber.
+
+use crate::rpc::mayastor::{LogRecord, TailLogsRequest};
+use chrono::Local;
+use env_logger::Builder;
+use futures::{stream, sync::mpsc, Stream};
+use log::{Level, Log, Metadata, Record};
+use std::{collections::VecDeque, mem, str::FromStr, sync::Mutex};
+
+/// Number of recent records returned to a new subscriber.
+const BACKLOG_SIZE: usize = 1000;
+/// Number of records which can wait for a slow subscriber.
+const QUEUE_SIZE: usize = 1000;
+
+lazy_static! {
+    static ref TAIL: Mutex<Tail> = Mutex::new(Tail::default());
+}
+
+/// Criteria for records sent to a subscriber.
+#[derive(Debug)]
+struct Filter {
+    level: Level,
+    module: String,
+}
+
+impl Filter {
+    fn matches(&self, level: Level, module: &str) -> bool {
+        level <= self.level && module.starts_with(&self.module)
+    }
+}
+
+struct Subscriber {
+    filter: Filter,
+    sender: mpsc::Sender<LogRecord>,
+}
+
+#[derive(Default)]
+struct Tail {
+    backlog: VecDeque<(Level, LogRecord)>,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Logger which prints the records using env_logger and publishes them to
+/// subscribers.
+struct TailLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for TailLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+            publish(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Install the logger built by given env_logger builder. Use it instead of
+/// calling init() on the builder.
+pub fn init(builder: &mut Builder) {
+    let inner = builder.build();
+
+    log::set_max_level(inner.filter());
+    log::set_boxed_logger(Box::new(TailLogger {
+        inner,
+    }))
+    .expect("Logger has been already set");
+}
+
+/// Store the record in backlog and send it to subscribers.
+fn publish(record: &Record) {
+    let level = record.level();
+    let rec = LogRecord {
+        timestamp: Local::now().format("%Y-%m-%dT%H:%M:%S%.3f%:z").to_string(),
+        level: level.to_string().to_lowercase(),
+        module: record.module_path().unwrap_or("").to_owned(),
+        message: record.args().to_string(),
+    };
+    // careful - nothing can be logged while holding the lock
+    let mut tail = TAIL.lock().unwrap();
+
+    let subscribers = mem::replace(&mut tail.subscribers, Vec::new());
+    tail.subscribers = subscribers
+        .into_iter()
+        .filter_map(|mut sub| {
+            if !sub.filter.matches(level, &rec.module) {
+                return Some(sub);
+            }
+            match sub.sender.try_send(rec.clone()) {
+                Ok(_) => Some(sub),
+                // the subscriber has gone away
+                Err(ref err) if err.is_disconnected() => None,
+                // queue is full - drop the record
+                Err(_) => Some(sub),
+            }
+        })
+        .collect();
+
+    if tail.backlog.len() >= BACKLOG_SIZE {
+        tail.backlog.pop_front();
+    }
+    tail.backlog.push_back((level, rec));
+}
+
+/// Return stream of recent log records matching the request, followed by
+/// new records if follow flag is set.
+pub fn subscribe(
+    req: &TailLogsRequest,
+) -> Result<Box<dyn Stream<Item = LogRecord, Error = ()> + Send>, String> {
+    let filter = Filter {
+        level: if req.level.is_empty() {
+            Level::Info
+        } else {
+            Level::from_str(&req.level)
+                .map_err(|_| format!("Invalid log level {}", req.level))?
+        },
+        module: req.module.clone(),
+    };
+    let mut tail = TAIL.lock().unwrap();
+    let backlog: Vec<LogRecord> = tail
+        .backlog
+        .iter()
+        .filter(|(level, rec)| filter.matches(*level, &rec.module))
+        .map(|(_, rec)| rec.clone())
+        .collect();
+
+    if req.follow {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tail.subscribers.push(Subscriber {
+            filter,
+            sender,
+        });
+        Ok(Box::new(stream::iter_ok(backlog).chain(receiver)))
+    } else {
+        Ok(Box::new(stream::iter_ok(backlog)))
+    }
+}
@@ -0,0 +1,321 @@
+//! Integrity manifests of volumes for compliance audits.
+//!
+//! The manifest lists checksums of the replicas on the node computed by the
+//! scrubber in mayastor, each with the time when it was computed. It is in
+//! json format and it is signed by HMAC-SHA256 with a key shared with the
+//! auditor (`--manifest-key`), so that the manifest can be archived anywhere
+//! and still prove that the data were intact at the time. The signature
+//! covers the exact bytes of the manifest as returned. The key id in the
+//! reply tells which key has been used without revealing it.
+
+use crate::{rpc::mayastor::GetIntegrityManifestReply, secrets::SecretString};
+use chrono::{TimeZone, Utc};
+use futures::{future, Future};
+use hmac::{Hmac, Mac};
+use jsonrpc::CallOptions;
+use rpc::jsonrpc as jsondata;
+use sha2::{Digest, Sha256};
+use std::{fs, sync::Arc};
+use tower_grpc::{Code, Status};
+
+/// Version of the format of the manifest.
+const MANIFEST_VERSION: u32 = 1;
+/// How many replicas are scrubbed at the same time.
+const SCRUB_CONCURRENCY: usize = 4;
+
+/// Hex representation of the bytes.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Key for signing of manifests.
+#[derive(Debug)]
+pub struct Signer {
+    key: SecretString,
+    key_id: String,
+}
+
+impl Signer {
+    /// Load the key from the file (trailing whitespace is ignored).
+    pub fn load(path: &str) -> Result<Self, String> {
+        let mut key = fs::read_to_string(path).map_err(|err| {
+            format!("Failed to read manifest key {}: {}", path, err)
+        })?;
+        let len = key.trim_end().len();
+        key.truncate(len);
+        if key.is_empty() {
+            return Err(format!("Manifest key {} is empty", path));
+        }
+        let key = SecretString::new(key);
+        let key_id = to_hex(&Sha256::digest(key.expose().as_bytes())[.. 8]);
+
+        info!("Loaded manifest key {}", key_id);
+        Ok(Self {
+            key,
+            key_id,
+        })
+    }
+
+    /// Return hex encoded HMAC-SHA256 of the data.
+    fn sign(&self, data: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(self.key.expose().as_bytes())
+            .expect("HMAC accepts any key size");
+        mac.input(data);
+        to_hex(&mac.result().code())
+    }
+}
+
+/// Scrub the replicas, a few of them at the same time (they share the
+/// background IO budget of mayastor anyway). All replicas on the node are
+/// scrubbed if no uuids are given. Mayastor refuses to scrub published
+/// replicas, these are skipped (keeping their previous checksum) unless
+/// they have been asked for explicitly.
+fn scrub(
+    socket: String,
+    uuids: Vec<String>,
+) -> Box<dyn Future<Item = (), Error = Status> + Send> {
+    let all = uuids.is_empty();
+    let uuids = if all {
+        Box::new(
+            jsonrpc::call::<(), Vec<jsondata::Replica>>(
+                &socket,
+                "list_replicas",
+                None,
+            )
+            .map(|replicas| replicas.into_iter().map(|r| r.uuid).collect())
+            .map_err(|err| err.into_status()),
+        ) as Box<dyn Future<Item = Vec<String>, Error = Status> + Send>
+    } else {
+        Box::new(future::ok(uuids))
+    };
+
+    Box::new(uuids.and_then(move |uuids| {
+        debug!("Scrubbing {} replicas ...", uuids.len());
+        let args = uuids
+            .iter()
+            .map(|uuid| jsondata::ScrubReplicaArgs {
+                uuid: uuid.clone(),
+            })
+            .collect();
+        // reading the whole replica takes long
+        jsonrpc::call_many::<_, jsondata::ScrubResult>(
+            &socket,
+            "scrub_replica",
+            args,
+            SCRUB_CONCURRENCY,
+            CallOptions::default().no_timeout(),
+        )
+        .map_err(|err| err.into_status())
+        .and_then(move |results| {
+            let mut first_err = None;
+            for (uuid, res) in uuids.iter().zip(results) {
+                if let Err(err) = res {
+                    let status = err.into_status();
+                    if all && status.code() == Code::InvalidArgument {
+                        warn!(
+                            "Skipping replica {}: {}",
+                            uuid,
+                            status.message()
+                        );
+                        continue;
+                    }
+                    error!(
+                        "Failed to scrub replica {}: {}",
+                        uuid,
+                        status.message()
+                    );
+                    if first_err.is_none() {
+                        first_err = Some(status);
+                    }
+                }
+            }
+            match first_err {
+                Some(status) => Err(status),
+                None => Ok(()),
+            }
+        })
+    }))
+}
+
+/// Render the manifest from the scrub results.
+fn render(
+    node: &str,
+    uuids: &[String],
+    results: Vec<jsondata::ScrubResult>,
+) -> Result<String, Status> {
+    let mut results: Vec<jsondata::ScrubResult> = if uuids.is_empty() {
+        results
+    } else {
+        results
+            .into_iter()
+            .filter(|r| uuids.contains(&r.uuid))
+            .collect()
+    };
+    if let Some(uuid) = uuids
+        .iter()
+        .find(|u| !results.iter().any(|r| &r.uuid == *u))
+    {
+        return Err(Status::new(
+            Code::FailedPrecondition,
+            format!("Replica {} has not been scrubbed", uuid),
+        ));
+    }
+    results.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+    let volumes: Vec<serde_json::Value> = results
+        .iter()
+        .map(|r| {
+            serde_json::json!({
+                "uuid": r.uuid,
+                "size": r.size,
+                "checksum": r.checksum,
+                "scrubbed": Utc.timestamp(r.scrubbed as i64, 0).to_rfc3339(),
+            })
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&serde_json::json!({
+        "version": MANIFEST_VERSION,
+        "node": node,
+        "generated": Utc::now().to_rfc3339(),
+        "algorithm": "sha256",
+        "volumes": volumes,
+    }))
+    .unwrap())
+}
+
+/// Create signed manifest of checksums of the replicas (all scrubbed
+/// replicas if no uuids are given), optionally scrubbing them first.
+pub fn integrity_manifest(
+    socket: String,
+    node: String,
+    signer: Option<Arc<Signer>>,
+    uuids: Vec<String>,
+    do_scrub: bool,
+) -> Box<dyn Future<Item = GetIntegrityManifestReply, Error = Status> + Send> {
+    let signer = match signer {
+        Some(signer) => signer,
+        None => {
+            return Box::new(future::err(Status::new(
+                Code::FailedPrecondition,
+                "Manifest key has not been configured (--manifest-key)"
+                    .to_owned(),
+            )))
+        }
+    };
+    let scrubbed: Box<dyn Future<Item = (), Error = Status> + Send> =
+        if do_scrub {
+            scrub(socket.clone(), uuids.clone())
+        } else {
+            Box::new(future::ok(()))
+        };
+
+    Box::new(
+        scrubbed
+            .and_then(move |_| {
+                jsonrpc::call::<(), Vec<jsondata::ScrubResult>>(
+                    &socket,
+                    "list_scrub_results",
+                    None,
+                )
+                .map_err(|err| err.into_status())
+            })
+            .and_then(move |results| {
+                let manifest = render(&node, &uuids, results)?;
+                let signature = signer.sign(manifest.as_bytes());
+
+                Ok(GetIntegrityManifestReply {
+                    manifest,
+                    signature,
+                    key_id: signer.key_id.clone(),
+                })
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn signer(key: &str) -> Signer {
+        let path = env::temp_dir().join("csi-manifest-test-key");
+        fs::write(&path, key).unwrap();
+        let signer = Signer::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        signer.unwrap()
+    }
+
+    fn result(uuid: &str) -> jsondata::ScrubResult {
+        jsondata::ScrubResult {
+            uuid: uuid.to_owned(),
+            size: 4096,
+            checksum: format!("{:064}", 0),
+            scrubbed: 1_580_000_000,
+        }
+    }
+
+    #[test]
+    fn sign_manifest() {
+        // RFC 4231 test case 2, the key file ends with a newline
+        let signer = signer("Jefe\n");
+        assert_eq!(
+            signer.sign(b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(signer.key_id.len(), 16);
+        assert!(!format!("{:?}", signer).contains("Jefe"));
+    }
+
+    #[test]
+    fn verify_manifest() {
+        let manifest =
+            render("node1", &[], vec![result("r2"), result("r1")]).unwrap();
+        let signature = signer("secret").sign(manifest.as_bytes());
+
+        // what the auditor does with the shared key
+        let verify = |key: &str, data: &[u8]| {
+            let mut mac = Hmac::<Sha256>::new_varkey(key.as_bytes()).unwrap();
+            mac.input(data);
+            to_hex(&mac.result().code()) == signature
+        };
+        assert!(verify("secret", manifest.as_bytes()));
+        assert!(!verify("other", manifest.as_bytes()));
+        let tampered = manifest.replace("\"size\": 4096", "\"size\": 4097");
+        assert_ne!(tampered, manifest);
+        assert!(!verify("secret", tampered.as_bytes()));
+    }
+
+    #[test]
+    fn render_manifest() {
+        let manifest: serde_json::Value = serde_json::from_str(
+            &render("node1", &[], vec![result("r2"), result("r1")]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest["node"], "node1");
+        assert_eq!(manifest["volumes"][0]["uuid"], "r1");
+        assert_eq!(manifest["volumes"][1]["uuid"], "r2");
+        assert_eq!(
+            manifest["volumes"][0]["scrubbed"],
+            "2020-01-26T00:53:20+00:00"
+        );
+
+        let uuids = vec!["r2".to_owned()];
+        let manifest: serde_json::Value = serde_json::from_str(
+            &render("node1", &uuids, vec![result("r2"), result("r1")]).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(manifest["volumes"].as_array().unwrap().len(), 1);
+
+        let uuids = vec!["r3".to_owned()];
+        let err = render("node1", &uuids, vec![result("r1")]).unwrap_err();
+        assert_eq!(err.code(), Code::FailedPrecondition);
+    }
+
+    #[test]
+    fn empty_key_is_refused() {
+        let path = env::temp_dir().join("csi-manifest-test-empty-key");
+        fs::write(&path, " \n").unwrap();
+        assert!(Signer::load(path.to_str().unwrap()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
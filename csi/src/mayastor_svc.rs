@@ -1,23 +1,112 @@
 //! Implementation of gRPC methods from mayastor gRPC service.
 
 use crate::{
+    benchmark,
     device,
+    history::History,
+    import,
+    logtail,
+    manifest::{self, Signer},
     nbd,
+    node::Node,
+    quiesce::{Operation, Quiesce},
+    ratelimit::RateLimiter,
+    reconcile,
     rpc::{mayastor::*, service},
+    secrets::SecretString,
+    soak,
+    staging::StagingStore,
+    support,
+    vhost,
 };
 
 use enclose::enclose;
 use futures::future::{self, Either};
 
-use futures::future::Future;
-use jsonrpc;
+use futures::{future::Future, Stream};
+use jsonrpc::{
+    self,
+    spdk_methods::{self, StartNbdDiskArgs},
+};
 use rpc::{jsonrpc as jsondata, mayastor::ListNexusReply};
-use std::{boxed::Box, vec::Vec};
+use std::{boxed::Box, mem, net::IpAddr, path::PathBuf, sync::Arc, vec::Vec};
 use tower_grpc::{Code, Request, Response, Status};
 /// mayastorService handles non CSI rpc calls
 #[derive(Clone, Debug)]
 pub struct MayastorService {
     pub socket: String,
+    /// address of the client connected to the service
+    pub peer: Option<IpAddr>,
+    /// limiter of management calls shared by all connections
+    pub limiter: Arc<RateLimiter>,
+    /// effective configuration of the agent (json) for support bundles
+    pub config: Arc<String>,
+    pub staging: StagingStore,
+    pub history: History,
+    /// rejects control operations while the node is being upgraded
+    pub quiesce: Quiesce,
+    pub node_name: String,
+    /// key for signing of integrity manifests
+    pub manifest_signer: Option<Arc<Signer>>,
+    /// node service driven by the soak test
+    pub node: Node,
+    /// serve only methods which don't change anything (for dashboards)
+    pub read_only: bool,
+    /// directory with vhost-user sockets created by mayastor
+    pub vhost_dir: String,
+    /// directories with files which may be imported
+    pub import_roots: Arc<Vec<PathBuf>>,
+}
+
+impl MayastorService {
+    /// Return error if the peer has exceeded the rate of management calls.
+    fn throttle(&self, method: &str) -> Option<Status> {
+        match self.peer {
+            Some(peer) if !self.limiter.check(peer, method) => {
+                Some(Status::new(
+                    Code::ResourceExhausted,
+                    format!("Rate limit for {} exceeded", method),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return error if the service is read-only.
+    fn check_writable(&self, method: &str) -> Result<(), Status> {
+        if self.read_only {
+            Err(Status::new(
+                Code::PermissionDenied,
+                format!("{} is not allowed on read-only endpoint", method),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Start control operation unless the service is read-only or the node
+    /// is quiesced. All methods changing something are control operations.
+    fn begin(&self, method: &'static str) -> Result<Operation, Status> {
+        self.check_writable(method)?;
+        self.quiesce.begin(method)
+    }
+
+    /// Same as Quiesce::run() but rejected by read-only service too.
+    fn run<T, F, R>(
+        &self,
+        method: &'static str,
+        start: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.begin(method) {
+            Ok(op) => op.track(start()),
+            Err(status) => Box::new(future::err(status)),
+        }
+    }
 }
 
 impl service::server::Mayastor for MayastorService {
@@ -45,6 +134,30 @@ impl service::server::Mayastor for MayastorService {
         dyn future::Future<Item = Response<StatReplicasReply>, Error = Status>
             + Send,
     >;
+    type AdoptVolumeFuture = Box<
+        dyn future::Future<Item = Response<AdoptVolumeReply>, Error = Status>
+            + Send,
+    >;
+    type GetIntegrityManifestFuture = Box<
+        dyn future::Future<
+                Item = Response<GetIntegrityManifestReply>,
+                Error = Status,
+            > + Send,
+    >;
+    type ImportVolumeFuture = Box<
+        dyn future::Future<Item = Response<ImportVolumeReply>, Error = Status>
+            + Send,
+    >;
+    type CreateTemplateFuture =
+        Box<dyn future::Future<Item = Response<Null>, Error = Status> + Send>;
+    type CloneTemplateFuture =
+        Box<dyn future::Future<Item = Response<Null>, Error = Status> + Send>;
+    type DestroyTemplateFuture =
+        Box<dyn future::Future<Item = Response<Null>, Error = Status> + Send>;
+    type ListTemplatesFuture = Box<
+        dyn future::Future<Item = Response<ListTemplatesReply>, Error = Status>
+            + Send,
+    >;
     type CreateBlkdevFuture = Box<
         dyn future::Future<Item = Response<CreateBlkdevReply>, Error = Status>
             + Send,
@@ -69,19 +182,97 @@ impl service::server::Mayastor for MayastorService {
         dyn future::Future<Item = Response<PublishNexusReply>, Error = Status>
             + Send,
     >;
+    type UnpublishNexusFuture =
+        Box<dyn future::Future<Item = Response<Null>, Error = Status> + Send>;
+    type ListVhostControllersFuture = Box<
+        dyn future::Future<
+                Item = Response<ListVhostControllersReply>,
+                Error = Status,
+            > + Send,
+    >;
 
     type ChildOperationFuture = Box<
         dyn future::Future<Item = Response<ChildNexusReply>, Error = Status>
             + Send,
     >;
 
+    type TailLogsStream =
+        Box<dyn Stream<Item = LogRecord, Error = Status> + Send>;
+
+    type TailLogsFuture = Box<
+        dyn future::Future<
+                Item = Response<Self::TailLogsStream>,
+                Error = Status,
+            > + Send,
+    >;
+
+    type GetSupportInfoFuture = Box<
+        dyn future::Future<Item = Response<SupportInfoReply>, Error = Status>
+            + Send,
+    >;
+
+    type SaveConfigFuture = Box<
+        dyn future::Future<Item = Response<SaveConfigReply>, Error = Status>
+            + Send,
+    >;
+
+    type LoadConfigFuture = Box<
+        dyn future::Future<Item = Response<LoadConfigReply>, Error = Status>
+            + Send,
+    >;
+
+    type CheckConfigFuture = Box<
+        dyn future::Future<Item = Response<CheckConfigReply>, Error = Status>
+            + Send,
+    >;
+
+    type BenchmarkVolumeFuture = Box<
+        dyn future::Future<
+                Item = Response<BenchmarkVolumeReply>,
+                Error = Status,
+            > + Send,
+    >;
+
+    type SoakFuture = Box<
+        dyn future::Future<Item = Response<SoakReply>, Error = Status> + Send,
+    >;
+
+    type GetVolumeHistoryFuture = Box<
+        dyn future::Future<
+                Item = Response<GetVolumeHistoryReply>,
+                Error = Status,
+            > + Send,
+    >;
+
+    type QuiesceIoFuture = Box<
+        dyn future::Future<Item = Response<QuiesceIoReply>, Error = Status>
+            + Send,
+    >;
+
+    type ResumeIoFuture =
+        Box<dyn future::Future<Item = Response<Null>, Error = Status> + Send>;
+
+    type ReconcileNowFuture = Box<
+        dyn future::Future<Item = Response<ReconcileNowReply>, Error = Status>
+            + Send,
+    >;
+
     /// Create storage pool (or import it if it already exists on the
     /// specified disk).
     fn create_pool(
         &mut self,
         request: Request<CreatePoolRequest>,
     ) -> Self::CreatePoolFuture {
-        let msg = request.into_inner();
+        let op = match self.begin("CreatePool") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let mut msg = request.into_inner();
+        // keep the key out of the logs
+        let key = SecretString::new(mem::replace(
+            &mut msg.encryption_key,
+            String::new(),
+        ));
 
         trace!("{:?}", msg);
 
@@ -106,6 +297,11 @@ impl service::server::Mayastor for MayastorService {
             name: msg.name,
             disks: msg.disks,
             block_size: Some(msg.block_size),
+            encryption_key: if key.expose().is_empty() {
+                None
+            } else {
+                Some(key.expose().to_owned())
+            },
         });
 
         let f =
@@ -119,7 +315,7 @@ impl service::server::Mayastor for MayastorService {
                     err.into_status()
                 }});
 
-        Box::new(f)
+        op.track(f)
     }
 
     /// Destroy pool -> destroy lvol store and delete underlying base bdev.
@@ -127,6 +323,10 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<DestroyPoolRequest>,
     ) -> Self::DestroyPoolFuture {
+        let op = match self.begin("DestroyPool") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
 
         trace!("{:?}", msg);
@@ -151,7 +351,7 @@ impl service::server::Mayastor for MayastorService {
                 err.into_status()
             }});
 
-        Box::new(f)
+        op.track(f)
     }
 
     /// Get list of lvol stores.
@@ -159,6 +359,10 @@ impl service::server::Mayastor for MayastorService {
     /// TODO: There is a state field which is always set to "online" state.
     /// Figure out how to set it properly.
     fn list_pools(&mut self, request: Request<Null>) -> Self::ListPoolsFuture {
+        if let Some(status) = self.throttle("list_pools") {
+            return Box::new(future::err(status));
+        }
+
         let msg = request.into_inner();
 
         trace!("{:?}", msg);
@@ -184,6 +388,12 @@ impl service::server::Mayastor for MayastorService {
                             "faulty" => PoolState::Faulty,
                             _ => PoolState::Faulty,
                         } as i32,
+                        usage: Some(PoolUsage {
+                            volumes: p.usage.volumes,
+                            snapshots: p.usage.snapshots,
+                            metadata: p.usage.metadata,
+                        }),
+                        encrypted: p.encrypted,
                     })
                     .collect(),
             });
@@ -203,6 +413,10 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<CreateReplicaRequest>,
     ) -> Self::CreateReplicaFuture {
+        let op = match self.begin("CreateReplica") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
         trace!("{:?}", msg);
 
@@ -215,6 +429,7 @@ impl service::server::Mayastor for MayastorService {
             pool: pool.clone(),
             thin_provision: msg.thin,
             size: msg.size,
+            compress: msg.compress,
         });
 
         let f = jsonrpc::call::<_, ()>(&self.socket, "create_replica", args)
@@ -228,7 +443,7 @@ impl service::server::Mayastor for MayastorService {
                 err.into_status()
             }});
 
-        Box::new(f)
+        op.track(f)
     }
 
     /// Destroy replica
@@ -236,6 +451,10 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<DestroyReplicaRequest>,
     ) -> Self::DestroyReplicaFuture {
+        let op = match self.begin("DestroyReplica") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
         trace!("{:?}", msg);
 
@@ -256,7 +475,7 @@ impl service::server::Mayastor for MayastorService {
                 err.into_status()
             }});
 
-        Box::new(f)
+        op.track(f)
     }
 
     /// List replicas
@@ -264,16 +483,29 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<Null>,
     ) -> Self::ListReplicasFuture {
+        if let Some(status) = self.throttle("list_replicas") {
+            return Box::new(future::err(status));
+        }
+
         let msg = request.into_inner();
 
         trace!("{:?}", msg);
 
+        let fencing = self.node.fencing.clone();
         let f = jsonrpc::call::<(), Vec<jsondata::Replica>>(
             &self.socket,
             "list_replicas",
             None,
         )
-        .map(move |replicas| {
+        .map_err(|err| {
+            error!("Getting replicas failed: {}", err);
+            err.into_status()
+        })
+        .join(nbd::exported_volumes(&self.socket).map_err(|err| {
+            error!("{}", err);
+            Status::new(Code::Internal, err)
+        }))
+        .map(move |(replicas, exported)| {
             debug!("Got list of {} replicas", replicas.len());
             let resp = Response::new(ListReplicasReply {
                 replicas: replicas
@@ -283,15 +515,26 @@ impl service::server::Mayastor for MayastorService {
                         pool: r.pool.clone(),
                         thin: r.thin_provision,
                         size: r.size,
+                        compressed: r.compressed,
+                        template: r.template.clone().unwrap_or_default(),
+                        quarantined: r.quarantined,
+                        fencing_epoch: fencing
+                            .get(&r.uuid)
+                            .unwrap_or_else(|reason| {
+                                warn!("{}", reason);
+                                None
+                            })
+                            .unwrap_or(0),
+                        blkdev: exported
+                            .iter()
+                            .find(|(_, uuid)| **uuid == r.uuid)
+                            .map(|(dev, _)| dev.clone())
+                            .unwrap_or_default(),
                     })
                     .collect(),
             });
             trace!("{:?}", resp);
             resp
-        })
-        .map_err(|err| {
-            error!("Getting replicas failed: {}", err);
-            err.into_status()
         });
 
         Box::new(f)
@@ -302,6 +545,10 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<Null>,
     ) -> Self::StatReplicasFuture {
+        if let Some(status) = self.throttle("stat_replicas") {
+            return Box::new(future::err(status));
+        }
+
         let msg = request.into_inner();
         let socket = &self.socket;
 
@@ -325,6 +572,9 @@ impl service::server::Mayastor for MayastorService {
                             bytes_read: st.bytes_read,
                             bytes_written: st.bytes_written,
                         }),
+                        compressed: st.compressed,
+                        size: st.size,
+                        allocated: st.allocated,
                     })
                     .collect(),
             });
@@ -339,28 +589,288 @@ impl service::server::Mayastor for MayastorService {
         Box::new(f)
     }
 
+    /// Bring quarantined lvol under management as a replica
+    fn adopt_volume(
+        &mut self,
+        request: Request<AdoptVolumeRequest>,
+    ) -> Self::AdoptVolumeFuture {
+        let op = match self.begin("AdoptVolume") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let name = format!("{}/{}", msg.pool, msg.name);
+        debug!("Adopting lvol {} ...", name);
+
+        let args = Some(jsondata::AdoptReplicaArgs {
+            pool: msg.pool,
+            name: msg.name,
+        });
+
+        let f = jsonrpc::call::<_, jsondata::AdoptReplicaReply>(
+            &self.socket,
+            "adopt_replica",
+            args,
+        )
+        .map(enclose! { (name) move |reply| {
+            info!("Adopted lvol {} as replica {}", name, reply.uuid);
+            Response::new(AdoptVolumeReply {
+                uuid: reply.uuid,
+            })
+        }})
+        .map_err(enclose! { (name) move |err| {
+            error!("Failed to adopt lvol {}: {}", name, err);
+            err.into_status()
+        }});
+
+        op.track(f)
+    }
+
+    /// Return signed manifest of checksums of replicas
+    fn get_integrity_manifest(
+        &mut self,
+        request: Request<GetIntegrityManifestRequest>,
+    ) -> Self::GetIntegrityManifestFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let socket = self.socket.clone();
+        let node = self.node_name.clone();
+        let signer = self.manifest_signer.clone();
+        let scrub = msg.scrub;
+        let start = move || {
+            manifest::integrity_manifest(socket, node, signer, msg.uuids, scrub)
+                .map(|reply| {
+                    info!(
+                        "Created integrity manifest signed by {}",
+                        reply.key_id
+                    );
+                    Response::new(reply)
+                })
+                .map_err(|status| {
+                    error!("Failed to create integrity manifest: {}", status);
+                    status
+                })
+        };
+
+        // scrubbing reads all data of the replicas
+        if scrub {
+            self.run("GetIntegrityManifest", start)
+        } else if let Some(status) = self.throttle("get_integrity_manifest") {
+            Box::new(future::err(status))
+        } else {
+            Box::new(start())
+        }
+    }
+
+    /// Create replica with data copied from block device or file
+    fn import_volume(
+        &mut self,
+        request: Request<ImportVolumeRequest>,
+    ) -> Self::ImportVolumeFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let socket = self.socket.clone();
+        let roots = Arc::clone(&self.import_roots);
+        self.run("ImportVolume", move || {
+            import::import_volume(socket, msg, &roots).map(Response::new)
+        })
+    }
+
+    /// Create template from replica
+    fn create_template(
+        &mut self,
+        request: Request<CreateTemplateRequest>,
+    ) -> Self::CreateTemplateFuture {
+        let op = match self.begin("CreateTemplate") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let uuid = msg.uuid;
+        let replica = msg.replica;
+        debug!("Creating template {} from replica {} ...", uuid, replica);
+
+        let args = Some(jsondata::CreateTemplateArgs {
+            uuid: uuid.clone(),
+            replica: replica.clone(),
+        });
+
+        let f = jsonrpc::call::<_, ()>(&self.socket, "create_template", args)
+            .map(enclose! { (uuid, replica) move |_| {
+                info!("Created template {} from replica {}", uuid, replica);
+                Response::new(Null {})
+            }})
+            .map_err(enclose! { (uuid, replica) move |err| {
+                error!("Failed to create template {} from replica {}: {}",
+                       uuid, replica, err);
+                err.into_status()
+            }});
+
+        op.track(f)
+    }
+
+    /// Create clones of template
+    fn clone_template(
+        &mut self,
+        request: Request<CloneTemplateRequest>,
+    ) -> Self::CloneTemplateFuture {
+        let op = match self.begin("CloneTemplate") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let uuid = msg.uuid;
+        let count = msg.clones.len();
+        debug!("Creating {} clones of template {} ...", count, uuid);
+
+        let args = Some(jsondata::CloneTemplateArgs {
+            uuid: uuid.clone(),
+            clones: msg.clones,
+        });
+
+        let f = jsonrpc::call::<_, ()>(&self.socket, "clone_template", args)
+            .map(enclose! { (uuid) move |_| {
+                info!("Created {} clones of template {}", count, uuid);
+                Response::new(Null {})
+            }})
+            .map_err(enclose! { (uuid) move |err| {
+                error!("Failed to clone template {}: {}", uuid, err);
+                err.into_status()
+            }});
+
+        op.track(f)
+    }
+
+    /// Destroy (or retire) template
+    fn destroy_template(
+        &mut self,
+        request: Request<DestroyTemplateRequest>,
+    ) -> Self::DestroyTemplateFuture {
+        let op = match self.begin("DestroyTemplate") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let uuid = msg.uuid;
+        debug!("Destroying template {} ...", uuid);
+
+        let args = Some(jsondata::DestroyTemplateArgs {
+            uuid: uuid.clone(),
+        });
+
+        let f = jsonrpc::call::<_, ()>(&self.socket, "destroy_template", args)
+            .map(enclose! { (uuid) move |_| {
+                info!("Destroyed template {}", uuid);
+                Response::new(Null {})
+            }})
+            .map_err(enclose! { (uuid) move |err| {
+                error!("Failed to destroy template {}: {}", uuid, err);
+                err.into_status()
+            }});
+
+        op.track(f)
+    }
+
+    /// List templates
+    fn list_templates(
+        &mut self,
+        request: Request<Null>,
+    ) -> Self::ListTemplatesFuture {
+        if let Some(status) = self.throttle("list_templates") {
+            return Box::new(future::err(status));
+        }
+
+        let msg = request.into_inner();
+
+        trace!("{:?}", msg);
+
+        let f = jsonrpc::call::<(), Vec<jsondata::Template>>(
+            &self.socket,
+            "list_templates",
+            None,
+        )
+        .map(move |templates| {
+            debug!("Got list of {} templates", templates.len());
+            let resp = Response::new(ListTemplatesReply {
+                templates: templates
+                    .iter()
+                    .map(|t| Template {
+                        uuid: t.uuid.clone(),
+                        pool: t.pool.clone(),
+                        size: t.size,
+                        clones: t.clones,
+                        retired: t.retired,
+                    })
+                    .collect(),
+            });
+            trace!("{:?}", resp);
+            resp
+        })
+        .map_err(|err| {
+            error!("Getting templates failed: {}", err);
+            err.into_status()
+        });
+
+        Box::new(f)
+    }
+
     fn create_blkdev(
         &mut self,
         request: Request<CreateBlkdevRequest>,
     ) -> Self::CreateBlkdevFuture {
-        nbd::create_blkdev(self.socket.clone(), &request.into_inner())
+        let socket = self.socket.clone();
+        let fencing = self.node.fencing.clone();
+
+        self.run("CreateBlkdev", move || {
+            let msg = request.into_inner();
+            // the node is the target of the volume, stale attachments
+            // must not get the device
+            if msg.fencing_epoch > 0 {
+                if let Err(reason) = fencing.check(&msg.uuid, msg.fencing_epoch)
+                {
+                    return Either::A(future::err(Status::new(
+                        Code::FailedPrecondition,
+                        reason,
+                    )));
+                }
+            }
+            Either::B(nbd::create_blkdev(socket, &msg))
+        })
     }
 
     fn destroy_blkdev(
         &mut self,
         request: Request<DestroyBlkdevRequest>,
     ) -> Self::DestroyPoolFuture {
-        nbd::destroy_blkdev(self.socket.clone(), &request.into_inner())
+        let socket = self.socket.clone();
+
+        self.run("DestroyBlkdev", move || {
+            nbd::destroy_blkdev(socket, &request.into_inner())
+        })
     }
 
     fn create_nexus(
         &mut self,
         request: Request<CreateNexusRequest>,
     ) -> Self::CreateNexusFuture {
+        let op = match self.begin("CreateNexus") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
         trace!("{:?}", msg);
 
-        Box::new(
+        op.track(
             jsonrpc::call(&self.socket, "create_nexus", Some(msg))
                 .map_err(|e| e.into_status())
                 .map(|name| {
@@ -375,9 +885,13 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<DestroyNexusRequest>,
     ) -> Self::DestroyNexusFuture {
+        let op = match self.begin("DestroyNexus") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
         trace!("{:?}", msg);
-        Box::new(
+        op.track(
             jsonrpc::call(&self.socket, "destroy_nexus", Some(msg))
                 .map_err(|e| e.into_status())
                 .map(|_: String| Response::new(Null {})),
@@ -385,6 +899,10 @@ impl service::server::Mayastor for MayastorService {
     }
 
     fn list_nexus(&mut self, _request: Request<Null>) -> Self::ListNexusFuture {
+        if let Some(status) = self.throttle("list_nexus") {
+            return Box::new(future::err(status));
+        }
+
         Box::new(
             jsonrpc::call::<(), ListNexusReply>(
                 &self.socket,
@@ -400,9 +918,28 @@ impl service::server::Mayastor for MayastorService {
         &mut self,
         request: Request<PublishNexusRequest>,
     ) -> Self::PublishNexusFuture {
+        let op = match self.begin("PublishNexus") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let mut msg = request.into_inner();
         trace!("{:?}", msg);
 
+        if msg.share == ShareProtocol::VhostUser as i32 {
+            return op.track(
+                vhost::publish(
+                    self.socket.clone(),
+                    self.vhost_dir.clone(),
+                    msg.bdev_name,
+                )
+                .map(|device_path| {
+                    Response::new(PublishNexusReply {
+                        device_path,
+                    })
+                }),
+            );
+        }
+
         if let Some(d) = nbd::NbdDevInfo::new() {
             let socket = self.socket.clone();
 
@@ -410,10 +947,10 @@ impl service::server::Mayastor for MayastorService {
                 msg.nbd_device = d.to_string();
             }
 
-            Box::new(
-                jsonrpc::call::<(), _>(&self.socket, "get_nbd_disks", None)
+            op.track(
+                spdk_methods::get_nbd_disks(&self.socket)
                     .map_err(|e| e.into_status())
-                    .and_then(move |nbds: Vec<jsondata::NbdDisk>| {
+                    .and_then(move |nbds| {
                         if let Some(nbd) =
                             nbds.iter().find(|n| n.bdev_name == msg.bdev_name)
                         {
@@ -429,17 +966,19 @@ impl service::server::Mayastor for MayastorService {
                             )))
                         } else {
                             Either::B(
-                                jsonrpc::call(
+                                spdk_methods::start_nbd_disk(
                                     &socket,
-                                    "start_nbd_disk",
-                                    Some(msg.clone()),
+                                    StartNbdDiskArgs {
+                                        bdev_name: msg.bdev_name.clone(),
+                                        nbd_device: msg.nbd_device.clone(),
+                                    },
                                 )
                                 .map_err(move |e| {
                                     d.put_back();
                                     e.into_status()
                                 })
                                 .and_then(
-                                    move |device_path: String| {
+                                    move |device_path| {
                                         info!(
                                             "{} published on {}",
                                             msg.bdev_name, device_path
@@ -471,12 +1010,45 @@ impl service::server::Mayastor for MayastorService {
         }
     }
 
+    fn unpublish_nexus(
+        &mut self,
+        request: Request<UnpublishNexusRequest>,
+    ) -> Self::UnpublishNexusFuture {
+        let socket = self.socket.clone();
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        self.run("UnpublishNexus", move || {
+            vhost::unpublish(socket.clone(), msg.bdev_name.clone())
+                .and_then(move |_| nbd::unpublish(socket, msg.bdev_name))
+                .map(|_| Response::new(Null {}))
+        })
+    }
+
+    fn list_vhost_controllers(
+        &mut self,
+        _request: Request<Null>,
+    ) -> Self::ListVhostControllersFuture {
+        if let Some(status) = self.throttle("list_vhost_controllers") {
+            return Box::new(future::err(status));
+        }
+        Box::new(vhost::list(self.socket.clone()).map(|controllers| {
+            Response::new(ListVhostControllersReply {
+                controllers,
+            })
+        }))
+    }
+
     fn child_operation(
         &mut self,
         request: Request<ChildNexusRequest>,
     ) -> Self::ChildOperationFuture {
+        let op = match self.begin("ChildOperation") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
         let msg = request.into_inner();
-        Box::new(
+        op.track(
             jsonrpc::call(&self.socket, "offline_child", Some(msg))
                 .map_err(|e| e.into_status())
                 .and_then(|name| {
@@ -487,4 +1059,219 @@ impl service::server::Mayastor for MayastorService {
                 }),
         )
     }
+
+    /// Return stream of log records of this server.
+    fn tail_logs(
+        &mut self,
+        request: Request<TailLogsRequest>,
+    ) -> Self::TailLogsFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        Box::new(future::result(
+            logtail::subscribe(&msg)
+                .map(|stream| {
+                    let stream: Self::TailLogsStream =
+                        Box::new(stream.map_err(|_| {
+                            Status::new(
+                                Code::Internal,
+                                "Log stream failed".to_owned(),
+                            )
+                        }));
+                    Response::new(stream)
+                })
+                .map_err(|err| Status::new(Code::InvalidArgument, err)),
+        ))
+    }
+
+    /// Return node-local information for support bundle.
+    fn get_support_info(
+        &mut self,
+        _request: Request<Null>,
+    ) -> Self::GetSupportInfoFuture {
+        debug!("Collecting support information");
+        let jobs = self.quiesce.pending();
+
+        Box::new(
+            support::collect(&self.socket, &self.config, &self.staging).map(
+                move |mut files| {
+                    files.push(SupportFile {
+                        name: "jobs.json".to_owned(),
+                        content: serde_json::to_string_pretty(&jobs).unwrap(),
+                    });
+                    Response::new(SupportInfoReply {
+                        files,
+                    })
+                },
+            ),
+        )
+    }
+
+    /// Save config of the data path to a file on the node.
+    fn save_config(
+        &mut self,
+        request: Request<SaveConfigRequest>,
+    ) -> Self::SaveConfigFuture {
+        let op = match self.begin("SaveConfig") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+        op.track(
+            jsonrpc::call(&self.socket, "save_config", Some(msg))
+                .map_err(|e| e.into_status())
+                .map(Response::new),
+        )
+    }
+
+    /// Re-create the data path from the config saved on the node.
+    fn load_config(
+        &mut self,
+        request: Request<LoadConfigRequest>,
+    ) -> Self::LoadConfigFuture {
+        let op = match self.begin("LoadConfig") {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+        op.track(
+            jsonrpc::call(&self.socket, "load_config", Some(msg))
+                .map_err(|e| e.into_status())
+                .map(Response::new),
+        )
+    }
+
+    /// Compare the data path with the config saved on the node and
+    /// optionally re-create missing objects.
+    fn check_config(
+        &mut self,
+        request: Request<CheckConfigRequest>,
+    ) -> Self::CheckConfigFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+        let reapply = msg.reapply;
+        let call = jsonrpc::call(&self.socket, "check_config", Some(msg))
+            .map_err(|e| e.into_status())
+            .map(Response::new);
+        if reapply {
+            let op = match self.begin("CheckConfig") {
+                Ok(op) => op,
+                Err(status) => return Box::new(future::err(status)),
+            };
+            op.track(call)
+        } else {
+            if let Some(status) = self.throttle("check_config") {
+                return Box::new(future::err(status));
+            }
+            Box::new(call)
+        }
+    }
+
+    /// Run fio benchmark on a staged volume.
+    fn benchmark_volume(
+        &mut self,
+        request: Request<BenchmarkVolumeRequest>,
+    ) -> Self::BenchmarkVolumeFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+        // the volume stays locked until fio finishes, so that it cannot be
+        // published after the check that it is not in use
+        let op = match self.check_writable("BenchmarkVolume").and_then(|_| {
+            self.quiesce.begin_volume("BenchmarkVolume", &msg.uuid)
+        }) {
+            Ok(op) => op,
+            Err(status) => return Box::new(future::err(status)),
+        };
+        op.track(
+            benchmark::benchmark_volume(&self.staging, &msg).map(Response::new),
+        )
+    }
+
+    /// Run the soak test of the node.
+    fn soak(&mut self, request: Request<SoakRequest>) -> Self::SoakFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let socket = self.socket.clone();
+        let node = self.node.clone();
+        self.run("Soak", move || {
+            soak::soak(socket, node, msg).map(Response::new)
+        })
+    }
+
+    /// Return recent operations on a volume.
+    fn get_volume_history(
+        &mut self,
+        request: Request<GetVolumeHistoryRequest>,
+    ) -> Self::GetVolumeHistoryFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        if msg.uuid.is_empty() || msg.uuid.contains('/') {
+            return Box::new(future::err(Status::new(
+                Code::InvalidArgument,
+                format!("Invalid volume uuid \"{}\"", msg.uuid),
+            )));
+        }
+        Box::new(match self.history.get(&msg.uuid) {
+            Ok(operations) => {
+                future::ok(Response::new(GetVolumeHistoryReply {
+                    operations,
+                }))
+            }
+            Err(reason) => future::err(Status::new(Code::Internal, reason)),
+        })
+    }
+
+    /// Stop accepting control operations and wait for those in progress.
+    fn quiesce_io(
+        &mut self,
+        request: Request<QuiesceIoRequest>,
+    ) -> Self::QuiesceIoFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        if let Err(status) = self.check_writable("QuiesceIo") {
+            return Box::new(future::err(status));
+        }
+        Box::new(self.quiesce.quiesce(msg.timeout).map(Response::new))
+    }
+
+    /// Accept control operations again.
+    fn resume_io(&mut self, _request: Request<Null>) -> Self::ResumeIoFuture {
+        if let Err(status) = self.check_writable("ResumeIo") {
+            return Box::new(future::err(status));
+        }
+        self.quiesce.resume();
+        Box::new(future::ok(Response::new(Null {})))
+    }
+
+    /// Run the reconcilers on the node, pool or volume and report what they
+    /// have found and fixed.
+    fn reconcile_now(
+        &mut self,
+        request: Request<ReconcileNowRequest>,
+    ) -> Self::ReconcileNowFuture {
+        let msg = request.into_inner();
+        trace!("{:?}", msg);
+
+        let dry_run = msg.dry_run;
+        let socket = self.socket.clone();
+        let staging = self.staging.clone();
+        let cleanup = self.node.cleanup.clone();
+        let reconcile = move || {
+            reconcile::reconcile_now(socket, staging, cleanup, msg)
+                .map(Response::new)
+        };
+        if dry_run {
+            if let Some(status) = self.throttle("reconcile_now") {
+                return Box::new(future::err(status));
+            }
+            Box::new(reconcile())
+        } else {
+            self.run("ReconcileNow", reconcile)
+        }
+    }
 }
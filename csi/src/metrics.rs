@@ -0,0 +1,363 @@
+//! Success/error counters of CSI node methods for alerting on error budgets.
+//!
+//! Every call of a node method is counted by the method and gRPC status code
+//! of the reply. Besides the all-time counters we keep per-minute buckets
+//! for a rolling window, from which the availability of each method is
+//! computed. Only server-side errors (internal, unavailable, timeouts, ...)
+//! count against the availability. Errors caused by the caller (invalid
+//! argument, not found, ...) do not.
+//!
+//! The metrics are served in prometheus text format on /metrics, together
+//! with metrics of json-rpc calls to mayastor collected by jsonrpc crate.
+
+use crate::{
+    csi::{server::Node as _, *},
+    history::{pod_requester, History},
+    node::Node,
+    quiesce::Quiesce,
+};
+use futures::Future;
+use hyper::{service::service_fn_ok, Body, Response as HttpResponse, Server};
+use prometheus::{Encoder, TextEncoder};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tower_grpc::{Code, Request, Response, Status};
+
+/// Codes which mean that we failed, rather than the caller.
+const SERVER_ERRORS: [Code; 5] = [
+    Code::Unknown,
+    Code::Internal,
+    Code::Unavailable,
+    Code::DeadlineExceeded,
+    Code::DataLoss,
+];
+
+/// Number of calls and server-side errors in one minute.
+#[derive(Debug)]
+struct Bucket {
+    minute: u64,
+    total: u64,
+    errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct MethodStats {
+    /// all-time counters by status code
+    codes: BTreeMap<String, u64>,
+    /// per-minute buckets of the rolling window (oldest first)
+    window: VecDeque<Bucket>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// length of the rolling window in minutes
+    window: u64,
+    methods: Arc<Mutex<BTreeMap<&'static str, MethodStats>>>,
+}
+
+fn now_minute() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+        / 60
+}
+
+impl Metrics {
+    pub fn new(window: u64) -> Self {
+        Self {
+            window: window.max(1),
+            methods: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Record result of a call.
+    pub fn record(&self, method: &'static str, code: Code) {
+        let minute = now_minute();
+        let error = SERVER_ERRORS.contains(&code);
+        let mut methods = self.methods.lock().unwrap();
+        let stats = methods.entry(method).or_default();
+
+        *stats.codes.entry(format!("{:?}", code)).or_insert(0) += 1;
+        match stats.window.back_mut() {
+            Some(bucket) if bucket.minute == minute => {
+                bucket.total += 1;
+                bucket.errors += error as u64;
+            }
+            _ => stats.window.push_back(Bucket {
+                minute,
+                total: 1,
+                errors: error as u64,
+            }),
+        }
+        while stats
+            .window
+            .front()
+            .map_or(false, |b| b.minute + self.window <= minute)
+        {
+            stats.window.pop_front();
+        }
+    }
+
+    /// Count the result of the call when the future completes.
+    pub fn observe<T, F>(
+        &self,
+        method: &'static str,
+        fut: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        let metrics = self.clone();
+
+        Box::new(fut.then(move |res| {
+            metrics.record(
+                method,
+                match &res {
+                    Ok(_) => Code::Ok,
+                    Err(status) => status.code(),
+                },
+            );
+            res
+        }))
+    }
+
+    /// Render the metrics in prometheus text format.
+    pub fn render(&self) -> String {
+        let oldest = now_minute().saturating_sub(self.window - 1);
+        let methods = self.methods.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP csi_requests_total Number of CSI calls by method and status code.\n\
+             # TYPE csi_requests_total counter\n",
+        );
+        for (method, stats) in methods.iter() {
+            for (code, count) in &stats.codes {
+                out.push_str(&format!(
+                    "csi_requests_total{{method=\"{}\",code=\"{}\"}} {}\n",
+                    method, code, count
+                ));
+            }
+        }
+        out.push_str(&format!(
+            "# HELP csi_availability Ratio of CSI calls without server error in the last {} minutes.\n\
+             # TYPE csi_availability gauge\n",
+            self.window
+        ));
+        for (method, stats) in methods.iter() {
+            let (total, errors) = stats
+                .window
+                .iter()
+                .filter(|b| b.minute >= oldest)
+                .fold((0, 0), |(t, e), b| (t + b.total, e + b.errors));
+            let availability = if total == 0 {
+                1.0
+            } else {
+                (total - errors) as f64 / total as f64
+            };
+            out.push_str(&format!(
+                "csi_availability{{method=\"{}\"}} {}\n",
+                method, availability
+            ));
+        }
+
+        let mut buf = Vec::new();
+        match TextEncoder::new().encode(&prometheus::gather(), &mut buf) {
+            Ok(()) => out.push_str(&String::from_utf8_lossy(&buf)),
+            Err(err) => warn!("Failed to encode json-rpc metrics: {}", err),
+        }
+        out
+    }
+
+    /// Serve the metrics over http.
+    pub fn serve(
+        &self,
+        addr: &SocketAddr,
+    ) -> impl Future<Item = (), Error = ()> {
+        let metrics = self.clone();
+
+        Server::bind(addr)
+            .serve(move || {
+                let metrics = metrics.clone();
+                service_fn_ok(move |req| {
+                    if req.uri().path() == "/metrics" {
+                        HttpResponse::new(Body::from(metrics.render()))
+                    } else {
+                        HttpResponse::builder()
+                            .status(404)
+                            .body(Body::empty())
+                            .unwrap()
+                    }
+                })
+            })
+            .map_err(|err| error!("Metrics server failed: {}", err))
+    }
+}
+
+type BoxFuture<T> = Box<dyn Future<Item = Response<T>, Error = Status> + Send>;
+
+/// CSI node service which counts results of the calls, records the
+/// lifecycle operations in the history of volumes and rejects them while
+/// the node is quiesced or another operation on the volume is in progress.
+#[derive(Clone)]
+pub struct MeteredNode {
+    pub node: Node,
+    pub metrics: Metrics,
+    pub history: History,
+    pub quiesce: Quiesce,
+}
+
+impl server::Node for MeteredNode {
+    type NodeGetInfoFuture = BoxFuture<NodeGetInfoResponse>;
+    type NodeGetCapabilitiesFuture = BoxFuture<NodeGetCapabilitiesResponse>;
+    type NodePublishVolumeFuture = BoxFuture<NodePublishVolumeResponse>;
+    type NodeUnpublishVolumeFuture = BoxFuture<NodeUnpublishVolumeResponse>;
+    type NodeGetVolumeStatsFuture = BoxFuture<NodeGetVolumeStatsResponse>;
+    type NodeStageVolumeFuture = BoxFuture<NodeStageVolumeResponse>;
+    type NodeUnstageVolumeFuture = BoxFuture<NodeUnstageVolumeResponse>;
+    type NodeExpandVolumeFuture = BoxFuture<NodeExpandVolumeResponse>;
+
+    fn node_get_info(
+        &mut self,
+        request: Request<NodeGetInfoRequest>,
+    ) -> Self::NodeGetInfoFuture {
+        self.metrics
+            .observe("NodeGetInfo", self.node.node_get_info(request))
+    }
+
+    fn node_get_capabilities(
+        &mut self,
+        request: Request<NodeGetCapabilitiesRequest>,
+    ) -> Self::NodeGetCapabilitiesFuture {
+        self.metrics.observe(
+            "NodeGetCapabilities",
+            self.node.node_get_capabilities(request),
+        )
+    }
+
+    fn node_publish_volume(
+        &mut self,
+        request: Request<NodePublishVolumeRequest>,
+    ) -> Self::NodePublishVolumeFuture {
+        let msg = request.get_ref();
+        let volume_id = msg.volume_id.clone();
+        let path = msg.target_path.clone();
+        let requester = pod_requester(&msg.volume_context);
+
+        let node = &mut self.node;
+
+        let fut =
+            self.quiesce
+                .run_volume("NodePublishVolume", &volume_id, || {
+                    node.node_publish_volume(request)
+                });
+
+        self.history.track(
+            volume_id,
+            "NodePublishVolume",
+            path,
+            requester,
+            self.metrics.observe("NodePublishVolume", fut),
+        )
+    }
+
+    fn node_unpublish_volume(
+        &mut self,
+        request: Request<NodeUnpublishVolumeRequest>,
+    ) -> Self::NodeUnpublishVolumeFuture {
+        let volume_id = request.get_ref().volume_id.clone();
+        let path = request.get_ref().target_path.clone();
+
+        let node = &mut self.node;
+
+        let fut =
+            self.quiesce
+                .run_volume("NodeUnpublishVolume", &volume_id, || {
+                    node.node_unpublish_volume(request)
+                });
+
+        self.history.track(
+            volume_id,
+            "NodeUnpublishVolume",
+            path,
+            String::new(),
+            self.metrics.observe("NodeUnpublishVolume", fut),
+        )
+    }
+
+    fn node_get_volume_stats(
+        &mut self,
+        request: Request<NodeGetVolumeStatsRequest>,
+    ) -> Self::NodeGetVolumeStatsFuture {
+        self.metrics.observe(
+            "NodeGetVolumeStats",
+            self.node.node_get_volume_stats(request),
+        )
+    }
+
+    fn node_stage_volume(
+        &mut self,
+        request: Request<NodeStageVolumeRequest>,
+    ) -> Self::NodeStageVolumeFuture {
+        let volume_id = request.get_ref().volume_id.clone();
+        let path = request.get_ref().staging_target_path.clone();
+
+        let node = &mut self.node;
+
+        let fut =
+            self.quiesce.run_volume("NodeStageVolume", &volume_id, || {
+                node.node_stage_volume(request)
+            });
+
+        self.history.track(
+            volume_id,
+            "NodeStageVolume",
+            path,
+            String::new(),
+            self.metrics.observe("NodeStageVolume", fut),
+        )
+    }
+
+    fn node_unstage_volume(
+        &mut self,
+        request: Request<NodeUnstageVolumeRequest>,
+    ) -> Self::NodeUnstageVolumeFuture {
+        let volume_id = request.get_ref().volume_id.clone();
+        let path = request.get_ref().staging_target_path.clone();
+
+        let node = &mut self.node;
+
+        let fut =
+            self.quiesce
+                .run_volume("NodeUnstageVolume", &volume_id, || {
+                    node.node_unstage_volume(request)
+                });
+
+        self.history.track(
+            volume_id,
+            "NodeUnstageVolume",
+            path,
+            String::new(),
+            self.metrics.observe("NodeUnstageVolume", fut),
+        )
+    }
+
+    fn node_expand_volume(
+        &mut self,
+        request: Request<NodeExpandVolumeRequest>,
+    ) -> Self::NodeExpandVolumeFuture {
+        let node = &mut self.node;
+
+        self.metrics.observe(
+            "NodeExpandVolume",
+            self.quiesce
+                .run("NodeExpandVolume", || node.node_expand_volume(request)),
+        )
+    }
+}
@@ -0,0 +1,109 @@
+//! Migration of state created by older versions of the plugin.
+//!
+//! Older versions did not keep staging records, the only evidence of staged
+//! volumes are nbd devices exported by mayastor and their mounts. Here we
+//! reconstruct the records from those, so that the plugin can be upgraded
+//! without unstaging all volumes on the node first. Existing records are
+//! verified against the mount table.
+
+use crate::{
+    mount::find_mounts,
+    nbd,
+    staging::{StagingRecord, StagingStore, RECORD_VERSION},
+};
+use jsonrpc::spdk_methods;
+use tokio::runtime::Runtime;
+
+/// Suffix of staging path used by kubelet for CSI volumes.
+pub const STAGING_SUFFIX: &str = "/globalmount";
+
+/// Create missing staging records and verify the existing ones. Returns
+/// error if any of the volumes on the node are in unexpected state.
+pub fn migrate_state(socket: &str, store: &StagingStore) -> Result<(), String> {
+    info!("Migrating staging records in {}", store.dir().display());
+
+    let mut rt = Runtime::new().unwrap();
+    let nbd_disks = rt
+        .block_on(spdk_methods::get_nbd_disks(socket))
+        .map_err(|err| format!("Failed to list nbd disks: {}", err))?;
+    let bdevs = rt
+        .block_on(spdk_methods::get_bdevs(socket, None))
+        .map_err(|err| format!("Failed to list bdevs: {}", err))?;
+    let volume_ids: Vec<String> = nbd_disks
+        .iter()
+        .map(|d| nbd::resolve_volume_id(&bdevs, &d.bdev_name))
+        .collect();
+    let records = store.list()?;
+    let mut failures = 0;
+
+    for (disk, volume_id) in nbd_disks.iter().zip(&volume_ids) {
+        let mounts = find_mounts(&disk.nbd_device);
+
+        match records.iter().find(|r| &r.volume_id == volume_id) {
+            Some(record) => {
+                if record.device != disk.nbd_device {
+                    error!(
+                        "Volume {} is staged on {} but exported on {}",
+                        record.volume_id, record.device, disk.nbd_device
+                    );
+                    failures += 1;
+                } else if !mounts.iter().any(|m| m.dest == record.staging_path)
+                {
+                    error!(
+                        "Volume {} is not mounted at {}",
+                        record.volume_id, record.staging_path
+                    );
+                    failures += 1;
+                } else if record.version < RECORD_VERSION {
+                    let mut record = record.clone();
+                    record.version = RECORD_VERSION;
+                    store.save(&record)?;
+                    info!("Upgraded staging record of {}", record.volume_id);
+                } else {
+                    debug!("Staging record of {} is valid", record.volume_id);
+                }
+            }
+            None => {
+                // the rest of the mounts are bind mounts done by publish
+                match mounts.iter().find(|m| m.dest.ends_with(STAGING_SUFFIX)) {
+                    Some(mount) => {
+                        store.save(&StagingRecord::new(
+                            volume_id,
+                            &mount.dest,
+                            &disk.nbd_device,
+                            &mount.fstype,
+                            &mount.opts,
+                        ))?;
+                        info!(
+                            "Created staging record of {} ({} at {})",
+                            volume_id, disk.nbd_device, mount.dest
+                        );
+                    }
+                    None => {
+                        warn!(
+                            "Volume {} exported on {} is not staged",
+                            volume_id, disk.nbd_device
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    for record in &records {
+        if !volume_ids.iter().any(|id| id == &record.volume_id) {
+            error!(
+                "Volume {} has a staging record but it is not exported",
+                record.volume_id
+            );
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        Err(format!("{} volumes failed verification", failures))
+    } else {
+        info!("Migration of {} volumes finished", nbd_disks.len());
+        Ok(())
+    }
+}
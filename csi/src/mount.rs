@@ -1,5 +1,9 @@
 //! Utility functions for working with mountpoints
 
+use crate::{
+    fshelper::{self, FsTool},
+    hostenv,
+};
 use proc_mounts::MountIter;
 use run_script::ScriptOptions;
 use std::process::Command;
@@ -9,6 +13,7 @@ use sys_mount::{unmount, FilesystemType, Mount, MountFlags, UnmountFlags};
 pub struct MountInfo {
     pub source: String,
     pub dest: String,
+    pub fstype: String,
     pub opts: Vec<String>,
 }
 
@@ -19,6 +24,15 @@ pub struct Fs {
     pub defaults: Vec<String>,
 }
 
+// Iterator over the mount table. Its availability is checked at startup, so
+// if it fails now, /proc has been masked meanwhile and there is no way to
+// tell what is mounted.
+fn mount_iter() -> MountIter {
+    MountIter::new().unwrap_or_else(|err| {
+        panic!("{}", hostenv::proc_unavailable(&err.to_string()))
+    })
+}
+
 // Return mountinfo matching source or destination or source and destination
 // depending on 'and' flag.
 pub fn match_mount(
@@ -26,7 +40,7 @@ pub fn match_mount(
     destination: Option<&str>,
     and: bool,
 ) -> Option<MountInfo> {
-    for mount in MountIter::new().unwrap() {
+    for mount in mount_iter() {
         if let Ok(mount) = mount {
             let source_match = if let Some(src) = source {
                 if mount.source.to_string_lossy() == src {
@@ -82,6 +96,7 @@ pub fn match_mount(
                 return Some(MountInfo {
                     source: mount.source.to_string_lossy().to_string(),
                     dest: mount.dest.to_string_lossy().to_string(),
+                    fstype: mount.fstype,
                     opts: mount.options,
                 });
             }
@@ -90,6 +105,21 @@ pub fn match_mount(
     None
 }
 
+// Return all mounts of given source device. Note that besides the mount of
+// the device itself, bind mounts of it show the device as the source too.
+pub fn find_mounts(source: &str) -> Vec<MountInfo> {
+    mount_iter()
+        .filter_map(|mount| mount.ok())
+        .filter(|mount| mount.source.to_string_lossy() == source)
+        .map(|mount| MountInfo {
+            source: mount.source.to_string_lossy().to_string(),
+            dest: mount.dest.to_string_lossy().to_string(),
+            fstype: mount.fstype,
+            opts: mount.options,
+        })
+        .collect()
+}
+
 // XXX we rely that ordering of options between the two mounts is the same
 // which is a bit fragile.
 pub fn mount_opts_compare(m1: &[String], m2: &[String], ro: bool) -> bool {
@@ -172,10 +202,12 @@ pub fn probe_filesystems() -> Result<Vec<Fs>, String> {
 // sysfs so here is a hack. I feel bad about this. I hate to do this
 // but I've given up. Linux won, there you have it.
 fn probe_defaults(fsname: &str) -> Result<Vec<String>, String> {
-    let output = Command::new(format!("mkfs.{}", fsname))
+    let output = fshelper::command(FsTool::Mkfs, fsname)
         .arg("/tmp/fs.img")
         .output()
-        .expect("Failed to execute mkfs command");
+        .map_err(|e| {
+            format!("Failed to execute mkfs.{} command: {}", fsname, e)
+        })?;
     if !output.status.success() {
         return Err(format!(
             "Failed to mkfs {} fs: {}",
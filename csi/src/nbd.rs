@@ -5,7 +5,9 @@ use crate::{
     csi::{NodeStageVolumeRequest, NodeStageVolumeResponse},
     device,
     format::probed_format,
+    fsfeatures,
     mount::{match_mount, mount_fs, Fs},
+    staging::{StagingRecord, StagingStore, RAW_BLOCK},
 };
 use enclose::enclose;
 use futures::{
@@ -13,13 +15,129 @@ use futures::{
     Future,
 };
 use glob::glob;
-use jsonrpc;
-use rpc::jsonrpc as jsondata;
+use jsonrpc::{
+    self,
+    spdk_methods::{self, Bdev, NbdDisk, StartNbdDiskArgs, StopNbdDiskArgs},
+};
 use std::fmt;
 use sysfs;
 use tower_grpc::{Code, Response, Status};
 
-use std::{path::PathBuf, sync::Mutex};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+
+/// Prefix of the name of compress bdev on top of the replica of compressed
+/// volume. The compress bdev is what gets exported over nbd.
+const COMPRESSED_PREFIX: &str = "COMP_";
+
+/// Return the volume id for the name of the bdev exported over nbd.
+pub fn volume_id(bdev_name: &str) -> &str {
+    if bdev_name.starts_with(COMPRESSED_PREFIX) {
+        &bdev_name[COMPRESSED_PREFIX.len() ..]
+    } else {
+        bdev_name
+    }
+}
+
+/// Return the volume id for the name of the bdev exported over nbd, which
+/// can be a clone of a template. Clones have random bdev names and the
+/// volume id is their alias (the other alias is "pool/name").
+pub fn resolve_volume_id(bdevs: &[Bdev], bdev_name: &str) -> String {
+    let name = volume_id(bdev_name);
+    bdevs
+        .iter()
+        .find(|b| b.name == name)
+        .and_then(|b| b.aliases.iter().find(|a| !a.contains('/')))
+        .map_or_else(|| name.to_owned(), |alias| alias.clone())
+}
+
+/// Resolve the name of the bdev which should be exported for the volume
+/// (compress bdev if the volume is compressed).
+fn exported_bdev(
+    socket: &str,
+    uuid: String,
+) -> impl Future<Item = String, Error = jsonrpc::error::Error> {
+    spdk_methods::get_bdevs(socket, None).map(move |bdevs| {
+        let compressed = format!("{}{}", COMPRESSED_PREFIX, uuid);
+        if bdevs.iter().any(|b| b.name == compressed) {
+            compressed
+        } else {
+            uuid
+        }
+    })
+}
+
+/// Directory of the nbd module in sysfs (exists if the module is loaded).
+const NBD_MODULE: &str = "/sys/module/nbd";
+/// How long to wait for the devices to appear after loading the module.
+const NBD_LOAD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Load nbd kernel module with the given number of devices and partitions
+/// per device if there are no nbd devices. The error says what is wrong and
+/// how to fix it, so that the node does not silently report zero volumes.
+pub fn load_module(nbds_max: u32, max_part: u32) -> Result<(), String> {
+    if NbdDevInfo::num_devices() > 0 {
+        debug!("Found {} nbd devices", NbdDevInfo::num_devices());
+        return Ok(());
+    }
+    if Path::new(NBD_MODULE).exists() {
+        return Err(format!(
+            "nbd module is loaded without any devices: reload it with \
+             nbds_max={} (rmmod nbd && modprobe nbd nbds_max={})",
+            nbds_max, nbds_max
+        ));
+    }
+
+    info!(
+        "Loading nbd module with nbds_max={} max_part={}",
+        nbds_max, max_part
+    );
+    let output = Command::new("modprobe")
+        .arg("nbd")
+        .arg(format!("nbds_max={}", nbds_max))
+        .arg(format!("max_part={}", max_part))
+        .output()
+        .map_err(|err| {
+            format!(
+                "Failed to run modprobe: {} (is kmod installed in the image?)",
+                err
+            )
+        })?;
+    if !output.status.success() {
+        return Err(format!(
+            "modprobe nbd failed ({}): {}. The module must be available in \
+             /lib/modules of the host mounted to the container and the \
+             container must be privileged",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    // devices are created by the module asynchronously in some kernels
+    let mut waited = Duration::from_millis(0);
+    while NbdDevInfo::num_devices() == 0 {
+        if waited >= NBD_LOAD_TIMEOUT {
+            return Err(format!(
+                "nbd module has been loaded but no nbd devices have appeared \
+                 in /sys/class/block within {}s",
+                NBD_LOAD_TIMEOUT.as_secs()
+            ));
+        }
+        thread::sleep(Duration::from_millis(100));
+        waited += Duration::from_millis(100);
+    }
+    info!(
+        "Loaded nbd module with {} devices",
+        NbdDevInfo::num_devices()
+    );
+    Ok(())
+}
 
 lazy_static! {
     static ref ARRAY: Mutex<Vec<u32>> =
@@ -50,6 +168,8 @@ pub fn nbd_stage_volume(
     msg: &NodeStageVolumeRequest,
     filesystem: Fs,
     mnt_opts: Vec<String>,
+    read_ahead: Option<u32>,
+    staging: StagingStore,
 ) -> Box<
     dyn Future<Item = Response<NodeStageVolumeResponse>, Error = Status> + Send,
 > {
@@ -94,6 +214,18 @@ pub fn nbd_stage_volume(
             ok((false, nbd_disk, target_path, uuid))
         })
         .and_then(move |mounted| {
+            // applied also if already mounted, the parameter may have changed
+            if let Some(kb) = read_ahead {
+                if let Err(reason) =
+                    device::set_read_ahead(&mounted.1.nbd_device, kb)
+                {
+                    error!("{}", reason);
+                    return Either::B(Box::new(err(Status::new(
+                        Code::Internal,
+                        reason,
+                    ))));
+                }
+            }
             if !mounted.0 {
                 Either::A(
                     probed_format(&mounted.1.nbd_device, &filesystem.name)
@@ -107,28 +239,52 @@ pub fn nbd_stage_volume(
                                         Err("simulated".to_owned())
                                     }
                                 } else {
-                                    mount_fs(
+                                    match fsfeatures::verify(
                                         &mounted.1.nbd_device,
-                                        &mounted.2,
-                                        false,
                                         &filesystem.name,
-                                        &mnt_opts,
-                                    )
+                                    ) {
+                                        Ok(features) => mount_fs(
+                                            &mounted.1.nbd_device,
+                                            &mounted.2,
+                                            false,
+                                            &filesystem.name,
+                                            &mnt_opts,
+                                        )
+                                        .map(|_| features),
+                                        Err(reason) => {
+                                            return Box::new(err(Status::new(
+                                                Code::FailedPrecondition,
+                                                reason,
+                                            )))
+                                        }
+                                    }
                                 };
 
-                            if let Err(reason) = mnt_result {
-                                Box::new(err(Status::new(
+                            match mnt_result {
+                                Err(reason) => Box::new(err(Status::new(
                                     Code::Internal,
                                     reason,
-                                )))
-                            } else {
-                                info!(
-                                    "staged {} on {}",
-                                    &mounted.3, &mounted.2
-                                );
-                                Box::new(ok(Response::new(
-                                    NodeStageVolumeResponse {},
-                                )))
+                                ))),
+                                Ok(features) => {
+                                    info!(
+                                        "staged {} on {}",
+                                        &mounted.3, &mounted.2
+                                    );
+                                    let mut record = StagingRecord::new(
+                                        &mounted.3,
+                                        &mounted.2,
+                                        &mounted.1.nbd_device,
+                                        &filesystem.name,
+                                        &mnt_opts,
+                                    );
+                                    record.features = features;
+                                    if let Err(reason) = staging.save(&record) {
+                                        warn!("{}", reason);
+                                    }
+                                    Box::new(ok(Response::new(
+                                        NodeStageVolumeResponse {},
+                                    )))
+                                }
                             }
                         }),
                 )
@@ -142,6 +298,56 @@ pub fn nbd_stage_volume(
     Box::new(f)
 }
 
+/// Stage a raw block volume. There is no filesystem to create and mount,
+/// the device is bind mounted to the target path by publish and the staging
+/// record is how publish finds it. The record also says whether the device
+/// should be opened with O_DIRECT only, which is checked by volume stats.
+pub fn nbd_stage_block(
+    socket: String,
+    msg: &NodeStageVolumeRequest,
+    read_ahead: Option<u32>,
+    direct_io: bool,
+    staging: StagingStore,
+) -> Box<
+    dyn Future<Item = Response<NodeStageVolumeResponse>, Error = Status> + Send,
+> {
+    let uuid = msg.volume_id.clone();
+    let target_path = msg.staging_target_path.to_string();
+
+    let f = get_nbd_instance(&socket, &uuid).and_then(move |nbd_disk| {
+        let nbd_disk = match nbd_disk {
+            Some(nbd_disk) => nbd_disk,
+            None => {
+                error!("No device instance found for {}, likely a bug", &uuid);
+                return Err(Status::new(
+                    Code::Internal,
+                    "no such bdev exists".to_string(),
+                ));
+            }
+        };
+        if let Some(kb) = read_ahead {
+            device::set_read_ahead(&nbd_disk.nbd_device, kb)
+                .map_err(|reason| Status::new(Code::Internal, reason))?;
+        }
+        // unlike for filesystem volumes, the record is all there is to stage
+        let mut record = StagingRecord::new(
+            &uuid,
+            &target_path,
+            &nbd_disk.nbd_device,
+            RAW_BLOCK,
+            &[],
+        );
+        record.direct_io = direct_io;
+        staging
+            .save(&record)
+            .map_err(|reason| Status::new(Code::Internal, reason))?;
+        info!("staged raw block {} on {}", &uuid, &target_path);
+        Ok(Response::new(NodeStageVolumeResponse {}))
+    });
+
+    Box::new(f)
+}
+
 pub fn create_blkdev(
     socket: String,
     msg: &CreateBlkdevRequest,
@@ -182,16 +388,18 @@ pub fn create_blkdev(
             ok(())
         }})
         .map_err(|e| jsonrpc::error::Error::GenericError(e.to_string()))
-        .and_then(enclose! { (uuid) move |_| {
-            jsonrpc::call::<jsondata::StartNbdDiskArgs, String>(
+        .and_then(enclose! { (socket, uuid) move |_| {
+            exported_bdev(&socket, uuid)
+        }})
+        .and_then(move |bdev_name| {
+            spdk_methods::start_nbd_disk(
                 &socket,
-                "start_nbd_disk",
-                Some(jsondata::StartNbdDiskArgs {
-                    bdev_name: uuid,
+                StartNbdDiskArgs {
+                    bdev_name,
                     nbd_device: format!("{}", nbd_dev_info),
-                }),
+                },
             )
-        }})
+        })
         .and_then(move |nbd_device| {
             trace!("NBD device {} created", &nbd_device);
             device::await_size(&nbd_device).map_err(jsonrpc::error::Error::from)
@@ -244,12 +452,11 @@ pub fn destroy_blkdev(
         })
         .and_then(move |nbd_disk| {
             trace!("Stopping NBD device {}", nbd_disk.nbd_device);
-            jsonrpc::call::<jsondata::StopNbdDiskArgs, bool>(
+            spdk_methods::stop_nbd_disk(
                 &socket,
-                "stop_nbd_disk",
-                Some(jsondata::StopNbdDiskArgs {
+                StopNbdDiskArgs {
                     nbd_device: nbd_disk.nbd_device.clone(),
-                }),
+                },
             )
             .map_err(|err| err.into_status())
             .and_then(|done| {
@@ -275,45 +482,103 @@ pub fn destroy_blkdev(
     Box::new(f)
 }
 
+/// Stop the nbd device exporting the bdev if there is one.
+pub fn unpublish(
+    socket: String,
+    bdev_name: String,
+) -> Box<dyn Future<Item = (), Error = Status> + Send> {
+    let f = spdk_methods::get_nbd_disks(&socket)
+        .map_err(|err| err.into_status())
+        .and_then(move |nbd_disks| {
+            let nbd_disk = match nbd_disks
+                .into_iter()
+                .find(|ent| ent.bdev_name == bdev_name)
+            {
+                Some(nbd_disk) => nbd_disk,
+                None => return Either::A(ok(())),
+            };
+            Either::B(
+                spdk_methods::stop_nbd_disk(
+                    &socket,
+                    StopNbdDiskArgs {
+                        nbd_device: nbd_disk.nbd_device.clone(),
+                    },
+                )
+                .map_err(|err| err.into_status())
+                .and_then(move |done| {
+                    if !done {
+                        return Err(Status::new(
+                            Code::Internal,
+                            format!(
+                                "Failed to stop nbd device {} for {}",
+                                nbd_disk.nbd_device, bdev_name
+                            ),
+                        ));
+                    }
+                    info!(
+                        "Stopped NBD device {} with bdev {}",
+                        nbd_disk.nbd_device, bdev_name
+                    );
+                    NbdDevInfo::from(nbd_disk.nbd_device).put_back();
+                    Ok(())
+                }),
+            )
+        });
+
+    Box::new(f)
+}
+
 pub fn get_nbd_instance(
     sock: &str,
     bdev_name: &str,
-) -> Box<dyn Future<Item = Option<jsondata::NbdDisk>, Error = Status> + Send> {
+) -> Box<dyn Future<Item = Option<NbdDisk>, Error = Status> + Send> {
     let bdev_name = bdev_name.to_string();
     let socket = sock.to_string();
 
-    let f = jsonrpc::call::<jsondata::GetBdevsArgs, Vec<jsondata::Bdev>>(
-        &socket,
-        "get_bdevs",
-        Some(jsondata::GetBdevsArgs {
-            name: bdev_name.clone(),
-        }),
-    )
-    .map_err(|e| {
-        Status::new(Code::NotFound, format!("Failed to list bdevs: {}", e))
-    })
-    .and_then(move |bdev| {
-        jsonrpc::call::<(), Vec<jsondata::NbdDisk>>(
-            &socket,
-            "get_nbd_disks",
-            None,
-        )
-        .map(move |nbd_disks| {
-            nbd_disks
-                .into_iter()
-                .find(|ent| ent.bdev_name == bdev[0].name)
+    let f = spdk_methods::get_bdevs(&socket, Some(bdev_name.as_str()))
+        .map_err(|e| {
+            Status::new(Code::NotFound, format!("Failed to list bdevs: {}", e))
         })
-        .map_err(|err| {
-            Status::new(
-                Code::NotFound,
-                format!("Failed to find nbd disk: {}", err),
-            )
-        })
-    });
+        .and_then(move |bdev| {
+            spdk_methods::get_nbd_disks(&socket)
+                .map(move |nbd_disks| {
+                    nbd_disks
+                        .into_iter()
+                        .find(|ent| volume_id(&ent.bdev_name) == bdev[0].name)
+                })
+                .map_err(|err| {
+                    Status::new(
+                        Code::NotFound,
+                        format!("Failed to find nbd disk: {}", err),
+                    )
+                })
+        });
 
     Box::new(f)
 }
 
+/// Return ids of the volumes exported over nbd by their nbd devices.
+pub fn exported_volumes(
+    socket: &str,
+) -> impl Future<Item = HashMap<String, String>, Error = String> {
+    let socket = socket.to_string();
+
+    spdk_methods::get_nbd_disks(&socket)
+        .and_then(move |nbd_disks| {
+            spdk_methods::get_bdevs(&socket, None).map(move |bdevs| {
+                nbd_disks
+                    .into_iter()
+                    .map(|disk| {
+                        let volume_id =
+                            resolve_volume_id(&bdevs, &disk.bdev_name);
+                        (disk.nbd_device, volume_id)
+                    })
+                    .collect()
+            })
+        })
+        .map_err(|err| format!("Failed to list nbd disks: {}", err))
+}
+
 impl NbdDevInfo {
     /// This will return the next available nbd device
     pub fn new() -> Option<Self> {
@@ -1,13 +1,30 @@
 use crate::csi::*;
-use futures::future::{err, ok, Either, Future, FutureResult};
-use jsonrpc;
-use rpc::jsonrpc as jsondata;
-use std::{boxed::Box, fs, io::ErrorKind, path::PathBuf, vec::Vec};
+use futures::future::{err, ok, result, Either, Future, FutureResult};
+use jsonrpc::spdk_methods::{self, Bdev};
+use std::{
+    boxed::Box,
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    mem,
+    path::{Path, PathBuf},
+    sync::Arc,
+    vec::Vec,
+};
 use tower_grpc::{Code, Request, Response, Status};
 
 use crate::{
+    canary::Canary,
+    cleanup::{device_busy, Cleanup},
+    deadline::{request_deadline, Deadlines},
+    device,
+    fencing::{fencing_epoch_param, FencingStore},
     mount::{match_mount, mount_fs, mount_opts_compare, unmount_fs, Fs},
-    nbd::{self, nbd_stage_volume},
+    nbd::{self, nbd_stage_block, nbd_stage_volume},
+    secrets::{check_credentials, redacted},
+    staging::{StagingRecord, StagingStore},
+    subpath::{prepare_sub_path, sub_path_param},
+    targetpath::check_target_path,
 };
 
 #[derive(Clone, Debug)]
@@ -17,6 +34,14 @@ pub struct Node {
     pub addr: String,
     pub port: u16,
     pub filesystems: Vec<Fs>,
+    pub staging: StagingStore,
+    pub deadlines: Arc<Deadlines>,
+    pub cleanup: Cleanup,
+    pub fencing: FencingStore,
+    /// periodic check of staged volumes (if enabled)
+    pub canary: Option<Canary>,
+    /// directories which volumes may be published to
+    pub publish_roots: Vec<PathBuf>,
 }
 
 // Shortcut for creating grpc error, logging it and exiting from function
@@ -71,6 +96,166 @@ fn check_access_mode(
     }
 }
 
+/// Find the device of a staged volume whose nbd device has gone (it has
+/// been disconnected or mayastor has been restarted), so that unstage can
+/// clean up the mount and the staging record left behind. The volume is not
+/// found if it has not been staged.
+fn find_removed_device(
+    staging: &StagingStore,
+    volume_id: &str,
+    stage_path: &str,
+) -> Result<(bool, String), Status> {
+    match staging.get(volume_id) {
+        Ok(Some(record)) => {
+            warn!(
+                "Device {} of volume {} has been removed",
+                record.device, volume_id
+            );
+            let mounted = record.staging_path == stage_path
+                && match_mount(None, Some(stage_path), false).is_some();
+            Ok((mounted, record.device))
+        }
+        Ok(None) => {
+            error!("No device instance found for {}", volume_id);
+            Err(Status::new(
+                Code::NotFound,
+                "no such bdev exists".to_string(),
+            ))
+        }
+        Err(reason) => Err(Status::new(Code::Internal, reason)),
+    }
+}
+
+/// Publish raw block volume by bind mounting its device to the target path,
+/// which is a file rather than a directory. The device is the one which has
+/// been recorded when staging the volume.
+fn publish_block(
+    staging: &StagingStore,
+    msg: &NodePublishVolumeRequest,
+) -> Result<(), Status> {
+    let volume_id = &msg.volume_id;
+    let target_path = &msg.target_path;
+
+    let device = match staging.get(volume_id) {
+        Ok(Some(ref record))
+            if record.is_block()
+                && record.staging_path == msg.staging_target_path =>
+        {
+            record.device.clone()
+        }
+        Ok(_) => {
+            let reason = format!(
+                "Raw block volume {} is not staged at {}",
+                volume_id, msg.staging_target_path
+            );
+            error!("{}", reason);
+            return Err(Status::new(Code::InvalidArgument, reason));
+        }
+        Err(reason) => return Err(Status::new(Code::Internal, reason)),
+    };
+
+    if let Some(mount) = match_mount(None, Some(target_path), true) {
+        // the source of the bind mount is devtmpfs, not the device
+        let reason = if !device::same_block_device(&device, target_path) {
+            format!(
+                "Target path {} of volume {} is a mount point of {}",
+                target_path, volume_id, mount.source
+            )
+        } else if mount.opts.iter().any(|opt| opt == "ro") == msg.readonly {
+            info!("Volume {} already published", volume_id);
+            return Ok(());
+        } else {
+            format!(
+                "Volume {} is already published with incompatible flags",
+                volume_id
+            )
+        };
+        error!("{}", reason);
+        return Err(Status::new(Code::AlreadyExists, reason));
+    }
+
+    let create = Path::new(target_path)
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(target_path)
+                .map(|_| ())
+        });
+    if let Err(err) = create {
+        let reason = format!(
+            "Failed to create target file {} for volume {}: {}",
+            target_path, volume_id, err
+        );
+        error!("{}", reason);
+        return Err(Status::new(Code::Internal, reason));
+    }
+
+    let flags = vec![if msg.readonly { "ro" } else { "rw" }.to_owned()];
+    if let Err(err) = mount_fs(&device, target_path, true, "none", &flags) {
+        let _ = fs::remove_file(target_path);
+        let reason = format!("Failed to publish volume {}: {}", volume_id, err);
+        error!("{}", reason);
+        return Err(Status::new(Code::Internal, reason));
+    }
+    info!("Published raw block volume {}", volume_id);
+    Ok(())
+}
+
+/// Undo what a stage cut short by its deadline may have done, so that the
+/// volume is not left half-staged. The nbd device belongs to the controller
+/// (it is created and destroyed by publish and unpublish of the volume on
+/// the node) and is left alone.
+fn undo_stage(staging: &StagingStore, volume_id: &str, staging_path: &str) {
+    if match_mount(None, Some(staging_path), false).is_some() {
+        if let Err(reason) = unmount_fs(staging_path, false) {
+            warn!("Failed to undo stage of volume {}: {}", volume_id, reason);
+            return;
+        }
+    }
+    if let Err(reason) = staging.remove(volume_id) {
+        warn!("Failed to undo stage of volume {}: {}", volume_id, reason);
+        return;
+    }
+    info!("Undone stage of volume {} at {}", volume_id, staging_path);
+}
+
+/// Add the open mode of the device of a raw block volume to its condition.
+/// A volume which should be opened with O_DIRECT only is abnormal while it
+/// is open buffered.
+fn open_mode_condition(
+    record: &StagingRecord,
+    condition: Option<VolumeCondition>,
+) -> Option<VolumeCondition> {
+    let mode = match device::open_mode(&record.device) {
+        Ok(mode) => mode,
+        Err(reason) => {
+            warn!(
+                "Cannot find out open mode of volume {}: {}",
+                record.volume_id, reason
+            );
+            return condition;
+        }
+    };
+    let buffered = record.direct_io && !mode.buffered.is_empty();
+    let mut message = format!("Device {} is {}", record.device, mode);
+    if buffered {
+        message.push_str(" but it should be opened with O_DIRECT only");
+    }
+    if let Some(condition) = condition {
+        if condition.abnormal {
+            return Some(condition);
+        }
+        message = format!("{}; {}", condition.message, message);
+    }
+    Some(VolumeCondition {
+        abnormal: buffered,
+        message,
+    })
+}
+
 impl Node {}
 
 impl server::Node for Node {
@@ -83,10 +268,8 @@ impl server::Node for Node {
             + Send,
     >;
     type NodeUnpublishVolumeFuture = Box<
-        dyn Future<
-                Item = Response<NodeUnpublishVolumeResponse>,
-                Error = Status,
-            > + Send,
+        dyn Future<Item = Response<NodeUnpublishVolumeResponse>, Error = Status>
+            + Send,
     >;
     type NodeGetVolumeStatsFuture = Box<
         dyn Future<Item = Response<NodeGetVolumeStatsResponse>, Error = Status>
@@ -129,9 +312,12 @@ impl server::Node for Node {
         &mut self,
         _request: Request<NodeGetCapabilitiesRequest>,
     ) -> Self::NodeGetCapabilitiesFuture {
+        // the condition is reported for every volume, with the canary or
+        // the open mode of raw block volumes it is more than just normal
         let caps = vec![
             node_service_capability::rpc::Type::GetVolumeStats,
             node_service_capability::rpc::Type::StageUnstageVolume,
+            node_service_capability::rpc::Type::VolumeCondition,
         ];
 
         debug!("NodeGetCapabilities request: {:?}", caps);
@@ -172,9 +358,19 @@ impl server::Node for Node {
         &mut self,
         request: Request<NodePublishVolumeRequest>,
     ) -> Self::NodePublishVolumeFuture {
-        let msg = request.into_inner();
-
-        trace!("{:?}", msg);
+        let mut msg = request.into_inner();
+        // the secrets are consumed (and wiped) right away, so that they are
+        // not copied or left behind by an early return
+        let secrets = mem::replace(&mut msg.secrets, HashMap::new());
+
+        trace!(
+            "{:?}",
+            NodePublishVolumeRequest {
+                secrets: redacted(&secrets),
+                ..msg.clone()
+            }
+        );
+        let creds_check = check_credentials(&msg.volume_id, secrets);
 
         let staging_path = &msg.staging_target_path;
         let target_path = &msg.target_path;
@@ -188,15 +384,30 @@ impl server::Node for Node {
                 format!("Invalid target or staging path for {}", volume_id)
             );
         }
+        if let Err(reason) = check_target_path(target_path, &self.publish_roots)
+        {
+            grpc_return!(Code::InvalidArgument, reason);
+        }
+
+        if let Err(reason) = check_access_mode(
+            volume_id,
+            &msg.volume_capability.as_ref().unwrap().access_mode,
+            msg.readonly,
+        ) {
+            grpc_return!(Code::InvalidArgument, reason);
+        };
+
+        if let Err(reason) = creds_check {
+            grpc_return!(Code::InvalidArgument, reason);
+        }
 
-        // TODO: Support raw volumes
         let mnt = match msg.volume_capability.as_ref().unwrap().access_type {
             Some(volume_capability::AccessType::Mount(ref m)) => m,
             Some(volume_capability::AccessType::Block(_)) => {
-                grpc_return!(
-                    Code::InvalidArgument,
-                    "Raw block not ratified yet"
-                );
+                return Box::new(result(
+                    publish_block(&self.staging, &msg)
+                        .map(|_| Response::new(NodePublishVolumeResponse {})),
+                ));
             }
             None => {
                 grpc_return!(
@@ -208,22 +419,15 @@ impl server::Node for Node {
 
         // apparently, it does not matter what the source (device) is
         // to me thats odd but thats how the spec says it today
-        if match_mount(None, Some(staging_path), true).is_none() {
-            grpc_return!(
+        let staged = match match_mount(None, Some(staging_path), true) {
+            Some(mount) => mount,
+            None => grpc_return!(
                 Code::InvalidArgument,
                 format!(
                     "No mount {} for volume {} (hint: volume unstaged?)",
                     staging_path, volume_id
                 )
-            );
-        }
-
-        if let Err(reason) = check_access_mode(
-            volume_id,
-            &msg.volume_capability.as_ref().unwrap().access_mode,
-            msg.readonly,
-        ) {
-            grpc_return!(Code::InvalidArgument, reason);
+            ),
         };
 
         let filesystem = if mnt.fs_type.is_empty() {
@@ -249,8 +453,30 @@ impl server::Node for Node {
 
         mnt_flags.extend(filesystem.defaults.clone());
 
-        if let Some(mount) =
-            match_mount(Some(staging_path), Some(target_path), true)
+        // publish either the whole filesystem or its sub-directory, which
+        // is kept open until it is mounted
+        let sub_dir = match sub_path_param(&msg.volume_context) {
+            Ok(Some(sub_path)) => {
+                match prepare_sub_path(staging_path, &sub_path) {
+                    Ok(sub_dir) => Some(sub_dir),
+                    Err(reason) => grpc_return!(
+                        Code::Internal,
+                        format!(
+                            "Failed to prepare sub-path of volume {}: {}",
+                            volume_id, reason
+                        )
+                    ),
+                }
+            }
+            Ok(None) => None,
+            Err(reason) => grpc_return!(Code::InvalidArgument, reason),
+        };
+        let source = sub_dir
+            .as_ref()
+            .map_or(staging_path, |sub_dir| sub_dir.path())
+            .to_owned();
+
+        if let Some(mount) = match_mount(Some(&source), Some(target_path), true)
         {
             // we are already mounted check flags, if they match return OK
             let equal =
@@ -270,6 +496,20 @@ impl server::Node for Node {
             }
         }
 
+        // don't cover something else mounted there (bind mounts of the volume
+        // show its device as the source)
+        if let Some(mount) = match_mount(None, Some(target_path), true)
+            .filter(|mount| mount.source != staged.source)
+        {
+            grpc_return!(
+                Code::FailedPrecondition,
+                format!(
+                    "Target path {} of volume {} is a mount point of {}",
+                    target_path, volume_id, mount.source
+                )
+            );
+        }
+
         // if we are here, it means that we mount it for the first time or -- we
         // are mounting the same staged volume again to a different target.
         if let Err(err) = fs::create_dir_all(PathBuf::from(target_path)) {
@@ -281,8 +521,11 @@ impl server::Node for Node {
                 )
             );
         }
+        let mount_source = sub_dir
+            .as_ref()
+            .map_or_else(|| source.clone(), |sub_dir| sub_dir.mount_source());
         if let Err(err) = mount_fs(
-            &staging_path,
+            &mount_source,
             &target_path,
             true,
             &filesystem.name,
@@ -319,12 +562,33 @@ impl server::Node for Node {
         let target_path = &msg.target_path;
         let volume_id = &msg.volume_id;
 
-        // TODO: Support raw volumes
+        if let Err(reason) = check_target_path(target_path, &self.publish_roots)
+        {
+            grpc_return!(Code::InvalidArgument, reason);
+        }
+
         match match_mount(None, Some(target_path), true) {
-            Some(_) => {
+            Some(mount) => {
                 debug!("Unmount volume {} at {}...", volume_id, target_path);
 
-                if let Err(err) = unmount_fs(target_path, true) {
+                // raw block volume is a bind mount of the device node
+                let block = device::is_block_device(target_path);
+
+                // make the data written through the mount durable before
+                // kubelet gets rid of the pod
+                let res = if block {
+                    device::flush(target_path)
+                } else {
+                    device::sync_fs(target_path).and_then(|_| {
+                        if mount.source.starts_with("/dev/") {
+                            device::flush(&mount.source)
+                        } else {
+                            Ok(())
+                        }
+                    })
+                }
+                .and_then(|_| unmount_fs(target_path, true));
+                if let Err(err) = res {
                     grpc_return!(
                         Code::Internal,
                         format!(
@@ -333,6 +597,12 @@ impl server::Node for Node {
                         )
                     );
                 }
+                // the file for the device node has been created by publish
+                if block {
+                    if let Err(err) = fs::remove_file(target_path) {
+                        warn!("Failed to remove {}: {}", target_path, err);
+                    }
+                }
                 info!("Unpublished volume {} at {}", volume_id, target_path);
             }
             None => error!("Volume {} is not published", volume_id),
@@ -345,13 +615,29 @@ impl server::Node for Node {
         &mut self,
         request: Request<NodeGetVolumeStatsRequest>,
     ) -> Self::NodeGetVolumeStatsFuture {
+        let deadline = request_deadline(&request);
         let msg = request.into_inner();
         trace!("{:?}", msg);
         // self is a reference and we can't use it in the closure below
         let socket = self.socket.clone();
         let volume_id = msg.volume_id;
-
-        let bdev_to_stats = |bdev: jsondata::Bdev| {
+        let mut volume_condition =
+            self.canary.as_ref().map(|c| c.condition(&volume_id));
+        if let Ok(Some(record)) = self.staging.get(&volume_id) {
+            if record.is_block() {
+                volume_condition =
+                    open_mode_condition(&record, volume_condition);
+            }
+        }
+        // VOLUME_CONDITION is advertised, so the condition must be set even
+        // if there is nothing to check
+        let volume_condition =
+            Some(volume_condition.unwrap_or_else(|| VolumeCondition {
+                abnormal: false,
+                message: "The volume is not checked".to_owned(),
+            }));
+
+        let bdev_to_stats = move |bdev: Bdev| {
             NodeGetVolumeStatsResponse {
                 usage: vec![VolumeUsage {
                     total: i64::from(bdev.block_size) * bdev.num_blocks as i64,
@@ -361,23 +647,22 @@ impl server::Node for Node {
                     available: 0,
                     used: 0,
                 }],
+                volume_condition,
             }
         };
 
         let f = nbd::get_nbd_instance(&self.socket, &volume_id)
             .and_then(move |res| {
                 if let Some(disk) = res {
-                    assert_eq!(disk.bdev_name, volume_id);
+                    // size of the exported bdev is the logical size of the
+                    // volume (smaller than the replica if compressed)
                     Either::A(
-                        jsonrpc::call(
+                        spdk_methods::get_bdevs(
                             &socket,
-                            "get_bdevs",
-                            Some(jsondata::GetBdevsArgs {
-                                name: volume_id.to_owned(),
-                            }),
+                            Some(disk.bdev_name.as_str()),
                         )
                         .map_err(|err| err.into_status())
-                        .and_then(move |mut bdevs: Vec<jsondata::Bdev>| {
+                        .and_then(move |mut bdevs: Vec<Bdev>| {
                             if bdevs.is_empty() {
                                 return err(Status::new(
                                     Code::Internal,
@@ -395,7 +680,7 @@ impl server::Node for Node {
                     )))
                 }
             });
-        Box::new(f)
+        self.deadlines.apply("NodeGetVolumeStats", deadline, f)
     }
 
     fn node_expand_volume(
@@ -409,15 +694,26 @@ impl server::Node for Node {
 
     /// stage a volume means that we grab the raw block device and format it if
     /// so needed depending on the egress type (nbd or nvmf) call the proper
-    /// implementation
+    /// implementation. Raw block volumes are neither formatted nor mounted.
     fn node_stage_volume(
         &mut self,
         request: Request<NodeStageVolumeRequest>,
     ) -> Self::NodeStageVolumeFuture {
-        let msg = request.into_inner();
+        let deadline = request_deadline(&request);
+        let mut msg = request.into_inner();
         let volume_id = msg.volume_id.clone();
-
-        trace!("{:?}", msg);
+        // the secrets are consumed (and wiped) right away, so that they are
+        // not copied or left behind by an early return
+        let secrets = mem::replace(&mut msg.secrets, HashMap::new());
+
+        trace!(
+            "{:?}",
+            NodeStageVolumeRequest {
+                secrets: redacted(&secrets),
+                ..msg.clone()
+            }
+        );
+        let creds_check = check_credentials(&volume_id, secrets);
 
         if msg.staging_target_path == "" || msg.volume_id == "" {
             grpc_return!(
@@ -433,13 +729,15 @@ impl server::Node for Node {
             );
         }
 
-        // TODO: support raw block volumes
+        // the volume is in use again - leave its staging record alone
+        self.cleanup.cancel(&volume_id);
+
+        // raw block volumes are staged without a filesystem
         let mnt = match msg.volume_capability.as_ref().unwrap().access_type {
-            Some(volume_capability::AccessType::Mount(ref m)) => m.clone(),
-            Some(volume_capability::AccessType::Block(_)) => grpc_return!(
-                Code::InvalidArgument,
-                "Raw block support is not supported"
-            ),
+            Some(volume_capability::AccessType::Mount(ref m)) => {
+                Some(m.clone())
+            }
+            Some(volume_capability::AccessType::Block(_)) => None,
             None => grpc_return!(
                 Code::InvalidArgument,
                 format!("Missing access type for volume {}", volume_id)
@@ -455,18 +753,57 @@ impl server::Node for Node {
             grpc_return!(Code::InvalidArgument, reason);
         };
 
-        let filesystem = if mnt.fs_type.is_empty() {
-            self.filesystems[0].clone()
-        } else {
-            match self.filesystems.iter().find(|ent| ent.name == mnt.fs_type) {
-                Some(fs) => fs.clone(),
-                None => {
-                    grpc_return!(
-                        Code::InvalidArgument,
-                        format!("Filesystem {} is not supported", mnt.fs_type)
-                    );
+        if let Err(reason) = creds_check {
+            grpc_return!(Code::InvalidArgument, reason);
+        }
+
+        let read_ahead = match device::read_ahead_param(
+            &msg.publish_context,
+            &msg.volume_context,
+        ) {
+            Ok(val) => val,
+            Err(reason) => grpc_return!(Code::InvalidArgument, reason),
+        };
+
+        // applies to raw block volumes only
+        let direct_io = match device::direct_io_param(&msg.volume_context) {
+            Ok(val) => val,
+            Err(reason) => grpc_return!(Code::InvalidArgument, reason),
+        };
+
+        match fencing_epoch_param(&msg.publish_context) {
+            Ok(Some(epoch)) => {
+                if let Err(reason) = self.fencing.check(&volume_id, epoch) {
+                    grpc_return!(Code::FailedPrecondition, reason);
                 }
             }
+            Ok(None) => (),
+            Err(reason) => grpc_return!(Code::InvalidArgument, reason),
+        }
+
+        let filesystem = match mnt {
+            Some(ref mnt) if mnt.fs_type.is_empty() => {
+                Some(self.filesystems[0].clone())
+            }
+            Some(ref mnt) => {
+                match self
+                    .filesystems
+                    .iter()
+                    .find(|ent| ent.name == mnt.fs_type)
+                {
+                    Some(fs) => Some(fs.clone()),
+                    None => {
+                        grpc_return!(
+                            Code::InvalidArgument,
+                            format!(
+                                "Filesystem {} is not supported",
+                                mnt.fs_type
+                            )
+                        );
+                    }
+                }
+            }
+            None => None,
         };
 
         debug!(
@@ -488,7 +825,47 @@ impl server::Node for Node {
             }
         }
 
-        nbd_stage_volume(self.socket.clone(), &msg, filesystem, mnt.mount_flags)
+        // a volume which has been staged already is left alone on timeout
+        let staged = match_mount(None, Some(&msg.staging_target_path), false)
+            .is_some()
+            || self
+                .staging
+                .get(&volume_id)
+                .ok()
+                .and_then(|r| r)
+                .map_or(false, |record| {
+                    record.staging_path == msg.staging_target_path
+                });
+        let staging = self.staging.clone();
+        let staging_path = msg.staging_target_path.clone();
+
+        let f = match (mnt, filesystem) {
+            (Some(mnt), Some(filesystem)) => nbd_stage_volume(
+                self.socket.clone(),
+                &msg,
+                filesystem,
+                mnt.mount_flags,
+                read_ahead,
+                self.staging.clone(),
+            ),
+            _ => nbd_stage_block(
+                self.socket.clone(),
+                &msg,
+                read_ahead,
+                direct_io,
+                self.staging.clone(),
+            ),
+        };
+        Box::new(
+            self.deadlines
+                .apply("NodeStageVolume", deadline, f)
+                .or_else(move |status| {
+                    if status.code() == Code::DeadlineExceeded && !staged {
+                        undo_stage(&staging, &volume_id, &staging_path);
+                    }
+                    Err(status)
+                }),
+        )
     }
     // A Node Plugin MUST implement this RPC call if it has STAGE_UNSTAGE_VOLUME
     // node capability. This RPC is a reverse operation of NodeStageVolume.
@@ -516,29 +893,31 @@ impl server::Node for Node {
         &mut self,
         request: Request<NodeUnstageVolumeRequest>,
     ) -> Self::NodeUnstageVolumeFuture {
+        let deadline = request_deadline(&request);
         let msg = request.into_inner();
         let volume_id = msg.volume_id.clone();
         let stage_path = msg.staging_target_path.clone();
+        let staging = self.staging.clone();
+        let records = self.staging.clone();
+        let cleanup = self.cleanup.clone();
 
         debug!("Unstaging volume {} at {}", volume_id, stage_path);
 
         let f = nbd::get_nbd_instance(&self.socket.clone(), &msg.volume_id)
             .and_then(move |nbd_disk| {
-                if nbd_disk.is_none() {
-                    // if we dont have a nbd device with a corresponding bdev,
-                    // its an error ass it should
-                    error!(
-                        "No device instance found for {}, likely a bug",
-                        &msg.volume_id
-                    );
-
-                    return err(Status::new(
-                        Code::NotFound,
-                        "no such bdev exists".to_string(),
-                    ));
-                }
-
-                let nbd_disk = nbd_disk.unwrap();
+                let nbd_disk = match nbd_disk {
+                    Some(nbd_disk) => nbd_disk,
+                    None => {
+                        return result(
+                            find_removed_device(
+                                &records,
+                                &msg.volume_id,
+                                &msg.staging_target_path,
+                            )
+                            .map(|(mounted, device)| (mounted, device, false)),
+                        )
+                    }
+                };
 
                 if let Some(mount) = match_mount(
                     Some(&nbd_disk.nbd_device),
@@ -549,22 +928,57 @@ impl server::Node for Node {
                     if mount.source == nbd_disk.nbd_device
                         && msg.staging_target_path == mount.dest
                     {
-                        return ok(true);
+                        return ok((true, nbd_disk.nbd_device, true));
                     }
                 }
                 // staging does not match target path must reply OK
-                ok(false)
+                ok((false, nbd_disk.nbd_device, true))
             })
-            .and_then(move |res| {
-                if res {
+            .and_then(move |(mounted, device, present)| {
+                if mounted {
+                    // the unmount is lazy, write back the filesystem while
+                    // it is still reachable
+                    if let Err(reason) = device::sync_fs(&stage_path) {
+                        grpc_return!(
+                            Code::Internal,
+                            format!(
+                                "Failed to unstage volume {}: {}",
+                                volume_id, reason
+                            )
+                        );
+                    }
                     if let Err(reason) = unmount_fs(&stage_path, false) {
-                        dbg!(&reason);
-                        grpc_return!(Code::Internal, reason);
+                        // the device is busy - unless the mount is gone
+                        // anyway, let the cleanup retry the unmount
+                        if match_mount(None, Some(&stage_path), false).is_some()
+                        {
+                            cleanup.defer(&volume_id, &stage_path, &device);
+                            grpc_return!(Code::Unavailable, reason);
+                        }
+                        warn!("{}", reason);
+                    }
+                }
+                // nothing can be flushed to a device which has been removed
+                if present {
+                    if let Err(reason) = device::flush(&device) {
+                        grpc_return!(
+                            Code::Internal,
+                            format!(
+                                "Failed to unstage volume {}: {}",
+                                volume_id, reason
+                            )
+                        );
                     }
                 }
+                // the mount is gone, but the filesystem may still be in use
+                if device_busy(&device) {
+                    cleanup.defer(&volume_id, &stage_path, &device);
+                } else if let Err(reason) = staging.remove(&volume_id) {
+                    warn!("{}", reason);
+                }
                 Box::new(ok(Response::new(NodeUnstageVolumeResponse {})))
             });
 
-        Box::new(f)
+        self.deadlines.apply("NodeUnstageVolume", deadline, f)
     }
 }
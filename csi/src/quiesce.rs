@@ -0,0 +1,347 @@
+//! Quiescing of the node before an upgrade.
+//!
+//! QuiesceIo method of the mayastor service stops accepting new control
+//! operations on the node (stage, publish, create pool, ...) and waits until
+//! the operations in progress and background jobs (deferred cleanups,
+//! benchmark, canary reads) are finished. Then the orchestration tooling
+//! can restart the pods of mayastor without interrupting anything half-way.
+//! If the jobs are not finished in time, the reply says what is still in
+//! progress and the node stays quiesced, so that the caller can decide to
+//! wait more or to give up and call ResumeIo. Read-only calls (list, stat,
+//! ...) are served all the time.
+//!
+//! Control operations on a volume (stage, publish, benchmark, ...) exclude
+//! each other: while one is in progress, others on the same volume are
+//! rejected with ABORTED, as the CSI spec suggests, so that i.e. a volume
+//! cannot be published in the middle of a benchmark which has checked that
+//! it is not.
+
+use crate::{
+    benchmark,
+    canary::Canary,
+    cleanup::Cleanup,
+    rpc::mayastor::QuiesceIoReply,
+};
+use futures::future::{self, Either, Future, Loop};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+use tower_grpc::{Code, Status};
+
+/// How long to wait for draining if the caller does not say.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Upper bound of the time to wait for draining.
+const MAX_TIMEOUT: Duration = Duration::from_secs(600);
+/// How often it is checked whether everything has been drained.
+const CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Default)]
+struct State {
+    quiesced: bool,
+    next_id: u64,
+    /// control operations in progress by their id
+    in_flight: HashMap<u64, &'static str>,
+    /// volumes with a control operation in progress
+    volumes: HashMap<String, &'static str>,
+}
+
+/// Control operation in progress. It is finished when dropped.
+pub struct Operation {
+    id: u64,
+    /// volume which the operation is exclusive for
+    volume_id: Option<String>,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.in_flight.remove(&self.id);
+        if let Some(volume_id) = &self.volume_id {
+            state.volumes.remove(volume_id);
+        }
+    }
+}
+
+impl Operation {
+    /// Keep the operation in progress until the future completes.
+    pub fn track<T, F>(
+        self,
+        fut: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        Box::new(fut.then(move |res| {
+            drop(self);
+            res
+        }))
+    }
+}
+
+#[derive(Clone)]
+pub struct Quiesce {
+    state: Arc<Mutex<State>>,
+    cleanup: Cleanup,
+    canary: Option<Canary>,
+}
+
+impl Quiesce {
+    pub fn new(cleanup: Cleanup, canary: Option<Canary>) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::default())),
+            cleanup,
+            canary,
+        }
+    }
+
+    /// Start control operation. It fails with UNAVAILABLE if the node is
+    /// quiesced.
+    pub fn begin(&self, method: &'static str) -> Result<Operation, Status> {
+        self.start(method, None)
+    }
+
+    /// Start control operation on the volume. Besides failing when the node
+    /// is quiesced, it fails with ABORTED if there is another operation on
+    /// the volume in progress.
+    pub fn begin_volume(
+        &self,
+        method: &'static str,
+        volume_id: &str,
+    ) -> Result<Operation, Status> {
+        self.start(method, Some(volume_id))
+    }
+
+    fn start(
+        &self,
+        method: &'static str,
+        volume_id: Option<&str>,
+    ) -> Result<Operation, Status> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.quiesced {
+            warn!("Rejecting {}, the node is quiesced", method);
+            return Err(Status::new(
+                Code::Unavailable,
+                format!("{} rejected, the node is quiesced", method),
+            ));
+        }
+        if let Some(volume_id) = volume_id {
+            if let Some(other) = state.volumes.get(volume_id) {
+                return Err(Status::new(
+                    Code::Aborted,
+                    format!(
+                        "{} rejected, {} of volume {} is in progress",
+                        method, other, volume_id
+                    ),
+                ));
+            }
+            state.volumes.insert(volume_id.to_owned(), method);
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.in_flight.insert(id, method);
+        Ok(Operation {
+            id,
+            volume_id: volume_id.map(str::to_owned),
+            state: Arc::clone(&self.state),
+        })
+    }
+
+    /// Start the control operation created by the closure unless the node
+    /// is quiesced and keep it in progress until it completes.
+    pub fn run<T, F, R>(
+        &self,
+        method: &'static str,
+        start: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.begin(method) {
+            Ok(op) => op.track(start()),
+            Err(status) => Box::new(future::err(status)),
+        }
+    }
+
+    /// Same as run() but the operation is exclusive for the volume.
+    pub fn run_volume<T, F, R>(
+        &self,
+        method: &'static str,
+        volume_id: &str,
+        start: F,
+    ) -> Box<dyn Future<Item = T, Error = Status> + Send>
+    where
+        F: FnOnce() -> R,
+        R: Future<Item = T, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.begin_volume(method, volume_id) {
+            Ok(op) => op.track(start()),
+            Err(status) => Box::new(future::err(status)),
+        }
+    }
+
+    /// Return descriptions of operations and jobs which are in progress.
+    pub fn pending(&self) -> Vec<String> {
+        let mut pending: Vec<String> = self
+            .state
+            .lock()
+            .unwrap()
+            .in_flight
+            .values()
+            .map(|method| format!("{} in progress", method))
+            .collect();
+        pending.sort();
+
+        for volume_id in self.cleanup.pending() {
+            pending.push(format!("cleanup of volume {}", volume_id));
+        }
+        if benchmark::is_running() {
+            pending.push("benchmark".to_owned());
+        }
+        if let Some(canary) = &self.canary {
+            let reads = canary.reads_in_flight();
+            if reads > 0 {
+                pending.push(format!("{} canary reads", reads));
+            }
+        }
+        pending
+    }
+
+    /// Stop accepting control operations and wait until everything in
+    /// progress has finished or the timeout (in seconds) expires.
+    pub fn quiesce(
+        &self,
+        timeout: u32,
+    ) -> impl Future<Item = QuiesceIoReply, Error = Status> {
+        let timeout = match timeout {
+            0 => DEFAULT_TIMEOUT,
+            n => Duration::from_secs(u64::from(n)).min(MAX_TIMEOUT),
+        };
+        let deadline = Instant::now() + timeout;
+        let quiesce = self.clone();
+
+        self.state.lock().unwrap().quiesced = true;
+        if let Some(canary) = &self.canary {
+            canary.pause(true);
+        }
+        info!("Quiescing the node (timeout {}s)", timeout.as_secs());
+
+        future::loop_fn((), move |_| {
+            let pending = quiesce.pending();
+
+            if pending.is_empty() || Instant::now() >= deadline {
+                let drained = pending.is_empty();
+                if drained {
+                    info!("The node has been quiesced");
+                } else {
+                    warn!(
+                        "The node has not been drained in {}s: {}",
+                        timeout.as_secs(),
+                        pending.join(", ")
+                    );
+                }
+                return Either::A(future::ok(Loop::Break(QuiesceIoReply {
+                    drained,
+                    pending,
+                })));
+            }
+            Either::B(
+                Delay::new(Instant::now() + CHECK_PERIOD)
+                    .map(|_| Loop::Continue(()))
+                    .map_err(|err| {
+                        Status::new(
+                            Code::Internal,
+                            format!("Timer failed: {}", err),
+                        )
+                    }),
+            )
+        })
+    }
+
+    /// Accept control operations again.
+    pub fn resume(&self) {
+        self.state.lock().unwrap().quiesced = false;
+        if let Some(canary) = &self.canary {
+            canary.pause(false);
+        }
+        info!("The node has been resumed");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::staging::StagingStore;
+    use std::{env, fs};
+    use tokio::runtime::Runtime;
+
+    fn quiesce(name: &str) -> Quiesce {
+        let dir = env::temp_dir().join(name);
+        let _ = fs::remove_dir_all(&dir);
+        let staging = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        Quiesce::new(Cleanup::new(staging, ""), None)
+    }
+
+    #[test]
+    fn volume_operations_exclude_each_other() {
+        let quiesce = quiesce("csi-quiesce-volume-test");
+
+        let bench = quiesce.begin_volume("BenchmarkVolume", "vol").unwrap();
+        let status = quiesce
+            .begin_volume("NodePublishVolume", "vol")
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::Aborted);
+        // other volumes and operations not bound to a volume are not held
+        let other = quiesce.begin_volume("NodePublishVolume", "vol2").unwrap();
+        let pool = quiesce.begin("CreatePool").unwrap();
+        drop(other);
+        drop(pool);
+
+        drop(bench);
+        assert!(quiesce.begin_volume("NodePublishVolume", "vol").is_ok());
+        assert!(quiesce.pending().is_empty());
+    }
+
+    #[test]
+    fn quiesced_node_rejects_operations_until_resumed() {
+        let quiesce = quiesce("csi-quiesce-resume-test");
+        let mut rt = Runtime::new().unwrap();
+
+        // operation in progress is waited for and reported if not finished
+        let op = quiesce.begin("CreatePool").unwrap();
+        let reply = rt.block_on(quiesce.quiesce(1)).unwrap();
+        assert!(!reply.drained);
+        assert_eq!(reply.pending, vec!["CreatePool in progress".to_owned()]);
+        drop(op);
+        let reply = rt.block_on(quiesce.quiesce(1)).unwrap();
+        assert!(reply.drained);
+
+        let status = rt
+            .block_on(quiesce.run("CreatePool", || future::ok(())))
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::Unavailable);
+        let status = quiesce
+            .begin_volume("NodeStageVolume", "vol")
+            .err()
+            .unwrap();
+        assert_eq!(status.code(), Code::Unavailable);
+
+        quiesce.resume();
+        assert!(rt
+            .block_on(quiesce.run("CreatePool", || future::ok(())))
+            .is_ok());
+        assert!(quiesce.begin_volume("NodeStageVolume", "vol").is_ok());
+        assert!(quiesce.pending().is_empty());
+    }
+}
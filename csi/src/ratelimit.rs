@@ -0,0 +1,188 @@
+//! Rate limiting of management calls on egress gRPC interface.
+//!
+//! Calls like list pools or stat replicas are cheap for a single caller but
+//! a dashboard polling them at high frequency occupies mayastor's json-rpc
+//! socket and delays provisioning calls coming from moac. We keep a token
+//! bucket for each peer (IP address) and reject management calls from peers
+//! which exhausted their bucket. Provisioning calls are never limited.
+//! Rejected calls are counted by method in `mgmt_throttled_total` metric.
+
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Buckets which have not been used for this long are forgotten.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    /// number of calls rejected since the bucket was created
+    throttled: u64,
+}
+
+/// Token bucket rate limiter keyed by peer address.
+pub struct RateLimiter {
+    /// tokens added to the bucket per second (0 means unlimited)
+    rate: f64,
+    /// capacity of the bucket
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    /// rejected calls by method
+    throttled: IntCounterVec,
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish()
+    }
+}
+
+impl RateLimiter {
+    pub fn new(rate: u32, burst: u32) -> Self {
+        Self {
+            rate: f64::from(rate),
+            burst: f64::from(burst.max(1)),
+            buckets: Mutex::new(HashMap::new()),
+            throttled: IntCounterVec::new(
+                Opts::new(
+                    "mgmt_throttled_total",
+                    "Number of management calls rejected by rate limiting.",
+                ),
+                &["method"],
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Register the counter of rejected calls in the prometheus registry.
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.throttled.clone()))
+    }
+
+    /// Take a token from the bucket of the peer. Return false if the call
+    /// should be rejected.
+    pub fn check(&self, peer: IpAddr, method: &str) -> bool {
+        self.check_at(peer, method, Instant::now())
+    }
+
+    fn check_at(&self, peer: IpAddr, method: &str, now: Instant) -> bool {
+        if self.rate == 0.0 {
+            return true;
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if !buckets.contains_key(&peer) {
+            buckets.retain(|_, b| now.duration_since(b.last) < IDLE_TIMEOUT);
+        }
+        let bucket = buckets.entry(peer).or_insert(Bucket {
+            tokens: self.burst,
+            last: now,
+            throttled: 0,
+        });
+
+        let elapsed = now.duration_since(bucket.last);
+        let elapsed =
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) * 1e-9;
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            bucket.throttled += 1;
+            self.throttled.with_label_values(&[method]).inc();
+            // don't flood the log - report the first and every 100th call
+            if bucket.throttled == 1 || bucket.throttled % 100 == 0 {
+                warn!(
+                    "Throttling {} calls from {} ({} calls rejected so far)",
+                    method, peer, bucket.throttled
+                );
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn burst_is_exhausted_and_refilled() {
+        let limiter = RateLimiter::new(10, 20);
+        let peer = ip("10.0.0.1");
+        let start = Instant::now();
+
+        for _ in 0 .. 20 {
+            assert!(limiter.check_at(peer, "list_pools", start));
+        }
+        assert!(!limiter.check_at(peer, "list_pools", start));
+        // other peers have their own bucket
+        assert!(limiter.check_at(ip("10.0.0.2"), "list_pools", start));
+
+        // 10 tokens per second
+        let now = start + Duration::from_millis(250);
+        assert!(limiter.check_at(peer, "list_pools", now));
+        assert!(limiter.check_at(peer, "list_pools", now));
+        assert!(!limiter.check_at(peer, "list_pools", now));
+
+        // the bucket does not grow beyond the burst
+        let now = now + Duration::from_secs(60);
+        for _ in 0 .. 20 {
+            assert!(limiter.check_at(peer, "list_pools", now));
+        }
+        assert!(!limiter.check_at(peer, "list_pools", now));
+
+        let registry = Registry::new();
+        limiter.register(&registry).unwrap();
+        let metrics = registry.gather();
+        let counter = metrics[0].get_metric()[0].get_counter();
+        assert_eq!(counter.get_value(), 3.0);
+    }
+
+    #[test]
+    fn zero_rate_is_unlimited() {
+        let limiter = RateLimiter::new(0, 1);
+        let start = Instant::now();
+
+        for _ in 0 .. 100 {
+            assert!(limiter.check_at(ip("10.0.0.1"), "list_pools", start));
+        }
+        assert!(limiter.buckets.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn idle_buckets_are_evicted() {
+        let limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+
+        assert!(limiter.check_at(ip("10.0.0.1"), "list_pools", start));
+        assert!(!limiter.check_at(ip("10.0.0.1"), "list_pools", start));
+        let now = start + IDLE_TIMEOUT - Duration::from_secs(1);
+        assert!(limiter.check_at(ip("10.0.0.2"), "list_pools", now));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+
+        // a new peer evicts the buckets which have not been used for long
+        let now = start + IDLE_TIMEOUT;
+        assert!(limiter.check_at(ip("10.0.0.3"), "list_pools", now));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert!(!buckets.contains_key(&ip("10.0.0.1")));
+    }
+}
@@ -0,0 +1,536 @@
+//! On-demand reconciliation of the node.
+//!
+//! Runbooks often end with a manual intervention on the node (unmounting a
+//! filesystem, restarting mayastor, editing its config, ...) after which the
+//! node should be brought back to the state it is supposed to be in. Rather
+//! than waiting for the next stage or restart, ReconcileNow runs the
+//! reconcilers right away and reports what they have found and fixed:
+//!
+//! - mount: staging records are compared with the nbd devices exported by
+//!   mayastor and with the mount table. A volume which is exported but not
+//!   mounted at its staging path is mounted again and a staging record is
+//!   created for a volume which is mounted without one.
+//! - nbd: nbd devices exporting bdevs which don't exist anymore are stopped
+//!   unless they are mounted. Devices connected in the kernel which mayastor
+//!   does not know about are reported.
+//! - config: the data path is compared with the config saved by mayastor and
+//!   missing objects are re-created. Only the node scope re-creates them, since
+//!   re-creating is not limited to a pool or a volume.
+//!
+//! With a pool or volume scope only the objects of the pool or the volume
+//! are looked at. With dry run nothing is fixed.
+
+use crate::{
+    cleanup::{device_busy, Cleanup},
+    migrate::STAGING_SUFFIX,
+    mount::{find_mounts, match_mount, mount_fs},
+    nbd,
+    staging::{StagingRecord, StagingStore},
+};
+use futures::{future, Future};
+use glob::glob;
+use jsonrpc::spdk_methods::{self, Bdev, NbdDisk};
+use rpc::{
+    jsonrpc as jsondata,
+    mayastor::{
+        CheckConfigReply,
+        CheckConfigRequest,
+        ReconcileFinding,
+        ReconcileNowReply,
+        ReconcileNowRequest,
+        ReconcileScope,
+    },
+};
+use std::path::Path;
+use tower_grpc::{Code, Status};
+
+/// What the reconcilers are limited to.
+struct Scope {
+    kind: ReconcileScope,
+    name: String,
+    /// uuids of replicas on the pool (pool scope)
+    replicas: Vec<String>,
+}
+
+impl Scope {
+    fn new(msg: &ReconcileNowRequest) -> Result<Self, Status> {
+        let kind = ReconcileScope::from_i32(msg.scope).ok_or_else(|| {
+            Status::new(
+                Code::InvalidArgument,
+                format!("Invalid reconcile scope {}", msg.scope),
+            )
+        })?;
+        if kind != ReconcileScope::Node
+            && (msg.name.is_empty() || msg.name.contains('/'))
+        {
+            return Err(Status::new(
+                Code::InvalidArgument,
+                format!("Invalid name \"{}\" of {:?} scope", msg.name, kind),
+            ));
+        }
+        Ok(Self {
+            kind,
+            name: msg.name.clone(),
+            replicas: Vec::new(),
+        })
+    }
+
+    /// Return true if the volume is in the scope.
+    fn has_volume(&self, volume_id: &str) -> bool {
+        match self.kind {
+            ReconcileScope::Node => true,
+            ReconcileScope::Pool => {
+                self.replicas.iter().any(|r| r == volume_id)
+            }
+            ReconcileScope::Volume => self.name == volume_id,
+        }
+    }
+
+    /// Return true if the config drift of the object ("kind name") with the
+    /// given problem is in the scope.
+    fn has_drift(&self, object: &str, problem: &str) -> bool {
+        let name = object.splitn(2, ' ').nth(1).unwrap_or("");
+        match self.kind {
+            ReconcileScope::Node => true,
+            ReconcileScope::Pool => {
+                let pool = format!("pool {}", self.name);
+                object == pool
+                    || problem.ends_with(&pool)
+                    || problem.contains(&format!("{} ", pool))
+                    || self.replicas.iter().any(|r| r == name)
+            }
+            ReconcileScope::Volume => name.contains(&self.name),
+        }
+    }
+}
+
+fn finding(
+    reconciler: &str,
+    object: &str,
+    problem: String,
+) -> ReconcileFinding {
+    ReconcileFinding {
+        reconciler: reconciler.to_owned(),
+        object: object.to_owned(),
+        problem,
+        action: String::new(),
+        fixed: false,
+    }
+}
+
+/// Turn drift of the data path from the saved config into findings.
+fn check_config(
+    scope: &Scope,
+    res: Result<CheckConfigReply, String>,
+    reply: &mut ReconcileNowReply,
+) {
+    let config = match res {
+        Ok(config) => config,
+        Err(reason) => {
+            reply.errors.push(format!("config: {}", reason));
+            return;
+        }
+    };
+    reply.reconcilers.push("config".to_owned());
+
+    for drift in &config.drift {
+        let (object, problem) = match drift.find(": ") {
+            Some(idx) => (&drift[.. idx], &drift[idx + 2 ..]),
+            None => (drift.as_str(), ""),
+        };
+        if !scope.has_drift(object, problem) {
+            continue;
+        }
+        let mut f = finding("config", object, problem.to_owned());
+        if config.restored.iter().any(|r| r == object) {
+            f.action = "re-created".to_owned();
+            f.fixed = true;
+        } else if let Some(failed) = config
+            .failed
+            .iter()
+            .find(|msg| msg.starts_with(&format!("{}:", object)))
+        {
+            f.action = format!("re-create failed: {}", failed);
+        }
+        reply.findings.push(f);
+    }
+}
+
+/// Compare staging records with exported devices and mounts, mount the
+/// volumes which should be mounted and create missing records.
+fn check_mounts(
+    scope: &Scope,
+    dry_run: bool,
+    staging: &StagingStore,
+    cleanup: &Cleanup,
+    exported: &[(String, NbdDisk)],
+    reply: &mut ReconcileNowReply,
+) -> Result<(), String> {
+    let records = staging.list()?;
+    // volumes being unstaged are left to the deferred cleanup
+    let pending = cleanup.pending();
+    let in_scope = |volume_id: &str| {
+        scope.has_volume(volume_id) && !pending.iter().any(|p| p == volume_id)
+    };
+    reply.reconcilers.push("mount".to_owned());
+
+    for record in records.iter().filter(|r| in_scope(&r.volume_id)) {
+        reply.checked += 1;
+        let disk = exported
+            .iter()
+            .find(|(volume_id, _)| volume_id == &record.volume_id)
+            .map(|(_, disk)| disk);
+        let problem = match disk {
+            None => format!(
+                "staged on {} but not exported by mayastor",
+                record.device
+            ),
+            Some(disk) if disk.nbd_device != record.device => format!(
+                "staged on {} but exported on {}",
+                record.device, disk.nbd_device
+            ),
+            // raw block volumes are not mounted at the staging path
+            Some(_) if record.is_block() => continue,
+            Some(_) => {
+                if match_mount(
+                    Some(&record.device),
+                    Some(&record.staging_path),
+                    true,
+                )
+                .is_some()
+                {
+                    continue;
+                }
+                match match_mount(None, Some(&record.staging_path), false) {
+                    Some(other) => format!(
+                        "{} is mounted at {} instead of {}",
+                        other.source, record.staging_path, record.device
+                    ),
+                    None => {
+                        let mut f = finding(
+                            "mount",
+                            &record.volume_id,
+                            format!("not mounted at {}", record.staging_path),
+                        );
+                        if !dry_run {
+                            match mount_fs(
+                                &record.device,
+                                &record.staging_path,
+                                false,
+                                &record.fs_type,
+                                &record.mount_flags,
+                            ) {
+                                Ok(()) => {
+                                    f.action = "mounted again".to_owned();
+                                    f.fixed = true;
+                                }
+                                Err(reason) => f.action = reason,
+                            }
+                        }
+                        reply.findings.push(f);
+                        continue;
+                    }
+                }
+            }
+        };
+        reply
+            .findings
+            .push(finding("mount", &record.volume_id, problem));
+    }
+
+    for (volume_id, disk) in exported.iter().filter(|(id, _)| in_scope(id)) {
+        if records.iter().any(|r| &r.volume_id == volume_id) {
+            continue;
+        }
+        reply.checked += 1;
+        // the rest of the mounts are bind mounts done by publish
+        let mounts = find_mounts(&disk.nbd_device);
+        let mount =
+            match mounts.iter().find(|m| m.dest.ends_with(STAGING_SUFFIX)) {
+                Some(mount) => mount,
+                // exported by CreateBlkdev and waiting for stage
+                None => continue,
+            };
+        let mut f = finding(
+            "mount",
+            volume_id,
+            format!("mounted at {} without staging record", mount.dest),
+        );
+        if !dry_run {
+            match staging.save(&StagingRecord::new(
+                volume_id,
+                &mount.dest,
+                &disk.nbd_device,
+                &mount.fstype,
+                &mount.opts,
+            )) {
+                Ok(()) => {
+                    f.action = "created staging record".to_owned();
+                    f.fixed = true;
+                }
+                Err(reason) => f.action = reason,
+            }
+        }
+        reply.findings.push(f);
+    }
+    Ok(())
+}
+
+/// Find nbd devices exporting bdevs which don't exist and devices which are
+/// connected but not exported by mayastor. Return the bdevs of orphaned
+/// devices which should be stopped with index of their finding.
+fn check_nbd(
+    scope: &Scope,
+    dry_run: bool,
+    disks: &[NbdDisk],
+    bdevs: &[Bdev],
+    reply: &mut ReconcileNowReply,
+) -> Vec<(usize, String)> {
+    let mut stop = Vec::new();
+    reply.reconcilers.push("nbd".to_owned());
+
+    for disk in disks {
+        let volume_id = nbd::resolve_volume_id(bdevs, &disk.bdev_name);
+        // the pool of a missing bdev is unknown
+        if scope.kind == ReconcileScope::Pool || !scope.has_volume(&volume_id) {
+            continue;
+        }
+        reply.checked += 1;
+        if bdevs.iter().any(|b| b.name == disk.bdev_name) {
+            continue;
+        }
+        let mut f = finding(
+            "nbd",
+            &disk.nbd_device,
+            format!("exports bdev {} which does not exist", disk.bdev_name),
+        );
+        if !find_mounts(&disk.nbd_device).is_empty() {
+            f.action = "not stopped, the device is mounted".to_owned();
+        } else if device_busy(&disk.nbd_device) {
+            f.action = "not stopped, the device is in use".to_owned();
+        } else if !dry_run {
+            stop.push((reply.findings.len(), disk.bdev_name.clone()));
+        }
+        reply.findings.push(f);
+    }
+
+    if scope.kind == ReconcileScope::Node {
+        // connected devices have pid of the process serving them in sysfs
+        for entry in glob("/sys/class/block/nbd*/pid").unwrap() {
+            let device = match entry
+                .ok()
+                .as_ref()
+                .and_then(|p| p.parent())
+                .and_then(Path::file_name)
+            {
+                Some(name) => format!("/dev/{}", name.to_string_lossy()),
+                None => continue,
+            };
+            if !disks.iter().any(|d| d.nbd_device == device) {
+                reply.findings.push(finding(
+                    "nbd",
+                    &device,
+                    "connected but not exported by mayastor".to_owned(),
+                ));
+            }
+        }
+    }
+    stop
+}
+
+/// Run the reconcilers in the scope given by the request.
+pub fn reconcile_now(
+    socket: String,
+    staging: StagingStore,
+    cleanup: Cleanup,
+    msg: ReconcileNowRequest,
+) -> Box<dyn Future<Item = ReconcileNowReply, Error = Status> + Send> {
+    let mut scope = match Scope::new(&msg) {
+        Ok(scope) => scope,
+        Err(status) => return Box::new(future::err(status)),
+    };
+    let dry_run = msg.dry_run;
+    info!(
+        "Reconciling {:?} {}{}",
+        scope.kind,
+        scope.name,
+        if dry_run { " (dry run)" } else { "" }
+    );
+
+    // the data path first, the devices and mounts depend on it
+    let config = jsonrpc::call::<_, CheckConfigReply>(
+        &socket,
+        "check_config",
+        Some(CheckConfigRequest {
+            path: String::new(),
+            reapply: scope.kind == ReconcileScope::Node && !dry_run,
+        }),
+    )
+    .then(|res| Ok::<_, Status>(res.map_err(|err| err.to_string())));
+
+    let f = config.and_then(move |config| {
+        spdk_methods::get_nbd_disks(&socket)
+            .join3(
+                spdk_methods::get_bdevs(&socket, None),
+                jsonrpc::call::<(), Vec<jsondata::Replica>>(
+                    &socket,
+                    "list_replicas",
+                    None,
+                ),
+            )
+            .map_err(|err| err.into_status())
+            .and_then(move |(disks, bdevs, replicas)| {
+                if scope.kind == ReconcileScope::Pool {
+                    scope.replicas = replicas
+                        .into_iter()
+                        .filter(|r| r.pool == scope.name)
+                        .map(|r| r.uuid)
+                        .collect();
+                }
+                let mut reply = ReconcileNowReply::default();
+                check_config(&scope, config, &mut reply);
+
+                let exported: Vec<(String, NbdDisk)> = disks
+                    .iter()
+                    .map(|d| {
+                        (
+                            nbd::resolve_volume_id(&bdevs, &d.bdev_name),
+                            d.clone(),
+                        )
+                    })
+                    .collect();
+                if let Err(reason) = check_mounts(
+                    &scope, dry_run, &staging, &cleanup, &exported, &mut reply,
+                ) {
+                    reply.errors.push(format!("mount: {}", reason));
+                }
+                let stop =
+                    check_nbd(&scope, dry_run, &disks, &bdevs, &mut reply);
+
+                future::join_all(stop.into_iter().map(move |(idx, bdev)| {
+                    nbd::unpublish(socket.clone(), bdev)
+                        .then(move |res| Ok::<_, Status>((idx, res)))
+                }))
+                .map(move |stopped| {
+                    for (idx, res) in stopped {
+                        let f = &mut reply.findings[idx];
+                        match res {
+                            Ok(()) => {
+                                f.action = "stopped".to_owned();
+                                f.fixed = true;
+                            }
+                            Err(status) => {
+                                f.action = status.message().to_owned()
+                            }
+                        }
+                    }
+                    for f in &reply.findings {
+                        warn!(
+                            "Reconcile {} {}: {}{}",
+                            f.reconciler,
+                            f.object,
+                            f.problem,
+                            if f.action.is_empty() {
+                                String::new()
+                            } else {
+                                format!(" ({})", f.action)
+                            }
+                        );
+                    }
+                    info!(
+                        "Reconciled {} objects, {} findings, {} fixed",
+                        reply.checked,
+                        reply.findings.len(),
+                        reply.findings.iter().filter(|f| f.fixed).count()
+                    );
+                    reply
+                })
+            })
+    });
+
+    Box::new(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOLUME: &str = "0f6d2ae4-54a8-4a1b-9b0b-2a3f4e3d6c11";
+    const CLONE: &str = "6c0c2d1f-8a3e-4f2b-a5d1-7e9c3b4a2f10";
+    // clones of templates get random lvol uuids (and bdev names)
+    const CLONE_BDEV: &str = "b2e4a9c0-1d3f-4e6a-8c7b-5f0e9d2a1b33";
+
+    fn bdev(name: &str, aliases: &[&str]) -> Bdev {
+        Bdev {
+            name: name.to_owned(),
+            aliases: aliases.iter().map(|a| (*a).to_owned()).collect(),
+            product_name: "Logical Volume".to_owned(),
+            block_size: 512,
+            num_blocks: 16384,
+            uuid: Some(name.to_owned()),
+            driver_specific: serde_json::Value::Null,
+        }
+    }
+
+    fn disk(nbd_device: &str, bdev_name: &str) -> NbdDisk {
+        NbdDisk {
+            nbd_device: nbd_device.to_owned(),
+            bdev_name: bdev_name.to_owned(),
+        }
+    }
+
+    fn scope(kind: ReconcileScope, name: &str) -> Scope {
+        Scope {
+            kind,
+            name: name.to_owned(),
+            replicas: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn check_nbd_of_volumes() {
+        let bdevs = vec![
+            bdev(VOLUME, &[&format!("pool/{}", VOLUME)]),
+            bdev(CLONE_BDEV, &[&format!("pool/{}", CLONE), CLONE]),
+        ];
+        let disks = vec![
+            disk("/dev/nbd0", VOLUME),
+            disk("/dev/nbd1", CLONE_BDEV),
+            disk("/dev/nbd2", "COMP_7a1e0c55-3b9d-4f0e-9a6c-2d8b1e4f5a66"),
+        ];
+
+        for volume in &[VOLUME, CLONE] {
+            let mut reply = ReconcileNowReply::default();
+            let stop = check_nbd(
+                &scope(ReconcileScope::Volume, volume),
+                false,
+                &disks,
+                &bdevs,
+                &mut reply,
+            );
+            assert_eq!(reply.checked, 1, "{}", volume);
+            assert!(reply.findings.is_empty(), "{}", volume);
+            assert!(stop.is_empty(), "{}", volume);
+        }
+
+        // the device of the destroyed volume is orphaned
+        let mut reply = ReconcileNowReply::default();
+        let stop = check_nbd(
+            &scope(ReconcileScope::Node, ""),
+            true,
+            &disks,
+            &bdevs,
+            &mut reply,
+        );
+        assert_eq!(reply.checked, 3);
+        let orphans: Vec<&ReconcileFinding> = reply
+            .findings
+            .iter()
+            .filter(|f| disks.iter().any(|d| d.nbd_device == f.object))
+            .collect();
+        assert_eq!(orphans.len(), 1);
+        assert_eq!(orphans[0].object, "/dev/nbd2");
+        // nothing is stopped in dry run
+        assert!(stop.is_empty());
+    }
+}
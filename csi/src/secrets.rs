@@ -0,0 +1,258 @@
+//! Handling of secrets passed to us by CO in stage and publish requests.
+//!
+//! CSI hands us secrets as a plain map of strings. We convert them to typed
+//! credential structures as early as possible, so that each backend which
+//! needs credentials (LUKS encryption, iSCSI CHAP, ...) does not have to
+//! parse the map on its own. Sensitive values are wrapped in SecretString,
+//! which never shows up in the logs and is wiped from memory when dropped.
+//! The map is taken out of the request before anything else touches it and
+//! all of its values end up in SecretStrings, so no copy of a secret is left
+//! behind by us (copies made by the gRPC layer while decoding the request are
+//! out of our reach).
+
+use std::{collections::HashMap, fmt, ptr, sync::atomic};
+
+/// Keys of the secrets map which we understand.
+pub const CHAP_USERNAME: &str = "node.session.auth.username";
+pub const CHAP_PASSWORD: &str = "node.session.auth.password";
+pub const CHAP_MUTUAL_USERNAME: &str = "node.session.auth.username_in";
+pub const CHAP_MUTUAL_PASSWORD: &str = "node.session.auth.password_in";
+pub const LUKS_PASSPHRASE: &str = "encryptionPassphrase";
+
+/// String holding a sensitive value. The content is zeroed on drop and it is
+/// replaced by a placeholder when formatted.
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(val: String) -> Self {
+        SecretString(val)
+    }
+
+    /// Get the secret value. Use with care and don't log it!
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        // volatile writes prevent compiler from optimizing the zeroing away
+        unsafe {
+            for byte in self.0.as_mut_vec().iter_mut() {
+                ptr::write_volatile(byte, 0);
+            }
+        }
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
+/// CHAP credentials for authentication to iSCSI targets.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ChapCredentials {
+    pub username: String,
+    pub password: SecretString,
+    /// credentials used by target to authenticate to us (mutual CHAP)
+    pub mutual: Option<(String, SecretString)>,
+}
+
+/// Credentials needed to open an encrypted LUKS device.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct LuksCredentials {
+    pub passphrase: SecretString,
+}
+
+/// All credentials found in secrets map of a stage or publish request.
+#[derive(Debug, Default)]
+pub struct Credentials {
+    pub chap: Option<ChapCredentials>,
+    pub luks: Option<LuksCredentials>,
+}
+
+/// Take value of the secret out of the map and remove it from there.
+fn take(
+    secrets: &mut HashMap<String, SecretString>,
+    key: &str,
+) -> Option<SecretString> {
+    secrets.remove(key).filter(|val| !val.expose().is_empty())
+}
+
+impl Credentials {
+    /// Parse credentials from secrets map. Return error if the secrets are
+    /// incomplete (i.e. CHAP username without password). The error message
+    /// never contains values of the secrets. All values of the map are wiped
+    /// when they are no longer needed, including the unknown ones.
+    pub fn from_secrets(
+        secrets: HashMap<String, String>,
+    ) -> Result<Self, String> {
+        let mut secrets: HashMap<String, SecretString> = secrets
+            .into_iter()
+            .map(|(key, val)| (key, SecretString::new(val)))
+            .collect();
+        let mut creds = Credentials::default();
+
+        creds.chap = match (
+            take(&mut secrets, CHAP_USERNAME),
+            take(&mut secrets, CHAP_PASSWORD),
+        ) {
+            (Some(username), Some(password)) => Some(ChapCredentials {
+                username: username.expose().to_owned(),
+                password,
+                mutual: match (
+                    take(&mut secrets, CHAP_MUTUAL_USERNAME),
+                    take(&mut secrets, CHAP_MUTUAL_PASSWORD),
+                ) {
+                    (Some(username), Some(password)) => {
+                        Some((username.expose().to_owned(), password))
+                    }
+                    (None, None) => None,
+                    _ => {
+                        return Err(format!(
+                            "Both {} and {} secrets must be specified",
+                            CHAP_MUTUAL_USERNAME, CHAP_MUTUAL_PASSWORD
+                        ))
+                    }
+                },
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(format!(
+                    "Both {} and {} secrets must be specified",
+                    CHAP_USERNAME, CHAP_PASSWORD
+                ))
+            }
+        };
+
+        creds.luks = take(&mut secrets, LUKS_PASSPHRASE).map(|passphrase| {
+            LuksCredentials {
+                passphrase,
+            }
+        });
+
+        for key in secrets.keys() {
+            warn!("Ignoring unknown secret {}", key);
+        }
+        Ok(creds)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chap.is_none() && self.luks.is_none()
+    }
+}
+
+/// Parse secrets from the request and check that we are able to honour them.
+/// Encrypted volumes are not supported yet and silently ignoring the
+/// passphrase would leave the data unencrypted, so we rather fail.
+pub fn check_credentials(
+    volume_id: &str,
+    secrets: HashMap<String, String>,
+) -> Result<(), String> {
+    let creds = Credentials::from_secrets(secrets)
+        .map_err(|err| format!("Invalid secrets for {}: {}", volume_id, err))?;
+
+    if creds.luks.is_some() {
+        return Err(format!("Encryption is not supported for {}", volume_id));
+    }
+    if !creds.is_empty() {
+        debug!("Got credentials for {}: {:?}", volume_id, creds);
+    }
+    Ok(())
+}
+
+/// Return copy of secrets map with values replaced by placeholder, suitable
+/// for printing the request to the log.
+pub fn redacted(secrets: &HashMap<String, String>) -> HashMap<String, String> {
+    secrets
+        .keys()
+        .map(|key| (key.clone(), "<redacted>".to_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secrets(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, val)| (key.to_string(), val.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn chap_credentials() {
+        let creds = Credentials::from_secrets(secrets(&[
+            (CHAP_USERNAME, "user"),
+            (CHAP_PASSWORD, "s3cr3t"),
+            (CHAP_MUTUAL_USERNAME, "target"),
+            (CHAP_MUTUAL_PASSWORD, "t4rg3t"),
+            ("unknown", "val"),
+        ]))
+        .unwrap();
+        let chap = creds.chap.as_ref().unwrap();
+        assert_eq!(chap.username, "user");
+        assert_eq!(chap.password.expose(), "s3cr3t");
+        let (username, password) = chap.mutual.as_ref().unwrap();
+        assert_eq!(username, "target");
+        assert_eq!(password.expose(), "t4rg3t");
+        assert!(creds.luks.is_none());
+        let debug = format!("{:?}", creds);
+        assert!(!debug.contains("s3cr3t") && !debug.contains("t4rg3t"));
+    }
+
+    #[test]
+    fn empty_values_are_missing() {
+        let creds = Credentials::from_secrets(secrets(&[
+            (CHAP_USERNAME, ""),
+            (CHAP_PASSWORD, ""),
+            (LUKS_PASSPHRASE, ""),
+        ]))
+        .unwrap();
+        assert!(creds.is_empty());
+
+        let err = Credentials::from_secrets(secrets(&[
+            (CHAP_USERNAME, "user"),
+            (CHAP_PASSWORD, ""),
+        ]))
+        .unwrap_err();
+        assert!(err.contains(CHAP_PASSWORD));
+    }
+
+    #[test]
+    fn mutual_chap_needs_password() {
+        let err = Credentials::from_secrets(secrets(&[
+            (CHAP_USERNAME, "user"),
+            (CHAP_PASSWORD, "s3cr3t"),
+            (CHAP_MUTUAL_USERNAME, "target"),
+        ]))
+        .unwrap_err();
+        assert!(err.contains(CHAP_MUTUAL_PASSWORD));
+        assert!(!err.contains("s3cr3t"));
+    }
+
+    #[test]
+    fn luks_is_refused() {
+        let err = check_credentials(
+            "vol",
+            secrets(&[(LUKS_PASSPHRASE, "secret passphrase")]),
+        )
+        .unwrap_err();
+        assert!(err.contains("Encryption is not supported"));
+        assert!(!err.contains("secret passphrase"));
+
+        assert!(check_credentials("vol", HashMap::new()).is_ok());
+        let chap = secrets(&[(CHAP_USERNAME, "user"), (CHAP_PASSWORD, "pass")]);
+        assert!(check_credentials("vol", chap).is_ok());
+        let chap = secrets(&[(CHAP_USERNAME, "user")]);
+        assert!(check_credentials("vol", chap)
+            .unwrap_err()
+            .starts_with("Invalid secrets for vol"));
+    }
+}
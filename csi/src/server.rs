@@ -20,14 +20,39 @@ extern crate ioctl_gen;
 #[macro_use]
 extern crate lazy_static;
 
+mod benchmark;
+mod canary;
+mod cleanup;
+mod deadline;
 mod device;
+mod fencing;
 mod format;
+mod fsfeatures;
+mod fshelper;
+mod history;
+mod hostenv;
 mod identity;
+mod import;
+mod logtail;
+mod manifest;
 mod mayastor_svc;
+mod metrics;
+mod migrate;
 mod mount;
 mod nbd;
 #[macro_use]
 mod node;
+mod quiesce;
+mod ratelimit;
+mod reconcile;
+mod secrets;
+mod soak;
+mod staging;
+mod statspush;
+mod subpath;
+mod support;
+mod targetpath;
+mod vhost;
 // These libs are needed for gRPC generated code
 use rpc;
 
@@ -41,13 +66,25 @@ mod csi {
 }
 
 use crate::{
-    identity::Identity,
+    canary::Canary,
+    cleanup::Cleanup,
+    deadline::Deadlines,
+    fencing::FencingStore,
+    history::History,
+    identity::{self, Identity},
+    manifest::Signer,
     mayastor_svc::MayastorService,
+    metrics::{MeteredNode, Metrics},
+    migrate::migrate_state,
     mount::probe_filesystems,
     node::Node,
+    quiesce::Quiesce,
+    ratelimit::RateLimiter,
+    staging::StagingStore,
+    statspush::StatsPush,
 };
 use chrono::Local;
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 use env_logger::{Builder, Env};
 use futures::{Future, Stream};
 use git_version::git_version;
@@ -55,15 +92,63 @@ use grpc_router::Router2;
 use std::{
     fs,
     io::{Error as IoError, Write},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    runtime::Runtime,
 };
-use tokio::net::{TcpListener, UnixListener};
 use tower_hyper::server::{Http, Server};
 
+/// Serve the mayastor service on the port. Each connection gets its own
+/// instance of the service which knows the peer, so that management calls
+/// can be rate limited per client.
+fn accept_egress(
+    port: u16,
+    svc: MayastorService,
+) -> impl Future<Item = (), Error = IoError> {
+    let endpoint = format!("0.0.0.0:{}", port).parse().unwrap();
+    let bind = TcpListener::bind(&endpoint).expect("bind");
+
+    if svc.read_only {
+        info!("Read-only egress listening on {}", endpoint);
+    } else {
+        info!("Egress listening on {}", endpoint);
+    }
+
+    bind.incoming().for_each(move |sock| {
+        let peer = sock.peer_addr().ok();
+        debug!(
+            "New connection from {}",
+            match peer {
+                Some(addr) => addr.to_string(),
+                None => "unknown".to_owned(),
+            }
+        );
+        sock.set_nodelay(true)?;
+
+        let mut svc = svc.clone();
+        svc.peer = peer.map(|addr| addr.ip());
+        let mut egress_server =
+            Server::new(rpc::service::server::MayastorServer::new(svc));
+        let http = Http::new().http2_only(true).clone();
+        let serve = egress_server.serve_with(sock, http.clone());
+        tokio::spawn(
+            serve.map_err(|e| {
+                error!("http2 error on egress connection: {:?}", e)
+            }),
+        );
+        Ok(())
+    })
+}
+
 pub fn main() {
     let matches = App::new("Mayastor grpc server")
         .version(git_version!())
         .about("gRPC mayastor server with CSI and egress services")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("address")
                 .short("a")
@@ -81,12 +166,19 @@ pub fn main() {
                 .help("Port number to listen on for egress svc (default 10124)")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("readonly-port")
+                .long("readonly-port")
+                .value_name("NUMBER")
+                .help("Port number to listen on for read-only egress svc (i.e. for dashboards)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("mayastor-socket")
                 .short("s")
                 .long("mayastor-socket")
                 .value_name("PATH")
-                .help("Socket path to mayastor backend (default /var/tmp/mayastor.sock)")
+                .help("Socket path or host:port of mayastor backend (default /var/tmp/mayastor.sock)")
                 .takes_value(true),
         )
         .arg(
@@ -111,23 +203,262 @@ pub fn main() {
                 .required(true)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("state-dir")
+                .long("state-dir")
+                .value_name("PATH")
+                .help("Directory for persistent state of the plugin (default /var/tmp/mayastor-csi)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mgmt-rate")
+                .long("mgmt-rate")
+                .value_name("NUMBER")
+                .help("Max rate of management calls (list, stat) per client and second, 0 is unlimited (default 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mgmt-burst")
+                .long("mgmt-burst")
+                .value_name("NUMBER")
+                .help("Number of management calls allowed in a burst (default 20)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fs-helper")
+                .long("fs-helper")
+                .value_name("FSTYPE=COMMAND")
+                .help("Run mkfs and other tools of the filesystem through the command (i.e. xfs=\"nsenter -t 1 -m --\")")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fs-compat-kernel")
+                .long("fs-compat-kernel")
+                .value_name("VERSION")
+                .help("Format filesystems mountable by kernel of the version, the oldest one of the nodes (i.e. 4.15, default is the running kernel)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("nbds-max")
+                .long("nbds-max")
+                .value_name("NUMBER")
+                .help("Number of nbd devices if the nbd module is loaded by us (default 16)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("nbd-max-part")
+                .long("nbd-max-part")
+                .value_name("NUMBER")
+                .help("Max partitions per nbd device if the nbd module is loaded by us (default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-nbd-load")
+                .long("no-nbd-load")
+                .help("Don't load the nbd module if there are no nbd devices"),
+        )
+        .arg(
+            Arg::with_name("vhost-dir")
+                .long("vhost-dir")
+                .value_name("PATH")
+                .help("Directory with vhost-user sockets of mayastor (default /var/tmp/mayastor-vhost)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("deadline")
+                .long("deadline")
+                .value_name("METHOD=SECONDS")
+                .help("Max duration of CSI method (NodeStageVolume, NodeUnstageVolume or NodeGetVolumeStats)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("publish-root")
+                .long("publish-root")
+                .value_name("DIR")
+                .help("Directory which volumes may be published to, can be repeated (default /var/lib/kubelet/pods)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-timeout")
+                .long("rpc-timeout")
+                .value_name("SECONDS")
+                .help("Max time to wait for reply from mayastor, 0 is forever (default 60)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-connections")
+                .long("rpc-connections")
+                .value_name("NUMBER")
+                .help("Max number of connections to mayastor, 0 is unlimited (default 32)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-requests")
+                .long("rpc-requests")
+                .value_name("NUMBER")
+                .help("Max number of requests in flight to mayastor, 0 is unlimited (default 8)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("wait-ready")
+                .long("wait-ready")
+                .value_name("SECS")
+                .help("Wait for mayastor to answer json-rpc before serving, 0 is no wait (default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-max-response")
+                .long("rpc-max-response")
+                .value_name("MIB")
+                .help("Max size of reply from mayastor, 0 is unlimited (default 16)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-validation")
+                .long("rpc-validation")
+                .value_name("MODE")
+                .possible_values(&["strict", "standard", "lenient"])
+                .help("Checking of replies from mayastor (default standard)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-peer-uid")
+                .long("rpc-peer-uid")
+                .value_name("UID")
+                .help("Talk to mayastor socket only if mayastor runs as the user")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-peer-gid")
+                .long("rpc-peer-gid")
+                .value_name("GID")
+                .help("Talk to mayastor socket only if mayastor runs as the group")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-keepalive")
+                .long("rpc-keepalive")
+                .value_name("SECONDS")
+                .help("Check health of the connection to mayastor periodically for Probe (0 disables it, default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats-push")
+                .long("stats-push")
+                .value_name("URL")
+                .help("Push changes of replica stats to the control plane (i.e. http://moac:4000/stats/push)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stats-push-interval")
+                .long("stats-push-interval")
+                .value_name("SECONDS")
+                .requires("stats-push")
+                .help("Interval of pushing replica stats (default 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-tls-ca")
+                .long("rpc-tls-ca")
+                .value_name("FILE")
+                .help("CA certificates (PEM) of mayastor at tls:// or https:// address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-tls-cert")
+                .long("rpc-tls-cert")
+                .value_name("FILE")
+                .requires_all(&["rpc-tls-ca", "rpc-tls-key"])
+                .help("Client certificate (PEM) for TLS connections to mayastor")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-tls-key")
+                .long("rpc-tls-key")
+                .value_name("FILE")
+                .requires("rpc-tls-cert")
+                .help("Private key (PEM) of the client certificate")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rpc-tls-name")
+                .long("rpc-tls-name")
+                .value_name("NAME")
+                .requires("rpc-tls-ca")
+                .help("Name in certificate of mayastor given by IP address")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-port")
+                .long("metrics-port")
+                .value_name("NUMBER")
+                .help("Port number to serve prometheus metrics on (default none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("metrics-window")
+                .long("metrics-window")
+                .value_name("MINUTES")
+                .help("Length of window for availability of CSI methods (default 60)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("canary-period")
+                .long("canary-period")
+                .value_name("SECONDS")
+                .help("Check staged volumes by a read in this interval, 0 is off (default 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("canary-timeout")
+                .long("canary-timeout")
+                .value_name("SECONDS")
+                .help("Volume is abnormal if the canary read takes longer (default 10)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("import-root")
+                .long("import-root")
+                .value_name("DIR")
+                .help("Directory with files which may be imported, can be repeated (default none, only block devices)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manifest-key")
+                .long("manifest-key")
+                .value_name("PATH")
+                .help("File with the key for signing of integrity manifests (default none)")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("v")
                 .short("v")
                 .multiple(true)
                 .help("Sets the verbosity level"),
         )
+        .subcommand(
+            SubCommand::with_name("migrate-state")
+                .about("Convert state of volumes staged by older plugin versions and exit"),
+        )
         .get_matches();
 
-    let node_name = matches.value_of("node-name").unwrap();
-    let port = value_t!(matches.value_of("port"), u16).unwrap_or(10124);
-    let addr = matches.value_of("address").unwrap();
     let ms_socket = matches
         .value_of("mayastor-socket")
         .unwrap_or("/var/tmp/spdk.sock");
     let csi_socket = matches
         .value_of("csi-socket")
         .unwrap_or("/var/tmp/csi.sock");
+    let state_dir = matches
+        .value_of("state-dir")
+        .unwrap_or("/var/tmp/mayastor-csi");
     let level = match matches.occurrences_of("v") as usize {
         0 => "info",
         1 => "debug",
@@ -157,55 +488,293 @@ pub fn main() {
             )
         });
     }
-    builder.init();
+    logtail::init(&mut builder);
+
+    let staging = StagingStore::new(state_dir).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1);
+    });
+
+    if matches.subcommand_matches("migrate-state").is_some() {
+        if let Err(err) = migrate_state(ms_socket, &staging) {
+            error!("Migration of state failed: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if let Err(err) = hostenv::check() {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    let nbds_max = value_t!(matches.value_of("nbds-max"), u32).unwrap_or(16);
+    let nbd_max_part =
+        value_t!(matches.value_of("nbd-max-part"), u32).unwrap_or(0);
+    if !matches.is_present("no-nbd-load") {
+        if let Err(err) = nbd::load_module(nbds_max, nbd_max_part) {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+    let vhost_dir = matches
+        .value_of("vhost-dir")
+        .unwrap_or("/var/tmp/mayastor-vhost")
+        .to_owned();
+
+    let fs_helpers: Vec<&str> = matches
+        .values_of("fs-helper")
+        .map(|vals| vals.collect())
+        .unwrap_or_default();
+    if let Err(err) = fshelper::configure(fs_helpers) {
+        error!("{}", err);
+        std::process::exit(1);
+    }
+
+    if let Err(err) =
+        fsfeatures::configure(matches.value_of("fs-compat-kernel"))
+    {
+        error!("{}", err);
+        std::process::exit(1);
+    }
 
+    let deadline_specs: Vec<&str> = matches
+        .values_of("deadline")
+        .map(|vals| vals.collect())
+        .unwrap_or_default();
+    let deadlines =
+        Arc::new(Deadlines::parse(deadline_specs).unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1);
+        }));
+
+    let rpc_timeout =
+        value_t!(matches.value_of("rpc-timeout"), u64).unwrap_or(60);
+    jsonrpc::set_default_timeout(if rpc_timeout == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(rpc_timeout))
+    });
+    jsonrpc::set_max_connections(
+        value_t!(matches.value_of("rpc-connections"), usize)
+            .unwrap_or(jsonrpc::DEFAULT_MAX_CONNECTIONS),
+    );
+    let rpc_requests = value_t!(matches.value_of("rpc-requests"), usize)
+        .unwrap_or(jsonrpc::DEFAULT_MAX_REQUESTS);
+    jsonrpc::set_max_requests(rpc_requests);
+    let rpc_max_response =
+        value_t!(matches.value_of("rpc-max-response"), usize)
+            .map(|mib| mib * 1024 * 1024)
+            .unwrap_or(jsonrpc::DEFAULT_MAX_RESPONSE_SIZE);
+    jsonrpc::set_max_response_size(rpc_max_response);
+    let rpc_validation = matches
+        .value_of("rpc-validation")
+        .unwrap_or("standard")
+        .parse()
+        .unwrap();
+    jsonrpc::set_validation_mode(rpc_validation);
+    let peer = jsonrpc::peercred::ExpectedPeer {
+        uid: value_t!(matches.value_of("rpc-peer-uid"), u32).ok(),
+        gid: value_t!(matches.value_of("rpc-peer-gid"), u32).ok(),
+    };
+    if peer != jsonrpc::peercred::ExpectedPeer::default() {
+        info!("Expecting mayastor on {} to run as {}", ms_socket, peer);
+        jsonrpc::peercred::set_expected_peer(ms_socket, Some(peer));
+    }
+    if let Some(ca) = matches.value_of("rpc-tls-ca") {
+        let mut tls = jsonrpc::TlsConfig::new(ca);
+        if let Some(cert) = matches.value_of("rpc-tls-cert") {
+            let key = matches.value_of("rpc-tls-key").unwrap();
+            tls = tls.and_then(|tls| tls.client_cert(cert, key));
+        }
+        if let Some(name) = matches.value_of("rpc-tls-name") {
+            tls = tls.map(|tls| tls.server_name(name));
+        }
+        match tls {
+            Ok(tls) => jsonrpc::set_tls_config(tls),
+            Err(err) => {
+                error!("Invalid TLS settings: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+    let wait_ready = value_t!(matches.value_of("wait-ready"), u64).unwrap_or(0);
+    if wait_ready > 0 {
+        info!(
+            "Waiting up to {}s for mayastor on {}",
+            wait_ready, ms_socket
+        );
+        let deadline = Instant::now() + Duration::from_secs(wait_ready);
+        let mut rt = Runtime::new().unwrap();
+        if let Err(err) = rt.block_on(jsonrpc::wait_ready(ms_socket, deadline))
+        {
+            error!("{}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let node_name = matches.value_of("node-name").unwrap();
+    let publish_roots: Vec<PathBuf> = match matches.values_of("publish-root") {
+        Some(roots) => roots.map(PathBuf::from).collect(),
+        None => vec![PathBuf::from(targetpath::DEFAULT_PUBLISH_ROOT)],
+    };
+    let port = value_t!(matches.value_of("port"), u16).unwrap_or(10124);
+    let addr = matches.value_of("address").unwrap();
+    let mgmt_rate = value_t!(matches.value_of("mgmt-rate"), u32).unwrap_or(10);
+    let mgmt_burst =
+        value_t!(matches.value_of("mgmt-burst"), u32).unwrap_or(20);
+    let metrics_port = value_t!(matches.value_of("metrics-port"), u16).ok();
+    let readonly_port = value_t!(matches.value_of("readonly-port"), u16).ok();
+    let metrics_window =
+        value_t!(matches.value_of("metrics-window"), u64).unwrap_or(60);
+    let metrics = Metrics::new(metrics_window);
+    let cleanup = Cleanup::new(staging.clone(), ms_socket);
+    let fencing = FencingStore::new(state_dir).unwrap_or_else(|err| {
+        error!("{}", err);
+        std::process::exit(1);
+    });
+    let history =
+        History::new(state_dir, staging.clone()).unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1);
+        });
+    let canary_period =
+        value_t!(matches.value_of("canary-period"), u64).unwrap_or(0);
+    let canary_timeout =
+        value_t!(matches.value_of("canary-timeout"), u64).unwrap_or(10);
+    let canary = if canary_period == 0 {
+        None
+    } else {
+        Some(Canary::new(
+            Duration::from_secs(canary_period),
+            Duration::from_secs(canary_timeout),
+            staging.clone(),
+        ))
+    };
+    let quiesce = Quiesce::new(cleanup.clone(), canary.clone());
+    let import_roots: Vec<PathBuf> = matches
+        .values_of("import-root")
+        .map_or_else(Vec::new, |roots| roots.map(PathBuf::from).collect());
+    let manifest_key = matches.value_of("manifest-key");
+    let manifest_signer = manifest_key.map(|path| {
+        Arc::new(Signer::load(path).unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1);
+        }))
+    });
+
+    let rpc_keepalive =
+        value_t!(matches.value_of("rpc-keepalive"), u64).unwrap_or(0);
+    let mayastor_health = Arc::new(Mutex::new(None));
+    let stats_push_interval =
+        value_t!(matches.value_of("stats-push-interval"), u64).unwrap_or(10);
+    let stats_push = matches.value_of("stats-push").map(|url| {
+        StatsPush::new(
+            url,
+            node_name,
+            ms_socket,
+            Duration::from_secs(stats_push_interval),
+        )
+        .unwrap_or_else(|err| {
+            error!("{}", err);
+            std::process::exit(1);
+        })
+    });
+
+    let node = Node {
+        node_name: node_name.to_string(),
+        addr: addr.to_string(),
+        port,
+        socket: ms_socket.to_owned(),
+        filesystems: probe_filesystems().expect("Failed to probe filesystems"),
+        staging: staging.clone(),
+        deadlines: Arc::clone(&deadlines),
+        cleanup: cleanup.clone(),
+        fencing,
+        canary: canary.clone(),
+        publish_roots,
+    };
     let csi_svc = Router2::new(
         "/csi.v1.Identity/",
         csi::server::IdentityServer::new(Identity {
             socket: ms_socket.to_owned(),
+            health: Arc::clone(&mayastor_health),
         }),
-        csi::server::NodeServer::new(Node {
-            node_name: node_name.to_string(),
-            addr: addr.to_string(),
-            port,
-            socket: ms_socket.to_owned(),
-            filesystems: probe_filesystems()
-                .expect("Failed to probe filesystems"),
+        csi::server::NodeServer::new(MeteredNode {
+            node: node.clone(),
+            metrics: metrics.clone(),
+            history: history.clone(),
+            quiesce: quiesce.clone(),
         }),
     );
-    let egress_svc =
-        rpc::service::server::MayastorServer::new(MayastorService {
-            socket: ms_socket.to_owned(),
-        });
+    let config = Arc::new(
+        serde_json::to_string_pretty(&serde_json::json!({
+            "version": git_version!(),
+            "node_name": node_name,
+            "address": addr,
+            "port": port,
+            "mayastor_socket": ms_socket,
+            "csi_socket": csi_socket,
+            "state_dir": state_dir,
+            "mgmt_rate": mgmt_rate,
+            "mgmt_burst": mgmt_burst,
+            "log_level": level,
+            "fs_helpers": fshelper::list(),
+            "fs_compat_kernel": fsfeatures::target().map(|v| v.to_string()),
+            "deadlines": deadlines.list(),
+            "metrics_port": metrics_port,
+            "readonly_port": readonly_port,
+            "metrics_window": metrics_window,
+            "canary_period": canary_period,
+            "canary_timeout": canary_timeout,
+            "manifest_key": manifest_key,
+            "import_roots": import_roots,
+            "rpc_requests": rpc_requests,
+            "rpc_keepalive": rpc_keepalive,
+            "stats_push": matches.value_of("stats-push"),
+            "stats_push_interval": stats_push_interval,
+            "wait_ready": wait_ready,
+            "nbds_max": nbds_max,
+            "nbd_max_part": nbd_max_part,
+            "nbd_load": !matches.is_present("no-nbd-load"),
+            "vhost_dir": vhost_dir,
+            "rpc_max_response": rpc_max_response,
+            "rpc_validation": format!("{:?}", rpc_validation),
+            "rpc_tls_ca": matches.value_of("rpc-tls-ca"),
+            "rpc_tls_cert": matches.value_of("rpc-tls-cert"),
+            "rpc_tls_name": matches.value_of("rpc-tls-name"),
+        }))
+        .unwrap(),
+    );
 
     let mut csi_server = Server::new(csi_svc);
-    let mut egress_server = Server::new(egress_svc);
-
-    let endpoint_egress = format!("0.0.0.0:{}", port).parse().unwrap();
-    let bind_egress = TcpListener::bind(&endpoint_egress).expect("bind");
 
-    info!("Egress listening on {}", endpoint_egress);
-
-    let accept_egress =
-        bind_egress.incoming().for_each(move |sock| {
-            debug!(
-                "New connection from {}",
-                match sock.peer_addr() {
-                    Ok(addr) => addr.to_string(),
-                    Err(_) => "unknown".to_owned(),
-                }
-            );
-            if let Err(e) = sock.set_nodelay(true) {
-                return Err(e);
+    let limiter = Arc::new(RateLimiter::new(mgmt_rate, mgmt_burst));
+    let egress_svc = MayastorService {
+        socket: ms_socket.to_owned(),
+        peer: None,
+        limiter: Arc::clone(&limiter),
+        config,
+        staging: staging.clone(),
+        history: history.clone(),
+        quiesce: quiesce.clone(),
+        node_name: node_name.to_owned(),
+        manifest_signer,
+        node,
+        read_only: false,
+        vhost_dir,
+        import_roots: Arc::new(import_roots),
+    };
+    let accept_readonly: Box<dyn Future<Item = (), Error = IoError> + Send> =
+        match readonly_port {
+            Some(port) => {
+                let mut svc = egress_svc.clone();
+                svc.read_only = true;
+                Box::new(accept_egress(port, svc))
             }
-
-            let http = Http::new().http2_only(true).clone();
-            let serve = egress_server.serve_with(sock, http.clone());
-            tokio::spawn(serve.map_err(|e| {
-                error!("http2 error on egress connection: {:?}", e)
-            }));
-            Ok(())
-        });
+            None => Box::new(futures::future::ok(())),
+        };
+    let accept_egress = accept_egress(port, egress_svc);
 
     // Besides the obvious unix domain socket for CSI we need to support TCP
     // as well, because grpc-node used in the tests does not support UDS:
@@ -244,10 +813,61 @@ pub fn main() {
 
     info!("CSI listening on {}", csi_socket);
 
-    tokio::run(accept_egress.join(accept_csi).then(|res| {
-        if let Err(err) = res {
-            error!("accept error: {}", err);
-        }
-        Ok(())
-    }))
+    let serve_metrics: Box<dyn Future<Item = (), Error = ()> + Send> =
+        match metrics_port {
+            Some(port) => {
+                let endpoint = format!("0.0.0.0:{}", port).parse().unwrap();
+                info!("Metrics served on {}", endpoint);
+                if let Err(err) =
+                    jsonrpc::metrics::register(prometheus::default_registry())
+                {
+                    warn!("Failed to register json-rpc metrics: {}", err);
+                }
+                if let Err(err) =
+                    limiter.register(prometheus::default_registry())
+                {
+                    warn!("Failed to register rate limit metrics: {}", err);
+                }
+                Box::new(metrics.serve(&endpoint))
+            }
+            None => Box::new(futures::future::ok(())),
+        };
+    let check_volumes: Box<dyn Future<Item = (), Error = ()> + Send> =
+        match canary {
+            Some(canary) => Box::new(canary.run()),
+            None => Box::new(futures::future::ok(())),
+        };
+    let keep_alive: Box<dyn Future<Item = (), Error = ()> + Send> =
+        if rpc_keepalive > 0 {
+            Box::new(identity::watch_health(
+                ms_socket.to_owned(),
+                Duration::from_secs(rpc_keepalive),
+                mayastor_health,
+            ))
+        } else {
+            Box::new(futures::future::ok(()))
+        };
+    let push_stats: Box<dyn Future<Item = (), Error = ()> + Send> =
+        match stats_push {
+            Some(stats_push) => Box::new(stats_push.run()),
+            None => Box::new(futures::future::ok(())),
+        };
+
+    tokio::run(
+        accept_egress
+            .join(accept_readonly)
+            .join(accept_csi)
+            .then(|res| {
+                if let Err(err) = res {
+                    error!("accept error: {}", err);
+                }
+                Ok(())
+            })
+            .join(serve_metrics)
+            .join(cleanup.run())
+            .join(check_volumes)
+            .join(keep_alive)
+            .join(push_stats)
+            .map(|_| ()),
+    )
 }
@@ -0,0 +1,505 @@
+//! Soak test of a live node.
+//!
+//! Before a node is returned to production (i.e. after an upgrade), the
+//! operator can check that volumes go through their whole life cycle on it:
+//! create → stage → publish → write → unpublish → unstage → delete. The
+//! volumes are tested concurrently, each of them for the given number of
+//! cycles, using the same code paths as kubelet (node service) and moac
+//! (replica and nbd device). Stage, publish, unpublish and unstage are done
+//! twice in a row to verify that they are idempotent. A volume which fails
+//! is cleaned up as far as possible and not tested anymore. At the end the
+//! node is checked for replicas, nbd devices, mounts and staging records
+//! left behind by the test.
+
+use crate::{
+    csi::{
+        server::Node as _,
+        volume_capability,
+        NodePublishVolumeRequest,
+        NodeStageVolumeRequest,
+        NodeUnpublishVolumeRequest,
+        NodeUnstageVolumeRequest,
+        VolumeCapability,
+    },
+    mount::match_mount,
+    nbd,
+    node::Node,
+    rpc::mayastor::{
+        CreateBlkdevRequest,
+        DestroyBlkdevRequest,
+        SoakReply,
+        SoakRequest,
+    },
+};
+use enclose::enclose;
+use futures::{
+    future::{self, Either, Loop},
+    Future,
+};
+use jsonrpc::spdk_methods;
+use rpc::jsonrpc as jsondata;
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::timer::Delay;
+use tower_grpc::{Code, Request, Status};
+
+/// Max number of volumes tested at once (there are only a few nbd devices).
+const MAX_VOLUMES: u32 = 8;
+/// Default size of the volumes.
+const DEFAULT_SIZE: u64 = 64 * 1024 * 1024;
+/// Amount of data written to a volume in each cycle.
+const WRITE_SIZE: usize = 1024 * 1024;
+/// How many times the node is checked for leftovers of the test before they
+/// are reported (unstaged volumes may be cleaned up in the background).
+const LEAK_CHECKS: u32 = 10;
+
+type StepFuture = Box<dyn Future<Item = (), Error = String> + Send>;
+
+/// Volume of the test.
+#[derive(Clone, Debug)]
+struct Volume {
+    uuid: String,
+    staging_path: String,
+    target_path: String,
+}
+
+impl Volume {
+    fn new(dir: &Path, uuid: String) -> Self {
+        let dir = dir.join(&uuid);
+        Self {
+            staging_path: dir.join("staging").to_string_lossy().into_owned(),
+            target_path: dir.join("target").to_string_lossy().into_owned(),
+            uuid,
+        }
+    }
+}
+
+/// Volumes are staged and published as ext4 filesystem for a single writer.
+fn capability() -> Option<VolumeCapability> {
+    Some(VolumeCapability {
+        access_mode: Some(volume_capability::AccessMode {
+            mode: volume_capability::access_mode::Mode::SingleNodeWriter as i32,
+        }),
+        access_type: Some(volume_capability::AccessType::Mount(
+            volume_capability::MountVolume {
+                fs_type: "ext4".to_owned(),
+                mount_flags: Vec::new(),
+            },
+        )),
+    })
+}
+
+/// Wrap a step of the cycle, the error tells which step of which volume
+/// has failed.
+fn step<F>(name: &'static str, uuid: &str, fut: F) -> StepFuture
+where
+    F: Future<Error = Status> + Send + 'static,
+{
+    let uuid = uuid.to_owned();
+    Box::new(fut.map(|_| ()).map_err(move |status| {
+        format!("{} of volume {} failed: {}", name, uuid, status.message())
+    }))
+}
+
+/// Write data to the file, read them back and compare them.
+fn write_and_verify(path: &Path) -> Result<(), String> {
+    let data: Vec<u8> = (0 .. WRITE_SIZE).map(|i| (i % 251) as u8).collect();
+    fs::File::create(path)
+        .and_then(|mut file| {
+            file.write_all(&data)?;
+            file.sync_all()
+        })
+        .map_err(|err| {
+            format!("Failed to write {}: {}", path.display(), err)
+        })?;
+
+    let mut copy = Vec::new();
+    fs::File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut copy))
+        .map_err(|err| format!("Failed to read {}: {}", path.display(), err))?;
+    if copy != data {
+        return Err(format!("Data read from {} differ", path.display()));
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Soak {
+    socket: String,
+    node: Node,
+    pool: String,
+    size: u64,
+}
+
+impl Soak {
+    fn create(&self, vol: &Volume) -> StepFuture {
+        let args = Some(jsondata::CreateReplicaArgs {
+            uuid: vol.uuid.clone(),
+            pool: self.pool.clone(),
+            thin_provision: true,
+            size: self.size,
+            compress: false,
+        });
+        step(
+            "create",
+            &vol.uuid,
+            jsonrpc::call::<_, ()>(&self.socket, "create_replica", args)
+                .map_err(|err| err.into_status()),
+        )
+    }
+
+    fn export(&self, vol: &Volume) -> StepFuture {
+        step(
+            "export",
+            &vol.uuid,
+            nbd::create_blkdev(
+                self.socket.clone(),
+                &CreateBlkdevRequest {
+                    uuid: vol.uuid.clone(),
+                    fencing_epoch: 0,
+                },
+            ),
+        )
+    }
+
+    fn stage(&self, vol: &Volume) -> StepFuture {
+        let mut node = self.node.clone();
+        step(
+            "stage",
+            &vol.uuid,
+            node.node_stage_volume(Request::new(NodeStageVolumeRequest {
+                volume_id: vol.uuid.clone(),
+                staging_target_path: vol.staging_path.clone(),
+                volume_capability: capability(),
+                ..Default::default()
+            })),
+        )
+    }
+
+    fn publish(&self, vol: &Volume) -> StepFuture {
+        let mut node = self.node.clone();
+        step(
+            "publish",
+            &vol.uuid,
+            node.node_publish_volume(Request::new(NodePublishVolumeRequest {
+                volume_id: vol.uuid.clone(),
+                staging_target_path: vol.staging_path.clone(),
+                target_path: vol.target_path.clone(),
+                volume_capability: capability(),
+                ..Default::default()
+            })),
+        )
+    }
+
+    fn write(&self, vol: &Volume) -> StepFuture {
+        let path = Path::new(&vol.target_path).join("soak.dat");
+        let uuid = vol.uuid.clone();
+
+        Box::new(future::lazy(move || {
+            write_and_verify(&path).map_err(|reason| {
+                format!("write of volume {} failed: {}", uuid, reason)
+            })
+        }))
+    }
+
+    fn unpublish(&self, vol: &Volume) -> StepFuture {
+        let mut node = self.node.clone();
+        step(
+            "unpublish",
+            &vol.uuid,
+            node.node_unpublish_volume(Request::new(
+                NodeUnpublishVolumeRequest {
+                    volume_id: vol.uuid.clone(),
+                    target_path: vol.target_path.clone(),
+                },
+            )),
+        )
+    }
+
+    fn unstage(&self, vol: &Volume) -> StepFuture {
+        let mut node = self.node.clone();
+        step(
+            "unstage",
+            &vol.uuid,
+            node.node_unstage_volume(Request::new(NodeUnstageVolumeRequest {
+                volume_id: vol.uuid.clone(),
+                staging_target_path: vol.staging_path.clone(),
+            })),
+        )
+    }
+
+    fn unexport(&self, vol: &Volume) -> StepFuture {
+        step(
+            "unexport",
+            &vol.uuid,
+            nbd::destroy_blkdev(
+                self.socket.clone(),
+                &DestroyBlkdevRequest {
+                    uuid: vol.uuid.clone(),
+                },
+            ),
+        )
+    }
+
+    fn delete(&self, vol: &Volume) -> StepFuture {
+        let args = Some(jsondata::DestroyReplicaArgs {
+            uuid: vol.uuid.clone(),
+        });
+        step(
+            "delete",
+            &vol.uuid,
+            jsonrpc::call::<_, ()>(&self.socket, "destroy_replica", args)
+                .map_err(|err| err.into_status()),
+        )
+    }
+
+    /// Do the step and then the same step again, which must succeed too.
+    fn twice(
+        &self,
+        vol: &Volume,
+        f: fn(&Soak, &Volume) -> StepFuture,
+    ) -> StepFuture {
+        let soak = self.clone();
+        let vol = vol.clone();
+        Box::new(f(self, &vol).and_then(move |_| f(&soak, &vol)))
+    }
+
+    /// One life cycle of the volume.
+    fn cycle(&self, vol: &Volume) -> StepFuture {
+        let s = self.clone();
+        let v = vol.clone();
+
+        Box::new(
+            self.create(vol)
+                .and_then(enclose! { (s, v) move |_| s.export(&v) })
+                .and_then(enclose! { (s, v) move |_| s.twice(&v, Soak::stage) })
+                .and_then(
+                    enclose! { (s, v) move |_| s.twice(&v, Soak::publish) },
+                )
+                .and_then(enclose! { (s, v) move |_| s.write(&v) })
+                .and_then(
+                    enclose! { (s, v) move |_| s.twice(&v, Soak::unpublish) },
+                )
+                .and_then(
+                    enclose! { (s, v) move |_| s.twice(&v, Soak::unstage) },
+                )
+                .and_then(enclose! { (s, v) move |_| s.unexport(&v) })
+                .and_then(enclose! { (s, v) move |_| s.delete(&v) })
+                .or_else(move |reason| {
+                    s.cleanup(&v).then(move |_| Err::<(), String>(reason))
+                }),
+        )
+    }
+
+    /// Undo as much of a failed cycle as possible ignoring errors.
+    fn cleanup(&self, vol: &Volume) -> StepFuture {
+        let s = self.clone();
+        let v = vol.clone();
+
+        warn!("Cleaning up soak test volume {}", vol.uuid);
+        Box::new(
+            self.unpublish(vol)
+                .then(enclose! { (s, v) move |_| s.unstage(&v) })
+                .then(enclose! { (s, v) move |_| s.unexport(&v) })
+                .then(move |_| s.delete(&v))
+                .then(|_| Ok::<(), String>(())),
+        )
+    }
+
+    /// Run the cycles of the volume until one of them fails. Return the
+    /// number of completed cycles and the failure.
+    fn run(
+        &self,
+        vol: Volume,
+        cycles: u32,
+    ) -> Box<dyn Future<Item = (u32, Option<String>), Error = Status> + Send>
+    {
+        let soak = self.clone();
+
+        Box::new(future::loop_fn(0, move |done| {
+            if done == cycles {
+                return Either::A(future::ok(Loop::Break((done, None))));
+            }
+            debug!("Soak test cycle {} of volume {}", done + 1, vol.uuid);
+            Either::B(soak.cycle(&vol).then(move |res| match res {
+                Ok(()) => Ok::<_, Status>(Loop::Continue(done + 1)),
+                Err(reason) => {
+                    error!("Soak test: {}", reason);
+                    Ok(Loop::Break((done, Some(reason))))
+                }
+            }))
+        }))
+    }
+
+    /// Return what has been left behind by the volumes.
+    fn leaks(
+        &self,
+        vols: Vec<Volume>,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = Status> + Send> {
+        let staging = self.node.staging.clone();
+
+        Box::new(
+            jsonrpc::call::<(), Vec<jsondata::Replica>>(
+                &self.socket,
+                "list_replicas",
+                None,
+            )
+            .join(spdk_methods::get_nbd_disks(&self.socket))
+            .map_err(|err| err.into_status())
+            .map(move |(replicas, disks)| {
+                let mut leaks = Vec::new();
+
+                for vol in vols {
+                    if replicas.iter().any(|r| r.uuid == vol.uuid) {
+                        leaks.push(format!("replica {}", vol.uuid));
+                    }
+                    if let Some(disk) = disks
+                        .iter()
+                        .find(|d| nbd::volume_id(&d.bdev_name) == vol.uuid)
+                    {
+                        leaks.push(format!(
+                            "nbd device {} of volume {}",
+                            disk.nbd_device, vol.uuid
+                        ));
+                    }
+                    for path in &[&vol.staging_path, &vol.target_path] {
+                        if match_mount(None, Some(path.as_str()), false)
+                            .is_some()
+                        {
+                            leaks.push(format!("mount {}", path));
+                        }
+                    }
+                    match staging.get(&vol.uuid) {
+                        Ok(Some(_)) => leaks.push(format!(
+                            "staging record of volume {}",
+                            vol.uuid
+                        )),
+                        Ok(None) => (),
+                        Err(reason) => leaks.push(reason),
+                    }
+                }
+                leaks
+            }),
+        )
+    }
+
+    /// Check for leftovers until there are none or we give up.
+    fn check_leaks(
+        &self,
+        vols: Vec<Volume>,
+    ) -> Box<dyn Future<Item = Vec<String>, Error = Status> + Send> {
+        let soak = self.clone();
+
+        Box::new(future::loop_fn(1, move |attempt| {
+            soak.leaks(vols.clone()).and_then(move |leaks| {
+                if leaks.is_empty() || attempt == LEAK_CHECKS {
+                    return Either::A(future::ok(Loop::Break(leaks)));
+                }
+                Either::B(
+                    Delay::new(Instant::now() + Duration::from_secs(1)).then(
+                        move |_| Ok::<_, Status>(Loop::Continue(attempt + 1)),
+                    ),
+                )
+            })
+        }))
+    }
+}
+
+/// Run the soak test and report failed volumes and leftovers.
+pub fn soak(
+    socket: String,
+    mut node: Node,
+    msg: SoakRequest,
+) -> Box<dyn Future<Item = SoakReply, Error = Status> + Send> {
+    if msg.pool.is_empty() {
+        return Box::new(future::err(Status::new(
+            Code::InvalidArgument,
+            "Pool for the soak test is required".to_owned(),
+        )));
+    }
+    if msg.volumes == 0 || msg.volumes > MAX_VOLUMES || msg.cycles == 0 {
+        return Box::new(future::err(Status::new(
+            Code::InvalidArgument,
+            format!(
+                "Soak test needs 1 to {} volumes and at least one cycle",
+                MAX_VOLUMES
+            ),
+        )));
+    }
+    let dir = std::env::temp_dir().join("mayastor-soak");
+    if let Err(err) = fs::create_dir_all(&dir) {
+        return Box::new(future::err(Status::new(
+            Code::Internal,
+            format!("Failed to create directory {}: {}", dir.display(), err),
+        )));
+    }
+    // volumes of the test are published to its directory, the copy of the
+    // node service which serves kubelet is not affected
+    node.publish_roots.push(dir.clone());
+    // uuids of the volumes are unique for each run of the test
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32;
+    let vols: Vec<Volume> = (0 .. msg.volumes)
+        .map(|i| {
+            Volume::new(&dir, format!("{:08x}-50a4-4000-8000-{:012x}", seed, i))
+        })
+        .collect();
+    let soak = Soak {
+        socket,
+        node,
+        pool: msg.pool.clone(),
+        size: if msg.size == 0 {
+            DEFAULT_SIZE
+        } else {
+            msg.size
+        },
+    };
+    let started = Instant::now();
+
+    info!(
+        "Starting soak test of {} volumes with {} cycles on pool {}",
+        msg.volumes, msg.cycles, msg.pool
+    );
+    let runs: Vec<_> = vols
+        .iter()
+        .map(|vol| soak.run(vol.clone(), msg.cycles))
+        .collect();
+
+    Box::new(future::join_all(runs).and_then(move |results| {
+        let cycles: u32 = results.iter().map(|(done, _)| done).sum();
+        let failures: Vec<String> =
+            results.into_iter().filter_map(|(_, res)| res).collect();
+
+        soak.check_leaks(vols.clone()).map(move |leaks| {
+            if leaks.is_empty() {
+                for vol in &vols {
+                    let _ = fs::remove_dir_all(
+                        PathBuf::from(&vol.staging_path).parent().unwrap(),
+                    );
+                }
+            } else {
+                for leak in &leaks {
+                    error!("Soak test left behind {}", leak);
+                }
+            }
+            info!(
+                "Soak test finished: {} cycles, {} failures, {} leaks",
+                cycles,
+                failures.len(),
+                leaks.len()
+            );
+            SoakReply {
+                cycles,
+                failures,
+                leaks,
+                duration: started.elapsed().as_secs() as u32,
+            }
+        })
+    }))
+}
@@ -0,0 +1,385 @@
+//! Persistent records of staged volumes.
+//!
+//! For every staged volume we keep a small json file in the state directory
+//! describing which device has been mounted where and how (raw block
+//! volumes are not mounted at the staging path, their record is what tells
+//! publish which device to bind mount). The records
+//! survive restarts of the plugin, so that we know what has been staged even
+//! if the information is not available from mayastor (i.e. after the
+//! mayastor container has been restarted).
+//!
+//! Records are written to a temporary file which is fsynced and renamed over
+//! the old record, so a power loss leaves either the old or the new record
+//! behind and never a torn one. Leftovers of interrupted writes are cleaned
+//! up when the store is opened.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use sysfs::TMP_SUFFIX;
+
+/// Suffix of records which could not be parsed and were moved aside.
+const CORRUPT_SUFFIX: &str = "corrupt";
+
+/// Version of the record format. Bump it when making incompatible changes.
+pub const RECORD_VERSION: u32 = 1;
+
+/// Filesystem type in records of raw block volumes.
+pub const RAW_BLOCK: &str = "block";
+
+/// Check that the volume id can be used as a name of a file in the state
+/// directory. The id comes from the CO and must not lead out of it.
+pub fn check_volume_id(volume_id: &str) -> Result<(), String> {
+    if volume_id.is_empty()
+        || volume_id == "."
+        || volume_id == ".."
+        || volume_id.contains('/')
+        || volume_id.contains('\0')
+    {
+        return Err(format!("Invalid volume id \"{}\"", volume_id));
+    }
+    Ok(())
+}
+
+/// Information about a staged volume.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StagingRecord {
+    /// version of the record format
+    pub version: u32,
+    /// CSI volume id (it is the same as the name of the nexus)
+    pub volume_id: String,
+    /// where the volume has been staged
+    pub staging_path: String,
+    /// block device which has been mounted at the staging path
+    pub device: String,
+    /// type of filesystem on the device (`RAW_BLOCK` if there is none)
+    pub fs_type: String,
+    /// options which the filesystem has been mounted with
+    pub mount_flags: Vec<String>,
+    /// features of the filesystem (empty if not known)
+    #[serde(default)]
+    pub features: Vec<String>,
+    /// raw block volume should be opened with O_DIRECT only
+    #[serde(default)]
+    pub direct_io: bool,
+}
+
+impl StagingRecord {
+    pub fn new(
+        volume_id: &str,
+        staging_path: &str,
+        device: &str,
+        fs_type: &str,
+        mount_flags: &[String],
+    ) -> Self {
+        Self {
+            version: RECORD_VERSION,
+            volume_id: volume_id.to_owned(),
+            staging_path: staging_path.to_owned(),
+            device: device.to_owned(),
+            fs_type: fs_type.to_owned(),
+            mount_flags: mount_flags.to_vec(),
+            features: Vec::new(),
+            direct_io: false,
+        }
+    }
+
+    /// Return true if the volume has been staged as a raw block device.
+    pub fn is_block(&self) -> bool {
+        self.fs_type == RAW_BLOCK
+    }
+}
+
+/// Directory with staging records - one file per volume.
+#[derive(Clone, Debug)]
+pub struct StagingStore {
+    dir: PathBuf,
+}
+
+impl StagingStore {
+    /// Open the store and create the state directory if it does not exist.
+    /// Records damaged by a crash in the middle of a write are recovered.
+    pub fn new(dir: &str) -> Result<Self, String> {
+        if let Err(err) = fs::create_dir_all(dir) {
+            return Err(format!(
+                "Failed to create state directory {}: {}",
+                dir, err
+            ));
+        }
+        let store = Self {
+            dir: PathBuf::from(dir),
+        };
+        store.recover()?;
+        Ok(store)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, volume_id: &str) -> Result<PathBuf, String> {
+        check_volume_id(volume_id)?;
+        Ok(self.dir.join(format!("{}.json", volume_id)))
+    }
+
+    /// Make changes of directory entries (create, rename, unlink) durable.
+    fn sync_dir(&self) -> Result<(), String> {
+        sysfs::sync_dir(&self.dir).map_err(|err| {
+            format!("Failed to sync {}: {}", self.dir.display(), err)
+        })
+    }
+
+    /// Create or overwrite the record for a volume.
+    pub fn save(&self, record: &StagingRecord) -> Result<(), String> {
+        let path = self.path(&record.volume_id)?;
+        let data = serde_json::to_vec_pretty(record).unwrap();
+
+        sysfs::write_atomic(&path, &data).map_err(|err| {
+            format!("Failed to write {}: {}", path.display(), err)
+        })?;
+        debug!("Saved staging record for {}", record.volume_id);
+        Ok(())
+    }
+
+    /// Return the record for a volume if there is one.
+    pub fn get(
+        &self,
+        volume_id: &str,
+    ) -> Result<Option<StagingRecord>, String> {
+        read_record(&self.path(volume_id)?)
+    }
+
+    /// Remove the record for a volume. It is not an error if it does not
+    /// exist.
+    pub fn remove(&self, volume_id: &str) -> Result<(), String> {
+        let path = self.path(volume_id)?;
+
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                self.sync_dir()?;
+                debug!("Removed staging record for {}", volume_id);
+                Ok(())
+            }
+            Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+            Err(err) => {
+                Err(format!("Failed to remove {}: {}", path.display(), err))
+            }
+        }
+    }
+
+    /// Remove temporary files left behind by interrupted writes and move
+    /// aside records which cannot be parsed, so that they don't prevent
+    /// listing of the valid ones. A record with unsupported version is not
+    /// damaged and is left alone.
+    fn recover(&self) -> Result<(), String> {
+        let entries = fs::read_dir(&self.dir).map_err(|err| {
+            format!("Failed to read {}: {}", self.dir.display(), err)
+        })?;
+        let mut changed = false;
+
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    return Err(format!(
+                        "Failed to read {}: {}",
+                        self.dir.display(),
+                        err
+                    ))
+                }
+            };
+            match path.extension().and_then(|ext| ext.to_str()) {
+                Some(TMP_SUFFIX) => {
+                    warn!(
+                        "Removing partially written staging record {}",
+                        path.display()
+                    );
+                    fs::remove_file(&path).map_err(|err| {
+                        format!("Failed to remove {}: {}", path.display(), err)
+                    })?;
+                    changed = true;
+                }
+                Some("json") => {
+                    if let Err(ReadError::Invalid(err)) = parse_record(&path) {
+                        let corrupt_path = path
+                            .with_extension(format!("json.{}", CORRUPT_SUFFIX));
+                        warn!(
+                            "{}: moving it to {}",
+                            err,
+                            corrupt_path.display()
+                        );
+                        fs::rename(&path, &corrupt_path).map_err(|err| {
+                            format!(
+                                "Failed to rename {}: {}",
+                                path.display(),
+                                err
+                            )
+                        })?;
+                        changed = true;
+                    }
+                }
+                _ => (),
+            }
+        }
+        if changed {
+            self.sync_dir()?;
+        }
+        Ok(())
+    }
+
+    /// Return all records in the store.
+    pub fn list(&self) -> Result<Vec<StagingRecord>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|err| {
+            format!("Failed to read {}: {}", self.dir.display(), err)
+        })?;
+        let mut records = Vec::new();
+
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(err) => {
+                    return Err(format!(
+                        "Failed to read {}: {}",
+                        self.dir.display(),
+                        err
+                    ))
+                }
+            };
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            if let Some(record) = read_record(&path)? {
+                records.push(record);
+            }
+        }
+        Ok(records)
+    }
+}
+
+/// Failure to read a record.
+enum ReadError {
+    /// the record could not be read
+    Io(String),
+    /// the record was read but it is not valid json record
+    Invalid(String),
+}
+
+fn parse_record(path: &Path) -> Result<Option<StagingRecord>, ReadError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(ReadError::Io(format!(
+                "Failed to read {}: {}",
+                path.display(),
+                err
+            )))
+        }
+    };
+    serde_json::from_slice(&data).map(Some).map_err(|err| {
+        ReadError::Invalid(format!(
+            "Invalid staging record {}: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+fn read_record(path: &Path) -> Result<Option<StagingRecord>, String> {
+    let record = match parse_record(path) {
+        Ok(Some(record)) => record,
+        Ok(None) => return Ok(None),
+        Err(ReadError::Io(err)) | Err(ReadError::Invalid(err)) => {
+            return Err(err)
+        }
+    };
+
+    if record.version > RECORD_VERSION {
+        return Err(format!(
+            "Staging record {} has unsupported version {}",
+            path.display(),
+            record.version
+        ));
+    }
+    Ok(Some(record))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn volume_id_must_be_file_name() {
+        assert!(check_volume_id("3bd81ad5-1b6f-4e5c-9f85-d2f6fbd7fa9c").is_ok());
+        assert!(check_volume_id("pvc.1").is_ok());
+        for id in &["", ".", "..", "../etc/passwd", "a/b", "/abs", "a\0b"] {
+            assert!(check_volume_id(id).is_err(), "{:?} accepted", id);
+        }
+    }
+
+    #[test]
+    fn record_outside_store_is_refused() {
+        let dir = env::temp_dir().join("csi-staging-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        let record =
+            StagingRecord::new("../escaped", "/stage", "/dev/nbd0", "xfs", &[]);
+
+        assert!(store.save(&record).is_err());
+        assert!(store.get("../escaped").is_err());
+        assert!(store.remove("../escaped").is_err());
+        assert!(!env::temp_dir().join("escaped.json").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn files(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn save_leaves_no_temporary_file() {
+        let dir = env::temp_dir().join("csi-staging-save-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        let mut record =
+            StagingRecord::new("vol", "/stage", "/dev/nbd0", "xfs", &[]);
+
+        store.save(&record).unwrap();
+        record.device = "/dev/nbd1".to_owned();
+        store.save(&record).unwrap();
+        assert_eq!(files(&dir), vec!["vol.json"]);
+        assert_eq!(store.get("vol").unwrap(), Some(record));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interrupted_writes_are_recovered() {
+        let dir = env::temp_dir().join("csi-staging-recover-test");
+        let _ = fs::remove_dir_all(&dir);
+        let store = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        let record =
+            StagingRecord::new("vol1", "/stage1", "/dev/nbd0", "xfs", &[]);
+        store.save(&record).unwrap();
+
+        // crash before the rename of the new record
+        fs::write(dir.join("vol1.json.tmp"), b"{\"version\": 1, \"vol")
+            .unwrap();
+        // crash of a writer which did not rename the record atomically
+        fs::write(dir.join("vol2.json"), b"{\"version\": 1, \"vol").unwrap();
+
+        let store = StagingStore::new(dir.to_str().unwrap()).unwrap();
+        assert_eq!(files(&dir), vec!["vol1.json", "vol2.json.corrupt"]);
+        assert_eq!(store.list().unwrap(), vec![record]);
+        assert_eq!(store.get("vol2").unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
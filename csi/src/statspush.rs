@@ -0,0 +1,369 @@
+//! Incremental push of replica stats to the control plane.
+//!
+//! Instead of the control plane polling every node for stats of all its
+//! replicas, the node can push them in a given interval (`--stats-push`).
+//! A push carries only the replicas whose counters have changed since the
+//! previous push with the counters as deltas, and the replicas which are
+//! gone. It is compressed (HTTP deflate content encoding). Each push has a
+//! sequence number and says which push it is based on. If the control plane
+//! does not have that one (i.e. it has been restarted or the push was lost),
+//! it replies with 409 Conflict and the next push is a full snapshot. So is
+//! the push after any failure, since we can't know whether the control plane
+//! got the failed one.
+
+use flate2::{write::ZlibEncoder, Compression};
+use futures::{future, Future, Stream};
+use hyper::{
+    client::HttpConnector,
+    header::{CONTENT_ENCODING, CONTENT_TYPE},
+    Body,
+    Client,
+    Request,
+    StatusCode,
+    Uri,
+};
+use rpc::jsonrpc as jsondata;
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// Compress the data in zlib format (which is what HTTP deflate means).
+fn deflate(data: &[u8]) -> Result<Vec<u8>, String> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(data)
+        .and_then(|_| encoder.finish())
+        .map_err(|err| format!("Failed to compress stats: {}", err))
+}
+
+fn is_false(val: &bool) -> bool {
+    !*val
+}
+
+/// Change of stats of a replica since the previous push.
+#[derive(Debug, PartialEq, Serialize)]
+struct ReplicaDelta {
+    uuid: String,
+    pool: String,
+    /// the counters are absolute values rather than deltas (the replica is
+    /// new or its counters have been reset)
+    #[serde(skip_serializing_if = "is_false")]
+    reset: bool,
+    num_read_ops: u64,
+    num_write_ops: u64,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+/// Body of the push.
+#[derive(Debug, Serialize)]
+struct Push<'a> {
+    node: &'a str,
+    seq: u64,
+    /// sequence number of the push which this one is relative to (None for
+    /// full snapshot)
+    base: Option<u64>,
+    /// seconds until the next push
+    interval: u64,
+    replicas: Vec<ReplicaDelta>,
+    /// replicas which have been removed since the base push
+    removed: Vec<String>,
+}
+
+/// Return changes of the stats since the last ones (all of them if there
+/// are no last ones) and the replicas which are gone.
+fn deltas(
+    last: Option<&HashMap<String, jsondata::Stats>>,
+    current: &[jsondata::Stats],
+) -> (Vec<ReplicaDelta>, Vec<String>) {
+    let empty = HashMap::new();
+    let last = last.unwrap_or(&empty);
+
+    let replicas = current
+        .iter()
+        .filter_map(|st| {
+            let absolute = ReplicaDelta {
+                uuid: st.uuid.clone(),
+                pool: st.pool.clone(),
+                reset: true,
+                num_read_ops: st.num_read_ops,
+                num_write_ops: st.num_write_ops,
+                bytes_read: st.bytes_read,
+                bytes_written: st.bytes_written,
+            };
+            let prev = match last.get(&st.uuid) {
+                Some(prev) => prev,
+                None => return Some(absolute),
+            };
+            if st.pool != prev.pool
+                || st.num_read_ops < prev.num_read_ops
+                || st.num_write_ops < prev.num_write_ops
+                || st.bytes_read < prev.bytes_read
+                || st.bytes_written < prev.bytes_written
+            {
+                return Some(absolute);
+            }
+            let delta = ReplicaDelta {
+                reset: false,
+                num_read_ops: st.num_read_ops - prev.num_read_ops,
+                num_write_ops: st.num_write_ops - prev.num_write_ops,
+                bytes_read: st.bytes_read - prev.bytes_read,
+                bytes_written: st.bytes_written - prev.bytes_written,
+                ..absolute
+            };
+            if delta.num_read_ops == 0
+                && delta.num_write_ops == 0
+                && delta.bytes_read == 0
+                && delta.bytes_written == 0
+            {
+                None
+            } else {
+                Some(delta)
+            }
+        })
+        .collect();
+    let removed = last
+        .keys()
+        .filter(|uuid| !current.iter().any(|st| &st.uuid == *uuid))
+        .cloned()
+        .collect();
+
+    (replicas, removed)
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// sequence number of the last push
+    seq: u64,
+    /// sequence number and stats of the last push acknowledged by the
+    /// control plane
+    acked: Option<(u64, HashMap<String, jsondata::Stats>)>,
+}
+
+/// Periodic push of replica stats of the node.
+#[derive(Clone)]
+pub struct StatsPush {
+    url: Uri,
+    node: String,
+    socket: String,
+    interval: Duration,
+    client: Client<HttpConnector>,
+    state: Arc<Mutex<State>>,
+}
+
+impl StatsPush {
+    pub fn new(
+        url: &str,
+        node: &str,
+        socket: &str,
+        interval: Duration,
+    ) -> Result<Self, String> {
+        let url: Uri = url.parse().map_err(|err| {
+            format!("Invalid stats push URL {}: {}", url, err)
+        })?;
+        if url.scheme_part().map(|s| s.as_str()) != Some("http") {
+            return Err(format!("Stats push URL {} must be http://", url));
+        }
+        info!("Pushing stats every {:?} to {}", interval, url);
+        Ok(Self {
+            url,
+            node: node.to_owned(),
+            socket: socket.to_owned(),
+            interval,
+            client: Client::new(),
+            state: Arc::new(Mutex::new(State::default())),
+        })
+    }
+
+    /// Build compressed body of the next push from the current stats.
+    fn prepare(
+        &self,
+        stats: &[jsondata::Stats],
+    ) -> Result<(u64, usize, Vec<u8>), String> {
+        let mut state = self.state.lock().unwrap();
+        state.seq += 1;
+        let (replicas, removed) =
+            deltas(state.acked.as_ref().map(|(_, last)| last), stats);
+        let push = Push {
+            node: &self.node,
+            seq: state.seq,
+            base: state.acked.as_ref().map(|(seq, _)| *seq),
+            interval: self.interval.as_secs(),
+            replicas,
+            removed,
+        };
+        let count = push.replicas.len() + push.removed.len();
+        let body = deflate(&serde_json::to_vec(&push).unwrap())?;
+        Ok((state.seq, count, body))
+    }
+
+    /// Record the result of the push.
+    fn done(&self, seq: u64, stats: Vec<jsondata::Stats>, acked: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.acked = if acked {
+            Some((
+                seq,
+                stats.into_iter().map(|st| (st.uuid.clone(), st)).collect(),
+            ))
+        } else {
+            None
+        };
+    }
+
+    /// Push the changes of the stats since the last push.
+    fn push(&self) -> impl Future<Item = (), Error = String> {
+        let this = self.clone();
+
+        jsonrpc::call::<(), Vec<jsondata::Stats>>(
+            &self.socket,
+            "stat_replicas",
+            None,
+        )
+        .map_err(|err| format!("Failed to get stats of replicas: {}", err))
+        .and_then(move |stats| {
+            let (seq, count, body) = match this.prepare(&stats) {
+                Ok(res) => res,
+                Err(err) => {
+                    this.done(0, stats, false);
+                    return future::Either::A(future::err(err));
+                }
+            };
+            let len = body.len();
+            let req = Request::post(this.url.clone())
+                .header(CONTENT_TYPE, "application/json")
+                .header(CONTENT_ENCODING, "deflate")
+                .body(Body::from(body))
+                .unwrap();
+
+            future::Either::B(this.client.request(req).then(move |res| {
+                let res = match res {
+                    Ok(resp) if resp.status().is_success() => {
+                        debug!(
+                            "Pushed {} changes of stats in {} bytes (seq {})",
+                            count, len, seq
+                        );
+                        Ok(true)
+                    }
+                    Ok(resp) if resp.status() == StatusCode::CONFLICT => {
+                        debug!("Stats push {} refused, sending all", seq);
+                        Ok(false)
+                    }
+                    Ok(resp) => Err(format!(
+                        "Stats push to {} failed: {}",
+                        this.url,
+                        resp.status()
+                    )),
+                    Err(err) => Err(format!(
+                        "Stats push to {} failed: {}",
+                        this.url, err
+                    )),
+                };
+                this.done(seq, stats, *res.as_ref().unwrap_or(&false));
+                res.map(|_| ())
+            }))
+        })
+    }
+
+    /// Push the stats periodically. The future does not complete.
+    pub fn run(self) -> impl Future<Item = (), Error = ()> {
+        Interval::new(Instant::now() + self.interval, self.interval)
+            .map_err(|err| error!("Stats push timer failed: {}", err))
+            .for_each(move |_| {
+                self.push().then(|res| {
+                    if let Err(err) = res {
+                        warn!("{}", err);
+                    }
+                    Ok(())
+                })
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn stats(uuid: &str, pool: &str, ops: u64) -> jsondata::Stats {
+        jsondata::Stats {
+            uuid: uuid.to_owned(),
+            pool: pool.to_owned(),
+            num_read_ops: ops,
+            num_write_ops: ops,
+            bytes_read: ops * 512,
+            bytes_written: ops * 512,
+            compressed: false,
+            size: 0,
+            allocated: 0,
+        }
+    }
+
+    fn delta(uuid: &str, reset: bool, ops: u64) -> ReplicaDelta {
+        ReplicaDelta {
+            uuid: uuid.to_owned(),
+            pool: "pool".to_owned(),
+            reset,
+            num_read_ops: ops,
+            num_write_ops: ops,
+            bytes_read: ops * 512,
+            bytes_written: ops * 512,
+        }
+    }
+
+    #[test]
+    fn full_snapshot_without_last_stats() {
+        let current = vec![stats("r1", "pool", 5), stats("r2", "pool", 0)];
+        let (replicas, removed) = deltas(None, &current);
+
+        assert_eq!(replicas, vec![delta("r1", true, 5), delta("r2", true, 0)]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn only_changes_are_pushed() {
+        let last: HashMap<_, _> = vec![
+            stats("same", "pool", 5),
+            stats("grown", "pool", 5),
+            stats("reset", "pool", 5),
+            stats("moved", "other", 5),
+            stats("gone", "pool", 5),
+        ]
+        .into_iter()
+        .map(|st| (st.uuid.clone(), st))
+        .collect();
+        let current = vec![
+            stats("same", "pool", 5),
+            stats("grown", "pool", 8),
+            stats("reset", "pool", 2),
+            stats("moved", "pool", 5),
+            stats("new", "pool", 1),
+        ];
+        let (replicas, removed) = deltas(Some(&last), &current);
+
+        assert_eq!(
+            replicas,
+            vec![
+                delta("grown", false, 3),
+                delta("reset", true, 2),
+                delta("moved", true, 5),
+                delta("new", true, 1),
+            ]
+        );
+        assert_eq!(removed, vec!["gone".to_owned()]);
+    }
+
+    #[test]
+    fn deflate_is_zlib_format() {
+        let data = br#"{"node":"node","replicas":[]}"#.repeat(10);
+        let mut inflated = Vec::new();
+        ZlibDecoder::new(&deflate(&data).unwrap()[..])
+            .read_to_end(&mut inflated)
+            .unwrap();
+        assert_eq!(inflated, data);
+    }
+}
@@ -0,0 +1,303 @@
+//! Publishing of a sub-directory of the staged filesystem.
+//!
+//! With `sub_path` parameter in the volume context, the volume is published
+//! by bind mounting a sub-directory of the staging path instead of its root,
+//! so that several pods can share one volume, each with a directory of its
+//! own. The sub-path is relative to the root of the filesystem and it may
+//! contain placeholders `${pod.name}`, `${pod.namespace}` and `${pod.uid}`,
+//! which are replaced by the pod info from the volume context (the CSI driver
+//! object must ask for pod info on mount).
+//!
+//! Missing directories on the sub-path are created with the owner given by
+//! `sub_path_owner` ("uid:gid", the owner of the agent by default) and mode
+//! given by `sub_path_mode` (octal, 0755 by default). Existing directories
+//! are left alone. Symbolic links on the sub-path are refused, so that a
+//! pod cannot make another pod's publish escape the volume: the sub-path is
+//! walked one directory at a time with O_NOFOLLOW relative to the directory
+//! opened before, and the last directory is bind mounted through its file
+//! descriptor (/proc/self/fd), so a pod swapping a directory for a link
+//! after it has been checked does not change what is mounted.
+
+use nix::{
+    errno::Errno,
+    fcntl::{open, openat, OFlag},
+    sys::stat::{fchmod, mkdirat, Mode},
+    Error,
+};
+use std::{
+    collections::HashMap,
+    fs::File,
+    os::unix::io::{AsRawFd, FromRawFd, RawFd},
+    path::{Component, Path, PathBuf},
+};
+
+/// Key of the sub-path in the volume context.
+pub const SUB_PATH_PARAM: &str = "sub_path";
+/// Key of the owner of created directories in the volume context.
+pub const SUB_PATH_OWNER_PARAM: &str = "sub_path_owner";
+/// Key of the mode of created directories in the volume context.
+pub const SUB_PATH_MODE_PARAM: &str = "sub_path_mode";
+
+/// Mode of created directories if none is given.
+const DEFAULT_MODE: u32 = 0o755;
+
+/// Placeholders in the sub-path and keys of their values in volume context.
+const PLACEHOLDERS: [(&str, &str); 3] = [
+    ("${pod.name}", "csi.storage.k8s.io/pod.name"),
+    ("${pod.namespace}", "csi.storage.k8s.io/pod.namespace"),
+    ("${pod.uid}", "csi.storage.k8s.io/pod.uid"),
+];
+
+/// Sub-directory of the filesystem to publish.
+#[derive(Debug)]
+pub struct SubPath {
+    /// path relative to the root of the filesystem
+    pub path: PathBuf,
+    /// uid and gid of created directories
+    pub owner: Option<(u32, u32)>,
+    /// mode of created directories
+    pub mode: u32,
+}
+
+/// Parse the sub-path parameters from the volume context. Return None if
+/// the whole filesystem should be published.
+pub fn sub_path_param(
+    volume_context: &HashMap<String, String>,
+) -> Result<Option<SubPath>, String> {
+    let mut path = match volume_context.get(SUB_PATH_PARAM) {
+        Some(path) => path.to_owned(),
+        None => return Ok(None),
+    };
+
+    for (placeholder, key) in PLACEHOLDERS.iter() {
+        if !path.contains(placeholder) {
+            continue;
+        }
+        match volume_context.get(*key) {
+            Some(val) if !val.is_empty() && !val.contains('/') => {
+                path = path.replace(placeholder, val);
+            }
+            Some(val) => {
+                return Err(format!(
+                    "Invalid value \"{}\" of {} in {}",
+                    val, placeholder, SUB_PATH_PARAM
+                ))
+            }
+            None => {
+                return Err(format!(
+                    "{} in {} requires pod info on mount",
+                    placeholder, SUB_PATH_PARAM
+                ))
+            }
+        }
+    }
+
+    let path = PathBuf::from(path);
+    if path.as_os_str().is_empty()
+        || !path.components().all(|c| match c {
+            Component::Normal(_) => true,
+            _ => false,
+        })
+    {
+        return Err(format!(
+            "Invalid {} \"{}\": expected relative path without \"..\"",
+            SUB_PATH_PARAM,
+            path.display()
+        ));
+    }
+
+    let owner = match volume_context.get(SUB_PATH_OWNER_PARAM) {
+        Some(val) => {
+            let mut parts = val.splitn(2, ':');
+            match (
+                parts.next().and_then(|uid| uid.parse::<u32>().ok()),
+                parts.next().and_then(|gid| gid.parse::<u32>().ok()),
+            ) {
+                (Some(uid), Some(gid)) => Some((uid, gid)),
+                _ => {
+                    return Err(format!(
+                        "Invalid {} \"{}\": expected uid:gid",
+                        SUB_PATH_OWNER_PARAM, val
+                    ))
+                }
+            }
+        }
+        None => None,
+    };
+
+    let mode = match volume_context.get(SUB_PATH_MODE_PARAM) {
+        Some(val) => match u32::from_str_radix(val, 8) {
+            Ok(mode) if mode <= 0o7777 => mode,
+            _ => {
+                return Err(format!(
+                    "Invalid {} \"{}\": expected octal mode",
+                    SUB_PATH_MODE_PARAM, val
+                ))
+            }
+        },
+        None => DEFAULT_MODE,
+    };
+
+    Ok(Some(SubPath {
+        path,
+        owner,
+        mode,
+    }))
+}
+
+/// Opened directory of the sub-path. It must be kept until the directory is
+/// mounted.
+#[derive(Debug)]
+pub struct SubDir {
+    file: File,
+    path: String,
+}
+
+impl SubDir {
+    /// Full path of the sub-directory.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Path to use as the source of the bind mount. It leads to the opened
+    /// directory whatever happens to its path.
+    pub fn mount_source(&self) -> String {
+        format!("/proc/self/fd/{}", self.file.as_raw_fd())
+    }
+}
+
+fn dir_flags() -> OFlag {
+    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC
+}
+
+/// Open the directory entry relative to the parent directory without
+/// following symbolic links. Return None if it does not exist.
+fn open_dir(
+    parent: RawFd,
+    name: &Path,
+    path: &Path,
+) -> Result<Option<File>, String> {
+    match openat(parent, name, dir_flags(), Mode::empty()) {
+        Ok(fd) => Ok(Some(unsafe { File::from_raw_fd(fd) })),
+        Err(Error::Sys(Errno::ENOENT)) => Ok(None),
+        Err(Error::Sys(Errno::ELOOP)) | Err(Error::Sys(Errno::ENOTDIR)) => {
+            Err(format!("{} is not a directory", path.display()))
+        }
+        Err(err) => Err(format!("Failed to open {}: {}", path.display(), err)),
+    }
+}
+
+/// Create missing directories of the sub-path under the staging path and
+/// return the opened sub-directory.
+pub fn prepare_sub_path(
+    staging_path: &str,
+    sub_path: &SubPath,
+) -> Result<SubDir, String> {
+    let mut path = PathBuf::from(staging_path);
+    let mut dir = open(staging_path, dir_flags(), Mode::empty())
+        .map(|fd| unsafe { File::from_raw_fd(fd) })
+        .map_err(|err| format!("Failed to open {}: {}", staging_path, err))?;
+
+    for component in sub_path.path.components() {
+        let name = Path::new(component.as_os_str());
+        path.push(name);
+
+        dir = match open_dir(dir.as_raw_fd(), name, &path)? {
+            Some(subdir) => subdir,
+            None => create_dir(&dir, name, &path, sub_path)?,
+        };
+    }
+    Ok(SubDir {
+        file: dir,
+        path: path.to_string_lossy().into_owned(),
+    })
+}
+
+/// Create the directory in the parent directory with the owner and mode of
+/// the sub-path and return it opened.
+fn create_dir(
+    parent: &File,
+    name: &Path,
+    path: &Path,
+    sub_path: &SubPath,
+) -> Result<File, String> {
+    let created = match mkdirat(parent.as_raw_fd(), name, Mode::S_IRWXU) {
+        Ok(_) => true,
+        // somebody was faster, but it must be a directory too
+        Err(Error::Sys(Errno::EEXIST)) => false,
+        Err(err) => {
+            return Err(format!(
+                "Failed to create directory {}: {}",
+                path.display(),
+                err
+            ))
+        }
+    };
+    let dir = match open_dir(parent.as_raw_fd(), name, path)? {
+        Some(dir) => dir,
+        None => return Err(format!("{} has disappeared", path.display())),
+    };
+    if !created {
+        return Ok(dir);
+    }
+    // mode of mkdir is subject to umask
+    fchmod(dir.as_raw_fd(), Mode::from_bits_truncate(sub_path.mode)).map_err(
+        |err| {
+            format!(
+                "Failed to set mode {:o} of {}: {}",
+                sub_path.mode,
+                path.display(),
+                err
+            )
+        },
+    )?;
+    if let Some((uid, gid)) = sub_path.owner {
+        if unsafe { libc::fchown(dir.as_raw_fd(), uid, gid) } != 0 {
+            return Err(format!(
+                "Failed to set owner {}:{} of {}: {}",
+                uid,
+                gid,
+                path.display(),
+                Errno::last()
+            ));
+        }
+    }
+    debug!("Created directory {} for sub-path", path.display());
+    Ok(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn sub_path_is_created_without_following_links() {
+        let dir = env::temp_dir().join("csi-subpath-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let staging_path = dir.to_str().unwrap();
+        let sub_path = SubPath {
+            path: PathBuf::from("a/b"),
+            owner: None,
+            mode: 0o750,
+        };
+
+        let sub_dir = prepare_sub_path(staging_path, &sub_path).unwrap();
+        assert_eq!(sub_dir.path(), dir.join("a/b").to_str().unwrap());
+        assert_eq!(
+            fs::read_link(sub_dir.mount_source()).unwrap(),
+            dir.join("a/b")
+        );
+        let meta = fs::metadata(dir.join("a/b")).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o750);
+        drop(sub_dir);
+
+        // a link to somewhere else must not be followed
+        fs::remove_dir(dir.join("a/b")).unwrap();
+        std::os::unix::fs::symlink(env::temp_dir(), dir.join("a/b")).unwrap();
+        assert!(prepare_sub_path(staging_path, &sub_path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
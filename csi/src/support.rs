@@ -0,0 +1,89 @@
+//! Node-local information for support bundles.
+//!
+//! Support bundle is created by mayastor-client. Most of the information in
+//! it can be obtained by other gRPC methods (pools, replicas, logs, ...).
+//! Here we collect the rest, which is known only to the agent on the node.
+//! Values which could be secrets are redacted.
+
+use crate::{rpc::mayastor::SupportFile, staging::StagingStore};
+use futures::Future;
+use jsonrpc::spdk_methods;
+use std::fs;
+use tower_grpc::Status;
+
+/// Substrings of mount option names which indicate that the value is secret.
+const SECRET_OPTS: [&str; 5] = ["pass", "secret", "key", "token", "cred"];
+
+fn is_secret(opt_name: &str) -> bool {
+    let name = opt_name.to_lowercase();
+    SECRET_OPTS.iter().any(|s| name.contains(s))
+}
+
+/// Replace values of mount options which look like secrets.
+fn redact_mounts(mounts: &str) -> String {
+    mounts
+        .lines()
+        .map(|line| {
+            let mut fields: Vec<String> =
+                line.split(' ').map(String::from).collect();
+            if fields.len() > 3 {
+                fields[3] = fields[3]
+                    .split(',')
+                    .map(|opt| match opt.find('=') {
+                        Some(idx) if is_secret(&opt[.. idx]) => {
+                            format!("{}=<redacted>", &opt[.. idx])
+                        }
+                        _ => opt.to_owned(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(",");
+            }
+            fields.join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn file(name: &str, content: String) -> SupportFile {
+    SupportFile {
+        name: name.to_owned(),
+        content,
+    }
+}
+
+/// Collect the files. Failure to obtain any piece of information is not an
+/// error - the error message becomes content of the file instead.
+pub fn collect(
+    socket: &str,
+    config: &str,
+    staging: &StagingStore,
+) -> Box<dyn Future<Item = Vec<SupportFile>, Error = Status> + Send> {
+    let mut files = vec![
+        file("config.json", config.to_owned()),
+        file(
+            "mounts.txt",
+            match fs::read_to_string("/proc/self/mounts") {
+                Ok(mounts) => redact_mounts(&mounts),
+                Err(err) => format!("Failed to read mount table: {}", err),
+            },
+        ),
+        file(
+            "staging.json",
+            match staging.list() {
+                Ok(records) => serde_json::to_string_pretty(&records).unwrap(),
+                Err(err) => err,
+            },
+        ),
+    ];
+
+    Box::new(spdk_methods::get_nbd_disks(socket).then(move |res| {
+        files.push(file(
+            "nbd_disks.json",
+            match res {
+                Ok(disks) => serde_json::to_string_pretty(&disks).unwrap(),
+                Err(err) => format!("Failed to list nbd disks: {}", err),
+            },
+        ));
+        Ok(files)
+    }))
+}
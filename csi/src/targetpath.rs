@@ -0,0 +1,100 @@
+//! Verification of target paths of published volumes.
+//!
+//! The target path of publish (and unpublish) comes from the CO and the
+//! plugin mounts (or unmounts) whatever is there as root. A compromised CO
+//! could make it mount a volume over a system directory or unmount one. The
+//! target path must be within one of the allowed directories (kubelet's
+//! pods directory by default, `--publish-root` to change it), both as given
+//! and with symbolic links resolved, so that a link planted by a pod can't
+//! point it elsewhere. A target path which is itself a symbolic link is
+//! refused.
+
+use std::{
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Directory of the pods of kubelet, where it asks for volumes to be
+/// published.
+pub const DEFAULT_PUBLISH_ROOT: &str = "/var/lib/kubelet/pods";
+
+/// Check that the target path is within one of the allowed directories.
+pub fn check_target_path(
+    target: &str,
+    roots: &[PathBuf],
+) -> Result<(), String> {
+    let path = Path::new(target);
+
+    if !path.is_absolute() {
+        return Err(format!("Target path {} is not absolute", target));
+    }
+    if path
+        .components()
+        .any(|c| c == Component::ParentDir || c == Component::CurDir)
+    {
+        return Err(format!("Target path {} is not normalized", target));
+    }
+    let root = match roots.iter().find(|root| path.starts_with(root)) {
+        Some(root) => root,
+        None => {
+            return Err(format!(
+                "Target path {} is not within allowed directories {:?}",
+                target, roots
+            ))
+        }
+    };
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if meta.file_type().is_symlink() {
+            return Err(format!("Target path {} is a symbolic link", target));
+        }
+    }
+    let real_root = fs::canonicalize(root).map_err(|err| {
+        format!("Failed to resolve directory {}: {}", root.display(), err)
+    })?;
+    // the target is created by publish if it does not exist yet, its nearest
+    // existing ancestor is what it ends up in
+    let existing = path
+        .ancestors()
+        .find(|p| fs::symlink_metadata(p).is_ok())
+        .unwrap_or(root);
+    let real = fs::canonicalize(existing).map_err(|err| {
+        format!("Failed to resolve {}: {}", existing.display(), err)
+    })?;
+    if !real.starts_with(&real_root) {
+        return Err(format!(
+            "Target path {} leads out of {} (to {})",
+            target,
+            root.display(),
+            real.display()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, os::unix::fs::symlink};
+
+    #[test]
+    fn target_outside_of_roots_is_refused() {
+        let dir = env::temp_dir().join("csi-targetpath-test");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("pods");
+        fs::create_dir_all(root.join("pod1")).unwrap();
+        symlink(env::temp_dir(), root.join("pod1/escape")).unwrap();
+        let roots = vec![root.clone()];
+        let target =
+            |path: &str| root.join(path).to_string_lossy().into_owned();
+
+        assert!(check_target_path(&target("pod1/vol"), &roots).is_ok());
+        assert!(check_target_path(&target("pod1/new/vol"), &roots).is_ok());
+        assert!(check_target_path("/var/lib/mayastor", &roots).is_err());
+        assert!(check_target_path("relative/vol", &roots).is_err());
+        assert!(check_target_path(&target("pod1/../../vol"), &roots).is_err());
+        // links planted in the root must not lead out of it
+        assert!(check_target_path(&target("pod1/escape"), &roots).is_err());
+        assert!(check_target_path(&target("pod1/escape/vol"), &roots).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
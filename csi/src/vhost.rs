@@ -0,0 +1,175 @@
+//! Export of nexus over vhost-user-blk for VM workloads.
+//!
+//! Instead of a kernel block device, a nexus can be published as a
+//! vhost-user-blk socket, which a hypervisor (i.e. KubeVirt's qemu) uses to
+//! submit IO straight to mayastor over shared memory. SPDK creates the socket
+//! in its vhost directory (working directory of mayastor unless set by `-S`),
+//! which must be shared with the agent, so that it can clean up sockets left
+//! behind by a crashed mayastor. The controller of a nexus is named after
+//! it, so publishing is idempotent and a stale socket of the same name is
+//! always ours to remove.
+
+use crate::rpc::mayastor::{Stats, VhostController};
+use futures::future::{self, Either, Future};
+use jsonrpc::spdk_methods::{
+    self,
+    ConstructVhostBlkControllerArgs,
+    RemoveVhostControllerArgs,
+};
+use std::{fs, io::ErrorKind, path::Path};
+use tower_grpc::{Code, Status};
+
+/// Return name of the vhost controller of the bdev.
+fn controller_name(bdev_name: &str) -> String {
+    format!("vhost.{}", bdev_name)
+}
+
+/// Remove socket file left behind by a controller which is gone.
+fn remove_stale_socket(path: &Path) -> Result<(), Status> {
+    match fs::remove_file(path) {
+        Ok(_) => {
+            warn!("Removed stale vhost socket {}", path.display());
+            Ok(())
+        }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Status::new(
+            Code::Internal,
+            format!(
+                "Failed to remove stale vhost socket {}: {}",
+                path.display(),
+                err
+            ),
+        )),
+    }
+}
+
+/// Export the bdev over vhost-user-blk and return path of the socket.
+pub fn publish(
+    socket: String,
+    vhost_dir: String,
+    bdev_name: String,
+) -> Box<dyn Future<Item = String, Error = Status> + Send> {
+    let f = spdk_methods::get_vhost_controllers(&socket)
+        .map_err(|err| err.into_status())
+        .and_then(move |ctrlrs| {
+            if let Some(ctrlr) = ctrlrs
+                .iter()
+                .find(|c| c.blk_bdev_name() == Some(bdev_name.as_str()))
+            {
+                info!("{} already published on {}", bdev_name, ctrlr.socket);
+                return Either::A(future::ok(ctrlr.socket.clone()));
+            }
+            let name = controller_name(&bdev_name);
+            let path = Path::new(&vhost_dir).join(&name);
+            // SPDK refuses to create the socket if the file exists
+            if let Err(status) = remove_stale_socket(&path) {
+                return Either::A(future::err(status));
+            }
+            let path = path.to_string_lossy().into_owned();
+
+            Either::B(
+                spdk_methods::construct_vhost_blk_controller(
+                    &socket,
+                    ConstructVhostBlkControllerArgs {
+                        ctrlr: name,
+                        dev_name: bdev_name.clone(),
+                        readonly: None,
+                    },
+                )
+                .map_err(|err| err.into_status())
+                .and_then(move |done| {
+                    if done {
+                        info!("{} published on {}", bdev_name, path);
+                        Ok(path)
+                    } else {
+                        Err(Status::new(
+                            Code::Internal,
+                            format!(
+                                "Failed to create vhost controller for {}",
+                                bdev_name
+                            ),
+                        ))
+                    }
+                }),
+            )
+        });
+
+    Box::new(f)
+}
+
+/// Remove the vhost controller of the bdev and its socket if there is one.
+pub fn unpublish(
+    socket: String,
+    bdev_name: String,
+) -> Box<dyn Future<Item = (), Error = Status> + Send> {
+    let f = spdk_methods::get_vhost_controllers(&socket)
+        .map_err(|err| err.into_status())
+        .and_then(move |ctrlrs| {
+            let ctrlr = match ctrlrs
+                .into_iter()
+                .find(|c| c.blk_bdev_name() == Some(bdev_name.as_str()))
+            {
+                Some(ctrlr) => ctrlr,
+                None => return Either::A(future::ok(())),
+            };
+            Either::B(
+                spdk_methods::remove_vhost_controller(
+                    &socket,
+                    RemoveVhostControllerArgs {
+                        ctrlr: ctrlr.ctrlr.clone(),
+                    },
+                )
+                .map_err(|err| err.into_status())
+                .and_then(move |done| {
+                    if !done {
+                        return Err(Status::new(
+                            Code::Internal,
+                            format!(
+                                "Failed to remove vhost controller {}",
+                                ctrlr.ctrlr
+                            ),
+                        ));
+                    }
+                    info!("Removed vhost controller {}", ctrlr.ctrlr);
+                    // SPDK unlinks the socket, unless it has crashed half-way
+                    remove_stale_socket(Path::new(&ctrlr.socket))
+                }),
+            )
+        });
+
+    Box::new(f)
+}
+
+/// Return vhost-blk controllers with IO stats of the exported bdevs.
+pub fn list(
+    socket: String,
+) -> Box<dyn Future<Item = Vec<VhostController>, Error = Status> + Send> {
+    let f = spdk_methods::get_vhost_controllers(&socket)
+        .join(spdk_methods::get_bdevs_iostat(&socket, None))
+        .map_err(|err| err.into_status())
+        .map(|(ctrlrs, iostat)| {
+            ctrlrs
+                .into_iter()
+                .filter_map(|ctrlr| {
+                    let bdev_name = ctrlr.blk_bdev_name()?.to_owned();
+                    let stats =
+                        iostat.bdevs.iter().find(|s| s.name == bdev_name).map(
+                            |s| Stats {
+                                num_read_ops: s.num_read_ops,
+                                num_write_ops: s.num_write_ops,
+                                bytes_read: s.bytes_read,
+                                bytes_written: s.bytes_written,
+                            },
+                        );
+                    Some(VhostController {
+                        name: ctrlr.ctrlr,
+                        bdev_name,
+                        socket: ctrlr.socket,
+                        stats,
+                    })
+                })
+                .collect()
+        });
+
+    Box::new(f)
+}
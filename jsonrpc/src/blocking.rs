@@ -0,0 +1,111 @@
+//! Blocking variants of the calls for callers without a tokio runtime (i.e.
+//! command line tools and tests making one-shot calls).
+//!
+//! Each function drives the future of the call on a current-thread runtime
+//! created just for it and returns when the call completes. They must not
+//! be used from within a runtime, since it can't be entered recursively.
+//! `RpcClient` is not available here, because its connection is served by
+//! tasks which would not outlive the runtime.
+
+use crate::{error::Error, BatchCall, CallOptions, Response};
+use futures::Future;
+use std::time::Instant;
+use tokio::runtime::current_thread::Runtime;
+
+/// Run the future to completion on a new current-thread runtime.
+fn block_on<F>(fut: F) -> Result<F::Item, Error>
+where
+    F: Future<Error = Error>,
+{
+    let mut rt = Runtime::new().map_err(|err| {
+        Error::GenericError(format!("Failed to create runtime: {}", err))
+    })?;
+    rt.block_on(fut)
+}
+
+/// Blocking `call()`.
+pub fn call<A, R>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+) -> Result<R, Error>
+where
+    A: serde::ser::Serialize,
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    block_on(crate::call(sock_path, method, args))
+}
+
+/// Blocking `call_with_options()`.
+pub fn call_with_options<A, R>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+    options: CallOptions,
+) -> Result<R, Error>
+where
+    A: serde::ser::Serialize,
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    block_on(crate::call_with_options(sock_path, method, args, options))
+}
+
+/// Blocking `call_response()`.
+pub fn call_response<A>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+    options: CallOptions,
+) -> Result<Response, Error>
+where
+    A: serde::ser::Serialize,
+{
+    block_on(crate::call_response(sock_path, method, args, options))
+}
+
+/// Blocking `call_batch()`.
+pub fn call_batch(
+    sock_path: &str,
+    calls: Vec<BatchCall>,
+    options: CallOptions,
+) -> Result<Vec<Result<serde_json::Value, Error>>, Error> {
+    block_on(crate::call_batch(sock_path, calls, options))
+}
+
+/// Blocking `call_many()`.
+pub fn call_many<A, R>(
+    sock_path: &str,
+    method: &str,
+    args: Vec<A>,
+    concurrency: usize,
+    options: CallOptions,
+) -> Result<Vec<Result<R, Error>>, Error>
+where
+    A: serde::ser::Serialize + Send + 'static,
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    block_on(crate::call_many(
+        sock_path,
+        method,
+        args,
+        concurrency,
+        options,
+    ))
+}
+
+/// Blocking `notify()`.
+pub fn notify<A>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+) -> Result<(), Error>
+where
+    A: serde::ser::Serialize,
+{
+    block_on(crate::notify(sock_path, method, args))
+}
+
+/// Blocking `wait_ready()`.
+pub fn wait_ready(sock_path: &str, deadline: Instant) -> Result<(), Error> {
+    block_on(crate::wait_ready(sock_path, deadline))
+}
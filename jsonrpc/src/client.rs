@@ -0,0 +1,854 @@
+//! json-rpc client with persistent connection to the server.
+//!
+//! Unlike `call()`, which opens a new connection for each request, the
+//! client keeps the connection open for its lifetime. Each request gets a
+//! unique id and is written to the socket right away without waiting for
+//! replies to previous requests. Replies are matched to the requests by
+//! their ids, so the server is free to reply in any order.
+//!
+//! The connection is served by two tasks spawned on the default executor:
+//! a writer sending the requests and a reader dispatching the replies. When
+//! all clones of the client and futures returned by it have been dropped,
+//! the connection is closed.
+//!
+//! A call whose future is dropped before the reply arrives (i.e. the gRPC
+//! request which made it was cancelled) is forgotten by the client and its
+//! late reply is discarded. The request is always written to the socket as
+//! a whole by the writer task, so the connection stays usable for other
+//! calls.
+//!
+//! A reply larger than the max response size breaks the connection, since
+//! the rest of the stream can't be told apart from it, and all pending calls
+//! fail.
+//!
+//! Requests count against the limit of requests in flight to the server
+//! like the requests of `call()`. A request waiting for its turn is not
+//! written to the socket yet.
+//!
+//! Persistent connections are not available over HTTP transport.
+//!
+//! The client is configured by `RpcClient::builder()`. Besides the server,
+//! the builder sets how connecting is retried and the default options
+//! (timeout, max response size and validation mode) of the calls made by
+//! `call()`.
+//!
+//! A batch of calls is sent over the connection the same way. Each call of
+//! the batch gets its own result, so a failure of one call does not fail the
+//! others and the caller can retry just the failed ones. (SPDK does not
+//! support json-rpc batch requests, hence we don't send them as an array.)
+//!
+//! With keep-alive (`RpcClientBuilder::keepalive()`), health of the
+//! connection is checked periodically by a lightweight call
+//! (`rpc_get_methods`). Any reply, even an error, means that the server is
+//! alive. If the call fails or the connection has been closed, the client
+//! reconnects at the next check, so that the calls made after the server
+//! has come back go to the new connection. Changes of the health are
+//! reported to the callback set by `RpcClientBuilder::on_health()`.
+
+use crate::{
+    check_reply_id,
+    error::Error,
+    hooks,
+    methods::{self, Methods},
+    params,
+    pool,
+    redact,
+    reply_id,
+    reply_result,
+    retry::{self, RetryPolicy},
+    transport::{Connection, Endpoint},
+    with_timeout,
+    CallOptions,
+    Request,
+    Response,
+    ValidationMode,
+};
+use futures::{
+    future::{self, Either, Future, Loop},
+    sync::{mpsc, oneshot},
+    Stream,
+};
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{read, write_all, AsyncRead, AsyncWrite},
+    prelude::Poll,
+    timer::Interval,
+};
+
+/// Size of the buffer for reading replies from the socket.
+const READ_CHUNK: usize = 4096;
+/// Method called by health checks of the connection.
+const HEALTH_CHECK_METHOD: &str = "rpc_get_methods";
+
+type ReplySender = oneshot::Sender<Result<Response, Error>>;
+
+/// One call of a batch.
+#[derive(Clone, Debug)]
+pub struct BatchCall {
+    pub method: String,
+    pub params: Option<serde_json::Value>,
+}
+
+impl BatchCall {
+    pub fn new<A>(method: &str, args: Option<A>) -> Self
+    where
+        A: serde::ser::Serialize,
+    {
+        Self {
+            method: method.to_owned(),
+            params: args.map(|val| serde_json::to_value(val).unwrap()),
+        }
+    }
+}
+
+/// Stream which can be shared by reader and writer task and shut down from
+/// either of them.
+#[derive(Clone)]
+struct SharedStream(Arc<Connection>);
+
+impl Read for SharedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+impl Write for SharedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.0).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.0).flush()
+    }
+}
+
+impl AsyncRead for SharedStream {}
+
+impl AsyncWrite for SharedStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
+
+/// State shared by the client handles and the reader task.
+struct Inner {
+    /// Id of the next request.
+    next_id: u64,
+    /// Requests waiting for a reply.
+    pending: HashMap<u64, ReplySender>,
+    /// Set to the reason when the connection is not usable anymore.
+    closed: Option<String>,
+}
+
+impl Inner {
+    /// Mark the connection as closed and fail all pending requests.
+    fn close(&mut self, reason: String) {
+        debug!("json-rpc connection closed: {}", reason);
+        for (_, sender) in self.pending.drain() {
+            let _ = sender.send(Err(closed_error(&reason)));
+        }
+        self.closed = Some(reason);
+    }
+}
+
+/// Call waiting for a reply. It is removed from the pending calls when
+/// dropped, so that calls which were cancelled or timed out don't pile up.
+struct PendingCall {
+    id: u64,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        if self
+            .inner
+            .lock()
+            .unwrap()
+            .pending
+            .remove(&self.id)
+            .is_some()
+        {
+            debug!("json-rpc call with id {} was abandoned", self.id);
+        }
+    }
+}
+
+fn closed_error(reason: &str) -> Error {
+    Error::IoError(io::Error::new(
+        io::ErrorKind::ConnectionAborted,
+        format!("json-rpc connection closed: {}", reason),
+    ))
+}
+
+/// Connection of the client to the server with its reader and writer task.
+/// It is replaced by a new one when the client reconnects.
+#[derive(Clone)]
+struct Link {
+    inner: Arc<Mutex<Inner>>,
+    sender: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Link {
+    /// Spawn the tasks serving the connection.
+    fn start(socket: Connection, max_response_size: Option<usize>) -> Self {
+        let stream = SharedStream(Arc::new(socket));
+        let inner = Arc::new(Mutex::new(Inner {
+            next_id: 0,
+            pending: HashMap::new(),
+            closed: None,
+        }));
+        let (sender, receiver) = mpsc::unbounded();
+
+        tokio::spawn(write_requests(
+            stream.clone(),
+            receiver,
+            Arc::clone(&inner),
+        ));
+        tokio::spawn(read_replies(
+            stream,
+            Arc::clone(&inner),
+            max_response_size,
+        ));
+
+        Link {
+            inner,
+            sender,
+        }
+    }
+}
+
+/// Change of health of the connection found out by keep-alive checks.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthEvent {
+    /// The health check failed or the connection has been closed (with the
+    /// reason).
+    Unhealthy(String),
+    /// The client has connected to the server again.
+    Reconnected,
+    /// The health check succeeded after the connection was unhealthy.
+    Healthy,
+}
+
+/// Callback receiving changes of health of the connection.
+#[derive(Clone)]
+struct HealthCallback(Arc<dyn Fn(&HealthEvent) + Send + Sync>);
+
+impl fmt::Debug for HealthCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HealthCallback")
+    }
+}
+
+impl HealthCallback {
+    fn notify(callback: &Option<Self>, event: HealthEvent) {
+        if let Some(callback) = callback {
+            (callback.0)(&event);
+        }
+    }
+}
+
+/// Configuration of a client to be connected.
+#[derive(Clone, Debug, Default)]
+pub struct RpcClientBuilder {
+    socket: Option<String>,
+    /// default options of the calls (and retrying of connecting)
+    options: CallOptions,
+    /// period of health checks
+    keepalive: Option<Duration>,
+    on_health: Option<HealthCallback>,
+}
+
+impl RpcClientBuilder {
+    /// Unix domain socket or address of the server (see `Endpoint`).
+    pub fn socket(mut self, sock_path: &str) -> Self {
+        self.socket = Some(sock_path.to_owned());
+        self
+    }
+
+    /// Max time to wait for the reply to a call.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.options.timeout = Some(timeout);
+        self
+    }
+
+    /// Wait for the replies forever.
+    pub fn no_timeout(mut self) -> Self {
+        self.options.timeout = None;
+        self
+    }
+
+    /// Number of retries of a failed connection attempt (with the default
+    /// backoff).
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.options.retry.max_attempts = retries + 1;
+        self
+    }
+
+    /// Retrying of connection failures.
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.options.retry = retry;
+        self
+    }
+
+    /// Max size of a reply. A larger reply breaks the connection.
+    pub fn max_response_size(mut self, size: usize) -> Self {
+        self.options.max_response_size = Some(size);
+        self
+    }
+
+    /// Don't limit the size of replies.
+    pub fn no_response_limit(mut self) -> Self {
+        self.options.max_response_size = None;
+        self
+    }
+
+    /// How strictly the replies are checked.
+    pub fn validation(mut self, mode: ValidationMode) -> Self {
+        self.options.validation = mode;
+        self
+    }
+
+    /// Check health of the connection periodically and reconnect if it is
+    /// broken. A check which does not get a reply within the period fails.
+    pub fn keepalive(mut self, interval: Duration) -> Self {
+        self.keepalive = Some(interval);
+        self
+    }
+
+    /// Call the function when health of the connection changes (see
+    /// `keepalive`).
+    pub fn on_health<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&HealthEvent) + Send + Sync + 'static,
+    {
+        self.on_health = Some(HealthCallback(Arc::new(callback)));
+        self
+    }
+
+    /// Connect to the server.
+    pub fn build(
+        self,
+    ) -> Box<dyn Future<Item = RpcClient, Error = Error> + Send> {
+        let endpoint = match self.socket {
+            Some(socket) => socket,
+            None => {
+                return Box::new(future::err(Error::GenericError(
+                    "Socket of json-rpc server is not set".to_owned(),
+                )))
+            }
+        };
+        if let Endpoint::Http(_) = Endpoint::parse(&endpoint) {
+            return Box::new(future::err(Error::GenericError(
+                "Persistent connections are not supported over HTTP".to_owned(),
+            )));
+        }
+        let options = self.options;
+        let keepalive = self.keepalive;
+        let on_health = self.on_health;
+        let f = retry::connect(&endpoint, options.retry.clone()).map(
+            move |socket| {
+                let link = Link::start(socket, options.max_response_size);
+                let client = RpcClient {
+                    endpoint,
+                    options,
+                    methods: Arc::new(Mutex::new(None)),
+                    link: Arc::new(Mutex::new(link)),
+                    health: Arc::new(Mutex::new(None)),
+                };
+                if let Some(interval) = keepalive {
+                    tokio::spawn(keep_alive(&client, interval, on_health));
+                }
+                client
+            },
+        );
+
+        Box::new(f)
+    }
+}
+
+/// Handle of a persistent json-rpc connection. It is cheap to clone and the
+/// clones share the same connection.
+#[derive(Clone)]
+pub struct RpcClient {
+    /// address of the server (for the limit of requests in flight)
+    endpoint: String,
+    /// default options of the calls
+    options: CallOptions,
+    /// methods supported by the server (queried when first needed)
+    methods: Arc<Mutex<Option<Arc<Methods>>>>,
+    /// current connection
+    link: Arc<Mutex<Link>>,
+    /// why the connection is unhealthy (None if it is healthy)
+    health: Arc<Mutex<Option<String>>>,
+}
+
+impl RpcClient {
+    /// Return builder of a client with default options.
+    pub fn builder() -> RpcClientBuilder {
+        RpcClientBuilder::default()
+    }
+
+    /// Connect to json-rpc server listening on the unix domain socket or
+    /// TCP address (host:port) with default options.
+    pub fn connect(
+        sock_path: &str,
+    ) -> Box<dyn Future<Item = RpcClient, Error = Error> + Send> {
+        Self::builder().socket(sock_path).build()
+    }
+
+    /// Return methods supported by the server. They are queried by
+    /// `rpc_get_methods` only once for the connection.
+    pub fn methods(
+        &self,
+    ) -> Box<dyn Future<Item = Arc<Methods>, Error = Error> + Send> {
+        if let Some(methods) = self.methods.lock().unwrap().as_ref() {
+            return Box::new(future::ok(Arc::clone(methods)));
+        }
+        let cache = Arc::clone(&self.methods);
+
+        Box::new(self.call::<(), Vec<String>>("rpc_get_methods", None).map(
+            move |names| {
+                let methods = Arc::new(Methods::new(names));
+                *cache.lock().unwrap() = Some(Arc::clone(&methods));
+                methods
+            },
+        ))
+    }
+
+    /// Make json-rpc request with the options of the client and return user
+    /// data from the reply. Requests are sent immediately even if replies to
+    /// previous requests have not been received yet.
+    pub fn call<A, R>(
+        &self,
+        method: &str,
+        args: Option<A>,
+    ) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        A: serde::ser::Serialize,
+        R: 'static + serde::de::DeserializeOwned + Send,
+    {
+        self.call_with_options(method, args, self.options.clone())
+    }
+
+    /// Same as call() but with options controlling the call.
+    pub fn call_with_options<A, R>(
+        &self,
+        method: &str,
+        args: Option<A>,
+        options: CallOptions,
+    ) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        A: serde::ser::Serialize,
+        R: 'static + serde::de::DeserializeOwned + Send,
+    {
+        let params = params::serialize(args, options.positional_params);
+        let client = self.clone();
+        let method_name = method.to_owned();
+        let endpoint = Endpoint::parse(&self.endpoint).to_string();
+        Box::new(
+            methods::with_alias(&self.endpoint, method, move |name| {
+                client.call_params(name, params.clone(), options.clone())
+            })
+            .map_err(move |err| err.in_call(&method_name, &endpoint)),
+        )
+    }
+
+    /// Make the call with serialized parameters.
+    fn call_params<R>(
+        &self,
+        method: &str,
+        mut params: Option<serde_json::Value>,
+        options: CallOptions,
+    ) -> Box<dyn Future<Item = R, Error = Error> + Send>
+    where
+        R: 'static + serde::de::DeserializeOwned + Send,
+    {
+        let link = self.link();
+        let (id, request_raw) = {
+            let mut inner = link.inner.lock().unwrap();
+            if let Some(reason) = &inner.closed {
+                return Box::new(future::err(closed_error(reason)));
+            }
+            let id = inner.next_id;
+            inner.next_id += 1;
+            hooks::before(method, id, &mut params);
+
+            let request = Request {
+                method,
+                params,
+                id: From::from(id),
+                jsonrpc: Some("2.0"),
+            };
+            (id, serde_json::to_vec(&request).unwrap())
+        };
+
+        let validation = options.validation;
+        // the request is sent right away unless it has to wait for its turn
+        let f: Box<dyn Future<Item = Response, Error = Error> + Send> =
+            match pool::try_acquire_request(&self.endpoint) {
+                Some(permit) => self.send(&link, id, request_raw, permit),
+                None => {
+                    let client = self.clone();
+                    Box::new(pool::acquire_request(&self.endpoint).and_then(
+                        move |permit| {
+                            client.send(&link, id, request_raw, permit)
+                        },
+                    ))
+                }
+            };
+
+        let f = f.and_then(move |reply| {
+            check_reply_id(&reply.id, id, validation)?;
+            reply_result(reply, validation)
+        });
+
+        hooks::observe(
+            method,
+            id,
+            with_timeout(f, method, options.timeout, options.deadline),
+        )
+    }
+
+    /// Send the request over the connection and return future of its reply.
+    /// The permit is held until the reply arrives.
+    fn send(
+        &self,
+        link: &Link,
+        id: u64,
+        request_raw: Vec<u8>,
+        permit: pool::Slot,
+    ) -> Box<dyn Future<Item = Response, Error = Error> + Send> {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        {
+            let mut inner = link.inner.lock().unwrap();
+            if let Some(reason) = &inner.closed {
+                return Box::new(future::err(closed_error(reason)));
+            }
+            trace!("JSON request: {}", redact::redacted_raw(&request_raw));
+            if link.sender.unbounded_send(request_raw).is_err() {
+                return Box::new(future::err(closed_error(
+                    "writer has terminated",
+                )));
+            }
+            inner.pending.insert(id, reply_sender);
+        }
+
+        // the client is held until the reply arrives, so that the
+        // connection is not closed under the request
+        let client = self.clone();
+        let pending = PendingCall {
+            id,
+            inner: Arc::clone(&link.inner),
+        };
+
+        Box::new(reply_receiver.then(move |res| {
+            drop(pending);
+            drop(permit);
+            drop(client);
+            match res {
+                Ok(res) => res,
+                Err(_) => Err(closed_error("request was cancelled")),
+            }
+        }))
+    }
+
+    /// Return number of calls waiting for a reply.
+    pub fn pending_calls(&self) -> usize {
+        self.link().inner.lock().unwrap().pending.len()
+    }
+
+    /// Return why the connection is unhealthy or None if it is healthy as
+    /// far as the client knows.
+    pub fn unhealthy(&self) -> Option<String> {
+        let health = self.health.lock().unwrap().clone();
+        health.or_else(|| self.link().inner.lock().unwrap().closed.clone())
+    }
+
+    /// Return the current connection.
+    fn link(&self) -> Link {
+        self.link.lock().unwrap().clone()
+    }
+
+    /// Make the health check call. Any reply means that the server is alive.
+    fn ping(
+        &self,
+        timeout: Duration,
+    ) -> impl Future<Item = (), Error = String> {
+        let mut options = self.options.clone();
+        options.timeout = Some(timeout);
+        options.deadline = None;
+
+        self.call_params::<serde_json::Value>(
+            HEALTH_CHECK_METHOD,
+            None,
+            options,
+        )
+        .then(|res| match res {
+            Ok(_) => Ok(()),
+            Err(err) => match err.root() {
+                Error::RpcError {
+                    ..
+                } => Ok(()),
+                _ => Err(err.to_string()),
+            },
+        })
+    }
+
+    /// Connect to the server again replacing the current connection.
+    fn reconnect(&self) -> impl Future<Item = (), Error = String> {
+        let link = Arc::clone(&self.link);
+        let methods = Arc::clone(&self.methods);
+        let max_response_size = self.options.max_response_size;
+
+        retry::connect(&self.endpoint, RetryPolicy::none())
+            .map_err(|err| err.to_string())
+            .map(move |socket| {
+                *link.lock().unwrap() = Link::start(socket, max_response_size);
+                // the server may have been upgraded meanwhile
+                *methods.lock().unwrap() = None;
+            })
+    }
+
+    /// Check health of the connection and reconnect if it has been closed.
+    fn check_health(
+        &self,
+        timeout: Duration,
+        callback: Option<HealthCallback>,
+    ) -> Box<dyn Future<Item = (), Error = String> + Send> {
+        let closed = self.link().inner.lock().unwrap().closed.clone();
+        let client = self.clone();
+
+        match closed {
+            Some(_) => Box::new(self.reconnect().and_then(move |()| {
+                info!(
+                    "Reconnected to json-rpc server {}",
+                    Endpoint::parse(&client.endpoint)
+                );
+                HealthCallback::notify(&callback, HealthEvent::Reconnected);
+                client.ping(timeout)
+            })),
+            // the connection is not usable, the next check reconnects
+            None => Box::new(self.ping(timeout).map_err(move |reason| {
+                client.link().inner.lock().unwrap().close(reason.clone());
+                reason
+            })),
+        }
+    }
+
+    /// Record the result of health check and report its change.
+    fn set_health(
+        &self,
+        res: Result<(), String>,
+        callback: &Option<HealthCallback>,
+    ) {
+        let event = {
+            let mut health = self.health.lock().unwrap();
+            let was_healthy = health.is_none();
+            *health = res.err();
+            match (was_healthy, health.as_ref()) {
+                (true, Some(reason)) => {
+                    warn!(
+                        "json-rpc connection to {} is unhealthy: {}",
+                        Endpoint::parse(&self.endpoint),
+                        reason
+                    );
+                    Some(HealthEvent::Unhealthy(reason.clone()))
+                }
+                (false, None) => {
+                    info!(
+                        "json-rpc connection to {} is healthy again",
+                        Endpoint::parse(&self.endpoint)
+                    );
+                    Some(HealthEvent::Healthy)
+                }
+                _ => None,
+            }
+        };
+        if let Some(event) = event {
+            HealthCallback::notify(callback, event);
+        }
+    }
+
+    /// Make all calls of the batch concurrently and return their results in
+    /// the same order as the calls. Errors of individual calls are returned
+    /// in their slots and don't fail the batch.
+    pub fn call_batch(
+        &self,
+        calls: Vec<BatchCall>,
+        options: CallOptions,
+    ) -> Box<
+        dyn Future<Item = Vec<Result<serde_json::Value, Error>>, Error = Error>
+            + Send,
+    > {
+        let results: Vec<_> = calls
+            .into_iter()
+            .map(|call| {
+                self.call_with_options::<_, serde_json::Value>(
+                    &call.method,
+                    call.params,
+                    options.clone(),
+                )
+                .then(Ok)
+            })
+            .collect();
+
+        Box::new(future::join_all(results))
+    }
+}
+
+/// Check health of the connection of the client periodically. The task ends
+/// when all clones of the client have been dropped.
+fn keep_alive(
+    client: &RpcClient,
+    interval: Duration,
+    callback: Option<HealthCallback>,
+) -> impl Future<Item = (), Error = ()> {
+    let link = Arc::downgrade(&client.link);
+    let endpoint = client.endpoint.clone();
+    let options = client.options.clone();
+    let methods = Arc::clone(&client.methods);
+    let health = Arc::clone(&client.health);
+
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(|err| error!("json-rpc keep-alive timer failed: {}", err))
+        .for_each(move |_| {
+            // the client is held only for the time of the check
+            let client = match link.upgrade() {
+                Some(link) => RpcClient {
+                    endpoint: endpoint.clone(),
+                    options: options.clone(),
+                    methods: Arc::clone(&methods),
+                    link,
+                    health: Arc::clone(&health),
+                },
+                None => return Either::A(future::err(())),
+            };
+            let callback = callback.clone();
+
+            Either::B(client.check_health(interval, callback.clone()).then(
+                move |res| {
+                    client.set_health(res, &callback);
+                    Ok(())
+                },
+            ))
+        })
+}
+
+/// Write requests to the socket in the order in which they were made. When
+/// all senders are gone, the connection is shut down, which terminates the
+/// reader too.
+fn write_requests(
+    stream: SharedStream,
+    receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    inner: Arc<Mutex<Inner>>,
+) -> impl Future<Item = (), Error = ()> {
+    let socket = Arc::clone(&stream.0);
+
+    receiver
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "channel failed"))
+        .fold(stream, |stream, request_raw| {
+            write_all(stream, request_raw).map(|(stream, _)| stream)
+        })
+        .then(move |res| {
+            if let Err(err) = res {
+                inner.lock().unwrap().close(err.to_string());
+            }
+            let _ = socket.shutdown(Shutdown::Both);
+            Ok(())
+        })
+}
+
+/// Read replies from the socket and pass them to the requests with the same
+/// id until the connection is closed.
+fn read_replies(
+    stream: SharedStream,
+    inner: Arc<Mutex<Inner>>,
+    max_response_size: Option<usize>,
+) -> impl Future<Item = (), Error = ()> {
+    let loop_inner = Arc::clone(&inner);
+
+    future::loop_fn((stream, Vec::new()), move |(stream, mut buf)| {
+        let inner = Arc::clone(&loop_inner);
+
+        read(stream, vec![0; READ_CHUNK]).and_then(
+            move |(stream, chunk, len)| {
+                if len == 0 {
+                    return Either::A(future::ok(Loop::Break(
+                        "end of stream".to_owned(),
+                    )));
+                }
+                buf.extend_from_slice(&chunk[.. len]);
+                match dispatch_replies(&mut buf, &inner) {
+                    // what is left in the buffer is an incomplete reply
+                    Ok(()) => match max_response_size {
+                        Some(limit) if buf.len() > limit => {
+                            Either::A(future::ok(Loop::Break(format!(
+                                "reply larger than {} bytes",
+                                limit
+                            ))))
+                        }
+                        _ => {
+                            Either::B(future::ok(Loop::Continue((stream, buf))))
+                        }
+                    },
+                    Err(err) => Either::A(future::ok(Loop::Break(format!(
+                        "invalid reply: {}",
+                        err
+                    )))),
+                }
+            },
+        )
+    })
+    .then(move |res| {
+        let reason = match res {
+            Ok(reason) => reason,
+            Err(err) => err.to_string(),
+        };
+        inner.lock().unwrap().close(reason);
+        Ok(())
+    })
+}
+
+/// Parse all complete replies in the buffer, hand them over to the waiting
+/// requests and remove them from the buffer. Incomplete reply at the end of
+/// the buffer is left there until more data arrives.
+fn dispatch_replies(
+    buf: &mut Vec<u8>,
+    inner: &Mutex<Inner>,
+) -> Result<(), serde_json::Error> {
+    let mut replies =
+        serde_json::Deserializer::from_slice(buf).into_iter::<Response>();
+
+    loop {
+        match replies.next() {
+            Some(Ok(reply)) => {
+                trace!("JSON response: {}", redact::redacted(&reply));
+                let id = reply_id(&reply.id);
+                let sender =
+                    id.and_then(|id| inner.lock().unwrap().pending.remove(&id));
+                match sender {
+                    // the receiver may have been dropped meanwhile
+                    Some(sender) => {
+                        let _ = sender.send(Ok(reply));
+                    }
+                    None => {
+                        warn!("Unexpected json-rpc reply with id {}", reply.id)
+                    }
+                }
+            }
+            Some(Err(ref err)) if err.is_eof() => break,
+            Some(Err(err)) => return Err(err),
+            None => break,
+        }
+    }
+    let consumed = replies.byte_offset();
+    buf.drain(.. consumed);
+    Ok(())
+}
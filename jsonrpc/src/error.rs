@@ -1,7 +1,12 @@
 //! json-rpc error enum which contains all different errors which can happen
 //! when sending request and processing reply from json-rpc server.
+//!
+//! Errors of calls are wrapped in `Error::Call` with the method and the
+//! server, so that the message says which call has failed. The error which
+//! has caused it is its `source()` and `root()` returns it for matching.
 
-use std::{convert::From, fmt, io};
+use nix::errno::Errno;
+use std::{convert::From, fmt, io, time::Duration};
 use tower_grpc::{Code, Status};
 
 #[derive(Debug, PartialEq)]
@@ -15,39 +20,212 @@ pub enum RpcCode {
     AlreadyExists,
 }
 
+impl RpcCode {
+    /// Error code on the wire. Codes which are not defined by json-rpc spec
+    /// are negative errno values as used by SPDK.
+    pub fn raw(&self) -> i32 {
+        match self {
+            RpcCode::ParseError => -32700,
+            RpcCode::InvalidRequest => -32600,
+            RpcCode::MethodNotFound => -32601,
+            RpcCode::InvalidParams => -32602,
+            RpcCode::InternalError => -32603,
+            RpcCode::NotFound => -(Errno::ENOENT as i32),
+            RpcCode::AlreadyExists => -(Errno::EEXIST as i32),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidVersion,
     InvalidReplyId,
     IoError(io::Error),
     ParseError(serde_json::Error),
-    ConnectError { sock: String, err: io::Error },
-    RpcError { code: RpcCode, msg: String },
+    ConnectError {
+        sock: String,
+        err: io::Error,
+    },
+    /// Error reply from the server. Codes which we don't know are mapped to
+    /// InternalError, the code as received and the optional data of the
+    /// error are kept as they are.
+    RpcError {
+        code: RpcCode,
+        msg: String,
+        raw_code: i32,
+        data: Option<serde_json::Value>,
+    },
+    Timeout {
+        method: String,
+        timeout: Duration,
+    },
+    ResponseTooLarge {
+        method: String,
+        limit: usize,
+    },
+    /// Response of HTTP proxy other than 200 OK.
+    HttpError {
+        status: u16,
+        reason: String,
+    },
+    /// The unix socket of the server has not appeared (see `wait_ready`).
+    SocketMissing {
+        sock: String,
+    },
+    /// The server has not answered in time (see `wait_ready`).
+    NotReady {
+        sock: String,
+        reason: String,
+    },
+    /// The process at the other end of the unix socket does not run as the
+    /// expected user (see `peercred`).
+    UntrustedPeer {
+        sock: String,
+        reason: String,
+    },
     GenericError(String),
+    /// Failure of a call of the method on the server with the error which
+    /// has caused it.
+    Call {
+        method: String,
+        sock: String,
+        source: Box<Error>,
+    },
 }
 
 impl Error {
-    /// Conversion from jsonrpc error to grpc status.
-    ///
-    /// NOTE: normally we would have a From<Error> trait for Status type, but
-    /// we can't since both Status type and From trait are external.
-    pub fn into_status(self) -> Status {
+    /// Error reply with the code on the wire matching the code and no data.
+    pub fn rpc(code: RpcCode, msg: String) -> Self {
+        Error::RpcError {
+            raw_code: code.raw(),
+            code,
+            msg,
+            data: None,
+        }
+    }
+
+    /// Wrap the error with the method and the server of the call which it
+    /// has failed (unless it has been wrapped already).
+    pub fn in_call(self, method: &str, sock: &str) -> Self {
+        match self {
+            Error::Call {
+                ..
+            } => self,
+            err => Error::Call {
+                method: method.to_owned(),
+                sock: sock.to_owned(),
+                source: Box::new(err),
+            },
+        }
+    }
+
+    /// Return the error which has caused the call to fail, or the error
+    /// itself if it is not a failed call.
+    pub fn root(&self) -> &Error {
         match self {
+            Error::Call {
+                source, ..
+            } => source.root(),
+            err => err,
+        }
+    }
+
+    /// Same as root() but taking the error.
+    pub fn into_root(self) -> Error {
+        match self {
+            Error::Call {
+                source, ..
+            } => source.into_root(),
+            err => err,
+        }
+    }
+
+    /// Return errno of the error reply if its code is a negative errno
+    /// value (i.e. EBUSY or EIO from SPDK) rather than a code from json-rpc
+    /// spec.
+    pub fn errno(&self) -> Option<Errno> {
+        match self.root() {
+            Error::RpcError {
+                raw_code, ..
+            } if *raw_code < 0 && *raw_code > -32000 => {
+                Some(Errno::from_i32(-raw_code))
+            }
+            _ => None,
+        }
+    }
+
+    /// Return gRPC code matching the error.
+    pub fn grpc_code(&self) -> Code {
+        match self.root() {
             Error::RpcError {
                 code,
-                msg,
-            } => {
-                let code = match code {
-                    RpcCode::InvalidParams => Code::InvalidArgument,
-                    RpcCode::NotFound => Code::NotFound,
-                    RpcCode::AlreadyExists => Code::AlreadyExists,
-                    _ => Code::Internal,
-                };
-                Status::new(code, msg)
+                raw_code,
+                ..
+            } => match code {
+                RpcCode::InvalidParams => Code::InvalidArgument,
+                RpcCode::NotFound => Code::NotFound,
+                RpcCode::AlreadyExists => Code::AlreadyExists,
+                // long running job in mayastor has been cancelled
+                _ if *raw_code == -(Errno::ECANCELED as i32) => Code::Cancelled,
+                _ => Code::Internal,
+            },
+            Error::Timeout {
+                ..
+            } => Code::DeadlineExceeded,
+            Error::ResponseTooLarge {
+                ..
+            } => Code::ResourceExhausted,
+            Error::HttpError {
+                status: 401, ..
+            } => Code::Unauthenticated,
+            Error::HttpError {
+                status: 403, ..
+            } => Code::PermissionDenied,
+            Error::SocketMissing {
+                ..
             }
-            _ => Status::new(Code::Internal, self.to_string()),
+            | Error::NotReady {
+                ..
+            } => Code::Unavailable,
+            Error::UntrustedPeer {
+                ..
+            } => Code::PermissionDenied,
+            _ => Code::Internal,
         }
     }
+
+    /// Message of the error for grpc status. Error replies carry just the
+    /// message from the server.
+    fn status_message(&self) -> String {
+        match self {
+            Error::RpcError {
+                msg, ..
+            } => msg.clone(),
+            Error::Call {
+                method,
+                sock,
+                source,
+            } => format!(
+                "Json-rpc call {} to {} failed: {}",
+                method,
+                sock,
+                source.status_message()
+            ),
+            err => err.to_string(),
+        }
+    }
+
+    /// Conversion from jsonrpc error to grpc status (the same as
+    /// `Status::from`).
+    pub fn into_status(self) -> Status {
+        Status::new(self.grpc_code(), self.status_message())
+    }
+}
+
+impl From<Error> for Status {
+    fn from(err: Error) -> Self {
+        err.into_status()
+    }
 }
 
 impl fmt::Display for Error {
@@ -64,8 +242,63 @@ impl fmt::Display for Error {
             Error::RpcError {
                 code,
                 msg,
-            } => write!(f, "Json-rpc error {:?}: {}", code, msg),
+                raw_code,
+                ..
+            } => {
+                if *raw_code == code.raw() {
+                    write!(f, "Json-rpc error {:?}: {}", code, msg)
+                } else {
+                    write!(
+                        f,
+                        "Json-rpc error {:?} ({}): {}",
+                        code, raw_code, msg
+                    )
+                }
+            }
+            Error::Timeout {
+                method,
+                timeout,
+            } => write!(
+                f,
+                "Json-rpc call {} timed out after {:.1}s",
+                method,
+                timeout.as_millis() as f64 / 1000.0
+            ),
+            Error::ResponseTooLarge {
+                method,
+                limit,
+            } => write!(
+                f,
+                "Reply to json-rpc call {} is larger than {} bytes",
+                method, limit
+            ),
+            Error::HttpError {
+                status,
+                reason,
+            } => write!(f, "HTTP error {} {}", status, reason),
+            Error::SocketMissing {
+                sock,
+            } => write!(f, "Json-rpc socket {} does not exist", sock),
+            Error::NotReady {
+                sock,
+                reason,
+            } => write!(f, "Json-rpc server {} is not ready: {}", sock, reason),
+            Error::UntrustedPeer {
+                sock,
+                reason,
+            } => {
+                write!(f, "Json-rpc socket {} is not trusted: {}", sock, reason)
+            }
             Error::GenericError(msg) => write!(f, "{}", msg),
+            Error::Call {
+                method,
+                sock,
+                source,
+            } => write!(
+                f,
+                "Json-rpc call {} to {} failed: {}",
+                method, sock, source
+            ),
         }
     }
 }
@@ -74,8 +307,18 @@ impl fmt::Display for Error {
 // types follow
 
 impl std::error::Error for Error {
-    fn cause(&self) -> Option<&dyn std::error::Error> {
-        None
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(err)
+            | Error::ConnectError {
+                err, ..
+            } => Some(err),
+            Error::ParseError(err) => Some(err),
+            Error::Call {
+                source, ..
+            } => Some(source.as_ref()),
+            _ => None,
+        }
     }
 }
 
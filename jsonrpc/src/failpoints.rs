@@ -0,0 +1,156 @@
+//! Injection of faults into calls for tests of error paths of callers.
+//!
+//! With the `failpoints` feature, a test can make the calls of a method (or
+//! of all methods) to a server fail in a given way: the connection is reset
+//! before the request is sent, the reply is cut off or replaced by garbage,
+//! or it comes late. The faults are injected on the client side, so the
+//! server does not have to misbehave on purpose, and they are keyed by the
+//! server, so that tests running in parallel with their own servers don't
+//! affect each other. Faults apply to one-shot calls (`call`, `call_many`,
+//! ...), not to calls over `RpcClient` connections. Without the feature no
+//! fault can be injected and the checks in the calls are no-ops.
+
+#![cfg_attr(not(feature = "failpoints"), allow(dead_code))]
+
+use crate::{error::Error, reply::RawReply};
+use futures::{future, Future};
+use std::{
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+/// What happens to the call.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// The connection is reset after it has been established and before
+    /// the request is sent.
+    ConnectionReset,
+    /// Only the first n bytes of the reply are received.
+    TruncatedReply(usize),
+    /// The reply is replaced by the bytes (i.e. invalid json).
+    Garbage(Vec<u8>),
+    /// The reply is received after the delay, which counts against the
+    /// timeout of the call.
+    Delay(Duration),
+}
+
+struct Failpoint {
+    sock: String,
+    /// None matches all methods
+    method: Option<String>,
+    fault: Fault,
+    /// how many more calls fail (None = until cleared)
+    count: Option<usize>,
+}
+
+lazy_static! {
+    static ref FAILPOINTS: Mutex<Vec<Failpoint>> = Mutex::new(Vec::new());
+}
+
+/// Make the next `count` calls of the method (any method if None) to the
+/// server fail with the fault, or all of them if count is None. Failpoints
+/// are matched in the order in which they were injected. A count of zero
+/// injects nothing.
+#[cfg(feature = "failpoints")]
+pub fn inject(
+    sock_path: &str,
+    method: Option<&str>,
+    fault: Fault,
+    count: Option<usize>,
+) {
+    if count == Some(0) {
+        return;
+    }
+    FAILPOINTS.lock().unwrap().push(Failpoint {
+        sock: sock_path.to_owned(),
+        method: method.map(String::from),
+        fault,
+        count,
+    });
+}
+
+/// Remove all failpoints of the server.
+#[cfg(feature = "failpoints")]
+pub fn clear(sock_path: &str) {
+    FAILPOINTS.lock().unwrap().retain(|fp| fp.sock != sock_path);
+}
+
+/// Return the fault of the call if there is one.
+pub(crate) fn take(sock_path: &str, method: &str) -> Option<Fault> {
+    if !cfg!(feature = "failpoints") {
+        return None;
+    }
+    let mut failpoints = FAILPOINTS.lock().unwrap();
+    let idx = failpoints.iter().position(|fp| {
+        fp.sock == sock_path
+            && fp.method.as_ref().map(|m| m == method).unwrap_or(true)
+    })?;
+    let fault = failpoints[idx].fault.clone();
+    if let Some(count) = failpoints[idx].count.as_mut() {
+        *count -= 1;
+        if *count == 0 {
+            failpoints.remove(idx);
+        }
+    }
+    debug!(
+        "Injecting {:?} into {} call to {}",
+        fault, method, sock_path
+    );
+    Some(fault)
+}
+
+/// Fail the call before the request is sent if the connection is reset.
+pub(crate) fn before_send(fault: &Option<Fault>) -> Result<(), Error> {
+    match fault {
+        Some(Fault::ConnectionReset) => Err(Error::IoError(io::Error::new(
+            io::ErrorKind::ConnectionReset,
+            "Connection reset (injected)",
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Wait before the reply is read if it is delayed.
+pub(crate) fn before_reply(
+    fault: &Option<Fault>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    match fault {
+        Some(Fault::Delay(delay)) => {
+            Box::new(Delay::new(Instant::now() + *delay).map_err(|err| {
+                Error::GenericError(format!("Timer error: {}", err))
+            }))
+        }
+        _ => Box::new(future::ok(())),
+    }
+}
+
+/// Cut off or replace the reply.
+pub(crate) fn received(
+    fault: &Option<Fault>,
+    reply: RawReply,
+) -> Result<RawReply, Error> {
+    match (fault, reply) {
+        (Some(Fault::TruncatedReply(n)), RawReply::Memory(mut buf)) => {
+            buf.truncate(*n);
+            Ok(RawReply::Memory(buf))
+        }
+        (
+            Some(Fault::TruncatedReply(n)),
+            RawReply::Spooled {
+                file,
+                len,
+            },
+        ) => {
+            let len = len.min(*n);
+            file.set_len(len as u64)?;
+            Ok(RawReply::Spooled {
+                file,
+                len,
+            })
+        }
+        (Some(Fault::Garbage(bytes)), _) => Ok(RawReply::Memory(bytes.clone())),
+        (_, reply) => Ok(reply),
+    }
+}
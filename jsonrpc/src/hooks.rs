@@ -0,0 +1,89 @@
+//! Hooks called around every json-rpc call made by the crate.
+//!
+//! Users of the crate can register hooks to observe the calls in one place
+//! instead of wrapping each call site (i.e. to log calls with their ids or
+//! to record metrics). Hooks are global and apply to `call()`, `notify()`
+//! and calls of `RpcClient` alike. For each call, `on_request` is called
+//! before the request is sent and then exactly one of `on_response` or
+//! `on_error` when the call completes. A call which is dropped before it
+//! completes is not reported.
+
+use crate::error::Error;
+use futures::Future;
+use serde_json::Value;
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+/// Interceptor of json-rpc calls. All methods have empty default
+/// implementations, so a hook implements only those it needs.
+pub trait Hook: Send + Sync {
+    /// Called before the request is sent. The parameters can be modified.
+    fn on_request(&self, _method: &str, _id: u64, _params: &mut Option<Value>) {
+    }
+
+    /// Called when the call has succeeded.
+    fn on_response(&self, _method: &str, _id: u64, _elapsed: Duration) {}
+
+    /// Called when the call has failed for any reason (connection failure,
+    /// timeout, error reply, invalid reply, ...).
+    fn on_error(
+        &self,
+        _method: &str,
+        _id: u64,
+        _elapsed: Duration,
+        _err: &Error,
+    ) {
+    }
+}
+
+lazy_static! {
+    static ref HOOKS: RwLock<Vec<Arc<dyn Hook>>> = RwLock::new(Vec::new());
+}
+
+/// Register hook for all subsequent calls. Hooks are called in the order in
+/// which they were registered.
+pub fn add_hook(hook: Arc<dyn Hook>) {
+    HOOKS.write().unwrap().push(hook);
+}
+
+/// Remove all registered hooks.
+pub fn clear_hooks() {
+    HOOKS.write().unwrap().clear();
+}
+
+/// Run on_request hooks for the call.
+pub(crate) fn before(method: &str, id: u64, params: &mut Option<Value>) {
+    for hook in HOOKS.read().unwrap().iter() {
+        hook.on_request(method, id, params);
+    }
+}
+
+/// Run on_response or on_error hooks when the call completes.
+pub(crate) fn observe<F>(
+    method: &str,
+    id: u64,
+    fut: F,
+) -> Box<dyn Future<Item = F::Item, Error = Error> + Send>
+where
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send,
+{
+    if HOOKS.read().unwrap().is_empty() {
+        return Box::new(fut);
+    }
+    let method = method.to_owned();
+    let started = Instant::now();
+
+    Box::new(fut.then(move |res| {
+        let elapsed = started.elapsed();
+        for hook in HOOKS.read().unwrap().iter() {
+            match &res {
+                Ok(_) => hook.on_response(&method, id, elapsed),
+                Err(err) => hook.on_error(&method, id, elapsed, err),
+            }
+        }
+        res
+    }))
+}
@@ -0,0 +1,197 @@
+//! json-rpc over HTTP.
+//!
+//! SPDK ships a proxy (scripts/rpc_http_proxy.py) which passes json-rpc
+//! requests POSTed to it with basic authentication to the unix domain socket
+//! of the server. Operators can deploy it instead of exposing the socket.
+//! The request is the body of a POST request and the reply is the body of
+//! the response, framed by Content-Length (or by the end of the stream if
+//! the server does not send it). There is one request per connection, so
+//! persistent connections (`RpcClient`) are not available over HTTP.
+
+use crate::{error::Error, transport::HttpEndpoint};
+use futures::future::{self, Future, Loop};
+use std::io::{self, Read};
+use tokio::io::{read, AsyncRead};
+
+/// Max size of the header of a response.
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+/// Size of the buffer for reading the header.
+const READ_CHUNK: usize = 4096;
+
+/// Wrap the json-rpc request in HTTP request.
+pub(crate) fn request(endpoint: &HttpEndpoint, body: &[u8]) -> Vec<u8> {
+    let mut header = format!(
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n",
+        endpoint.path,
+        endpoint.addr,
+        body.len()
+    );
+    if let Some((user, password)) = &endpoint.auth {
+        header.push_str(&format!(
+            "Authorization: Basic {}\r\n",
+            base64::encode(&format!("{}:{}", user, password))
+        ));
+    }
+    header.push_str("\r\n");
+
+    let mut request = header.into_bytes();
+    request.extend_from_slice(body);
+    request
+}
+
+/// Body of the response. Part of it may have been read together with the
+/// header. It ends after Content-Length bytes even if the server does not
+/// close the connection.
+pub(crate) struct Body<S> {
+    buffered: Vec<u8>,
+    pos: usize,
+    remaining: Option<usize>,
+    inner: S,
+}
+
+impl<S> Body<S> {
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Read> Read for Body<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let max = match self.remaining {
+            Some(0) => return Ok(0),
+            Some(remaining) => buf.len().min(remaining),
+            None => buf.len(),
+        };
+        let n = if self.pos < self.buffered.len() {
+            let n = max.min(self.buffered.len() - self.pos);
+            buf[.. n].copy_from_slice(&self.buffered[self.pos .. self.pos + n]);
+            self.pos += n;
+            n
+        } else {
+            self.inner.read(&mut buf[.. max])?
+        };
+        if let Some(remaining) = self.remaining.as_mut() {
+            if n == 0 && max > 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before the end of HTTP response",
+                ));
+            }
+            *remaining -= n;
+        }
+        Ok(n)
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Body<S> {}
+
+/// Return offset of the body if the buffer contains the whole header.
+fn header_end(buf: &[u8]) -> Option<usize> {
+    buf.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|pos| pos + 4)
+}
+
+/// Check the status of the response and return its Content-Length.
+fn parse_header(header: &[u8]) -> Result<Option<usize>, Error> {
+    let header = String::from_utf8_lossy(header);
+    let mut lines = header.split("\r\n");
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+
+    let status = match (parts.next(), parts.next()) {
+        (Some(version), Some(status)) if version.starts_with("HTTP/") => {
+            status.parse::<u16>().ok()
+        }
+        _ => None,
+    };
+    match status {
+        Some(200) => (),
+        Some(status) => {
+            return Err(Error::HttpError {
+                status,
+                reason: parts.next().unwrap_or("").to_owned(),
+            })
+        }
+        None => {
+            return Err(Error::GenericError(format!(
+                "Invalid HTTP status line \"{}\"",
+                status_line
+            )))
+        }
+    }
+
+    let mut length = None;
+    for line in lines {
+        let idx = match line.find(':') {
+            Some(idx) => idx,
+            None => continue,
+        };
+        let name = line[.. idx].trim();
+        let value = line[idx + 1 ..].trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            length = Some(value.parse::<usize>().map_err(|_| {
+                Error::GenericError(format!(
+                    "Invalid HTTP Content-Length \"{}\"",
+                    value
+                ))
+            })?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding")
+            && !value.eq_ignore_ascii_case("identity")
+        {
+            return Err(Error::GenericError(format!(
+                "Unsupported HTTP Transfer-Encoding \"{}\"",
+                value
+            )));
+        }
+    }
+    Ok(length)
+}
+
+/// Read the header of the response and return its body if the request has
+/// succeeded.
+pub(crate) fn read_response<S>(
+    socket: S,
+) -> Box<dyn Future<Item = Body<S>, Error = Error> + Send>
+where
+    S: AsyncRead + Send + 'static,
+{
+    Box::new(future::loop_fn(
+        (socket, Vec::new()),
+        |(socket, mut buf)| {
+            read(socket, vec![0; READ_CHUNK])
+                .map_err(Error::from)
+                .and_then(move |(socket, chunk, n)| {
+                    buf.extend_from_slice(&chunk[.. n]);
+                    if let Some(end) = header_end(&buf) {
+                        let body = buf.split_off(end);
+                        return parse_header(&buf).map(|remaining| {
+                            Loop::Break(Body {
+                                buffered: body,
+                                pos: 0,
+                                remaining,
+                                inner: socket,
+                            })
+                        });
+                    }
+                    if n == 0 {
+                        Err(Error::GenericError(
+                            "Connection closed before the end of HTTP header"
+                                .to_owned(),
+                        ))
+                    } else if buf.len() > MAX_HEADER_SIZE {
+                        Err(Error::GenericError(format!(
+                            "HTTP header is larger than {} bytes",
+                            MAX_HEADER_SIZE
+                        )))
+                    } else {
+                        Ok(Loop::Continue((socket, buf)))
+                    }
+                })
+        },
+    ))
+}
@@ -1,6 +1,35 @@
 //! json-rpc protocol over unix domain socket implementation as described
 //! in spec: https://www.jsonrpc.org/specification.
+//!
+//! The server can be reached over TCP too: wherever a socket path is
+//! expected, `host:port` address can be used instead, `tls://host:port` of
+//! a TLS proxy in front of it (see `set_tls_config`) or an URL of HTTP proxy
+//! of the server (see `Endpoint`).
+//!
+//! The number of connections to a server open at the same time is limited
+//! (see `set_max_connections`), so is the number of requests in flight (see
+//! `set_max_requests`) and the size of replies (see
+//! `set_max_response_size`).
+//!
+//! How strictly replies are checked against the spec is given by
+//! `ValidationMode` (see `set_validation_mode`).
+//!
+//! Besides the timeout, a call can have a deadline (`CallOptions::deadline`),
+//! which is meant for calls made on behalf of a caller with a deadline of its
+//! own (i.e. gRPC request). When it expires, the call is abandoned: its
+//! connection is closed and it fails with `Error::Timeout`.
+//!
+//! Bodies of requests and replies are logged at trace level with secrets
+//! redacted (see `redact`).
+//!
+//! A call of a method renamed by SPDK is retried with the other name of the
+//! method if the server does not know it (see `methods::ALIASES`).
+//!
+//! The server on unix socket can be required to run as a given user (see
+//! `peercred`), so that a socket planted by someone else is not trusted.
 
+#[macro_use]
+extern crate lazy_static;
 extern crate nix;
 extern crate serde;
 #[macro_use]
@@ -9,18 +38,253 @@ extern crate serde_json;
 #[macro_use]
 extern crate log;
 
+pub mod blocking;
+mod client;
 pub mod error;
+#[cfg(feature = "failpoints")]
+pub mod failpoints;
+#[cfg(not(feature = "failpoints"))]
+mod failpoints;
+pub mod hooks;
+mod http;
+pub mod methods;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+mod params;
+pub mod peercred;
+mod pool;
+mod ready;
+pub mod redact;
+mod reply;
+mod retry;
+mod server;
+pub mod spdk_methods;
 #[cfg(test)]
 mod test;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
+mod tls;
+mod transport;
+
+pub use client::{BatchCall, HealthEvent, RpcClient, RpcClientBuilder};
+pub use pool::{
+    connections,
+    requests,
+    set_max_connections,
+    set_max_requests,
+    DEFAULT_MAX_CONNECTIONS,
+    DEFAULT_MAX_REQUESTS,
+};
+pub use ready::wait_ready;
+pub use retry::RetryPolicy;
+pub use server::Server;
+pub use tls::{set_tls_config, TlsConfig};
+pub use transport::{Endpoint, HttpEndpoint};
 
 use self::error::{Error, RpcCode};
-use futures::future::{self, Future};
+use futures::{
+    future::{self, Either, Future},
+    stream,
+    Stream,
+};
 use nix::errno::Errno;
-use std::{boxed::Box, io, net::Shutdown};
-use tokio::{
-    io::{read_to_end, write_all},
-    net::UnixStream,
+use std::{
+    boxed::Box,
+    net::Shutdown,
+    str::FromStr,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::{Duration, Instant},
 };
+use tokio::{io::write_all, timer::Timeout};
+
+/// Timeout of json-rpc calls unless changed by set_default_timeout().
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Current default timeout in milliseconds (zero means no timeout).
+static DEFAULT_TIMEOUT_MS: AtomicU64 = AtomicU64::new(60_000);
+
+/// Max size of a reply unless changed by set_max_response_size().
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Current max size of a reply in bytes (zero means no limit).
+static MAX_RESPONSE_SIZE: AtomicUsize =
+    AtomicUsize::new(DEFAULT_MAX_RESPONSE_SIZE);
+
+/// Current validation mode of replies (see ValidationMode::to_raw()).
+static VALIDATION_MODE: AtomicUsize = AtomicUsize::new(1);
+
+/// Id of the next request made by call(). Ids are unique within the process,
+/// so that a reply to one request can't be mistaken for a reply to another.
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Change the timeout used by calls which don't specify their own. None
+/// means that the calls wait for the reply forever.
+pub fn set_default_timeout(timeout: Option<Duration>) {
+    let ms = timeout.map(|t| t.as_millis() as u64).unwrap_or(0);
+    DEFAULT_TIMEOUT_MS.store(ms, Ordering::Relaxed);
+}
+
+/// Change the max size of a reply used by calls which don't specify their
+/// own. Zero means no limit.
+pub fn set_max_response_size(size: usize) {
+    MAX_RESPONSE_SIZE.store(size, Ordering::Relaxed);
+}
+
+/// How strictly replies are checked against the json-rpc spec.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValidationMode {
+    /// The version must be "2.0" and the id must be the number which we
+    /// have sent in the request (for CI and debugging of servers).
+    Strict,
+    /// The version may be missing and the id may be the number in a string
+    /// (default).
+    Standard,
+    /// The version is not checked and a reply without id is accepted, as
+    /// sent by some older SPDK versions. A reply with id must still match
+    /// the request. Replies on persistent connections (`RpcClient`) can't
+    /// do without id, since they are matched to the requests by it.
+    Lenient,
+}
+
+impl ValidationMode {
+    fn to_raw(self) -> usize {
+        match self {
+            ValidationMode::Strict => 0,
+            ValidationMode::Standard => 1,
+            ValidationMode::Lenient => 2,
+        }
+    }
+
+    fn from_raw(raw: usize) -> Self {
+        match raw {
+            0 => ValidationMode::Strict,
+            2 => ValidationMode::Lenient,
+            _ => ValidationMode::Standard,
+        }
+    }
+}
+
+impl Default for ValidationMode {
+    fn default() -> Self {
+        ValidationMode::from_raw(VALIDATION_MODE.load(Ordering::Relaxed))
+    }
+}
+
+impl FromStr for ValidationMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ValidationMode::Strict),
+            "standard" => Ok(ValidationMode::Standard),
+            "lenient" => Ok(ValidationMode::Lenient),
+            _ => Err(format!("Invalid validation mode \"{}\"", s)),
+        }
+    }
+}
+
+/// Change the validation mode used by calls which don't specify their own.
+pub fn set_validation_mode(mode: ValidationMode) {
+    VALIDATION_MODE.store(mode.to_raw(), Ordering::Relaxed);
+}
+
+/// Return the max size of a reply for the persistent connections (None is
+/// unlimited).
+fn max_response_size() -> Option<usize> {
+    match MAX_RESPONSE_SIZE.load(Ordering::Relaxed) {
+        0 => None,
+        size => Some(size),
+    }
+}
+
+/// Options of a json-rpc call.
+#[derive(Clone, Debug)]
+pub struct CallOptions {
+    /// Max time to wait for the reply (None is forever).
+    pub timeout: Option<Duration>,
+    /// Retrying of transient connection failures.
+    pub retry: RetryPolicy,
+    /// Max size of the reply in bytes (None is unlimited).
+    pub max_response_size: Option<usize>,
+    /// Stream the reply through a temporary file instead of reading it to
+    /// memory. It is meant for methods with large replies, which are then
+    /// not limited by max_response_size.
+    pub stream_reply: bool,
+    /// How strictly the reply is checked.
+    pub validation: ValidationMode,
+    /// Point in time after which the reply is of no use to the caller. The
+    /// call waits for the sooner of the timeout and the deadline.
+    pub deadline: Option<Instant>,
+    /// Send params as an array of values instead of an object with named
+    /// values (see `params`).
+    pub positional_params: bool,
+}
+
+impl Default for CallOptions {
+    fn default() -> Self {
+        let ms = DEFAULT_TIMEOUT_MS.load(Ordering::Relaxed);
+        Self {
+            timeout: if ms == 0 {
+                None
+            } else {
+                Some(Duration::from_millis(ms))
+            },
+            retry: RetryPolicy::default(),
+            max_response_size: max_response_size(),
+            stream_reply: false,
+            validation: ValidationMode::default(),
+            deadline: None,
+            positional_params: false,
+        }
+    }
+}
+
+impl CallOptions {
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn no_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    pub fn max_response_size(mut self, size: usize) -> Self {
+        self.max_response_size = Some(size);
+        self
+    }
+
+    pub fn no_response_limit(mut self) -> Self {
+        self.max_response_size = None;
+        self
+    }
+
+    pub fn stream_reply(mut self) -> Self {
+        self.stream_reply = true;
+        self
+    }
+
+    pub fn validation(mut self, mode: ValidationMode) -> Self {
+        self.validation = mode;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub fn positional_params(mut self) -> Self {
+        self.positional_params = true;
+        self
+    }
+}
 #[derive(Debug, Serialize, Deserialize)]
 /// A JSONRPC request object
 pub struct Request<'a> {
@@ -35,17 +299,35 @@ pub struct Request<'a> {
     pub jsonrpc: Option<&'a str>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+/// A JSONRPC notification object (a request without id)
+pub struct Notification<'a> {
+    /// The name of the RPC call
+    pub method: &'a str,
+    /// Parameters to the RPC call
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<serde_json::Value>,
+    /// jsonrpc field, MUST be "2.0"
+    pub jsonrpc: Option<&'a str>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 /// A JSONRPC response object
 pub struct Response {
     /// A result if there is one, or null
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub result: Option<serde_json::Value>,
     /// An error if there is one, or null
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<RpcError>,
     /// Identifier for this Request, which should match that of the request
+    #[serde(default)]
     pub id: serde_json::Value,
     /// jsonrpc field, MUST be "2.0"
     pub jsonrpc: Option<String>,
+    /// Fields which are not defined by the spec (returned by some servers)
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -60,6 +342,7 @@ pub struct RpcError {
 }
 
 /// Make json-rpc request and parse reply and return user data to caller.
+/// The call fails if the reply does not arrive within the default timeout.
 pub fn call<A, R>(
     sock_path: &str,
     method: &str,
@@ -69,114 +352,473 @@ where
     A: serde::ser::Serialize,
     R: 'static + serde::de::DeserializeOwned + Send,
 {
-    let params = match args {
-        Some(val) => Some(serde_json::to_value(val).unwrap()),
-        None => None,
-    };
+    call_with_options(sock_path, method, args, CallOptions::default())
+}
+
+/// Same as call() but with options controlling the call.
+pub fn call_with_options<A, R>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+    options: CallOptions,
+) -> Box<dyn Future<Item = R, Error = Error> + Send>
+where
+    A: serde::ser::Serialize,
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    let params = params::serialize(args, options.positional_params);
+    let sock = sock_path.to_owned();
+    let method_name = method.to_owned();
+    let endpoint = Endpoint::parse(sock_path).to_string();
+    Box::new(
+        methods::with_alias(sock_path, method, move |name| {
+            call_params(&sock, name, params.clone(), options.clone())
+        })
+        .map_err(move |err| err.in_call(&method_name, &endpoint)),
+    )
+}
+
+/// Same as call_with_options() but return the whole reply (result, error,
+/// id, version and any non-standard fields) as the server has sent it.
+/// Error in the reply is not turned into `Error::RpcError` and neither the
+/// version nor the result is checked (only the id), so that diagnostic
+/// tools can show exactly what the server has returned. The method is
+/// called by the given name, without trying its alias.
+pub fn call_response<A>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+    options: CallOptions,
+) -> Box<dyn Future<Item = Response, Error = Error> + Send>
+where
+    A: serde::ser::Serialize,
+{
+    let params = params::serialize(args, options.positional_params);
+    let method_name = method.to_owned();
+    let endpoint = Endpoint::parse(sock_path).to_string();
+    Box::new(
+        call_params_with(
+            sock_path,
+            method,
+            params,
+            options,
+            reply::parse_raw_response,
+        )
+        .map_err(move |err| err.in_call(&method_name, &endpoint)),
+    )
+}
+
+/// Make the call with serialized parameters.
+fn call_params<R>(
+    sock_path: &str,
+    method: &str,
+    params: Option<serde_json::Value>,
+    options: CallOptions,
+) -> Box<dyn Future<Item = R, Error = Error> + Send>
+where
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    call_params_with(
+        sock_path,
+        method,
+        params,
+        options,
+        reply::parse_raw_reply::<R>,
+    )
+}
+
+/// Make the call with serialized parameters and parse the reply by `parse`.
+fn call_params_with<R, P>(
+    sock_path: &str,
+    method: &str,
+    mut params: Option<serde_json::Value>,
+    options: CallOptions,
+    parse: P,
+) -> Box<dyn Future<Item = R, Error = Error> + Send>
+where
+    R: 'static + Send,
+    P: FnOnce(reply::RawReply, u64, ValidationMode) -> Result<R, Error>
+        + Send
+        + 'static,
+{
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    hooks::before(method, id, &mut params);
     let request = Request {
         method,
         params,
-        id: From::from(0),
+        id: From::from(id),
         jsonrpc: Some("2.0"),
     };
-    let request_raw = serde_json::to_vec(&request).unwrap();
-    let sock = sock_path.to_string();
+    trace!("JSON request: {}", redact::redacted(&request));
+    let mut request_raw = serde_json::to_vec(&request).unwrap();
+    let http = match Endpoint::parse(sock_path) {
+        Endpoint::Http(endpoint) => {
+            request_raw = http::request(&endpoint, &request_raw);
+            true
+        }
+        _ => false,
+    };
 
     // We cannot send data, close connection and read data until connection
     // closed, which would be the easist way. There is a bug in SPDK when
     // write half of the connection can't be closed until the whole reply is
     // read from the server (see https://github.com/spdk/spdk/issues/604).
     // Hence we need to adopt more complex way of reading the data from the
-    // server in loop, trying to feed them to parser until we succeed or
-    // connection is closed.
-    let f = UnixStream::connect(sock_path)
-        .and_then(|socket| {
-            trace!("JSON request: {}", String::from_utf8_lossy(&request_raw));
-            write_all(socket, request_raw)
+    // server in loop until a complete json document has been received or
+    // connection is closed. Over HTTP the reply is the body of the response
+    // and the write half is left open, since proxies may take its shutdown
+    // for abort of the request.
+    let sock = sock_path.to_owned();
+    let retry = options.retry;
+    let max_response_size = options.max_response_size;
+    let stream_reply = options.stream_reply;
+    let validation = options.validation;
+    let method_name = method.to_owned();
+    let fault = failpoints::take(sock_path, method);
+    let f = pool::acquire(sock_path)
+        .and_then(move |slot| {
+            pool::acquire_request(&sock).and_then(move |permit| {
+                retry::connect(&sock, retry)
+                    .map(|socket| ((slot, permit), socket))
+            })
         })
-        .and_then(|(socket, _request)| {
-            // XXX is unwrap safe?
-            socket.shutdown(Shutdown::Write).unwrap();
-            read_to_end(socket, Vec::new())
-        })
-        // map io error to jsonrpc error
-        .map_err(move |err| match err.kind() {
-            io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
-                Error::ConnectError {
-                    sock,
-                    err,
-                }
-            }
-            _ => err.into(),
+        .and_then(move |(slots, socket)| {
+            let sent = failpoints::before_send(&fault);
+            future::result(sent)
+                .and_then(move |_| {
+                    write_all(socket, request_raw)
+                        .and_then(move |(socket, _request)| {
+                            if http {
+                                return Ok(socket);
+                            }
+                            // fails if the server has closed the connection
+                            // already
+                            socket.shutdown(Shutdown::Write).map(|_| socket)
+                        })
+                        .map_err(Error::from)
+                })
+                .and_then(move |socket| {
+                    failpoints::before_reply(&fault).map(|_| (socket, fault))
+                })
+                .and_then(move |(socket, fault)| {
+                    let read = if !http {
+                        Either::A(reply::read_reply(
+                            socket,
+                            &method_name,
+                            max_response_size,
+                            stream_reply,
+                        ))
+                    } else {
+                        Either::B(http::read_response(socket).and_then(
+                            move |body| {
+                                reply::read_reply(
+                                    body,
+                                    &method_name,
+                                    max_response_size,
+                                    stream_reply,
+                                )
+                                .map(|(body, reply)| (body.into_inner(), reply))
+                            },
+                        ))
+                    };
+                    read.and_then(move |(socket, reply)| {
+                        failpoints::received(&fault, reply)
+                            .map(|reply| (socket, reply))
+                    })
+                })
+                .map(|res| (slots, res))
         })
-        .and_then(|(socket, reply_raw)| {
-            // XXX is unwrap safe?
-            socket.shutdown(Shutdown::Read).unwrap();
-            match parse_reply::<R>(&reply_raw) {
+        .and_then(move |(slots, (socket, reply_raw))| {
+            // TCP socket closed by the peer is not connected anymore and
+            // the shutdown fails, which does not matter at this point
+            let _ = socket.shutdown(Shutdown::Read);
+            drop(socket);
+            drop(slots);
+            match parse(reply_raw, id, validation) {
                 Ok(val) => future::ok(val),
                 Err(err) => future::err(err),
             }
         });
 
-    Box::new(f)
+    hooks::observe(
+        method,
+        id,
+        with_timeout(f, method, options.timeout, options.deadline),
+    )
+}
+
+/// Fail the future with timeout error if it does not complete in time,
+/// which is the sooner of the timeout and the deadline. If the deadline has
+/// passed already, the future is not polled at all (nothing is sent).
+fn with_timeout<F>(
+    fut: F,
+    method: &str,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Box<dyn Future<Item = F::Item, Error = Error> + Send>
+where
+    F: Future<Error = Error> + Send + 'static,
+    F::Item: Send,
+{
+    let left = deadline.map(|deadline| {
+        let now = Instant::now();
+        if deadline > now {
+            deadline - now
+        } else {
+            Duration::from_secs(0)
+        }
+    });
+    let timeout = match (timeout, left) {
+        (Some(timeout), Some(left)) => timeout.min(left),
+        (Some(timeout), None) => timeout,
+        (None, Some(left)) => left,
+        (None, None) => return Box::new(fut),
+    };
+    let method = method.to_owned();
+    if timeout == Duration::from_secs(0) {
+        return Box::new(future::err(Error::Timeout {
+            method,
+            timeout,
+        }));
+    }
+
+    Box::new(Timeout::new(fut, timeout).map_err(move |err| {
+        if err.is_elapsed() {
+            Error::Timeout {
+                method,
+                timeout,
+            }
+        } else if err.is_inner() {
+            err.into_inner().unwrap()
+        } else {
+            Error::GenericError(format!("Timer error in {}: {}", method, err))
+        }
+    }))
+}
+
+/// Make a batch of calls over one connection and return results of the
+/// calls in the same order as the calls. Only a failure to connect fails the
+/// whole batch (none of the calls has been made then). Otherwise each call
+/// succeeds or fails on its own.
+pub fn call_batch(
+    sock_path: &str,
+    calls: Vec<BatchCall>,
+    options: CallOptions,
+) -> Box<
+    dyn Future<Item = Vec<Result<serde_json::Value, Error>>, Error = Error>
+        + Send,
+> {
+    Box::new(
+        RpcClient::builder()
+            .socket(sock_path)
+            .retry(options.retry.clone())
+            .build()
+            .and_then(move |client| client.call_batch(calls, options)),
+    )
+}
+
+/// Call the method once for each of the arguments, at most `concurrency`
+/// calls at a time, and return results of the calls in the same order as
+/// the arguments. Each call succeeds or fails on its own, so the future
+/// itself does not fail. The calls count against the limits of connections
+/// and requests in flight to the server like any other calls.
+pub fn call_many<A, R>(
+    sock_path: &str,
+    method: &str,
+    args: Vec<A>,
+    concurrency: usize,
+    options: CallOptions,
+) -> Box<dyn Future<Item = Vec<Result<R, Error>>, Error = Error> + Send>
+where
+    A: serde::ser::Serialize + Send + 'static,
+    R: 'static + serde::de::DeserializeOwned + Send,
+{
+    let sock = sock_path.to_owned();
+    let method = method.to_owned();
+
+    Box::new(
+        stream::iter_ok::<_, Error>(args)
+            .map(move |args| {
+                call_with_options(&sock, &method, Some(args), options.clone())
+                    .then(Ok)
+            })
+            .buffered(concurrency.max(1))
+            .collect(),
+    )
+}
+
+/// Send json-rpc notification. The server must not reply to a notification,
+/// so the future completes as soon as the notification has been sent.
+pub fn notify<A>(
+    sock_path: &str,
+    method: &str,
+    args: Option<A>,
+) -> Box<dyn Future<Item = (), Error = Error> + Send>
+where
+    A: serde::ser::Serialize,
+{
+    let mut params = match args {
+        Some(val) => Some(serde_json::to_value(val).unwrap()),
+        None => None,
+    };
+    // notifications don't have an id, this one is just for the hooks
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    hooks::before(method, id, &mut params);
+    let notification = Notification {
+        method,
+        params,
+        jsonrpc: Some("2.0"),
+    };
+    trace!("JSON notification: {}", redact::redacted(&notification));
+    let mut notification_raw = serde_json::to_vec(&notification).unwrap();
+    if let Endpoint::Http(endpoint) = Endpoint::parse(sock_path) {
+        notification_raw = http::request(&endpoint, &notification_raw);
+    }
+
+    let sock = sock_path.to_owned();
+    let f = pool::acquire(sock_path)
+        .and_then(move |slot| {
+            pool::acquire_request(&sock).and_then(move |permit| {
+                retry::connect(&sock, RetryPolicy::default())
+                    .map(|socket| ((slot, permit), socket))
+            })
+        })
+        .and_then(|(slots, socket)| {
+            write_all(socket, notification_raw)
+                .map(|res| (slots, res))
+                .map_err(Error::from)
+        })
+        .map(|(slots, (socket, _notification))| {
+            // nothing is coming back, the server may have closed the
+            // connection already
+            let _ = socket.shutdown(Shutdown::Both);
+            drop(socket);
+            drop(slots);
+        });
+
+    let method_name = method.to_owned();
+    let endpoint = Endpoint::parse(sock_path).to_string();
+    Box::new(
+        hooks::observe(method, id, f)
+            .map_err(move |err| err.in_call(&method_name, &endpoint)),
+    )
 }
 
-/// Parse json-rpc reply (defined by spec) and return user data embedded in
-/// the reply.
-fn parse_reply<T>(reply_raw: &[u8]) -> Result<T, Error>
+/// Parse json-rpc reply (defined by spec) to the request with given id and
+/// return user data embedded in the reply.
+fn parse_reply<T>(
+    reply_raw: &[u8],
+    id: u64,
+    mode: ValidationMode,
+) -> Result<T, Error>
 where
     T: serde::de::DeserializeOwned,
 {
-    trace!("JSON response: {}", String::from_utf8_lossy(reply_raw));
+    trace!("JSON response: {}", redact::redacted_raw(reply_raw));
 
     match serde_json::from_slice::<Response>(reply_raw) {
         Ok(reply) => {
-            if let Some(vers) = reply.jsonrpc {
-                if vers != "2.0" {
-                    return Err(Error::InvalidVersion);
+            check_reply_id(&reply.id, id, mode)?;
+            reply_result(reply, mode)
+        }
+        Err(err) => Err(Error::ParseError(err)),
+    }
+}
+
+/// Return id of the reply as the number which we have sent in the request.
+/// The spec allows string ids too and some proxies rewrite numeric ids to
+/// strings, so a string with the number is accepted as well.
+fn reply_id(id: &serde_json::Value) -> Option<u64> {
+    match id {
+        serde_json::Value::String(id) => id.parse().ok(),
+        id => id.as_u64(),
+    }
+}
+
+/// Check that the reply is a reply to the request with given id.
+fn check_reply_id(
+    reply: &serde_json::Value,
+    id: u64,
+    mode: ValidationMode,
+) -> Result<(), Error> {
+    let matches = match mode {
+        ValidationMode::Strict => reply.as_u64() == Some(id),
+        ValidationMode::Standard => reply_id(reply) == Some(id),
+        ValidationMode::Lenient => {
+            reply.is_null() || reply_id(reply) == Some(id)
+        }
+    };
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::InvalidReplyId)
+    }
+}
+
+/// Check the json-rpc version of the reply.
+fn check_version(
+    version: &Option<String>,
+    mode: ValidationMode,
+) -> Result<(), Error> {
+    match (version, mode) {
+        (_, ValidationMode::Lenient) => Ok(()),
+        (None, ValidationMode::Strict) => Err(Error::InvalidVersion),
+        (Some(vers), _) if vers != "2.0" => Err(Error::InvalidVersion),
+        _ => Ok(()),
+    }
+}
+
+/// Convert the error in the reply to json-rpc error.
+fn rpc_error(err: RpcError) -> Error {
+    Error::RpcError {
+        raw_code: err.code,
+        code: match err.code {
+            -32700 => RpcCode::ParseError,
+            -32600 => RpcCode::InvalidRequest,
+            -32601 => RpcCode::MethodNotFound,
+            -32602 => RpcCode::InvalidParams,
+            -32603 => RpcCode::InternalError,
+            val => {
+                if val == -(Errno::ENOENT as i32) {
+                    RpcCode::NotFound
+                } else if val == -(Errno::EEXIST as i32) {
+                    RpcCode::AlreadyExists
+                } else {
+                    error!("Unknown json-rpc error code {}", val);
+                    RpcCode::InternalError
                 }
             }
-            if !reply.id.is_number() || reply.id.as_i64().unwrap() != 0 {
-                return Err(Error::InvalidReplyId);
-            }
+        },
+        msg: err.message,
+        data: err.data,
+    }
+}
 
-            if let Some(err) = reply.error {
-                Err(Error::RpcError {
-                    code: match err.code {
-                        -32700 => RpcCode::ParseError,
-                        -32600 => RpcCode::InvalidRequest,
-                        -32601 => RpcCode::MethodNotFound,
-                        -32602 => RpcCode::InvalidParams,
-                        -32603 => RpcCode::InternalError,
-                        val => {
-                            if val == -(Errno::ENOENT as i32) {
-                                RpcCode::NotFound
-                            } else if val == -(Errno::EEXIST as i32) {
-                                RpcCode::AlreadyExists
-                            } else {
-                                error!("Unknown json-rpc error code {}", val);
-                                RpcCode::InternalError
-                            }
-                        }
-                    },
-                    msg: err.message.clone(),
-                })
-            } else {
-                match reply.result {
-                    Some(result) => match serde_json::from_value::<T>(result) {
-                        Ok(val) => Ok(val),
-                        Err(err) => Err(Error::ParseError(err)),
-                    },
-                    // if there is no result fabricate null value == ()
-                    None => match serde_json::from_value::<T>(
-                        serde_json::value::Value::Null,
-                    ) {
-                        Ok(val) => Ok(val),
-                        Err(err) => Err(Error::ParseError(err)),
-                    },
+/// Return user data from the reply or convert the error in the reply to
+/// json-rpc error.
+fn reply_result<T>(reply: Response, mode: ValidationMode) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    check_version(&reply.jsonrpc, mode)?;
+
+    if let Some(err) = reply.error {
+        Err(rpc_error(err))
+    } else {
+        match reply.result {
+            Some(result) => match serde_json::from_value::<T>(result) {
+                Ok(val) => Ok(val),
+                Err(err) => Err(Error::ParseError(err)),
+            },
+            // if there is no result fabricate null value == ()
+            None => {
+                match serde_json::from_value::<T>(
+                    serde_json::value::Value::Null,
+                ) {
+                    Ok(val) => Ok(val),
+                    Err(err) => Err(Error::ParseError(err)),
                 }
             }
         }
-        Err(err) => Err(Error::ParseError(err)),
     }
 }
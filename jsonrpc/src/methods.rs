@@ -0,0 +1,194 @@
+//! Feature detection by methods supported by the server.
+//!
+//! SPDK renames its methods from time to time (i.e. `construct_nvme_bdev`
+//! became `bdev_nvme_attach_controller`) and keeps the old names only for a
+//! while, so a client working with more than one version of SPDK must pick
+//! the name which the server knows. The methods of a server are queried by
+//! `rpc_get_methods` the first time they are needed and cached by the
+//! address of the server (`RpcClient` caches them for its connection). The
+//! cache should be cleared by `forget_methods` when the server restarts,
+//! since it may have been upgraded.
+//!
+//! Methods renamed by SPDK 19.10 are called by either name without picking
+//! it: if the server does not know the method, the call is transparently
+//! retried with its alias (see `ALIASES`). The name which has worked is
+//! remembered for the server, so that the next calls use it right away.
+
+use crate::{
+    call,
+    error::{Error, RpcCode},
+};
+use futures::future::{self, Either, Future};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Methods renamed by SPDK as (old name, new name).
+pub const ALIASES: &[(&str, &str)] = &[
+    ("construct_aio_bdev", "bdev_aio_create"),
+    ("delete_aio_bdev", "bdev_aio_delete"),
+    ("construct_iscsi_bdev", "bdev_iscsi_create"),
+    ("delete_iscsi_bdev", "bdev_iscsi_delete"),
+    ("construct_malloc_bdev", "bdev_malloc_create"),
+    ("delete_malloc_bdev", "bdev_malloc_delete"),
+    ("construct_nvme_bdev", "bdev_nvme_attach_controller"),
+    ("delete_nvme_controller", "bdev_nvme_detach_controller"),
+    ("get_bdevs", "bdev_get_bdevs"),
+    ("get_bdevs_iostat", "bdev_get_iostat"),
+    ("construct_lvol_store", "bdev_lvol_create_lvstore"),
+    ("get_lvol_stores", "bdev_lvol_get_lvstores"),
+    ("construct_lvol_bdev", "bdev_lvol_create"),
+    ("destroy_lvol_bdev", "bdev_lvol_delete"),
+    ("start_nbd_disk", "nbd_start_disk"),
+    ("stop_nbd_disk", "nbd_stop_disk"),
+    ("get_nbd_disks", "nbd_get_disks"),
+    (
+        "construct_vhost_blk_controller",
+        "vhost_create_blk_controller",
+    ),
+    ("remove_vhost_controller", "vhost_delete_controller"),
+    ("get_vhost_controllers", "vhost_get_controllers"),
+    ("get_subsystems", "framework_get_subsystems"),
+    ("start_subsystem_init", "framework_start_init"),
+    ("get_spdk_version", "spdk_get_version"),
+    ("kill_instance", "spdk_kill_instance"),
+];
+
+lazy_static! {
+    /// methods of the servers by their address
+    static ref CACHE: Mutex<HashMap<String, Arc<Methods>>> =
+        Mutex::new(HashMap::new());
+    /// name of the method known to the server by (address, method)
+    static ref RENAMED: Mutex<HashMap<(String, String), String>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Return the other name of a renamed method (either old or new one).
+pub fn alias(method: &str) -> Option<&'static str> {
+    ALIASES.iter().find_map(|(old, new)| {
+        if *old == method {
+            Some(*new)
+        } else if *new == method {
+            Some(*old)
+        } else {
+            None
+        }
+    })
+}
+
+/// Make the call by `make` with the name of the method known to the server
+/// and retry it with the alias of the method if the server does not know
+/// the method.
+pub(crate) fn with_alias<R, F>(
+    sock_path: &str,
+    method: &str,
+    make: F,
+) -> Box<dyn Future<Item = R, Error = Error> + Send>
+where
+    R: Send + 'static,
+    F: Fn(&str) -> Box<dyn Future<Item = R, Error = Error> + Send>
+        + Send
+        + 'static,
+{
+    let key = (sock_path.to_owned(), method.to_owned());
+    let name = RENAMED
+        .lock()
+        .unwrap()
+        .get(&key)
+        .cloned()
+        .unwrap_or_else(|| method.to_owned());
+    let other = match alias(&name) {
+        Some(other) => other,
+        None => return make(&name),
+    };
+
+    Box::new(make(&name).or_else(move |err| match err {
+        Error::RpcError {
+            code: RpcCode::MethodNotFound,
+            ..
+        } => {
+            debug!(
+                "Method {} not found on {}, calling {} instead",
+                name, key.0, other
+            );
+            Either::A(make(other).map(move |res| {
+                RENAMED.lock().unwrap().insert(key, other.to_owned());
+                res
+            }))
+        }
+        err => Either::B(future::err(err)),
+    }))
+}
+
+/// Set of methods supported by a server.
+#[derive(Clone, Debug, Default)]
+pub struct Methods(HashSet<String>);
+
+impl Methods {
+    pub fn new<I>(names: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Methods(names.into_iter().collect())
+    }
+
+    /// Return true if the server supports the method.
+    pub fn supports(&self, method: &str) -> bool {
+        self.0.contains(method)
+    }
+
+    /// Return the first method from the candidates (most preferred first)
+    /// which is supported by the server.
+    pub fn pick<'a>(&self, candidates: &[&'a str]) -> Option<&'a str> {
+        candidates.iter().find(|m| self.supports(m)).cloned()
+    }
+
+    /// Same as pick() but fails if none of the candidates is supported.
+    pub fn pick_or_err<'a>(
+        &self,
+        candidates: &[&'a str],
+    ) -> Result<&'a str, Error> {
+        self.pick(candidates).ok_or_else(|| {
+            Error::GenericError(format!(
+                "None of the methods {} is supported by the server",
+                candidates.join(", ")
+            ))
+        })
+    }
+}
+
+/// Query the methods of the server unless they are cached.
+pub fn get_methods(
+    sock_path: &str,
+) -> Box<dyn Future<Item = Arc<Methods>, Error = Error> + Send> {
+    if let Some(methods) = CACHE.lock().unwrap().get(sock_path) {
+        return Box::new(future::ok(Arc::clone(methods)));
+    }
+    let sock = sock_path.to_owned();
+
+    Box::new(
+        call::<(), Vec<String>>(sock_path, "rpc_get_methods", None).map(
+            move |names| {
+                let methods = Arc::new(Methods::new(names));
+                debug!(
+                    "Json-rpc server {} supports {} methods",
+                    sock,
+                    methods.0.len()
+                );
+                CACHE.lock().unwrap().insert(sock, Arc::clone(&methods));
+                methods
+            },
+        ),
+    )
+}
+
+/// Drop the cached methods of the server and the names of renamed methods
+/// which have worked with it.
+pub fn forget_methods(sock_path: &str) {
+    CACHE.lock().unwrap().remove(sock_path);
+    RENAMED
+        .lock()
+        .unwrap()
+        .retain(|(sock, _), _| sock != sock_path);
+}
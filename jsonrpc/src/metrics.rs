@@ -0,0 +1,131 @@
+//! Prometheus metrics of json-rpc calls (`metrics` feature).
+//!
+//! `register()` creates the metrics in the given prometheus registry and
+//! installs a hook which updates them when a call completes. Calls are
+//! counted by method, errors by method and code and the duration of calls
+//! is recorded in a histogram by method. The code of an error is the name
+//! of the `RpcCode` if the server has replied with an error, otherwise it
+//! says what has gone wrong on our side (Timeout, ConnectError, ...).
+
+use crate::{
+    error::{Error, RpcCode},
+    hooks::{self, Hook},
+};
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry};
+use std::{sync::Arc, time::Duration};
+
+/// Buckets of the latency histogram in seconds. Most calls take a few
+/// milliseconds, but creating a pool or a nexus can take seconds.
+const LATENCY_BUCKETS: [f64; 14] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+    5.0, 10.0,
+];
+
+/// Label of the error for the error counter.
+fn error_code(err: &Error) -> &'static str {
+    match err.root() {
+        Error::RpcError {
+            code, ..
+        } => match code {
+            RpcCode::ParseError => "ParseError",
+            RpcCode::InvalidRequest => "InvalidRequest",
+            RpcCode::MethodNotFound => "MethodNotFound",
+            RpcCode::InvalidParams => "InvalidParams",
+            RpcCode::InternalError => "InternalError",
+            RpcCode::NotFound => "NotFound",
+            RpcCode::AlreadyExists => "AlreadyExists",
+        },
+        Error::Timeout {
+            ..
+        } => "Timeout",
+        Error::ConnectError {
+            ..
+        } => "ConnectError",
+        Error::IoError(_) => "IoError",
+        Error::ResponseTooLarge {
+            ..
+        } => "ResponseTooLarge",
+        Error::HttpError {
+            ..
+        } => "HttpError",
+        Error::SocketMissing {
+            ..
+        }
+        | Error::NotReady {
+            ..
+        } => "NotReady",
+        Error::UntrustedPeer {
+            ..
+        } => "UntrustedPeer",
+        Error::InvalidVersion
+        | Error::InvalidReplyId
+        | Error::ParseError(_) => "InvalidReply",
+        Error::GenericError(_)
+        | Error::Call {
+            ..
+        } => "GenericError",
+    }
+}
+
+struct MetricsHook {
+    calls: IntCounterVec,
+    errors: IntCounterVec,
+    latency: HistogramVec,
+}
+
+impl MetricsHook {
+    fn record(&self, method: &str, elapsed: Duration) {
+        self.calls.with_label_values(&[method]).inc();
+        self.latency.with_label_values(&[method]).observe(
+            elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9,
+        );
+    }
+}
+
+impl Hook for MetricsHook {
+    fn on_response(&self, method: &str, _id: u64, elapsed: Duration) {
+        self.record(method, elapsed);
+    }
+
+    fn on_error(&self, method: &str, _id: u64, elapsed: Duration, err: &Error) {
+        self.record(method, elapsed);
+        self.errors
+            .with_label_values(&[method, error_code(err)])
+            .inc();
+    }
+}
+
+/// Create metrics of json-rpc calls in the registry and start updating
+/// them. It fails if the metrics have been registered already.
+pub fn register(registry: &Registry) -> prometheus::Result<()> {
+    let calls = IntCounterVec::new(
+        Opts::new("jsonrpc_calls_total", "Number of json-rpc calls by method."),
+        &["method"],
+    )?;
+    let errors = IntCounterVec::new(
+        Opts::new(
+            "jsonrpc_errors_total",
+            "Number of failed json-rpc calls by method and error code.",
+        ),
+        &["method", "code"],
+    )?;
+    let latency = HistogramVec::new(
+        HistogramOpts::new(
+            "jsonrpc_call_duration_seconds",
+            "Duration of json-rpc calls by method.",
+        )
+        .buckets(LATENCY_BUCKETS.to_vec()),
+        &["method"],
+    )?;
+
+    registry.register(Box::new(calls.clone()))?;
+    registry.register(Box::new(errors.clone()))?;
+    registry.register(Box::new(latency.clone()))?;
+
+    hooks::add_hook(Arc::new(MetricsHook {
+        calls,
+        errors,
+        latency,
+    }));
+    Ok(())
+}
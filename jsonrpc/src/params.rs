@@ -0,0 +1,103 @@
+//! Serialization of call parameters.
+//!
+//! Parameters are sent by name (json object) unless the call asks for
+//! positional parameters (`CallOptions::positional_params`), which some
+//! json-rpc servers expect. Then the values of fields of the struct (or
+//! entries of the map) become elements of an array in the order in which
+//! they are serialized, which for structs is the order of declaration of
+//! the fields. Arrays are sent as they are and a single value is wrapped
+//! in an array of one element.
+
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess};
+use serde_json::Value;
+use std::fmt;
+
+/// Serialize arguments of the call to params of the request.
+pub(crate) fn serialize<A>(args: Option<A>, positional: bool) -> Option<Value>
+where
+    A: serde::ser::Serialize,
+{
+    let args = args?;
+    if !positional {
+        return Some(serde_json::to_value(args).unwrap());
+    }
+    // serde_json::Value does not keep the order of keys of objects, so the
+    // values are collected from the serialized text instead
+    let raw = serde_json::to_vec(&args).unwrap();
+    let positional: Positional = serde_json::from_slice(&raw).unwrap();
+    Some(Value::Array(positional.0))
+}
+
+/// Values of the arguments in the order in which they have been serialized.
+struct Positional(Vec<Value>);
+
+impl<'de> Deserialize<'de> for Positional {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(PositionalVisitor)
+    }
+}
+
+struct PositionalVisitor;
+
+impl PositionalVisitor {
+    fn single<E>(self, val: Value) -> Result<Positional, E> {
+        Ok(Positional(vec![val]))
+    }
+}
+
+impl<'de> de::Visitor<'de> for PositionalVisitor {
+    type Value = Positional;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("json-rpc params")
+    }
+
+    fn visit_map<M>(self, mut map: M) -> Result<Positional, M::Error>
+    where
+        M: MapAccess<'de>,
+    {
+        let mut values = Vec::new();
+        while let Some((_, val)) = map.next_entry::<de::IgnoredAny, Value>()? {
+            values.push(val);
+        }
+        Ok(Positional(values))
+    }
+
+    fn visit_seq<S>(self, mut seq: S) -> Result<Positional, S::Error>
+    where
+        S: SeqAccess<'de>,
+    {
+        let mut values = Vec::new();
+        while let Some(val) = seq.next_element::<Value>()? {
+            values.push(val);
+        }
+        Ok(Positional(values))
+    }
+
+    fn visit_unit<E>(self) -> Result<Positional, E> {
+        Ok(Positional(Vec::new()))
+    }
+
+    fn visit_bool<E>(self, val: bool) -> Result<Positional, E> {
+        self.single(Value::from(val))
+    }
+
+    fn visit_i64<E>(self, val: i64) -> Result<Positional, E> {
+        self.single(Value::from(val))
+    }
+
+    fn visit_u64<E>(self, val: u64) -> Result<Positional, E> {
+        self.single(Value::from(val))
+    }
+
+    fn visit_f64<E>(self, val: f64) -> Result<Positional, E> {
+        self.single(Value::from(val))
+    }
+
+    fn visit_str<E>(self, val: &str) -> Result<Positional, E> {
+        self.single(Value::from(val))
+    }
+}
@@ -0,0 +1,127 @@
+//! Check of the credentials of the process at the other end of unix socket.
+//!
+//! The socket of the server is a file in a directory like /var/tmp, where
+//! any process may create it before the server does (or after the server
+//! has exited). A client which trusts the path alone would send its
+//! requests, which may carry secrets, to whoever has planted the socket and
+//! act on its replies. If the user (and group) which the server runs as is
+//! set for the socket by `set_expected_peer`, the uid and gid of the server
+//! process (SO_PEERCRED) are checked right after connecting, before any
+//! request is sent, and the call fails with `Error::UntrustedPeer` if they
+//! don't match. The same check of clients is done by `Server` if it has
+//! been given the expected peer (`Server::expect_peer`). It does not apply
+//! to TCP and HTTP endpoints, which don't have peer credentials.
+
+use crate::error::Error;
+use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+use std::{collections::HashMap, fmt, io, os::unix::io::RawFd, sync::Mutex};
+
+/// Credentials of the process at the other end of the socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PeerCred {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Who may be at the other end of the socket. None matches anyone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExpectedPeer {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl ExpectedPeer {
+    /// Process running as the user.
+    pub fn user(uid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            gid: None,
+        }
+    }
+
+    /// Process running as the user and group.
+    pub fn user_group(uid: u32, gid: u32) -> Self {
+        Self {
+            uid: Some(uid),
+            gid: Some(gid),
+        }
+    }
+
+    /// Return true if the process with the credentials is the expected one.
+    pub fn matches(&self, cred: &PeerCred) -> bool {
+        self.uid.map(|uid| uid == cred.uid).unwrap_or(true)
+            && self.gid.map(|gid| gid == cred.gid).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for ExpectedPeer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.uid, self.gid) {
+            (Some(uid), Some(gid)) => write!(f, "uid {} gid {}", uid, gid),
+            (Some(uid), None) => write!(f, "uid {}", uid),
+            (None, Some(gid)) => write!(f, "gid {}", gid),
+            (None, None) => write!(f, "anyone"),
+        }
+    }
+}
+
+lazy_static! {
+    static ref EXPECTED: Mutex<HashMap<String, ExpectedPeer>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Set (or clear if None) the expected credentials of the server listening
+/// on the socket.
+pub fn set_expected_peer(sock_path: &str, peer: Option<ExpectedPeer>) {
+    let mut expected = EXPECTED.lock().unwrap();
+    match peer {
+        Some(peer) => {
+            expected.insert(sock_path.to_owned(), peer);
+        }
+        None => {
+            expected.remove(sock_path);
+        }
+    }
+}
+
+/// Return the expected credentials of the server listening on the socket.
+pub fn expected_peer(sock_path: &str) -> Option<ExpectedPeer> {
+    EXPECTED.lock().unwrap().get(sock_path).cloned()
+}
+
+/// Return the credentials of the process at the other end of the socket.
+pub fn peer_credentials(fd: RawFd) -> io::Result<PeerCred> {
+    let cred = getsockopt(fd, PeerCredentials).map_err(|err| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("Failed to get peer credentials: {}", err),
+        )
+    })?;
+    Ok(PeerCred {
+        pid: cred.pid(),
+        uid: cred.uid(),
+        gid: cred.gid(),
+    })
+}
+
+/// Check the credentials of the peer on the socket against the expected
+/// ones.
+pub(crate) fn check(
+    sock: &str,
+    fd: RawFd,
+    expected: &ExpectedPeer,
+) -> Result<(), Error> {
+    let cred = peer_credentials(fd)?;
+    if expected.matches(&cred) {
+        Ok(())
+    } else {
+        Err(Error::UntrustedPeer {
+            sock: sock.to_owned(),
+            reason: format!(
+                "peer (pid {}) runs as uid {} gid {}, expected {}",
+                cred.pid, cred.uid, cred.gid, expected
+            ),
+        })
+    }
+}
@@ -0,0 +1,248 @@
+//! Bounding the number of connections to the server and of requests in
+//! flight.
+//!
+//! `call()` and `notify()` open a new connection for each request. When
+//! many calls are made at once (i.e. kubelet staging many volumes in
+//! parallel), we could run out of file descriptors and overload the server,
+//! which serves json-rpc from a single thread (SPDK). Hence the number of
+//! connections to each server open at the same time is limited. Calls over
+//! the limit wait in a queue and get a connection in the order in which
+//! they were made, so that no call is starved by the others. Waiting in the
+//! queue counts against the timeout of the call. Connections are never
+//! reused, so the connection of a call which is cancelled half-way is just
+//! shut down and its slot goes to the next call in the queue.
+//!
+//! Persistent connections of `RpcClient` are not counted, because they are
+//! open for as long as the client lives.
+//!
+//! The requests are limited too, since SPDK serves them on the reactor which
+//! does IO as well and a burst of requests shows in the latency of the data
+//! path. A request (also of `RpcClient` and a notification) needs a permit
+//! to be sent, which it holds until its reply arrives. The permits are
+//! handed out in FIFO order like the connections. A call gets its connection
+//! slot first and then waits for a permit before it connects.
+
+use crate::error::Error;
+use futures::{
+    future::{self, Future},
+    sync::oneshot,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+/// Max number of connections to one server unless changed by
+/// set_max_connections(). SPDK accepts at most 64.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+/// Max number of requests in flight to one server unless changed by
+/// set_max_requests().
+pub const DEFAULT_MAX_REQUESTS: usize = 8;
+
+lazy_static! {
+    static ref CONNECTIONS: Limiter =
+        Limiter::new("connection", DEFAULT_MAX_CONNECTIONS);
+    static ref REQUESTS: Limiter =
+        Limiter::new("request", DEFAULT_MAX_REQUESTS);
+}
+
+/// Things in use for each server and calls waiting for one.
+#[derive(Default)]
+struct Limit {
+    in_use: usize,
+    waiters: VecDeque<oneshot::Sender<Slot>>,
+}
+
+/// FIFO semaphore for each server.
+struct Limiter {
+    /// what is limited (for messages)
+    what: &'static str,
+    /// current max number per server (zero means no limit)
+    max: AtomicUsize,
+    limits: Mutex<HashMap<String, Limit>>,
+}
+
+/// Change the max number of connections to one server. Zero means no
+/// limit. Calls which are already waiting keep waiting until a connection
+/// is released.
+pub fn set_max_connections(max: usize) {
+    CONNECTIONS.max.store(max, Ordering::Relaxed);
+}
+
+/// Change the max number of requests in flight to one server. Zero means no
+/// limit. Calls which are already waiting keep waiting until a request
+/// completes.
+pub fn set_max_requests(max: usize) {
+    REQUESTS.max.store(max, Ordering::Relaxed);
+}
+
+impl Limit {
+    /// Pass a slot to the first call in the queue which is still waiting.
+    /// Return false if there is none.
+    fn wake(&mut self, limiter: &'static Limiter, endpoint: &str) -> bool {
+        while let Some(waiter) = self.waiters.pop_front() {
+            let slot = Slot {
+                limiter,
+                endpoint: Some(endpoint.to_owned()),
+            };
+            match waiter.send(slot) {
+                Ok(()) => return true,
+                // the call has given up waiting, releasing the slot here
+                // would deadlock on the lock
+                Err(slot) => slot.disarm(),
+            }
+        }
+        false
+    }
+}
+
+/// Permission to have a connection open to the server or a request in
+/// flight. It is passed to the next call in the queue when dropped.
+pub struct Slot {
+    limiter: &'static Limiter,
+    /// None if the number is not limited
+    endpoint: Option<String>,
+}
+
+impl Slot {
+    /// Drop the slot without releasing it (the caller keeps it in use).
+    fn disarm(mut self) {
+        self.endpoint = None;
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let endpoint = match self.endpoint.take() {
+            Some(endpoint) => endpoint,
+            None => return,
+        };
+        let limiter = self.limiter;
+        let mut limits = limiter.limits.lock().unwrap();
+        let limit = limits.get_mut(&endpoint).expect("Unknown endpoint");
+        let max = limiter.max.load(Ordering::Relaxed);
+
+        // the limit might have been lowered in the meantime
+        if (max == 0 || limit.in_use <= max) && limit.wake(limiter, &endpoint) {
+            return;
+        }
+        limit.in_use -= 1;
+        if limit.in_use == 0 && limit.waiters.is_empty() {
+            limits.remove(&endpoint);
+        }
+    }
+}
+
+impl Limiter {
+    fn new(what: &'static str, max: usize) -> Self {
+        Self {
+            what,
+            max: AtomicUsize::new(max),
+            limits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get a slot for the server if the number in use is below the limit
+    /// and nobody is waiting for one, or queue the sender to wait for it.
+    fn try_acquire_or_queue(
+        &'static self,
+        sock_path: &str,
+        waiter: Option<oneshot::Sender<Slot>>,
+    ) -> Option<Slot> {
+        let max = self.max.load(Ordering::Relaxed);
+        if max == 0 {
+            return Some(Slot {
+                limiter: self,
+                endpoint: None,
+            });
+        }
+        let mut limits = self.limits.lock().unwrap();
+        let limit = limits.entry(sock_path.to_owned()).or_default();
+        limit.waiters.retain(|waiter| !waiter.is_canceled());
+
+        // the limit might have been raised in the meantime
+        while limit.in_use < max && limit.wake(self, sock_path) {
+            limit.in_use += 1;
+        }
+        if limit.in_use < max && limit.waiters.is_empty() {
+            limit.in_use += 1;
+            return Some(Slot {
+                limiter: self,
+                endpoint: Some(sock_path.to_owned()),
+            });
+        }
+        // the limit is reached, so the entry is not left empty
+        if let Some(waiter) = waiter {
+            trace!(
+                "Waiting for {} to {} ({} in use, {} waiting)",
+                self.what,
+                sock_path,
+                limit.in_use,
+                limit.waiters.len()
+            );
+            limit.waiters.push_back(waiter);
+        }
+        None
+    }
+
+    /// Get a slot for the server. The future completes when the number in
+    /// use is below the limit.
+    fn acquire(
+        &'static self,
+        sock_path: &str,
+    ) -> Box<dyn Future<Item = Slot, Error = Error> + Send> {
+        let (sender, receiver) = oneshot::channel();
+        if let Some(slot) = self.try_acquire_or_queue(sock_path, Some(sender)) {
+            return Box::new(future::ok(slot));
+        }
+        let what = self.what;
+        Box::new(receiver.map_err(move |_| {
+            Error::GenericError(format!("Slot for {} has been lost", what))
+        }))
+    }
+
+    /// Return number in use for the server and calls waiting for one.
+    fn usage(&self, sock_path: &str) -> (usize, usize) {
+        match self.limits.lock().unwrap().get(sock_path) {
+            Some(limit) => (limit.in_use, limit.waiters.len()),
+            None => (0, 0),
+        }
+    }
+}
+
+/// Get a slot for a connection to the server. The future completes when
+/// the number of connections to the server is below the limit.
+pub(crate) fn acquire(
+    sock_path: &str,
+) -> Box<dyn Future<Item = Slot, Error = Error> + Send> {
+    CONNECTIONS.acquire(sock_path)
+}
+
+/// Get a permit to send a request to the server. The future completes when
+/// the number of requests in flight is below the limit.
+pub(crate) fn acquire_request(
+    sock_path: &str,
+) -> Box<dyn Future<Item = Slot, Error = Error> + Send> {
+    REQUESTS.acquire(sock_path)
+}
+
+/// Get a permit to send a request to the server right away if there is one
+/// available.
+pub(crate) fn try_acquire_request(sock_path: &str) -> Option<Slot> {
+    REQUESTS.try_acquire_or_queue(sock_path, None)
+}
+
+/// Return number of connections to the server and calls waiting for one.
+pub fn connections(sock_path: &str) -> (usize, usize) {
+    CONNECTIONS.usage(sock_path)
+}
+
+/// Return number of requests in flight to the server and calls waiting to
+/// send one.
+pub fn requests(sock_path: &str) -> (usize, usize) {
+    REQUESTS.usage(sock_path)
+}
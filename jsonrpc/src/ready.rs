@@ -0,0 +1,104 @@
+//! Waiting for the server to come up.
+//!
+//! When mayastor and its clients are started at the same time (i.e. in one
+//! pod), the clients must not serve anything until the server is ready.
+//! `wait_ready` polls the server until it answers `rpc_get_methods` and tells
+//! apart the server which has not created its socket yet from the one which
+//! does not answer (i.e. SPDK has not finished initialization).
+
+use crate::{
+    call_with_options,
+    error::Error,
+    retry::RetryPolicy,
+    transport::Endpoint,
+    CallOptions,
+};
+use futures::future::{self, Either, Future, Loop};
+use serde_json::Value;
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+/// How often the server is polled.
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+/// Max time to wait for the reply to one poll.
+const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why the server is not ready.
+enum NotReady {
+    SocketMissing,
+    NoReply(String),
+}
+
+/// Poll the server until it answers `rpc_get_methods`. Fail with
+/// `Error::SocketMissing` if the unix socket of the server does not exist by
+/// the deadline and with `Error::NotReady` if the server has not answered.
+pub fn wait_ready(
+    sock_path: &str,
+    deadline: Instant,
+) -> Box<dyn Future<Item = (), Error = Error> + Send> {
+    let sock = sock_path.to_owned();
+    let path = match Endpoint::parse(sock_path) {
+        Endpoint::Unix(path) => Some(path),
+        _ => None,
+    };
+
+    Box::new(future::loop_fn((), move |()| {
+        let sock = sock.clone();
+        let poll = match &path {
+            Some(path) if !Path::new(path).exists() => {
+                Either::A(future::err(NotReady::SocketMissing))
+            }
+            _ => {
+                let now = Instant::now();
+                let timeout = if deadline > now {
+                    (deadline - now).max(POLL_INTERVAL).min(POLL_TIMEOUT)
+                } else {
+                    POLL_INTERVAL
+                };
+                let options = CallOptions::default()
+                    .timeout(timeout)
+                    .retry(RetryPolicy::none());
+                Either::B(
+                    call_with_options::<(), Value>(
+                        &sock,
+                        "rpc_get_methods",
+                        None,
+                        options,
+                    )
+                    .map(|_| ())
+                    .map_err(|err| NotReady::NoReply(err.root().to_string())),
+                )
+            }
+        };
+
+        poll.then(move |res| {
+            let reason = match res {
+                Ok(()) => {
+                    debug!("Json-rpc server {} is ready", sock);
+                    return Either::A(future::ok(Loop::Break(())));
+                }
+                Err(reason) => reason,
+            };
+            let next = Instant::now() + POLL_INTERVAL;
+            if next > deadline {
+                return Either::A(future::err(match reason {
+                    NotReady::SocketMissing => Error::SocketMissing {
+                        sock,
+                    },
+                    NotReady::NoReply(reason) => Error::NotReady {
+                        sock,
+                        reason,
+                    },
+                }));
+            }
+            Either::B(
+                Delay::new(next)
+                    .map_err(|err| Error::GenericError(err.to_string()))
+                    .map(|_| Loop::Continue(())),
+            )
+        })
+    }))
+}
@@ -0,0 +1,113 @@
+//! Redaction of secrets in trace logs of requests and replies.
+//!
+//! Bodies of requests and replies are logged at trace level. Some of them
+//! carry secrets (CHAP credentials, encryption keys), which must not end up
+//! in the logs. Before a body is logged, values of the fields with names
+//! from the denylist (`DEFAULT_REDACTED_FIELDS` unless changed by
+//! `set_redacted_fields`) are replaced by `<redacted>` at any depth of the
+//! document, and then the redactor set by `set_redactor` (if any) is called
+//! for what the denylist can't express. Only the logs are redacted, the data
+//! sent and received are left intact. Redaction is done only if trace
+//! logging is enabled.
+
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::{Arc, RwLock};
+
+/// Names of the fields redacted unless changed by set_redacted_fields().
+pub const DEFAULT_REDACTED_FIELDS: &[&str] = &[
+    "secret",
+    "msecret",
+    "chap_secret",
+    "mutual_chap_secret",
+    "password",
+    "passphrase",
+    "key",
+    "encryption_key",
+    "token",
+];
+
+/// What the value of a redacted field is replaced with.
+pub const REDACTED: &str = "<redacted>";
+
+/// Callback redacting a json document before it is logged.
+pub type Redactor = Arc<dyn Fn(&mut Value) + Send + Sync>;
+
+lazy_static! {
+    static ref FIELDS: RwLock<Vec<String>> = RwLock::new(
+        DEFAULT_REDACTED_FIELDS
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
+    );
+    static ref REDACTOR: RwLock<Option<Redactor>> = RwLock::new(None);
+}
+
+/// Change the names of the redacted fields (compared case-insensitively).
+pub fn set_redacted_fields<I>(names: I)
+where
+    I: IntoIterator<Item = String>,
+{
+    *FIELDS.write().unwrap() =
+        names.into_iter().map(|name| name.to_lowercase()).collect();
+}
+
+/// Return the names of the redacted fields.
+pub fn redacted_fields() -> Vec<String> {
+    FIELDS.read().unwrap().clone()
+}
+
+/// Set or clear the redactor called after the fields have been redacted.
+pub fn set_redactor(redactor: Option<Redactor>) {
+    *REDACTOR.write().unwrap() = redactor;
+}
+
+/// Replace values of the fields with given names by REDACTED.
+pub(crate) fn redact_fields(value: &mut Value, names: &[String]) {
+    match value {
+        Value::Object(map) => {
+            for (key, val) in map.iter_mut() {
+                let key = key.to_lowercase();
+                if names.contains(&key) {
+                    *val = Value::String(REDACTED.to_owned());
+                } else {
+                    redact_fields(val, names);
+                }
+            }
+        }
+        Value::Array(vals) => {
+            for val in vals.iter_mut() {
+                redact_fields(val, names);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Redact the document as it is done before it is logged.
+pub fn redact(value: &mut Value) {
+    redact_fields(value, &FIELDS.read().unwrap());
+    if let Some(redactor) = REDACTOR.read().unwrap().as_ref() {
+        redactor(value);
+    }
+}
+
+/// Return the redacted message for the log.
+pub(crate) fn redacted<T: Serialize>(msg: &T) -> String {
+    match serde_json::to_value(msg) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        }
+        Err(err) => format!("<not serializable: {}>", err),
+    }
+}
+
+/// Return the redacted raw json for the log. Anything else than json is
+/// not logged, since it can't be redacted.
+pub(crate) fn redacted_raw(raw: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(raw) {
+        Ok(value) => redacted(&value),
+        Err(_) => format!("<{} bytes of invalid json>", raw.len()),
+    }
+}
@@ -0,0 +1,254 @@
+//! Reading of replies with bounded memory.
+//!
+//! A reply is read until a complete json document has been received or
+//! until the server closes the connection. The end of the document is found
+//! by an incremental scanner which looks only at the newly received data,
+//! so we don't have to wait for EOF (SPDK may keep the connection open) and
+//! we don't try to parse the whole reply again after each read. It is kept in
+//! memory and the call fails with `ResponseTooLarge` as soon as the reply
+//! grows over the limit (`set_max_response_size`), so that a misbehaving
+//! server can't make us allocate without bounds. Replies of methods which
+//! are known to be large (i.e. `get_bdevs` with thousands of bdevs) can be
+//! streamed instead (`CallOptions::stream_reply`): the reply is spooled to
+//! an unlinked temporary file as it arrives and then deserialized from the
+//! file straight to the result type, without holding the raw reply or an
+//! intermediate json value in memory. Streamed replies are not limited.
+
+use crate::{
+    check_reply_id,
+    check_version,
+    error::Error,
+    rpc_error,
+    Response,
+    RpcError,
+    ValidationMode,
+};
+use futures::future::{self, Future, Loop};
+use nix::unistd::{mkstemp, unlink};
+use std::{
+    fs::File,
+    io::{BufReader, Seek, SeekFrom, Write},
+    os::unix::io::FromRawFd,
+};
+use tokio::io::{read, AsyncRead};
+
+/// Size of the buffer for reading replies from the socket.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Raw reply read from the socket.
+pub(crate) enum RawReply {
+    Memory(Vec<u8>),
+    Spooled { file: File, len: usize },
+}
+
+impl RawReply {
+    fn len(&self) -> usize {
+        match self {
+            RawReply::Memory(buf) => buf.len(),
+            RawReply::Spooled {
+                len, ..
+            } => *len,
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) -> Result<(), Error> {
+        match self {
+            RawReply::Memory(buf) => buf.extend_from_slice(data),
+            RawReply::Spooled {
+                file,
+                len,
+            } => {
+                file.write_all(data)?;
+                *len += data.len();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Incremental scanner finding the end of the first json object or array
+/// in a stream of bytes. It tracks only nesting and strings, the document is
+/// validated by the parser afterwards.
+#[derive(Default)]
+pub(crate) struct DocumentScanner {
+    depth: usize,
+    in_string: bool,
+    escape: bool,
+}
+
+impl DocumentScanner {
+    /// Scan the next chunk of the stream and return the length of the part
+    /// of the chunk up to the end of the document if the document ends in it.
+    pub(crate) fn feed(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, byte) in data.iter().enumerate() {
+            if self.in_string {
+                if self.escape {
+                    self.escape = false;
+                } else if *byte == b'\\' {
+                    self.escape = true;
+                } else if *byte == b'"' {
+                    self.in_string = false;
+                }
+                continue;
+            }
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                // unbalanced bracket is left for the parser to report
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(i + 1);
+                    }
+                }
+                _ => (),
+            }
+        }
+        None
+    }
+}
+
+/// Same as the typed reply but the result is deserialized directly to the
+/// type which the caller expects.
+#[derive(Deserialize)]
+struct TypedResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+    #[serde(default)]
+    id: serde_json::Value,
+    jsonrpc: Option<String>,
+}
+
+/// Create temporary file which disappears when closed.
+fn spool_file() -> Result<File, Error> {
+    let template = std::env::temp_dir().join("jsonrpc-reply.XXXXXX");
+    let (fd, path) = mkstemp(&template).map_err(|err| {
+        Error::GenericError(format!("Failed to create spool file: {}", err))
+    })?;
+    let file = unsafe { File::from_raw_fd(fd) };
+    let _ = unlink(&path);
+    Ok(file)
+}
+
+/// Read the reply until the end of the json document or the end of the
+/// stream. The reply is spooled to a temporary file if `stream` is set,
+/// otherwise it must not be larger than the limit. Whatever follows the
+/// document is ignored.
+pub(crate) fn read_reply<S>(
+    socket: S,
+    method: &str,
+    limit: Option<usize>,
+    stream: bool,
+) -> Box<dyn Future<Item = (S, RawReply), Error = Error> + Send>
+where
+    S: AsyncRead + Send + 'static,
+{
+    let reply = if stream {
+        match spool_file() {
+            Ok(file) => RawReply::Spooled {
+                file,
+                len: 0,
+            },
+            Err(err) => return Box::new(future::err(err)),
+        }
+    } else {
+        RawReply::Memory(Vec::new())
+    };
+    let method = method.to_owned();
+
+    Box::new(future::loop_fn(
+        (
+            socket,
+            vec![0; READ_CHUNK],
+            reply,
+            DocumentScanner::default(),
+        ),
+        move |(socket, chunk, mut reply, mut scanner)| {
+            let method = method.clone();
+
+            read(socket, chunk).map_err(Error::from).and_then(
+                move |(socket, chunk, n)| {
+                    if n == 0 {
+                        return Ok(Loop::Break((socket, reply)));
+                    }
+                    let end = scanner.feed(&chunk[.. n]);
+                    reply.append(&chunk[.. end.unwrap_or(n)])?;
+                    match (&reply, limit) {
+                        (RawReply::Memory(buf), Some(limit))
+                            if buf.len() > limit =>
+                        {
+                            Err(Error::ResponseTooLarge {
+                                method,
+                                limit,
+                            })
+                        }
+                        // don't wait for the server to close the connection
+                        _ if end.is_some() => Ok(Loop::Break((socket, reply))),
+                        _ => {
+                            Ok(Loop::Continue((socket, chunk, reply, scanner)))
+                        }
+                    }
+                },
+            )
+        },
+    ))
+}
+
+/// Parse the reply to the request with given id and return user data
+/// embedded in it.
+pub(crate) fn parse_raw_reply<T>(
+    reply: RawReply,
+    id: u64,
+    mode: ValidationMode,
+) -> Result<T, Error>
+where
+    T: serde::de::DeserializeOwned,
+{
+    let len = reply.len();
+    let mut file = match reply {
+        RawReply::Memory(buf) => return crate::parse_reply(&buf, id, mode),
+        RawReply::Spooled {
+            file, ..
+        } => file,
+    };
+    trace!("JSON response streamed from spool file ({} bytes)", len);
+
+    file.seek(SeekFrom::Start(0))?;
+    let reply: TypedResponse<T> =
+        serde_json::from_reader(BufReader::new(file))?;
+    check_reply_id(&reply.id, id, mode)?;
+    check_version(&reply.jsonrpc, mode)?;
+    if let Some(err) = reply.error {
+        return Err(rpc_error(err));
+    }
+    match reply.result {
+        Some(val) => Ok(val),
+        // if there is no result fabricate null value == ()
+        None => serde_json::from_value::<T>(serde_json::Value::Null)
+            .map_err(Error::ParseError),
+    }
+}
+
+/// Parse the reply to the request with given id and return it as a whole.
+pub(crate) fn parse_raw_response(
+    reply: RawReply,
+    id: u64,
+    mode: ValidationMode,
+) -> Result<Response, Error> {
+    let len = reply.len();
+    let response: Response = match reply {
+        RawReply::Memory(buf) => {
+            trace!("JSON response: {}", crate::redact::redacted_raw(&buf));
+            serde_json::from_slice(&buf)?
+        }
+        RawReply::Spooled {
+            mut file, ..
+        } => {
+            trace!("JSON response streamed from spool file ({} bytes)", len);
+            file.seek(SeekFrom::Start(0))?;
+            serde_json::from_reader(BufReader::new(file))?
+        }
+    };
+    check_reply_id(&response.id, id, mode)?;
+    Ok(response)
+}
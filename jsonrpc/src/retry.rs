@@ -0,0 +1,146 @@
+//! Retrying of transient connection failures.
+//!
+//! While mayastor is starting, its socket either does not exist yet or
+//! nobody listens on it (the same applies to TCP port). Connecting to the
+//! socket is retried with exponential backoff in these cases, so that callers
+//! don't have to deal with the short window when the server is not available.
+//! Nothing has been sent to the server when the connection fails, so the retry
+//! is always safe.
+
+use crate::{
+    error::Error,
+    peercred,
+    transport::{self, Connection, Endpoint},
+};
+use futures::future::{self, Either, Future, IntoFuture, Loop};
+use std::{
+    io,
+    os::unix::io::AsRawFd,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::timer::Delay;
+
+/// How many times and how often to try to connect to the server.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Max number of connection attempts (1 means no retries).
+    pub max_attempts: u32,
+    /// Delay before the first retry. It doubles with each retry.
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between retries.
+    pub max_backoff: Duration,
+    /// Randomize the delay (between half and full backoff), so that clients
+    /// waiting for the same server don't retry all at the same time.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy which gives up after the first failure.
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Delay before the given retry (counted from zero).
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .checked_mul(1 << retry.min(16))
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff);
+        if !self.jitter {
+            return backoff;
+        }
+        // we don't need a good random generator to spread the retries
+        let random = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or(0)
+            % 1000;
+        backoff / 2 + backoff / 2 * random / 1000
+    }
+}
+
+/// Return true if the connection may succeed if tried again later.
+fn is_transient(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => true,
+        _ => false,
+    }
+}
+
+/// Convert error from connect to json-rpc error.
+fn connect_error(sock: &str, err: io::Error) -> Error {
+    match err.kind() {
+        io::ErrorKind::NotFound
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::PermissionDenied => Error::ConnectError {
+            sock: sock.to_owned(),
+            err,
+        },
+        _ => err.into(),
+    }
+}
+
+/// Check the credentials of the server on unix socket if they are expected.
+fn check_peer(sock: &str, conn: Connection) -> Result<Connection, Error> {
+    if let Connection::Unix(stream) = &conn {
+        if let Some(expected) = peercred::expected_peer(sock) {
+            peercred::check(sock, stream.as_raw_fd(), &expected)?;
+        }
+    }
+    Ok(conn)
+}
+
+/// Connect to the server and retry transient failures according to policy.
+/// A server on unix socket which is not the expected one (see `peercred`)
+/// fails the connection without retries.
+pub(crate) fn connect(
+    addr: &str,
+    policy: RetryPolicy,
+) -> impl Future<Item = Connection, Error = Error> + Send {
+    let endpoint = Endpoint::parse(addr);
+
+    future::loop_fn(0, move |attempt| {
+        let sock = endpoint.to_string();
+        let policy = policy.clone();
+
+        transport::connect(&endpoint).then(move |res| match res {
+            Ok(socket) => Either::A(
+                check_peer(&sock, socket).map(Loop::Break).into_future(),
+            ),
+            Err(err) => {
+                if !is_transient(&err) || attempt + 1 >= policy.max_attempts {
+                    return Either::A(future::err(connect_error(&sock, err)));
+                }
+                let delay = policy.backoff(attempt);
+                debug!(
+                    "Failed to connect to {} ({}), retrying in {}ms",
+                    sock,
+                    err,
+                    delay.as_millis()
+                );
+                Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(|err| {
+                            Error::GenericError(format!("Timer error: {}", err))
+                        })
+                        .map(move |_| Loop::Continue(attempt + 1)),
+                )
+            }
+        })
+    })
+}
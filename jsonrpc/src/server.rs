@@ -0,0 +1,356 @@
+//! json-rpc server listening on unix domain socket.
+//!
+//! Methods are registered as closures taking deserialized parameters and
+//! returning a future with the result. Errors defined by the spec are
+//! produced by the server itself: ParseError for malformed json (the
+//! connection is closed after it, because the stream can't be resynced),
+//! InvalidRequest for json which is not a request, MethodNotFound and
+//! InvalidParams. Errors returned by the methods are passed to the client
+//! with the same codes as used by SPDK, so the client can't tell the two
+//! servers apart.
+//!
+//! Requests on one connection are processed one by one in the order they
+//! were received. Each connection is served by its own task, so a slow
+//! method blocks only the connection which has called it.
+//!
+//! Connections of clients which don't run as the expected user (if set by
+//! `expect_peer`) are closed right away, before anything is read from them.
+
+use crate::{
+    error::{Error, RpcCode},
+    peercred::{self, ExpectedPeer},
+    redact,
+    Response,
+    RpcError,
+};
+use futures::{
+    future::{self, Either, Future, IntoFuture, Loop},
+    Stream,
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    os::unix::io::AsRawFd,
+    sync::Arc,
+};
+use tokio::{
+    io::{read, write_all},
+    net::{UnixListener, UnixStream},
+};
+
+/// Size of the buffer for reading requests from the socket.
+const READ_CHUNK: usize = 4096;
+
+type Handler = Box<
+    dyn Fn(Option<Value>) -> Box<dyn Future<Item = Value, Error = Error> + Send>
+        + Send
+        + Sync,
+>;
+
+/// Request as received from the client. Unlike the request sent by us, id
+/// is optional (notification) and everything is owned.
+#[derive(Debug, Deserialize)]
+struct IncomingRequest {
+    method: String,
+    #[serde(default)]
+    params: Option<Value>,
+    #[serde(default)]
+    id: Option<Value>,
+    #[serde(default)]
+    jsonrpc: Option<String>,
+}
+
+fn rpc_error(code: RpcCode, msg: String) -> Error {
+    Error::rpc(code, msg)
+}
+
+/// Serialize reply to the request with given id.
+fn reply(id: Value, res: Result<Value, Error>) -> Vec<u8> {
+    // a method failing on an error of a call passes on its error reply
+    let (result, error) = match res.map_err(Error::into_root) {
+        Ok(val) => (Some(val), None),
+        Err(Error::RpcError {
+            msg,
+            raw_code,
+            data,
+            ..
+        }) => (
+            None,
+            Some(RpcError {
+                code: raw_code,
+                message: msg,
+                data,
+            }),
+        ),
+        Err(err) => (
+            None,
+            Some(RpcError {
+                code: RpcCode::InternalError.raw(),
+                message: err.to_string(),
+                data: None,
+            }),
+        ),
+    };
+    let reply = Response {
+        result,
+        error,
+        id,
+        jsonrpc: Some("2.0".to_owned()),
+        extra: Default::default(),
+    };
+    trace!("JSON response: {}", redact::redacted(&reply));
+    serde_json::to_vec(&reply).unwrap()
+}
+
+/// json-rpc server with registered methods.
+#[derive(Default)]
+pub struct Server {
+    methods: HashMap<String, Handler>,
+    expected_peer: Option<ExpectedPeer>,
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register method with given name. Parameters of the request are
+    /// deserialized to the argument of the handler (missing parameters are
+    /// null, so use `()` or `Option` for methods without them) and the
+    /// result of the handler becomes the result of the reply. Registering
+    /// the same name twice replaces the previous handler.
+    pub fn register<A, R, F, T>(&mut self, method: &str, handler: F)
+    where
+        A: serde::de::DeserializeOwned + 'static,
+        R: serde::ser::Serialize + 'static,
+        F: Fn(A) -> T + Send + Sync + 'static,
+        T: IntoFuture<Item = R, Error = Error>,
+        T::Future: Send + 'static,
+    {
+        let handler: Handler =
+            Box::new(move |params| {
+                let args = match serde_json::from_value::<A>(
+                    params.unwrap_or(Value::Null),
+                ) {
+                    Ok(args) => args,
+                    Err(err) => {
+                        return Box::new(future::err(rpc_error(
+                            RpcCode::InvalidParams,
+                            format!("Invalid parameters: {}", err),
+                        )))
+                    }
+                };
+                Box::new(handler(args).into_future().and_then(|res| {
+                    serde_json::to_value(res).map_err(Error::from)
+                }))
+            });
+        self.methods.insert(method.to_owned(), handler);
+    }
+
+    /// Serve only clients running as the expected user (and group).
+    pub fn expect_peer(&mut self, peer: ExpectedPeer) {
+        self.expected_peer = Some(peer);
+    }
+
+    /// Start listening on the unix domain socket. A stale socket file left
+    /// behind by a previous server is removed. The returned future serves
+    /// connections until it is dropped and must be run on tokio runtime.
+    pub fn listen(
+        self,
+        sock_path: &str,
+    ) -> Result<impl Future<Item = (), Error = ()> + Send, Error> {
+        if let Err(err) = fs::remove_file(sock_path) {
+            if err.kind() != ErrorKind::NotFound {
+                return Err(err.into());
+            }
+        }
+        let listener = UnixListener::bind(sock_path)?;
+        let methods = Arc::new(self.methods);
+        let expected_peer = self.expected_peer;
+        let sock = sock_path.to_owned();
+        let sock_peer = sock.clone();
+
+        debug!("json-rpc server listening on {}", sock_path);
+        Ok(listener
+            .incoming()
+            .map_err(move |err| {
+                error!("json-rpc server on {} failed: {}", sock, err)
+            })
+            .for_each(move |stream| {
+                if let Some(expected) = &expected_peer {
+                    let fd = stream.as_raw_fd();
+                    if let Err(err) = peercred::check(&sock_peer, fd, expected)
+                    {
+                        warn!("Rejected json-rpc client: {}", err);
+                        return Ok(());
+                    }
+                }
+                tokio::spawn(serve_connection(stream, Arc::clone(&methods)));
+                Ok(())
+            }))
+    }
+}
+
+/// Call the method of the request and return the reply (None for
+/// notification).
+fn dispatch(
+    methods: &HashMap<String, Handler>,
+    request: Value,
+) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = ()> + Send> {
+    trace!("JSON request: {}", redact::redacted(&request));
+    // the id is needed for the error reply even if the request is invalid
+    let id = request.get("id").cloned();
+
+    let request: IncomingRequest = match serde_json::from_value(request) {
+        Ok(request) => request,
+        Err(err) => {
+            return Box::new(future::ok(Some(reply(
+                id.unwrap_or(Value::Null),
+                Err(rpc_error(
+                    RpcCode::InvalidRequest,
+                    format!("Invalid request: {}", err),
+                )),
+            ))))
+        }
+    };
+    let res = match request.jsonrpc.as_ref().map(String::as_str) {
+        Some("2.0") | None => match methods.get(&request.method) {
+            Some(handler) => handler(request.params),
+            None => Box::new(future::err(rpc_error(
+                RpcCode::MethodNotFound,
+                format!("Method {} not found", request.method),
+            ))),
+        },
+        Some(vers) => Box::new(future::err(rpc_error(
+            RpcCode::InvalidRequest,
+            format!("Unsupported json-rpc version {}", vers),
+        ))),
+    };
+    let id = request.id;
+
+    Box::new(res.then(move |res| {
+        Ok(match id {
+            Some(id) => Some(reply(id, res)),
+            None => {
+                if let Err(err) = res {
+                    warn!("json-rpc notification failed: {}", err);
+                }
+                None
+            }
+        })
+    }))
+}
+
+/// True if there is nothing but white space in the buffer.
+fn is_blank(buf: &[u8]) -> bool {
+    buf.iter().all(|b| b.is_ascii_whitespace())
+}
+
+/// Parse complete requests in the buffer and remove them from it.
+/// Incomplete request at the end of the buffer is left there until more
+/// data arrives.
+fn parse_requests(buf: &mut Vec<u8>) -> Result<Vec<Value>, serde_json::Error> {
+    let mut requests = Vec::new();
+    let consumed = {
+        let mut values =
+            serde_json::Deserializer::from_slice(buf).into_iter::<Value>();
+        loop {
+            match values.next() {
+                Some(Ok(val)) => requests.push(val),
+                Some(Err(ref err)) if err.is_eof() => break,
+                Some(Err(err)) => return Err(err),
+                None => break,
+            }
+        }
+        values.byte_offset()
+    };
+    buf.drain(.. consumed);
+    Ok(requests)
+}
+
+/// Process the requests one by one and write replies to them.
+fn serve_requests(
+    stream: UnixStream,
+    methods: Arc<HashMap<String, Handler>>,
+    requests: Vec<Value>,
+) -> impl Future<Item = UnixStream, Error = ()> {
+    future::loop_fn(
+        (stream, requests.into_iter()),
+        move |(stream, mut requests)| {
+            let request = match requests.next() {
+                Some(request) => request,
+                None => return Either::A(future::ok(Loop::Break(stream))),
+            };
+            Either::B(dispatch(&methods, request).and_then(|resp| {
+                match resp {
+                    Some(resp) => Either::A(
+                        write_all(stream, resp)
+                            .map(|(stream, _)| {
+                                Loop::Continue((stream, requests))
+                            })
+                            .map_err(|err| {
+                                debug!("Failed to send json-rpc reply: {}", err)
+                            }),
+                    ),
+                    None => Either::B(future::ok(Loop::Continue((
+                        stream, requests,
+                    )))),
+                }
+            }))
+        },
+    )
+}
+
+/// Read requests from the connection and write replies to them until the
+/// client closes the connection or sends malformed json.
+fn serve_connection(
+    stream: UnixStream,
+    methods: Arc<HashMap<String, Handler>>,
+) -> impl Future<Item = (), Error = ()> {
+    future::loop_fn((stream, Vec::new()), move |(stream, mut buf)| {
+        let methods = Arc::clone(&methods);
+
+        read(stream, vec![0; READ_CHUNK])
+            .map_err(|err| debug!("json-rpc connection failed: {}", err))
+            .and_then(move |(stream, chunk, len)| {
+                let eof = len == 0;
+                buf.extend_from_slice(&chunk[.. len]);
+
+                let (requests, parse_err) = match parse_requests(&mut buf) {
+                    Ok(requests) => (requests, None),
+                    Err(err) => (Vec::new(), Some(err)),
+                };
+                // incomplete request at the end of the stream is an error too
+                let parse_err = match parse_err {
+                    None if eof && !is_blank(&buf) => {
+                        serde_json::from_slice::<Value>(&buf).err()
+                    }
+                    err => err,
+                };
+
+                serve_requests(stream, methods, requests).and_then(
+                    move |stream| match parse_err {
+                        Some(err) => {
+                            let resp = reply(
+                                Value::Null,
+                                Err(rpc_error(
+                                    RpcCode::ParseError,
+                                    format!("Invalid json: {}", err),
+                                )),
+                            );
+                            Either::A(
+                                write_all(stream, resp)
+                                    .then(|_| Ok(Loop::Break(()))),
+                            )
+                        }
+                        None if eof => Either::B(future::ok(Loop::Break(()))),
+                        None => {
+                            Either::B(future::ok(Loop::Continue((stream, buf))))
+                        }
+                    },
+                )
+            })
+    })
+}
@@ -0,0 +1,229 @@
+//! Typed bindings of SPDK json-rpc methods used by us.
+//!
+//! Each method has a function with arguments and reply described by structs
+//! which match the parameters of the SPDK method, so that callers don't have
+//! to spell method and field names on their own. Optional parameters which
+//! are not set are left out of the request and SPDK uses its defaults.
+
+use crate::{call, call_with_options, error::Error, CallOptions};
+use futures::Future;
+
+/// Arguments of get_bdevs method.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GetBdevsArgs {
+    /// Return only the bdev with this name (all bdevs if not set).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Block device as returned by get_bdevs method.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Bdev {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub product_name: String,
+    pub block_size: u32,
+    pub num_blocks: u64,
+    pub uuid: Option<String>,
+    // ... other fields which are not used by us (i.e. qos, etc.)
+    /// Properties specific to the bdev module.
+    pub driver_specific: serde_json::Value,
+}
+
+/// Arguments of start_nbd_disk method.
+#[derive(Clone, Debug, Serialize)]
+pub struct StartNbdDiskArgs {
+    /// Bdev to export.
+    pub bdev_name: String,
+    /// Nbd device to export it on (i.e. /dev/nbd0).
+    pub nbd_device: String,
+}
+
+/// Arguments of stop_nbd_disk method.
+#[derive(Clone, Debug, Serialize)]
+pub struct StopNbdDiskArgs {
+    /// Nbd device to stop.
+    pub nbd_device: String,
+}
+
+/// Nbd device as returned by get_nbd_disks method.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct NbdDisk {
+    pub nbd_device: String,
+    pub bdev_name: String,
+}
+
+/// Arguments of construct_vhost_blk_controller method.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConstructVhostBlkControllerArgs {
+    /// Name of the controller (and of its socket in the vhost directory).
+    pub ctrlr: String,
+    /// Bdev to export.
+    pub dev_name: String,
+    /// Export the bdev read-only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub readonly: Option<bool>,
+}
+
+/// Arguments of remove_vhost_controller method.
+#[derive(Clone, Debug, Serialize)]
+pub struct RemoveVhostControllerArgs {
+    /// Controller to remove.
+    pub ctrlr: String,
+}
+
+/// Vhost controller as returned by get_vhost_controllers method.
+#[derive(Clone, Debug, Deserialize)]
+pub struct VhostController {
+    pub ctrlr: String,
+    pub cpumask: String,
+    /// Path of the vhost-user socket.
+    pub socket: String,
+    /// Properties specific to the type of controller.
+    pub backend_specific: serde_json::Value,
+}
+
+impl VhostController {
+    /// Return name of the bdev exported by vhost-blk controller.
+    pub fn blk_bdev_name(&self) -> Option<&str> {
+        self.backend_specific["block"]["bdev"].as_str()
+    }
+}
+
+/// Arguments of get_bdevs_iostat method.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GetBdevsIostatArgs {
+    /// Return only the stats of bdev with this name (all if not set).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// IO stats of a bdev as returned by get_bdevs_iostat method.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BdevIostat {
+    pub name: String,
+    pub bytes_read: u64,
+    pub num_read_ops: u64,
+    pub bytes_written: u64,
+    pub num_write_ops: u64,
+    // ... latency ticks which are not used by us
+}
+
+/// Reply of get_bdevs_iostat method.
+#[derive(Clone, Debug, Deserialize)]
+pub struct BdevsIostat {
+    pub tick_rate: u64,
+    pub bdevs: Vec<BdevIostat>,
+}
+
+/// Arguments of construct_lvol_bdev method. The lvol store is identified
+/// either by its uuid or by its name.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ConstructLvolBdevArgs {
+    /// Name of the new lvol.
+    pub lvol_name: String,
+    /// Size of the lvol in bytes.
+    pub size: u64,
+    /// Allocate clusters on first write.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thin_provision: Option<bool>,
+    /// Uuid of the lvol store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Name of the lvol store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lvs_name: Option<String>,
+    /// How to clear the data of the lvol (none, unmap or write_zeroes).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clear_method: Option<String>,
+}
+
+/// Return the bdev with given name or all bdevs if the name is not given.
+/// A bdev which does not exist is reported as an error by SPDK.
+pub fn get_bdevs(
+    sock: &str,
+    name: Option<&str>,
+) -> Box<dyn Future<Item = Vec<Bdev>, Error = Error> + Send> {
+    // the reply can be huge if there are thousands of bdevs
+    call_with_options(
+        sock,
+        "get_bdevs",
+        Some(GetBdevsArgs {
+            name: name.map(|n| n.to_owned()),
+        }),
+        CallOptions::default().stream_reply(),
+    )
+}
+
+/// Export the bdev on the nbd device and return the name of the device.
+pub fn start_nbd_disk(
+    sock: &str,
+    args: StartNbdDiskArgs,
+) -> Box<dyn Future<Item = String, Error = Error> + Send> {
+    call(sock, "start_nbd_disk", Some(args))
+}
+
+/// Stop the nbd device. Returns true if it has been stopped.
+pub fn stop_nbd_disk(
+    sock: &str,
+    args: StopNbdDiskArgs,
+) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+    call(sock, "stop_nbd_disk", Some(args))
+}
+
+/// Return all nbd devices with exported bdevs.
+pub fn get_nbd_disks(
+    sock: &str,
+) -> Box<dyn Future<Item = Vec<NbdDisk>, Error = Error> + Send> {
+    call_with_options::<(), _>(
+        sock,
+        "get_nbd_disks",
+        None,
+        CallOptions::default().stream_reply(),
+    )
+}
+
+/// Export the bdev over vhost-user-blk socket. Returns true on success.
+pub fn construct_vhost_blk_controller(
+    sock: &str,
+    args: ConstructVhostBlkControllerArgs,
+) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+    call(sock, "construct_vhost_blk_controller", Some(args))
+}
+
+/// Remove the vhost controller and its socket. Returns true on success.
+pub fn remove_vhost_controller(
+    sock: &str,
+    args: RemoveVhostControllerArgs,
+) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+    call(sock, "remove_vhost_controller", Some(args))
+}
+
+/// Return all vhost controllers.
+pub fn get_vhost_controllers(
+    sock: &str,
+) -> Box<dyn Future<Item = Vec<VhostController>, Error = Error> + Send> {
+    call::<(), _>(sock, "get_vhost_controllers", None)
+}
+
+/// Return IO stats of the bdev with given name or of all bdevs.
+pub fn get_bdevs_iostat(
+    sock: &str,
+    name: Option<&str>,
+) -> Box<dyn Future<Item = BdevsIostat, Error = Error> + Send> {
+    call(
+        sock,
+        "get_bdevs_iostat",
+        Some(GetBdevsIostatArgs {
+            name: name.map(|n| n.to_owned()),
+        }),
+    )
+}
+
+/// Create lvol in the lvol store and return the uuid of the new lvol bdev.
+pub fn construct_lvol_bdev(
+    sock: &str,
+    args: ConstructLvolBdevArgs,
+) -> Box<dyn Future<Item = String, Error = Error> + Send> {
+    call(sock, "construct_lvol_bdev", Some(args))
+}
@@ -0,0 +1,242 @@
+//! Mock json-rpc server for tests of code talking to mayastor/SPDK.
+//!
+//! The server listens on a temporary unix domain socket and replies to the
+//! requests with canned results or errors according to the expectations
+//! given when it was started. A request matches an expectation if it has
+//! the same method and (if given) the same parameters. Expectations of the
+//! same method are tried in the order in which they were added and each of
+//! them is used the given number of times (once by default).
+//!
+//! A call of an expected method which does not match any expectation fails
+//! with an internal error (methods without expectations are not known to
+//! the server at all). `verify` (also called when the server is dropped,
+//! unless the test is panicking already) panics if there were such calls or
+//! if some expectations have not been met.
+//!
+//! ```ignore
+//! let mock = MockServer::start(vec![
+//!     Expectation::call("get_bdevs").returns(json!([])),
+//!     Expectation::call("destroy_replica")
+//!         .params(json!({"uuid": "dead"}))
+//!         .fails(RpcCode::NotFound, "Replica not found"),
+//! ]);
+//! // call(mock.socket(), ...)
+//! mock.verify();
+//! ```
+
+use crate::{
+    error::{Error, RpcCode},
+    server::Server,
+};
+use futures::Future;
+use nix::errno::Errno;
+use serde_json::Value;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    thread,
+};
+use tokio::runtime::Runtime;
+
+/// Number of the next mock server in the process (for unique socket names).
+static NEXT_SERVER: AtomicUsize = AtomicUsize::new(0);
+
+/// Expected call and the reply to it.
+#[derive(Clone, Debug)]
+pub struct Expectation {
+    method: String,
+    params: Option<Value>,
+    reply: Result<Value, (i32, String)>,
+    times: usize,
+}
+
+impl Expectation {
+    /// Expect a call of the method with any parameters, which succeeds with
+    /// null result.
+    pub fn call(method: &str) -> Self {
+        Self {
+            method: method.to_owned(),
+            params: None,
+            reply: Ok(Value::Null),
+            times: 1,
+        }
+    }
+
+    /// Match only calls with the parameters (null for calls without them).
+    pub fn params(mut self, params: Value) -> Self {
+        self.params = Some(params);
+        self
+    }
+
+    /// Reply with the result.
+    pub fn returns(mut self, result: Value) -> Self {
+        self.reply = Ok(result);
+        self
+    }
+
+    /// Reply with error.
+    pub fn fails(mut self, code: RpcCode, msg: &str) -> Self {
+        self.reply = Err((code.raw(), msg.to_owned()));
+        self
+    }
+
+    /// Reply with error with negative errno as the code, as SPDK does.
+    pub fn fails_errno(mut self, errno: Errno, msg: &str) -> Self {
+        self.reply = Err((-(errno as i32), msg.to_owned()));
+        self
+    }
+
+    /// Number of calls matching the expectation (1 by default).
+    pub fn times(mut self, times: usize) -> Self {
+        self.times = times;
+        self
+    }
+
+    fn matches(&self, method: &str, params: &Value) -> bool {
+        let params_match = match &self.params {
+            Some(expected) => expected == params,
+            None => true,
+        };
+        self.times > 0 && self.method == method && params_match
+    }
+
+    fn reply(&self) -> Result<Value, Error> {
+        match &self.reply {
+            Ok(result) => Ok(result.clone()),
+            Err((raw_code, msg)) => Err(Error::RpcError {
+                code: RpcCode::InternalError,
+                msg: msg.clone(),
+                raw_code: *raw_code,
+                data: None,
+            }),
+        }
+    }
+}
+
+/// Expectations which haven't been used up and requests which haven't
+/// matched any of them.
+#[derive(Debug)]
+struct State {
+    expected: Vec<Expectation>,
+    unexpected: Vec<String>,
+}
+
+impl State {
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, Error> {
+        match self
+            .expected
+            .iter_mut()
+            .find(|exp| exp.matches(method, &params))
+        {
+            Some(exp) => {
+                exp.times -= 1;
+                exp.reply()
+            }
+            None => {
+                let call = format!("{}({})", method, params);
+                let err = Error::rpc(
+                    RpcCode::InternalError,
+                    format!("Unexpected call {}", call),
+                );
+                self.unexpected.push(call);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Mock server serving requests in the background until it is dropped.
+pub struct MockServer {
+    socket: String,
+    state: Arc<Mutex<State>>,
+    runtime: Option<Runtime>,
+}
+
+impl MockServer {
+    /// Start serving the expected calls on a temporary socket on a runtime
+    /// of its own.
+    pub fn start(expected: Vec<Expectation>) -> Self {
+        let socket = std::env::temp_dir()
+            .join(format!(
+                "jsonrpc-mock.{}.{}.sock",
+                std::process::id(),
+                NEXT_SERVER.fetch_add(1, Ordering::Relaxed)
+            ))
+            .to_string_lossy()
+            .into_owned();
+        let mut methods: Vec<String> =
+            expected.iter().map(|exp| exp.method.clone()).collect();
+        methods.sort();
+        methods.dedup();
+        let state = Arc::new(Mutex::new(State {
+            expected,
+            unexpected: Vec::new(),
+        }));
+
+        let mut server = Server::new();
+        for method in methods {
+            let state = Arc::clone(&state);
+            let name = method.clone();
+            server.register(&method, move |params: Option<Value>| {
+                state
+                    .lock()
+                    .unwrap()
+                    .call(&name, params.unwrap_or(Value::Null))
+            });
+        }
+        let mut runtime = Runtime::new().unwrap();
+        runtime.spawn(server.listen(&socket).unwrap());
+
+        Self {
+            socket,
+            state,
+            runtime: Some(runtime),
+        }
+    }
+
+    /// Path of the socket which the server listens on.
+    pub fn socket(&self) -> &str {
+        &self.socket
+    }
+
+    /// Panic if some expectations have not been met or if there were
+    /// unexpected calls.
+    pub fn verify(&self) {
+        let state = self.state.lock().unwrap();
+        let unmet: Vec<String> = state
+            .expected
+            .iter()
+            .filter(|exp| exp.times > 0)
+            .map(|exp| match &exp.params {
+                Some(params) => format!("{}({})", exp.method, params),
+                None => exp.method.clone(),
+            })
+            .collect();
+
+        if !state.unexpected.is_empty() {
+            panic!(
+                "Unexpected json-rpc calls: {}",
+                state.unexpected.join(", ")
+            );
+        }
+        if !unmet.is_empty() {
+            panic!("Expected json-rpc calls not made: {}", unmet.join(", "));
+        }
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            let _ = runtime.shutdown_now().wait();
+        }
+        let _ = fs::remove_file(&self.socket);
+        if !thread::panicking() {
+            self.verify();
+        }
+    }
+}
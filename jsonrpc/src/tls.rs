@@ -0,0 +1,154 @@
+//! TLS of connections to `tls://host:port` and `https://` endpoints.
+//!
+//! SPDK's json-rpc server does not speak TLS itself. It is terminated by a
+//! proxy in front of its TCP socket (i.e. stunnel) or by the HTTP proxy, so
+//! that a management plane on another node can call mayastor without
+//! exposing the server to anybody who can reach the port.
+//!
+//! The certificate of the server is verified against CA certificates given
+//! by `set_tls_config` and the client authenticates with its own certificate
+//! if one is configured. Servers addressed by a host name must have the name
+//! in their certificate. Certificates with IP addresses are not supported, so
+//! servers addressed by IP address must have the name given by
+//! `TlsConfig::server_name` instead.
+
+use crate::error::Error;
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::IpAddr,
+    sync::{Arc, RwLock},
+};
+use tokio_rustls::{
+    rustls::{
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+        ClientConfig,
+    },
+    webpki::{DNSName, DNSNameRef},
+    TlsConnector,
+};
+
+lazy_static! {
+    static ref TLS_CONFIG: RwLock<Option<TlsConfig>> = RwLock::new(None);
+}
+
+/// Settings of TLS connections.
+#[derive(Clone)]
+pub struct TlsConfig {
+    config: ClientConfig,
+    server_name: Option<String>,
+}
+
+/// Open the PEM file.
+fn open_pem(path: &str) -> Result<BufReader<File>, Error> {
+    File::open(path).map(BufReader::new).map_err(|err| {
+        Error::GenericError(format!("Failed to open {}: {}", path, err))
+    })
+}
+
+impl TlsConfig {
+    /// Trust servers with certificates signed by the CA certificates in the
+    /// PEM file.
+    pub fn new(ca_file: &str) -> Result<Self, Error> {
+        let mut config = ClientConfig::new();
+        match config.root_store.add_pem_file(&mut open_pem(ca_file)?) {
+            Ok((valid, _)) if valid > 0 => (),
+            _ => {
+                return Err(Error::GenericError(format!(
+                    "No valid CA certificate in {}",
+                    ca_file
+                )))
+            }
+        }
+        Ok(Self {
+            config,
+            server_name: None,
+        })
+    }
+
+    /// Authenticate with the certificate chain in the PEM file and its key
+    /// (PKCS#8 or RSA) in another one.
+    pub fn client_cert(
+        mut self,
+        cert_file: &str,
+        key_file: &str,
+    ) -> Result<Self, Error> {
+        let chain = match certs(&mut open_pem(cert_file)?) {
+            Ok(chain) if !chain.is_empty() => chain,
+            _ => {
+                return Err(Error::GenericError(format!(
+                    "No certificate in {}",
+                    cert_file
+                )))
+            }
+        };
+        let mut keys =
+            pkcs8_private_keys(&mut open_pem(key_file)?).unwrap_or_default();
+        if keys.is_empty() {
+            keys =
+                rsa_private_keys(&mut open_pem(key_file)?).unwrap_or_default();
+        }
+        if keys.is_empty() {
+            return Err(Error::GenericError(format!(
+                "No private key in {}",
+                key_file
+            )));
+        }
+        self.config.set_single_client_cert(chain, keys.remove(0));
+        Ok(self)
+    }
+
+    /// Name expected in certificates of servers addressed by IP address.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_owned());
+        self
+    }
+}
+
+/// Set the TLS settings used by all connections to TLS endpoints from now
+/// on. Connecting to them fails until it is set.
+pub fn set_tls_config(config: TlsConfig) {
+    *TLS_CONFIG.write().unwrap() = Some(config);
+}
+
+/// Return the connector and the name of the server which must be in its
+/// certificate.
+pub(crate) fn connector(addr: &str) -> io::Result<(TlsConnector, DNSName)> {
+    let tls = TLS_CONFIG.read().unwrap();
+    let tls = tls.as_ref().ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Other, "TLS is not configured")
+    })?;
+
+    let host = match addr.rfind(':') {
+        Some(idx) => &addr[.. idx],
+        None => addr,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let name = if host.parse::<IpAddr>().is_ok() {
+        tls.server_name
+            .as_ref()
+            .map(String::as_str)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "Server name of TLS endpoint {} is not configured",
+                        addr
+                    ),
+                )
+            })?
+    } else {
+        host
+    };
+    let name = DNSNameRef::try_from_ascii_str(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid server name \"{}\"", name),
+        )
+    })?;
+
+    Ok((
+        TlsConnector::from(Arc::new(tls.config.clone())),
+        name.to_owned(),
+    ))
+}
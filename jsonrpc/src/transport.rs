@@ -0,0 +1,300 @@
+//! Transports which the json-rpc client can use to reach the server.
+//!
+//! The server is addressed by a string which is either a path to the unix
+//! domain socket or `host:port` of a TCP socket (SPDK can listen on TCP when
+//! started with `-r host:port`). Anything containing a slash is a path, so
+//! a socket in the current directory must be given as `./name`. TCP is
+//! useful for remote debugging and for running the client in a container
+//! which does not share a mount with the server's socket.
+//!
+//! `tls://host:port` is a TCP socket of a TLS proxy in front of the server,
+//! see the tls module.
+//!
+//! An URL `http://[user:password@]host[:port][/path]` addresses a json-rpc
+//! server behind HTTP proxy (SPDK's rpc_http_proxy.py), see the http module.
+//! Its `https://` variant uses TLS too.
+
+use crate::tls;
+use futures::future::{self, Either, Future};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, ToSocketAddrs},
+    sync::Mutex,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UnixStream},
+    prelude::Poll,
+};
+use tokio_rustls::{client::TlsStream, rustls::Session};
+
+/// Address of the json-rpc server.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    Unix(String),
+    Tcp(String),
+    Tls(String),
+    Http(HttpEndpoint),
+}
+
+/// json-rpc server behind HTTP proxy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpEndpoint {
+    /// host:port of the proxy
+    pub addr: String,
+    /// path which the requests are posted to
+    pub path: String,
+    /// user and password for basic authentication
+    pub auth: Option<(String, String)>,
+    /// https
+    pub tls: bool,
+}
+
+impl HttpEndpoint {
+    /// Parse the URL without the scheme.
+    fn parse(rest: &str, tls: bool) -> Self {
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => (&rest[.. idx], &rest[idx ..]),
+            None => (rest, "/"),
+        };
+        let (auth, host) = match authority.rfind('@') {
+            Some(idx) => {
+                let userinfo = &authority[.. idx];
+                let auth = match userinfo.find(':') {
+                    Some(colon) => (
+                        userinfo[.. colon].to_owned(),
+                        userinfo[colon + 1 ..].to_owned(),
+                    ),
+                    None => (userinfo.to_owned(), String::new()),
+                };
+                (Some(auth), &authority[idx + 1 ..])
+            }
+            None => (None, authority),
+        };
+        let has_port = match host.rfind(':') {
+            Some(idx) => host[idx + 1 ..].parse::<u16>().is_ok(),
+            None => false,
+        };
+        let addr = if has_port {
+            host.to_owned()
+        } else {
+            format!("{}:{}", host, if tls { 443 } else { 80 })
+        };
+
+        Self {
+            addr,
+            path: path.to_owned(),
+            auth,
+            tls,
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn parse(addr: &str) -> Self {
+        if addr.starts_with("http://") {
+            return Endpoint::Http(HttpEndpoint::parse(&addr[7 ..], false));
+        }
+        if addr.starts_with("https://") {
+            return Endpoint::Http(HttpEndpoint::parse(&addr[8 ..], true));
+        }
+        if addr.starts_with("tls://") {
+            return Endpoint::Tls(addr[6 ..].to_owned());
+        }
+        if addr.contains('/') {
+            return Endpoint::Unix(addr.to_owned());
+        }
+        match addr.rfind(':') {
+            Some(idx) if addr[idx + 1 ..].parse::<u16>().is_ok() => {
+                Endpoint::Tcp(addr.to_owned())
+            }
+            _ => Endpoint::Unix(addr.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Endpoint::Unix(path) => write!(f, "{}", path),
+            Endpoint::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Endpoint::Tls(addr) => write!(f, "tls://{}", addr),
+            // without the credentials, it ends up in logs
+            Endpoint::Http(ep) => write!(
+                f,
+                "{}://{}{}",
+                if ep.tls { "https" } else { "http" },
+                ep.addr,
+                ep.path
+            ),
+        }
+    }
+}
+
+/// Connection to the server over any of the transports. TLS session is
+/// stateful and it needs a lock to be shared by reader and writer.
+pub enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+    Tls(Mutex<TlsStream<TcpStream>>),
+}
+
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connection::Unix(s) => write!(f, "Unix({:?})", s),
+            Connection::Tcp(s) => write!(f, "Tcp({:?})", s),
+            Connection::Tls(_) => write!(f, "Tls"),
+        }
+    }
+}
+
+/// The connection is shut down when dropped, also when a call is abandoned
+/// half-way (the future of the call is dropped while the request is being
+/// written or the reply read). The server sees the end of the stream and
+/// closes its side right away, even if a copy of the descriptor has been
+/// inherited by a child process.
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // fails if the connection is not connected anymore, which is fine
+        let _ = Connection::shutdown(self, Shutdown::Both);
+    }
+}
+
+impl Connection {
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Unix(s) => s.shutdown(how),
+            Connection::Tcp(s) => s.shutdown(how),
+            Connection::Tls(s) => {
+                let mut s = s.lock().unwrap();
+                if how != Shutdown::Read {
+                    // the server tells the end of the request from an
+                    // attack truncating it by close_notify
+                    s.get_mut().1.send_close_notify();
+                    let _ = s.flush();
+                }
+                s.get_ref().0.shutdown(how)
+            }
+        }
+    }
+}
+
+/// Resolve the address. Resolution of a host name blocks, but it is done
+/// once per connection and json-rpc servers are normally given by an IP
+/// address anyway.
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?.next().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} does not resolve to any address", addr),
+        )
+    })
+}
+
+/// Connect to the TCP address.
+fn connect_tcp(
+    addr: &str,
+) -> impl Future<Item = TcpStream, Error = io::Error> + Send {
+    match resolve(addr) {
+        Ok(addr) => Either::A(TcpStream::connect(&addr).map(|s| {
+            // requests are small and we wait for the reply
+            let _ = s.set_nodelay(true);
+            s
+        })),
+        Err(err) => Either::B(future::err(err)),
+    }
+}
+
+/// Connect to the TCP address and establish TLS session.
+fn connect_tls(
+    addr: &str,
+) -> Box<dyn Future<Item = Connection, Error = io::Error> + Send> {
+    let (connector, name) = match tls::connector(addr) {
+        Ok(res) => res,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    Box::new(
+        connect_tcp(addr)
+            .and_then(move |s| connector.connect(name.as_ref(), s))
+            .map(|s| Connection::Tls(Mutex::new(s))),
+    )
+}
+
+/// Connect to the server.
+pub fn connect(
+    endpoint: &Endpoint,
+) -> Box<dyn Future<Item = Connection, Error = io::Error> + Send> {
+    match endpoint {
+        Endpoint::Unix(path) => {
+            Box::new(UnixStream::connect(path).map(Connection::Unix))
+        }
+        Endpoint::Tcp(addr) => Box::new(connect_tcp(addr).map(Connection::Tcp)),
+        Endpoint::Tls(addr) => connect_tls(addr),
+        Endpoint::Http(ep) if ep.tls => connect_tls(&ep.addr),
+        Endpoint::Http(ep) => {
+            Box::new(connect_tcp(&ep.addr).map(Connection::Tcp))
+        }
+    }
+}
+
+impl<'a> Read for &'a Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(s) => (&*s).read(buf),
+            Connection::Tcp(s) => (&*s).read(buf),
+            Connection::Tls(s) => s.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl<'a> Write for &'a Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Unix(s) => (&*s).write(buf),
+            Connection::Tcp(s) => (&*s).write(buf),
+            Connection::Tls(s) => s.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Unix(s) => (&*s).flush(),
+            Connection::Tcp(s) => (&*s).flush(),
+            Connection::Tls(s) => s.lock().unwrap().flush(),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl<'a> AsyncRead for &'a Connection {}
+
+impl<'a> AsyncWrite for &'a Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(().into())
+    }
+}
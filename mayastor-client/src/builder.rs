@@ -0,0 +1,195 @@
+//! Builders of create requests which check that mandatory fields are set and
+//! fill in sensible defaults for the rest.
+
+use crate::Error;
+use rpc::{
+    capacity::Capacity,
+    mayastor::{CreateNexusRequest, CreatePoolRequest, CreateReplicaRequest},
+};
+
+/// Default block size of a nexus in bytes.
+const DEFAULT_BLOCK_LEN: u32 = 512;
+
+fn required(field: &str, value: &Option<String>) -> Result<String, Error> {
+    match value {
+        Some(val) if !val.is_empty() => Ok(val.clone()),
+        _ => Err(Error::InvalidRequest(format!("{} is required", field))),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CreatePoolRequestBuilder {
+    name: Option<String>,
+    disks: Vec<String>,
+    block_size: u32,
+    encryption_key: String,
+}
+
+impl CreatePoolRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Add disk device to the pool (can be called repeatedly).
+    pub fn disk(mut self, disk: &str) -> Self {
+        self.disks.push(disk.to_owned());
+        self
+    }
+
+    /// Block size for disks which are files (autodetected otherwise).
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Encrypt the pool at rest with the key (16 bytes).
+    pub fn encryption_key(mut self, key: &str) -> Self {
+        self.encryption_key = key.to_owned();
+        self
+    }
+
+    pub fn build(self) -> Result<CreatePoolRequest, Error> {
+        let name = required("pool name", &self.name)?;
+        if self.disks.is_empty() {
+            return Err(Error::InvalidRequest(
+                "at least one disk is required".to_owned(),
+            ));
+        }
+        Ok(CreatePoolRequest {
+            name,
+            disks: self.disks,
+            block_size: self.block_size,
+            encryption_key: self.encryption_key,
+        })
+    }
+}
+
+/// Volumes are backed by a single replica on a storage pool for now, which
+/// is exactly what CreateReplicaRequest creates.
+#[derive(Debug, Default)]
+pub struct CreateVolumeRequestBuilder {
+    uuid: Option<String>,
+    pool: Option<String>,
+    size: u64,
+    thin: bool,
+    compress: bool,
+}
+
+impl CreateVolumeRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn uuid(mut self, uuid: &str) -> Self {
+        self.uuid = Some(uuid.to_owned());
+        self
+    }
+
+    pub fn pool(mut self, pool: &str) -> Self {
+        self.pool = Some(pool.to_owned());
+        self
+    }
+
+    /// Size of the volume (in bytes if given by a number).
+    pub fn size<S: Into<Capacity>>(mut self, size: S) -> Self {
+        self.size = size.into().bytes();
+        self
+    }
+
+    pub fn thin(mut self, thin: bool) -> Self {
+        self.thin = thin;
+        self
+    }
+
+    /// Compress data of the volume (it is thin provisioned then).
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn build(self) -> Result<CreateReplicaRequest, Error> {
+        let uuid = required("volume uuid", &self.uuid)?;
+        let pool = required("pool name", &self.pool)?;
+        if self.size == 0 {
+            return Err(Error::InvalidRequest(
+                "volume size must be greater than zero".to_owned(),
+            ));
+        }
+        Ok(CreateReplicaRequest {
+            uuid,
+            pool,
+            size: self.size,
+            thin: self.thin,
+            compress: self.compress,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CreateNexusRequestBuilder {
+    uuid: Option<String>,
+    name: Option<String>,
+    size: u64,
+    block_len: Option<u32>,
+    replicas: Vec<String>,
+}
+
+impl CreateNexusRequestBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn uuid(mut self, uuid: &str) -> Self {
+        self.uuid = Some(uuid.to_owned());
+        self
+    }
+
+    /// Name of the nexus (defaults to uuid).
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_owned());
+        self
+    }
+
+    /// Size of the nexus (in bytes if given by a number).
+    pub fn size<S: Into<Capacity>>(mut self, size: S) -> Self {
+        self.size = size.into().bytes();
+        self
+    }
+
+    pub fn block_len(mut self, block_len: u32) -> Self {
+        self.block_len = Some(block_len);
+        self
+    }
+
+    /// Add uri of a replica to the nexus (can be called repeatedly).
+    pub fn replica(mut self, uri: &str) -> Self {
+        self.replicas.push(uri.to_owned());
+        self
+    }
+
+    pub fn build(self) -> Result<CreateNexusRequest, Error> {
+        let uuid = required("nexus uuid", &self.uuid)?;
+        if self.size == 0 {
+            return Err(Error::InvalidRequest(
+                "nexus size must be greater than zero".to_owned(),
+            ));
+        }
+        if self.replicas.is_empty() {
+            return Err(Error::InvalidRequest(
+                "at least one replica is required".to_owned(),
+            ));
+        }
+        Ok(CreateNexusRequest {
+            name: self.name.unwrap_or_else(|| uuid.clone()),
+            uuid,
+            block_len: self.block_len.unwrap_or(DEFAULT_BLOCK_LEN),
+            size: self.size,
+            replicas: self.replicas,
+        })
+    }
+}
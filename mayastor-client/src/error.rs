@@ -0,0 +1,59 @@
+//! Errors returned by the client. Errors reported by the server keep the gRPC
+//! status code, so that the caller can tell apart i.e. a missing pool from a
+//! failure of the server.
+
+use std::{convert::From, fmt};
+use tower_grpc::{Code, Status};
+
+#[derive(Debug)]
+pub enum Error {
+    /// failed to connect to the server
+    ConnectError { endpoint: String, msg: String },
+    /// request was not sent because it is incomplete or invalid
+    InvalidRequest(String),
+    /// server replied with error status
+    GrpcError { code: Code, msg: String },
+}
+
+impl Error {
+    /// Return gRPC status code of the error (if it came from the server).
+    pub fn code(&self) -> Option<Code> {
+        match self {
+            Error::GrpcError {
+                code, ..
+            } => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConnectError {
+                endpoint,
+                msg,
+            } => write!(f, "Error connecting to {}: {}", endpoint, msg),
+            Error::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
+            Error::GrpcError {
+                code,
+                msg,
+            } => write!(f, "gRPC error {:?}: {}", code, msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        None
+    }
+}
+
+impl From<Status> for Error {
+    fn from(status: Status) -> Self {
+        Error::GrpcError {
+            code: status.code(),
+            msg: status.message().to_owned(),
+        }
+    }
+}
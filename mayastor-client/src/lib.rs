@@ -0,0 +1,338 @@
+//! Client library for the gRPC API of mayastor.
+//!
+//! It wraps the generated gRPC client with functions which take and return
+//! plain proto messages instead of tower requests and responses, and which
+//! fail with typed errors. Create requests with many fields can be assembled
+//! by builders, which validate the request before it is sent.
+//!
+//! ```ignore
+//! let fut = mayastor_client::connect("127.0.0.1:10124").and_then(|client| {
+//!     let req = CreateVolumeRequestBuilder::new()
+//!         .uuid("dbe4d7eb-118a-4d15-b789-a18d9af6ff21")
+//!         .pool("pool")
+//!         .size(Capacity::from_mib(64))
+//!         .build();
+//!     future::result(req).and_then(move |req| client.create_volume(req))
+//! });
+//! ```
+
+use futures::{future, Future};
+use hyper::client::connect::{Destination, HttpConnector};
+use rpc::{mayastor::*, service::client::Mayastor};
+use tower_grpc::{BoxBody, Request, Response, Status};
+use tower_hyper::{client, util, Connection};
+use tower_request_modifier::{Builder, RequestModifier};
+use tower_util::MakeService;
+
+pub use builder::{
+    CreateNexusRequestBuilder,
+    CreatePoolRequestBuilder,
+    CreateVolumeRequestBuilder,
+};
+pub use error::Error;
+pub use rpc::capacity::Capacity;
+/// Proto messages used by the client functions.
+pub use rpc::mayastor as types;
+
+mod builder;
+mod error;
+#[cfg(test)]
+mod test;
+
+type GrpcClient = Mayastor<RequestModifier<Connection<BoxBody>, BoxBody>>;
+
+pub type BoxFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+
+/// Connect to mayastor gRPC server listening at endpoint (host:port).
+pub fn connect(endpoint: &str) -> BoxFuture<MayastorClient> {
+    let endpoint = endpoint.to_owned();
+    let connect_error = {
+        let endpoint = endpoint.clone();
+        move |msg: String| Error::ConnectError {
+            endpoint: endpoint.clone(),
+            msg,
+        }
+    };
+
+    let uri: http::Uri = match format!("http://{}", endpoint).parse() {
+        Ok(uri) => uri,
+        Err(err) => {
+            return Box::new(future::err(connect_error(err.to_string())))
+        }
+    };
+    let dst = match Destination::try_from_uri(uri.clone()) {
+        Ok(dst) => dst,
+        Err(err) => {
+            return Box::new(future::err(connect_error(err.to_string())))
+        }
+    };
+    let connector = util::Connector::new(HttpConnector::new(1));
+    let settings = client::Builder::new().http2_only(true).clone();
+    let mut make_client = client::Connect::with_builder(connector, settings);
+
+    Box::new(
+        make_client
+            .make_service(dst)
+            .map_err({
+                let connect_error = connect_error.clone();
+                move |err| connect_error(err.to_string())
+            })
+            .and_then(move |conn| {
+                Builder::new()
+                    .set_origin(uri)
+                    .build(conn)
+                    .map_err(|_| connect_error("invalid origin".to_owned()))
+            })
+            .map(|conn| MayastorClient {
+                client: Mayastor::new(conn),
+            }),
+    )
+}
+
+/// Handle of a connection to mayastor. It is cheap to clone and the clones
+/// share the same connection.
+#[derive(Clone)]
+pub struct MayastorClient {
+    client: GrpcClient,
+}
+
+impl MayastorClient {
+    /// Wait for the connection to be ready and call the gRPC method.
+    fn call<T, F, R>(&self, method: F) -> BoxFuture<T>
+    where
+        F: FnOnce(&mut GrpcClient) -> R + Send + 'static,
+        R: Future<Item = Response<T>, Error = Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        Box::new(self.client.clone().ready().map_err(Error::from).and_then(
+            move |mut client| {
+                method(&mut client)
+                    .map(|resp| resp.into_inner())
+                    .map_err(Error::from)
+            },
+        ))
+    }
+
+    pub fn create_pool(&self, req: CreatePoolRequest) -> BoxFuture<()> {
+        Box::new(
+            self.call(move |c| c.create_pool(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    pub fn destroy_pool(&self, name: &str) -> BoxFuture<()> {
+        let req = DestroyPoolRequest {
+            name: name.to_owned(),
+        };
+        Box::new(
+            self.call(move |c| c.destroy_pool(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    pub fn list_pools(&self) -> BoxFuture<Vec<Pool>> {
+        Box::new(
+            self.call(|c| c.list_pools(Request::new(Null {})))
+                .map(|reply| reply.pools),
+        )
+    }
+
+    /// Create a volume, which is a replica on the storage pool.
+    pub fn create_volume(&self, req: CreateReplicaRequest) -> BoxFuture<()> {
+        Box::new(
+            self.call(move |c| c.create_replica(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    pub fn destroy_volume(&self, uuid: &str) -> BoxFuture<()> {
+        let req = DestroyReplicaRequest {
+            uuid: uuid.to_owned(),
+        };
+        Box::new(
+            self.call(move |c| c.destroy_replica(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    pub fn list_volumes(&self) -> BoxFuture<Vec<Replica>> {
+        Box::new(
+            self.call(|c| c.list_replicas(Request::new(Null {})))
+                .map(|reply| reply.replicas),
+        )
+    }
+
+    pub fn stat_volumes(&self) -> BoxFuture<Vec<ReplicaStats>> {
+        Box::new(
+            self.call(|c| c.stat_replicas(Request::new(Null {})))
+                .map(|reply| reply.replicas),
+        )
+    }
+
+    /// Create a nexus and return its name.
+    pub fn create_nexus(&self, req: CreateNexusRequest) -> BoxFuture<String> {
+        Box::new(
+            self.call(move |c| c.create_nexus(Request::new(req)))
+                .map(|reply| reply.name),
+        )
+    }
+
+    pub fn destroy_nexus(&self, name: &str) -> BoxFuture<()> {
+        let req = DestroyNexusRequest {
+            name: name.to_owned(),
+        };
+        Box::new(
+            self.call(move |c| c.destroy_nexus(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    pub fn list_nexus(&self) -> BoxFuture<Vec<Nexus>> {
+        Box::new(
+            self.call(|c| c.list_nexus(Request::new(Null {})))
+                .map(|reply| reply.nexus_list),
+        )
+    }
+
+    /// Publish the nexus as nbd device or vhost-user socket and return path
+    /// of the device (socket).
+    pub fn publish_nexus(
+        &self,
+        name: &str,
+        nbd_device: &str,
+        share: ShareProtocol,
+    ) -> BoxFuture<String> {
+        let req = PublishNexusRequest {
+            bdev_name: name.to_owned(),
+            nbd_device: nbd_device.to_owned(),
+            share: share as i32,
+        };
+        Box::new(
+            self.call(move |c| c.publish_nexus(Request::new(req)))
+                .map(|reply| reply.device_path),
+        )
+    }
+
+    /// Stop exporting the nexus.
+    pub fn unpublish_nexus(&self, name: &str) -> BoxFuture<()> {
+        let req = UnpublishNexusRequest {
+            bdev_name: name.to_owned(),
+        };
+        Box::new(
+            self.call(move |c| c.unpublish_nexus(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    /// Return vhost-user controllers with IO stats of the exported nexus.
+    pub fn list_vhost_controllers(&self) -> BoxFuture<Vec<VhostController>> {
+        Box::new(
+            self.call(|c| c.list_vhost_controllers(Request::new(Null {})))
+                .map(|reply| reply.controllers),
+        )
+    }
+
+    /// Take the child of the nexus offline or bring it back online.
+    pub fn set_child_online(
+        &self,
+        name: &str,
+        child_name: &str,
+        online: bool,
+    ) -> BoxFuture<()> {
+        let action = if online {
+            ChildAction::Online
+        } else {
+            ChildAction::Offline
+        };
+        let req = ChildNexusRequest {
+            name: name.to_owned(),
+            child_name: child_name.to_owned(),
+            action: action as i32,
+        };
+        Box::new(
+            self.call(move |c| c.child_operation(Request::new(req)))
+                .map(|_| ()),
+        )
+    }
+
+    /// Save config of the data path to a file on the node. The path is
+    /// relative to the directory of mayastor's default config file (empty
+    /// path means the default).
+    pub fn save_config(&self, path: &str) -> BoxFuture<SaveConfigReply> {
+        let req = SaveConfigRequest {
+            path: path.to_owned(),
+        };
+        self.call(move |c| c.save_config(Request::new(req)))
+    }
+
+    /// Re-create pools and nexus from the config saved on the node.
+    pub fn load_config(&self, path: &str) -> BoxFuture<LoadConfigReply> {
+        let req = LoadConfigRequest {
+            path: path.to_owned(),
+        };
+        self.call(move |c| c.load_config(Request::new(req)))
+    }
+
+    /// Report drift of the data path from the config saved on the node and
+    /// re-create missing objects if `reapply` is true.
+    pub fn check_config(
+        &self,
+        path: &str,
+        reapply: bool,
+    ) -> BoxFuture<CheckConfigReply> {
+        let req = CheckConfigRequest {
+            path: path.to_owned(),
+            reapply,
+        };
+        self.call(move |c| c.check_config(Request::new(req)))
+    }
+
+    /// Run fio benchmark on a volume which is staged on the node.
+    pub fn benchmark_volume(
+        &self,
+        req: BenchmarkVolumeRequest,
+    ) -> BoxFuture<BenchmarkVolumeReply> {
+        self.call(move |c| c.benchmark_volume(Request::new(req)))
+    }
+
+    /// Return recent lifecycle operations on a volume on the node.
+    pub fn get_volume_history(
+        &self,
+        req: GetVolumeHistoryRequest,
+    ) -> BoxFuture<GetVolumeHistoryReply> {
+        self.call(move |c| c.get_volume_history(Request::new(req)))
+    }
+
+    /// Stop control operations on the node and wait for those in progress
+    /// to finish (timeout in seconds).
+    pub fn quiesce_io(&self, timeout: u32) -> BoxFuture<QuiesceIoReply> {
+        let req = QuiesceIoRequest {
+            timeout,
+        };
+        self.call(move |c| c.quiesce_io(Request::new(req)))
+    }
+
+    /// Accept control operations on the node again.
+    pub fn resume_io(&self) -> BoxFuture<()> {
+        Box::new(
+            self.call(|c| c.resume_io(Request::new(Null {})))
+                .map(|_| ()),
+        )
+    }
+
+    /// Run the reconcilers on the node, pool or volume now and return what
+    /// they have found and fixed (nothing is fixed if `dry_run` is true).
+    pub fn reconcile_now(
+        &self,
+        scope: ReconcileScope,
+        name: &str,
+        dry_run: bool,
+    ) -> BoxFuture<ReconcileNowReply> {
+        let req = ReconcileNowRequest {
+            scope: scope as i32,
+            name: name.to_owned(),
+            dry_run,
+        };
+        self.call(move |c| c.reconcile_now(Request::new(req)))
+    }
+}
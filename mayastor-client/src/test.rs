@@ -0,0 +1,96 @@
+//! Unit tests of request builders. Calls to the server are exercised by
+//! mayastor-test suite.
+
+use crate::{
+    Capacity,
+    CreateNexusRequestBuilder,
+    CreatePoolRequestBuilder,
+    CreateVolumeRequestBuilder,
+    Error,
+};
+
+const UUID: &str = "dbe4d7eb-118a-4d15-b789-a18d9af6ff21";
+
+#[test]
+fn volume_request() {
+    let req = CreateVolumeRequestBuilder::new()
+        .uuid(UUID)
+        .pool("pool")
+        .size(1024)
+        .thin(true)
+        .build()
+        .unwrap();
+
+    assert_eq!(req.uuid, UUID);
+    assert_eq!(req.pool, "pool");
+    assert_eq!(req.size, 1024);
+    assert!(req.thin);
+}
+
+#[test]
+fn volume_request_capacity() {
+    let req = CreateVolumeRequestBuilder::new()
+        .uuid(UUID)
+        .pool("pool")
+        .size(Capacity::from_gib(10))
+        .build()
+        .unwrap();
+    assert_eq!(req.size, 10 * 1024 * 1024 * 1024);
+
+    let req = CreateVolumeRequestBuilder::new()
+        .uuid(UUID)
+        .pool("pool")
+        .size("1.5GiB".parse::<Capacity>().unwrap())
+        .build()
+        .unwrap();
+    assert_eq!(req.size, 3 * 512 * 1024 * 1024);
+}
+
+#[test]
+fn volume_request_missing_pool() {
+    match CreateVolumeRequestBuilder::new()
+        .uuid(UUID)
+        .size(1024)
+        .build()
+    {
+        Err(Error::InvalidRequest(msg)) => {
+            assert_eq!(msg, "pool name is required")
+        }
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn volume_request_zero_size() {
+    match CreateVolumeRequestBuilder::new()
+        .uuid(UUID)
+        .pool("pool")
+        .build()
+    {
+        Err(Error::InvalidRequest(_)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn pool_request_without_disks() {
+    match CreatePoolRequestBuilder::new().name("pool").build() {
+        Err(Error::InvalidRequest(_)) => (),
+        res => panic!("Unexpected result: {:?}", res),
+    }
+}
+
+#[test]
+fn nexus_request_defaults() {
+    let req = CreateNexusRequestBuilder::new()
+        .uuid(UUID)
+        .size(1024)
+        .replica("bdev:///replica1")
+        .replica("bdev:///replica2")
+        .build()
+        .unwrap();
+
+    assert_eq!(req.name, UUID);
+    assert_eq!(req.block_len, 512);
+    assert_eq!(req.replicas.len(), 2);
+}
@@ -13,10 +13,12 @@ const sudo = require('./sudo');
 const SOCK = '/tmp/mayastor_test.sock';
 const CONFIG_PATH = '/tmp/mayastor_test.cfg';
 const GRPC_PORT = 10777;
+const READONLY_PORT = 10778;
 const CSI_ENDPOINT = '127.0.0.1:13987';
 const CSI_ID = 'test-node-id';
 
 var endpoint = '127.0.0.1:' + GRPC_PORT;
+var readonlyEndpoint = '127.0.0.1:' + READONLY_PORT;
 var mayastorProc;
 var mayastorGrpcProc;
 var mayastorOutput = [];
@@ -96,10 +98,18 @@ function startMayastorGrpc(done) {
       '127.0.0.1',
       '-p',
       GRPC_PORT.toString(),
+      '--readonly-port',
+      READONLY_PORT.toString(),
       '-c',
       CSI_ENDPOINT,
       '-s',
       SOCK,
+      // catch deviations of mayastor from the json-rpc spec
+      '--rpc-validation',
+      'strict',
+      // the tests publish volumes to /tmp
+      '--publish-root',
+      '/tmp',
     ],
     {},
     'mayastor-agent'
@@ -228,6 +238,38 @@ function dumbCommand(method, args, done) {
   );
 }
 
+// Simulate surprise disconnect of the nbd device. SPDK disconnects the
+// device in the kernel and forgets it as if the connection had been broken.
+function disconnectNbd(device, done) {
+  rpcCommand('stop_nbd_disk ' + device, done);
+}
+
+// Simulate disappearance of the device node (i.e. removed by udev) by moving
+// it aside. Device which has been hidden already is not an error.
+function hideDevice(device, done) {
+  let proc = sudo(['mv', device, device + '.hidden']);
+  proc.once('close', (code, signal) => {
+    if (code != 0 && !fs.existsSync(device + '.hidden')) {
+      return done(new Error('Failed to hide ' + device));
+    }
+    done();
+  });
+}
+
+// Restore the device node hidden by hideDevice().
+function restoreDevice(device, done) {
+  if (!fs.existsSync(device + '.hidden')) {
+    return done();
+  }
+  let proc = sudo(['mv', device + '.hidden', device]);
+  proc.once('close', (code, signal) => {
+    if (code != 0) {
+      return done(new Error('Failed to restore ' + device));
+    }
+    done();
+  });
+}
+
 module.exports = {
   CSI_ENDPOINT,
   CSI_ID,
@@ -237,6 +279,10 @@ module.exports = {
   waitForMayastor,
   restartMayastor,
   endpoint,
+  readonlyEndpoint,
   rpcCommand,
   dumbCommand,
+  disconnectNbd,
+  hideDevice,
+  restoreDevice,
 };
@@ -320,10 +320,11 @@ describe('csi', function() {
     it('get capabilities', done => {
       client.nodeGetCapabilities({}, (err, res) => {
         if (err) return done(err);
-        assert.lengthOf(res.capabilities, 2);
+        assert.lengthOf(res.capabilities, 3);
         assert.equal(res.capabilities[0].type, 'rpc');
         assert.equal(res.capabilities[0].rpc.type, 'GET_VOLUME_STATS');
         assert.equal(res.capabilities[1].rpc.type, 'STAGE_UNSTAGE_VOLUME');
+        assert.equal(res.capabilities[2].rpc.type, 'VOLUME_CONDITION');
         done();
       });
     });
@@ -391,6 +392,8 @@ describe('csi', function() {
           // TODO: These are not available yet:
           //assert.equal(res.usage[0].available, 1);
           //assert.equal(res.usage[0].used, 0);
+          // the condition is set even without the read canary
+          assert.isFalse(res.volume_condition.abnormal);
           done();
         }
       );
@@ -752,6 +755,32 @@ describe('csi', function() {
         );
       });
 
+      it('should fail to publish outside of the allowed directories', done => {
+        let args = {
+          volume_id: UUID4,
+          staging_target_path: mountTarget,
+          target_path: '/var/mayastor-bind',
+          volume_capability: {
+            access_mode: {
+              mode: 'MULTI_NODE_READER_ONLY',
+            },
+            mount: {
+              fs_type: 'xfs',
+            },
+          },
+          readonly: true,
+        };
+
+        client.nodePublishVolume(
+          args,
+          shouldFailWith(grpc.status.INVALID_ARGUMENT, err => {
+            if (err) return done(err);
+            assert.isFalse(fs.existsSync(args.target_path));
+            done();
+          })
+        );
+      });
+
       it('should fail to publish the volume as rw', done => {
         let args = {
           volume_id: UUID4,
@@ -940,4 +969,253 @@ describe('csi', function() {
       });
     });
   });
+
+  describe('raw block volume', function() {
+    var client;
+    var stagingPath = '/tmp/target6';
+    var blockTarget = '/tmp/block6';
+    // nbd device of UUID1 created in the top-level before hook
+    var device = '/dev/nbd0';
+    var capability = {
+      access_mode: {
+        mode: 'SINGLE_NODE_WRITER',
+      },
+      block: {},
+    };
+
+    function getCondition(done) {
+      client.nodeGetVolumeStats(
+        {
+          volume_id: UUID1,
+          volume_path: blockTarget,
+        },
+        (err, res) => {
+          if (err) return done(err);
+          done(null, res.volume_condition);
+        }
+      );
+    }
+
+    function cleanBlockTarget(done) {
+      let proc = sudo(['umount', '-f', blockTarget]);
+      proc.once('close', () => {
+        sudo(['rm', '-f', blockTarget]).once('close', () => done());
+      });
+    }
+
+    before(done => {
+      client = createCsiClient('Node');
+      cleanBlockTarget(() => {
+        cleanPublishDir(stagingPath, () => {
+          createPublishDir(stagingPath);
+          done();
+        });
+      });
+    });
+
+    after(done => {
+      if (client != null) {
+        client.close();
+      }
+      cleanBlockTarget(() => cleanPublishDir(stagingPath, done));
+    });
+
+    it('should stage volume without mounting it', done => {
+      client.nodeStageVolume(
+        {
+          volume_id: UUID1,
+          publish_context: {},
+          staging_target_path: stagingPath,
+          volume_capability: capability,
+          readonly: false,
+          secrets: {},
+          volume_context: { direct_io: 'true' },
+        },
+        err => {
+          if (err) return done(err);
+          assert.isUndefined(getFsType(stagingPath));
+          done();
+        }
+      );
+    });
+
+    it('should publish the device at the target path', done => {
+      let args = {
+        volume_id: UUID1,
+        staging_target_path: stagingPath,
+        target_path: blockTarget,
+        volume_capability: capability,
+        readonly: false,
+      };
+
+      client.nodePublishVolume(args, err => {
+        if (err) return done(err);
+        let stat = fs.statSync(blockTarget);
+        assert.isTrue(stat.isBlockDevice());
+        assert.equal(stat.rdev, fs.statSync(device).rdev);
+        // re-publish should succeed (idempotent)
+        client.nodePublishVolume(args, done);
+      });
+    });
+
+    it('should report open mode of the device in volume condition', done => {
+      getCondition((err, condition) => {
+        if (err) return done(err);
+        assert.isFalse(condition.abnormal);
+        assert.include(condition.message, 'is not open');
+
+        // buffered open of a volume which asks for O_DIRECT is abnormal
+        let proc = sudo(['sh', '-c', `exec 3<${blockTarget}; sleep 5`]);
+        async.retry(
+          { times: 20, interval: 200 },
+          next => {
+            getCondition((err, condition) => {
+              if (err) return next(err);
+              if (!condition.abnormal) return next(new Error('not abnormal'));
+              assert.include(condition.message, 'open buffered');
+              next();
+            });
+          },
+          err => {
+            proc.once('close', () => done(err));
+          }
+        );
+      });
+    });
+
+    it('should unpublish the device', done => {
+      client.nodeUnpublishVolume(
+        {
+          volume_id: UUID1,
+          target_path: blockTarget,
+        },
+        err => {
+          if (err) return done(err);
+          assert.isFalse(fs.existsSync(blockTarget));
+          done();
+        }
+      );
+    });
+
+    it('should unstage volume', done => {
+      client.nodeUnstageVolume(
+        {
+          volume_id: UUID1,
+          staging_target_path: stagingPath,
+        },
+        err => {
+          if (err) return done(err);
+          // without the staging record the open mode is not reported
+          getCondition((err, condition) => {
+            if (err) return done(err);
+            assert.isFalse(condition.abnormal);
+            assert.notInclude(condition.message, device);
+            done();
+          });
+        }
+      );
+    });
+  });
+
+  // Failure injection: the nbd device goes away under a staged volume.
+  describe('surprise device removal', function() {
+    var client;
+    var mountTarget = '/tmp/target5';
+    // nbd device of UUID2 created in the top-level before hook
+    var device = '/dev/nbd1';
+
+    before(done => {
+      client = createCsiClient('Node');
+      cleanPublishDir(mountTarget, () => {
+        createPublishDir(mountTarget);
+        done();
+      });
+    });
+
+    after(done => {
+      if (client != null) {
+        client.close();
+      }
+      common.restoreDevice(device, () => {
+        cleanPublishDir(mountTarget, done);
+      });
+    });
+
+    it('should be able to stage volume', done => {
+      client.nodeStageVolume(
+        {
+          volume_id: UUID2,
+          publish_context: {},
+          staging_target_path: mountTarget,
+          volume_capability: {
+            access_mode: {
+              mode: 'MULTI_NODE_READER_ONLY',
+            },
+            mount: {
+              fs_type: 'ext4',
+            },
+          },
+          readonly: false,
+          secrets: {},
+          volume_context: {},
+        },
+        err => {
+          if (err) return done(err);
+          assert.equal(getFsType(mountTarget), 'ext4');
+          done();
+        }
+      );
+    });
+
+    it('should get volume stats when the device node has disappeared', done => {
+      async.series(
+        [
+          next => common.hideDevice(device, next),
+          next => {
+            client.nodeGetVolumeStats(
+              {
+                volume_id: UUID2,
+                volume_path: mountTarget,
+              },
+              (err, res) => {
+                if (err) return next(err);
+                assert.lengthOf(res.usage, 1);
+                assert.equal(res.usage[0].total, 16 * 1024 * 1024);
+                next();
+              }
+            );
+          },
+          next => common.restoreDevice(device, next),
+        ],
+        done
+      );
+    });
+
+    it('should fail to get volume stats after nbd disconnect', done => {
+      common.disconnectNbd(device, err => {
+        if (err) return done(err);
+        client.nodeGetVolumeStats(
+          {
+            volume_id: UUID2,
+            volume_path: mountTarget,
+          },
+          shouldFailWith(grpc.status.NOT_FOUND, done)
+        );
+      });
+    });
+
+    it('should unstage volume after nbd disconnect', done => {
+      client.nodeUnstageVolume(
+        {
+          volume_id: UUID2,
+          staging_target_path: mountTarget,
+        },
+        err => {
+          if (err) return done(err);
+          assert.isUndefined(getFsType(mountTarget));
+          done();
+        }
+      );
+    });
+  });
 });
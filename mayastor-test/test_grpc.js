@@ -18,10 +18,16 @@ const sudo = require('./sudo');
 
 const POOL = 'tpool';
 const DISK_FILE = '/tmp/mayastor_test_disk';
+// config file relative to mayastor's config dir
+const CONFIG = 'mayastor_test_config.json';
+const CONFIG_DIR = '/var/local/mayastor';
 // arbitrary uuid used for creating a replica
 const UUID = 'dbe4d7eb-118a-4d15-b789-a18d9af6ff21';
 // uuid without the last digit for generating a set of uuids
 const BASE_UUID = 'c35fa4dd-d527-4b7b-9cf0-436b8bb0ba7';
+// uuid of the template and uuid without the last digit for its clones
+const TEMPLATE_UUID = '6c2b3a46-5a6c-4f4b-8a0f-6f2bb9e4c3d1';
+const CLONE_BASE_UUID = '9f1c7e1e-3f53-4f7a-b1b8-2c4ed7d0a6a';
 
 // tunables of the test suite
 var endpoint = process.env.MAYASTOR_ENDPOINT;
@@ -30,6 +36,31 @@ var disks = process.env.MAYASTOR_DISKS;
 var remote; // true if the test suite is run against a remote grpc server
 var implicitDisk;
 
+// Create client of mayastor service at the endpoint.
+function createMayastorClient(endpoint) {
+  return createClient(
+    {
+      protoPath: path.join(
+        __dirname,
+        '..',
+        'rpc',
+        'proto',
+        'mayastor_service.proto'
+      ),
+      packageName: 'mayastor_service',
+      serviceName: 'Mayastor',
+      options: {
+        keepCase: true,
+        longs: String,
+        enums: String,
+        defaults: true,
+        oneofs: true,
+      },
+    },
+    endpoint
+  );
+}
+
 // Create fake disk device used for testing (size 100M)
 function createTestDisk(done) {
   exec('truncate -s 100m ' + DISK_FILE, (err, stdout, stderr) => {
@@ -101,27 +132,7 @@ describe('grpc', function() {
     }
 
     before(done => {
-      client = createClient(
-        {
-          protoPath: path.join(
-            __dirname,
-            '..',
-            'rpc',
-            'proto',
-            'mayastor_service.proto'
-          ),
-          packageName: 'mayastor_service',
-          serviceName: 'Mayastor',
-          options: {
-            keepCase: true,
-            longs: String,
-            enums: String,
-            defaults: true,
-            oneofs: true,
-          },
-        },
-        endpoint
-      );
+      client = createMayastorClient(endpoint);
 
       if (!client) {
         return done(new Error('Failed to initialize grpc client'));
@@ -259,12 +270,84 @@ describe('grpc', function() {
           assert.equal(Math.floor(res.capacity / (1024 * 1024)), 96);
         }
         assert.equal(res.used, 0);
+        assert.equal(res.usage.volumes, 0);
+        assert.equal(res.usage.snapshots, 0);
+        // super block and other metadata are not part of the capacity
+        assert.isAbove(parseInt(res.usage.metadata), 0);
         assert.equal(res.state, 'ONLINE');
         assert.deepEqual(res.disks, disks);
         done();
       });
     });
 
+    describe('read-only endpoint', function() {
+      var roClient;
+
+      before(function() {
+        // the endpoint of a remote server is not known
+        if (remote) this.skip();
+        roClient = createMayastorClient(common.readonlyEndpoint);
+        if (!roClient) throw new Error('Failed to initialize grpc client');
+      });
+
+      it('should list the pool', done => {
+        roClient.listPools({}, (err, res) => {
+          if (err) return done(err);
+          assert.lengthOf(res.pools.filter(ent => ent.name == POOL), 1);
+          done();
+        });
+      });
+
+      it('should not destroy the pool', done => {
+        roClient.destroyPool({ name: POOL }, (err, res) => {
+          assert.equal(err.code, grpc.status.PERMISSION_DENIED);
+          done();
+        });
+      });
+
+      it('should not resume the node', done => {
+        roClient.resumeIo({}, (err, res) => {
+          assert.equal(err.code, grpc.status.PERMISSION_DENIED);
+          done();
+        });
+      });
+    });
+
+    it('should return recent log records', done => {
+      let records = [];
+      let stream = client.tailLogs({ level: 'info', module: '', follow: false });
+
+      stream.on('data', rec => records.push(rec));
+      stream.on('error', done);
+      stream.on('end', () => {
+        assert.isAbove(records.length, 0);
+        records.forEach(rec => assert.notEqual(rec.level, 'debug'));
+        assert(
+          records.find(rec => rec.message.match(/Created or imported pool/)),
+          'Missing record about created pool'
+        );
+        done();
+      });
+    });
+
+    it('should return support information', done => {
+      client.getSupportInfo({}, (err, res) => {
+        if (err) return done(err);
+        let names = res.files.map(f => f.name);
+        assert.includeMembers(names, [
+          'config.json',
+          'mounts.txt',
+          'staging.json',
+          'nbd_disks.json',
+        ]);
+        let config = JSON.parse(
+          res.files.find(f => f.name == 'config.json').content
+        );
+        assert.equal(config.node_name, 'test-node-id');
+        done();
+      });
+    });
+
     it('should create the replica', done => {
       client.createReplica(
         {
@@ -465,6 +548,154 @@ describe('grpc', function() {
       });
     });
 
+    describe('templates', function() {
+      // the replica which the template is made from becomes a clone too
+      const SOURCE = CLONE_BASE_UUID + '0';
+      const CLONES = [CLONE_BASE_UUID + '1', CLONE_BASE_UUID + '2'];
+
+      function getTemplate(cb) {
+        client.listTemplates({}, (err, res) => {
+          if (err) return cb(err);
+          cb(null, res.templates.find(t => t.uuid == TEMPLATE_UUID));
+        });
+      }
+
+      it('should create the source replica', done => {
+        client.createReplica(
+          {
+            uuid: SOURCE,
+            pool: POOL,
+            thin: true,
+            size: 8 * (1024 * 1024),
+          },
+          done
+        );
+      });
+
+      it('should not create a template from a published replica', done => {
+        client.createBlkdev({ uuid: SOURCE }, (err, res) => {
+          if (err) return done(err);
+          client.createTemplate(
+            { uuid: TEMPLATE_UUID, replica: SOURCE },
+            (err, res) => {
+              if (!err) return done(new Error('Expected error'));
+              assert.equal(err.code, grpc.status.INVALID_ARGUMENT);
+              client.destroyBlkdev({ uuid: SOURCE }, done);
+            }
+          );
+        });
+      });
+
+      it('should create the template', done => {
+        client.createTemplate({ uuid: TEMPLATE_UUID, replica: SOURCE }, done);
+      });
+
+      it('should list the template', done => {
+        getTemplate((err, template) => {
+          if (err) return done(err);
+          assert.isDefined(template);
+          assert.equal(template.pool, POOL);
+          assert.equal(template.size, 8 * 1024 * 1024);
+          assert.equal(template.clones, 1);
+          assert.equal(template.retired, false);
+          done();
+        });
+      });
+
+      it('should not list the template as a replica', done => {
+        client.listReplicas({}, (err, res) => {
+          if (err) return done(err);
+          assert.lengthOf(
+            res.replicas.filter(r => r.uuid == TEMPLATE_UUID),
+            0
+          );
+          done();
+        });
+      });
+
+      it('should clone the template', done => {
+        client.cloneTemplate({ uuid: TEMPLATE_UUID, clones: CLONES }, err => {
+          if (err) return done(err);
+          client.listReplicas({}, (err, res) => {
+            if (err) return done(err);
+            [SOURCE].concat(CLONES).forEach(uuid => {
+              let replica = res.replicas.find(r => r.uuid == uuid);
+              assert.isDefined(replica, uuid);
+              assert.equal(replica.template, TEMPLATE_UUID);
+              assert.equal(replica.size, 8 * 1024 * 1024);
+            });
+            getTemplate((err, template) => {
+              if (err) return done(err);
+              assert.equal(template.clones, 3);
+              done();
+            });
+          });
+        });
+      });
+
+      it('should not create a clone which exists', done => {
+        client.cloneTemplate(
+          { uuid: TEMPLATE_UUID, clones: [CLONES[0]] },
+          (err, res) => {
+            if (!err) return done(new Error('Expected error'));
+            assert.equal(err.code, grpc.status.ALREADY_EXISTS);
+            done();
+          }
+        );
+      });
+
+      it('should retire the template which has clones', done => {
+        client.destroyTemplate({ uuid: TEMPLATE_UUID }, err => {
+          if (err) return done(err);
+          getTemplate((err, template) => {
+            if (err) return done(err);
+            assert.isDefined(template);
+            assert.equal(template.retired, true);
+            assert.equal(template.clones, 3);
+            done();
+          });
+        });
+      });
+
+      it('should not clone the retired template', done => {
+        client.cloneTemplate(
+          { uuid: TEMPLATE_UUID, clones: [CLONE_BASE_UUID + '3'] },
+          (err, res) => {
+            if (!err) return done(new Error('Expected error'));
+            assert.equal(err.code, grpc.status.INVALID_ARGUMENT);
+            done();
+          }
+        );
+      });
+
+      it('should keep the retired template until the last clone', done => {
+        async.eachSeries(
+          [SOURCE, CLONES[0]],
+          (uuid, next) => client.destroyReplica({ uuid }, next),
+          err => {
+            if (err) return done(err);
+            getTemplate((err, template) => {
+              if (err) return done(err);
+              assert.isDefined(template);
+              assert.equal(template.clones, 1);
+              done();
+            });
+          }
+        );
+      });
+
+      it('should destroy the retired template with its last clone', done => {
+        client.destroyReplica({ uuid: CLONES[1] }, err => {
+          if (err) return done(err);
+          getTemplate((err, template) => {
+            if (err) return done(err);
+            assert.isUndefined(template);
+            done();
+          });
+        });
+      });
+    });
+
     it('should destroy the pool', done => {
       client.destroyPool({ name: POOL }, (err, res) => {
         if (err) return done(err);
@@ -503,6 +734,12 @@ describe('grpc', function() {
         }
       });
 
+      after(done => {
+        // the config has been written by mayastor running as root
+        let child = sudo(['rm', '-f', CONFIG_DIR + '/' + CONFIG]);
+        child.on('close', () => done());
+      });
+
       it('should create the pool first time', done => {
         client.createPool({ name: POOL, disks: disks }, done);
       });
@@ -548,6 +785,64 @@ describe('grpc', function() {
         );
       });
 
+      it('should save the config', done => {
+        client.saveConfig({ path: CONFIG }, (err, res) => {
+          if (err) return done(err);
+          assert.equal(res.path, CONFIG_DIR + '/' + CONFIG);
+          assert.equal(res.pools, 1);
+          done();
+        });
+      });
+
+      it('should restore the pool from the config after restart', done => {
+        async.series(
+          [
+            next => {
+              common.restartMayastor(pingDone => {
+                client.listPools({}, pingDone);
+              }, next);
+            },
+            next =>
+              client.loadConfig({ path: CONFIG }, (err, res) => {
+                if (err) return next(err);
+                assert.include(res.restored, 'pool ' + POOL);
+                assert.lengthOf(res.failed, 0);
+                next();
+              }),
+            next =>
+              client.listPools({}, (err, res) => {
+                if (err) return next(err);
+                res = res.pools.filter(ent => ent.name == POOL);
+                assert.lengthOf(res, 1);
+                next();
+              }),
+          ],
+          done
+        );
+      });
+
+      it('should not save config outside of the config dir', done => {
+        async.eachSeries(
+          ['/tmp/' + CONFIG, '../' + CONFIG],
+          (path, next) => {
+            client.saveConfig({ path: path }, (err, res) => {
+              assert(err);
+              assert.equal(err.code, grpc.status.INVALID_ARGUMENT);
+              next();
+            });
+          },
+          done
+        );
+      });
+
+      it('should not load config which does not exist', done => {
+        client.loadConfig({ path: CONFIG + '.nonexistent' }, (err, res) => {
+          assert(err);
+          assert.equal(err.code, grpc.status.NOT_FOUND);
+          done();
+        });
+      });
+
       it('should not import a pool which does not exist on device', done => {
         client.createPool(
           { name: 'non-existing', disks: disks },
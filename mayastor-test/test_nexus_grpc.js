@@ -197,6 +197,13 @@ describe('nexus_grpc', function() {
         assert(nexus.state === 'online');
         assert(nexus.children.length === 2);
         assert(nexus.children[0].state === nexus.children[1].state);
+        nexus.children.forEach(child => {
+          assert(child.suspect === false);
+          assert(child.suspect_count == 0);
+          assert(child.io_errors == 0);
+          assert(child.last_fault === '');
+          assert.deepEqual(child.online_events, []);
+        });
         done();
       });
     });
@@ -317,6 +324,14 @@ describe('nexus_grpc', function() {
       });
     });
 
+    it('should be able to unpublish the nexus', done => {
+      client.UnpublishNexus({ bdev_name: 'nexus0' }, (err, res) => {
+        if (err) return done(err);
+        // unpublished nexus is not an error
+        client.UnpublishNexus({ bdev_name: 'nexus0' }, done);
+      });
+    });
+
     it('should be able to destroy the nexus', done => {
       client.DestroyNexus({ name: 'nexus0' }, (err, res) => {
         if (err) done(err);
@@ -0,0 +1,228 @@
+//! Node-wide bandwidth budget of background IO.
+//!
+//! Jobs doing bulk IO in the background (scrubbing of replicas for now)
+//! share one budget of bytes per second, so that more jobs running at the
+//! same time don't saturate the devices at the expense of the IO of the
+//! users. Each job joins the budget with a weight given by its kind and asks
+//! for bandwidth before each IO. Every 100ms the budget of the period is
+//! divided among the jobs which wait for it in proportion to their weights.
+//! A job which does not ask gets nothing, so its part goes to the others.
+//! An IO is never split: the job goes into debt for it and waits until the
+//! debt has been paid off.
+//!
+//! The budget is set by `set_background_budget` json-rpc method or by
+//! `MAYASTOR_BACKGROUND_BW` env variable (bytes per second) at startup.
+//! Zero means unlimited, which is the default.
+
+use crate::jsonrpc::jsonrpc_register;
+use futures::{channel::oneshot, future, FutureExt};
+use libc::c_void;
+use rpc::jsonrpc as jsondata;
+use spdk_sys::{spdk_poller, spdk_poller_register, spdk_poller_unregister};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+/// how often the budget is divided (in us)
+const PERIOD: u64 = 100_000;
+/// number of periods in a second
+const PERIODS_PER_SEC: u64 = 1_000_000 / PERIOD;
+
+/// State of a job sharing the budget
+#[derive(Default)]
+struct ShareState {
+    weight: u64,
+    /// bytes which the job may do before it must wait (negative is debt)
+    credit: i64,
+    /// bytes done by the job so far
+    bytes: u64,
+    /// the job waiting for its debt to be paid off
+    waiter: Option<oneshot::Sender<()>>,
+}
+
+thread_local! {
+    /// budget in bytes per second (0 = unlimited)
+    static BUDGET: Cell<u64> = Cell::new(0);
+    static SHARES: RefCell<HashMap<String, ShareState>> =
+        RefCell::new(HashMap::new());
+    static POLLER: RefCell<Option<*mut spdk_poller>> = RefCell::new(None);
+}
+
+/// Part of the budget used by a job. The job leaves the budget when its
+/// share is dropped.
+pub struct Share {
+    name: String,
+}
+
+impl Share {
+    /// Join the budget. The name of the job must be unique (i.e. the name
+    /// of the job from `cancel::Job`).
+    pub fn join(name: &str, weight: u64) -> Self {
+        SHARES.with(|shares| {
+            shares.borrow_mut().insert(
+                name.to_owned(),
+                ShareState {
+                    weight: weight.max(1),
+                    ..Default::default()
+                },
+            )
+        });
+        Self {
+            name: name.to_owned(),
+        }
+    }
+
+    /// Wait until the job may do IO of the given size.
+    pub async fn acquire(&self, bytes: u64) {
+        loop {
+            let receiver = SHARES.with(|shares| {
+                let mut shares = shares.borrow_mut();
+                let share = shares.get_mut(&self.name)?;
+                if BUDGET.with(Cell::get) == 0 {
+                    share.bytes += bytes;
+                    return None;
+                }
+                if share.credit >= 0 {
+                    share.credit -= bytes as i64;
+                    share.bytes += bytes;
+                    return None;
+                }
+                let (sender, receiver) = oneshot::channel();
+                share.waiter = Some(sender);
+                Some(receiver)
+            });
+            match receiver {
+                Some(receiver) => {
+                    let _ = receiver.await;
+                }
+                None => return,
+            }
+        }
+    }
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        SHARES.with(|shares| shares.borrow_mut().remove(&self.name));
+    }
+}
+
+/// Return the budget in bytes per second (0 = unlimited).
+pub fn get_budget() -> u64 {
+    BUDGET.with(Cell::get)
+}
+
+/// Set the budget (0 = unlimited) and start or stop dividing it.
+pub fn set_budget(bytes_per_sec: u64) {
+    let enable = bytes_per_sec > 0;
+
+    BUDGET.with(|budget| budget.set(bytes_per_sec));
+    if enable {
+        info!("Background IO budget set to {} B/s", bytes_per_sec);
+    } else {
+        info!("Background IO budget disabled");
+    }
+    POLLER.with(|poller| {
+        let mut poller = poller.borrow_mut();
+        match (enable, poller.take()) {
+            (true, None) => {
+                *poller = Some(unsafe {
+                    spdk_poller_register(
+                        Some(divide),
+                        std::ptr::null_mut(),
+                        PERIOD,
+                    )
+                });
+            }
+            (false, Some(mut old)) => unsafe {
+                spdk_poller_unregister(&mut old);
+            },
+            (_, old) => *poller = old,
+        }
+    });
+    // debts don't matter without budget and old ones not with a new one
+    SHARES.with(|shares| {
+        for share in shares.borrow_mut().values_mut() {
+            share.credit = 0;
+            if let Some(waiter) = share.waiter.take() {
+                let _ = waiter.send(());
+            }
+        }
+    });
+}
+
+/// Divide the budget of the period among the jobs in debt by their weights.
+/// A job gets at most what it owes, the rest goes to the others.
+extern "C" fn divide(_ctx: *mut c_void) -> i32 {
+    let mut left = (BUDGET.with(Cell::get) / PERIODS_PER_SEC) as i64;
+
+    SHARES.with(|shares| {
+        let mut shares = shares.borrow_mut();
+        loop {
+            let weights: u64 = shares
+                .values()
+                .filter(|s| s.credit < 0)
+                .map(|s| s.weight)
+                .sum();
+            if weights == 0 || left <= 0 {
+                break;
+            }
+            let total = left;
+            for share in shares.values_mut().filter(|s| s.credit < 0) {
+                let part = (total as u64 * share.weight / weights) as i64;
+                let paid = part.min(-share.credit).max(1).min(left);
+                share.credit += paid;
+                left -= paid;
+            }
+        }
+        for share in shares.values_mut().filter(|s| s.credit >= 0) {
+            if let Some(waiter) = share.waiter.take() {
+                let _ = waiter.send(());
+            }
+        }
+    });
+    0
+}
+
+/// Set the budget from MAYASTOR_BACKGROUND_BW env variable if it is set.
+pub fn init_budget() {
+    if let Ok(val) = std::env::var("MAYASTOR_BACKGROUND_BW") {
+        match val.parse::<u64>() {
+            Ok(bytes_per_sec) => set_budget(bytes_per_sec),
+            Err(_) => error!("Invalid MAYASTOR_BACKGROUND_BW value {}", val),
+        }
+    }
+}
+
+/// Register json-rpc methods for the budget.
+pub fn register_budget_methods() {
+    jsonrpc_register(
+        "set_background_budget",
+        |args: jsondata::BackgroundBudget| {
+            set_budget(args.bytes_per_sec);
+            future::ok(()).boxed_local()
+        },
+    );
+
+    jsonrpc_register::<(), _, _>("get_background_budget", |_| {
+        let mut jobs: Vec<jsondata::BackgroundShare> = SHARES.with(|shares| {
+            shares
+                .borrow()
+                .iter()
+                .map(|(name, share)| jsondata::BackgroundShare {
+                    name: name.clone(),
+                    weight: share.weight,
+                    bytes: share.bytes,
+                    waiting: share.waiter.is_some(),
+                })
+                .collect()
+        });
+        jobs.sort_by(|a, b| a.name.cmp(&b.name));
+        future::ok(jsondata::BackgroundBudgetStatus {
+            bytes_per_sec: get_budget(),
+            jobs,
+        })
+        .boxed_local()
+    });
+}
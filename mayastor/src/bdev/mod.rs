@@ -103,6 +103,11 @@ pub struct Stat {
     pub num_write_ops: u64,
     pub bytes_read: u64,
     pub bytes_written: u64,
+    /// cumulative time spent in reads and writes (in ticks)
+    pub read_latency_ticks: u64,
+    pub write_latency_ticks: u64,
+    /// number of ticks per second
+    pub ticks_rate: u64,
 }
 
 impl Bdev {
@@ -198,6 +203,12 @@ impl Bdev {
         unsafe { spdk_bdev_io_type_supported(self.inner, io_type) }
     }
 
+    /// returns true if the bdev has been opened, i.e. it is a child of a
+    /// nexus or it is exported by nbd or a target
+    pub fn is_open(&self) -> bool {
+        unsafe { !(*self.inner).internal.open_descs.tqh_first.is_null() }
+    }
+
     /// returns the bdev als a ptr
     pub fn as_ptr(&self) -> *mut spdk_bdev {
         self.inner
@@ -260,6 +271,9 @@ impl Bdev {
                 num_write_ops: stat.num_write_ops,
                 bytes_read: stat.bytes_read,
                 bytes_written: stat.bytes_written,
+                read_latency_ticks: stat.read_latency_ticks,
+                write_latency_ticks: stat.write_latency_ticks,
+                ticks_rate: stat.ticks_rate,
             })
         }
     }
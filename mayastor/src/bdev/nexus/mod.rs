@@ -36,6 +36,8 @@ pub enum Error {
     ChildExists,
     /// the nexus is does not have enough children to come online
     NexusIncomplete,
+    /// the host name of a remote child could not be resolved
+    Unresolvable,
 }
 
 impl From<std::ffi::NulError> for Error {
@@ -76,6 +78,7 @@ mod nexus_fn_table;
 mod nexus_io;
 pub mod nexus_module;
 pub mod nexus_rpc;
+pub mod nexus_slo;
 
 /// public function which simply calls register module
 pub fn register_module() {
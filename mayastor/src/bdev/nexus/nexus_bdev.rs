@@ -69,6 +69,7 @@ use crate::bdev::{
 use crate::{
     bdev::nexus::{nexus_channel::NexusChannelInner, nexus_io::IoStatus},
     nexus_uri::BdevType,
+    nvme_dev::NvmfBdev,
 };
 
 use crate::bdev::nexus::instances;
@@ -76,6 +77,7 @@ use serde::Serialize;
 use spdk_sys::{
     spdk_bdev,
     spdk_bdev_desc,
+    spdk_bdev_flush_blocks,
     spdk_bdev_io,
     spdk_bdev_io_get_buf,
     spdk_bdev_readv_blocks,
@@ -95,6 +97,7 @@ use std::{
     fmt::{Display, Formatter},
     ops::Neg,
     os::raw::c_void,
+    sync::atomic::Ordering,
 };
 
 use crate::{
@@ -284,6 +287,77 @@ impl Nexus {
         }
     }
 
+    /// fault a child device and reconfigure the IO channels. Unlike offline,
+    /// the child is not expected to come back without an operator stepping in.
+    pub async fn fault_child(
+        &mut self,
+        name: &str,
+        reason: &str,
+    ) -> Result<NexusState, nexus::Error> {
+        trace!("{}: Fault child request for {}", self.name(), name);
+
+        if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
+            if child.state != ChildState::Open {
+                return Err(Error::Invalid);
+            }
+            child.close()?;
+            child.state = ChildState::Faulted;
+            child.last_fault = Some(reason.to_owned());
+            let ch = unsafe { spdk_get_io_channel(self.as_ptr()) };
+            self.reconfigure(DREvent::ChildOffline).await;
+            unsafe { spdk_put_io_channel(ch) }
+            self.set_state(NexusState::Degraded);
+            Ok(NexusState::Degraded)
+        } else {
+            Err(Error::NotFound)
+        }
+    }
+
+    /// Reconnect a remote (nvmf) child, i.e. when the address of its host
+    /// has changed. The child is taken offline, its nvme controller is
+    /// destroyed and created again (resolving the host name) and the child
+    /// is brought back online unless it has been offlined before. SPDK
+    /// destroys the controller asynchronously, so creating it may fail for a
+    /// while and the call should be repeated until it succeeds.
+    pub async fn reconnect_child(
+        &mut self,
+        name: &str,
+    ) -> Result<(), nexus::Error> {
+        trace!("{}: Reconnect child request for {}", self.name(), name);
+
+        let args = NvmfBdev::from_child_name(name)?;
+        let state = match self.children.iter().find(|c| c.name == name) {
+            Some(child) => child.state,
+            None => return Err(Error::NotFound),
+        };
+        if state == ChildState::Open {
+            self.offline_child(name).await?;
+        }
+        if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
+            if child.bdev.take().is_some() {
+                child.destroy().await?;
+            }
+            // the bdev is gone, the child waits to be opened again
+            if state != ChildState::Closed && state != ChildState::ConfigInvalid
+            {
+                child.state = ChildState::Init;
+            }
+        }
+
+        let bdev_name = args.create().await?;
+        let online = match self.children.iter_mut().find(|c| c.name == name) {
+            Some(child) => {
+                child.bdev = bdev_lookup_by_name(&bdev_name);
+                child.state == ChildState::Init
+            }
+            None => return Err(Error::NotFound),
+        };
+        if online {
+            self.online_child(name).await?;
+        }
+        Ok(())
+    }
+
     /// online a chilld and reconfigure the IO channels
     pub async fn online_child(
         &mut self,
@@ -291,11 +365,22 @@ impl Nexus {
     ) -> Result<NexusState, nexus::Error> {
         trace!("{} Online child request", self.name());
 
+        let (num_blocks, block_size) =
+            (self.bdev.num_blocks(), self.bdev.block_size());
         if let Some(child) = self.children.iter_mut().find(|c| c.name == name) {
-            child.open(self.bdev.num_blocks(), self.bdev.block_size())?;
+            if let Err(err) = child.open(num_blocks, block_size) {
+                child.record_online(Some(format!("{:?}", err)));
+                return Err(err);
+            }
+            child.suspect = false;
             let ch = unsafe { spdk_get_io_channel(self.as_ptr()) };
             self.reconfigure(DREvent::ChildOnline).await;
             unsafe { spdk_put_io_channel(ch) };
+            if let Some(child) =
+                self.children.iter_mut().find(|c| c.name == name)
+            {
+                child.record_online(None);
+            }
             if self.is_healty() {
                 self.set_state(NexusState::Online);
                 Ok(NexusState::Online)
@@ -542,6 +627,11 @@ impl Nexus {
                 parent_io,
                 child_io,
             );
+            if let Some(child) = nexus.children.iter().find(|c| {
+                c.bdev.as_ref().map(|b| b.inner) == Some((*child_io).bdev)
+            }) {
+                child.io_errors.fetch_add(1, Ordering::Relaxed);
+            }
             pio.io_complete(IoStatus::Failed);
         }
 
@@ -672,6 +762,39 @@ impl Nexus {
         }
     }
 
+    /// flush all the children, the IO completes when every child has
+    /// confirmed that the data written so far is durable
+    pub(crate) fn flush(
+        &self,
+        pio: *mut spdk_bdev_io,
+        channels: &NexusChannelInner,
+    ) {
+        let mut io = Nio::from(pio);
+        io.set_outstanding(channels.ch.len());
+        let results = channels
+            .ch
+            .iter()
+            .map(|c| unsafe {
+                spdk_bdev_flush_blocks(
+                    c.0,
+                    c.1,
+                    io.offset(),
+                    io.num_blocks(),
+                    Some(Self::io_completion),
+                    pio as *mut _,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if results.iter().any(|r| *r != 0) {
+            error!(
+                "{}: Failed to submit dispatched IO {:p}",
+                io.nexus_as_ref().name(),
+                pio
+            );
+        }
+    }
+
     pub(crate) fn unmap(
         &self,
         pio: *mut spdk_bdev_io,
@@ -12,7 +12,16 @@ use spdk_sys::{
     spdk_bdev_module_release_bdev,
     spdk_io_channel,
 };
-use std::{fmt::Display, ops::Neg};
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    ops::Neg,
+    sync::atomic::AtomicU64,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Max number of past online events remembered for each child.
+const MAX_ONLINE_EVENTS: usize = 16;
 
 #[derive(Debug, Clone, Copy, Serialize, PartialEq)]
 pub(crate) enum ChildState {
@@ -42,6 +51,17 @@ impl ToString for ChildState {
     }
 }
 
+/// Outcome of bringing a child back online (online_child). The child is
+/// reopened and added back to the IO channels of the nexus, no data are
+/// copied to it.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct OnlineEvent {
+    /// when the child has been reopened (seconds since epoch)
+    pub(crate) time: u64,
+    /// the reason of the failure if it has failed
+    pub(crate) error: Option<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub(crate) struct NexusChild {
     /// name of the parent this child belongs too
@@ -59,6 +79,17 @@ pub(crate) struct NexusChild {
     pub(crate) ch: *mut spdk_io_channel,
     /// current state of the child
     pub(crate) state: ChildState,
+    /// the child has been consistently slower than its siblings
+    pub(crate) suspect: bool,
+    /// number of times the child has been marked as suspect
+    pub(crate) suspect_count: u64,
+    #[serde(skip_serializing)]
+    /// number of failed IOs since the child has been added to the nexus
+    pub(crate) io_errors: AtomicU64,
+    /// the reason why the child has been faulted the last time
+    pub(crate) last_fault: Option<String>,
+    /// outcomes of past attempts to bring the child online, oldest first
+    pub(crate) online_events: VecDeque<OnlineEvent>,
 }
 
 impl Display for NexusChild {
@@ -125,6 +156,7 @@ impl NexusChild {
         if rc != 0 {
             error!("{}: Failed to open child {}", self.parent, self.name);
             self.state = ChildState::Faulted;
+            self.last_fault = Some(format!("failed to open (errno {})", -rc));
             self.desc = std::ptr::null_mut();
             return Err(match rc.neg() {
                 libc::EPERM => nexus::Error::ReadOnly,
@@ -143,6 +175,7 @@ impl NexusChild {
 
         if rc != 0 {
             self.state = ChildState::Faulted;
+            self.last_fault = Some(format!("failed to claim (errno {})", -rc));
             error!("{}: Failed to claim device {}", self.parent, self.name);
             unsafe { spdk_bdev_close(self.desc) }
             self.desc = std::ptr::null_mut();
@@ -197,7 +230,27 @@ impl NexusChild {
             desc: std::ptr::null_mut(),
             ch: std::ptr::null_mut(),
             state: ChildState::Init,
+            suspect: false,
+            suspect_count: 0,
+            io_errors: AtomicU64::new(0),
+            last_fault: None,
+            online_events: VecDeque::new(),
+        }
+    }
+
+    /// remember the outcome of bringing the child online, dropping the
+    /// oldest events
+    pub(crate) fn record_online(&mut self, error: Option<String>) {
+        if self.online_events.len() == MAX_ONLINE_EVENTS {
+            self.online_events.pop_front();
         }
+        self.online_events.push_back(OnlineEvent {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            error,
+        });
     }
 
     /// destroy the child bdev
@@ -109,6 +109,10 @@ impl NexusFnTable {
                     trace!("{} Dispatching UNMAP {:p}", nexus.name(), io);
                     nexus.unmap(io, &ch)
                 }
+                NioType::Flush => {
+                    trace!("{} Dispatching FLUSH {:p}", nexus.name(), io);
+                    nexus.flush(io, &ch)
+                }
                 _ => panic!("{} Received unsupported IO!", nexus.name()),
             };
         } else {
@@ -7,7 +7,10 @@ use crate::{
     jsonrpc::{jsonrpc_register, Code, JsonRpcError},
 };
 
-use crate::bdev::nexus::nexus_bdev::nexus_lookup;
+use crate::bdev::nexus::{
+    nexus_bdev::nexus_lookup,
+    nexus_slo::{get_latency_slo, set_latency_slo, LatencySlo},
+};
 use futures::{future, FutureExt};
 use rpc::mayastor::{
     Child,
@@ -16,7 +19,9 @@ use rpc::mayastor::{
     DestroyNexusRequest,
     ListNexusReply,
     Nexus,
+    OnlineEvent,
 };
+use std::sync::atomic::Ordering;
 
 pub(crate) fn register_rpc_methods() {
     // JSON rpc method to list the nexus and their states
@@ -33,6 +38,22 @@ pub(crate) fn register_rpc_methods() {
                         .map(|child| Child {
                             name: child.name.clone(),
                             state: child.state.to_string(),
+                            suspect: child.suspect,
+                            suspect_count: child.suspect_count,
+                            io_errors: child.io_errors.load(Ordering::Relaxed),
+                            last_fault: child
+                                .last_fault
+                                .clone()
+                                .unwrap_or_default(),
+                            online_events: child
+                                .online_events
+                                .iter()
+                                .map(|r| OnlineEvent {
+                                    time: r.time,
+                                    success: r.error.is_none(),
+                                    error: r.error.clone().unwrap_or_default(),
+                                })
+                                .collect(),
                         })
                         .collect::<Vec<_>>(),
                 })
@@ -128,4 +149,14 @@ pub(crate) fn register_rpc_methods() {
         };
         fut.boxed_local()
     });
+
+    // set or disable (latency_us = 0) latency SLO of nexus children
+    jsonrpc_register("set_nexus_latency_slo", |args: LatencySlo| {
+        set_latency_slo(args);
+        future::ok(()).boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _>("get_nexus_latency_slo", |_| {
+        future::ok(get_latency_slo()).boxed_local()
+    });
 }
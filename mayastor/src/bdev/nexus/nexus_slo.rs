@@ -0,0 +1,363 @@
+//!
+//! Detection of nexus children which are much slower than their siblings.
+//!
+//! When a latency SLO is configured, we sample IO stats of the children of
+//! each nexus every second and compute the average latency of the IOs which
+//! completed since the previous sample. A child which exceeds the SLO for
+//! `window` consecutive samples, while all of its siblings stay within the
+//! SLO, is marked as suspect. If all children are slow, it is the workload
+//! and not the device, so nobody is blamed.
+//!
+//! Optionally the suspect child can be faulted, so that IO is served by the
+//! healthy children only.
+
+use crate::{
+    bdev::{
+        nexus::{instances, nexus_bdev::nexus_lookup, nexus_child::ChildState},
+        Bdev,
+    },
+    executor,
+};
+use libc::c_void;
+use serde::{Deserialize, Serialize};
+use spdk_sys::{spdk_poller, spdk_poller_register, spdk_poller_unregister};
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+};
+
+/// how often the children are sampled (in us)
+const SAMPLE_PERIOD: u64 = 1_000_000;
+
+/// Latency SLO of nexus children
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LatencySlo {
+    /// maximum average latency of a child IO in microseconds (0 = disabled)
+    pub latency_us: u64,
+    /// number of consecutive samples (seconds) breaching the SLO before the
+    /// child is marked as suspect
+    pub window: u32,
+    /// fault the suspect child
+    #[serde(default)]
+    pub auto_fault: bool,
+}
+
+/// Stats of the child from the previous sample
+#[derive(Debug, Default)]
+struct ChildSample {
+    ops: u64,
+    ticks: u64,
+    /// number of consecutive samples breaching the SLO
+    breaches: u32,
+}
+
+impl ChildSample {
+    /// Return average latency (in us) of the IOs which have completed since
+    /// the previous sample and remember the new counters. Nothing is
+    /// returned if no IO has completed or the counters went backwards (the
+    /// child has been reopened meanwhile).
+    fn update(&mut self, ops: u64, ticks: u64, ticks_rate: u64) -> Option<u64> {
+        let res =
+            match (ops.checked_sub(self.ops), ticks.checked_sub(self.ticks)) {
+                (Some(ops), Some(ticks)) if ops > 0 && ticks_rate > 0 => {
+                    Some(ticks.saturating_mul(1_000_000) / ticks_rate / ops)
+                }
+                _ => None,
+            };
+        self.ops = ops;
+        self.ticks = ticks;
+        res
+    }
+}
+
+type Samples = HashMap<(String, String), ChildSample>;
+
+thread_local! {
+    static SLO: RefCell<Option<LatencySlo>> = RefCell::new(None);
+    static SLO_POLLER: RefCell<Option<*mut spdk_poller>> = RefCell::new(None);
+    static SAMPLES: RefCell<Samples> = RefCell::new(HashMap::new());
+    /// set while the children are being checked
+    static CHECKING: Cell<bool> = Cell::new(false);
+}
+
+/// Return currently configured SLO (if any).
+pub fn get_latency_slo() -> Option<LatencySlo> {
+    SLO.with(|slo| slo.borrow().clone())
+}
+
+/// Set or clear (latency_us = 0) the SLO and start or stop the sampling.
+pub fn set_latency_slo(new_slo: LatencySlo) {
+    let enable = new_slo.latency_us > 0;
+
+    SAMPLES.with(|samples| samples.borrow_mut().clear());
+    SLO.with(|slo| {
+        *slo.borrow_mut() = if enable {
+            info!(
+                "Nexus child latency SLO set to {}us over {}s (auto fault: {})",
+                new_slo.latency_us, new_slo.window, new_slo.auto_fault
+            );
+            Some(new_slo)
+        } else {
+            info!("Nexus child latency SLO disabled");
+            None
+        }
+    });
+    SLO_POLLER.with(|poller| {
+        let mut poller = poller.borrow_mut();
+        match (enable, poller.take()) {
+            (true, None) => {
+                *poller = Some(unsafe {
+                    spdk_poller_register(
+                        Some(sample),
+                        std::ptr::null_mut(),
+                        SAMPLE_PERIOD,
+                    )
+                });
+            }
+            (false, Some(mut old)) => unsafe {
+                spdk_poller_unregister(&mut old);
+            },
+            (_, old) => *poller = old,
+        }
+    });
+}
+
+extern "C" fn sample(_ctx: *mut c_void) -> i32 {
+    // stats are collected asynchronously, so the previous check may still
+    // be running - skip this sample rather than run two of them at once
+    if CHECKING.with(|checking| checking.replace(true)) {
+        return 0;
+    }
+    executor::get_spawner()
+        .spawn_local(check_children_once())
+        .expect("failed to spawn latency check");
+    0
+}
+
+async fn check_children_once() {
+    check_children().await;
+    CHECKING.with(|checking| checking.set(false));
+}
+
+/// Sample the children of all nexus instances and act on SLO breaches.
+async fn check_children() {
+    let slo = match get_latency_slo() {
+        Some(slo) => slo,
+        None => return,
+    };
+    let names: Vec<String> =
+        instances().iter().map(|n| n.name().to_string()).collect();
+
+    // forget children of nexus instances which are gone
+    SAMPLES.with(|samples| {
+        samples
+            .borrow_mut()
+            .retain(|(nexus, _), _| names.contains(nexus))
+    });
+
+    for name in names {
+        // collect average latency of each open child since the last sample
+        let mut latencies: Vec<(String, Option<u64>)> = Vec::new();
+        let children = match nexus_lookup(&name) {
+            Some(nexus) => nexus
+                .children
+                .iter()
+                .filter(|c| c.state == ChildState::Open)
+                .filter_map(|c| {
+                    c.bdev
+                        .as_ref()
+                        .map(|b| (c.name.clone(), Bdev::from(b.inner)))
+                })
+                .collect::<Vec<_>>(),
+            None => continue,
+        };
+        // there is nobody to compare with
+        if children.len() < 2 {
+            continue;
+        }
+        for (child, bdev) in children {
+            let stat = match bdev.stats().await {
+                Ok(stat) => stat,
+                Err(errno) => {
+                    warn!(
+                        "{}: Failed to get stats of child {} (errno={})",
+                        name, child, errno
+                    );
+                    latencies.push((child, None));
+                    continue;
+                }
+            };
+            let ops = stat.num_read_ops + stat.num_write_ops;
+            let ticks = stat.read_latency_ticks + stat.write_latency_ticks;
+            let latency = SAMPLES.with(|samples| {
+                samples
+                    .borrow_mut()
+                    .entry((name.clone(), child.clone()))
+                    .or_insert_with(ChildSample::default)
+                    .update(ops, ticks, stat.ticks_rate)
+            });
+            latencies.push((child, latency));
+        }
+
+        let suspects = SAMPLES.with(|samples| {
+            count_breaches(&mut samples.borrow_mut(), &name, &latencies, &slo)
+        });
+
+        for child in suspects {
+            mark_suspect(&name, &child, slo.auto_fault).await;
+        }
+    }
+}
+
+/// Count breaches of the SLO by the children of the nexus given their
+/// latencies in the last sample and return the children which have just
+/// become suspect. A breach counts only if the siblings are fine.
+fn count_breaches(
+    samples: &mut Samples,
+    nexus: &str,
+    latencies: &[(String, Option<u64>)],
+    slo: &LatencySlo,
+) -> Vec<String> {
+    let slow: Vec<&String> = latencies
+        .iter()
+        .filter(|(_, lat)| lat.map_or(false, |l| l > slo.latency_us))
+        .map(|(child, _)| child)
+        .collect();
+    let mut suspects = Vec::new();
+
+    for (child, _) in latencies {
+        let entry = samples
+            .entry((nexus.to_string(), child.clone()))
+            .or_insert_with(ChildSample::default);
+        if slow.len() == 1 && slow[0] == child {
+            entry.breaches += 1;
+            if entry.breaches == slo.window.max(1) {
+                suspects.push(child.clone());
+            }
+        } else {
+            entry.breaches = 0;
+        }
+    }
+    suspects
+}
+
+/// Mark the child as suspect and fault it if asked to do so.
+async fn mark_suspect(name: &str, child_name: &str, auto_fault: bool) {
+    let nexus = match nexus_lookup(name) {
+        Some(nexus) => nexus,
+        None => return,
+    };
+    if let Some(child) =
+        nexus.children.iter_mut().find(|c| c.name == child_name)
+    {
+        child.suspect = true;
+        child.suspect_count += 1;
+        warn!(
+            "{}: Child {} breached latency SLO for a sustained period",
+            name, child_name
+        );
+    }
+    if auto_fault {
+        match nexus.fault_child(child_name, "breached latency SLO").await {
+            Ok(_) => warn!("{}: Faulted slow child {}", name, child_name),
+            Err(err) => error!(
+                "{}: Failed to fault slow child {}: {:?}",
+                name, child_name, err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slo(window: u32) -> LatencySlo {
+        LatencySlo {
+            latency_us: 1000,
+            window,
+            auto_fault: false,
+        }
+    }
+
+    fn lat(child: &str, latency: Option<u64>) -> (String, Option<u64>) {
+        (child.to_string(), latency)
+    }
+
+    #[test]
+    fn average_latency_since_previous_sample() {
+        let mut sample = ChildSample::default();
+        assert_eq!(sample.update(10, 1_000, 1_000_000), Some(100));
+        // 10 more IOs took 20ms in total
+        assert_eq!(sample.update(20, 21_000, 1_000_000), Some(2_000));
+        // nothing completed
+        assert_eq!(sample.update(20, 21_000, 1_000_000), None);
+        assert_eq!(sample.update(30, 22_000, 0), None);
+    }
+
+    #[test]
+    fn counters_going_backwards_are_ignored() {
+        let mut sample = ChildSample::default();
+        sample.update(100, 50_000, 1_000_000);
+        assert_eq!(sample.update(200, 10_000, 1_000_000), None);
+        assert_eq!(sample.update(5, 20_000, 1_000_000), None);
+        // the new counters are the base for the next sample
+        assert_eq!(sample.update(15, 30_000, 1_000_000), Some(1_000));
+    }
+
+    #[test]
+    fn suspect_after_window_of_breaches() {
+        let mut samples = Samples::new();
+        let latencies = vec![lat("a", Some(5000)), lat("b", Some(100))];
+
+        for _ in 0 .. 2 {
+            assert!(count_breaches(&mut samples, "n", &latencies, &slo(3))
+                .is_empty());
+        }
+        assert_eq!(
+            count_breaches(&mut samples, "n", &latencies, &slo(3)),
+            vec!["a".to_string()]
+        );
+        // reported once, not on every following sample
+        assert!(
+            count_breaches(&mut samples, "n", &latencies, &slo(3)).is_empty()
+        );
+    }
+
+    #[test]
+    fn breaches_reset_when_child_recovers() {
+        let mut samples = Samples::new();
+        let slow = vec![lat("a", Some(5000)), lat("b", Some(100))];
+        let fine = vec![lat("a", Some(1000)), lat("b", Some(100))];
+
+        count_breaches(&mut samples, "n", &slow, &slo(2));
+        count_breaches(&mut samples, "n", &fine, &slo(2));
+        assert!(count_breaches(&mut samples, "n", &slow, &slo(2)).is_empty());
+        assert_eq!(
+            count_breaches(&mut samples, "n", &slow, &slo(2)),
+            vec!["a".to_string()]
+        );
+    }
+
+    #[test]
+    fn nobody_blamed_if_all_children_are_slow() {
+        let mut samples = Samples::new();
+        let latencies = vec![lat("a", Some(5000)), lat("b", Some(4000))];
+
+        for _ in 0 .. 3 {
+            assert!(count_breaches(&mut samples, "n", &latencies, &slo(1))
+                .is_empty());
+        }
+        assert!(samples.values().all(|s| s.breaches == 0));
+    }
+
+    #[test]
+    fn unknown_latency_is_not_a_breach() {
+        let mut samples = Samples::new();
+        let latencies = vec![lat("a", None), lat("b", Some(100))];
+
+        assert!(
+            count_breaches(&mut samples, "n", &latencies, &slo(1)).is_empty()
+        );
+    }
+}
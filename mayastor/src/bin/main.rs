@@ -5,8 +5,7 @@ use sysfs;
 extern crate git_version;
 
 use git_version::git_version;
-use mayastor::{mayastor_start, spdklog::SpdkLog};
-use std::path::Path;
+use mayastor::{capabilities, mayastor_start, spdklog::SpdkLog};
 
 mayastor::CPS_INIT!();
 
@@ -14,16 +13,34 @@ fn main() -> Result<(), std::io::Error> {
     let log = SpdkLog::new();
     log.init().expect("Failed to set logger");
 
-    let hugepage_path = Path::new("/sys/kernel/mm/hugepages/hugepages-2048kB");
-    let nr_pages: u32 = sysfs::parse_value(&hugepage_path, "nr_hugepages")?;
+    capabilities::report();
+
+    let hugepage_size = capabilities::default_hugepage_size();
+    let hugepage_path = capabilities::hugepage_dir(hugepage_size);
+    if !hugepage_path.exists() {
+        error!(
+            "Hugepages of {}kB are not supported by kernel, check \
+             default_hugepagesz kernel parameter",
+            hugepage_size
+        );
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{} does not exist", hugepage_path.display()),
+        ));
+    }
+    let nr_pages: u64 = sysfs::parse_value(&hugepage_path, "nr_hugepages")?;
 
     if nr_pages == 0 {
-        info!("no hugepages available, allocating 512 default pages of 2048k");
-        sysfs::write_value(&hugepage_path, "nr_hugepages", 512)?;
+        let nr_pages = capabilities::default_nr_hugepages(hugepage_size);
+        info!(
+            "no hugepages available, allocating {} default pages of {}k",
+            nr_pages, hugepage_size
+        );
+        sysfs::write_value(&hugepage_path, "nr_hugepages", nr_pages)?;
     }
 
-    let free_pages: u32 = sysfs::parse_value(&hugepage_path, "free_hugepages")?;
-    let nr_pages: u32 = sysfs::parse_value(&hugepage_path, "nr_hugepages")?;
+    let free_pages: u64 = sysfs::parse_value(&hugepage_path, "free_hugepages")?;
+    let nr_pages: u64 = sysfs::parse_value(&hugepage_path, "nr_hugepages")?;
 
     info!("free_pages: {} nr_pages: {}", free_pages, nr_pages);
 
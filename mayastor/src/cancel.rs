@@ -0,0 +1,143 @@
+//! Cancellation of long running operations.
+//!
+//! An operation which takes long (i.e. scrubbing a replica or restoring the
+//! config) runs as a named job. The job holds a cancellation token, which the
+//! operation checks between its steps, and stops at the first check after
+//! the job has been cancelled. A step is never interrupted half-way, so the
+//! operation is left in a state from which it can be started again: the
+//! steps done so far stay done and nothing is half-done.
+//!
+//! Jobs are cancelled by `cancel_job` json-rpc method and all of them when
+//! mayastor is shutting down. A job is unregistered when it is dropped. Only
+//! one job of the same name can run at a time.
+
+use crate::jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result};
+use futures::{future, FutureExt};
+use rpc::jsonrpc as jsondata;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+};
+
+thread_local! {
+    /// tokens of running jobs by name of the job
+    static JOBS: RefCell<HashMap<String, CancelToken>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Token telling the operation that it should stop. It is cheap to clone
+/// and the clones share the state.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Rc<Cell<bool>>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the operation to stop.
+    pub fn cancel(&self) {
+        self.0.set(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.get()
+    }
+}
+
+/// Registered long running operation.
+pub struct Job {
+    name: String,
+    token: CancelToken,
+}
+
+impl Job {
+    /// Register a job. Fails if a job of the same name is running.
+    pub fn start(name: &str) -> Result<Self> {
+        JOBS.with(|jobs| {
+            let mut jobs = jobs.borrow_mut();
+            if jobs.contains_key(name) {
+                return Err(JsonRpcError::new(
+                    Code::AlreadyExists,
+                    format!("Job {} is already running", name),
+                ));
+            }
+            let token = CancelToken::new();
+            jobs.insert(name.to_owned(), token.clone());
+            Ok(Self {
+                name: name.to_owned(),
+                token,
+            })
+        })
+    }
+
+    pub fn token(&self) -> &CancelToken {
+        &self.token
+    }
+
+    /// Return error if the job has been cancelled. Meant to be called
+    /// between the steps of the operation.
+    pub fn check(&self) -> Result<()> {
+        if self.token.is_cancelled() {
+            Err(JsonRpcError::new(
+                Code::Cancelled,
+                format!("Job {} has been cancelled", self.name),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        JOBS.with(|jobs| jobs.borrow_mut().remove(&self.name));
+    }
+}
+
+/// Cancel the job. Return false if no such job is running.
+pub fn cancel(name: &str) -> bool {
+    JOBS.with(|jobs| match jobs.borrow().get(name) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    })
+}
+
+/// Cancel all running jobs (when shutting down).
+pub fn cancel_all() {
+    JOBS.with(|jobs| {
+        for (name, token) in jobs.borrow().iter() {
+            info!("Cancelling job {}", name);
+            token.cancel();
+        }
+    })
+}
+
+/// Register json-rpc methods for listing and cancelling jobs.
+pub fn register_job_methods() {
+    jsonrpc_register::<(), _, _>("list_jobs", |_| {
+        let mut names: Vec<String> =
+            JOBS.with(|jobs| jobs.borrow().keys().cloned().collect());
+        names.sort();
+        future::ok(names).boxed_local()
+    });
+
+    jsonrpc_register("cancel_job", |args: jsondata::CancelJobArgs| {
+        let fut = async move {
+            if cancel(&args.name) {
+                info!("Job {} cancelled", args.name);
+                Ok(())
+            } else {
+                Err(JsonRpcError::new(
+                    Code::NotFound,
+                    format!("Job {} is not running", args.name),
+                ))
+            }
+        };
+        fut.boxed_local()
+    });
+}
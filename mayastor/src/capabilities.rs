@@ -0,0 +1,191 @@
+//! What mayastor can do on the machine it runs on.
+//!
+//! Mayastor is built for x86_64 and aarch64. The two differ in what the
+//! kernel usually offers (the default hugepage size of an aarch64 kernel
+//! with 64KiB base pages is 512MiB instead of 2MiB) and in what SPDK and
+//! DPDK have been built with. Instead of failing somewhere deep in the
+//! initialization of SPDK, the environment is detected at startup, problems
+//! are logged as warnings with what to do about them, and the report is
+//! available by `get_capabilities` json-rpc method.
+
+use crate::jsonrpc::jsonrpc_register;
+use futures::{future, FutureExt};
+use rpc::jsonrpc as jsondata;
+use std::{fs, path::PathBuf};
+
+/// Directory with a subdirectory for each hugepage size supported by kernel.
+const HUGEPAGES_DIR: &str = "/sys/kernel/mm/hugepages";
+/// Memory allocated as hugepages at startup if there are none (in kB).
+const DEFAULT_HUGEPAGE_MEM: u64 = 1024 * 1024;
+/// io_uring_setup syscall (the same number on all architectures).
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+
+/// Name of the architecture which mayastor has been built for.
+pub fn arch() -> &'static str {
+    std::env::consts::ARCH
+}
+
+/// Return true if the architecture is one which mayastor is built and
+/// tested for.
+pub fn arch_supported() -> bool {
+    cfg!(any(target_arch = "x86_64", target_arch = "aarch64"))
+}
+
+/// Return the default hugepage size of the kernel in kB. If the kernel does
+/// not say, it is the usual one of the architecture.
+pub fn default_hugepage_size() -> u64 {
+    fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|meminfo| {
+            meminfo
+                .lines()
+                .find(|line| line.starts_with("Hugepagesize:"))
+                .and_then(|line| line.split_whitespace().nth(1))
+                .and_then(|size| size.parse().ok())
+        })
+        .unwrap_or(2048)
+}
+
+/// Return the hugepage sizes (in kB) supported by the kernel.
+pub fn hugepage_sizes() -> Vec<u64> {
+    let mut sizes: Vec<u64> = match fs::read_dir(HUGEPAGES_DIR) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let name = name.trim_start_matches("hugepages-");
+                name.trim_end_matches("kB").parse().ok()
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    sizes.sort();
+    sizes
+}
+
+/// Return the sysfs directory of hugepages of the given size (in kB).
+pub fn hugepage_dir(size: u64) -> PathBuf {
+    PathBuf::from(format!("{}/hugepages-{}kB", HUGEPAGES_DIR, size))
+}
+
+/// Return the number of hugepages of the given size (in kB) allocated at
+/// startup if there are none.
+pub fn default_nr_hugepages(size: u64) -> u64 {
+    (DEFAULT_HUGEPAGE_MEM / size.max(1)).max(1)
+}
+
+/// Return true if the kernel supports io_uring. io_uring_setup() with zero
+/// entries fails with EINVAL if it does and with ENOSYS if it does not (or
+/// EPERM if it has been disabled).
+pub fn io_uring_supported() -> bool {
+    let rc = unsafe {
+        libc::syscall(SYS_IO_URING_SETUP, 0, std::ptr::null_mut::<u8>())
+    };
+    rc < 0
+        && std::io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL)
+}
+
+/// Return the base page size of the kernel in bytes.
+fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
+
+/// Detect the capabilities of the node.
+pub fn detect() -> jsondata::Capabilities {
+    let hugepage_size = default_hugepage_size();
+    let hugepage_sizes = hugepage_sizes();
+    let io_uring = io_uring_supported();
+    let mut warnings = Vec::new();
+
+    if !arch_supported() {
+        warnings.push(format!(
+            "Architecture {} is not supported, only x86_64 and aarch64 are",
+            arch()
+        ));
+    }
+    if hugepage_sizes.is_empty() {
+        warnings.push(
+            "Kernel does not support hugepages (CONFIG_HUGETLBFS), \
+             SPDK can't allocate its memory"
+                .to_owned(),
+        );
+    } else if !hugepage_sizes.contains(&hugepage_size) {
+        warnings.push(format!(
+            "Default hugepage size {}kB is not one of supported sizes {:?}",
+            hugepage_size, hugepage_sizes
+        ));
+    }
+    if cfg!(target_arch = "aarch64") && hugepage_size >= 512 * 1024 {
+        warnings.push(format!(
+            "Hugepages of {}MiB (64KiB base pages) take a lot of memory, \
+             consider booting with default_hugepagesz=2M or using \
+             a kernel with 4KiB base pages",
+            hugepage_size / 1024
+        ));
+    }
+
+    let backends = vec![
+        jsondata::Backend {
+            name: "aio".to_owned(),
+            available: true,
+            reason: None,
+        },
+        jsondata::Backend {
+            name: "iscsi".to_owned(),
+            available: true,
+            reason: None,
+        },
+        jsondata::Backend {
+            name: "nvmf".to_owned(),
+            available: true,
+            reason: None,
+        },
+        jsondata::Backend {
+            name: "io_uring".to_owned(),
+            available: false,
+            reason: Some(if io_uring {
+                "not built into this mayastor, aio is used instead".to_owned()
+            } else {
+                "not supported by kernel (5.1 or newer is needed), \
+                 aio is used instead"
+                    .to_owned()
+            }),
+        },
+    ];
+
+    jsondata::Capabilities {
+        arch: arch().to_owned(),
+        arch_supported: arch_supported(),
+        page_size: page_size(),
+        hugepage_size,
+        hugepage_sizes,
+        io_uring,
+        backends,
+        warnings,
+    }
+}
+
+/// Log the capabilities of the node and what is wrong with them.
+pub fn report() {
+    let caps = detect();
+    info!(
+        "Running on {} with {}kB hugepages (io_uring {})",
+        caps.arch,
+        caps.hugepage_size,
+        if caps.io_uring {
+            "supported"
+        } else {
+            "not supported"
+        }
+    );
+    for warning in caps.warnings {
+        warn!("{}", warning);
+    }
+}
+
+/// Register json-rpc method for the capabilities.
+pub fn register_capabilities_methods() {
+    jsonrpc_register::<(), _, _>("get_capabilities", |_| {
+        future::ok(detect()).boxed_local()
+    });
+}
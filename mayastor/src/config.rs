@@ -0,0 +1,556 @@
+//! Snapshot of the data path configuration.
+//!
+//! Pools, replicas and nexus are saved to a json file on the node and can be
+//! re-created from it after mayastor restarts, so that the data path comes
+//! back without waiting for the control plane. The user data live on the
+//! disks, hence pools are only imported and never created from scratch.
+//! Encrypted pools are left to the control plane, which has their keys.
+//! Replicas come back with their pool, we just check that they are there.
+//! Nexus are created with the children they had when the config was saved.
+//!
+//! If MAYASTOR_CONFIG env variable is set, it is used as default path of the
+//! config file and the config is loaded automatically when mayastor starts.
+//! Paths given by callers of the methods are relative to the directory of
+//! the default config file and must not lead out of it: mayastor runs as
+//! root and must not write or read arbitrary files on behalf of the caller.
+//!
+//! The saved config is the state which the node is supposed to be in. Drift
+//! from it (pools, replicas or nexus which are missing, differ or are not in
+//! the config) is reported by `check_config` and, if
+//! MAYASTOR_CONFIG_CHECK_INTERVAL env variable is set (in seconds), checked
+//! periodically and logged. With MAYASTOR_CONFIG_REAPPLY=1 missing objects
+//! are re-created by the periodic check as they are by `load_config`.
+//! Objects which are not in the config are never destroyed.
+//!
+//! Restoring runs as `restore_config` job (see `cancel`). When cancelled, it
+//! stops before the next object and the objects restored so far are kept,
+//! so that loading the config again restores the rest.
+
+use crate::{
+    bdev::{
+        bdev_lookup_by_name,
+        nexus::{
+            instances,
+            nexus_bdev::{nexus_create, nexus_lookup},
+        },
+    },
+    cancel::Job,
+    executor,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
+    pool::{create_base_bdev, Pool, PoolsIter},
+    replica::{Replica, ReplicaIter},
+};
+use futures::FutureExt;
+use libc::c_void;
+use rpc::mayastor::{
+    CheckConfigReply,
+    CheckConfigRequest,
+    LoadConfigReply,
+    LoadConfigRequest,
+    SaveConfigReply,
+    SaveConfigRequest,
+};
+use serde::{Deserialize, Serialize};
+use spdk_sys::spdk_poller_register;
+use std::{
+    cell::Cell,
+    env,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+/// Env variable with path of the config file.
+const CONFIG_ENV: &str = "MAYASTOR_CONFIG";
+/// Config file used if none is specified (on a host path in the deployment,
+/// so that it survives re-creation of the container).
+const DEFAULT_CONFIG: &str = "/var/local/mayastor/config.json";
+/// Version of the config format. Bump it when making incompatible changes.
+const CONFIG_VERSION: u32 = 1;
+/// Env variable with period of the drift check in seconds.
+const CHECK_INTERVAL_ENV: &str = "MAYASTOR_CONFIG_CHECK_INTERVAL";
+/// Env variable enabling re-creation of missing objects by the drift check.
+const REAPPLY_ENV: &str = "MAYASTOR_CONFIG_REAPPLY";
+/// Name of the job restoring the config.
+const RESTORE_JOB: &str = "restore_config";
+
+thread_local! {
+    /// number of drift checks done so far
+    static CHECKS: Cell<u64> = Cell::new(0);
+    /// number of drift checks which have found drift
+    static DRIFTED_CHECKS: Cell<u64> = Cell::new(0);
+    /// periodic check is in progress (it may take longer than the period)
+    static CHECKING: Cell<bool> = Cell::new(false);
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolConfig {
+    name: String,
+    disk: String,
+    block_size: u32,
+    /// the key of encrypted pool is never saved, so that it does not end up
+    /// on the node in plain text
+    #[serde(default)]
+    encrypted: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReplicaConfig {
+    uuid: String,
+    pool: String,
+    size: u64,
+    thin: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct NexusConfig {
+    name: String,
+    uuid: String,
+    size: u64,
+    block_len: u32,
+    children: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    version: u32,
+    pools: Vec<PoolConfig>,
+    replicas: Vec<ReplicaConfig>,
+    nexus: Vec<NexusConfig>,
+}
+
+/// Return path of the default config file.
+fn default_config() -> String {
+    env::var(CONFIG_ENV).unwrap_or_else(|_| DEFAULT_CONFIG.to_owned())
+}
+
+/// Return path of the config file to use. A path given by the caller is
+/// resolved in the directory of the default config file.
+pub(crate) fn config_path(path: &str) -> Result<String> {
+    let default = default_config();
+    if path.is_empty() {
+        return Ok(default);
+    }
+    let rel = Path::new(path);
+    if !rel.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    }) {
+        return Err(JsonRpcError::new(
+            Code::InvalidParams,
+            format!(
+                "Config path {} must be relative and must not contain ..",
+                path
+            ),
+        ));
+    }
+    let dir = Path::new(&default)
+        .parent()
+        .unwrap_or_else(|| Path::new("/"));
+    Ok(dir.join(rel).to_string_lossy().into_owned())
+}
+
+/// Collect current configuration of the data path.
+fn snapshot() -> Config {
+    Config {
+        version: CONFIG_VERSION,
+        pools: PoolsIter::new()
+            .map(|p| {
+                let bdev = p.get_base_bdev();
+                PoolConfig {
+                    name: p.get_name().to_owned(),
+                    disk: p.get_disk(),
+                    block_size: bdev.block_size(),
+                    encrypted: p.is_encrypted(),
+                }
+            })
+            .collect(),
+        replicas: ReplicaIter::new()
+            .map(|r| ReplicaConfig {
+                uuid: r
+                    .get_clone_uuid()
+                    .unwrap_or_else(|| r.get_uuid())
+                    .to_owned(),
+                pool: r.get_pool_name().to_owned(),
+                size: r.get_size(),
+                thin: r.is_thin(),
+            })
+            .collect(),
+        nexus: instances()
+            .iter()
+            .map(|n| NexusConfig {
+                name: n.name().to_owned(),
+                uuid: n.bdev.uuid_as_string(),
+                size: n.bdev.num_blocks() * u64::from(n.bdev.block_size()),
+                block_len: n.bdev.block_size(),
+                children: n.children.iter().map(|c| c.name.clone()).collect(),
+            })
+            .collect(),
+    }
+}
+
+/// Write the config to a temporary file which is fsynced and renamed over
+/// the old one, so that a crash never leaves a partially written config
+/// behind.
+fn save(path: &str) -> Result<SaveConfigReply> {
+    let config = snapshot();
+    let json = serde_json::to_string_pretty(&config).unwrap();
+    let dir = Path::new(path)
+        .parent()
+        .map_or_else(|| PathBuf::from("/"), Path::to_path_buf);
+
+    if let Err(err) = fs::create_dir_all(&dir)
+        .and_then(|_| sysfs::write_atomic(Path::new(path), json.as_bytes()))
+    {
+        return Err(JsonRpcError::new(
+            Code::InternalError,
+            format!("Failed to save config to {}: {}", path, err),
+        ));
+    }
+    info!(
+        "Saved config of {} pools, {} replicas and {} nexus to {}",
+        config.pools.len(),
+        config.replicas.len(),
+        config.nexus.len(),
+        path
+    );
+    Ok(SaveConfigReply {
+        path: path.to_owned(),
+        pools: config.pools.len() as u32,
+        replicas: config.replicas.len() as u32,
+        nexus: config.nexus.len() as u32,
+    })
+}
+
+/// Read and parse the config file.
+fn read(path: &str) -> Result<Config> {
+    let content = fs::read_to_string(path).map_err(|err| {
+        JsonRpcError::new(
+            Code::NotFound,
+            format!("Failed to read config {}: {}", path, err),
+        )
+    })?;
+    let config: Config = serde_json::from_str(&content).map_err(|err| {
+        JsonRpcError::new(
+            Code::InvalidParams,
+            format!("Invalid config {}: {}", path, err),
+        )
+    })?;
+    if config.version != CONFIG_VERSION {
+        return Err(JsonRpcError::new(
+            Code::InvalidParams,
+            format!(
+                "Unsupported version {} of config {}",
+                config.version, path
+            ),
+        ));
+    }
+    Ok(config)
+}
+
+/// Re-create objects from the config which don't exist.
+async fn restore(config: Config, job: &Job) -> LoadConfigReply {
+    let mut reply = LoadConfigReply {
+        restored: Vec::new(),
+        failed: Vec::new(),
+    };
+
+    if let Err(err) = restore_objects(config, job, &mut reply).await {
+        warn!("{}", err);
+        reply.failed.push(err.to_string());
+    }
+    for msg in &reply.failed {
+        error!("Failed to restore {}", msg);
+    }
+    info!("Restored {} objects from config", reply.restored.len());
+    reply
+}
+
+/// Restore pools and nexus one by one. Fails only if cancelled.
+async fn restore_objects(
+    config: Config,
+    job: &Job,
+    reply: &mut LoadConfigReply,
+) -> Result<()> {
+    for pool in config.pools {
+        job.check()?;
+        if Pool::lookup(&pool.name).is_some() {
+            continue;
+        }
+        if pool.encrypted {
+            reply.failed.push(format!(
+                "pool {}: encrypted pool must be imported with its key",
+                pool.name
+            ));
+            continue;
+        }
+        if bdev_lookup_by_name(&pool.disk).is_none() {
+            if let Err(err) = create_base_bdev(&pool.disk, pool.block_size) {
+                reply.failed.push(format!("pool {}: {}", pool.name, err));
+                continue;
+            }
+        }
+        match Pool::import(&pool.name, &pool.disk).await {
+            Ok(_) => reply.restored.push(format!("pool {}", pool.name)),
+            Err(err) => {
+                reply.failed.push(format!("pool {}: {}", pool.name, err))
+            }
+        }
+    }
+
+    for replica in config.replicas {
+        if Replica::lookup(&replica.uuid).is_none() {
+            reply.failed.push(format!(
+                "replica {}: not found on pool {}",
+                replica.uuid, replica.pool
+            ));
+        }
+    }
+
+    for nexus in config.nexus {
+        job.check()?;
+        if nexus_lookup(&nexus.name).is_some() {
+            continue;
+        }
+        match nexus_create(
+            &nexus.name,
+            nexus.block_len,
+            nexus.size / u64::from(nexus.block_len),
+            Some(nexus.uuid),
+            &nexus.children,
+        )
+        .await
+        {
+            Ok(_) => reply.restored.push(format!("nexus {}", nexus.name)),
+            Err(err) => reply
+                .failed
+                .push(format!("nexus {}: {:?}", nexus.name, err)),
+        }
+    }
+    Ok(())
+}
+
+/// Return sorted copy of the names.
+fn sorted(names: &[String]) -> Vec<String> {
+    let mut names = names.to_vec();
+    names.sort();
+    names
+}
+
+/// Compare the config with the objects which exist and describe each
+/// difference.
+fn drift(config: &Config) -> Vec<String> {
+    let mut drift = Vec::new();
+
+    for pool in &config.pools {
+        match Pool::lookup(&pool.name) {
+            Some(p) if p.get_disk() != pool.disk => drift.push(format!(
+                "pool {}: on disk {} instead of {}",
+                pool.name,
+                p.get_disk(),
+                pool.disk
+            )),
+            Some(_) => (),
+            None if bdev_lookup_by_name(&pool.disk).is_none() => drift.push(
+                format!("pool {}: missing (no bdev {})", pool.name, pool.disk),
+            ),
+            None => drift.push(format!("pool {}: missing", pool.name)),
+        }
+    }
+    for p in PoolsIter::new() {
+        if !config.pools.iter().any(|pool| pool.name == p.get_name()) {
+            drift.push(format!("pool {}: not in config", p.get_name()));
+        }
+    }
+
+    for replica in &config.replicas {
+        match Replica::lookup(&replica.uuid) {
+            Some(r) if r.get_pool_name() != replica.pool => {
+                drift.push(format!(
+                    "replica {}: on pool {} instead of {}",
+                    replica.uuid,
+                    r.get_pool_name(),
+                    replica.pool
+                ))
+            }
+            Some(r) if r.get_size() != replica.size => drift.push(format!(
+                "replica {}: size {} instead of {}",
+                replica.uuid,
+                r.get_size(),
+                replica.size
+            )),
+            Some(_) => (),
+            None => drift.push(format!(
+                "replica {}: missing on pool {}",
+                replica.uuid, replica.pool
+            )),
+        }
+    }
+    for r in ReplicaIter::new() {
+        let uuid = r.get_clone_uuid().unwrap_or_else(|| r.get_uuid());
+        if !config.replicas.iter().any(|replica| replica.uuid == uuid) {
+            drift.push(format!("replica {}: not in config", uuid));
+        }
+    }
+
+    for nexus in &config.nexus {
+        match nexus_lookup(&nexus.name) {
+            Some(n) => {
+                let children: Vec<String> =
+                    n.children.iter().map(|c| c.name.clone()).collect();
+                if sorted(&children) != sorted(&nexus.children) {
+                    drift.push(format!(
+                        "nexus {}: children {:?} instead of {:?}",
+                        nexus.name, children, nexus.children
+                    ));
+                }
+            }
+            None => drift.push(format!("nexus {}: missing", nexus.name)),
+        }
+    }
+    for n in instances().iter() {
+        if !config.nexus.iter().any(|nexus| nexus.name == n.name()) {
+            drift.push(format!("nexus {}: not in config", n.name()));
+        }
+    }
+
+    drift
+}
+
+/// Check the config for drift and re-create missing objects if asked to.
+async fn check(config: Config, reapply: bool) -> CheckConfigReply {
+    let drift = drift(&config);
+
+    CHECKS.with(|checks| checks.set(checks.get() + 1));
+    if !drift.is_empty() {
+        DRIFTED_CHECKS.with(|checks| checks.set(checks.get() + 1));
+    }
+    let mut reply = CheckConfigReply {
+        drift,
+        restored: Vec::new(),
+        failed: Vec::new(),
+        checks: 0,
+        drifted_checks: 0,
+    };
+    if reapply && !reply.drift.is_empty() {
+        match Job::start(RESTORE_JOB) {
+            Ok(job) => {
+                let restored = restore(config, &job).await;
+                reply.restored = restored.restored;
+                reply.failed = restored.failed;
+            }
+            Err(err) => reply.failed.push(err.to_string()),
+        }
+    }
+    reply.checks = CHECKS.with(Cell::get);
+    reply.drifted_checks = DRIFTED_CHECKS.with(Cell::get);
+    reply
+}
+
+extern "C" fn check_tick(_ctx: *mut c_void) -> i32 {
+    if CHECKING.with(|checking| checking.replace(true)) {
+        return 0;
+    }
+    let fut = async {
+        let path = default_config();
+        let reapply = env::var(REAPPLY_ENV).map_or(false, |val| val == "1");
+        match read(&path) {
+            Ok(config) => {
+                let reply = check(config, reapply).await;
+                for msg in &reply.drift {
+                    warn!("Config drift from {}: {}", path, msg);
+                }
+            }
+            Err(err) => debug!("Config drift not checked: {}", err),
+        }
+        CHECKING.with(|checking| checking.set(false));
+    };
+    executor::get_spawner()
+        .spawn_local(fut)
+        .expect("failed to spawn config drift check");
+    0
+}
+
+/// Start the periodic drift check if it has been enabled by env variables.
+pub fn start_drift_check() {
+    if env::var(CONFIG_ENV).is_err() {
+        return;
+    }
+    let interval = match env::var(CHECK_INTERVAL_ENV)
+        .ok()
+        .and_then(|val| val.parse::<u64>().ok())
+    {
+        Some(interval) if interval > 0 => interval,
+        _ => return,
+    };
+    info!("Checking config for drift every {}s", interval);
+    unsafe {
+        spdk_poller_register(
+            Some(check_tick),
+            std::ptr::null_mut(),
+            interval * 1_000_000,
+        );
+    }
+}
+
+/// Load the config at startup if it has been enabled by env variable.
+pub async fn load_at_start() {
+    let path = match env::var(CONFIG_ENV) {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    if !Path::new(&path).exists() {
+        info!("Config {} does not exist (yet)", path);
+        return;
+    }
+    match read(&path).and_then(|config| Ok((config, Job::start(RESTORE_JOB)?)))
+    {
+        Ok((config, job)) => {
+            restore(config, &job).await;
+        }
+        Err(err) => error!("{}", err),
+    }
+}
+
+/// Register json-rpc methods for saving, loading and checking the config.
+pub fn register_config_methods() {
+    jsonrpc_register("save_config", |args: SaveConfigRequest| {
+        let fut = async move { save(&config_path(&args.path)?) };
+        fut.boxed_local()
+    });
+
+    jsonrpc_register("load_config", |args: LoadConfigRequest| {
+        let fut = async move {
+            let config = read(&config_path(&args.path)?)?;
+            let job = Job::start(RESTORE_JOB)?;
+            Ok(restore(config, &job).await)
+        };
+        fut.boxed_local()
+    });
+
+    jsonrpc_register("check_config", |args: CheckConfigRequest| {
+        let fut = async move {
+            let config = read(&config_path(&args.path)?)?;
+            Ok(check(config, args.reapply).await)
+        };
+        fut.boxed_local()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_path_stays_in_config_dir() {
+        let dir = Path::new(&default_config()).parent().unwrap().to_owned();
+
+        assert_eq!(config_path("").unwrap(), default_config());
+        assert_eq!(
+            config_path("saved/config.json").unwrap(),
+            dir.join("saved/config.json").to_string_lossy()
+        );
+        for path in &["/etc/shadow", "../config.json", "a/../../b", "./a"] {
+            assert!(config_path(path).is_err(), "{} accepted", path);
+        }
+    }
+}
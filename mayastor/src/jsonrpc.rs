@@ -41,6 +41,7 @@ pub enum Code {
     InternalError,
     NotFound,
     AlreadyExists,
+    Cancelled,
 }
 
 /// Error object returned from json-rpc method handlers
@@ -74,6 +75,7 @@ impl JsonRpcError {
             Code::InternalError => SPDK_JSONRPC_ERROR_INTERNAL_ERROR,
             Code::NotFound => -(Errno::ENOENT as i32),
             Code::AlreadyExists => -(Errno::EEXIST as i32),
+            Code::Cancelled => -(Errno::ECANCELED as i32),
         }
     }
 }
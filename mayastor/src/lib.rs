@@ -13,7 +13,11 @@ extern crate spdk_sys;
 #[macro_use]
 extern crate num_derive;
 pub mod aio_dev;
+pub mod bandwidth;
 pub mod bdev;
+pub mod cancel;
+pub mod capabilities;
+pub mod config;
 pub mod descriptor;
 pub mod executor;
 pub mod iscsi_dev;
@@ -22,8 +26,12 @@ pub mod nexus_uri;
 pub mod nvme_dev;
 pub mod nvmf_target;
 pub mod pool;
+pub mod pool_md;
 pub mod replica;
+pub mod resolver;
+pub mod scrub;
 pub mod spdklog;
+pub mod template;
 
 use futures::task::LocalSpawnExt;
 use libc::{c_char, c_int};
@@ -151,12 +159,22 @@ where
     executor::start_executor();
     pool::register_pool_methods();
     replica::register_replica_methods();
+    template::register_template_methods();
+    scrub::register_scrub_methods();
+    config::register_config_methods();
+    cancel::register_job_methods();
+    bandwidth::register_budget_methods();
+    bandwidth::init_budget();
+    capabilities::register_capabilities_methods();
     let fut = async move {
         if let Err(msg) = nvmf_target::init_nvmf().await {
             error!("Failed to initialize Mayastor nvmf target: {}", msg);
             //spdk_stop(-1);
             return;
         }
+        config::load_at_start().await;
+        config::start_drift_check();
+        resolver::start_refresh();
         let cb: Box<Box<F>> = unsafe { Box::from_raw(arg1 as *mut Box<F>) };
         cb();
     };
@@ -171,5 +189,6 @@ pub fn spdk_stop(rc: i32) {
 
 /// A callback called by spdk when it is shutting down.
 extern "C" fn mayastor_shutdown_cb() {
+    cancel::cancel_all();
     spdk_stop(0);
 }
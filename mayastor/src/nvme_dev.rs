@@ -5,6 +5,7 @@ use crate::{
     bdev::nexus,
     executor::{cb_arg, complete_callback_1},
     nexus_uri::UriError,
+    resolver,
 };
 use futures::channel::oneshot;
 use spdk_sys::{
@@ -85,17 +86,41 @@ pub struct NvmfBdev {
 }
 
 impl NvmfBdev {
+    /// Return the args of the bdev of a nexus child. The bdev is named after
+    /// the URI of the child with the suffix of the namespace (n1), which is
+    /// not part of the URI.
+    pub fn from_child_name(name: &str) -> Result<Self, UriError> {
+        let uri = if name.ends_with("n1") {
+            &name[.. name.len() - 2]
+        } else {
+            name
+        };
+        let u = Url::parse(uri).map_err(|_| UriError::InvalidScheme)?;
+        NvmfBdev::try_from(&u)
+    }
+
     /// async function to construct a bdev given a NvmfUri
-    pub async fn create(self) -> Result<String, nexus::Error> {
+    pub async fn create(mut self) -> Result<String, nexus::Error> {
         type CbType = i32;
 
-        let mut ctx = NvmeCreateCtx::new(&self);
-        let (sender, receiver) = oneshot::channel::<CbType>();
-
         if crate::bdev::bdev_lookup_by_name(&self.name).is_some() {
             return Err(nexus::Error::ChildExists);
         }
 
+        // SPDK would resolve the name on the reactor, blocking all IO
+        if resolver::is_hostname(&self.traddr) {
+            let addr =
+                resolver::resolve(&self.traddr).await.map_err(|err| {
+                    error!("{}", err);
+                    nexus::Error::Unresolvable
+                })?;
+            debug!("Connecting {} to {}", self.name, addr);
+            self.traddr = addr.to_string();
+        }
+
+        let mut ctx = NvmeCreateCtx::new(&self);
+        let (sender, receiver) = oneshot::channel::<CbType>();
+
         let str;
         // TODO add this to ctx
         let hostnqn = if self.hostnqn.is_empty() {
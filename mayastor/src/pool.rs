@@ -7,6 +7,9 @@ use crate::{
     bdev::{bdev_lookup_by_name, Bdev},
     executor::{cb_arg, complete_callback_1},
     jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
+    pool_md,
+    replica,
+    template,
 };
 use futures::{
     channel::oneshot,
@@ -15,14 +18,21 @@ use futures::{
 use rpc::jsonrpc as jsondata;
 use spdk_sys::{
     create_aio_bdev,
+    create_crypto_disk,
     delete_aio_bdev,
+    delete_crypto_disk,
     lvol_store_bdev,
+    spdk_bdev_first,
+    spdk_bdev_next,
+    spdk_blob_get_num_clusters,
+    spdk_blob_is_snapshot,
     spdk_bs_free_cluster_count,
     spdk_bs_get_cluster_size,
     spdk_bs_total_data_cluster_count,
     spdk_lvol_store,
     vbdev_get_lvol_store_by_name,
     vbdev_get_lvs_bdev_by_lvs,
+    vbdev_lvol_get_from_bdev,
     vbdev_lvol_store_first,
     vbdev_lvol_store_next,
     vbdev_lvs_create,
@@ -32,11 +42,25 @@ use spdk_sys::{
 };
 use std::{
     ffi::{c_void, CStr, CString},
+    fs::{self, File},
+    io::Read,
     os::raw::c_char,
+    path::Path,
 };
 
+/// Poll mode driver used by crypto bdevs of encrypted pools. It is a
+/// software implementation (accelerated by AES-NI), so it works without
+/// crypto hardware.
+const CRYPTO_PMD: &str = "crypto_aesni_mb";
+/// Length of the key of AES-CBC cipher used by crypto bdev.
+const CRYPTO_KEY_LEN: usize = 16;
+/// Prefix of the name of crypto bdev on top of the disk of encrypted pool.
+const CRYPTO_PREFIX: &str = "crypto:";
+/// Signature at the beginning of the super block of lvol store.
+const LVS_SIGNATURE: &[u8] = b"SPDKBLOB";
+
 /// Wrapper for create aio bdev C function
-fn create_base_bdev(file: &str, block_size: u32) -> Result<()> {
+pub(crate) fn create_base_bdev(file: &str, block_size: u32) -> Result<()> {
     debug!("Creating aio bdev {} ...", file);
     let cstr_file = CString::new(file).unwrap();
     let rc = unsafe {
@@ -53,6 +77,120 @@ fn create_base_bdev(file: &str, block_size: u32) -> Result<()> {
     }
 }
 
+/// Name of the crypto bdev which encrypts the disk of encrypted pool.
+pub(crate) fn crypto_bdev_name(disk: &str) -> String {
+    format!("{}{}", CRYPTO_PREFIX, disk)
+}
+
+/// Decode hex encoded encryption key to raw bytes.
+fn decode_key(key: &str) -> Result<Vec<u8>> {
+    let invalid = || {
+        JsonRpcError::new(
+            Code::InvalidParams,
+            format!(
+                "Encryption key must be {} bytes encoded as {} hex digits",
+                CRYPTO_KEY_LEN,
+                2 * CRYPTO_KEY_LEN
+            ),
+        )
+    };
+    if key.len() != 2 * CRYPTO_KEY_LEN
+        || !key.bytes().all(|b| b.is_ascii_hexdigit())
+    {
+        return Err(invalid());
+    }
+    (0 .. CRYPTO_KEY_LEN)
+        .map(|i| u8::from_str_radix(&key[2 * i .. 2 * i + 2], 16))
+        .collect::<std::result::Result<Vec<u8>, _>>()
+        .map_err(|_| invalid())
+}
+
+/// Create crypto bdev on top of the base bdev of the disk. Everything
+/// written through it (lvol store metadata included) is encrypted by the
+/// key of the pool (hex encoded).
+pub(crate) fn create_crypto_bdev(disk: &str, key: &str) -> Result<()> {
+    // SPDK takes the raw key as a C string, so it can't have a zero byte
+    let cstr_key = CString::new(decode_key(key)?).map_err(|_| {
+        JsonRpcError::new(
+            Code::InvalidParams,
+            "Encryption key must not contain zero bytes",
+        )
+    })?;
+    let name = crypto_bdev_name(disk);
+    debug!("Creating crypto bdev {} ...", name);
+    let cstr_disk = CString::new(disk).unwrap();
+    let cstr_name = CString::new(name.as_str()).unwrap();
+    let cstr_pmd = CString::new(CRYPTO_PMD).unwrap();
+    let rc = unsafe {
+        create_crypto_disk(
+            cstr_disk.as_ptr(),
+            cstr_name.as_ptr(),
+            cstr_pmd.as_ptr(),
+            cstr_key.as_ptr(),
+        )
+    };
+    if rc != 0 {
+        Err(JsonRpcError::new(
+            Code::InternalError,
+            format!("Failed to create crypto bdev {} (errno={})", name, -rc),
+        ))
+    } else {
+        info!("crypto bdev {} was created", name);
+        Ok(())
+    }
+}
+
+/// True if there is an unencrypted lvol store on the disk. Without the
+/// check, a pool which was created without encryption would be overwritten
+/// by a new encrypted pool, because it cannot be imported through the
+/// crypto bdev.
+fn has_plain_pool(disk: &str) -> bool {
+    let mut sig = [0u8; 8];
+    File::open(disk)
+        .and_then(|mut f| f.read_exact(&mut sig))
+        .map(|_| sig == LVS_SIGNATURE)
+        .unwrap_or(false)
+}
+
+/// Return zoned model of the block device ("none", "host-aware" or
+/// "host-managed") as reported by the kernel. None is returned if the disk
+/// is not a block device known to sysfs (i.e. a file).
+fn zoned_model(disk: &str) -> Option<String> {
+    let dev = fs::canonicalize(disk).ok()?;
+    let name = dev.file_name()?.to_str()?.to_owned();
+    let dir = Path::new("/sys/class/block").join(name);
+    // partitions don't have queue attributes, they are on the parent device
+    let queue = if dir.join("queue").exists() {
+        dir.join("queue")
+    } else {
+        dir.join("..").join("queue")
+    };
+    sysfs::parse_value(&queue, "zoned").ok()
+}
+
+/// Zoned devices require sequential writes within a zone, which lvol store
+/// does not honour. A pool created on a host-managed device would fail
+/// writes later on, so we refuse to create it in the first place.
+fn check_zoned(disk: &str) -> Result<()> {
+    match zoned_model(disk).as_ref().map(String::as_str) {
+        Some("host-managed") => Err(JsonRpcError::new(
+            Code::InvalidParams,
+            format!(
+                "Disk {} is a host-managed zoned device, which cannot be used for a pool",
+                disk
+            ),
+        )),
+        Some("host-aware") => {
+            warn!(
+                "Disk {} is a host-aware zoned device, random writes may perform poorly",
+                disk
+            );
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
 /// Callback called from SPDK for pool create and import methods.
 extern "C" fn pool_done_cb(
     sender_ptr: *mut c_void,
@@ -111,12 +249,28 @@ impl Pool {
         }
     }
 
-    /// Get base bdev for the pool (in our case AIO bdev).
+    /// Get base bdev for the pool (AIO bdev or crypto bdev on top of it if
+    /// the pool is encrypted).
     pub fn get_base_bdev(&self) -> Bdev {
         let base_bdev_ptr = unsafe { (*self.lvs_bdev_ptr).bdev };
         base_bdev_ptr.into()
     }
 
+    /// True if the pool is encrypted at rest.
+    pub fn is_encrypted(&self) -> bool {
+        self.get_base_bdev().name().starts_with(CRYPTO_PREFIX)
+    }
+
+    /// Get the disk of the pool (name of the AIO bdev).
+    pub fn get_disk(&self) -> String {
+        let name = self.get_base_bdev().name();
+        if name.starts_with(CRYPTO_PREFIX) {
+            name[CRYPTO_PREFIX.len() ..].to_owned()
+        } else {
+            name
+        }
+    }
+
     /// Get capacity of the pool in bytes.
     pub fn get_capacity(&self) -> u64 {
         unsafe {
@@ -137,6 +291,39 @@ impl Pool {
         }
     }
 
+    /// Get breakdown of the used space by type of lvols which occupy it and
+    /// size of the metadata on the base bdev.
+    pub fn get_usage(&self) -> jsondata::PoolUsage {
+        let mut usage = jsondata::PoolUsage::default();
+        let cluster_size =
+            unsafe { spdk_bs_get_cluster_size((*self.lvs_ptr).blobstore) };
+        // walk all bdevs and not just the leaves, because lvols used by a
+        // nexus are claimed
+        let mut bdev_ptr = unsafe { spdk_bdev_first() };
+
+        while !bdev_ptr.is_null() {
+            let lvol = unsafe { vbdev_lvol_get_from_bdev(bdev_ptr) };
+            if !lvol.is_null() && unsafe { (*lvol).lvol_store } == self.lvs_ptr
+            {
+                let blob = unsafe { (*lvol).blob };
+                let bytes =
+                    unsafe { spdk_blob_get_num_clusters(blob) } * cluster_size;
+                if unsafe { spdk_blob_is_snapshot(blob) } {
+                    usage.snapshots += bytes;
+                } else {
+                    usage.volumes += bytes;
+                }
+            }
+            bdev_ptr = unsafe { spdk_bdev_next(bdev_ptr) };
+        }
+
+        let base_bdev = self.get_base_bdev();
+        usage.metadata = (u64::from(base_bdev.block_size())
+            * base_bdev.num_blocks())
+        .saturating_sub(self.get_capacity());
+        usage
+    }
+
     /// Return raw pointer to spdk lvol store structure
     pub fn as_ptr(&self) -> *mut spdk_lvol_store {
         self.lvs_ptr
@@ -190,6 +377,7 @@ impl Pool {
         match Pool::lookup(&name) {
             Some(pool) => {
                 info!("The pool {} has been created", name);
+                pool_md::save(&pool);
                 Ok(pool)
             }
             None => Err(JsonRpcError::new(
@@ -228,6 +416,9 @@ impl Pool {
             match Pool::lookup(&name) {
                 Some(pool) => {
                     info!("The pool {} has been imported", name);
+                    pool_md::check_import(&pool);
+                    template::alias_clones(&name);
+                    replica::report_quarantined();
                     Ok(pool)
                 }
                 None => Err(JsonRpcError::new(
@@ -249,7 +440,8 @@ impl Pool {
     /// Destroy the pool
     pub async fn destroy(self) -> Result<()> {
         let name = self.get_name().to_string();
-        let base_bdev_name = self.get_base_bdev().name();
+        let encrypted = self.is_encrypted();
+        let base_bdev_name = self.get_disk();
 
         // we will destroy lvol store now
         let (sender, receiver) = oneshot::channel::<i32>();
@@ -270,6 +462,33 @@ impl Pool {
                 ),
             ));
         }
+        pool_md::remove(&name);
+
+        // crypto bdev of encrypted pool is between the lvol store and aio bdev
+        if encrypted {
+            let crypto_name = crypto_bdev_name(&base_bdev_name);
+            if let Some(bdev) = bdev_lookup_by_name(&crypto_name) {
+                let (sender, receiver) = oneshot::channel::<i32>();
+                unsafe {
+                    delete_crypto_disk(
+                        bdev.as_ptr(),
+                        Some(complete_callback_1),
+                        cb_arg(sender),
+                    );
+                }
+                let errno =
+                    receiver.await.expect("Cancellation is not supported");
+                if errno != 0 {
+                    return Err(JsonRpcError::new(
+                        Code::InternalError,
+                        format!(
+                            "Failed to destroy crypto bdev {} for the pool {} (errno={})",
+                            crypto_name, name, errno
+                        ),
+                    ));
+                }
+            }
+        }
 
         // we will destroy base bdev now
         let base_bdev = match bdev_lookup_by_name(&base_bdev_name) {
@@ -353,11 +572,13 @@ fn list_pools() -> Vec<jsondata::Pool> {
     for pool in PoolsIter::new() {
         pools.push(jsondata::Pool {
             name: pool.get_name().to_owned(),
-            disks: vec![pool.get_base_bdev().name()],
+            disks: vec![pool.get_disk()],
             // TODO: figure out how to detect state of pool
             state: "online".to_owned(),
             capacity: pool.get_capacity(),
             used: pool.get_capacity() - pool.get_free(),
+            usage: pool.get_usage(),
+            encrypted: pool.is_encrypted(),
         });
     }
     pools
@@ -396,16 +617,35 @@ pub fn register_pool_methods() {
                         format!("Base bdev {} already exists", disk),
                     ));
                 }
+                check_zoned(disk)?;
+                if args.encryption_key.is_some() && has_plain_pool(disk) {
+                    return Err(JsonRpcError::new(
+                        Code::InvalidParams,
+                        format!(
+                            "Disk {} hosts a pool which is not encrypted",
+                            disk
+                        ),
+                    ));
+                }
                 if let Err(err) =
                     create_base_bdev(disk, args.block_size.unwrap_or(0))
                 {
                     return Err(err);
                 };
+                // lvol store of encrypted pool lives on the crypto bdev
+                let bdev = match &args.encryption_key {
+                    Some(key) => {
+                        create_crypto_bdev(disk, key)?;
+                        crypto_bdev_name(disk)
+                    }
+                    None => disk.to_owned(),
+                };
 
-                if Pool::import(&args.name, disk).await.is_ok() {
+                if Pool::import(&args.name, &bdev).await.is_ok() {
                     return Ok(());
                 }
-                match Pool::create(&args.name, disk).await {
+                pool_md::check_create(&args.name, disk)?;
+                match Pool::create(&args.name, &bdev).await {
                     Ok(_) => Ok(()),
                     Err(err) => Err(err),
                 }
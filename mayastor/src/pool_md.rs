@@ -0,0 +1,364 @@
+//! Mirrored copies of pool metadata.
+//!
+//! Lvol store keeps its metadata in a region at the beginning of the disk.
+//! If the region gets damaged, the pool cannot be imported although the
+//! data of the replicas may still be intact. Worse, create_or_import_pool
+//! would create a new pool over the disk and the data would be lost for
+//! good. To prevent that, a copy of the pool metadata (the disk and the
+//! replicas with their sizes) is kept in each of the directories listed in
+//! MAYASTOR_POOL_MD_MIRRORS env variable (colon separated), which should
+//! reside on other devices than the pools.
+//!
+//! Each copy starts with a line with CRC32 of the rest of the file, so that
+//! damaged copies are detected and ignored. The copies are updated whenever
+//! a pool or a replica is created or destroyed, and they carry a generation
+//! number, so that the most recent valid copy wins. When a pool is imported,
+//! its replicas are checked against the metadata.
+//!
+//! The copies are a guard, not a backup: they don't hold the lvol store
+//! metadata itself, so a pool with damaged metadata region cannot be
+//! restored from them. They only prevent a new pool from being created over
+//! the data and tell which replicas have been lost.
+
+use crate::{
+    jsonrpc::{Code, JsonRpcError, Result},
+    pool::Pool,
+    replica::ReplicaIter,
+};
+use serde::{Deserialize, Serialize};
+use spdk_sys::spdk_bs_get_cluster_size;
+use std::{
+    env,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Env variable with directories for the copies of pool metadata.
+const MIRRORS_ENV: &str = "MAYASTOR_POOL_MD_MIRRORS";
+/// Version of the metadata format. Bump it when making incompatible changes.
+const MD_VERSION: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct ReplicaMd {
+    uuid: String,
+    size: u64,
+    thin: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PoolMd {
+    version: u32,
+    /// incremented with each update of the metadata
+    generation: u64,
+    name: String,
+    disk: String,
+    block_size: u32,
+    cluster_size: u64,
+    replicas: Vec<ReplicaMd>,
+}
+
+/// CRC32 (IEEE) of the data.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Directories with the copies of metadata (empty if mirroring is off).
+fn mirror_dirs() -> Vec<PathBuf> {
+    match env::var(MIRRORS_ENV) {
+        Ok(dirs) => dirs
+            .split(':')
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn md_path(dir: &Path, pool: &str) -> PathBuf {
+    dir.join(format!("pool-{}.md", pool))
+}
+
+/// Collect metadata of the pool as it is now.
+fn snapshot(pool: &Pool, generation: u64) -> PoolMd {
+    let bdev = pool.get_base_bdev();
+    let name = pool.get_name().to_owned();
+    let mut replicas: Vec<ReplicaMd> = ReplicaIter::new()
+        .filter(|r| r.get_pool_name() == name)
+        .map(|r| ReplicaMd {
+            uuid: r
+                .get_clone_uuid()
+                .unwrap_or_else(|| r.get_uuid())
+                .to_owned(),
+            size: r.get_size(),
+            thin: r.is_thin(),
+        })
+        .collect();
+    replicas.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+    PoolMd {
+        version: MD_VERSION,
+        generation,
+        name,
+        disk: pool.get_disk(),
+        block_size: bdev.block_size(),
+        cluster_size: unsafe {
+            spdk_bs_get_cluster_size((*pool.as_ptr()).blobstore)
+        },
+        replicas,
+    }
+}
+
+/// Read one copy of the metadata and verify its checksum.
+fn read_copy(path: &Path) -> std::result::Result<Option<PoolMd>, String> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => {
+            return Err(format!("Failed to read {}: {}", path.display(), err))
+        }
+    };
+    let (header, body) = match content.find('\n') {
+        Some(idx) => (&content[.. idx], &content[idx + 1 ..]),
+        None => return Err(format!("Missing checksum in {}", path.display())),
+    };
+    let checksum = if header.starts_with("crc32 ") {
+        u32::from_str_radix(header[6 ..].trim(), 16).ok()
+    } else {
+        None
+    };
+    if checksum != Some(crc32(body.as_bytes())) {
+        return Err(format!("Checksum mismatch in {}", path.display()));
+    }
+    let md: PoolMd = serde_json::from_str(body).map_err(|err| {
+        format!("Invalid metadata {}: {}", path.display(), err)
+    })?;
+    if md.version != MD_VERSION {
+        return Err(format!(
+            "Unsupported version {} of metadata {}",
+            md.version,
+            path.display()
+        ));
+    }
+    Ok(Some(md))
+}
+
+/// Return the most recent valid copy of the pool metadata. Damaged copies
+/// are reported and skipped.
+fn load(pool: &str) -> Option<PoolMd> {
+    load_from(&mirror_dirs(), pool)
+}
+
+/// Return the most recent valid copy of the pool metadata in the dirs.
+fn load_from(dirs: &[PathBuf], pool: &str) -> Option<PoolMd> {
+    let mut newest: Option<PoolMd> = None;
+
+    for dir in dirs {
+        match read_copy(&md_path(dir, pool)) {
+            Ok(Some(md)) => {
+                if newest
+                    .as_ref()
+                    .map_or(true, |n| md.generation > n.generation)
+                {
+                    newest = Some(md);
+                }
+            }
+            Ok(None) => (),
+            Err(msg) => warn!("Ignoring copy of pool metadata: {}", msg),
+        }
+    }
+    newest
+}
+
+/// Write one copy of the metadata atomically.
+fn write_copy(path: &Path, body: &str) -> std::io::Result<()> {
+    let content = format!("crc32 {:08x}\n{}", crc32(body.as_bytes()), body);
+
+    sysfs::write_atomic(path, content.as_bytes())
+}
+
+/// Update all copies of the pool metadata. Failing copies are reported, but
+/// they don't fail the operation which has changed the pool.
+pub(crate) fn save(pool: &Pool) {
+    let dirs = mirror_dirs();
+    if dirs.is_empty() {
+        return;
+    }
+    let generation = load(pool.get_name()).map_or(0, |md| md.generation + 1);
+    let md = snapshot(pool, generation);
+    let body = serde_json::to_string_pretty(&md).unwrap();
+    let mut saved = 0;
+
+    for dir in dirs {
+        let path = md_path(&dir, &md.name);
+        match fs::create_dir_all(&dir).and_then(|_| write_copy(&path, &body)) {
+            Ok(()) => saved += 1,
+            Err(err) => error!(
+                "Failed to write copy of pool metadata {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    debug!(
+        "Saved {} copies of metadata of pool {} (generation {})",
+        saved, md.name, generation
+    );
+}
+
+/// Remove all copies of the pool metadata when the pool is destroyed.
+pub(crate) fn remove(pool: &str) {
+    for dir in mirror_dirs() {
+        let path = md_path(&dir, pool);
+        if let Err(err) = fs::remove_file(&path) {
+            if err.kind() != ErrorKind::NotFound {
+                error!(
+                    "Failed to remove copy of pool metadata {}: {}",
+                    path.display(),
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// Check replicas of an imported pool against the metadata. If some are
+/// missing, the copies are left as they are, so that the information about
+/// the missing replicas is not lost.
+pub(crate) fn check_import(pool: &Pool) {
+    let md = match load(pool.get_name()) {
+        Some(md) => md,
+        None => {
+            save(pool);
+            return;
+        }
+    };
+    let current = snapshot(pool, md.generation);
+    let missing: Vec<&str> = md
+        .replicas
+        .iter()
+        .filter(|r| !current.replicas.contains(r))
+        .map(|r| r.uuid.as_str())
+        .collect();
+
+    if missing.is_empty() {
+        save(pool);
+    } else {
+        error!(
+            "Replicas {} of pool {} recorded in its metadata are missing",
+            missing.join(", "),
+            md.name
+        );
+    }
+}
+
+/// Fail if the disk is known to host a pool according to the metadata,
+/// which means that the lvol store metadata on the disk is damaged and
+/// creating a new pool would destroy the data of the replicas.
+pub(crate) fn check_create(pool: &str, disk: &str) -> Result<()> {
+    match load(pool) {
+        Some(ref md) if md.disk == disk => Err(JsonRpcError::new(
+            Code::InternalError,
+            format!(
+                "Pool {} with {} replicas exists on {} according to its \
+                 metadata copy, but it cannot be imported (damaged \
+                 metadata?); refusing to create a new pool over it",
+                pool,
+                md.replicas.len(),
+                disk
+            ),
+        )),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool_md(generation: u64) -> PoolMd {
+        PoolMd {
+            version: MD_VERSION,
+            generation,
+            name: "pool".to_owned(),
+            disk: "aio:///dev/sdb".to_owned(),
+            block_size: 512,
+            cluster_size: 4 * 1024 * 1024,
+            replicas: vec![ReplicaMd {
+                uuid: "r1".to_owned(),
+                size: 1024 * 1024 * 1024,
+                thin: false,
+            }],
+        }
+    }
+
+    fn write_md(dir: &Path, md: &PoolMd) -> PathBuf {
+        let path = md_path(dir, &md.name);
+        fs::create_dir_all(dir).unwrap();
+        write_copy(&path, &serde_json::to_string_pretty(md).unwrap()).unwrap();
+        path
+    }
+
+    #[test]
+    fn crc32_of_check_string() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn damaged_copies_are_detected() {
+        let dir = env::temp_dir().join("mayastor-pool-md-read-test");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(read_copy(&md_path(&dir, "pool")).unwrap().is_none());
+        let path = write_md(&dir, &pool_md(3));
+        assert!(!sysfs::tmp_path(&path).exists());
+        let md = read_copy(&path).unwrap().unwrap();
+        assert_eq!(md.generation, 3);
+        assert_eq!(md.replicas, pool_md(3).replicas);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replace("sdb", "sdc")).unwrap();
+        assert!(read_copy(&path).unwrap_err().contains("Checksum mismatch"));
+        fs::write(&path, "{}").unwrap();
+        assert!(read_copy(&path).unwrap_err().contains("Missing checksum"));
+
+        let mut md = pool_md(3);
+        md.version = MD_VERSION + 1;
+        write_md(&dir, &md);
+        assert!(read_copy(&path).unwrap_err().contains("version"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newest_valid_copy_wins() {
+        let base = env::temp_dir().join("mayastor-pool-md-load-test");
+        let _ = fs::remove_dir_all(&base);
+        let dirs: Vec<PathBuf> =
+            (0 .. 3).map(|i| base.join(i.to_string())).collect();
+
+        assert!(load_from(&dirs, "pool").is_none());
+        write_md(&dirs[0], &pool_md(1));
+        write_md(&dirs[1], &pool_md(5));
+        let damaged = write_md(&dirs[2], &pool_md(7));
+        assert_eq!(load_from(&dirs, "pool").unwrap().generation, 7);
+
+        // a torn copy of the newest generation is skipped
+        let content = fs::read_to_string(&damaged).unwrap();
+        fs::write(&damaged, &content[.. content.len() / 2]).unwrap();
+        assert_eq!(load_from(&dirs, "pool").unwrap().generation, 5);
+        assert!(load_from(&dirs, "other").is_none());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -2,12 +2,27 @@
 //!
 //! Replica is a logical data volume exported over nvmf (in SPDK terminology
 //! an lvol). Here we define methods for easy management of replicas.
+//!
+//! Data of a replica can be transparently compressed by SPDK compress bdev
+//! on top of the lvol. The compress bdev is named `COMP_<uuid>` and it is
+//! the bdev which is used for IO. Its metadata are stored in the lvol
+//! itself and in persistent memory file in the directory given by
+//! `MAYASTOR_COMPRESS_PM_DIR` env variable, so the compress bdev is
+//! recreated automatically when the pool is imported.
+//!
+//! Lvols on a pool which have not been created by mayastor (i.e. by SPDK's
+//! rpc script) are quarantined: they are listed with the replicas and
+//! flagged as such, so that they are not overlooked, but they can't be used
+//! as replicas until they are adopted (`adopt_replica`). Mayastor's lvols
+//! are named by the uuid of their bdev, so an adopted lvol is renamed to it.
 
 use crate::{
     bdev::{bdev_first, bdev_lookup_by_name, Bdev},
     executor::{cb_arg, complete_callback_1},
     jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
     pool::Pool,
+    pool_md,
+    template,
 };
 use futures::{
     channel::oneshot,
@@ -15,16 +30,115 @@ use futures::{
 };
 use rpc::jsonrpc as jsondata;
 use spdk_sys::{
+    create_compress_bdev,
+    delete_compress_bdev,
+    spdk_blob_get_num_clusters,
+    spdk_blob_is_clone,
+    spdk_blob_is_snapshot,
+    spdk_bs_get_cluster_size,
     spdk_lvol,
+    spdk_poller,
+    spdk_poller_register,
+    spdk_poller_unregister,
     vbdev_lvol_create_with_uuid,
     vbdev_lvol_destroy,
     vbdev_lvol_get_from_bdev,
+    vbdev_lvol_rename,
     LVOL_CLEAR_WITH_DEFAULT,
 };
-use std::ffi::{c_void, CStr, CString};
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    env,
+    ffi::{c_void, CStr, CString},
+    fs,
+    time::{Duration, Instant},
+};
+
+/// Prefix of the name of compress bdev on top of the lvol.
+const COMPRESS_PREFIX: &str = "COMP_";
+/// Directory for persistent memory files of compress bdevs if not set by
+/// env variable.
+const DEFAULT_PM_DIR: &str = "/var/tmp/mayastor-pmem";
+/// Size of the chunk of reduce volume (fixed by compress bdev).
+const CHUNK_SIZE: u64 = 16 * 1024;
+/// Reduce volume needs some spare chunks on the backing device in addition
+/// to the chunks of the logical volume.
+const REDUCE_EXTRA_CHUNKS: u64 = 128;
+/// How long to wait for the compress bdev to appear after it was created.
+const COMPRESS_TIMEOUT: Duration = Duration::from_secs(10);
+
+thread_local! {
+    /// quarantined lvols (pool/name) which have been reported already
+    static REPORTED: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+}
+
+/// Name of the compress bdev of the replica.
+fn compress_bdev_name(uuid: &str) -> String {
+    format!("{}{}", COMPRESS_PREFIX, uuid)
+}
+
+/// Directory with persistent memory files of compress bdevs.
+fn compress_pm_dir() -> String {
+    env::var("MAYASTOR_COMPRESS_PM_DIR")
+        .unwrap_or_else(|_| DEFAULT_PM_DIR.to_owned())
+}
+
+/// Size of the lvol needed for compressed replica of given size.
+fn compress_backing_size(size: u64) -> u64 {
+    ((size + CHUNK_SIZE - 1) / CHUNK_SIZE + REDUCE_EXTRA_CHUNKS) * CHUNK_SIZE
+}
+
+/// State of the poller waiting for a bdev.
+struct BdevWait {
+    name: String,
+    deadline: Instant,
+    poller: *mut spdk_poller,
+    sender: Option<oneshot::Sender<bool>>,
+}
+
+/// Poller callback which checks if the awaited bdev exists.
+extern "C" fn bdev_wait_poll(ctx: *mut c_void) -> i32 {
+    let wait = unsafe { &mut *(ctx as *mut BdevWait) };
+    let found = bdev_lookup_by_name(&wait.name).is_some();
+
+    if found || Instant::now() >= wait.deadline {
+        if let Some(sender) = wait.sender.take() {
+            let _ = sender.send(found);
+        }
+        unsafe {
+            spdk_poller_unregister(&mut wait.poller);
+            drop(Box::from_raw(ctx as *mut BdevWait));
+        }
+    }
+    0
+}
+
+/// Wait for bdev which is created asynchronously by SPDK. Return false if
+/// it has not appeared in time.
+async fn wait_for_bdev(name: &str, timeout: Duration) -> bool {
+    if bdev_lookup_by_name(name).is_some() {
+        return true;
+    }
+    let (sender, receiver) = oneshot::channel::<bool>();
+    let wait = Box::into_raw(Box::new(BdevWait {
+        name: name.to_owned(),
+        deadline: Instant::now() + timeout,
+        poller: std::ptr::null_mut(),
+        sender: Some(sender),
+    }));
+    unsafe {
+        (*wait).poller = spdk_poller_register(
+            Some(bdev_wait_poll),
+            wait as *mut c_void,
+            1000,
+        );
+    }
+    receiver.await.unwrap_or(false)
+}
 
 /// Callback called from SPDK for replica create method.
-extern "C" fn replica_done_cb(
+pub(crate) extern "C" fn replica_done_cb(
     sender_ptr: *mut c_void,
     _lvol_ptr: *mut spdk_lvol,
     errno: i32,
@@ -40,7 +154,7 @@ extern "C" fn replica_done_cb(
 /// It is safe to use only in synchronous context. If you keep Replica for
 /// longer than that then something else can run on reactor_0 inbetween
 /// which may destroy the replica and invalidate the pointer!
-struct Replica {
+pub(crate) struct Replica {
     lvol_ptr: *mut spdk_lvol,
 }
 
@@ -49,12 +163,16 @@ impl Replica {
     ///
     /// Contrary to expectation this method does not return created Replica.
     /// It can be added later when needed.
+    ///
+    /// Compressed replica is always thin provisioned, because the lvol is
+    /// bigger than the replica and the saved space would be lost otherwise.
     // TODO: Check if the lvol exists, if it does then return "exist error".
     pub async fn create(
         uuid: &str,
         pool: &str,
         size: u64,
         thin: bool,
+        compress: bool,
     ) -> Result<()> {
         let lvs = match Pool::lookup(pool) {
             Some(p) => p.as_ptr(),
@@ -65,6 +183,11 @@ impl Replica {
                 ));
             }
         };
+        let (size, thin) = if compress {
+            (compress_backing_size(size), true)
+        } else {
+            (size, thin)
+        };
         let c_uuid = CString::new(uuid).unwrap();
         let (sender, receiver) = oneshot::channel::<i32>();
         let rc = unsafe {
@@ -91,12 +214,60 @@ impl Replica {
 
         let errno = receiver.await.expect("Cancellation is not supported");
         if errno != 0 {
-            Err(JsonRpcError::new(
+            return Err(JsonRpcError::new(
                 Code::InvalidParams,
                 format!("Failed to create replica {} (errno={})", uuid, errno),
-            ))
-        } else {
+            ));
+        }
+        if compress {
+            if let Err(err) = Self::create_compress(uuid).await {
+                if let Some(replica) = Self::lookup(uuid) {
+                    if let Err(err) = replica.destroy().await {
+                        error!("{}", err);
+                    }
+                }
+                return Err(err);
+            }
+        }
+        if let Some(pool) = Pool::lookup(pool) {
+            pool_md::save(&pool);
+        }
+        Ok(())
+    }
+
+    /// Create compress bdev on top of the lvol of the replica.
+    async fn create_compress(uuid: &str) -> Result<()> {
+        let pm_dir = compress_pm_dir();
+        if let Err(err) = fs::create_dir_all(&pm_dir) {
+            return Err(JsonRpcError::new(
+                Code::InternalError,
+                format!("Failed to create directory {}: {}", pm_dir, err),
+            ));
+        }
+        let name = compress_bdev_name(uuid);
+        debug!("Creating compress bdev {} ...", name);
+        let c_uuid = CString::new(uuid).unwrap();
+        let c_pm_dir = CString::new(pm_dir).unwrap();
+        let rc =
+            unsafe { create_compress_bdev(c_uuid.as_ptr(), c_pm_dir.as_ptr()) };
+        if rc != 0 {
+            return Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to create compress bdev {} (errno={})",
+                    name, -rc
+                ),
+            ));
+        }
+        // the reduce volume is initialized asynchronously
+        if wait_for_bdev(&name, COMPRESS_TIMEOUT).await {
+            info!("compress bdev {} was created", name);
             Ok(())
+        } else {
+            Err(JsonRpcError::new(
+                Code::InternalError,
+                format!("compress bdev {} has not been created", name),
+            ))
         }
     }
 
@@ -124,6 +295,31 @@ impl Replica {
     // destroy fails.
     // TODO: Check if it exists and return ENOENT if it does not.
     pub async fn destroy(self) -> Result<()> {
+        let pool_name = self.get_pool_name().to_owned();
+
+        // compress bdev claims the lvol so it must go first
+        if let Some(bdev) = self.get_compress_bdev() {
+            let (sender, receiver) = oneshot::channel::<i32>();
+            unsafe {
+                delete_compress_bdev(
+                    bdev.as_ptr(),
+                    Some(complete_callback_1),
+                    cb_arg(sender),
+                );
+            }
+            let errno = receiver.await.expect("Cancellation is not supported");
+            if errno != 0 {
+                return Err(JsonRpcError::new(
+                    Code::InternalError,
+                    format!(
+                        "Failed to destroy compress bdev of replica {} (errno={})",
+                        self.get_uuid(),
+                        errno
+                    ),
+                ));
+            }
+        }
+
         let (sender, receiver) = oneshot::channel::<i32>();
         unsafe {
             vbdev_lvol_destroy(
@@ -144,6 +340,11 @@ impl Replica {
                 ),
             ))
         } else {
+            // it might have been the last clone of a retired template
+            template::collect_garbage().await;
+            if let Some(pool) = Pool::lookup(&pool_name) {
+                pool_md::save(&pool);
+            }
             Ok(())
         }
     }
@@ -154,6 +355,41 @@ impl Replica {
         u64::from(bdev.block_size()) * bdev.num_blocks()
     }
 
+    /// Get compress bdev on top of the lvol if the replica is compressed.
+    pub fn get_compress_bdev(&self) -> Option<Bdev> {
+        bdev_lookup_by_name(&compress_bdev_name(self.get_uuid()))
+    }
+
+    /// Return if data of the replica are compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.get_compress_bdev().is_some()
+    }
+
+    /// Get bdev with the data of the replica as seen by its users (compress
+    /// bdev if the replica is compressed).
+    pub fn get_data_bdev(&self) -> Bdev {
+        self.get_compress_bdev()
+            .unwrap_or_else(|| unsafe { (*self.lvol_ptr).bdev.into() })
+    }
+
+    /// Get size of the replica as seen by its users. It is smaller than
+    /// the size of the lvol if the replica is compressed.
+    pub fn get_logical_size(&self) -> u64 {
+        match self.get_compress_bdev() {
+            Some(bdev) => u64::from(bdev.block_size()) * bdev.num_blocks(),
+            None => self.get_size(),
+        }
+    }
+
+    /// Get number of bytes allocated in the pool by the replica.
+    pub fn get_allocated(&self) -> u64 {
+        unsafe {
+            let lvol = &*self.lvol_ptr;
+            spdk_blob_get_num_clusters(lvol.blob)
+                * spdk_bs_get_cluster_size((*lvol.lvol_store).blobstore)
+        }
+    }
+
     /// Get name of the pool which replica belongs to.
     pub fn get_pool_name(&self) -> &str {
         unsafe {
@@ -171,27 +407,147 @@ impl Replica {
         }
     }
 
+    /// Get name of the lvol. It differs from the uuid for clones and for
+    /// lvols which have not been created by mayastor.
+    pub(crate) fn get_name(&self) -> &str {
+        unsafe {
+            CStr::from_ptr(&(*self.lvol_ptr).name as *const i8)
+                .to_str()
+                .unwrap()
+        }
+    }
+
+    /// Get uuid of the replica if it is a clone of a template. SPDK gives
+    /// clones random lvol uuids, so the uuid of the replica is the name of
+    /// the lvol (and an alias of the bdev) instead.
+    pub fn get_clone_uuid(&self) -> Option<&str> {
+        if self.is_clone() {
+            Some(self.get_name())
+        } else {
+            None
+        }
+    }
+
     /// Return if replica has been thin provisioned.
     pub fn is_thin(&self) -> bool {
         unsafe { (*self.lvol_ptr).thin_provision }
     }
 
+    /// Return if the replica is a clone of a template (or has become one
+    /// when a template was made from it).
+    pub fn is_clone(&self) -> bool {
+        unsafe { spdk_blob_is_clone((*self.lvol_ptr).blob) }
+    }
+
+    /// Return if the lvol is a snapshot (template) rather than a replica.
+    fn is_snapshot(&self) -> bool {
+        unsafe { spdk_blob_is_snapshot((*self.lvol_ptr).blob) }
+    }
+
     /// Return raw pointer to lvol (C struct spdk_lvol).
     pub fn as_ptr(&self) -> *mut spdk_lvol {
         self.lvol_ptr
     }
+
+    /// Bring the quarantined lvol under management by renaming it to the
+    /// uuid of its bdev, and return the uuid of the new replica.
+    pub async fn adopt(pool: &str, name: &str) -> Result<String> {
+        let replica = match ReplicaIter::quarantined()
+            .find(|r| r.get_pool_name() == pool && r.get_name() == name)
+        {
+            Some(replica) => replica,
+            None => {
+                return Err(JsonRpcError::new(
+                    Code::NotFound,
+                    format!(
+                        "Quarantined lvol {}/{} does not exist",
+                        pool, name
+                    ),
+                ));
+            }
+        };
+        let bdev: Bdev = unsafe { (*replica.lvol_ptr).bdev.into() };
+        let uuid = bdev.name();
+        if ReplicaIter::new()
+            .any(|r| r.get_clone_uuid().unwrap_or_else(|| r.get_uuid()) == uuid)
+        {
+            return Err(JsonRpcError::new(
+                Code::AlreadyExists,
+                format!("Replica {} already exists", uuid),
+            ));
+        }
+
+        let c_uuid = CString::new(uuid.clone()).unwrap();
+        let (sender, receiver) = oneshot::channel::<i32>();
+        unsafe {
+            vbdev_lvol_rename(
+                replica.lvol_ptr,
+                c_uuid.as_ptr(),
+                Some(complete_callback_1),
+                cb_arg(sender),
+            );
+        }
+        let errno = receiver.await.expect("Cancellation is not supported");
+        if errno != 0 {
+            return Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to adopt lvol {}/{} (errno={})",
+                    pool, name, errno
+                ),
+            ));
+        }
+        info!("Adopted lvol {}/{} as replica {}", pool, name, uuid);
+        REPORTED.with(|reported| {
+            reported.borrow_mut().remove(&format!("{}/{}", pool, name))
+        });
+        if let Some(pool) = Pool::lookup(pool) {
+            pool_md::save(&pool);
+        }
+        Ok(uuid)
+    }
+}
+
+/// Warn about quarantined lvols which have not been reported yet.
+pub(crate) fn report_quarantined() {
+    let found: HashSet<String> = ReplicaIter::quarantined()
+        .map(|r| format!("{}/{}", r.get_pool_name(), r.get_name()))
+        .collect();
+
+    REPORTED.with(|reported| {
+        let mut reported = reported.borrow_mut();
+        for name in found.difference(&reported) {
+            warn!(
+                "Lvol {} has not been created by mayastor, it is quarantined until adopted",
+                name
+            );
+        }
+        *reported = found;
+    });
 }
 
 /// Iterator over replicas
-struct ReplicaIter {
+pub(crate) struct ReplicaIter {
     /// Last bdev examined by the iterator during the call to next()
     bdev: Option<Bdev>,
+    /// Return quarantined lvols instead of replicas
+    quarantined: bool,
 }
 
 impl ReplicaIter {
-    fn new() -> ReplicaIter {
+    pub(crate) fn new() -> ReplicaIter {
         ReplicaIter {
             bdev: None,
+            quarantined: false,
+        }
+    }
+
+    /// Iterate over lvols on the pools which have not been created by
+    /// mayastor.
+    pub(crate) fn quarantined() -> ReplicaIter {
+        ReplicaIter {
+            bdev: None,
+            quarantined: true,
         }
     }
 }
@@ -219,15 +575,20 @@ impl Iterator for ReplicaIter {
                     let alias = aliases.remove(0);
                     let parts: Vec<&str> = alias.split('/').collect();
 
-                    if parts.len() == 2 && bdev.name() == parts[1] {
+                    if parts.len() == 2 {
                         let replica = Replica {
                             lvol_ptr: lvol,
                         };
 
-                        if replica.get_pool_name() == parts[0] {
-                            // our lvols have uuid == name
-                            if bdev.name() == replica.get_uuid() {
-                                // we found a replica
+                        if replica.get_pool_name() == parts[0]
+                            && !replica.is_snapshot()
+                        {
+                            // our lvols have uuid == name except clones of
+                            // templates, which have the name as an alias
+                            let ours = bdev.name() == replica.get_name()
+                                || replica.is_clone();
+                            if ours != self.quarantined {
+                                // we found a replica (or a foreign lvol)
                                 self.bdev = Some(bdev);
                                 return Some(replica);
                             }
@@ -249,6 +610,7 @@ pub fn register_replica_methods() {
                 &args.pool,
                 args.size,
                 args.thin_provision,
+                args.compress,
             )
             .await
         };
@@ -272,17 +634,44 @@ pub fn register_replica_methods() {
     );
 
     jsonrpc_register::<(), _, _>("list_replicas", |_| {
-        future::ok(
-            ReplicaIter::new()
-                .map(|r| jsondata::Replica {
-                    uuid: r.get_uuid().to_owned(),
-                    pool: r.get_pool_name().to_owned(),
-                    size: r.get_size(),
-                    thin_provision: r.is_thin(),
-                })
-                .collect::<Vec<jsondata::Replica>>(),
-        )
-        .boxed_local()
+        report_quarantined();
+        let mut replicas = ReplicaIter::new()
+            .map(|r| jsondata::Replica {
+                uuid: r
+                    .get_clone_uuid()
+                    .unwrap_or_else(|| r.get_uuid())
+                    .to_owned(),
+                pool: r.get_pool_name().to_owned(),
+                size: r.get_logical_size(),
+                thin_provision: r.is_thin(),
+                compressed: r.is_compressed(),
+                template: template::of_replica(&r),
+                quarantined: false,
+            })
+            .collect::<Vec<jsondata::Replica>>();
+        replicas.extend(ReplicaIter::quarantined().map(|r| {
+            jsondata::Replica {
+                uuid: r.get_name().to_owned(),
+                pool: r.get_pool_name().to_owned(),
+                size: r.get_size(),
+                thin_provision: r.is_thin(),
+                compressed: false,
+                template: None,
+                quarantined: true,
+            }
+        }));
+        future::ok(replicas).boxed_local()
+    });
+
+    jsonrpc_register("adopt_replica", |args: jsondata::AdoptReplicaArgs| {
+        let fut = async move {
+            Replica::adopt(&args.pool, &args.name).await.map(|uuid| {
+                jsondata::AdoptReplicaReply {
+                    uuid,
+                }
+            })
+        };
+        fut.boxed_local()
     });
 
     jsonrpc_register::<(), _, _>("stat_replicas", |_| {
@@ -293,9 +682,19 @@ pub fn register_replica_methods() {
             // switch!?
             for r in ReplicaIter::new() {
                 let lvol = r.as_ptr();
-                let uuid = r.get_uuid().to_owned();
+                let uuid = r
+                    .get_clone_uuid()
+                    .unwrap_or_else(|| r.get_uuid())
+                    .to_owned();
                 let pool = r.get_pool_name().to_owned();
-                let bdev: Bdev = unsafe { (*lvol).bdev.into() };
+                let compressed = r.is_compressed();
+                let size = r.get_logical_size();
+                let allocated = r.get_allocated();
+                // IO of compressed replica goes through the compress bdev
+                let bdev: Bdev = match r.get_compress_bdev() {
+                    Some(bdev) => bdev,
+                    None => unsafe { (*lvol).bdev.into() },
+                };
 
                 // cancelation point here
                 let st = bdev.stats().await;
@@ -309,6 +708,9 @@ pub fn register_replica_methods() {
                             num_write_ops: st.num_write_ops,
                             bytes_read: st.bytes_read,
                             bytes_written: st.bytes_written,
+                            compressed,
+                            size,
+                            allocated,
                         });
                     }
                     Err(errno) => {
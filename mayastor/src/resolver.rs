@@ -0,0 +1,266 @@
+//! Asynchronous resolution of host names of remote nexus children.
+//!
+//! A nvmf child can reference its replica by host name (i.e. a service in
+//! front of the replica) instead of an IP address. SPDK would resolve the
+//! name on the reactor, so we resolve it on a helper thread using the system
+//! resolver and connect to the address. The address is cached for the TTL
+//! and resolved again in the background when the TTL expires. The system
+//! resolver does not tell the TTL of the records, so it is a fixed time
+//! (`MAYASTOR_DNS_TTL` seconds, 30 by default).
+//!
+//! When the address of a host changes, the children connected to the old
+//! address are reconnected to the new one, so that a replica failing over
+//! behind a service VIP does not require the nexus to be re-created. A
+//! child which fails to reconnect is retried every second. If the host
+//! resolves to more addresses (round-robin DNS), the children stay connected
+//! for as long as their address is one of them.
+
+use crate::{
+    bdev::nexus::{instances, nexus_bdev::nexus_lookup},
+    executor,
+};
+use futures::{channel::oneshot, future::join_all};
+use libc::c_void;
+use spdk_sys::spdk_poller_register;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    net::{IpAddr, ToSocketAddrs},
+    thread,
+    time::{Duration, Instant},
+};
+use url::Url;
+
+/// TTL of resolved addresses in seconds unless set by MAYASTOR_DNS_TTL
+const DEFAULT_TTL: u64 = 30;
+/// how often the TTLs are checked (in us)
+const REFRESH_PERIOD: u64 = 1_000_000;
+
+/// Resolved address of a host
+#[derive(Debug)]
+struct Entry {
+    /// address which the children are connected to
+    addr: IpAddr,
+    /// when the address should be resolved again
+    expires: Instant,
+}
+
+thread_local! {
+    static CACHE: RefCell<HashMap<String, Entry>> = RefCell::new(HashMap::new());
+    /// children which failed to reconnect as (nexus, child)
+    static PENDING: RefCell<HashSet<(String, String)>> =
+        RefCell::new(HashSet::new());
+    /// refresh from the previous tick has not finished yet
+    static BUSY: Cell<bool> = Cell::new(false);
+}
+
+fn ttl() -> Duration {
+    let secs = std::env::var("MAYASTOR_DNS_TTL")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(DEFAULT_TTL);
+    Duration::from_secs(secs)
+}
+
+/// Return true if the host is a name rather than an IP address.
+pub fn is_hostname(host: &str) -> bool {
+    host.parse::<IpAddr>().is_err()
+}
+
+/// Resolve IPv4 addresses of the host on a helper thread.
+async fn lookup(host: String) -> Result<Vec<IpAddr>, String> {
+    let (sender, receiver) = oneshot::channel();
+    let name = host.clone();
+
+    thread::Builder::new()
+        .name("resolver".to_owned())
+        .spawn(move || {
+            let res = (name.as_str(), 0)
+                .to_socket_addrs()
+                .map(|addrs| {
+                    addrs
+                        .map(|a| a.ip())
+                        .filter(IpAddr::is_ipv4)
+                        .collect::<Vec<_>>()
+                })
+                .map_err(|err| err.to_string());
+            let _ = sender.send(res);
+        })
+        .map_err(|err| format!("Failed to start resolver thread: {}", err))?;
+
+    match receiver.await {
+        Ok(Ok(addrs)) => {
+            if addrs.is_empty() {
+                Err(format!("Host {} has no IPv4 address", host))
+            } else {
+                Ok(addrs)
+            }
+        }
+        Ok(Err(err)) => Err(format!("Failed to resolve {}: {}", host, err)),
+        Err(_) => Err(format!("Resolver of {} has crashed", host)),
+    }
+}
+
+/// Return the address of the host. The cached address is returned if there
+/// is one, since the children of the host use it until the refresh finds
+/// out that it has changed.
+pub async fn resolve(host: &str) -> Result<IpAddr, String> {
+    if let Some(addr) =
+        CACHE.with(|cache| cache.borrow().get(host).map(|e| e.addr))
+    {
+        return Ok(addr);
+    }
+    let addrs = lookup(host.to_owned()).await?;
+    let addr = CACHE.with(|cache| {
+        cache
+            .borrow_mut()
+            .entry(host.to_owned())
+            .or_insert_with(|| Entry {
+                addr: addrs[0],
+                expires: Instant::now() + ttl(),
+            })
+            .addr
+    });
+    debug!("Resolved {} to {}", host, addr);
+    Ok(addr)
+}
+
+/// Start refreshing addresses of the hosts of nexus children.
+pub fn start_refresh() {
+    info!(
+        "Host names of nexus children are resolved every {:?}",
+        ttl()
+    );
+    unsafe {
+        spdk_poller_register(
+            Some(refresh),
+            std::ptr::null_mut(),
+            REFRESH_PERIOD,
+        );
+    }
+}
+
+extern "C" fn refresh(_ctx: *mut c_void) -> i32 {
+    if !BUSY.with(|busy| busy.replace(true)) {
+        executor::get_spawner()
+            .spawn_local(async {
+                refresh_hosts().await;
+                BUSY.with(|busy| busy.set(false));
+            })
+            .expect("failed to spawn address refresh");
+    }
+    0
+}
+
+/// Return nvmf children of all nexus instances, which reference their host
+/// by name, as (nexus, child, host).
+fn named_children() -> Vec<(String, String, String)> {
+    let mut children = Vec::new();
+    for nexus in instances().iter() {
+        for child in &nexus.children {
+            let url = match Url::parse(&child.name) {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+            if url.scheme() != "nvmf" {
+                continue;
+            }
+            if let Some(host) = url.host_str().filter(|h| is_hostname(h)) {
+                children.push((
+                    nexus.name().to_owned(),
+                    child.name.clone(),
+                    host.to_owned(),
+                ));
+            }
+        }
+    }
+    children
+}
+
+/// Reconnect the child and remember it for retry if it fails.
+async fn reconnect(nexus_name: &str, child_name: &str) {
+    let key = (nexus_name.to_owned(), child_name.to_owned());
+    let nexus = match nexus_lookup(nexus_name) {
+        Some(nexus) => nexus,
+        None => {
+            PENDING.with(|pending| pending.borrow_mut().remove(&key));
+            return;
+        }
+    };
+    match nexus.reconnect_child(child_name).await {
+        Ok(_) => {
+            info!("{}: Reconnected child {}", nexus_name, child_name);
+            PENDING.with(|pending| pending.borrow_mut().remove(&key));
+        }
+        Err(err) => {
+            warn!(
+                "{}: Failed to reconnect child {}: {:?}",
+                nexus_name, child_name, err
+            );
+            PENDING.with(|pending| pending.borrow_mut().insert(key));
+        }
+    }
+}
+
+/// Retry failed reconnects, resolve hosts with expired TTL and reconnect
+/// children of hosts which have changed the address.
+async fn refresh_hosts() {
+    let children = named_children();
+
+    let pending: Vec<(String, String)> = PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.retain(|(nexus, child)| {
+            children.iter().any(|(n, c, _)| n == nexus && c == child)
+        });
+        pending.iter().cloned().collect()
+    });
+    for (nexus, child) in pending {
+        reconnect(&nexus, &child).await;
+    }
+
+    // forget hosts which are not used by any child
+    let now = Instant::now();
+    let due: Vec<String> = CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.retain(|host, _| children.iter().any(|(_, _, h)| h == host));
+        cache
+            .iter()
+            .filter(|(_, entry)| entry.expires <= now)
+            .map(|(host, _)| host.clone())
+            .collect()
+    });
+    if due.is_empty() {
+        return;
+    }
+
+    let results = join_all(due.iter().map(|host| lookup(host.clone()))).await;
+    for (host, res) in due.into_iter().zip(results) {
+        let changed = CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            let entry = cache.get_mut(&host)?;
+            entry.expires = Instant::now() + ttl();
+            match res {
+                Ok(addrs) => {
+                    if addrs.contains(&entry.addr) {
+                        None
+                    } else {
+                        let old = entry.addr;
+                        entry.addr = addrs[0];
+                        Some((old, entry.addr))
+                    }
+                }
+                Err(err) => {
+                    // keep the old address, the resolver may be down
+                    warn!("{}", err);
+                    None
+                }
+            }
+        });
+        if let Some((old, new)) = changed {
+            info!("Address of {} has changed from {} to {}", host, old, new);
+            for (nexus, child, _) in children.iter().filter(|c| c.2 == host) {
+                reconnect(nexus, child).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! Scrubber computing checksums of the data of replicas.
+//!
+//! Scrubbing a replica reads all of its data (as seen by its users, so the
+//! compress bdev if the replica is compressed) and computes SHA-256 of it.
+//! The last result for each replica is kept together with the time when the
+//! scrub finished, so that checksums of all replicas can be collected for
+//! integrity manifests. The results are saved in mayastor's config dir and
+//! survive restarts of mayastor.
+//!
+//! A checksum is meaningful only if the replica has not been written to
+//! while it was scrubbed. Therefore published replicas (children of a nexus
+//! or exported replicas) are not scrubbed and the result is thrown away if
+//! the replica has been published before the scrub finished.
+//!
+//! The reads count against the background IO budget of the node.
+
+use crate::{
+    bandwidth::Share,
+    bdev::bdev_lookup_by_name,
+    cancel::Job,
+    config,
+    descriptor::Descriptor,
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
+    replica::Replica,
+};
+use futures::future::{self, FutureExt};
+use rpc::jsonrpc as jsondata;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Size of the reads done by the scrubber.
+const CHUNK_SIZE: u64 = 1024 * 1024;
+/// Weight of a scrub when sharing the background IO budget.
+const SCRUB_WEIGHT: u64 = 1;
+/// File with the results in mayastor's config dir.
+const RESULTS_FILE: &str = "scrub_results.json";
+
+lazy_static! {
+    /// last scrub result by uuid of the replica
+    static ref RESULTS: Mutex<HashMap<String, jsondata::ScrubResult>> =
+        Mutex::new(load_results(&results_path()));
+}
+
+fn results_path() -> String {
+    config::config_path(RESULTS_FILE).unwrap()
+}
+
+/// Load saved results. Missing or damaged file is not fatal, the replicas
+/// are just scrubbed again.
+fn load_results(path: &str) -> HashMap<String, jsondata::ScrubResult> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            return HashMap::new()
+        }
+        Err(err) => {
+            error!("Failed to read scrub results {}: {}", path, err);
+            return HashMap::new();
+        }
+    };
+    match serde_json::from_slice::<Vec<jsondata::ScrubResult>>(&data) {
+        Ok(results) => {
+            results.into_iter().map(|r| (r.uuid.clone(), r)).collect()
+        }
+        Err(err) => {
+            error!("Invalid scrub results {}: {}", path, err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Save the results, so that a power loss leaves either the old or the new
+/// file behind.
+fn save_results(
+    path: &str,
+    results: &HashMap<String, jsondata::ScrubResult>,
+) -> std::io::Result<()> {
+    let mut results: Vec<&jsondata::ScrubResult> = results.values().collect();
+    results.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+    let json = serde_json::to_string_pretty(&results).unwrap();
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new("/"));
+
+    fs::create_dir_all(dir)
+        .and_then(|_| sysfs::write_atomic(Path::new(path), json.as_bytes()))
+}
+
+/// Return true if the bdev is opened by someone (nexus, nbd, target).
+fn is_published(bdev_name: &str) -> bool {
+    bdev_lookup_by_name(bdev_name).map_or(false, |bdev| bdev.is_open())
+}
+
+/// Read all data of the replica and compute their checksum. If the scrub
+/// is cancelled, the result of the previous scrub is kept.
+async fn scrub(uuid: &str) -> Result<jsondata::ScrubResult> {
+    let name = format!("scrub_replica/{}", uuid);
+    let job = Job::start(&name)?;
+    let (bdev_name, size) = match Replica::lookup(uuid) {
+        Some(replica) => {
+            let bdev = replica.get_data_bdev();
+            (
+                bdev.name(),
+                u64::from(bdev.block_size()) * bdev.num_blocks(),
+            )
+        }
+        None => {
+            return Err(JsonRpcError::new(
+                Code::NotFound,
+                format!("Replica {} does not exist", uuid),
+            ))
+        }
+    };
+    if is_published(&bdev_name) {
+        return Err(JsonRpcError::new(
+            Code::InvalidParams,
+            format!("Replica {} is published and cannot be scrubbed", uuid),
+        ));
+    }
+    // the open descriptor keeps the bdev from going away under us
+    let desc = match Descriptor::open(&bdev_name, false) {
+        Some(desc) => desc,
+        None => {
+            return Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to open bdev {} of replica {}",
+                    bdev_name, uuid
+                ),
+            ))
+        }
+    };
+    debug!("Scrubbing replica {} ({} bytes)", uuid, size);
+    let share = Share::join(&name, SCRUB_WEIGHT);
+
+    let mut hasher = Sha256::new();
+    let mut offset = 0;
+    let mut res = Ok(());
+    // reads are as long as the buffer, so the tail of the replica shorter
+    // than a chunk needs its own buffer
+    let mut chunk_buf = None;
+    let mut tail_buf = None;
+
+    while offset < size {
+        if let Err(err) = job.check() {
+            res = Err(err);
+            break;
+        }
+        let len = (size - offset).min(CHUNK_SIZE) as usize;
+        share.acquire(len as u64).await;
+        let slot = if len as u64 == CHUNK_SIZE {
+            &mut chunk_buf
+        } else {
+            &mut tail_buf
+        };
+        if slot.is_none() {
+            *slot = desc.dma_malloc(len);
+        }
+        let buf = match slot {
+            Some(buf) => buf,
+            None => {
+                res = Err(JsonRpcError::new(
+                    Code::InternalError,
+                    "Failed to allocate buffer for scrubbing",
+                ));
+                break;
+            }
+        };
+        if let Err(errno) = desc.read_at(offset, buf).await {
+            res = Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to read replica {} at offset {} (errno={})",
+                    uuid, offset, errno
+                ),
+            ));
+            break;
+        }
+        hasher.input(buf.as_slice());
+        offset += len as u64;
+    }
+    desc.close();
+    res?;
+    // the scrubber does not keep others from opening the replica
+    if is_published(&bdev_name) {
+        return Err(JsonRpcError::new(
+            Code::InvalidParams,
+            format!("Replica {} has been published while scrubbed", uuid),
+        ));
+    }
+
+    let result = jsondata::ScrubResult {
+        uuid: uuid.to_owned(),
+        size,
+        checksum: hasher
+            .result()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect(),
+        scrubbed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+    info!("Scrubbed replica {}: sha256 {}", uuid, result.checksum);
+    let mut results = RESULTS.lock().unwrap();
+    results.insert(uuid.to_owned(), result.clone());
+    // the checksum is still good for this call if it is not saved
+    if let Err(err) = save_results(&results_path(), &results) {
+        error!("Failed to save scrub results: {}", err);
+    }
+    Ok(result)
+}
+
+/// Register scrubber json-rpc methods.
+pub fn register_scrub_methods() {
+    jsonrpc_register("scrub_replica", |args: jsondata::ScrubReplicaArgs| {
+        let fut = async move { scrub(&args.uuid).await };
+        fut.boxed_local()
+    });
+
+    jsonrpc_register::<(), _, _>("list_scrub_results", |_| {
+        let mut results = RESULTS.lock().unwrap();
+
+        // forget replicas which have been destroyed
+        let count = results.len();
+        results.retain(|uuid, _| Replica::lookup(uuid).is_some());
+        if results.len() != count {
+            if let Err(err) = save_results(&results_path(), &results) {
+                error!("Failed to save scrub results: {}", err);
+            }
+        }
+        future::ok(results.values().cloned().collect::<Vec<_>>()).boxed_local()
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn result(uuid: &str) -> jsondata::ScrubResult {
+        jsondata::ScrubResult {
+            uuid: uuid.to_owned(),
+            size: 8 * 1024 * 1024,
+            checksum: format!("{:064x}", uuid.len()),
+            scrubbed: 1_580_000_000,
+        }
+    }
+
+    #[test]
+    fn results_survive_restart() {
+        let dir = env::temp_dir().join("mayastor-scrub-test");
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join(RESULTS_FILE).to_string_lossy().into_owned();
+
+        assert!(load_results(&path).is_empty());
+        let mut results = HashMap::new();
+        for uuid in &["r1", "r22"] {
+            results.insert(uuid.to_string(), result(uuid));
+        }
+        save_results(&path, &results).unwrap();
+        let loaded = load_results(&path);
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded["r22"].checksum, results["r22"].checksum);
+        assert_eq!(loaded["r1"].scrubbed, 1_580_000_000);
+        assert!(!sysfs::tmp_path(Path::new(&path)).exists());
+
+        // damaged results are dropped and the replicas scrubbed again
+        fs::write(&path, "[{\"uuid\":").unwrap();
+        assert!(load_results(&path).is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,496 @@
+//! Golden image templates and their thin clones.
+//!
+//! A template is a read-only snapshot of a replica (an lvol snapshot in
+//! SPDK terminology). Any number of replicas can be cloned from it quickly,
+//! because the clones share the data of the template and only their own
+//! writes allocate space in the pool (copy-on-write). The replica which the
+//! template was made from becomes a clone of the template as well.
+//!
+//! SPDK gives the clones random lvol uuids, so the name of the clone (which
+//! is the uuid of the replica) is added as an alias to the clone bdev, so
+//! that the clone can be found by the uuid like any other replica. Aliases
+//! are not persistent and they are added again when the pool is imported.
+//!
+//! The lvol of a template is named after its uuid with a "template-" prefix,
+//! so that it is not mistaken for a snapshot made for other purposes. Only
+//! replicas which are not published can be made templates, because the
+//! data would change while the snapshot is being taken.
+//!
+//! A template cannot be destroyed while it has clones. Destroying such a
+//! template retires it instead: no more clones can be made from it and it
+//! is garbage collected when its last clone is destroyed. The retirement is
+//! persistent, because the lvol of the template is renamed.
+
+use crate::{
+    bdev::{bdev_first, bdev_lookup_by_name, Bdev},
+    executor::{cb_arg, complete_callback_1},
+    jsonrpc::{jsonrpc_register, Code, JsonRpcError, Result},
+    pool::Pool,
+    pool_md,
+    replica::{replica_done_cb, Replica},
+};
+use futures::{
+    channel::oneshot,
+    future::{self, FutureExt},
+};
+use rpc::jsonrpc as jsondata;
+use spdk_sys::{
+    spdk_blob_get_clones,
+    spdk_blob_get_id,
+    spdk_blob_get_parent_snapshot,
+    spdk_blob_id,
+    spdk_blob_is_clone,
+    spdk_blob_is_snapshot,
+    spdk_lvol,
+    vbdev_lvol_create_clone,
+    vbdev_lvol_create_snapshot,
+    vbdev_lvol_destroy,
+    vbdev_lvol_get_from_bdev,
+    vbdev_lvol_rename,
+};
+use std::ffi::{CStr, CString};
+
+/// Prefix of the lvol name of template, which tells templates apart from
+/// other snapshots in the pool.
+const TEMPLATE_PREFIX: &str = "template-";
+/// Suffix of the lvol name of retired template.
+const RETIRED_SUFFIX: &str = ".retired";
+
+/// Template is a snapshot lvol.
+///
+/// The same note about safety as for Replica applies: don't keep it across
+/// await points.
+pub(crate) struct Template {
+    lvol_ptr: *mut spdk_lvol,
+}
+
+impl Template {
+    /// Get name of the lvol (uuid of the template with the template prefix
+    /// and retired suffix).
+    fn get_name(&self) -> &str {
+        unsafe {
+            CStr::from_ptr(&(*self.lvol_ptr).name as *const i8)
+                .to_str()
+                .unwrap()
+        }
+    }
+
+    /// Get uuid of the template.
+    pub fn get_uuid(&self) -> &str {
+        let name = &self.get_name()[TEMPLATE_PREFIX.len() ..];
+        if self.is_retired() {
+            &name[.. name.len() - RETIRED_SUFFIX.len()]
+        } else {
+            name
+        }
+    }
+
+    /// Return if the template is waiting for its clones to be destroyed.
+    pub fn is_retired(&self) -> bool {
+        self.get_name().ends_with(RETIRED_SUFFIX)
+    }
+
+    /// Get name of the pool which template belongs to.
+    pub fn get_pool_name(&self) -> &str {
+        unsafe {
+            let lvs = &*(*self.lvol_ptr).lvol_store;
+            CStr::from_ptr(&lvs.name as *const i8).to_str().unwrap()
+        }
+    }
+
+    /// Get size of the template (and of its clones) in bytes.
+    pub fn get_size(&self) -> u64 {
+        let bdev: Bdev = unsafe { (*self.lvol_ptr).bdev.into() };
+        u64::from(bdev.block_size()) * bdev.num_blocks()
+    }
+
+    /// Get id of the blob of the template.
+    fn get_blob_id(&self) -> spdk_blob_id {
+        unsafe { spdk_blob_get_id((*self.lvol_ptr).blob) }
+    }
+
+    /// Get number of clones of the template (the reference count).
+    pub fn count_clones(&self) -> usize {
+        let mut count: usize = 0;
+        // without a buffer for the ids it just returns the count
+        unsafe {
+            spdk_blob_get_clones(
+                (*(*self.lvol_ptr).lvol_store).blobstore,
+                self.get_blob_id(),
+                std::ptr::null_mut(),
+                &mut count,
+            );
+        }
+        count
+    }
+
+    /// Lookup template by uuid.
+    pub fn lookup(uuid: &str) -> Option<Template> {
+        templates().into_iter().find(|t| t.get_uuid() == uuid)
+    }
+
+    /// Make a template from the replica. The replica becomes the first
+    /// clone of the template.
+    pub async fn create(uuid: &str, replica_uuid: &str) -> Result<()> {
+        if Self::lookup(uuid).is_some() {
+            return Err(JsonRpcError::new(
+                Code::AlreadyExists,
+                format!("The template {} already exists", uuid),
+            ));
+        }
+        let replica = match Replica::lookup(replica_uuid) {
+            Some(replica) => replica,
+            None => {
+                return Err(JsonRpcError::new(
+                    Code::NotFound,
+                    format!("Replica {} does not exist", replica_uuid),
+                ))
+            }
+        };
+        // the data of compressed replica are in the compress bdev format
+        if replica.is_compressed() {
+            return Err(JsonRpcError::new(
+                Code::InvalidParams,
+                format!(
+                    "Compressed replica {} cannot be a template",
+                    replica_uuid
+                ),
+            ));
+        }
+        // writes of the consumer would race with the snapshot
+        if replica.get_data_bdev().is_open() {
+            return Err(JsonRpcError::new(
+                Code::InvalidParams,
+                format!(
+                    "Replica {} is published and cannot be a template",
+                    replica_uuid
+                ),
+            ));
+        }
+        let pool = replica.get_pool_name().to_owned();
+        let c_uuid =
+            CString::new(format!("{}{}", TEMPLATE_PREFIX, uuid)).unwrap();
+        let (sender, receiver) = oneshot::channel::<i32>();
+        unsafe {
+            vbdev_lvol_create_snapshot(
+                replica.as_ptr(),
+                c_uuid.as_ptr(),
+                Some(replica_done_cb),
+                cb_arg(sender),
+            );
+        }
+        let errno = receiver.await.expect("Cancellation is not supported");
+        if errno != 0 {
+            return Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to create template {} from replica {} (errno={})",
+                    uuid, replica_uuid, errno
+                ),
+            ));
+        }
+        info!("Created template {} from replica {}", uuid, replica_uuid);
+        if let Some(pool) = Pool::lookup(&pool) {
+            pool_md::save(&pool);
+        }
+        Ok(())
+    }
+
+    /// Create thin clones of the template with given uuids. The clones are
+    /// created one by one and the first failure stops the rest.
+    pub async fn clone_to(uuid: &str, clones: &[String]) -> Result<()> {
+        let pool = match Self::lookup(uuid) {
+            Some(template) => {
+                if template.is_retired() {
+                    return Err(JsonRpcError::new(
+                        Code::InvalidParams,
+                        format!("The template {} has been retired", uuid),
+                    ));
+                }
+                template.get_pool_name().to_owned()
+            }
+            None => {
+                return Err(JsonRpcError::new(
+                    Code::NotFound,
+                    format!("The template {} does not exist", uuid),
+                ))
+            }
+        };
+        if let Some(clone) =
+            clones.iter().find(|c| Replica::lookup(c).is_some())
+        {
+            return Err(JsonRpcError::new(
+                Code::AlreadyExists,
+                format!("Replica {} already exists", clone),
+            ));
+        }
+
+        let mut res = Ok(());
+        for (i, clone) in clones.iter().enumerate() {
+            // the template could have been destroyed in the meantime
+            let template = match Self::lookup(uuid) {
+                Some(template) => template,
+                None => {
+                    res = Err(JsonRpcError::new(
+                        Code::NotFound,
+                        format!("The template {} does not exist", uuid),
+                    ));
+                    break;
+                }
+            };
+            let c_clone = CString::new(clone.as_str()).unwrap();
+            let (sender, receiver) = oneshot::channel::<i32>();
+            unsafe {
+                vbdev_lvol_create_clone(
+                    template.as_ptr(),
+                    c_clone.as_ptr(),
+                    Some(replica_done_cb),
+                    cb_arg(sender),
+                );
+            }
+            let errno = receiver.await.expect("Cancellation is not supported");
+            if errno != 0 {
+                res = Err(JsonRpcError::new(
+                    Code::InternalError,
+                    format!(
+                        "Failed to create clone {} of template {} after {} clones (errno={})",
+                        clone, uuid, i, errno
+                    ),
+                ));
+                break;
+            }
+            add_alias(&pool, clone);
+        }
+        if res.is_ok() {
+            info!("Created {} clones of template {}", clones.len(), uuid);
+        }
+        if let Some(pool) = Pool::lookup(&pool) {
+            pool_md::save(&pool);
+        }
+        res
+    }
+
+    /// Destroy the template if it has no clones, otherwise retire it.
+    pub async fn destroy(self) -> Result<()> {
+        let uuid = self.get_uuid().to_owned();
+        let clones = self.count_clones();
+
+        if clones == 0 {
+            return self.destroy_lvol().await;
+        }
+        if self.is_retired() {
+            return Ok(());
+        }
+        let c_name = CString::new(format!(
+            "{}{}{}",
+            TEMPLATE_PREFIX, uuid, RETIRED_SUFFIX
+        ))
+        .unwrap();
+        let (sender, receiver) = oneshot::channel::<i32>();
+        unsafe {
+            vbdev_lvol_rename(
+                self.lvol_ptr,
+                c_name.as_ptr(),
+                Some(complete_callback_1),
+                cb_arg(sender),
+            );
+        }
+        let errno = receiver.await.expect("Cancellation is not supported");
+        if errno != 0 {
+            Err(JsonRpcError::new(
+                Code::InternalError,
+                format!("Failed to retire template {} (errno={})", uuid, errno),
+            ))
+        } else {
+            info!(
+                "Template {} has been retired and will be destroyed with its last clone ({} left)",
+                uuid, clones
+            );
+            Ok(())
+        }
+    }
+
+    /// Destroy the lvol of the template.
+    async fn destroy_lvol(self) -> Result<()> {
+        let uuid = self.get_uuid().to_owned();
+        let pool = self.get_pool_name().to_owned();
+        let (sender, receiver) = oneshot::channel::<i32>();
+        unsafe {
+            vbdev_lvol_destroy(
+                self.lvol_ptr,
+                Some(complete_callback_1),
+                cb_arg(sender),
+            );
+        }
+        let errno = receiver.await.expect("Cancellation is not supported");
+        if errno != 0 {
+            Err(JsonRpcError::new(
+                Code::InternalError,
+                format!(
+                    "Failed to destroy template {} (errno={})",
+                    uuid, errno
+                ),
+            ))
+        } else {
+            info!("Destroyed template {}", uuid);
+            if let Some(pool) = Pool::lookup(&pool) {
+                pool_md::save(&pool);
+            }
+            Ok(())
+        }
+    }
+
+    /// Return raw pointer to lvol (C struct spdk_lvol).
+    pub fn as_ptr(&self) -> *mut spdk_lvol {
+        self.lvol_ptr
+    }
+}
+
+/// Return all templates in all pools. Snapshots which have not been made
+/// by create_template method are not templates.
+fn templates() -> Vec<Template> {
+    let mut templates = Vec::new();
+    let mut maybe_bdev = bdev_first();
+
+    while let Some(mut bdev) = maybe_bdev {
+        let lvol = unsafe { vbdev_lvol_get_from_bdev(bdev.as_ptr()) };
+        if !lvol.is_null() && unsafe { spdk_blob_is_snapshot((*lvol).blob) } {
+            let template = Template {
+                lvol_ptr: lvol,
+            };
+            if template.get_name().starts_with(TEMPLATE_PREFIX) {
+                templates.push(template);
+            }
+        }
+        maybe_bdev = bdev.next();
+    }
+    templates
+}
+
+/// Add uuid of the replica as an alias to the bdev of the clone.
+fn add_alias(pool: &str, uuid: &str) {
+    // each lvol has an alias of form "pool/lvol-name"
+    match bdev_lookup_by_name(&format!("{}/{}", pool, uuid)) {
+        Some(bdev) => {
+            if bdev.name() != uuid && !bdev.add_alias(uuid) {
+                error!("Failed to add alias {} to bdev {}", uuid, bdev.name());
+            }
+        }
+        None => error!("Cannot find bdev of clone {}", uuid),
+    }
+}
+
+/// Add aliases to the clones in the pool after it has been imported.
+pub(crate) fn alias_clones(pool: &str) {
+    let mut maybe_bdev = bdev_first();
+
+    while let Some(mut bdev) = maybe_bdev {
+        let lvol = unsafe { vbdev_lvol_get_from_bdev(bdev.as_ptr()) };
+        if !lvol.is_null() && unsafe { spdk_blob_is_clone((*lvol).blob) } {
+            let lvs_name = unsafe {
+                CStr::from_ptr(&(*(*lvol).lvol_store).name as *const i8)
+            };
+            let name = unsafe { CStr::from_ptr(&(*lvol).name as *const i8) };
+            if lvs_name.to_str() == Ok(pool) {
+                if let Ok(name) = name.to_str() {
+                    add_alias(pool, name);
+                }
+            }
+        }
+        maybe_bdev = bdev.next();
+    }
+}
+
+/// Return uuid of the template which the replica is a clone of.
+pub(crate) fn of_replica(replica: &Replica) -> Option<String> {
+    if !replica.is_clone() {
+        return None;
+    }
+    let lvol = replica.as_ptr();
+    let parent = unsafe {
+        spdk_blob_get_parent_snapshot(
+            (*(*lvol).lvol_store).blobstore,
+            spdk_blob_get_id((*lvol).blob),
+        )
+    };
+    templates()
+        .into_iter()
+        .find(|t| {
+            t.get_pool_name() == replica.get_pool_name()
+                && t.get_blob_id() == parent
+        })
+        .map(|t| t.get_uuid().to_owned())
+}
+
+/// Destroy retired templates without clones. Destroying a template can
+/// drop the last reference to another template (template made from a
+/// clone), so it is repeated until there is nothing to collect.
+pub(crate) async fn collect_garbage() {
+    loop {
+        let garbage = templates()
+            .into_iter()
+            .find(|t| t.is_retired() && t.count_clones() == 0);
+        match garbage {
+            Some(template) => {
+                let uuid = template.get_uuid().to_owned();
+                if let Err(err) = template.destroy_lvol().await {
+                    error!("Failed to collect template {}: {}", uuid, err);
+                    return;
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+fn list_templates() -> Vec<jsondata::Template> {
+    templates()
+        .iter()
+        .map(|t| jsondata::Template {
+            uuid: t.get_uuid().to_owned(),
+            pool: t.get_pool_name().to_owned(),
+            size: t.get_size(),
+            clones: t.count_clones() as u64,
+            retired: t.is_retired(),
+        })
+        .collect()
+}
+
+/// Register template json-rpc methods.
+pub fn register_template_methods() {
+    jsonrpc_register(
+        "create_template",
+        |args: jsondata::CreateTemplateArgs| {
+            let fut = async move {
+                Template::create(&args.uuid, &args.replica).await
+            };
+            fut.boxed_local()
+        },
+    );
+
+    jsonrpc_register("clone_template", |args: jsondata::CloneTemplateArgs| {
+        let fut =
+            async move { Template::clone_to(&args.uuid, &args.clones).await };
+        fut.boxed_local()
+    });
+
+    jsonrpc_register(
+        "destroy_template",
+        |args: jsondata::DestroyTemplateArgs| {
+            let fut = async move {
+                match Template::lookup(&args.uuid) {
+                    Some(template) => template.destroy().await,
+                    None => Err(JsonRpcError::new(
+                        Code::NotFound,
+                        format!("The template {} does not exist", args.uuid),
+                    )),
+                }
+            };
+            fut.boxed_local()
+        },
+    );
+
+    jsonrpc_register::<(), _, _>("list_templates", |_| {
+        future::ok(list_templates()).boxed_local()
+    });
+}
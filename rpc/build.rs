@@ -1,5 +1,8 @@
 extern crate tower_grpc_build;
 use prost_build::Config;
+use std::{env, path::PathBuf};
+
+mod schema;
 
 fn main() {
     let mut config = Config::new();
@@ -27,4 +30,10 @@ fn main() {
         .unwrap_or_else(|e| {
             panic!("egress protobuf compilation failed: {}", e)
         });
+
+    schema::generate(
+        &["proto/mayastor.proto", "proto/mayastor_service.proto"],
+        "proto",
+        &PathBuf::from(env::var("OUT_DIR").unwrap()),
+    );
 }
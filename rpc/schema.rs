@@ -0,0 +1,202 @@
+//! Generator of JSON schema for the messages of mayastor API.
+//!
+//! The schema is derived from proto descriptors, so it describes exactly the
+//! types generated by prost (and their serde representation) and it cannot
+//! get out of sync with them. Enums are serialized as their integer values,
+//! which are listed in the description of the enum.
+
+use prost::Message;
+use prost_types::{
+    field_descriptor_proto::{Label, Type},
+    DescriptorProto,
+    EnumDescriptorProto,
+    FieldDescriptorProto,
+    FileDescriptorSet,
+    SourceCodeInfo,
+};
+use serde_json::{json, Map, Value};
+use std::{fs, path::Path, process::Command};
+
+// field numbers of the descriptors used in source code info paths
+const FILE_MESSAGE_TYPE: i32 = 4;
+const FILE_ENUM_TYPE: i32 = 5;
+const MESSAGE_FIELD: i32 = 2;
+const ENUM_VALUE: i32 = 2;
+
+/// Return comment of the element at given path in the proto file.
+fn comment(info: &Option<SourceCodeInfo>, path: &[i32]) -> Option<String> {
+    let location = info.as_ref()?.location.iter().find(|l| l.path == path)?;
+    let text = location
+        .leading_comments
+        .as_ref()
+        .or_else(|| location.trailing_comments.as_ref())?;
+    let text = text
+        .lines()
+        .map(|l| l.trim())
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .trim()
+        .to_owned();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Return name of the definition for fully qualified proto type name.
+fn type_name(name: &str) -> &str {
+    name.rsplit('.').next().unwrap()
+}
+
+fn field_schema(field: &FieldDescriptorProto) -> Value {
+    let item = match Type::from_i32(field.r#type.unwrap_or_default()) {
+        Some(Type::String) => json!({"type": "string"}),
+        Some(Type::Bool) => json!({"type": "boolean"}),
+        Some(Type::Double) | Some(Type::Float) => json!({"type": "number"}),
+        Some(Type::Uint32) | Some(Type::Uint64) | Some(Type::Fixed32)
+        | Some(Type::Fixed64) => json!({"type": "integer", "minimum": 0}),
+        Some(Type::Bytes) => json!({
+            "type": "array",
+            "items": {"type": "integer", "minimum": 0, "maximum": 255}
+        }),
+        Some(Type::Enum) | Some(Type::Message) => json!({
+            "$ref": format!(
+                "#/definitions/{}",
+                type_name(field.type_name.as_ref().unwrap())
+            )
+        }),
+        _ => json!({"type": "integer"}),
+    };
+
+    if field.label == Some(Label::Repeated as i32) {
+        json!({"type": "array", "items": item})
+    } else if field.r#type == Some(Type::Message as i32) {
+        // embedded messages are optional in proto3
+        json!({"oneOf": [item, {"type": "null"}]})
+    } else {
+        item
+    }
+}
+
+fn message_schema(
+    msg: &DescriptorProto,
+    info: &Option<SourceCodeInfo>,
+    path: &[i32],
+) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+
+    for (i, field) in msg.field.iter().enumerate() {
+        let name = field.name.clone().unwrap();
+        let mut schema = field_schema(field);
+        let mut field_path = path.to_vec();
+        field_path.extend(&[MESSAGE_FIELD, i as i32]);
+        if let Some(text) = comment(info, &field_path) {
+            schema["description"] = Value::String(text);
+        }
+        properties.insert(name.clone(), schema);
+        required.push(Value::String(name));
+    }
+
+    let mut schema = json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+        "additionalProperties": false,
+    });
+    if let Some(text) = comment(info, path) {
+        schema["description"] = Value::String(text);
+    }
+    schema
+}
+
+fn enum_schema(
+    enm: &EnumDescriptorProto,
+    info: &Option<SourceCodeInfo>,
+    path: &[i32],
+) -> Value {
+    let values: Vec<String> = enm
+        .value
+        .iter()
+        .enumerate()
+        .map(|(i, val)| {
+            let mut val_path = path.to_vec();
+            val_path.extend(&[ENUM_VALUE, i as i32]);
+            let mut text = format!(
+                "{} = {}",
+                val.number.unwrap(),
+                val.name.clone().unwrap()
+            );
+            if let Some(comment) = comment(info, &val_path) {
+                text.push_str(&format!(" ({})", comment));
+            }
+            text
+        })
+        .collect();
+    let mut description = comment(info, path).unwrap_or_default();
+    if !description.is_empty() {
+        description.push_str(" ");
+    }
+    description.push_str(&format!("Values: {}.", values.join(", ")));
+    let numbers: Vec<i32> =
+        enm.value.iter().map(|v| v.number.unwrap()).collect();
+
+    json!({
+        "type": "integer",
+        "enum": numbers,
+        "description": description,
+    })
+}
+
+/// Compile the protos to descriptors and write JSON schema of all messages
+/// and enums to the output file.
+pub fn generate(protos: &[&str], include: &str, out_dir: &Path) {
+    let descriptor_path = out_dir.join("mayastor_descriptor.bin");
+    let status = Command::new(prost_build::protoc())
+        .arg("--include_imports")
+        .arg("--include_source_info")
+        .arg(format!(
+            "--descriptor_set_out={}",
+            descriptor_path.display()
+        ))
+        .arg(format!("-I{}", include))
+        .arg(format!("-I{}", prost_build::protoc_include().display()))
+        .args(protos)
+        .status()
+        .unwrap_or_else(|e| panic!("failed to run protoc: {}", e));
+    if !status.success() {
+        panic!("protoc failed to generate descriptors: {}", status);
+    }
+    let buf = fs::read(&descriptor_path).unwrap();
+    let set = FileDescriptorSet::decode(buf.as_slice())
+        .unwrap_or_else(|e| panic!("invalid descriptor set: {}", e));
+
+    let mut definitions = Map::new();
+    for file in &set.file {
+        let info = &file.source_code_info;
+        for (i, msg) in file.message_type.iter().enumerate() {
+            definitions.insert(
+                msg.name.clone().unwrap(),
+                message_schema(msg, info, &[FILE_MESSAGE_TYPE, i as i32]),
+            );
+        }
+        for (i, enm) in file.enum_type.iter().enumerate() {
+            definitions.insert(
+                enm.name.clone().unwrap(),
+                enum_schema(enm, info, &[FILE_ENUM_TYPE, i as i32]),
+            );
+        }
+    }
+
+    let schema = json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "Mayastor API",
+        "definitions": definitions,
+    });
+    fs::write(
+        out_dir.join("mayastor_schema.json"),
+        serde_json::to_string_pretty(&schema).unwrap(),
+    )
+    .unwrap();
+}
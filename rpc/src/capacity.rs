@@ -0,0 +1,162 @@
+//! Capacity of storage in the units which users see.
+//!
+//! All APIs (gRPC, json-rpc, CSI, metrics) carry sizes in bytes. When a size
+//! is shown to the user or given by the user, it goes through `Capacity`, so
+//! that all tools agree on what the units mean. Sizes are formatted in binary
+//! units (KiB, MiB, GiB, TiB), which are what the sizes of volumes and pools
+//! usually are. Parsing accepts both binary units and decimal ones (K, KB, M,
+//! MB, G, GB, T, TB are powers of 1000 as in k8s quantities), so that
+//! "10Gi" and "10GB" are not silently taken for the same size.
+
+use std::{error::Error, fmt, str::FromStr};
+
+pub const KIB: u64 = 1 << 10;
+pub const MIB: u64 = 1 << 20;
+pub const GIB: u64 = 1 << 30;
+pub const TIB: u64 = 1 << 40;
+
+/// Units accepted by parser (lowercase).
+const UNITS: [(&str, u64); 17] = [
+    ("b", 1),
+    ("k", 1_000),
+    ("kb", 1_000),
+    ("ki", KIB),
+    ("kib", KIB),
+    ("m", 1_000_000),
+    ("mb", 1_000_000),
+    ("mi", MIB),
+    ("mib", MIB),
+    ("g", 1_000_000_000),
+    ("gb", 1_000_000_000),
+    ("gi", GIB),
+    ("gib", GIB),
+    ("t", 1_000_000_000_000),
+    ("tb", 1_000_000_000_000),
+    ("ti", TIB),
+    ("tib", TIB),
+];
+
+/// Size in bytes.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct Capacity(u64);
+
+/// Error of parsing a capacity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseCapacityError(String);
+
+impl fmt::Display for ParseCapacityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid capacity {}", self.0)
+    }
+}
+
+impl Error for ParseCapacityError {}
+
+impl Capacity {
+    pub fn from_bytes(bytes: u64) -> Self {
+        Capacity(bytes)
+    }
+
+    pub fn from_mib(mib: u64) -> Self {
+        Capacity(mib.saturating_mul(MIB))
+    }
+
+    pub fn from_gib(gib: u64) -> Self {
+        Capacity(gib.saturating_mul(GIB))
+    }
+
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// Parse capacity given by a number with an optional unit ("10GiB",
+    /// "1.5 TB", ...). A number without a unit is taken in the given unit
+    /// (i.e. MIB for tools which have always taken sizes in MiB).
+    pub fn parse_with_unit(
+        src: &str,
+        default_unit: u64,
+    ) -> Result<Self, ParseCapacityError> {
+        let err = || ParseCapacityError(format!("\"{}\"", src));
+        let trimmed = src.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(trimmed.len());
+        let (number, unit) = trimmed.split_at(split);
+        let unit = unit.trim().to_lowercase();
+        let mult = if unit.is_empty() {
+            default_unit
+        } else {
+            UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .map(|(_, mult)| *mult)
+                .ok_or_else(err)?
+        };
+
+        if number.contains('.') {
+            let val = number.parse::<f64>().map_err(|_| err())? * mult as f64;
+            if !val.is_finite() || val > u64::max_value() as f64 {
+                return Err(err());
+            }
+            Ok(Capacity(val.round() as u64))
+        } else {
+            number
+                .parse::<u64>()
+                .ok()
+                .and_then(|val| val.checked_mul(mult))
+                .map(Capacity)
+                .ok_or_else(err)
+        }
+    }
+}
+
+impl FromStr for Capacity {
+    type Err = ParseCapacityError;
+
+    /// A number without a unit is in bytes.
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        Capacity::parse_with_unit(src, 1)
+    }
+}
+
+/// Formatted in the largest binary unit which is not bigger than the
+/// capacity with at most two decimal places (i.e. "1.5 GiB", "512 B").
+impl fmt::Display for Capacity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unit = [(TIB, "TiB"), (GIB, "GiB"), (MIB, "MiB"), (KIB, "KiB")]
+            .iter()
+            .find(|(size, _)| self.0 >= *size);
+        let (size, name) = match unit {
+            Some(unit) => *unit,
+            None => return write!(f, "{} B", self.0),
+        };
+        let val = format!("{:.2}", self.0 as f64 / size as f64);
+        let val = val.trim_end_matches('0').trim_end_matches('.');
+        write!(f, "{} {}", val, name)
+    }
+}
+
+impl From<u64> for Capacity {
+    fn from(bytes: u64) -> Self {
+        Capacity(bytes)
+    }
+}
+
+impl From<Capacity> for u64 {
+    fn from(capacity: Capacity) -> Self {
+        capacity.0
+    }
+}
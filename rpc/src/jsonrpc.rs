@@ -7,6 +7,10 @@ pub struct CreateOrImportPoolArgs {
     pub disks: Vec<String>,
     /// the block_size of the underlying block devices
     pub block_size: Option<u32>,
+    /// hex encoded key of AES-CBC cipher (16 bytes) if the pool is encrypted
+    /// at rest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<String>,
 }
 
 /// destroy the pool by name
@@ -29,6 +33,25 @@ pub struct Pool {
     pub capacity: u64,
     /// the used capacity in bytes
     pub used: u64,
+    /// breakdown of the used capacity (missing in replies from older
+    /// versions)
+    #[serde(default)]
+    pub usage: PoolUsage,
+    /// true if the pool is encrypted at rest
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// space occupied in the pool by type of the consumer
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PoolUsage {
+    /// bytes allocated by lvols which are not snapshots
+    pub volumes: u64,
+    /// bytes allocated by snapshots
+    pub snapshots: u64,
+    /// bytes of the base bdev occupied by blobstore metadata (it is not
+    /// part of the pool capacity)
+    pub metadata: u64,
 }
 
 /// create replica arguments
@@ -42,6 +65,9 @@ pub struct CreateReplicaArgs {
     pub thin_provision: bool,
     /// size of the replica in bytes
     pub size: u64,
+    /// compress data of the replica (it is always thin provisioned then)
+    #[serde(default)]
+    pub compress: bool,
 }
 
 /// destroy replica arguments
@@ -58,50 +84,166 @@ pub struct Replica {
     pub pool: String,
     pub size: u64,
     pub thin_provision: bool,
+    #[serde(default)]
+    pub compressed: bool,
+    /// uuid of the template if the replica is a clone of one
+    #[serde(default)]
+    pub template: Option<String>,
+    /// lvol which has not been created by mayastor (see adopt_replica)
+    #[serde(default)]
+    pub quarantined: bool,
 }
 
+/// adopt replica arguments
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct Stats {
+pub struct AdoptReplicaArgs {
+    /// name of the pool with the lvol
+    pub pool: String,
+    /// name of the quarantined lvol
+    pub name: String,
+}
+
+/// adopted replica
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdoptReplicaReply {
+    /// uuid (and name) of the replica which the lvol has become
+    pub uuid: String,
+}
+
+/// create template arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CreateTemplateArgs {
+    /// uuid of the template to create
+    pub uuid: String,
+    /// uuid of the replica with the data of the template
+    pub replica: String,
+}
+
+/// clone template arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CloneTemplateArgs {
+    /// uuid of the template
+    pub uuid: String,
+    /// uuids of the replicas to create
+    pub clones: Vec<String>,
+}
+
+/// destroy template arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DestroyTemplateArgs {
+    /// uuid of the template to destroy
+    pub uuid: String,
+}
+
+/// representation of a template
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Template {
     pub uuid: String,
     pub pool: String,
-    pub num_read_ops: u64,
-    pub num_write_ops: u64,
-    pub bytes_read: u64,
-    pub bytes_written: u64,
+    pub size: u64,
+    /// number of clones of the template
+    pub clones: u64,
+    /// the template is destroyed with its last clone
+    pub retired: bool,
 }
 
-// the underlying fields will be removed shortly
+/// cancel job arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CancelJobArgs {
+    /// name of the job as returned by list_jobs
+    pub name: String,
+}
 
-#[derive(Clone, Debug, Serialize)]
-pub struct GetBdevsArgs {
+/// background IO budget arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackgroundBudget {
+    /// bytes per second shared by all background jobs (0 = unlimited)
+    pub bytes_per_sec: u64,
+}
+
+/// background job sharing the budget
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackgroundShare {
+    /// name of the job as returned by list_jobs
     pub name: String,
+    /// weight of the job when dividing the budget
+    pub weight: u64,
+    /// bytes done by the job so far
+    pub bytes: u64,
+    /// the job is waiting for its part of the budget
+    pub waiting: bool,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct Bdev {
+/// background IO budget and the jobs sharing it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BackgroundBudgetStatus {
+    pub bytes_per_sec: u64,
+    pub jobs: Vec<BackgroundShare>,
+}
+
+/// storage backend (bdev type) and whether it can be used on the node
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Backend {
     pub name: String,
-    pub aliases: Vec<String>,
-    pub product_name: String,
-    pub block_size: u32,
-    pub num_blocks: u64,
-    pub uuid: Option<String>,
-    // ... other fields which are not used by us (i.e. uuid, qos, etc.)
-    pub driver_specific: serde_json::Value,
+    pub available: bool,
+    /// why the backend is not available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// capabilities of the node returned by get_capabilities
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// architecture mayastor has been built for (i.e. x86_64, aarch64)
+    pub arch: String,
+    pub arch_supported: bool,
+    /// base page size of the kernel in bytes
+    pub page_size: u64,
+    /// default hugepage size in kB
+    pub hugepage_size: u64,
+    /// hugepage sizes supported by the kernel in kB
+    pub hugepage_sizes: Vec<u64>,
+    /// kernel supports io_uring
+    pub io_uring: bool,
+    pub backends: Vec<Backend>,
+    /// problems found and what to do about them
+    pub warnings: Vec<String>,
 }
 
-#[derive(Clone, Debug, Serialize)]
-pub struct StartNbdDiskArgs {
-    pub bdev_name: String,
-    pub nbd_device: String,
+/// scrub replica arguments
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScrubReplicaArgs {
+    /// uuid of the replica to scrub
+    pub uuid: String,
 }
 
-#[derive(Clone, Debug, Serialize)]
-pub struct StopNbdDiskArgs {
-    pub nbd_device: String,
+/// checksum of the data of a replica computed by the scrubber
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScrubResult {
+    pub uuid: String,
+    /// size of the data in bytes
+    pub size: u64,
+    /// sha256 of the data (hex)
+    pub checksum: String,
+    /// when the scrub finished (seconds since the epoch)
+    pub scrubbed: u64,
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct NbdDisk {
-    pub nbd_device: String,
-    pub bdev_name: String,
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub uuid: String,
+    pub pool: String,
+    pub num_read_ops: u64,
+    pub num_write_ops: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// true if the data of the replica are compressed
+    #[serde(default)]
+    pub compressed: bool,
+    /// logical size of the replica in bytes
+    #[serde(default)]
+    pub size: u64,
+    /// bytes allocated in the pool by the replica
+    #[serde(default)]
+    pub allocated: u64,
 }
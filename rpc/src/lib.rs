@@ -22,4 +22,9 @@ pub mod service {
     include!(concat!(env!("OUT_DIR"), "/mayastor_service.rs"));
 }
 
+pub mod capacity;
 pub mod jsonrpc;
+
+/// JSON schema of all messages of the API generated from the proto files.
+pub const SCHEMA: &str =
+    include_str!(concat!(env!("OUT_DIR"), "/mayastor_schema.json"));
@@ -1,14 +1,18 @@
 ///! Utility functions for reading and modifying the state of sysfs
-/// objects.
-use std::path::Path;
+/// objects and for durable writes of state files.
+use std::path::{Path, PathBuf};
 use std::{
     collections::HashMap,
+    ffi::OsString,
     fs,
-    io::{BufRead, BufReader, Error, ErrorKind, Result},
+    io::{BufRead, BufReader, Error, ErrorKind, Result, Write},
     str::FromStr,
     string,
 };
 
+/// Extension of temporary files written by `write_atomic`.
+pub const TMP_SUFFIX: &str = "tmp";
+
 /// Read and parse value from a file
 pub fn parse_value<T>(dir: &Path, file: &str) -> Result<T>
 where
@@ -58,3 +62,101 @@ pub fn parse_dict(dir: &Path, file: &str) -> Result<HashMap<String, String>> {
     }
     Ok(dict)
 }
+
+/// Path of the temporary file used for writing the file. The suffix is
+/// appended to the full file name, so files which differ only in their
+/// extension don't share it.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path
+        .file_name()
+        .map_or_else(OsString::new, |name| name.to_os_string());
+    name.push(".");
+    name.push(TMP_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Make changes of directory entries (create, rename, unlink) durable.
+pub fn sync_dir(dir: &Path) -> Result<()> {
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Replace content of the file so that a crash leaves either the old or the
+/// new content behind and never a torn one. The data are written to a
+/// temporary file which is fsynced and renamed over the file, and the rename
+/// is made durable by fsync of the directory. The temporary file is removed
+/// if the write fails.
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let tmp_path = tmp_path(path);
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    fs::File::create(&tmp_path)
+        .and_then(|mut file| {
+            file.write_all(data)?;
+            file.sync_all()
+        })
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp_path);
+            err
+        })?;
+    sync_dir(dir)
+}
+
+/// Remove temporary files left behind in the directory by writes which were
+/// interrupted by a crash. Return paths of the removed files.
+pub fn remove_tmp_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) == Some(TMP_SUFFIX) {
+            fs::remove_file(&path)?;
+            removed.push(path);
+        }
+    }
+    if !removed.is_empty() {
+        sync_dir(dir)?;
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn tmp_path_appends_suffix() {
+        assert_eq!(tmp_path(Path::new("/a/vol")), Path::new("/a/vol.tmp"));
+        assert_eq!(tmp_path(Path::new("/a/b.c")), Path::new("/a/b.c.tmp"));
+        assert_ne!(tmp_path(Path::new("a.b")), tmp_path(Path::new("a")));
+    }
+
+    #[test]
+    fn write_atomic_replaces_file() {
+        let dir = env::temp_dir().join("sysfs-write-atomic-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!tmp_path(&path).exists());
+
+        // writing to a missing directory fails and leaves nothing behind
+        let missing = dir.join("missing").join("state.json");
+        assert!(write_atomic(&missing, b"data").is_err());
+        assert!(!tmp_path(&missing).exists());
+
+        fs::write(tmp_path(&path), b"torn").unwrap();
+        assert_eq!(remove_tmp_files(&dir).unwrap(), vec![tmp_path(&path)]);
+        assert_eq!(remove_tmp_files(&dir).unwrap(), Vec::<PathBuf>::new());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}